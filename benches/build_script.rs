@@ -0,0 +1,49 @@
+//! Compare a oneshot query of a single pid with the same query through the shared process list, as run by a build
+//! script which checks on one process and exits.
+//!
+//! Run with `cargo bench --bench build_script`. A build script runs each query once, so before the timings the cost of
+//! the first query of each kind in this process is printed. That is what a build script pays, and for the shared list
+//! it includes filling it with every process. The timings after that are of queries repeated in a loop, where the
+//! shared list has already been filled.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use proc_ctl::ProcQuery;
+use std::time::Instant;
+
+fn first_queries() {
+    let pid = std::process::id();
+
+    // The oneshot query must run first, since it would otherwise find the shared list already filled
+    let start = Instant::now();
+    ProcQuery::oneshot()
+        .process_id(pid)
+        .list_processes()
+        .unwrap();
+    let oneshot = start.elapsed();
+
+    let start = Instant::now();
+    ProcQuery::new().process_id(pid).list_processes().unwrap();
+    let shared = start.elapsed();
+
+    println!(
+        "first query: oneshot took {:?}, shared took {:?} including filling the process list",
+        oneshot, shared
+    );
+}
+
+fn single_pid_queries(c: &mut Criterion) {
+    first_queries();
+
+    let oneshot = ProcQuery::oneshot().process_id(std::process::id());
+    let shared = ProcQuery::new().process_id(std::process::id());
+
+    c.bench_function("oneshot single pid", |b| {
+        b.iter(|| oneshot.list_processes().unwrap())
+    });
+    c.bench_function("shared single pid", |b| {
+        b.iter(|| shared.list_processes().unwrap())
+    });
+}
+
+criterion_group!(benches, single_pid_queries);
+criterion_main!(benches);
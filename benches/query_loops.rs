@@ -0,0 +1,83 @@
+//! Compare the query functions which return a new list with those which fill a buffer, as used by loops which query
+//! many times a second.
+//!
+//! Run with `cargo bench --bench query_loops`. Before the timings, the number of allocations made by a single query of
+//! each kind is printed, as counted by a global allocator.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use proc_ctl::{PortQuery, ProcQuery};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// SAFETY: every call is passed straight on to the system allocator
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations(mut f: impl FnMut()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn port_queries(c: &mut Criterion) {
+    let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let query = PortQuery::new().process_id(std::process::id());
+
+    // Both are run once first, so that the buffers and the last observed value have grown to fit
+    let mut ports = Vec::new();
+    query.execute_into(&mut ports).unwrap();
+    query.execute().unwrap();
+    println!(
+        "ports: execute made {} allocations, execute_into made {}",
+        allocations(|| drop(query.execute().unwrap())),
+        allocations(|| query.execute_into(&mut ports).unwrap())
+    );
+
+    c.bench_function("execute", |b| b.iter(|| query.execute().unwrap()));
+    c.bench_function("execute_into", |b| {
+        b.iter(|| query.execute_into(&mut ports).unwrap())
+    });
+}
+
+fn proc_queries(c: &mut Criterion) {
+    let query = ProcQuery::new();
+
+    let mut processes = Vec::new();
+    query.list_processes_into(&mut processes).unwrap();
+    query.list_processes().unwrap();
+    println!(
+        "processes: list_processes made {} allocations, list_processes_into made {}",
+        allocations(|| drop(query.list_processes().unwrap())),
+        allocations(|| query.list_processes_into(&mut processes).unwrap())
+    );
+
+    c.bench_function("list_processes", |b| {
+        b.iter(|| query.list_processes().unwrap())
+    });
+    c.bench_function("list_processes_into", |b| {
+        b.iter(|| query.list_processes_into(&mut processes).unwrap())
+    });
+}
+
+criterion_group!(benches, port_queries, proc_queries);
+criterion_main!(benches);
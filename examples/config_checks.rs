@@ -0,0 +1,88 @@
+//! Load readiness checks from a TOML file and run each of them, reporting which passed.
+//!
+//! The checks in `examples/config_checks.toml` look for this example itself, which binds a TCP listener before running
+//! them. Run with `cargo run --example config_checks --features serde,resilience`, optionally followed by `--` and the
+//! path to another file of checks.
+
+use proc_ctl::{PortQuery, PortQueryConfig, ProcCtlError, ProcQuery, ProcQueryConfig};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Checks {
+    #[serde(default)]
+    ports: Vec<Check<PortQueryConfig>>,
+    #[serde(default)]
+    processes: Vec<Check<ProcQueryConfig>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Check<C> {
+    name: String,
+    query: C,
+}
+
+fn main() {
+    let path = std::env::args().nth(1).unwrap_or_else(|| {
+        concat!(env!("CARGO_MANIFEST_DIR"), "/examples/config_checks.toml").to_string()
+    });
+    let checks: Checks = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+    let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut failed = 0;
+    for check in &checks.processes {
+        let query = ProcQuery::from_config(&check.query);
+        // A check which expects children looks at the children of the process, and is the one kind which is retried
+        let result = match (check.query.expect_min_num_children, check.query.retry) {
+            (Some(_), Some(retry)) => query.children_with_retry_sync(retry.delay(), retry.attempts),
+            (Some(_), None) => query.children(),
+            (None, _) => query.list_processes().and_then(|processes| {
+                if processes.is_empty() {
+                    Err(ProcCtlError::NoMatchingProcess(format!(
+                        "{:?}",
+                        check.query
+                    )))
+                } else {
+                    Ok(processes)
+                }
+            }),
+        };
+        failed += report(
+            &check.name,
+            result.map(|processes| format!("{} processes", processes.len())),
+        );
+    }
+
+    for check in &checks.ports {
+        let query = PortQuery::from_config(&check.query);
+        let result = match check.query.retry {
+            Some(retry) => query.execute_with_retry_sync(retry.delay(), retry.attempts),
+            None => query.execute(),
+        };
+        failed += report(&check.name, result.map(|ports| format!("{:?}", ports)));
+    }
+
+    if failed > 0 {
+        eprintln!(
+            "{} of {} checks failed",
+            failed,
+            checks.processes.len() + checks.ports.len()
+        );
+        std::process::exit(1);
+    }
+}
+
+fn report(name: &str, result: proc_ctl::ProcCtlResult<String>) -> usize {
+    match result {
+        Ok(found) => {
+            println!("pass: {}, found {}", name, found);
+            0
+        }
+        Err(e) => {
+            println!("fail: {}, {}", name, e);
+            1
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Spawn a process with duct, wait for it to bind a port, check the result and then kill it.
+//!
+//! This uses one of the sample binaries, so build them first with `cargo build --release --bins`, then run with
+//! `cargo run --example duct_wait_for_ports --features duct,resilience`
+
+use proc_ctl::{PortQuery, ProtocolPort};
+use std::time::Duration;
+
+fn main() {
+    let binder = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("release")
+        .join("port-binder");
+
+    let handle = duct::cmd!(binder).unchecked().start().unwrap();
+
+    let ports = PortQuery::new()
+        .tcp_only()
+        .expect_min_num_ports(1)
+        .process_id_from_handle(&handle)
+        .unwrap()
+        .execute_with_retry_sync(Duration::from_millis(100), 50)
+        .unwrap();
+
+    assert!(matches!(ports.as_slice(), [ProtocolPort::Tcp(_)]));
+    println!("Found {:?}", ports);
+
+    handle.kill().unwrap();
+}
@@ -0,0 +1,102 @@
+//! Find a process by name and shut it down gracefully, forcing it to stop if it doesn't exit in time.
+//!
+//! The process found here is the waiter sample, started by this example so that nothing else is disturbed. It is
+//! found by name like any other process, and only processes started by this example are stopped. On Linux and macOS
+//! it is asked to stop with `SIGTERM`. Windows has no equivalent for a console process, so there the waiter is asked to
+//! stop by closing its input.
+//!
+//! Build the samples first with `cargo build --release --bins --features test-util`, then run with
+//! `cargo run --example find_and_kill`
+
+use proc_ctl::{ProcInfo, ProcQuery};
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+fn main() {
+    let mut waiter = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("release")
+        .join("waiter");
+    if cfg!(target_os = "windows") {
+        waiter.set_extension("exe");
+    }
+
+    let mut child = std::process::Command::new(waiter)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("the samples should be built first");
+
+    let found = match find_started_by_us("waiter") {
+        Ok(found) => found,
+        Err(e) => {
+            println!("Could not find the waiter: {}", e);
+            let _ = child.kill();
+            return;
+        }
+    };
+
+    for info in found {
+        println!(
+            "Found {} with pid {}, asking it to stop",
+            info.name, info.pid
+        );
+        request_stop(&info, &mut child);
+
+        if wait_for_exit(&mut child, Duration::from_secs(5)) {
+            println!("It stopped by itself");
+        } else {
+            println!("It did not stop in time, killing it");
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+    }
+}
+
+/// Find the processes with this name which were started by this example, retrying while the new process starts
+fn find_started_by_us(name: &str) -> proc_ctl::ProcCtlResult<Vec<ProcInfo>> {
+    let query = ProcQuery::new().process_name(name);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let found = query
+            .list_processes()?
+            .into_iter()
+            .filter(|info| info.parent == Some(std::process::id()))
+            .collect::<Vec<_>>();
+        if !found.is_empty() || Instant::now() > deadline {
+            return Ok(found);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn request_stop(info: &ProcInfo, _child: &mut Child) {
+    let pid = libc::pid_t::try_from(info.pid).expect("pid in range");
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        println!(
+            "Could not signal {}: {}",
+            info.pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn request_stop(_info: &ProcInfo, child: &mut Child) {
+    // The waiter exits once its input is closed
+    drop(child.stdin.take());
+}
+
+/// Wait for the child to exit, returning whether it did before the timeout
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if child.try_wait().unwrap().is_some() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    false
+}
@@ -0,0 +1,62 @@
+//! Audit the ports opened and closed across the whole system between two snapshots.
+//!
+//! The first snapshot is taken before this example opens a listener, and the second after, so the listener is
+//! reported as opened. Environments and command line arguments are left out of the snapshots, as they would be before
+//! sharing them.
+//!
+//! Run with `cargo run --example port_audit --features serde`
+
+use proc_ctl::{AddressFamily, Pid, PortInfo, ProtocolPort, Redaction, Snapshot};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+fn main() {
+    let redaction = Redaction::new().drop_env().truncate_cmd(1);
+
+    let before = match Snapshot::capture(&redaction) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            println!("Could not take a snapshot: {}", e);
+            return;
+        }
+    };
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("Listening on {}", listener.local_addr().unwrap());
+
+    let after = Snapshot::capture(&redaction).unwrap();
+
+    let (before_keys, after_keys) = (keys(&before), keys(&after));
+    for opened in after
+        .sockets
+        .iter()
+        .filter(|s| !before_keys.contains(&key(s)))
+    {
+        println!("Opened: {}", describe(&after, opened));
+    }
+    for closed in before
+        .sockets
+        .iter()
+        .filter(|s| !after_keys.contains(&key(s)))
+    {
+        println!("Closed: {}", describe(&before, closed));
+    }
+}
+
+/// What identifies a socket between snapshots. Other details, such as the queue length, change while it is open.
+type SocketKey = (Pid, ProtocolPort, AddressFamily, Option<SocketAddr>);
+
+fn key(info: &PortInfo) -> SocketKey {
+    (info.pid, info.port, info.family, info.local_addr)
+}
+
+fn keys(snapshot: &Snapshot) -> HashSet<SocketKey> {
+    snapshot.sockets.iter().map(key).collect()
+}
+
+fn describe(snapshot: &Snapshot, info: &PortInfo) -> String {
+    let name = snapshot
+        .process(info.pid)
+        .map_or("an unknown process", |p| p.name.as_str());
+    format!("{} by {}", info, name)
+}
@@ -0,0 +1,51 @@
+//! Guard a process so that it only ever listens on its allowed ports, reporting whenever that changes.
+//!
+//! This starts the multi-port binder sample with two TCP listeners and allows only the first of them, so the guard
+//! reports the second as unexpected. Once the binder is stopped, the guard reports that it has gone.
+//!
+//! Build the samples first with `cargo build --release --bins --features test-util`, then run with
+//! `cargo run --example port_guard --features test-util`
+
+use proc_ctl::binder::{BinderConfig, MultiPortBinder};
+use proc_ctl::{PortQuery, Reconciler};
+use std::time::Duration;
+
+fn main() {
+    let binder = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("release")
+        .join("multi-port-binder");
+
+    let binder = MultiPortBinder::spawn(
+        std::process::Command::new(binder),
+        &BinderConfig {
+            tcp4: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let allowed = binder.ports()[0];
+    println!("Allowing only {:?} of {:?}", allowed, binder.ports());
+
+    let guard = Reconciler::new(PortQuery::new().process_id(binder.pid()), [allowed])
+        .interval(Duration::from_millis(200))
+        .start(|change| match change {
+            Ok(violation) if violation.is_empty() => println!("In line with the allowlist"),
+            Ok(violation) => println!(
+                "Violation: unexpected {:?}, missing {:?}",
+                violation
+                    .unexpected
+                    .iter()
+                    .map(|p| p.port)
+                    .collect::<Vec<_>>(),
+                violation.missing
+            ),
+            Err(e) => println!("Check failed: {}", e),
+        });
+
+    std::thread::sleep(Duration::from_secs(1));
+    drop(binder);
+    std::thread::sleep(Duration::from_secs(1));
+
+    guard.stop();
+}
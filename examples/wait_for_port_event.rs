@@ -0,0 +1,50 @@
+//! Wait for a process to bind a port, giving up after a timeout.
+//!
+//! The process watched here is this example itself, which binds a port from a background thread after a short delay.
+//!
+//! Run with `cargo run --example wait_for_port_event --features async`
+
+use futures_util::StreamExt;
+use proc_ctl::{PortEvent, PortQuery};
+use std::net::TcpListener;
+use std::time::Duration;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    std::thread::spawn(|| {
+        std::thread::sleep(Duration::from_millis(500));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        println!("Bound {}", listener.local_addr().unwrap());
+        std::thread::sleep(Duration::from_secs(10));
+    });
+
+    let mut events = PortQuery::new()
+        .tcp_only()
+        .process_id(std::process::id())
+        .events(Duration::from_millis(100))
+        .ports_only();
+
+    let timeout = tokio::time::sleep(Duration::from_secs(5));
+    tokio::pin!(timeout);
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(PortEvent::PortBound(port))) => {
+                    println!("Found {:?}", port);
+                    break;
+                }
+                Some(Ok(event)) => println!("Ignoring {:?}", event),
+                Some(Err(e)) => println!("Query failed, will try again: {}", e),
+                None => {
+                    println!("The process exited before binding a port");
+                    break;
+                }
+            },
+            _ = &mut timeout => {
+                println!("Timed out waiting for a port");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Start a server, wait for it to listen on a port, then connect to it.
+//!
+//! The server is the port binder sample, which listens on a random TCP port and exits once it has accepted a
+//! connection. The port is never passed back to this example, it is found from the operating system.
+//!
+//! Build the samples first with `cargo build --release --bins --features test-util`, then run with
+//! `cargo run --example wait_for_server`
+
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn main() {
+    let mut binder = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("release")
+        .join("port-binder");
+    if cfg!(target_os = "windows") {
+        binder.set_extension("exe");
+    }
+
+    let mut server = std::process::Command::new(binder)
+        .spawn()
+        .expect("the samples should be built first");
+
+    let port = match proc_ctl::wait_for_tcp_port(&server, Duration::from_secs(10)) {
+        Ok(port) => port,
+        Err(e) => {
+            println!("The server did not start listening: {}", e);
+            let _ = server.kill();
+            return;
+        }
+    };
+    println!("The server is listening on port {}", port);
+
+    match TcpStream::connect(("127.0.0.1", port)) {
+        Ok(stream) => println!("Connected from {}", stream.local_addr().unwrap()),
+        Err(e) => println!("Could not connect: {}", e),
+    }
+
+    let status = server.wait().unwrap();
+    println!("The server exited with {}", status);
+}
@@ -0,0 +1,79 @@
+//! Supervise the children of a process, reporting each one as it starts and exits.
+//!
+//! The process supervised here is the process runner sample, which starts the waiter sample as its child. The waiter
+//! exits once its input is closed, which the runner passes down from this example. The events seen are kept in a
+//! history, which is printed at the end.
+//!
+//! Build the samples first with `cargo build --release --bins --features test-util`, then run with
+//! `cargo run --example watch_children --features async`
+
+use futures_util::StreamExt;
+use proc_ctl::{ProcEvent, ProcQuery};
+use std::process::Stdio;
+use std::time::Duration;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let samples = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("release");
+    let sample = |name: &str| {
+        let path = samples.join(name);
+        if cfg!(target_os = "windows") {
+            path.with_extension("exe")
+        } else {
+            path
+        }
+    };
+
+    let mut events = ProcQuery::new()
+        .parent_name("proc-runner")
+        .events(Duration::from_millis(100))
+        .with_history(16);
+
+    let mut runner = std::process::Command::new(sample("proc-runner"))
+        .arg(sample("waiter"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("the samples should be built first");
+
+    let mut stdin = runner.stdin.take();
+    loop {
+        let event = match tokio::time::timeout(Duration::from_secs(5), events.next()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(_) => {
+                println!("Timed out waiting for the next event");
+                break;
+            }
+        };
+
+        match event {
+            Ok(ProcEvent::Started(info)) => {
+                println!("Child {} started with pid {}", info.name, info.pid);
+                // Let the child finish
+                drop(stdin.take());
+            }
+            Ok(ProcEvent::Exited(pid)) => {
+                println!("Child with pid {} exited", pid);
+                break;
+            }
+            Err(e) => println!("Query failed, will try again: {}", e),
+        }
+    }
+
+    let _ = runner.kill();
+    let _ = runner.wait();
+
+    if let Some(history) = events.history() {
+        println!("Seen during the run:");
+        for recorded in history.events() {
+            let event = match &recorded.event {
+                ProcEvent::Started(info) => format!("started pid {}", info.pid),
+                ProcEvent::Exited(pid) => format!("exited pid {}", pid),
+            };
+            println!("  after {:?}, {}", recorded.elapsed, event);
+        }
+    }
+}
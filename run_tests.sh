@@ -1,9 +1,32 @@
 #!/usr/bin/env bash
 
+# The same checks as CI, see .github/workflows/main.yml
+
 set -xue
 
-cargo test
-cargo test --no-default-features --test lib_test
-cargo test --features resilience
-cargo test --features async
-cargo test --all-features
+cargo fmt --all -- --check
+
+cargo clippy --features test-util --all-targets -- -Dwarnings
+cargo clippy --no-default-features --features test-util --all-targets -- -Dwarnings
+cargo clippy --no-default-features --features core,test-util --all-targets -- -Dwarnings
+cargo clippy --features resilience,test-util --all-targets -- -Dwarnings
+cargo clippy --features async,test-util --all-targets -- -Dwarnings
+cargo clippy --all-features --all-targets -- -Dwarnings
+
+cargo build --examples
+cargo build --examples --all-features
+
+# Create test binaries
+cargo build --release --bins --features test-util
+
+cargo test --features test-util -- --test-threads=1
+cargo test --no-default-features --features test-util --test lib_test -- --test-threads=1
+cargo test --no-default-features --features core,test-util --lib --tests -- --test-threads=1
+cargo test --features resilience,test-util -- --test-threads=1
+cargo test --features async,test-util -- --test-threads=1
+cargo test --all-features -- --test-threads=1
+
+# Pid conversions are checked against the width of the platform types, so run the unit tests on a 32-bit target too
+if rustup target list --installed | grep -q i686-unknown-linux-gnu; then
+    cargo test --all-features --lib --target i686-unknown-linux-gnu
+fi
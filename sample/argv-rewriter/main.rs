@@ -0,0 +1,34 @@
+use std::io::stdin;
+
+/// Rewrites its own command line and name, like daemons that show their status in `ps`, then waits for input
+fn main() {
+    #[cfg(target_os = "linux")]
+    rewrite_process_title("argv-rewriter: worker", "rewritten");
+
+    println!("Rewritten");
+    let buf = &mut String::new();
+    stdin().read_line(buf).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+fn rewrite_process_title(title: &str, comm: &str) {
+    use std::os::unix::fs::FileExt;
+
+    let stat = procfs::process::Process::myself().unwrap().stat().unwrap();
+    let start = stat.arg_start.unwrap();
+    let end = stat.arg_end.unwrap();
+
+    // Overwrite the whole of the original argv area, padding with NULs, as setproctitle implementations do
+    let mut buf = vec![0u8; (end - start) as usize];
+    let len = title.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&title.as_bytes()[..len]);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open("/proc/self/mem")
+        .unwrap()
+        .write_all_at(&buf, start)
+        .unwrap();
+
+    std::fs::write("/proc/self/comm", comm).unwrap();
+}
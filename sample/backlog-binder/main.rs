@@ -0,0 +1,37 @@
+use std::io::stdin;
+
+/// The backlog the listener is created with, checked by the tests
+const BACKLOG: i32 = 7;
+
+/// Listens on a TCP port with a known backlog, never accepting connections, and waits for input before exiting
+fn main() {
+    let port = listen(BACKLOG);
+
+    println!("{}", port);
+    let buf = &mut String::new();
+    stdin().read_line(buf).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+fn listen(backlog: i32) -> u16 {
+    use std::os::fd::FromRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // std listens with its own backlog, so listen again to change it. The socket is leaked to keep it open.
+    let fd = std::os::fd::IntoRawFd::into_raw_fd(listener);
+    assert_eq!(0, unsafe { libc::listen(fd, backlog) });
+    std::mem::forget(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+
+    port
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen(_backlog: i32) -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::mem::forget(listener);
+
+    port
+}
@@ -0,0 +1,37 @@
+use std::net::{TcpListener, UdpSocket};
+use std::time::Duration;
+
+/// Binds the same TCP port on both `127.0.0.1` and `::1`, and the same UDP port on both, then prints the TCP port and
+/// the UDP port on a line each. The sockets are held until the process is killed.
+fn main() {
+    let (tcp4, tcp6) = bind_both(|addr| {
+        let listener = TcpListener::bind(addr)?;
+        let port = listener.local_addr()?.port();
+        Ok((listener, port))
+    });
+    let (udp4, udp6) = bind_both(|addr| {
+        let socket = UdpSocket::bind(addr)?;
+        let port = socket.local_addr()?.port();
+        Ok((socket, port))
+    });
+
+    println!("{}", tcp4.1);
+    println!("{}", udp4.1);
+
+    let _sockets = (tcp4, tcp6, udp4, udp6);
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+/// Bind an ephemeral port on IPv4, then the same port on IPv6, trying again with a new port if it is taken on IPv6
+fn bind_both<T>(bind: impl Fn(String) -> std::io::Result<(T, u16)>) -> ((T, u16), (T, u16)) {
+    for _ in 0..10 {
+        let v4 = bind("127.0.0.1:0".to_string()).unwrap();
+        if let Ok(v6) = bind(format!("[::1]:{}", v4.1)) {
+            return (v4, v6);
+        }
+    }
+
+    panic!("could not bind the same port on both IPv4 and IPv6");
+}
@@ -0,0 +1,24 @@
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Binds a TCP port and prints it, then binds and prints a second one after the delay in milliseconds given as the
+/// first argument. Both are held until the process is killed.
+fn main() {
+    let delay = std::env::args()
+        .nth(1)
+        .and_then(|delay| delay.parse().ok())
+        .map(Duration::from_millis)
+        .expect("usage: late-binder <delay-ms>");
+
+    let first = TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("{}", first.local_addr().unwrap().port());
+
+    std::thread::sleep(delay);
+
+    let second = TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("{}", second.local_addr().unwrap().port());
+
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
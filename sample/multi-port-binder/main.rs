@@ -0,0 +1,7 @@
+use proc_ctl::binder::{run, BinderConfig};
+
+/// Binds the sockets described by its arguments, such as `tcp4=2 udp6=1`, and holds them until stdin is closed
+fn main() {
+    let config = BinderConfig::from_args(std::env::args().skip(1)).unwrap();
+    run(&config).unwrap();
+}
@@ -0,0 +1,22 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+/// Binds a UDP port and joins the IPv4 multicast group given as the first argument, then prints the port. Prints
+/// `unavailable` instead if the group can't be joined, as when there is no multicast route. Either way it runs until
+/// it is killed.
+fn main() {
+    let group = std::env::args()
+        .nth(1)
+        .and_then(|group| group.parse::<Ipv4Addr>().ok())
+        .expect("usage: multicast-joiner <group>");
+
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    match socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED) {
+        Ok(()) => println!("{}", socket.local_addr().unwrap().port()),
+        Err(e) => println!("unavailable: {}", e),
+    }
+
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,17 @@
+use std::io::stdin;
+use std::net::TcpListener;
+
+/// Binds a TCP port and prints it, then closes it after reading a line of input and keeps running until a second line
+/// is read or input is closed.
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    println!("{}", listener.local_addr().unwrap().port());
+
+    let buf = &mut String::new();
+    stdin().read_line(buf).unwrap();
+    drop(listener);
+    println!("released");
+
+    buf.clear();
+    stdin().read_line(buf).unwrap();
+}
@@ -0,0 +1,63 @@
+/// Binds a TCP port and starts two workers which inherit the listener, like a pre-fork server. Prints the port and the
+/// pids of the workers, then waits for stdin to close before exiting. Workers exit when the parent does.
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("worker") => worker(
+            args.next()
+                .expect("missing fd")
+                .parse()
+                .expect("invalid fd"),
+        ),
+        _ => parent(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn parent() {
+    use std::io::{stdin, Read};
+    use std::os::fd::AsRawFd;
+    use std::process::{Command, Stdio};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Let the workers inherit the listener, as they would after a fork
+    let fd = listener.as_raw_fd();
+    assert_eq!(0, unsafe { libc::fcntl(fd, libc::F_SETFD, 0) });
+
+    let workers = (0..2)
+        .map(|_| {
+            Command::new(std::env::current_exe().unwrap())
+                .arg("worker")
+                .arg(fd.to_string())
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    println!("{} {} {}", port, workers[0].id(), workers[1].id());
+    stdin().read_to_end(&mut Vec::new()).unwrap();
+    drop(listener);
+}
+
+#[cfg(target_os = "linux")]
+fn worker(fd: i32) {
+    use std::io::{stdin, Read};
+    use std::os::fd::FromRawFd;
+
+    let _listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // The parent holds the other end of stdin, so this returns when the parent exits
+    stdin().read_to_end(&mut Vec::new()).unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn parent() {
+    panic!("the pre-fork binder is only supported on Linux");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn worker(_fd: i32) {
+    panic!("the pre-fork binder is only supported on Linux");
+}
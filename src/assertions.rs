@@ -0,0 +1,131 @@
+//! Assertions for use in tests, which retry a query until it succeeds or a timeout is reached.
+//!
+//! On failure, these panic with a message describing the query, the last result that was observed, how many attempts
+//! were made and how long was spent. Where possible the message also includes what is known about the process that
+//! was being queried, which is usually the first thing you need to know when a test fails.
+//!
+//! [assert_matches] is the exception, which checks processes that have already been found against what was expected of
+//! them, see [crate::expectations].
+
+use crate::common::MaybeHasPid;
+use crate::{PortQuery, ProtocolPort};
+use std::fmt::Debug;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Assert that a [PortQuery] succeeds within `timeout`, returning the ports that were found.
+///
+/// # Panics
+///
+/// If the query has not succeeded by the time `timeout` has elapsed.
+pub fn assert_ports(query: &PortQuery, timeout: Duration) -> Vec<ProtocolPort> {
+    match poll(query.clock().as_ref(), timeout, || query.execute()) {
+        Ok(ports) => ports,
+        Err(failure) => panic!("{}", failure.describe("port", query, query.get_pid())),
+    }
+}
+
+/// Assert that [crate::ProcQuery::children] succeeds within `timeout`, returning the children that were found.
+///
+/// # Panics
+///
+/// If the query has not succeeded by the time `timeout` has elapsed.
+#[cfg(feature = "proc")]
+pub fn assert_children(query: &crate::ProcQuery, timeout: Duration) -> Vec<crate::ProcInfo> {
+    match poll(query.clock().as_ref(), timeout, || query.children()) {
+        Ok(children) => children,
+        Err(failure) => panic!("{}", failure.describe("children", query, query.get_pid())),
+    }
+}
+
+/// Assert that `processes` are exactly those `expected`, as [crate::expectations::check_matches] checks.
+///
+/// # Panics
+///
+/// If they don't match, with each expectation which wasn't met and each process which wasn't expected.
+#[cfg(feature = "proc")]
+pub fn assert_matches(
+    processes: &[crate::ProcInfo],
+    expected: &[crate::expectations::ProcInfoExpectation],
+) {
+    if let Err(e) = crate::expectations::check_matches(processes, expected) {
+        panic!("{}", e);
+    }
+}
+
+struct Failure {
+    last_error: crate::ProcCtlError,
+    attempts: usize,
+    elapsed: Duration,
+}
+
+fn poll<T>(
+    clock: &dyn crate::Clock,
+    timeout: Duration,
+    mut f: impl FnMut() -> crate::ProcCtlResult<T>,
+) -> Result<T, Failure> {
+    let deadline = crate::time::Deadline::start(clock, timeout);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if deadline.remaining(clock) < POLL_INTERVAL => {
+                return Err(Failure {
+                    last_error: e,
+                    attempts,
+                    elapsed: deadline.elapsed(clock),
+                })
+            }
+            Err(_) => clock.sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+impl Failure {
+    fn describe(&self, kind: &str, query: &impl Debug, pid: Option<crate::Pid>) -> String {
+        let mut msg = format!(
+            "{} assertion failed after {} attempt(s) in {:.2?}\n",
+            kind, self.attempts, self.elapsed
+        );
+        msg.push_str(&format!("  query: {:?}\n", query));
+        msg.push_str(&format!(
+            "  last result: {} ({:?})\n",
+            self.last_error, self.last_error
+        ));
+        if let Some(pid) = pid {
+            msg.push_str(&format!("  process: {}\n", describe_process(pid)));
+        }
+
+        msg
+    }
+}
+
+#[cfg(feature = "proc")]
+fn describe_process(pid: crate::Pid) -> String {
+    match crate::ProcQuery::new().process_id(pid).list_processes() {
+        Ok(processes) => match processes.first() {
+            Some(info) => format!(
+                "{} (pid {}, parent {}) running {:?}",
+                info.name,
+                info.pid,
+                info.parent
+                    .map(|p| p.to_string())
+                    .unwrap_or_else(|| "none".to_string()),
+                info.cmd.join(" ")
+            ),
+            None => format!("pid {} was not found, it may have exited", pid),
+        },
+        Err(e) => format!("pid {} could not be inspected: {}", pid, e),
+    }
+}
+
+#[cfg(not(feature = "proc"))]
+fn describe_process(pid: crate::Pid) -> String {
+    format!(
+        "pid {} (enable the `proc` feature for process details)",
+        pid
+    )
+}
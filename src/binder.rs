@@ -0,0 +1,205 @@
+//! A sample program which binds a configurable set of ports, for testing queries against.
+//!
+//! The binder runs as its own process so that queries see only the ports it was asked to bind. To use it from another
+//! crate, add a binary which calls [run] with the configuration parsed from its arguments:
+//!
+//! ```rust no_run
+//! fn main() {
+//!     let config = proc_ctl::binder::BinderConfig::from_args(std::env::args().skip(1)).unwrap();
+//!     proc_ctl::binder::run(&config).unwrap();
+//! }
+//! ```
+//!
+//! Then start it from a test with [MultiPortBinder::spawn], passing a [std::process::Command] for that binary.
+
+use crate::ProtocolPort;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// The line printed once every socket has been bound
+const READY: &str = "ready";
+
+/// How many sockets of each kind to bind. Every socket is bound to an ephemeral port on a loopback address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BinderConfig {
+    /// TCP listeners on `127.0.0.1`
+    pub tcp4: usize,
+    /// TCP listeners on `::1`
+    pub tcp6: usize,
+    /// TCP listeners on `::`, which also accept IPv4 connections on platforms where IPv6 sockets are dual-stack by
+    /// default, such as Linux
+    pub tcp_dual: usize,
+    /// UDP sockets on `127.0.0.1`
+    pub udp4: usize,
+    /// UDP sockets on `::1`
+    pub udp6: usize,
+}
+
+impl BinderConfig {
+    /// Parse the configuration from arguments of the form `tcp4=2`, as produced by [BinderConfig::to_args]
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = BinderConfig::default();
+        for arg in args {
+            let (kind, count) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("expected kind=count but got {}", arg))?;
+            let count = count
+                .parse()
+                .map_err(|_| format!("invalid count in {}", arg))?;
+
+            match kind {
+                "tcp4" => config.tcp4 = count,
+                "tcp6" => config.tcp6 = count,
+                "tcp-dual" => config.tcp_dual = count,
+                "udp4" => config.udp4 = count,
+                "udp6" => config.udp6 = count,
+                _ => return Err(format!("unknown socket kind {}", kind)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Format the configuration as arguments for the binder
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            format!("tcp4={}", self.tcp4),
+            format!("tcp6={}", self.tcp6),
+            format!("tcp-dual={}", self.tcp_dual),
+            format!("udp4={}", self.udp4),
+            format!("udp6={}", self.udp6),
+        ]
+    }
+}
+
+/// Bind the configured sockets, print one line per socket followed by `ready`, then hold the sockets until stdin is
+/// closed.
+///
+/// Each socket is printed as its kind and address, such as `tcp4 127.0.0.1:41231`.
+pub fn run(config: &BinderConfig) -> std::io::Result<()> {
+    let mut tcp = Vec::new();
+    let mut udp = Vec::new();
+    let mut out = std::io::stdout().lock();
+
+    for (kind, count, addr) in [
+        ("tcp4", config.tcp4, "127.0.0.1:0"),
+        ("tcp6", config.tcp6, "[::1]:0"),
+        ("tcp-dual", config.tcp_dual, "[::]:0"),
+    ] {
+        for _ in 0..count {
+            let listener = TcpListener::bind(addr)?;
+            writeln!(out, "{} {}", kind, listener.local_addr()?)?;
+            tcp.push(listener);
+        }
+    }
+    for (kind, count, addr) in [
+        ("udp4", config.udp4, "127.0.0.1:0"),
+        ("udp6", config.udp6, "[::1]:0"),
+    ] {
+        for _ in 0..count {
+            let socket = UdpSocket::bind(addr)?;
+            writeln!(out, "{} {}", kind, socket.local_addr()?)?;
+            udp.push(socket);
+        }
+    }
+
+    writeln!(out, "{}", READY)?;
+    out.flush()?;
+    drop(out);
+
+    std::io::stdin().read_to_end(&mut Vec::new())?;
+
+    Ok(())
+}
+
+/// A running binder process, which is stopped when this is dropped
+#[derive(Debug)]
+pub struct MultiPortBinder {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    bound: Vec<(String, SocketAddr)>,
+}
+
+impl MultiPortBinder {
+    /// Start a binder and wait until all of its sockets are bound
+    ///
+    /// `command` must run a program which calls [run], such as the `multi-port-binder` sample in this repository.
+    /// The configuration is added to its arguments.
+    pub fn spawn(mut command: Command, config: &BinderConfig) -> std::io::Result<Self> {
+        let mut child = command
+            .args(config.to_args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut bound = Vec::new();
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            if line == READY {
+                return Ok(MultiPortBinder {
+                    child,
+                    stdin,
+                    bound,
+                });
+            }
+
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(kind, addr)| Some((kind.to_string(), addr.parse().ok()?)));
+            match parsed {
+                Some(socket) => bound.push(socket),
+                None => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unexpected output from binder: {}", line),
+                    ));
+                }
+            }
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "binder exited before binding its sockets",
+        ))
+    }
+
+    /// The process ID of the binder
+    pub fn pid(&self) -> crate::Pid {
+        self.child.id()
+    }
+
+    /// The kind, such as `tcp4`, and local address of every socket that was bound
+    pub fn bound(&self) -> &[(String, SocketAddr)] {
+        &self.bound
+    }
+
+    /// The ports that were bound, in the order they were bound
+    pub fn ports(&self) -> Vec<ProtocolPort> {
+        self.bound
+            .iter()
+            .map(|(kind, addr)| {
+                if kind.starts_with("udp") {
+                    ProtocolPort::Udp(addr.port())
+                } else {
+                    ProtocolPort::Tcp(addr.port())
+                }
+            })
+            .collect()
+    }
+}
+
+impl Drop for MultiPortBinder {
+    fn drop(&mut self) {
+        // Closing stdin asks the binder to exit, killing it makes sure it does
+        drop(self.stdin.take());
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
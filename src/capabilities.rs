@@ -0,0 +1,255 @@
+//! The capabilities of a process, read from `/proc/<pid>/status` on Linux.
+//!
+//! The status file lists each capability set as a 64 bit hex mask, such as `CapEff: 00000000a80425fb`, where bit `n`
+//! is the capability numbered `n` by the kernel. Masks from a kernel newer than the table here may have bits set which
+//! have no known name, and those are named by their number, as `CAP_41`.
+
+use crate::types::Pid;
+
+/// The names of the capabilities, indexed by their bit in a capability mask, as of Linux 5.9
+const CAPABILITY_NAMES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// The capabilities of a process on Linux, see [crate::ProcInfo::capabilities]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The effective set, which is what the kernel checks when the process does something privileged, as a mask with
+    /// one bit for each capability
+    pub effective: u64,
+    /// The permitted set, which limits what the process can add to its effective set, as a mask with one bit for
+    /// each capability
+    pub permitted: u64,
+    /// The names of the capabilities in the effective set, such as `CAP_NET_BIND_SERVICE`, in the order of their bits
+    pub names: Vec<String>,
+}
+
+impl Capabilities {
+    /// Create the capabilities of a process from its effective and permitted masks, for use as a test fixture
+    pub fn new(effective: u64, permitted: u64) -> Self {
+        Capabilities {
+            effective,
+            permitted,
+            names: names(effective),
+        }
+    }
+
+    /// The names of the capabilities in the permitted set, in the order of their bits
+    pub fn permitted_names(&self) -> Vec<String> {
+        names(self.permitted)
+    }
+
+    /// Whether the capability called `name` is in the effective set. The name is not case sensitive and the `CAP_`
+    /// prefix is optional, so `net_bind_service` finds `CAP_NET_BIND_SERVICE`. A name which is not known never matches.
+    pub fn has_effective(&self, name: &str) -> bool {
+        bit(name).is_some_and(|bit| self.effective & (1 << bit) != 0)
+    }
+}
+
+/// The names of the capabilities in `mask`
+fn names(mask: u64) -> Vec<String> {
+    (0..64)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .map(|bit| match CAPABILITY_NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("CAP_{}", bit),
+        })
+        .collect()
+}
+
+/// The bit of the capability called `name`, accepting the names returned by [names]
+fn bit(name: &str) -> Option<usize> {
+    let name = name.to_ascii_uppercase();
+    let name = match name.strip_prefix("CAP_") {
+        Some(_) => name,
+        None => format!("CAP_{}", name),
+    };
+
+    CAPABILITY_NAMES
+        .iter()
+        .position(|known| *known == name)
+        .or_else(|| {
+            name.strip_prefix("CAP_")?
+                .parse()
+                .ok()
+                .filter(|bit| *bit < 64)
+        })
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn read(pid: Pid) -> Option<Capabilities> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_status(&status)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read(_pid: Pid) -> Option<Capabilities> {
+    None
+}
+
+/// Read the `CapEff` and `CapPrm` lines from the contents of `/proc/<pid>/status`
+#[cfg(any(target_os = "linux", test))]
+fn parse_status(status: &str) -> Option<Capabilities> {
+    let mask = |key: &str| {
+        status.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix(':')?;
+            u64::from_str_radix(value.trim(), 16).ok()
+        })
+    };
+
+    Some(Capabilities::new(mask("CapEff")?, mask("CapPrm")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!("../tests/fixtures/status/", $name, ".txt"))
+        };
+    }
+
+    #[test]
+    fn root_has_every_known_capability() {
+        let caps = parse_status(fixture!("root")).unwrap();
+
+        assert_eq!(0x1ff_ffff_ffff, caps.effective);
+        assert_eq!(CAPABILITY_NAMES, caps.names.as_slice());
+        assert_eq!(caps.names, caps.permitted_names());
+    }
+
+    #[test]
+    fn docker_default_capabilities() {
+        let caps = parse_status(fixture!("docker")).unwrap();
+
+        assert_eq!(
+            vec![
+                "CAP_CHOWN",
+                "CAP_DAC_OVERRIDE",
+                "CAP_FOWNER",
+                "CAP_FSETID",
+                "CAP_KILL",
+                "CAP_SETGID",
+                "CAP_SETUID",
+                "CAP_SETPCAP",
+                "CAP_NET_BIND_SERVICE",
+                "CAP_NET_RAW",
+                "CAP_SYS_CHROOT",
+                "CAP_MKNOD",
+                "CAP_AUDIT_WRITE",
+                "CAP_SETFCAP",
+            ],
+            caps.names
+        );
+        assert!(caps.has_effective("CAP_NET_BIND_SERVICE"));
+        assert!(!caps.has_effective("CAP_NET_ADMIN"));
+    }
+
+    #[test]
+    fn unprivileged_process_has_none() {
+        let caps = parse_status(fixture!("unprivileged")).unwrap();
+
+        assert_eq!(Capabilities::new(0, 0), caps);
+        assert!(!caps.has_effective("CAP_NET_BIND_SERVICE"));
+    }
+
+    #[test]
+    fn effective_and_permitted_are_read_separately() {
+        let granted = parse_status(fixture!("net_bind_service")).unwrap();
+        assert_eq!(vec!["CAP_NET_BIND_SERVICE"], granted.names);
+        assert!(granted.has_effective("net_bind_service"));
+
+        // A process which dropped its effective set but can raise it again
+        let dropped = parse_status(fixture!("dropped_effective")).unwrap();
+        assert!(dropped.names.is_empty());
+        assert_eq!(
+            vec!["CAP_NET_BIND_SERVICE", "CAP_NET_ADMIN", "CAP_NET_RAW"],
+            dropped.permitted_names()
+        );
+    }
+
+    #[test]
+    fn capabilities_newer_than_the_table_are_numbered() {
+        let caps = parse_status(fixture!("newer_kernel")).unwrap();
+
+        assert_eq!(
+            Some("CAP_CHECKPOINT_RESTORE"),
+            caps.names.get(40).map(String::as_str)
+        );
+        assert_eq!(&["CAP_41", "CAP_42"], &caps.names[41..]);
+        assert!(caps.has_effective("CAP_42"));
+        assert!(!caps.has_effective("CAP_43"));
+    }
+
+    #[test]
+    fn names_are_matched_loosely() {
+        for name in ["CAP_SYS_ADMIN", "cap_sys_admin", "SYS_ADMIN", "sys_admin"] {
+            assert_eq!(Some(21), bit(name), "{}", name);
+        }
+        assert_eq!(None, bit("CAP_NOT_A_CAPABILITY"));
+        assert_eq!(None, bit("CAP_64"));
+    }
+
+    #[test]
+    fn status_without_capabilities() {
+        assert_eq!(None, parse_status("Name:\tserver\nPid:\t1\n"));
+        assert_eq!(None, parse_status("CapEff:\tnot hex\nCapPrm:\t0\n"));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parser_never_panics(status in "\\PC{0,200}") {
+            let _ = parse_status(&status);
+        }
+
+        #[test]
+        fn names_round_trip_to_bits(mask in proptest::num::u64::ANY) {
+            let caps = Capabilities::new(mask, 0);
+            let from_names = caps.names.iter().map(|name| bit(name).unwrap()).fold(0, |m, bit| m | 1 << bit);
+            proptest::prop_assert_eq!(mask, from_names);
+        }
+    }
+}
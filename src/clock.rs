@@ -0,0 +1,171 @@
+//! The source of time for retries, waits and watchers.
+//!
+//! Queries use the [SystemClock] unless another clock is given with `with_clock`, such as
+//! [crate::PortQuery::with_clock]. Tests can use a [ManualClock] instead, so that code which retries or times out runs
+//! without really sleeping.
+
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+/// A future returned by [Clock::sleep_async]
+#[cfg(feature = "async")]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>>;
+
+/// Tells the time and waits for it to pass
+///
+/// Queries keep their clock, so it must be safe to share between threads and across a panic like the rest of a query.
+pub trait Clock:
+    std::fmt::Debug + Send + Sync + std::panic::RefUnwindSafe + std::panic::UnwindSafe
+{
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// Block the current thread until `duration` has passed
+    fn sleep(&self, duration: Duration);
+
+    /// Wait until `duration` has passed without blocking the async runtime.
+    ///
+    /// By default this is a tokio sleep, so clocks which don't follow real time must override it.
+    #[cfg(feature = "async")]
+    fn sleep_async(&self, duration: Duration) -> SleepFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// The real time, as used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// A clock which only moves when it is told to, for testing code which retries or times out.
+///
+/// Sleeping on this clock returns straight away, after moving the clock forward by the time slept, so code under test
+/// runs as fast as it can while seeing the passage of time it expects. Time can also be moved forward with
+/// [ManualClock::advance]. Clones share the same time, so keep a clone to inspect the clock after giving it to a query.
+///
+/// ```rust
+/// # #[cfg(all(feature = "test-util", feature = "resilience"))]
+/// # fn main() {
+/// use proc_ctl::{ManualClock, PortQuery};
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::new();
+/// let query = PortQuery::new()
+///     .process_id(std::process::id())
+///     .expect_min_num_ports(1)
+///     .with_clock(clock.clone());
+///
+/// assert!(query.execute_with_retry_sync(Duration::from_secs(1), 3).is_err());
+///
+/// // Each failed attempt but the last slept for a second, without the test waiting for it
+/// assert_eq!(vec![Duration::from_secs(1); 2], clock.sleeps());
+/// # }
+/// # #[cfg(not(all(feature = "test-util", feature = "resilience")))]
+/// # fn main() {}
+/// ```
+#[cfg(any(feature = "test-util", test))]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: std::sync::Arc<std::sync::Mutex<ManualState>>,
+}
+
+#[cfg(any(feature = "test-util", test))]
+#[derive(Debug)]
+struct ManualState {
+    now: Instant,
+    sleeps: Vec<Duration>,
+}
+
+#[cfg(any(feature = "test-util", test))]
+impl ManualClock {
+    /// Create a clock which starts at the current time
+    pub fn new() -> Self {
+        ManualClock {
+            state: std::sync::Arc::new(std::sync::Mutex::new(ManualState {
+                now: Instant::now(),
+                sleeps: Vec::new(),
+            })),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        self.lock().now += duration;
+    }
+
+    /// Every sleep made on this clock, oldest first
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<ManualState> {
+        // Every change leaves the state valid, so a poisoned lock can be used as it is
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record_sleep(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.now += duration;
+        state.sleeps.push(duration);
+    }
+}
+
+#[cfg(any(feature = "test-util", test))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(feature = "test-util", test))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.record_sleep(duration);
+    }
+
+    #[cfg(feature = "async")]
+    fn sleep_async(&self, duration: Duration) -> SleepFuture {
+        let clock = self.clone();
+        Box::pin(async move { clock.record_sleep(duration) })
+    }
+}
+
+/// The clock used by queries which have not been given one
+pub(crate) fn system() -> std::sync::Arc<dyn Clock> {
+    std::sync::Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(start, clock.now());
+
+        clock.advance(Duration::from_secs(5));
+        clock.clone().sleep(Duration::from_secs(2));
+
+        assert_eq!(Duration::from_secs(7), clock.now() - start);
+        assert_eq!(vec![Duration::from_secs(2)], clock.sleeps());
+    }
+}
@@ -0,0 +1,211 @@
+//! Query definitions which can be loaded from configuration, such as a TOML file of readiness checks.
+//!
+//! A config holds everything about a query which can be written down as data. Closures, clocks, pidfds and child
+//! handles can't be, so they are set on the query after it is created with [crate::PortQuery::from_config] or
+//! [crate::ProcQuery::from_config]. Unknown fields are rejected when a config is deserialized, so that a misspelt
+//! expectation fails loudly instead of being ignored.
+
+use crate::port_query::MultipleMatchPolicy;
+#[cfg(feature = "proc")]
+use crate::proc_query::MatchField;
+use crate::types::{AddressFamily, Pid, Port, ProtocolPort, TcpState};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// A transport protocol to restrict a query to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Only TCP ports
+    Tcp,
+    /// Only UDP ports
+    Udp,
+}
+
+/// How often to retry a query from config until it succeeds.
+///
+/// A query does not keep its retry policy, so pass these to a function such as
+/// [crate::PortQuery::execute_with_retry_sync].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// The delay between attempts, in milliseconds
+    pub delay_ms: u64,
+    /// The most attempts to make, including the first
+    pub attempts: usize,
+}
+
+impl RetryConfig {
+    /// Create a retry policy
+    pub fn new(delay: Duration, attempts: usize) -> Self {
+        RetryConfig {
+            delay_ms: u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+            attempts,
+        }
+    }
+
+    /// The delay between attempts
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms)
+    }
+}
+
+/// The definition of a [crate::PortQuery], see [crate::PortQuery::from_config]
+///
+/// Every field is optional, and a field which is left out keeps the default of [crate::PortQuery::new]. Fields for
+/// builder functions which are not available in the current build, such as `process_name` on Windows without the
+/// `proc` feature, are rejected as unknown.
+///
+/// ```rust
+/// use proc_ctl::{PortQuery, PortQueryConfig};
+///
+/// let config: PortQueryConfig = serde_json::from_str(
+///     r#"{"process_id": 1234, "protocol": "tcp", "forbid_ports_except": [{"protocol": "tcp", "port": 8080}]}"#,
+/// )
+/// .unwrap();
+///
+/// let query = PortQuery::from_config(&config);
+/// assert_eq!(config, query.to_config());
+///
+/// assert!(serde_json::from_str::<PortQueryConfig>(r#"{"process_idd": 1234}"#).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct PortQueryConfig {
+    /// See [crate::PortQuery::process_id]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_id: Option<Pid>,
+    /// See [crate::PortQuery::process_ids]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_ids: Option<Vec<Pid>>,
+    /// See [crate::PortQuery::process_name]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_name: Option<String>,
+    /// See [crate::PortQuery::include_children]
+    pub include_children: bool,
+    /// See [crate::PortQuery::on_multiple_matches]
+    pub on_multiple_matches: MultipleMatchPolicy,
+    /// Only consider one protocol, see [crate::PortQuery::tcp_only] and [crate::PortQuery::udp_only]. Both are
+    /// considered if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+    /// Only consider one address family, see [crate::PortQuery::ip_v4_only] and [crate::PortQuery::ip_v6_only]. Both
+    /// are considered if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<AddressFamily>,
+    /// See [crate::PortQuery::tcp_states]. Only listeners are considered if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_states: Option<Vec<TcpState>>,
+    /// The lowest port to consider, see [crate::PortQuery::port_range]. Ports from 0 are considered if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_port: Option<Port>,
+    /// The highest port to consider, see [crate::PortQuery::port_range]. Ports up to 65535 are considered if this is
+    /// not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_port: Option<Port>,
+    /// Set to `false` to leave out ports bound to a loopback address, see [crate::PortQuery::exclude_loopback], or to
+    /// `true` to only consider those, see [crate::PortQuery::loopback_only]. Both are considered if this is not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loopback: Option<bool>,
+    /// See [crate::PortQuery::expect_min_num_ports]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_min_num_ports: Option<usize>,
+    /// See [crate::PortQuery::expect_max_num_ports]. Set this and `expect_min_num_ports` to the same number for
+    /// [crate::PortQuery::expect_exact_num_ports].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_max_num_ports: Option<usize>,
+    /// Ports which must be found, see [crate::PortQuery::expect_port]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_ports: Option<Vec<ProtocolPort>>,
+    /// See [crate::PortQuery::expect_backlog_at_least]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_backlog_at_least: Option<u32>,
+    /// See [crate::PortQuery::forbid_ports_except]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forbid_ports_except: Option<Vec<ProtocolPort>>,
+    /// See [crate::PortQuery::verify_accepting]
+    pub verify_accepting: bool,
+    /// See [crate::PortQuery::expect_accepting]. This also enables [PortQueryConfig::verify_accepting].
+    pub expect_accepting: bool,
+    /// See [crate::PortQuery::probe_address]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_address: Option<IpAddr>,
+    /// See [crate::PortQuery::include_system_owned]
+    pub include_system_owned: bool,
+    /// See [crate::PortQuery::split_families]
+    pub split_families: bool,
+    /// See [crate::PortQuery::sorted]
+    pub sorted: bool,
+    /// See [crate::PortQuery::max_tool_concurrency]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tool_concurrency: Option<usize>,
+    /// The time each execution of the query can take, in milliseconds, see [crate::PortQuery::timeout]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// How to retry the query, which is not kept by the query and is left out by [crate::PortQuery::to_config]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+}
+
+/// The definition of a [crate::ProcQuery], see [crate::ProcQuery::from_config]
+///
+/// Every field is optional, and a field which is left out keeps the default of [crate::ProcQuery::new].
+#[cfg(feature = "proc")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ProcQueryConfig {
+    /// See [crate::ProcQuery::process_id]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_id: Option<Pid>,
+    /// See [crate::ProcQuery::process_name]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_name: Option<String>,
+    /// See [crate::ProcQuery::parent_name]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+    /// See [crate::ProcQuery::match_on]
+    pub match_on: MatchField,
+    /// See [crate::ProcQuery::match_canonical_names]
+    pub match_canonical_names: bool,
+    /// See [crate::ProcQuery::refresh_cmd]
+    pub refresh_cmd: bool,
+    /// See [crate::ProcQuery::keep_empty_args]
+    pub keep_empty_args: bool,
+    /// See [crate::ProcQuery::explain]
+    pub explain: bool,
+    /// See [crate::ProcQuery::with_env]. The environment is collected unless this is `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub with_env: Option<bool>,
+    /// See [crate::ProcQuery::with_namespaces]
+    pub with_namespaces: bool,
+    /// See [crate::ProcQuery::in_container]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_container: Option<bool>,
+    /// See [crate::ProcQuery::container_id_prefix]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_id_prefix: Option<String>,
+    /// See [crate::ProcQuery::with_capabilities]
+    pub with_capabilities: bool,
+    /// See [crate::ProcQuery::has_capability], with one entry for each capability required
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub has_capability: Vec<String>,
+    /// See [crate::ProcQuery::with_limits]
+    pub with_limits: bool,
+    /// See [crate::ProcQuery::nofile_at_least]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nofile_at_least: Option<u64>,
+    /// See [crate::ProcQuery::expect_nofile_at_least]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_nofile_at_least: Option<u64>,
+    /// See [crate::ProcQuery::paths_relative_to_proc_root]
+    pub paths_relative_to_proc_root: bool,
+    /// See [crate::ProcQuery::expect_min_num_children]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_min_num_children: Option<usize>,
+    /// How to retry the query, which is not kept by the query and is left out by [crate::ProcQuery::to_config]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+}
@@ -0,0 +1,447 @@
+//! Listing the TCP connections of a process, see [ConnectionQuery].
+//!
+//! A [crate::PortQuery] finds the ports a process listens on. This finds the connections it has made or accepted,
+//! with the address of the peer, so that a test can check that a process has connected to what it should have.
+
+use crate::clock::Clock;
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::{AddressFamily, Connection, Pid, Port, TcpState};
+use std::process::Child;
+use std::sync::Arc;
+
+/// Find the TCP connections of a process.
+///
+/// Listening sockets are left out, use a [crate::PortQuery] for those. A connection which the process has closed no
+/// longer belongs to it, so one left in [TcpState::TimeWait] after the process closed it is not found.
+///
+/// ```rust
+/// use proc_ctl::{ConnectionQuery, TcpState};
+/// use std::net::{TcpListener, TcpStream};
+///
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+///
+/// let connections = ConnectionQuery::new()
+///     .process_id(std::process::id())
+///     .remote_port(listener.local_addr().unwrap().port())
+///     .expect_min_num_connections(1)
+///     .execute()
+///     .unwrap();
+/// assert_eq!(stream.local_addr().unwrap(), connections[0].local_addr);
+/// assert_eq!(TcpState::Established, connections[0].state);
+/// ```
+#[derive(Debug)]
+pub struct ConnectionQuery {
+    process_id: Option<Pid>,
+    ipv4_addresses: bool,
+    ipv6_addresses: bool,
+    established_only: bool,
+    remote_port: Option<Port>,
+    min_num_connections: Option<usize>,
+    #[cfg_attr(not(any(feature = "resilience", feature = "async")), allow(dead_code))]
+    clock: Arc<dyn Clock>,
+}
+
+impl ConnectionQuery {
+    /// Create a new query
+    pub fn new() -> Self {
+        ConnectionQuery {
+            process_id: None,
+            ipv4_addresses: true,
+            ipv6_addresses: true,
+            established_only: false,
+            remote_port: None,
+            min_num_connections: None,
+            clock: crate::clock::system(),
+        }
+    }
+
+    /// Set the process ID to match
+    ///
+    /// Either this function or `process_id_from_child` are required to be called before the query is usable.
+    pub fn process_id(mut self, pid: Pid) -> Self {
+        self.process_id = Some(pid);
+        self
+    }
+
+    /// Get the process ID of a child process
+    ///
+    /// Either this function or `process_id` are required to be called before the query is usable.
+    pub fn process_id_from_child(self, child: &Child) -> Self {
+        self.process_id(child.id())
+    }
+
+    /// Only find IPv4 connections
+    pub fn ip_v4_only(mut self) -> Self {
+        self.ipv4_addresses = true;
+        self.ipv6_addresses = false;
+        self
+    }
+
+    /// Only find IPv6 connections
+    pub fn ip_v6_only(mut self) -> Self {
+        self.ipv4_addresses = false;
+        self.ipv6_addresses = true;
+        self
+    }
+
+    /// Only find connections which are open, leaving out those still being opened or being closed
+    pub fn established_only(mut self) -> Self {
+        self.established_only = true;
+        self
+    }
+
+    /// Only find connections to a peer on `port`, such as those the process has made to a server listening on it
+    pub fn remote_port(mut self, port: Port) -> Self {
+        self.remote_port = Some(port);
+        self
+    }
+
+    /// Expect at least `num` connections, failing with [ProcCtlError::TooFewConnections] if there are fewer
+    pub fn expect_min_num_connections(mut self, num: usize) -> Self {
+        self.min_num_connections = Some(num);
+        self
+    }
+
+    /// Use `clock` rather than the real time for the delays of retries started from this query, such as a
+    /// [crate::ManualClock] in tests. The query itself always runs against the real system.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Execute the query, returning the connections sorted by local and then remote address
+    pub fn execute(&self) -> ProcCtlResult<Vec<Connection>> {
+        self.validate()?;
+
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        {
+            let pid = crate::common::resolve_pid(self)?;
+            let mut connections = list_connections(self, pid)?
+                .into_iter()
+                .filter(|c| match c.family() {
+                    AddressFamily::Ipv4 => self.ipv4_addresses,
+                    AddressFamily::Ipv6 => self.ipv6_addresses,
+                })
+                .filter(|c| !self.established_only || c.state == TcpState::Established)
+                .filter(|c| {
+                    self.remote_port
+                        .map_or(true, |port| c.remote_addr.port() == port)
+                })
+                .collect::<Vec<_>>();
+            connections.sort_by_key(|c| (c.local_addr, c.remote_addr));
+
+            if let Some(num) = self.min_num_connections {
+                if connections.len() < num {
+                    return Err(ProcCtlError::TooFewConnections(connections, num));
+                }
+            }
+
+            Ok(connections)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        Err(ProcCtlError::UnsupportedPlatform(
+            "listing connections is only supported on Linux, Windows and macOS".to_string(),
+        ))
+    }
+
+    /// Execute the query until it succeeds, making at most `count` attempts with `delay` between them
+    #[cfg(feature = "resilience")]
+    pub fn execute_with_retry_sync(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<Connection>> {
+        crate::retrying::retry_sync(self.clock.as_ref(), delay, count, || self.execute())
+    }
+
+    /// Async equivalent of `execute_with_retry_sync`, with the same number of attempts
+    #[cfg(feature = "async")]
+    pub async fn execute_with_retry(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<Connection>> {
+        crate::retrying::retry_async(self.clock.as_ref(), delay, count, || self.execute()).await
+    }
+
+    /// Reject configurations which could never find a connection
+    fn validate(&self) -> ProcCtlResult<()> {
+        if !self.ipv4_addresses && !self.ipv6_addresses {
+            return Err(ProcCtlError::ConfigurationError(
+                "both IPv4 and IPv6 are excluded, so no connections can match".to_string(),
+            ));
+        }
+        if self.process_id == Some(0) {
+            return Err(ProcCtlError::ConfigurationError(
+                "pid 0 is not a process, so it never has any connections".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+impl crate::common::MaybeHasPid for ConnectionQuery {
+    fn get_pid(&self) -> Option<Pid> {
+        self.process_id
+    }
+}
+
+impl Default for ConnectionQuery {
+    fn default() -> Self {
+        ConnectionQuery::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn list_connections(query: &ConnectionQuery, pid: Pid) -> ProcCtlResult<Vec<Connection>> {
+    use crate::port_query::{classify_proc_error, linux_tcp_state};
+
+    let proc = procfs::process::Process::new(crate::pid::to_procfs(pid)?)
+        .map_err(|e| classify_proc_error(pid, e))?;
+    let inodes = proc
+        .fd()
+        .map_err(|e| classify_proc_error(pid, e))?
+        .flatten()
+        .filter_map(|fd| match fd.target {
+            procfs::process::FDTarget::Socket(inode) => Some(inode),
+            _ => None,
+        })
+        .collect::<std::collections::HashSet<_>>();
+
+    let mut entries = Vec::new();
+    if query.ipv4_addresses {
+        entries.extend(proc.tcp()?);
+    }
+    if query.ipv6_addresses {
+        entries.extend(proc.tcp6()?);
+    }
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| inodes.contains(&entry.inode))
+        .map(|entry| Connection {
+            pid,
+            local_addr: entry.local_address,
+            remote_addr: entry.remote_address,
+            state: linux_tcp_state(&entry.state),
+        })
+        .filter(|c| c.state != TcpState::Listen)
+        .collect())
+}
+
+#[cfg(target_os = "windows")]
+fn list_connections(query: &ConnectionQuery, pid: Pid) -> ProcCtlResult<Vec<Connection>> {
+    use crate::port_query::{
+        load_tcp_table, owner_matches, table_rows, windows_port, windows_tcp_state,
+        windows_v4_addr, windows_v6_addr,
+    };
+    use std::mem::offset_of;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        MIB_TCP6ROW_OWNER_PID, MIB_TCP6TABLE_OWNER_PID, MIB_TCPROW_OWNER_PID,
+        MIB_TCPTABLE_OWNER_PID,
+    };
+    use windows::Win32::Networking::WinSock::{AF_INET, AF_INET6};
+
+    let mut out = Vec::new();
+
+    // SAFETY for each call to table_rows: the rows only contain integers and byte arrays, so any bytes are a valid row
+    if query.ipv4_addresses {
+        let table = load_tcp_table(AF_INET)?;
+        let rows: Vec<MIB_TCPROW_OWNER_PID> =
+            unsafe { table_rows(&table, offset_of!(MIB_TCPTABLE_OWNER_PID, table))? };
+
+        for row in rows {
+            let Some(state) = connection_state(windows_tcp_state(row.dwState)) else {
+                continue;
+            };
+            if owner_matches(row.dwOwningPid, pid, false) {
+                out.push(Connection {
+                    pid,
+                    local_addr: windows_v4_addr(row.dwLocalAddr, windows_port(row.dwLocalPort)),
+                    remote_addr: windows_v4_addr(row.dwRemoteAddr, windows_port(row.dwRemotePort)),
+                    state,
+                });
+            }
+        }
+    }
+    if query.ipv6_addresses {
+        let table = load_tcp_table(AF_INET6)?;
+        let rows: Vec<MIB_TCP6ROW_OWNER_PID> =
+            unsafe { table_rows(&table, offset_of!(MIB_TCP6TABLE_OWNER_PID, table))? };
+
+        for row in rows {
+            let Some(state) = connection_state(windows_tcp_state(row.dwState)) else {
+                continue;
+            };
+            if owner_matches(row.dwOwningPid, pid, false) {
+                out.push(Connection {
+                    pid,
+                    local_addr: windows_v6_addr(
+                        row.ucLocalAddr,
+                        row.dwLocalScopeId,
+                        windows_port(row.dwLocalPort),
+                    ),
+                    remote_addr: windows_v6_addr(
+                        row.ucRemoteAddr,
+                        row.dwRemoteScopeId,
+                        windows_port(row.dwRemotePort),
+                    ),
+                    state,
+                });
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// The state of a socket which is a connection, or `None` for a listener
+#[cfg(any(target_os = "windows", target_os = "macos", test))]
+fn connection_state(state: Option<TcpState>) -> Option<TcpState> {
+    state.filter(|state| *state != TcpState::Listen)
+}
+
+#[cfg(target_os = "macos")]
+fn list_connections(_query: &ConnectionQuery, pid: Pid) -> ProcCtlResult<Vec<Connection>> {
+    let pid = pid.to_string();
+    let output = crate::tool::output(
+        &crate::tool::SystemToolRunner,
+        "lsof",
+        &["-a", "-p", &pid, "-iTCP", "-sTCP:^LISTEN", "-nP", "-F0ptnT"],
+        crate::port_query::DEFAULT_MAX_TOOL_CONCURRENCY,
+    )
+    .map_err(|e| ProcCtlError::from_restricted_io("running lsof", e))?;
+
+    // lsof exits with an error when it finds nothing, so a failure only matters if it was denied access
+    if !output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains("Operation not permitted")
+    {
+        return Err(ProcCtlError::SandboxRestricted(
+            "reading connections with lsof".to_string(),
+        ));
+    }
+
+    Ok(parse_lsof_connections(&output.stdout))
+}
+
+/// Parse the output of `lsof -F0ptnT`.
+///
+/// The fields are as for [crate::PortQuery], with the name of a connected socket being its local address, `->` and
+/// then the remote address. The state follows the name in a `T` field, as `ST=ESTABLISHED`.
+#[cfg(any(target_os = "macos", test))]
+fn parse_lsof_connections(output: &[u8]) -> Vec<Connection> {
+    let mut out = Vec::new();
+
+    let mut pid = None;
+    let mut ipv6 = false;
+    let mut addrs = None;
+    for field in output.split(|b| *b == 0 || *b == b'\n') {
+        let Some((&id, value)) = field.split_first() else {
+            continue;
+        };
+        let value = String::from_utf8_lossy(value);
+
+        match id {
+            b'p' => pid = value.parse::<Pid>().ok(),
+            b'f' => addrs = None,
+            b't' => ipv6 = value == "IPv6",
+            b'n' => {
+                addrs = value.split_once("->").and_then(|(local, remote)| {
+                    Some((lsof_addr(local, ipv6)?, lsof_addr(remote, ipv6)?))
+                })
+            }
+            b'T' => {
+                let state = connection_state(
+                    value
+                        .strip_prefix("ST=")
+                        .and_then(crate::port_query::lsof_tcp_state),
+                );
+                if let (Some(pid), Some(state)) = (pid, state) {
+                    if let Some((local_addr, remote_addr)) = addrs.take() {
+                        out.push(Connection {
+                            pid,
+                            local_addr,
+                            remote_addr,
+                            state,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    out
+}
+
+/// Parse one end of a socket name from lsof, such as `127.0.0.1:8080` or `[::1]:8080`
+#[cfg(any(target_os = "macos", test))]
+fn lsof_addr(name: &str, ipv6: bool) -> Option<std::net::SocketAddr> {
+    let (host, port) = name.rsplit_once(':')?;
+    let port = port.parse::<Port>().ok()?;
+
+    Some(match crate::port_query::lsof_ip(host, ipv6)? {
+        (std::net::IpAddr::V4(ip), _) => (ip, port).into(),
+        (std::net::IpAddr::V6(ip), scope_id) => {
+            std::net::SocketAddrV6::new(ip, port, 0, scope_id).into()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsof_connections_have_both_ends_and_a_state() {
+        let output = b"p100\0\nf5\0tIPv4\0n127.0.0.1:50000->127.0.0.1:8080\0TST=ESTABLISHED\0\n\
+            f6\0tIPv6\0n[::1]:50001->[::1]:8081\0TST=CLOSE_WAIT\0\n\
+            f7\0tIPv4\0n*:9000\0TST=LISTEN\0\n\
+            p200\0\nf8\0tIPv4\0n10.0.0.2:50002->10.0.0.1:443\0TST=SYN_SENT\0\n";
+
+        assert_eq!(
+            vec![
+                Connection {
+                    pid: 100,
+                    local_addr: "127.0.0.1:50000".parse().unwrap(),
+                    remote_addr: "127.0.0.1:8080".parse().unwrap(),
+                    state: TcpState::Established,
+                },
+                Connection {
+                    pid: 100,
+                    local_addr: "[::1]:50001".parse().unwrap(),
+                    remote_addr: "[::1]:8081".parse().unwrap(),
+                    state: TcpState::CloseWait,
+                },
+                Connection {
+                    pid: 200,
+                    local_addr: "10.0.0.2:50002".parse().unwrap(),
+                    remote_addr: "10.0.0.1:443".parse().unwrap(),
+                    state: TcpState::SynSent,
+                },
+            ],
+            parse_lsof_connections(output)
+        );
+    }
+
+    #[test]
+    fn windows_rows_are_decoded() {
+        // 8080 is 0x1f90, stored in network byte order in the low 16 bits of a little endian u32
+        assert_eq!(8080, crate::port_query::windows_port(0x0000_901f));
+        assert_eq!(
+            Some(TcpState::Established),
+            connection_state(crate::port_query::windows_tcp_state(5))
+        );
+        assert_eq!(
+            Some(TcpState::Closed),
+            connection_state(crate::port_query::windows_tcp_state(12))
+        );
+        assert_eq!(
+            None,
+            connection_state(crate::port_query::windows_tcp_state(2))
+        );
+    }
+}
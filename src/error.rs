@@ -1,31 +1,530 @@
-use crate::types::ProtocolPort;
+use crate::types::{Pid, ProtocolPort};
 use thiserror::Error;
 
 /// A result type to return `ProcCtlError`s
 pub type ProcCtlResult<T> = Result<T, ProcCtlError>;
 
 /// Custom error type for proc-ctl
+///
+/// The message of each error starts with its [ProcCtlError::code] in brackets, such as
+/// `[too_few_ports] too few ports, got [] but expected 1`.
 #[derive(Error, Debug)]
 pub enum ProcCtlError {
     /// An error occurred while searching process information
     #[cfg(target_os = "linux")]
-    #[error("process error")]
+    #[error("[process_error] process error")]
     ProcessError(#[from] procfs::ProcError),
 
     /// An error occurred while searching process information
     #[cfg(any(target_os = "windows", target_os = "macos"))]
-    #[error("process error")]
+    #[error("[process_error] process error")]
     ProcessError(String),
 
+    /// The process to query does not exist, it may have exited
+    #[error("[process_not_found] process {0} not found")]
+    ProcessNotFound(Pid),
+
+    /// The process exited before it could be queried
+    #[error("[process_exited] process {0} has exited")]
+    ProcessExited(Pid),
+
+    /// The processes selected by name or identity kept exiting between being found and being queried, even after
+    /// being resolved again, see [crate::PortQuery::re_resolve_attempts]. Every resolution is included.
+    #[error("[resolved_process_exited] {0}")]
+    ResolvedProcessExited(Box<crate::re_resolve::ExitedDuringResolution>),
+
+    /// No running process matched the process being tracked
+    #[error("[no_matching_process] no process matching {0}")]
+    NoMatchingProcess(String),
+
+    /// More than one running process matched the process being tracked
+    #[error("[multiple_matching_processes] multiple processes matched, with pids {0:?}")]
+    MultipleMatchingProcesses(Vec<Pid>),
+
+    /// The environment of a process was asked for, but the query which found the process did not collect it, see
+    /// [crate::ProcQuery::with_env]
+    #[error("[env_not_collected] the environment of process {0} was not collected")]
+    EnvNotCollected(Pid),
+
+    /// The operating system refused access to information about the process
+    #[error("[permission_denied] permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// The operating system denied an operation which the query needs, as happens inside a restricted sandbox such as
+    /// seccomp, the macOS App Sandbox or a Windows AppContainer. The operation which was denied is named.
+    #[error(
+        "[sandbox_restricted] {0} was denied, this process may be running in a restricted sandbox"
+    )]
+    SandboxRestricted(String),
+
+    /// The query is not supported on this platform. Port queries return this outside Linux, Windows and macOS, where
+    /// earlier releases returned an empty list.
+    #[error("[unsupported_platform] unsupported platform: {0}")]
+    UnsupportedPlatform(String),
+
+    /// The query needs a crate feature which is not enabled on this platform, such as `proc` for finding processes by
+    /// name outside Linux. The feature is named.
+    #[error("[unsupported_without_feature] this query needs the `{0}` feature on this platform")]
+    UnsupportedWithoutFeature(&'static str),
+
+    /// An error occurred while writing query results
+    #[error("[io_error] io error")]
+    IoError(#[from] std::io::Error),
+
+    /// A non-blocking query could not run straight away because a shared resource was in use, such as the process
+    /// list. Try again later, or use the blocking equivalent.
+    #[error("[would_block] would block: {0}")]
+    WouldBlock(String),
+
+    /// A query ran for longer than the time it was given with [crate::PortQuery::timeout]. The time it was given and
+    /// what it was doing when it ran out are included.
+    #[error("[timeout] the query did not finish within {0:?}, it was {1}")]
+    Timeout(std::time::Duration, String),
+
     /// The user made an error using the API, a more specific error message will be provided
-    #[error("configuration error {0}")]
+    #[error("[configuration_error] configuration error {0}")]
     ConfigurationError(String),
 
+    /// The local address of a port was not found, so it can't be converted to a [std::net::SocketAddr]
+    #[error("[address_unknown] the local address of {0:?} is not known")]
+    AddressUnknown(ProtocolPort),
+
     /// Fewer ports than expected were found on the matched process
-    #[error("too few ports, got {0:?} but expected {1}")]
+    #[error("[too_few_ports] too few ports, got {0:?} but expected {1}")]
     TooFewPorts(Vec<ProtocolPort>, usize),
 
-    /// Too few children were found on the matched process
-    #[error("too few children, got {0} but expected {1}")]
-    TooFewChildren(usize, usize),
+    /// The same ports kept being found on a process which had settled, so that a retry gave up before it ran out of
+    /// attempts, see [crate::PortQuery::fail_fast_on_stall]. The ports found, the number expected and the number of
+    /// attempts in a row which found them are included.
+    #[error("[stalled_expectation] stuck at {0:?} for {2} attempts but expected {1} ports")]
+    StalledExpectation(Vec<ProtocolPort>, usize, usize),
+
+    /// Ports required by [crate::PortQuery::expect_port] were not found on the matched process. The missing ports and
+    /// the ports which were found are included.
+    #[error("[missing_ports] expected {0:?} but only found {1:?}")]
+    MissingPorts(Vec<ProtocolPort>, Vec<ProtocolPort>),
+
+    /// More ports than expected were found on the matched process, see [crate::PortQuery::expect_max_num_ports] and
+    /// [crate::wait_for_tcp_port]
+    #[error("[too_many_ports] too many ports, got {0:?} but expected {1}")]
+    TooManyPorts(Vec<ProtocolPort>, usize),
+
+    /// Fewer connections than expected were found on the matched process, see
+    /// [crate::ConnectionQuery::expect_min_num_connections]
+    #[error("[too_few_connections] too few connections, got {0:?} but expected {1}")]
+    TooFewConnections(Vec<crate::types::Connection>, usize),
+
+    /// A TCP listener was found with a smaller backlog than expected, or with an unknown backlog
+    #[error("[backlog_too_small] backlog of {0:?} is {1:?} but expected at least {2}")]
+    BacklogTooSmall(ProtocolPort, Option<u32>, u32),
+
+    /// A TCP listener was found which did not accept a test connection
+    #[error("[not_accepting] {0:?} is not accepting connections")]
+    NotAccepting(ProtocolPort),
+
+    /// The processes selected by a query still held a port when [crate::PortQuery::wait_for_release] timed out
+    #[error("[port_not_released] {0:?} is still held by pids {1:?}")]
+    PortNotReleased(ProtocolPort, Vec<Pid>),
+
+    /// A port outside the allowlist set with [crate::PortQuery::forbid_ports_except] was found. The details include
+    /// when it was found and every port found at the same time.
+    #[error("[forbidden_ports] {0}")]
+    ForbiddenPorts(Box<crate::monitor::ForbiddenPorts>),
+
+    /// Too few children were found on the matched process. The details include what the parent and any children
+    /// found were doing, to help explain why.
+    #[cfg(feature = "proc")]
+    #[error("[too_few_children] {0}")]
+    TooFewChildren(Box<crate::proc_query::ChildrenShortfall>),
+
+    /// A matched process had a lower resource limit than [crate::ProcQuery::expect_nofile_at_least] requires
+    #[cfg(feature = "proc")]
+    #[error("[limit_too_low] {0}")]
+    LimitTooLow(Box<crate::limits::LimitShortfall>),
+
+    /// Processes did not match what was expected of them by [crate::expectations::check_matches]. The details include
+    /// each expectation which wasn't met and each process which wasn't expected.
+    #[cfg(feature = "proc")]
+    #[error("[processes_mismatch] {0}")]
+    ProcessesMismatch(Box<crate::expectations::ProcessMismatch>),
+
+    /// One or more stages of a [crate::Pipeline] failed. Every failure is included, along with what was found.
+    #[cfg(all(
+        feature = "proc",
+        any(target_os = "linux", target_os = "windows", target_os = "macos")
+    ))]
+    #[error("[pipeline_failed] {0}")]
+    PipelineFailed(Box<crate::pipeline::PipelineFailure>),
+
+    /// A wait did not reach its condition before the timeout. The history shows what was seen along the way.
+    #[error("[wait_timed_out] timed out after {} attempts in {:?}", .0.attempts, .0.elapsed)]
+    WaitTimedOut(crate::wait::WaitHistory),
+}
+
+/// A broad classification of a [ProcCtlError], for callers which need to react to the kind of failure without
+/// matching on every variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The query ran but its results did not meet an expectation, such as [crate::PortQuery::expect_min_num_ports]
+    ExpectationNotMet,
+    /// The process to query does not exist
+    ProcessNotFound,
+    /// Access to information about the process was denied
+    PermissionDenied,
+    /// The query is not supported on this platform
+    UnsupportedPlatform,
+    /// Any other error
+    Other,
+}
+
+impl ProcCtlError {
+    /// Classify this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            #[cfg(target_os = "linux")]
+            ProcCtlError::ProcessError(e) => match e {
+                procfs::ProcError::NotFound(_) => ErrorKind::ProcessNotFound,
+                procfs::ProcError::PermissionDenied(_) => ErrorKind::PermissionDenied,
+                _ => ErrorKind::Other,
+            },
+            #[cfg(any(target_os = "windows", target_os = "macos"))]
+            ProcCtlError::ProcessError(_) => ErrorKind::Other,
+            ProcCtlError::ProcessNotFound(_)
+            | ProcCtlError::ProcessExited(_)
+            | ProcCtlError::ResolvedProcessExited(_)
+            | ProcCtlError::NoMatchingProcess(_) => ErrorKind::ProcessNotFound,
+            ProcCtlError::PermissionDenied(_) | ProcCtlError::SandboxRestricted(_) => {
+                ErrorKind::PermissionDenied
+            }
+            ProcCtlError::UnsupportedPlatform(_) | ProcCtlError::UnsupportedWithoutFeature(_) => {
+                ErrorKind::UnsupportedPlatform
+            }
+            ProcCtlError::IoError(e) => match e.kind() {
+                std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                std::io::ErrorKind::Unsupported => ErrorKind::UnsupportedPlatform,
+                _ => ErrorKind::Other,
+            },
+            ProcCtlError::ConfigurationError(_)
+            | ProcCtlError::AddressUnknown(_)
+            | ProcCtlError::EnvNotCollected(_)
+            | ProcCtlError::MultipleMatchingProcesses(_)
+            | ProcCtlError::WouldBlock(_)
+            | ProcCtlError::Timeout(_, _) => ErrorKind::Other,
+            ProcCtlError::TooFewPorts(_, _)
+            | ProcCtlError::StalledExpectation(_, _, _)
+            | ProcCtlError::MissingPorts(_, _)
+            | ProcCtlError::TooManyPorts(_, _)
+            | ProcCtlError::TooFewConnections(_, _)
+            | ProcCtlError::BacklogTooSmall(_, _, _)
+            | ProcCtlError::NotAccepting(_)
+            | ProcCtlError::PortNotReleased(_, _)
+            | ProcCtlError::ForbiddenPorts(_) => ErrorKind::ExpectationNotMet,
+            #[cfg(feature = "proc")]
+            ProcCtlError::TooFewChildren(_) => ErrorKind::ExpectationNotMet,
+            #[cfg(feature = "proc")]
+            ProcCtlError::LimitTooLow(_) => ErrorKind::ExpectationNotMet,
+            #[cfg(feature = "proc")]
+            ProcCtlError::ProcessesMismatch(_) => ErrorKind::ExpectationNotMet,
+            // The kind of the first failure, which is the earliest stage to fail
+            #[cfg(all(
+                feature = "proc",
+                any(target_os = "linux", target_os = "windows", target_os = "macos")
+            ))]
+            ProcCtlError::PipelineFailed(failure) => failure
+                .failures
+                .first()
+                .map_or(ErrorKind::ExpectationNotMet, |f| f.error.kind()),
+            ProcCtlError::WaitTimedOut(_) => ErrorKind::ExpectationNotMet,
+        }
+    }
+
+    /// A stable identifier for the variant of this error, such as `too_few_ports`, for aggregating failures by kind
+    /// without matching on their messages. Codes are never changed or reused once released.
+    pub fn code(&self) -> &'static str {
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+            ProcCtlError::ProcessError(_) => "process_error",
+            ProcCtlError::ProcessNotFound(_) => "process_not_found",
+            ProcCtlError::ProcessExited(_) => "process_exited",
+            ProcCtlError::ResolvedProcessExited(_) => "resolved_process_exited",
+            ProcCtlError::NoMatchingProcess(_) => "no_matching_process",
+            ProcCtlError::MultipleMatchingProcesses(_) => "multiple_matching_processes",
+            ProcCtlError::EnvNotCollected(_) => "env_not_collected",
+            ProcCtlError::PermissionDenied(_) => "permission_denied",
+            ProcCtlError::SandboxRestricted(_) => "sandbox_restricted",
+            ProcCtlError::UnsupportedPlatform(_) => "unsupported_platform",
+            ProcCtlError::UnsupportedWithoutFeature(_) => "unsupported_without_feature",
+            ProcCtlError::IoError(_) => "io_error",
+            ProcCtlError::WouldBlock(_) => "would_block",
+            ProcCtlError::Timeout(_, _) => "timeout",
+            ProcCtlError::ConfigurationError(_) => "configuration_error",
+            ProcCtlError::AddressUnknown(_) => "address_unknown",
+            ProcCtlError::TooFewPorts(_, _) => "too_few_ports",
+            ProcCtlError::StalledExpectation(_, _, _) => "stalled_expectation",
+            ProcCtlError::MissingPorts(_, _) => "missing_ports",
+            ProcCtlError::TooManyPorts(_, _) => "too_many_ports",
+            ProcCtlError::TooFewConnections(_, _) => "too_few_connections",
+            ProcCtlError::BacklogTooSmall(_, _, _) => "backlog_too_small",
+            ProcCtlError::NotAccepting(_) => "not_accepting",
+            ProcCtlError::PortNotReleased(_, _) => "port_not_released",
+            ProcCtlError::ForbiddenPorts(_) => "forbidden_ports",
+            #[cfg(feature = "proc")]
+            ProcCtlError::TooFewChildren(_) => "too_few_children",
+            #[cfg(feature = "proc")]
+            ProcCtlError::LimitTooLow(_) => "limit_too_low",
+            #[cfg(feature = "proc")]
+            ProcCtlError::ProcessesMismatch(_) => "processes_mismatch",
+            #[cfg(all(
+                feature = "proc",
+                any(target_os = "linux", target_os = "windows", target_os = "macos")
+            ))]
+            ProcCtlError::PipelineFailed(_) => "pipeline_failed",
+            ProcCtlError::WaitTimedOut(_) => "wait_timed_out",
+        }
+    }
+
+    /// Classify an io error from `operation`. Sandboxes deny operations either outright or by making them look
+    /// unsupported, as seccomp does when it fails a system call with `ENOSYS`, so both are treated as a restriction.
+    pub(crate) fn from_restricted_io(operation: &str, e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::Unsupported => {
+                ProcCtlError::SandboxRestricted(operation.to_string())
+            }
+            _ => ProcCtlError::IoError(e),
+        }
+    }
+}
+
+/// Errors serialize as their [ProcCtlError::code] and message, such as
+/// `{"code":"process_not_found","message":"[process_not_found] process 1234 not found"}`
+#[cfg(feature = "serde")]
+impl serde::Serialize for ProcCtlError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("ProcCtlError", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denied_io_is_a_sandbox_restriction() {
+        for kind in [
+            std::io::ErrorKind::PermissionDenied,
+            std::io::ErrorKind::Unsupported,
+        ] {
+            let err = ProcCtlError::from_restricted_io("running lsof", kind.into());
+            assert!(
+                matches!(&err, ProcCtlError::SandboxRestricted(op) if op == "running lsof"),
+                "{:?}",
+                err
+            );
+            assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        }
+    }
+
+    #[test]
+    fn other_io_errors_are_kept() {
+        let err =
+            ProcCtlError::from_restricted_io("running lsof", std::io::ErrorKind::NotFound.into());
+        assert!(matches!(err, ProcCtlError::IoError(_)), "{:?}", err);
+    }
+
+    /// One error of each variant, alongside the code it must have. [ProcCtlError::code] has no catch-all, so a new
+    /// variant doesn't build until it has a code, and should then be added here.
+    fn one_of_each() -> Vec<(ProcCtlError, &'static str)> {
+        let port = ProtocolPort::Tcp(8080);
+        let mut errors = vec![
+            (ProcCtlError::ProcessNotFound(1), "process_not_found"),
+            (ProcCtlError::ProcessExited(1), "process_exited"),
+            (
+                ProcCtlError::ResolvedProcessExited(Box::new(
+                    crate::re_resolve::ExitedDuringResolution {
+                        selection: "name server".to_string(),
+                        attempts: vec![],
+                    },
+                )),
+                "resolved_process_exited",
+            ),
+            (
+                ProcCtlError::NoMatchingProcess("server".to_string()),
+                "no_matching_process",
+            ),
+            (
+                ProcCtlError::MultipleMatchingProcesses(vec![1, 2]),
+                "multiple_matching_processes",
+            ),
+            (ProcCtlError::EnvNotCollected(1), "env_not_collected"),
+            (
+                ProcCtlError::PermissionDenied("pid 1".to_string()),
+                "permission_denied",
+            ),
+            (
+                ProcCtlError::SandboxRestricted("running lsof".to_string()),
+                "sandbox_restricted",
+            ),
+            (
+                ProcCtlError::UnsupportedPlatform("netlink".to_string()),
+                "unsupported_platform",
+            ),
+            (
+                ProcCtlError::UnsupportedWithoutFeature("proc"),
+                "unsupported_without_feature",
+            ),
+            (
+                ProcCtlError::IoError(std::io::ErrorKind::Other.into()),
+                "io_error",
+            ),
+            (
+                ProcCtlError::WouldBlock("process list".to_string()),
+                "would_block",
+            ),
+            (
+                ProcCtlError::Timeout(
+                    std::time::Duration::from_secs(5),
+                    "running lsof".to_string(),
+                ),
+                "timeout",
+            ),
+            (
+                ProcCtlError::ConfigurationError("no process".to_string()),
+                "configuration_error",
+            ),
+            (ProcCtlError::AddressUnknown(port), "address_unknown"),
+            (ProcCtlError::TooFewPorts(vec![], 1), "too_few_ports"),
+            (
+                ProcCtlError::StalledExpectation(vec![port], 2, 3),
+                "stalled_expectation",
+            ),
+            (
+                ProcCtlError::MissingPorts(vec![port], vec![]),
+                "missing_ports",
+            ),
+            (
+                ProcCtlError::TooManyPorts(vec![port, port], 1),
+                "too_many_ports",
+            ),
+            (
+                ProcCtlError::TooFewConnections(vec![], 1),
+                "too_few_connections",
+            ),
+            (
+                ProcCtlError::BacklogTooSmall(port, Some(1), 128),
+                "backlog_too_small",
+            ),
+            (ProcCtlError::NotAccepting(port), "not_accepting"),
+            (
+                ProcCtlError::PortNotReleased(port, vec![1]),
+                "port_not_released",
+            ),
+            (
+                ProcCtlError::ForbiddenPorts(Box::new(crate::monitor::ForbiddenPorts {
+                    at: std::time::SystemTime::now(),
+                    unexpected: vec![],
+                    snapshot: vec![],
+                })),
+                "forbidden_ports",
+            ),
+        ];
+
+        #[cfg(target_os = "linux")]
+        errors.push((
+            ProcCtlError::ProcessError(procfs::ProcError::Other("bad stat".to_string())),
+            "process_error",
+        ));
+        #[cfg(any(target_os = "windows", target_os = "macos"))]
+        errors.push((
+            ProcCtlError::ProcessError("bad stat".to_string()),
+            "process_error",
+        ));
+        #[cfg(feature = "proc")]
+        errors.push((
+            ProcCtlError::TooFewChildren(Box::new(crate::proc_query::ChildrenShortfall {
+                expected: 1,
+                found_count: 0,
+                found: vec![],
+                parent: None,
+                parent_status: None,
+            })),
+            "too_few_children",
+        ));
+        #[cfg(feature = "proc")]
+        errors.push((
+            ProcCtlError::LimitTooLow(Box::new(crate::limits::LimitShortfall {
+                process: crate::ProcInfo::new(1, "server"),
+                resource: crate::limits::OPEN_FILES.to_string(),
+                required: 4096,
+                found: None,
+            })),
+            "limit_too_low",
+        ));
+        #[cfg(feature = "proc")]
+        errors.push((
+            ProcCtlError::ProcessesMismatch(Box::new(crate::expectations::ProcessMismatch {
+                unmatched: vec![],
+                unexpected: vec![crate::ProcInfo::new(1, "server")],
+            })),
+            "processes_mismatch",
+        ));
+        #[cfg(all(
+            feature = "proc",
+            any(target_os = "linux", target_os = "windows", target_os = "macos")
+        ))]
+        errors.push((
+            ProcCtlError::PipelineFailed(Box::new(crate::pipeline::PipelineFailure {
+                failures: vec![],
+                found: vec![],
+            })),
+            "pipeline_failed",
+        ));
+        errors.push((
+            ProcCtlError::WaitTimedOut(crate::wait::WaitHistory {
+                attempts: 1,
+                elapsed: std::time::Duration::from_secs(1),
+                observations: vec![],
+            }),
+            "wait_timed_out",
+        ));
+
+        errors
+    }
+
+    #[test]
+    fn every_variant_has_a_distinct_code() {
+        let errors = one_of_each();
+        for (err, code) in &errors {
+            assert_eq!(*code, err.code(), "{:?}", err);
+        }
+
+        let mut codes = errors.iter().map(|(_, code)| *code).collect::<Vec<_>>();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(errors.len(), codes.len());
+    }
+
+    #[test]
+    fn message_starts_with_the_code() {
+        for (err, code) in one_of_each() {
+            let message = err.to_string();
+            assert!(message.starts_with(&format!("[{}] ", code)), "{}", message);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_code_and_message() {
+        assert_eq!(
+            serde_json::json!({
+                "code": "process_not_found",
+                "message": "[process_not_found] process 1234 not found",
+            }),
+            serde_json::to_value(ProcCtlError::ProcessNotFound(1234)).unwrap()
+        );
+    }
 }
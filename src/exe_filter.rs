@@ -0,0 +1,184 @@
+//! Filtering processes by the executable file they are running, see [crate::ProcQuery::exe_matches].
+//!
+//! The executable is found from the process rather than from its name, so it tells apart builds which share a name.
+//! On Linux it is read fresh from `/proc/<pid>/exe` on every query, since a process keeps running after its
+//! executable is replaced or deleted, and the kernel then reports the old path with ` (deleted)` appended.
+
+use crate::proc_query::{FilterKind, MatchField, SkipReason};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A predicate on the path of an executable, as given to [crate::ProcQuery::exe_matches]
+pub(crate) type ExePredicate =
+    dyn Fn(&Path) -> bool + Send + Sync + std::panic::RefUnwindSafe + 'static;
+
+/// One filter on the executable of a process
+pub(crate) enum ExeFilter {
+    /// A predicate given by the caller
+    Matches(Arc<ExePredicate>),
+    /// The size of the file in bytes
+    FileSize(u64),
+    /// The SHA-256 of the file, as lowercase hex
+    #[cfg(feature = "exe-hash")]
+    Sha256(String, Arc<HashCache>),
+}
+
+impl std::fmt::Debug for ExeFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExeFilter::Matches(_) => write!(f, "Matches(..)"),
+            ExeFilter::FileSize(size) => f.debug_tuple("FileSize").field(size).finish(),
+            #[cfg(feature = "exe-hash")]
+            ExeFilter::Sha256(hash, _) => f.debug_tuple("Sha256").field(hash).finish(),
+        }
+    }
+}
+
+impl ExeFilter {
+    /// Check the executable at `exe`, failing with why the process does not match
+    pub(crate) fn check(&self, exe: &Path) -> Result<(), SkipReason> {
+        let matches = match self {
+            ExeFilter::Matches(predicate) => predicate(exe),
+            ExeFilter::FileSize(size) => std::fs::metadata(exe).map_err(unreadable)?.len() == *size,
+            #[cfg(feature = "exe-hash")]
+            ExeFilter::Sha256(hash, cache) => cache.hash(exe).map_err(unreadable)? == *hash,
+        };
+
+        if matches {
+            Ok(())
+        } else {
+            Err(SkipReason::FilteredBy(FilterKind::Exe))
+        }
+    }
+}
+
+fn unreadable(e: std::io::Error) -> SkipReason {
+    if e.kind() == std::io::ErrorKind::PermissionDenied {
+        SkipReason::PermissionDenied(MatchField::Exe)
+    } else {
+        SkipReason::ExeUnreadable
+    }
+}
+
+/// The hashes of the executables seen by a query, so that each is read once however many processes run it.
+///
+/// An entry is only used while the size and modification time of the file are unchanged, so a binary rebuilt in
+/// place is hashed again.
+#[cfg(feature = "exe-hash")]
+#[derive(Debug, Default)]
+pub(crate) struct HashCache {
+    hashes: std::sync::Mutex<std::collections::HashMap<PathBuf, (FileStamp, String)>>,
+}
+
+#[cfg(feature = "exe-hash")]
+type FileStamp = (u64, Option<std::time::SystemTime>);
+
+#[cfg(feature = "exe-hash")]
+impl HashCache {
+    fn hash(&self, exe: &Path) -> std::io::Result<String> {
+        let metadata = std::fs::metadata(exe)?;
+        let stamp = (metadata.len(), metadata.modified().ok());
+
+        // Every change leaves the map valid, so a poisoned lock can be used as it is
+        let lock = || self.hashes.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached, hash)) = lock().get(exe) {
+            if *cached == stamp {
+                return Ok(hash.clone());
+            }
+        }
+
+        // Hashed without holding the lock, since a large executable takes a while
+        let hash = crate::sha256::file_sha256(exe)?;
+        lock().insert(exe.to_path_buf(), (stamp, hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// Find the executable a process is running, failing with why it can't be checked
+#[cfg(target_os = "linux")]
+pub(crate) fn exe_path(p: &sysinfo::Process) -> Result<PathBuf, SkipReason> {
+    match std::fs::read_link(format!("/proc/{}/exe", p.pid())) {
+        Ok(exe) if is_deleted(&exe) => Err(SkipReason::ExeDeleted),
+        Ok(exe) => Ok(exe),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(SkipReason::PermissionDenied(MatchField::Exe))
+        }
+        Err(_) => Err(SkipReason::FieldUnavailable(MatchField::Exe)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn exe_path(p: &sysinfo::Process) -> Result<PathBuf, SkipReason> {
+    p.exe()
+        .map(Path::to_path_buf)
+        .ok_or(SkipReason::FieldUnavailable(MatchField::Exe))
+}
+
+/// Whether the kernel has marked an executable path as deleted
+#[cfg(any(target_os = "linux", test))]
+fn is_deleted(exe: &Path) -> bool {
+    exe.as_os_str().to_string_lossy().ends_with(" (deleted)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deleted_executables_are_recognised() {
+        assert!(is_deleted(Path::new("/usr/bin/server (deleted)")));
+        assert!(!is_deleted(Path::new("/usr/bin/server")));
+        assert!(!is_deleted(Path::new("/usr/bin/server (deleted) copy")));
+    }
+
+    #[test]
+    fn file_size() {
+        let exe = std::env::current_exe().unwrap();
+        let size = std::fs::metadata(&exe).unwrap().len();
+
+        assert_eq!(Ok(()), ExeFilter::FileSize(size).check(&exe));
+        assert_eq!(
+            Err(SkipReason::FilteredBy(FilterKind::Exe)),
+            ExeFilter::FileSize(size + 1).check(&exe)
+        );
+        assert_eq!(
+            Err(SkipReason::ExeUnreadable),
+            ExeFilter::FileSize(size).check(Path::new("/no/such/executable"))
+        );
+    }
+
+    #[test]
+    fn predicate_sees_the_path() {
+        let filter = ExeFilter::Matches(Arc::new(|exe: &Path| exe.ends_with("v2/server")));
+
+        assert_eq!(Ok(()), filter.check(Path::new("/opt/v2/server")));
+        assert_eq!(
+            Err(SkipReason::FilteredBy(FilterKind::Exe)),
+            filter.check(Path::new("/opt/v1/server"))
+        );
+    }
+
+    #[cfg(feature = "exe-hash")]
+    #[test]
+    fn hashes_are_cached_until_the_file_changes() {
+        let dir = std::env::temp_dir().join(format!("proc-ctl-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("server");
+        std::fs::write(&exe, b"abc").unwrap();
+
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let cache = Arc::new(HashCache::default());
+        let filter = ExeFilter::Sha256(abc.to_string(), cache.clone());
+        assert_eq!(Ok(()), filter.check(&exe));
+        assert_eq!(Ok(()), filter.check(&exe));
+        assert_eq!(1, cache.hashes.lock().unwrap().len());
+
+        std::fs::write(&exe, b"abcd").unwrap();
+        assert_eq!(
+            Err(SkipReason::FilteredBy(FilterKind::Exe)),
+            filter.check(&exe)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Waiting for processes to exit without polling, for [crate::ProcQuery::wait_for_exit].
+//!
+//! On Linux a pidfd becomes readable once its process exits, so the pidfds are registered with the tokio reactor. On
+//! Windows a process handle is signalled once its process exits, which is waited for on a blocking thread since tokio
+//! has no way to register one. Either way the wait wakes as soon as the last process exits. Elsewhere, outside of a
+//! tokio runtime, or when a process can't be opened, the wait polls instead.
+
+use crate::clock::SleepFuture;
+use crate::types::Pid;
+use crate::wait::WaitStrategy;
+use std::time::Duration;
+
+/// A future which completes once every one of `pids` has exited, or `None` if they can't all be watched. A process
+/// which has already exited is left out.
+#[cfg(target_os = "linux")]
+pub(crate) fn all_exited(
+    pids: Vec<Pid>,
+    _timeout: Duration,
+) -> Option<(WaitStrategy, SleepFuture)> {
+    use crate::error::ProcCtlError;
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::Interest;
+
+    tokio::runtime::Handle::try_current().ok()?;
+
+    let mut fds = Vec::with_capacity(pids.len());
+    for pid in pids {
+        match crate::pidfd::open(pid) {
+            Ok(fd) => fds.push(AsyncFd::with_interest(fd, Interest::READABLE).ok()?),
+            Err(ProcCtlError::ProcessNotFound(_)) => {}
+            Err(_) => return None,
+        }
+    }
+
+    Some((
+        WaitStrategy::PidFd,
+        Box::pin(async move {
+            for fd in fds {
+                // An error means the pidfd can't be waited on, so stop and let the query check the process again
+                if fd.readable().await.is_err() {
+                    return;
+                }
+            }
+        }),
+    ))
+}
+
+/// A future which completes once every one of `pids` has exited, or `None` if they can't all be watched. A process
+/// which has already exited is left out.
+///
+/// Each process is waited for by a blocking thread for at most `timeout`, so that no thread outlives the wait for long.
+#[cfg(target_os = "windows")]
+pub(crate) fn all_exited(pids: Vec<Pid>, timeout: Duration) -> Option<(WaitStrategy, SleepFuture)> {
+    use windows::Win32::Foundation::{CloseHandle, ERROR_INVALID_PARAMETER, HANDLE};
+    use windows::Win32::System::Threading::{
+        OpenProcess, WaitForSingleObject, PROCESS_SYNCHRONIZE,
+    };
+
+    let runtime = tokio::runtime::Handle::try_current().ok()?;
+
+    let mut handles = Vec::with_capacity(pids.len());
+    for pid in pids {
+        // SAFETY: OpenProcess has no memory safety requirements, and the handle is closed by the thread waiting on it
+        match unsafe { OpenProcess(PROCESS_SYNCHRONIZE, false, pid) } {
+            // Handles are pointers, which aren't Send, so the value is carried over to the blocking thread instead
+            Ok(handle) => handles.push(handle.0 as isize),
+            // A process which has exited and been cleaned up can't be found, which is the same as having exited
+            Err(e) if e.code() == ERROR_INVALID_PARAMETER.to_hresult() => {}
+            Err(_) => {
+                for handle in handles {
+                    // SAFETY: the handle was opened above and isn't used again
+                    let _ = unsafe { CloseHandle(HANDLE(handle as *mut _)) };
+                }
+                return None;
+            }
+        }
+    }
+
+    let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX - 1);
+    let waits = handles
+        .into_iter()
+        .map(|handle| {
+            runtime.spawn_blocking(move || {
+                let handle = HANDLE(handle as *mut _);
+                // SAFETY: the handle is open until it is closed here, and nothing else uses it
+                unsafe {
+                    WaitForSingleObject(handle, timeout_ms);
+                    let _ = CloseHandle(handle);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Some((
+        WaitStrategy::ProcessHandle,
+        Box::pin(async move {
+            for wait in waits {
+                let _ = wait.await;
+            }
+        }),
+    ))
+}
+
+/// There is nothing to wait on for a process exiting on this platform, so the wait polls
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+pub(crate) fn all_exited(
+    _pids: Vec<Pid>,
+    _timeout: Duration,
+) -> Option<(WaitStrategy, SleepFuture)> {
+    None
+}
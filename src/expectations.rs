@@ -0,0 +1,346 @@
+//! Checking a set of processes, such as the children found by [crate::ProcQuery::children], against what a test
+//! expects to be running.
+//!
+//! Comparing whole [ProcInfo]s fails on fields which differ from run to run, such as the environment or start time, so
+//! each [ProcInfoExpectation] only checks the fields it sets. [check_matches] gives the processes and expectations
+//! which didn't match each other, and [crate::assertions::assert_matches] panics with the same details.
+//!
+//! ```rust
+//! use proc_ctl::expectations::{check_matches, expect};
+//! use proc_ctl::ProcInfo;
+//!
+//! let children = vec![
+//!     ProcInfo::builder().pid(11).name("worker").cmd(["worker", "--shard", "1"]).build(),
+//!     ProcInfo::builder().pid(12).name("worker").cmd(["worker", "--shard", "2"]).build(),
+//!     ProcInfo::builder().pid(13).name("metrics").build(),
+//! ];
+//!
+//! check_matches(
+//!     &children,
+//!     &[
+//!         expect().name("worker").count(2).cmd_contains("--shard"),
+//!         expect().name("metrics"),
+//!     ],
+//! )
+//! .unwrap();
+//! ```
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::proc_query::ProcInfo;
+use crate::types::Pid;
+
+/// Start an expectation of one process, which matches any process until its fields are set
+pub fn expect() -> ProcInfoExpectation {
+    ProcInfoExpectation::new()
+}
+
+/// What is expected of some processes, see [expect]
+///
+/// Only the fields which are set are checked. By default one process is expected to match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcInfoExpectation {
+    name: Option<String>,
+    pid: Option<Pid>,
+    parent: Option<Pid>,
+    cmd_contains: Vec<String>,
+    count: usize,
+}
+
+impl ProcInfoExpectation {
+    /// Create an expectation of one process, which matches any process until its fields are set
+    pub fn new() -> Self {
+        ProcInfoExpectation {
+            name: None,
+            pid: None,
+            parent: None,
+            cmd_contains: Vec::new(),
+            count: 1,
+        }
+    }
+
+    /// Expect processes called `name`. This matches [ProcInfo::name], or [ProcInfo::canonical_name] so that the same
+    /// name works on every platform, such as `worker` for `worker.exe` on Windows.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Expect the process with this pid
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Expect processes whose parent has this pid
+    pub fn parent(mut self, pid: Pid) -> Self {
+        self.parent = Some(pid);
+        self
+    }
+
+    /// Expect processes whose command line, with arguments joined by a space, contains `text`. When this is used more
+    /// than once, the command line must contain each of them.
+    pub fn cmd_contains(mut self, text: impl Into<String>) -> Self {
+        self.cmd_contains.push(text.into());
+        self
+    }
+
+    /// Expect exactly `count` processes to match, rather than one
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Whether `p` has every field this expectation sets. The count is not checked, since it is about a set of
+    /// processes.
+    pub fn matches(&self, p: &ProcInfo) -> bool {
+        let name = self.name.as_ref().map_or(true, |name| {
+            p.name == *name || p.canonical_name() == crate::names::canonical(name)
+        });
+        let cmd = p.cmd.join(" ");
+
+        name && self.pid.map_or(true, |pid| p.pid == pid)
+            && self.parent.map_or(true, |parent| p.parent == Some(parent))
+            && self.cmd_contains.iter().all(|text| cmd.contains(text))
+    }
+}
+
+impl Default for ProcInfoExpectation {
+    fn default() -> Self {
+        ProcInfoExpectation::new()
+    }
+}
+
+/// Describes the processes which are expected, such as `2 processes named "worker" with "--shard" in the command line`
+impl std::fmt::Display for ProcInfoExpectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.count {
+            1 => write!(f, "1 process")?,
+            count => write!(f, "{} processes", count)?,
+        }
+        if let Some(name) = &self.name {
+            write!(f, " named {:?}", name)?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, " with pid {}", pid)?;
+        }
+        if let Some(parent) = self.parent {
+            write!(f, " with parent {}", parent)?;
+        }
+        for text in &self.cmd_contains {
+            write!(f, " with {:?} in the command line", text)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The details of a [ProcCtlError::ProcessesMismatch] failure
+#[derive(Debug)]
+pub struct ProcessMismatch {
+    /// The expectations which didn't match as many processes as they expected
+    pub unmatched: Vec<UnmatchedExpectation>,
+    /// The processes which no expectation matched, including those beyond the count of an expectation
+    pub unexpected: Vec<ProcInfo>,
+}
+
+/// An expectation which matched fewer processes than its count, see [ProcessMismatch::unmatched]
+#[derive(Debug)]
+pub struct UnmatchedExpectation {
+    /// The expectation
+    pub expectation: ProcInfoExpectation,
+    /// How many processes it matched
+    pub found: usize,
+}
+
+/// A line for each expectation which wasn't met, starting with `-`, and for each unexpected process, starting with `+`
+impl std::fmt::Display for ProcessMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "processes did not match the expectations")?;
+        for unmatched in &self.unmatched {
+            write!(
+                f,
+                "\n  - expected {}, found {}",
+                unmatched.expectation, unmatched.found
+            )?;
+        }
+        for p in &self.unexpected {
+            write!(f, "\n  + unexpected {} (pid {})", p.name, p.pid)?;
+            if !p.cmd.is_empty() {
+                write!(f, ": {}", p.cmd.join(" "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that `processes` are exactly those `expected`, failing with [ProcCtlError::ProcessesMismatch] if not.
+///
+/// Each expectation in turn takes as many of the processes not taken by an earlier expectation as its count. It fails
+/// if it matches fewer, and a process which no expectation takes is unexpected. Put more specific expectations first,
+/// so that a process isn't taken by a broader expectation which a later one needed.
+pub fn check_matches(
+    processes: &[ProcInfo],
+    expected: &[ProcInfoExpectation],
+) -> ProcCtlResult<()> {
+    let mut taken = vec![false; processes.len()];
+    let mut unmatched = Vec::new();
+
+    for expectation in expected {
+        let mut found = 0;
+        for (p, taken) in processes.iter().zip(taken.iter_mut()) {
+            if found < expectation.count && !*taken && expectation.matches(p) {
+                *taken = true;
+                found += 1;
+            }
+        }
+
+        if found < expectation.count {
+            unmatched.push(UnmatchedExpectation {
+                expectation: expectation.clone(),
+                found,
+            });
+        }
+    }
+
+    let unexpected = processes
+        .iter()
+        .zip(taken)
+        .filter(|(_, taken)| !taken)
+        .map(|(p, _)| p.clone())
+        .collect::<Vec<_>>();
+
+    if unmatched.is_empty() && unexpected.is_empty() {
+        Ok(())
+    } else {
+        Err(ProcCtlError::ProcessesMismatch(Box::new(ProcessMismatch {
+            unmatched,
+            unexpected,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(pid: Pid, shard: &str) -> ProcInfo {
+        ProcInfo::builder()
+            .pid(pid)
+            .parent(10)
+            .name("worker")
+            .cmd(["/opt/app/worker", "--shard", shard])
+            .build()
+    }
+
+    fn mismatch(processes: &[ProcInfo], expected: &[ProcInfoExpectation]) -> ProcessMismatch {
+        match check_matches(processes, expected) {
+            Err(ProcCtlError::ProcessesMismatch(mismatch)) => *mismatch,
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_the_fields_set_are_checked() {
+        let p = worker(11, "1");
+
+        assert!(expect().matches(&p));
+        assert!(expect().name("worker").matches(&p));
+        assert!(expect().pid(11).parent(10).matches(&p));
+        assert!(expect()
+            .cmd_contains("--shard 1")
+            .cmd_contains("worker")
+            .matches(&p));
+
+        assert!(!expect().name("work").matches(&p));
+        assert!(!expect().pid(12).matches(&p));
+        assert!(!expect().parent(11).matches(&p));
+        assert!(!expect().cmd_contains("--shard 2").matches(&p));
+    }
+
+    #[test]
+    fn names_match_on_every_platform() {
+        let p = ProcInfo::builder()
+            .name("worker.exe")
+            .exe(r"C:\app\worker.exe")
+            .build();
+
+        assert!(expect().name("worker").matches(&p));
+        assert!(expect().name("worker.exe").matches(&p));
+        assert!(p.matches(&expect().name("worker")));
+    }
+
+    #[test]
+    fn exactly_the_expected_processes_pass() {
+        let processes = [worker(11, "1"), worker(12, "2")];
+
+        assert!(check_matches(&processes, &[expect().name("worker").count(2)]).is_ok());
+        assert!(check_matches(
+            &processes,
+            &[
+                expect().cmd_contains("--shard 2"),
+                expect().cmd_contains("--shard")
+            ]
+        )
+        .is_ok());
+        assert!(check_matches(&[], &[]).is_ok());
+        assert!(check_matches(&[], &[expect().count(0)]).is_ok());
+    }
+
+    #[test]
+    fn too_few_and_unexpected_processes_are_reported() {
+        let stray = ProcInfo::builder()
+            .pid(13)
+            .name("stray")
+            .cmd(["stray", "--flag"])
+            .build();
+        let processes = [worker(11, "1"), stray];
+
+        let found = mismatch(
+            &processes,
+            &[expect().name("worker").count(2).cmd_contains("--shard")],
+        );
+        assert_eq!(1, found.unmatched.len());
+        assert_eq!(1, found.unmatched[0].found);
+        assert_eq!(
+            vec![13],
+            found.unexpected.iter().map(|p| p.pid).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            "processes did not match the expectations\n\
+            \x20 - expected 2 processes named \"worker\" with \"--shard\" in the command line, found 1\n\
+            \x20 + unexpected stray (pid 13): stray --flag",
+            found.to_string()
+        );
+    }
+
+    #[test]
+    fn processes_beyond_the_count_are_unexpected() {
+        let processes = [worker(11, "1"), worker(12, "2")];
+
+        let found = mismatch(&processes, &[expect().name("worker")]);
+        assert!(found.unmatched.is_empty());
+        assert_eq!(
+            vec![12],
+            found.unexpected.iter().map(|p| p.pid).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn earlier_expectations_take_processes_first() {
+        let processes = [worker(11, "1"), worker(12, "2")];
+
+        // The broad expectation takes the process the specific one needed
+        let found = mismatch(
+            &processes,
+            &[expect().name("worker"), expect().cmd_contains("--shard 1")],
+        );
+        assert_eq!(1, found.unmatched.len());
+        assert_eq!(0, found.unmatched[0].found);
+        assert_eq!(
+            vec![12],
+            found.unexpected.iter().map(|p| p.pid).collect::<Vec<_>>()
+        );
+    }
+}
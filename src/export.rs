@@ -0,0 +1,114 @@
+use crate::error::{ProcCtlError, ProcCtlResult};
+use std::io::Write;
+
+/// The format used when exporting query results to a writer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line, using the serde representation of each result
+    JsonLines,
+    /// Comma separated values with a header row, using the same field names as the serde representation.
+    ///
+    /// List fields are joined with a single space and missing values are written as empty fields.
+    Csv,
+}
+
+/// A result type which can be written as a row of CSV
+pub(crate) trait CsvRecord {
+    /// The column names, matching the field names of the serde representation
+    fn csv_header() -> &'static [&'static str];
+
+    /// The values for each column, in the same order as the header
+    fn csv_fields(&self) -> Vec<String>;
+}
+
+#[derive(serde::Serialize)]
+struct ErrorRecord<'a> {
+    error: &'a ProcCtlError,
+}
+
+/// Writes records one at a time, flushing after each one so that large result sets are never buffered in memory
+pub(crate) struct RecordWriter<'a, W: Write> {
+    writer: &'a mut W,
+    format: ExportFormat,
+    header_written: bool,
+}
+
+impl<'a, W: Write> RecordWriter<'a, W> {
+    pub(crate) fn new(writer: &'a mut W, format: ExportFormat) -> Self {
+        RecordWriter {
+            writer,
+            format,
+            header_written: false,
+        }
+    }
+
+    pub(crate) fn write<T: serde::Serialize + CsvRecord>(
+        &mut self,
+        record: &T,
+    ) -> ProcCtlResult<()> {
+        match self.format {
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut *self.writer, record).map_err(std::io::Error::from)?;
+                self.writer.write_all(b"\n")?;
+            }
+            ExportFormat::Csv => {
+                self.write_csv_header::<T>()?;
+                write_csv_row(&mut *self.writer, &record.csv_fields())?;
+            }
+        }
+
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Write a final error record. Only the JSON Lines format has a representation for errors, for CSV this does nothing.
+    pub(crate) fn write_error(&mut self, error: &ProcCtlError) -> ProcCtlResult<()> {
+        if self.format == ExportFormat::JsonLines {
+            let record = ErrorRecord { error };
+            serde_json::to_writer(&mut *self.writer, &record).map_err(std::io::Error::from)?;
+            self.writer.write_all(b"\n")?;
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Complete the output, making sure a CSV header is present even if no records were written
+    pub(crate) fn finish<T: CsvRecord>(mut self) -> ProcCtlResult<()> {
+        if self.format == ExportFormat::Csv {
+            self.write_csv_header::<T>()?;
+        }
+
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_csv_header<T: CsvRecord>(&mut self) -> ProcCtlResult<()> {
+        if !self.header_written {
+            let header = T::csv_header()
+                .iter()
+                .map(|h| h.to_string())
+                .collect::<Vec<_>>();
+            write_csv_row(&mut *self.writer, &header)?;
+            self.header_written = true;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_csv_row(writer: &mut impl Write, fields: &[String]) -> std::io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+
+    writer.write_all(b"\n")
+}
@@ -0,0 +1,349 @@
+//! Whether the Windows firewall allows inbound connections to a port, for [crate::PortInfo::firewall_allowed].
+//!
+//! The rules are read once per query through the `INetFwPolicy2` COM interface and matched here, conservatively. Only
+//! enabled inbound rules for TCP which apply to a current profile are considered, and a rule must name no program or
+//! the program holding the port, and list the port in its local ports. A matching block rule wins over any allow
+//! rule, as it does in the firewall, and without a matching rule the default inbound action of the profile applies.
+//! Rules which select on anything else, such as a service, an interface or the remote address, are treated as if
+//! they matched every connection, so the answer is an approximation which can't tell apart two rules that only
+//! differ in those.
+
+use std::path::Path;
+
+/// The value of `Protocol` for a rule which applies to TCP only
+const PROTOCOL_TCP: i32 = 6;
+
+/// The value of `Protocol` for a rule which applies to every protocol
+const PROTOCOL_ANY: i32 = 256;
+
+/// One rule of the firewall, with only the fields used to match it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FirewallRule {
+    pub(crate) enabled: bool,
+    pub(crate) inbound: bool,
+    pub(crate) allow: bool,
+    pub(crate) protocol: i32,
+    /// The path of the program the rule applies to, or `None` for every program
+    pub(crate) application: Option<String>,
+    /// The local ports as the firewall shows them, such as `80,443,8000-8080` or `*`
+    pub(crate) local_ports: String,
+    /// A mask of the profiles the rule applies to
+    pub(crate) profiles: i32,
+}
+
+/// The state of the firewall which decides whether a port is reachable
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FirewallPolicy {
+    /// A mask of the profiles which currently apply, such as private for a home network
+    pub(crate) current_profiles: i32,
+    /// Whether the firewall is on for any of the current profiles
+    pub(crate) enabled: bool,
+    /// Whether a connection which no rule matches is allowed in any of the current profiles, including by a profile
+    /// whose firewall is off
+    pub(crate) default_allow: bool,
+    pub(crate) rules: Vec<FirewallRule>,
+}
+
+impl FirewallPolicy {
+    /// Whether an inbound TCP connection to `port` of the program at `exe` is allowed
+    pub(crate) fn allows_tcp(&self, exe: &Path, port: u16) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let mut allowed = None;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| self.applies(rule, exe, port))
+        {
+            if !rule.allow {
+                return false;
+            }
+            allowed = Some(true);
+        }
+
+        allowed.unwrap_or(self.default_allow)
+    }
+
+    fn applies(&self, rule: &FirewallRule, exe: &Path, port: u16) -> bool {
+        rule.enabled
+            && rule.inbound
+            && (rule.protocol == PROTOCOL_TCP || rule.protocol == PROTOCOL_ANY)
+            && rule.profiles & self.current_profiles != 0
+            && application_matches(rule.application.as_deref(), exe)
+            && local_ports_match(&rule.local_ports, port)
+    }
+}
+
+/// Whether a rule for the program `application` applies to the program at `exe`. The firewall shows paths as they
+/// were entered, so environment variables such as `%SystemRoot%` are expanded and case is ignored.
+fn application_matches(application: Option<&str>, exe: &Path) -> bool {
+    let Some(application) = application.filter(|app| !app.is_empty() && *app != "*") else {
+        return true;
+    };
+
+    let expanded = expand_env_vars(application);
+    expanded.eq_ignore_ascii_case(&exe.to_string_lossy())
+}
+
+/// Replace each `%NAME%` with the value of the environment variable, leaving any which are not set as they are
+fn expand_env_vars(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('%') {
+        let Some(len) = rest[start + 1..].find('%') else {
+            break;
+        };
+        let name = &rest[start + 1..start + 1 + len];
+        out.push_str(&rest[..start]);
+        match std::env::var(name) {
+            Ok(expanded) if !name.is_empty() => out.push_str(&expanded),
+            _ => out.push_str(&rest[start..start + len + 2]),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// Whether `port` is in the local ports of a rule, a comma separated list of ports and ranges, or `*` for every port.
+/// A rule for every protocol has no local ports, which also means every port. Keywords such as `RPC` stand for ports
+/// chosen by the system, which are not matched.
+fn local_ports_match(local_ports: &str, port: u16) -> bool {
+    if local_ports.trim().is_empty() {
+        return true;
+    }
+
+    local_ports.split(',').map(str::trim).any(|item| {
+        if item == "*" {
+            return true;
+        }
+
+        match item.split_once('-') {
+            Some((low, high)) => match (low.trim().parse::<u16>(), high.trim().parse::<u16>()) {
+                (Ok(low), Ok(high)) => (low..=high).contains(&port),
+                _ => false,
+            },
+            None => item.parse() == Ok(port),
+        }
+    })
+}
+
+/// Read the firewall policy through COM
+#[cfg(all(target_os = "windows", feature = "windows-firewall"))]
+pub(crate) fn load() -> windows::core::Result<FirewallPolicy> {
+    use windows::core::{IUnknown, Interface, VARIANT};
+    use windows::Win32::Foundation::RPC_E_CHANGED_MODE;
+    use windows::Win32::NetworkManagement::WindowsFirewall::{
+        INetFwPolicy2, INetFwRule, NetFwPolicy2, NET_FW_ACTION_ALLOW, NET_FW_PROFILE_TYPE2,
+        NET_FW_RULE_DIR_IN,
+    };
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER,
+        COINIT_MULTITHREADED,
+    };
+    use windows::Win32::System::Ole::IEnumVARIANT;
+
+    // SAFETY: COM is initialised for this thread before any interface is used, and only uninitialised if this call
+    // initialised it, after every interface has been dropped at the end of `read`
+    unsafe {
+        let init = CoInitializeEx(None, COINIT_MULTITHREADED);
+        // A thread which already uses a single threaded apartment can still create the policy object
+        if init.is_err() && init != RPC_E_CHANGED_MODE {
+            return Err(init.into());
+        }
+
+        let read = || -> windows::core::Result<FirewallPolicy> {
+            let policy: INetFwPolicy2 =
+                CoCreateInstance(&NetFwPolicy2, None, CLSCTX_INPROC_SERVER)?;
+            let current_profiles = policy.CurrentProfileTypes()?;
+
+            let mut enabled = false;
+            let mut default_allow = false;
+            for profile in [1, 2, 4].into_iter().filter(|p| current_profiles & p != 0) {
+                let profile = NET_FW_PROFILE_TYPE2(profile);
+                let on = policy.get_FirewallEnabled(profile)?.0 != 0;
+                enabled |= on;
+                default_allow |=
+                    !on || policy.get_DefaultInboundAction(profile)? == NET_FW_ACTION_ALLOW;
+            }
+
+            let rules: IEnumVARIANT = policy.Rules()?._NewEnum()?.cast()?;
+            let mut read_rules = Vec::new();
+            loop {
+                let mut item = [VARIANT::default()];
+                let mut fetched = 0;
+                rules.Next(&mut item, &mut fetched).ok()?;
+                if fetched == 0 {
+                    break;
+                }
+
+                let rule: INetFwRule = IUnknown::try_from(&item[0])?.cast()?;
+                let application = rule.ApplicationName()?.to_string();
+                read_rules.push(FirewallRule {
+                    enabled: rule.Enabled()?.0 != 0,
+                    inbound: rule.Direction()? == NET_FW_RULE_DIR_IN,
+                    allow: rule.Action()? == NET_FW_ACTION_ALLOW,
+                    protocol: rule.Protocol()?,
+                    application: Some(application).filter(|app| !app.is_empty()),
+                    local_ports: rule.LocalPorts()?.to_string(),
+                    profiles: rule.Profiles()?,
+                });
+            }
+
+            Ok(FirewallPolicy {
+                current_profiles,
+                enabled,
+                default_allow,
+                rules: read_rules,
+            })
+        };
+        let policy = read();
+
+        if init.is_ok() {
+            CoUninitialize();
+        }
+        policy
+    }
+}
+
+/// The path of the executable of `pid`, as the firewall names programs
+#[cfg(all(target_os = "windows", feature = "windows-firewall"))]
+pub(crate) fn exe_path(pid: crate::types::Pid) -> Option<std::path::PathBuf> {
+    use windows::core::PWSTR;
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    let mut buffer = vec![0u16; 1024];
+    let mut len = buffer.len() as u32;
+    // SAFETY: the handle is closed once the name has been read, and the buffer outlives the call which fills it
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut len,
+        );
+        let _ = CloseHandle(process);
+        result.ok()?;
+    }
+
+    buffer.truncate(len as usize);
+    Some(String::from_utf16_lossy(&buffer).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXE: &str = r"C:\Program Files\Server\server.exe";
+
+    fn rule(allow: bool, application: Option<&str>, local_ports: &str) -> FirewallRule {
+        FirewallRule {
+            enabled: true,
+            inbound: true,
+            allow,
+            protocol: PROTOCOL_TCP,
+            application: application.map(str::to_string),
+            local_ports: local_ports.to_string(),
+            profiles: 2,
+        }
+    }
+
+    fn policy(rules: Vec<FirewallRule>) -> FirewallPolicy {
+        FirewallPolicy {
+            current_profiles: 2,
+            enabled: true,
+            default_allow: false,
+            rules,
+        }
+    }
+
+    #[test]
+    fn local_ports_lists_and_ranges() {
+        assert!(local_ports_match("*", 8080));
+        assert!(local_ports_match("", 8080));
+        assert!(local_ports_match("80,443, 8080", 8080));
+        assert!(local_ports_match("8000-8100", 8080));
+        assert!(!local_ports_match("80,443", 8080));
+        assert!(!local_ports_match("RPC", 8080));
+        assert!(!local_ports_match("8100-8000", 8080));
+    }
+
+    #[test]
+    fn allow_rule_for_the_program_and_port() {
+        let exe = Path::new(EXE);
+        let policy = policy(vec![rule(true, Some(EXE), "8080")]);
+
+        assert!(policy.allows_tcp(exe, 8080));
+        assert!(!policy.allows_tcp(exe, 8081));
+        assert!(!policy.allows_tcp(Path::new(r"C:\other.exe"), 8080));
+        assert!(policy.allows_tcp(Path::new(&EXE.to_uppercase()), 8080));
+    }
+
+    #[test]
+    fn block_rule_wins() {
+        let exe = Path::new(EXE);
+        let policy = policy(vec![rule(true, None, "*"), rule(false, Some(EXE), "8080")]);
+
+        assert!(!policy.allows_tcp(exe, 8080));
+        assert!(policy.allows_tcp(exe, 8081));
+    }
+
+    #[test]
+    fn rules_which_do_not_apply_are_ignored() {
+        let exe = Path::new(EXE);
+        let mut disabled = rule(true, None, "*");
+        disabled.enabled = false;
+        let mut outbound = rule(true, None, "*");
+        outbound.inbound = false;
+        let mut udp = rule(true, None, "*");
+        udp.protocol = 17;
+        let mut public = rule(true, None, "*");
+        public.profiles = 4;
+        let mut any_protocol = rule(false, None, "*");
+        any_protocol.protocol = PROTOCOL_ANY;
+        any_protocol.profiles = 4;
+
+        let policy = policy(vec![disabled, outbound, udp, public, any_protocol]);
+        assert!(!policy.allows_tcp(exe, 8080));
+    }
+
+    #[test]
+    fn default_action_and_disabled_firewall() {
+        let exe = Path::new(EXE);
+        let mut policy = policy(vec![]);
+        assert!(!policy.allows_tcp(exe, 8080));
+
+        policy.default_allow = true;
+        assert!(policy.allows_tcp(exe, 8080));
+
+        policy.default_allow = false;
+        policy.enabled = false;
+        assert!(policy.allows_tcp(exe, 8080));
+    }
+
+    #[test]
+    fn environment_variables_in_program_paths() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(
+            format!(r"{}\server.exe", path),
+            expand_env_vars(r"%PATH%\server.exe")
+        );
+        assert_eq!(
+            r"%PROC_CTL_NOT_SET%\server.exe",
+            expand_env_vars(r"%PROC_CTL_NOT_SET%\server.exe")
+        );
+        assert_eq!("100%", expand_env_vars("100%"));
+        assert_eq!("%%", expand_env_vars("%%"));
+
+        assert!(application_matches(None, Path::new(EXE)));
+        assert!(application_matches(Some("*"), Path::new(EXE)));
+    }
+}
@@ -0,0 +1,86 @@
+//! Integration with the child handles of other process spawning crates.
+//!
+//! Each of these checks that the process is still running when the query is created, so that a child which failed at
+//! startup is reported as [ProcCtlError::ProcessNotFound] rather than as a query which never finds anything.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::port_query::PortQuery;
+use crate::types::Pid;
+
+#[cfg(feature = "duct")]
+fn pid_from_handle(handle: &duct::Handle) -> ProcCtlResult<Pid> {
+    let pids = handle.pids();
+    let pid = match pids.as_slice() {
+        [pid] => *pid,
+        _ => {
+            return Err(ProcCtlError::ConfigurationError(format!(
+                "expected an expression which runs one process, but it runs {}",
+                pids.len()
+            )))
+        }
+    };
+
+    match handle.try_wait()? {
+        None => Ok(pid),
+        Some(_) => Err(ProcCtlError::ProcessNotFound(pid)),
+    }
+}
+
+#[cfg(feature = "duct")]
+impl PortQuery {
+    /// Set the process ID to match from a running [duct::Handle]
+    ///
+    /// Fails if the handle is for a pipeline rather than a single command, or if the process has already exited.
+    pub fn process_id_from_handle(self, handle: &duct::Handle) -> ProcCtlResult<Self> {
+        Ok(self.process_id(pid_from_handle(handle)?))
+    }
+}
+
+#[cfg(all(feature = "duct", feature = "proc"))]
+impl crate::ProcQuery {
+    /// Set the process ID to match from a running [duct::Handle]
+    ///
+    /// Fails if the handle is for a pipeline rather than a single command, or if the process has already exited.
+    pub fn process_id_from_handle(self, handle: &duct::Handle) -> ProcCtlResult<Self> {
+        Ok(self.process_id(pid_from_handle(handle)?))
+    }
+}
+
+/// Create queries directly from a spawned child, such as one started from `assert_cmd`'s `Command::cargo_bin`
+///
+/// ```rust no_run
+/// use assert_cmd::cargo::CommandCargoExt;
+/// use proc_ctl::SpawnedChildExt;
+///
+/// let mut child = std::process::Command::cargo_bin("server").unwrap().spawn().unwrap();
+/// let ports = child.port_query().unwrap().tcp_only().execute().unwrap();
+/// ```
+#[cfg(feature = "assert-cmd")]
+pub trait SpawnedChildExt {
+    /// Create a [PortQuery] for this child, failing if it has already exited
+    fn port_query(&mut self) -> ProcCtlResult<PortQuery>;
+
+    /// Create a [crate::ProcQuery] for this child, failing if it has already exited
+    #[cfg(feature = "proc")]
+    fn proc_query(&mut self) -> ProcCtlResult<crate::ProcQuery>;
+}
+
+#[cfg(feature = "assert-cmd")]
+impl SpawnedChildExt for std::process::Child {
+    fn port_query(&mut self) -> ProcCtlResult<PortQuery> {
+        Ok(PortQuery::new().process_id(running_child_pid(self)?))
+    }
+
+    #[cfg(feature = "proc")]
+    fn proc_query(&mut self) -> ProcCtlResult<crate::ProcQuery> {
+        Ok(crate::ProcQuery::new().process_id(running_child_pid(self)?))
+    }
+}
+
+#[cfg(feature = "assert-cmd")]
+fn running_child_pid(child: &mut std::process::Child) -> ProcCtlResult<Pid> {
+    match child.try_wait()? {
+        None => Ok(child.id()),
+        Some(_) => Err(ProcCtlError::ProcessNotFound(child.id())),
+    }
+}
@@ -0,0 +1,386 @@
+//! Recording what a watcher saw, for assertions once a scenario has finished, see [PortHistory].
+//!
+//! A history keeps at most the number of events it was created with. Once it is full, recording an event drops the
+//! oldest one, and [PortHistory::dropped] counts how many have been dropped. The queries stay exact where they can:
+//! [PortHistory::first_bound] and [PortHistory::ever_bound] cover the whole watch whatever the capacity, and
+//! [PortHistory::was_continuously_bound] remembers which ports were bound when the last dropped event was seen, so it
+//! can answer for any time from then on.
+
+use crate::types::{Pid, ProtocolPort};
+use crate::watch::PortEvent;
+#[cfg(feature = "proc")]
+use crate::watch::ProcEvent;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// One event recorded by a watcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct RecordedEvent<E> {
+    /// When the event was seen, on the clock of the query. Events found by the same run of the query share a time.
+    ///
+    /// This is not serialized, since an [Instant] only has meaning within the process which read it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub at: Instant,
+    /// How long after the history was started the event was seen
+    pub elapsed: Duration,
+    /// What happened
+    pub event: E,
+}
+
+/// The most recent events, up to a capacity
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct EventLog<E> {
+    #[cfg_attr(feature = "serde", serde(skip))]
+    started: Instant,
+    capacity: usize,
+    dropped: usize,
+    events: VecDeque<RecordedEvent<E>>,
+}
+
+impl<E> EventLog<E> {
+    fn new(capacity: usize, started: Instant) -> Self {
+        EventLog {
+            started,
+            capacity,
+            dropped: 0,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record an event, giving back the oldest event if it had to be dropped to make room
+    fn record(&mut self, at: Instant, event: E) -> Option<RecordedEvent<E>> {
+        let recorded = RecordedEvent {
+            at,
+            elapsed: at.saturating_duration_since(self.started),
+            event,
+        };
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return Some(recorded);
+        }
+
+        let evicted = if self.events.len() == self.capacity {
+            self.dropped += 1;
+            self.events.pop_front()
+        } else {
+            None
+        };
+        self.events.push_back(recorded);
+
+        evicted
+    }
+}
+
+/// The events seen by a [crate::PortEvents] watcher, see [crate::PortEvents::with_history]
+///
+/// Its serde form lists the events still held, with the time of each since the history started, together with the
+/// capacity and the number of events dropped.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PortHistory {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    log: EventLog<PortEvent>,
+    /// The ports bound just before the oldest event held, as of `known_since`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    bound_before: BTreeSet<ProtocolPort>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    known_since: Instant,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    first_bound: BTreeMap<ProtocolPort, Instant>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_observation: Option<Instant>,
+}
+
+impl PortHistory {
+    pub(crate) fn new(capacity: usize, started: Instant) -> Self {
+        PortHistory {
+            log: EventLog::new(capacity, started),
+            bound_before: BTreeSet::new(),
+            known_since: started,
+            first_bound: BTreeMap::new(),
+            last_observation: None,
+        }
+    }
+
+    /// Note that the query was run at `at`, whether or not anything changed
+    pub(crate) fn observed(&mut self, at: Instant) {
+        self.last_observation = Some(at);
+    }
+
+    pub(crate) fn record(&mut self, at: Instant, event: PortEvent) {
+        if let PortEvent::PortBound(port) = event {
+            self.first_bound.entry(port).or_insert(at);
+        }
+
+        if let Some(evicted) = self.log.record(at, event) {
+            apply(&mut self.bound_before, &evicted.event);
+            self.known_since = evicted.at;
+        }
+    }
+
+    /// The events held, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &RecordedEvent<PortEvent>> {
+        self.log.events.iter()
+    }
+
+    /// How many events have been dropped because the history was full
+    pub fn dropped(&self) -> usize {
+        self.log.dropped
+    }
+
+    /// The most events the history holds at once
+    pub fn capacity(&self) -> usize {
+        self.log.capacity
+    }
+
+    /// When the query was last run, which is as far as the history knows about
+    pub fn last_observation(&self) -> Option<Instant> {
+        self.last_observation
+    }
+
+    /// When `port` was first seen bound, even if that event has since been dropped
+    pub fn first_bound(&self, port: ProtocolPort) -> Option<Instant> {
+        self.first_bound.get(&port).copied()
+    }
+
+    /// Whether `port` was ever seen bound, even if those events have since been dropped
+    pub fn ever_bound(&self, port: ProtocolPort) -> bool {
+        self.first_bound.contains_key(&port)
+    }
+
+    /// Whether `port` was bound for the whole of `range`, as far as the watcher could see.
+    ///
+    /// The query is only run once per interval, so a port released and bound again between two runs looks as if it
+    /// was never released. This is false if any of `range` is outside what the history knows about, which is from the
+    /// last event dropped, or the start if none were, until [PortHistory::last_observation].
+    ///
+    /// ```rust no_run
+    /// use futures_util::StreamExt;
+    /// use proc_ctl::{PortQuery, ProtocolPort};
+    /// use std::time::Duration;
+    ///
+    /// # async fn example() {
+    /// let mut events = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .events(Duration::from_millis(100))
+    ///     .with_history(1000);
+    /// // Poll the events while the scenario runs
+    /// while let Some(_) = events.next().await {}
+    ///
+    /// let history = events.history().unwrap();
+    /// let port = ProtocolPort::Tcp(8080);
+    /// let bound = history.first_bound(port).unwrap();
+    /// assert!(history.was_continuously_bound(port, bound..history.last_observation().unwrap()));
+    /// # }
+    /// ```
+    pub fn was_continuously_bound(&self, port: ProtocolPort, range: Range<Instant>) -> bool {
+        let known_until = match self.last_observation {
+            Some(at) => at,
+            None => return false,
+        };
+        if range.start < self.known_since || range.end > known_until || range.start > range.end {
+            return false;
+        }
+
+        let mut bound = self.bound_before.clone();
+        let mut events = self.log.events.iter().peekable();
+        while let Some(recorded) = events.next_if(|r| r.at <= range.start) {
+            apply(&mut bound, &recorded.event);
+        }
+        if !bound.contains(&port) {
+            return false;
+        }
+
+        events
+            .take_while(|r| r.at < range.end)
+            .all(|r| match r.event {
+                PortEvent::PortReleased(released) => released != port,
+                PortEvent::ProcessExited => false,
+                PortEvent::PortBound(_) => true,
+            })
+    }
+}
+
+/// Update the set of bound ports for an event
+fn apply(bound: &mut BTreeSet<ProtocolPort>, event: &PortEvent) {
+    match event {
+        PortEvent::PortBound(port) => {
+            bound.insert(*port);
+        }
+        PortEvent::PortReleased(port) => {
+            bound.remove(port);
+        }
+        PortEvent::ProcessExited => bound.clear(),
+    }
+}
+
+/// The events seen by a [crate::ProcEvents] watcher, see [crate::ProcEvents::with_history]
+///
+/// Like [PortHistory], once it is full the oldest events are dropped.
+#[cfg(feature = "proc")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProcHistory {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    log: EventLog<ProcEvent>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    first_started: BTreeMap<Pid, Instant>,
+}
+
+#[cfg(feature = "proc")]
+impl ProcHistory {
+    pub(crate) fn new(capacity: usize, started: Instant) -> Self {
+        ProcHistory {
+            log: EventLog::new(capacity, started),
+            first_started: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, at: Instant, event: ProcEvent) {
+        if let ProcEvent::Started(info) = &event {
+            self.first_started.entry(info.pid).or_insert(at);
+        }
+        self.log.record(at, event);
+    }
+
+    /// The events held, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &RecordedEvent<ProcEvent>> {
+        self.log.events.iter()
+    }
+
+    /// How many events have been dropped because the history was full
+    pub fn dropped(&self) -> usize {
+        self.log.dropped
+    }
+
+    /// The most events the history holds at once
+    pub fn capacity(&self) -> usize {
+        self.log.capacity
+    }
+
+    /// When a process with `pid` was first seen, even if that event has since been dropped
+    pub fn first_started(&self, pid: Pid) -> Option<Instant> {
+        self.first_started.get(&pid).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP: ProtocolPort = ProtocolPort::Tcp(8080);
+    const UDP: ProtocolPort = ProtocolPort::Udp(5353);
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    /// TCP bound at 100ms, UDP bound at 200ms and released at 300ms, observed until 500ms
+    fn history(capacity: usize) -> (Instant, PortHistory) {
+        let start = Instant::now();
+        let mut history = PortHistory::new(capacity, start);
+        history.record(at(start, 100), PortEvent::PortBound(TCP));
+        history.record(at(start, 200), PortEvent::PortBound(UDP));
+        history.record(at(start, 300), PortEvent::PortReleased(UDP));
+        history.observed(at(start, 500));
+
+        (start, history)
+    }
+
+    #[test]
+    fn records_events_with_their_times() {
+        let (start, history) = history(10);
+
+        assert_eq!(
+            vec![
+                (Duration::from_millis(100), PortEvent::PortBound(TCP)),
+                (Duration::from_millis(200), PortEvent::PortBound(UDP)),
+                (Duration::from_millis(300), PortEvent::PortReleased(UDP)),
+            ],
+            history
+                .events()
+                .map(|r| (r.elapsed, r.event.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(at(start, 100)), history.first_bound(TCP));
+        assert!(history.ever_bound(UDP));
+        assert!(!history.ever_bound(ProtocolPort::Tcp(9090)));
+        assert_eq!(0, history.dropped());
+    }
+
+    #[test]
+    fn continuously_bound() {
+        let (start, history) = history(10);
+
+        assert!(history.was_continuously_bound(TCP, at(start, 100)..at(start, 500)));
+        assert!(!history.was_continuously_bound(TCP, at(start, 50)..at(start, 500)));
+        assert!(history.was_continuously_bound(UDP, at(start, 200)..at(start, 300)));
+        assert!(!history.was_continuously_bound(UDP, at(start, 200)..at(start, 301)));
+        // Nothing is known after the last observation
+        assert!(!history.was_continuously_bound(TCP, at(start, 100)..at(start, 501)));
+    }
+
+    #[test]
+    fn exiting_releases_every_port() {
+        let (start, mut history) = history(10);
+        history.record(at(start, 600), PortEvent::ProcessExited);
+        history.observed(at(start, 600));
+
+        assert!(history.was_continuously_bound(TCP, at(start, 100)..at(start, 600)));
+        assert!(!history.was_continuously_bound(TCP, at(start, 100)..at(start, 601)));
+    }
+
+    #[test]
+    fn full_history_drops_the_oldest_events() {
+        let (start, history) = history(2);
+
+        assert_eq!(1, history.dropped());
+        assert_eq!(2, history.capacity());
+        assert_eq!(
+            vec![PortEvent::PortBound(UDP), PortEvent::PortReleased(UDP)],
+            history
+                .events()
+                .map(|r| r.event.clone())
+                .collect::<Vec<_>>()
+        );
+
+        // The dropped bind is still known, both as the first bind and as the state before the events held
+        assert_eq!(Some(at(start, 100)), history.first_bound(TCP));
+        assert!(history.was_continuously_bound(TCP, at(start, 100)..at(start, 500)));
+        // but nothing before it is
+        assert!(!history.was_continuously_bound(TCP, at(start, 99)..at(start, 500)));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_no_events() {
+        let (start, history) = history(0);
+
+        assert_eq!(0, history.events().count());
+        assert_eq!(3, history.dropped());
+        assert_eq!(Some(at(start, 100)), history.first_bound(TCP));
+        assert!(history.was_continuously_bound(TCP, at(start, 300)..at(start, 500)));
+        assert!(!history.was_continuously_bound(UDP, at(start, 300)..at(start, 500)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serializes_the_events_held() {
+        let (_, history) = history(2);
+
+        assert_eq!(
+            serde_json::json!({
+                "capacity": 2,
+                "dropped": 1,
+                "events": [
+                    {"elapsed": {"secs": 0, "nanos": 200_000_000}, "event": {"PortBound": {"protocol": "udp", "port": 5353}}},
+                    {"elapsed": {"secs": 0, "nanos": 300_000_000}, "event": {"PortReleased": {"protocol": "udp", "port": 5353}}},
+                ],
+            }),
+            serde_json::to_value(&history).unwrap()
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Properties which [crate::port_filters::apply] and [crate::proc_filters::apply] must have for any config and any
+//! ports or processes, for property-based tests with [proptest].
+//!
+//! Each check runs a config against some input and fails with a [TestCaseError] describing the property which didn't
+//! hold, so that it can be used with `?` inside [proptest::proptest!]. The strategies in [crate::strategies] generate
+//! suitable inputs, and checking your own configs against the same properties catches a config which is filtered
+//! differently than it looks, such as a port range which is empty.
+
+use crate::config::PortQueryConfig;
+use crate::error::ProcCtlError;
+use crate::types::{PortInfo, ProtocolPort};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+/// Check that filtering `ports` with `config` is consistent:
+///
+/// - Every port found is one of `ports`, with its address families merged unless `split_families` is set.
+/// - Merging address families neither drops nor repeats a socket, and never lists a family twice for one port.
+/// - The ports are found in the order they were given, or sorted by protocol and port if `sorted` is set.
+/// - `expect_min_num_ports` fails with [ProcCtlError::TooFewPorts], listing the ports found, exactly when fewer than
+///   that many ports pass the filters, and `expect_max_num_ports` fails with [ProcCtlError::TooManyPorts] exactly when
+///   more do.
+/// - A config which meets its expectations finds the same ports as one without them.
+/// - The config and the ports serialize and deserialize to what they were.
+pub fn check_port_filters(
+    config: &PortQueryConfig,
+    ports: &[PortInfo],
+) -> Result<(), TestCaseError> {
+    check_port_serde(config, ports)?;
+    if config.sorted {
+        let unsorted = PortQueryConfig {
+            sorted: false,
+            ..config.clone()
+        };
+        check_sorting(config, &unsorted, ports)?;
+        return check_port_filters(&unsorted, ports);
+    }
+
+    let filters = without_expectations(config);
+    let found = match crate::port_filters::apply(&filters, ports.to_vec()) {
+        Ok(found) => found,
+        Err(ProcCtlError::ConfigurationError(_)) => {
+            prop_assert!(
+                matches!(
+                    crate::port_filters::apply(config, ports.to_vec()),
+                    Err(ProcCtlError::ConfigurationError(_))
+                ),
+                "a config whose filters can't match must always fail"
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(TestCaseError::fail(format!("filtering failed: {}", e))),
+    };
+
+    let split = PortQueryConfig {
+        split_families: true,
+        ..filters.clone()
+    };
+    let sockets = crate::port_filters::apply(&split, ports.to_vec())
+        .map_err(|e| TestCaseError::fail(format!("filtering with split families failed: {}", e)))?;
+    prop_assert!(
+        is_subsequence(&sockets, ports),
+        "sockets found with split families must be some of those given, in order: {:?}",
+        sockets
+    );
+
+    for info in &found {
+        prop_assert!(
+            info.families.windows(2).all(|f| f[0] < f[1]),
+            "families must be in order and never repeated: {:?}",
+            info
+        );
+        prop_assert!(
+            info.families.contains(&info.family),
+            "families must include the family of the port: {:?}",
+            info
+        );
+        let socket = PortInfo {
+            families: vec![info.family],
+            ..info.clone()
+        };
+        prop_assert!(
+            sockets.contains(&socket),
+            "each port must be one of the sockets which passed the filters: {:?}",
+            info
+        );
+    }
+    let merged_count = found.iter().map(|info| info.families.len()).sum::<usize>();
+    prop_assert_eq!(
+        sockets.len(),
+        merged_count,
+        "merging families must keep every socket exactly once"
+    );
+    if config.split_families {
+        prop_assert_eq!(&sockets, &found);
+    }
+
+    check_min_num_ports(&filters, ports, &found)?;
+    check_max_num_ports(&filters, ports, &found)?;
+
+    if let Ok(checked) = crate::port_filters::apply(config, ports.to_vec()) {
+        prop_assert_eq!(
+            found,
+            checked,
+            "expectations which are met must not change the ports found"
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that `narrow`, which only adds to or tightens the filters of `wide`, never finds a socket which `wide`
+/// doesn't. Expectations are not checked, since a narrower filter can fail an expectation which a wider one meets.
+pub fn check_narrowing(
+    wide: &PortQueryConfig,
+    narrow: &PortQueryConfig,
+    ports: &[PortInfo],
+) -> Result<(), TestCaseError> {
+    let split = |config: &PortQueryConfig| PortQueryConfig {
+        split_families: true,
+        sorted: false,
+        ..without_expectations(config)
+    };
+    let narrow_found = match crate::port_filters::apply(&split(narrow), ports.to_vec()) {
+        Ok(found) => found,
+        // Filters which can't match anything find nothing, which is narrower than anything
+        Err(ProcCtlError::ConfigurationError(_)) => return Ok(()),
+        Err(e) => return Err(TestCaseError::fail(format!("filtering failed: {}", e))),
+    };
+    let wide_found = match crate::port_filters::apply(&split(wide), ports.to_vec()) {
+        Ok(found) => found,
+        Err(ProcCtlError::ConfigurationError(_)) => {
+            prop_assert!(
+                narrow_found.is_empty(),
+                "narrowing filters which can't match must not match anything: {:?}",
+                narrow_found
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(TestCaseError::fail(format!("filtering failed: {}", e))),
+    };
+
+    prop_assert!(
+        is_subsequence(&narrow_found, &wide_found),
+        "narrower filters found {:?}, which aren't all in {:?}",
+        narrow_found,
+        wide_found
+    );
+
+    Ok(())
+}
+
+/// Check that `sorted` finds the same ports as `unsorted`, sorted by protocol and port
+fn check_sorting(
+    sorted: &PortQueryConfig,
+    unsorted: &PortQueryConfig,
+    ports: &[PortInfo],
+) -> Result<(), TestCaseError> {
+    match (
+        crate::port_filters::apply(sorted, ports.to_vec()),
+        crate::port_filters::apply(unsorted, ports.to_vec()),
+    ) {
+        (Ok(sorted_found), Ok(found)) => {
+            prop_assert!(
+                sorted_found.windows(2).all(|p| p[0].port <= p[1].port),
+                "ports must be sorted by protocol and port: {:?}",
+                sorted_found
+            );
+            prop_assert_eq!(sorted_found.len(), found.len());
+            for info in &found {
+                prop_assert!(
+                    sorted_found.contains(info),
+                    "sorting must keep every port: {:?}",
+                    info
+                );
+            }
+        }
+        (Err(sorted_err), Err(err)) => prop_assert_eq!(sorted_err.code(), err.code()),
+        (sorted_found, found) => {
+            return Err(TestCaseError::fail(format!(
+                "sorting must not change whether the query succeeds, got {:?} and {:?}",
+                sorted_found, found
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `expect_min_num_ports` fails exactly when fewer ports than it asks for were `found` by `filters`
+fn check_min_num_ports(
+    filters: &PortQueryConfig,
+    ports: &[PortInfo],
+    found: &[PortInfo],
+) -> Result<(), TestCaseError> {
+    for expected in [0, found.len(), found.len() + 1] {
+        let with_min = PortQueryConfig {
+            expect_min_num_ports: Some(expected),
+            ..filters.clone()
+        };
+        match crate::port_filters::apply(&with_min, ports.to_vec()) {
+            Ok(checked) => prop_assert!(
+                checked.len() >= expected,
+                "expecting {} ports must fail with {} found",
+                expected,
+                checked.len()
+            ),
+            Err(ProcCtlError::TooFewPorts(listed, num)) => {
+                prop_assert!(
+                    found.len() < expected,
+                    "expecting {} ports must pass with {} found",
+                    expected,
+                    found.len()
+                );
+                prop_assert_eq!(expected, num);
+                prop_assert_eq!(
+                    found.iter().map(|p| p.port).collect::<Vec<ProtocolPort>>(),
+                    listed
+                );
+            }
+            Err(e) => {
+                return Err(TestCaseError::fail(format!(
+                    "expecting {} ports failed with {}",
+                    expected, e
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `expect_max_num_ports` fails exactly when more ports than it allows were `found` by `filters`
+fn check_max_num_ports(
+    filters: &PortQueryConfig,
+    ports: &[PortInfo],
+    found: &[PortInfo],
+) -> Result<(), TestCaseError> {
+    for allowed in [found.len().saturating_sub(1), found.len()] {
+        let with_max = PortQueryConfig {
+            expect_max_num_ports: Some(allowed),
+            ..filters.clone()
+        };
+        match crate::port_filters::apply(&with_max, ports.to_vec()) {
+            Ok(checked) => prop_assert!(
+                checked.len() <= allowed,
+                "allowing {} ports must fail with {} found",
+                allowed,
+                checked.len()
+            ),
+            Err(ProcCtlError::TooManyPorts(listed, num)) => {
+                prop_assert!(
+                    found.len() > allowed,
+                    "allowing {} ports must pass with {} found",
+                    allowed,
+                    found.len()
+                );
+                prop_assert_eq!(allowed, num);
+                prop_assert_eq!(
+                    found.iter().map(|p| p.port).collect::<Vec<ProtocolPort>>(),
+                    listed
+                );
+            }
+            Err(e) => {
+                return Err(TestCaseError::fail(format!(
+                    "allowing {} ports failed with {}",
+                    allowed, e
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a port config and ports survive being serialized and deserialized
+fn check_port_serde(config: &PortQueryConfig, ports: &[PortInfo]) -> Result<(), TestCaseError> {
+    let json = serde_json::to_string(config).map_err(|e| TestCaseError::fail(e.to_string()))?;
+    let loaded = serde_json::from_str::<PortQueryConfig>(&json)
+        .map_err(|e| TestCaseError::fail(format!("{} from {}", e, json)))?;
+    prop_assert_eq!(config, &loaded);
+
+    let json = serde_json::to_string(ports).map_err(|e| TestCaseError::fail(e.to_string()))?;
+    let loaded = serde_json::from_str::<Vec<PortInfo>>(&json)
+        .map_err(|e| TestCaseError::fail(format!("{} from {}", e, json)))?;
+    prop_assert_eq!(ports, &loaded[..]);
+
+    Ok(())
+}
+
+/// The config with its filters and none of its expectations
+fn without_expectations(config: &PortQueryConfig) -> PortQueryConfig {
+    PortQueryConfig {
+        expect_min_num_ports: None,
+        expect_max_num_ports: None,
+        expect_ports: None,
+        expect_backlog_at_least: None,
+        forbid_ports_except: None,
+        ..config.clone()
+    }
+}
+
+/// Whether every item of `items` is in `all`, in the same order
+fn is_subsequence<T: PartialEq>(items: &[T], all: &[T]) -> bool {
+    let mut all = all.iter();
+    items.iter().all(|item| all.any(|other| other == item))
+}
+
+#[cfg(feature = "proc")]
+pub use self::processes::*;
+
+#[cfg(feature = "proc")]
+mod processes {
+    use crate::config::ProcQueryConfig;
+    use crate::error::ProcCtlError;
+    use crate::proc_query::ProcInfo;
+    use proptest::prelude::*;
+    use proptest::test_runner::TestCaseError;
+
+    /// Check that filtering `processes` with `config` is consistent:
+    ///
+    /// - Every process found is one of `processes`, unchanged, in the order they were given.
+    /// - A config which meets its expectations finds the same processes as one without them.
+    /// - Requiring a name, or a name of the parent, never finds a process which isn't found without it.
+    /// - The config and the processes serialize and deserialize to what they were.
+    pub fn check_proc_filters(
+        config: &ProcQueryConfig,
+        processes: &[ProcInfo],
+    ) -> Result<(), TestCaseError> {
+        check_proc_serde(config, processes)?;
+
+        let filters = ProcQueryConfig {
+            expect_nofile_at_least: None,
+            ..config.clone()
+        };
+        let found = crate::proc_filters::apply(&filters, processes.to_vec())
+            .map_err(|e| TestCaseError::fail(format!("filtering failed: {}", e)))?;
+
+        let as_json = |processes: &[ProcInfo]| {
+            processes
+                .iter()
+                .map(|p| serde_json::to_value(p).unwrap_or_default())
+                .collect::<Vec<_>>()
+        };
+        let given = as_json(processes);
+        let found_json = as_json(&found);
+        prop_assert!(
+            super::is_subsequence(&found_json, &given),
+            "processes found must be some of those given, in order and unchanged: {:?}",
+            found
+        );
+
+        match crate::proc_filters::apply(config, processes.to_vec()) {
+            Ok(checked) => prop_assert_eq!(&found_json, &as_json(&checked)),
+            Err(ProcCtlError::LimitTooLow(_)) => prop_assert!(
+                config.expect_nofile_at_least.is_some(),
+                "only an expected limit can fail with a limit which is too low"
+            ),
+            Err(e) => return Err(TestCaseError::fail(format!("expectations failed: {}", e))),
+        }
+
+        for narrower in [
+            ProcQueryConfig {
+                process_name: filters.process_name.clone().or(Some("server".to_string())),
+                ..filters.clone()
+            },
+            ProcQueryConfig {
+                parent_name: filters
+                    .parent_name
+                    .clone()
+                    .or(Some("supervisor".to_string())),
+                ..filters.clone()
+            },
+        ] {
+            let narrow_found = crate::proc_filters::apply(&narrower, processes.to_vec())
+                .map_err(|e| TestCaseError::fail(format!("filtering failed: {}", e)))?;
+            prop_assert!(
+                super::is_subsequence(&as_json(&narrow_found), &found_json),
+                "a narrower config found {:?}, which aren't all in {:?}",
+                narrow_found,
+                found
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check that a process config and processes survive being serialized and deserialized
+    fn check_proc_serde(
+        config: &ProcQueryConfig,
+        processes: &[ProcInfo],
+    ) -> Result<(), TestCaseError> {
+        let json = serde_json::to_string(config).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        let loaded = serde_json::from_str::<ProcQueryConfig>(&json)
+            .map_err(|e| TestCaseError::fail(format!("{} from {}", e, json)))?;
+        prop_assert_eq!(config, &loaded);
+
+        let json =
+            serde_json::to_value(processes).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        let loaded = serde_json::from_value::<Vec<ProcInfo>>(json.clone())
+            .map_err(|e| TestCaseError::fail(format!("{} from {}", e, json)))?;
+        let reloaded =
+            serde_json::to_value(&loaded).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assert_eq!(json, reloaded);
+
+        Ok(())
+    }
+}
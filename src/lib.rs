@@ -1,15 +1,178 @@
 #![deny(missing_docs)]
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
 #![doc = include_str!("../README.md")]
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod assertions;
+#[cfg(any(test, feature = "test-util"))]
+pub mod binder;
+#[cfg(feature = "proc")]
+mod capabilities;
+mod clock;
 mod common;
+#[cfg(feature = "serde")]
+mod config;
+mod connection_query;
 mod error;
+#[cfg(feature = "proc")]
+mod exe_filter;
+#[cfg(all(feature = "async", feature = "proc"))]
+mod exit_watch;
+#[cfg(feature = "proc")]
+pub mod expectations;
+#[cfg(feature = "serde")]
+mod export;
+#[cfg(any(all(target_os = "windows", feature = "windows-firewall"), test))]
+mod firewall;
+#[cfg(any(feature = "duct", feature = "assert-cmd"))]
+mod handles;
+#[cfg(feature = "async")]
+pub mod history;
+#[cfg(feature = "proptest")]
+pub mod invariants;
+#[cfg(feature = "proc")]
+mod limits;
+mod monitor;
+#[cfg(target_os = "linux")]
+mod multicast;
+#[cfg(feature = "proc")]
+mod names;
+#[cfg(feature = "proc")]
+mod namespaces;
+mod pid;
+#[cfg(target_os = "linux")]
+mod pidfd;
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+mod pipeline;
+pub mod port_filters;
 mod port_query;
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+mod ports_for;
+mod probe;
+#[cfg(feature = "proc")]
+pub mod proc_filters;
 #[cfg(feature = "proc")]
 mod proc_query;
+#[cfg(target_os = "linux")]
+mod proc_scan;
+#[cfg(all(feature = "serde", feature = "proc", target_os = "linux"))]
+mod raw_sockets;
+mod re_resolve;
+mod reconcile;
+mod release;
+pub mod results;
+#[cfg(any(feature = "resilience", feature = "async"))]
+mod retrying;
+#[cfg(any(test, feature = "test-util"))]
+pub mod samples;
+mod self_check;
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+mod service;
+#[cfg(feature = "exe-hash")]
+mod sha256;
+mod simple;
+#[cfg(all(feature = "serde", feature = "proc"))]
+mod snapshot;
+#[cfg(target_os = "linux")]
+mod sock_diag;
+#[cfg(target_os = "linux")]
+mod socket_owners;
+#[cfg(any(feature = "resilience", feature = "async"))]
+mod stall;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+mod time;
+mod tool;
+#[cfg(all(feature = "proc", target_os = "windows"))]
+mod toolhelp;
 mod types;
+mod unix_sockets;
+mod wait;
+mod watch;
 
-pub use crate::error::{ProcCtlError, ProcCtlResult};
+#[cfg(feature = "proc")]
+pub use crate::capabilities::Capabilities;
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::clock::ManualClock;
+#[cfg(feature = "async")]
+pub use crate::clock::SleepFuture;
+pub use crate::clock::{Clock, SystemClock};
+#[cfg(all(feature = "serde", feature = "proc"))]
+pub use crate::config::ProcQueryConfig;
+#[cfg(feature = "serde")]
+pub use crate::config::{PortQueryConfig, Protocol, RetryConfig};
+pub use crate::connection_query::ConnectionQuery;
+pub use crate::error::{ErrorKind, ProcCtlError, ProcCtlResult};
+#[cfg(feature = "serde")]
+pub use crate::export::ExportFormat;
+#[cfg(feature = "assert-cmd")]
+pub use crate::handles::SpawnedChildExt;
+#[cfg(feature = "proc")]
+pub use crate::limits::{Limit, LimitShortfall, ProcLimits};
+pub use crate::monitor::ForbiddenPorts;
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+pub use crate::pipeline::{Pipeline, PipelineFailure, PipelineMatch, PipelineStage, StageFailure};
+pub use crate::port_query::MultipleMatchPolicy;
 pub use crate::port_query::PortQuery;
+#[cfg(all(
+    feature = "proc",
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+pub use crate::ports_for::ports_for_with_retry;
+#[cfg(all(
+    feature = "proc",
+    feature = "resilience",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+pub use crate::ports_for::ports_for_with_retry_sync;
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+pub use crate::ports_for::{ports_for, ProcessPorts};
+pub use crate::probe::is_port_in_use;
+#[cfg(all(feature = "proc", feature = "async"))]
+pub use crate::proc_query::check_pids_async;
 #[cfg(feature = "proc")]
-pub use crate::proc_query::{ProcInfo, ProcQuery};
+pub use crate::proc_query::{
+    check_pids, info_for_child, ChildrenShortfall, FilterKind, MatchField, PidStatus, ProcInfo,
+    ProcInfoBuilder, ProcQuery, ProcReport, ProcSelector, SkipReason, SkippedProcess,
+};
+pub use crate::re_resolve::{ExitedDuringResolution, ResolutionAttempt};
+pub use crate::reconcile::{Reconciler, ReconcilerHandle, Violation};
+pub use crate::release::PortRelease;
+pub use crate::self_check::{self_check, Capability, CapabilityReport};
+#[cfg(target_os = "linux")]
+pub use crate::service::systemd_unit_pid;
+#[cfg(target_os = "windows")]
+pub use crate::service::windows_service_pid;
+#[cfg(feature = "exe-hash")]
+pub use crate::sha256::file_sha256;
+pub use crate::simple::{wait_for_ports, wait_for_tcp_port};
+#[cfg(feature = "async")]
+pub use crate::simple::{wait_for_ports_async, wait_for_tcp_port_async};
+#[cfg(all(feature = "serde", feature = "proc"))]
+pub use crate::snapshot::{CaptureOptions, CapturedSockets, Redaction, RuntimeInfo, Snapshot};
+pub use crate::tool::{SystemToolRunner, ToolRunner};
 pub use crate::types::*;
+pub use crate::unix_sockets::{UnixSocket, UnixSocketAddr};
+pub use crate::wait::{ObservationSummary, WaitHistory, WaitOptions, WaitOutcome, WaitStrategy};
+pub use crate::watch::{PortEvent, PortWatcher};
+#[cfg(feature = "async")]
+pub use crate::watch::{PortEvents, PortsOnly};
+#[cfg(all(feature = "async", feature = "proc"))]
+pub use crate::watch::{ProcEvent, ProcEvents, StartedOnly};
+/// The version of sysinfo which [ProcInfo] converts from, re-exported so that code moving to this crate from sysinfo
+/// uses the same version
+#[cfg(feature = "proc")]
+pub use sysinfo;
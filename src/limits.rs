@@ -0,0 +1,187 @@
+//! The resource limits of a process, read from `/proc/<pid>/limits` on Linux.
+//!
+//! The limits file has a line for each resource with its soft and hard limit and their units, such as
+//! `Max open files            1024                 524288               files`. A limit which is not set is shown as
+//! `unlimited`. The soft limit is the one the kernel enforces, and the process can raise it as far as the hard limit.
+
+use crate::types::Pid;
+
+/// The soft and hard value of one resource limit, where `None` is unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Limit {
+    /// The limit the kernel enforces
+    pub soft: Option<u64>,
+    /// The highest the process can raise [Limit::soft] to without privileges
+    pub hard: Option<u64>,
+}
+
+impl Limit {
+    /// Create a limit, for use as a test fixture
+    pub fn new(soft: Option<u64>, hard: Option<u64>) -> Self {
+        Limit { soft, hard }
+    }
+
+    /// Whether the soft limit is unlimited or at least `value`
+    pub fn is_at_least(&self, value: u64) -> bool {
+        self.soft.map_or(true, |soft| soft >= value)
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: Option<u64>| value.map_or("unlimited".to_string(), |v| v.to_string());
+        write!(f, "{} (hard {})", show(self.soft), show(self.hard))
+    }
+}
+
+/// The resource limits of a process on Linux, see [crate::ProcInfo::limits]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProcLimits {
+    /// The number of files the process can have open, `RLIMIT_NOFILE`. Each socket counts as a file.
+    pub open_files: Limit,
+    /// The number of processes the user of the process can have, `RLIMIT_NPROC`
+    pub processes: Limit,
+    /// The size of the virtual memory of the process in bytes, `RLIMIT_AS`
+    pub address_space: Limit,
+}
+
+impl ProcLimits {
+    /// Create the limits of a process, for use as a test fixture
+    pub fn new(open_files: Limit, processes: Limit, address_space: Limit) -> Self {
+        ProcLimits {
+            open_files,
+            processes,
+            address_space,
+        }
+    }
+}
+
+/// The details of a [crate::ProcCtlError::LimitTooLow] failure
+#[derive(Debug)]
+pub struct LimitShortfall {
+    /// The process whose limit was too low
+    pub process: crate::ProcInfo,
+    /// The resource, as named in the limits file, such as `Max open files`
+    pub resource: String,
+    /// The lowest soft limit which was expected
+    pub required: u64,
+    /// The limit of the process, or `None` if it could not be read
+    pub found: Option<Limit>,
+}
+
+impl std::fmt::Display for LimitShortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} (pid {}) should be at least {} but ",
+            self.resource, self.process.name, self.process.pid, self.required
+        )?;
+        match &self.found {
+            Some(limit) => write!(f, "is {}", limit),
+            None => write!(f, "could not be read"),
+        }
+    }
+}
+
+/// The name of the open files limit in the limits file
+pub(crate) const OPEN_FILES: &str = "Max open files";
+
+#[cfg(target_os = "linux")]
+pub(crate) fn read(pid: Pid) -> Option<ProcLimits> {
+    let limits = std::fs::read_to_string(format!("/proc/{}/limits", pid)).ok()?;
+    parse_limits(&limits)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read(_pid: Pid) -> Option<ProcLimits> {
+    None
+}
+
+/// Read the limits from the contents of `/proc/<pid>/limits`, failing if any of them is missing
+#[cfg(any(target_os = "linux", test))]
+fn parse_limits(limits: &str) -> Option<ProcLimits> {
+    let limit = |name: &str, unit: &str| {
+        limits.lines().find_map(|line| {
+            let mut values = line.strip_prefix(name)?.split_whitespace();
+            let mut value = || match values.next()? {
+                "unlimited" => Some(None),
+                value => value.parse().ok().map(Some),
+            };
+            let limit = Limit::new(value()?, value()?);
+
+            (values.next() == Some(unit)).then_some(limit)
+        })
+    };
+
+    Some(ProcLimits {
+        open_files: limit(OPEN_FILES, "files")?,
+        processes: limit("Max processes", "processes")?,
+        address_space: limit("Max address space", "bytes")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!("../tests/fixtures/limits/", $name, ".txt"))
+        };
+    }
+
+    #[test]
+    fn default_limits() {
+        let limits = parse_limits(fixture!("default")).unwrap();
+
+        assert_eq!(Limit::new(Some(1024), Some(524288)), limits.open_files);
+        assert_eq!(Limit::new(Some(127431), Some(127431)), limits.processes);
+        assert_eq!(Limit::new(None, None), limits.address_space);
+    }
+
+    #[test]
+    fn constrained_limits() {
+        let limits = parse_limits(fixture!("constrained")).unwrap();
+
+        assert_eq!(Limit::new(Some(256), Some(4096)), limits.open_files);
+        assert_eq!(Limit::new(Some(512), None), limits.processes);
+        assert_eq!(Limit::new(Some(4294967296), None), limits.address_space);
+        assert!(!limits.open_files.is_at_least(4096));
+        assert!(limits.processes.is_at_least(512));
+    }
+
+    #[test]
+    fn unlimited_is_at_least_anything() {
+        assert!(Limit::new(None, None).is_at_least(u64::MAX));
+        assert_eq!(
+            "unlimited (hard unlimited)",
+            Limit::new(None, None).to_string()
+        );
+        assert_eq!(
+            "1024 (hard 4096)",
+            Limit::new(Some(1024), Some(4096)).to_string()
+        );
+    }
+
+    #[test]
+    fn missing_or_malformed_limits_are_not_read() {
+        let without_address_space = fixture!("default")
+            .lines()
+            .filter(|line| !line.starts_with("Max address space"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(None, parse_limits(&without_address_space));
+
+        let bad_value = fixture!("default").replace("1024                 524288", "many  524288");
+        assert_eq!(None, parse_limits(&bad_value));
+
+        // A limit in other units than expected is from a kernel this was not written for
+        let other_units =
+            fixture!("default").replace("524288               files", "524288               bytes");
+        assert_eq!(None, parse_limits(&other_units));
+    }
+}
@@ -0,0 +1,130 @@
+//! Checking that a process never binds a port outside an allowlist, see [crate::PortQuery::monitor_for].
+//!
+//! Unlike a [crate::Reconciler], which reports each change and carries on, monitoring fails on the first sample which
+//! finds a forbidden port. It is meant for tests which run a scenario and must fail as soon as anything unexpected is
+//! bound, with a record of exactly what was seen at that moment.
+
+use crate::clock::Clock;
+use crate::error::ProcCtlResult;
+use crate::time::Deadline;
+use crate::types::PortInfo;
+use std::time::{Duration, SystemTime};
+
+/// Ports found outside the allowlist set with [crate::PortQuery::forbid_ports_except]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ForbiddenPorts {
+    /// When the ports were found
+    pub at: SystemTime,
+    /// The ports which are not allowed
+    pub unexpected: Vec<PortInfo>,
+    /// Every port found by the same query, allowed or not
+    pub snapshot: Vec<PortInfo>,
+}
+
+impl std::fmt::Display for ForbiddenPorts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ports = |ports: &[PortInfo]| ports.iter().map(|p| p.port).collect::<Vec<_>>();
+
+        write!(
+            f,
+            "forbidden ports {:?} found among {:?}",
+            ports(&self.unexpected),
+            ports(&self.snapshot)
+        )
+    }
+}
+
+/// Take a sample straight away and then once per `interval` until `duration` has passed, failing on the first sample
+/// which fails. The last sample is taken at the end of `duration`.
+pub(crate) fn monitor_sync(
+    clock: &dyn Clock,
+    duration: Duration,
+    interval: Duration,
+    mut sample: impl FnMut() -> ProcCtlResult<()>,
+) -> ProcCtlResult<()> {
+    let deadline = Deadline::start(clock, duration);
+    loop {
+        sample()?;
+
+        match deadline.next_sleep(clock, interval) {
+            Some(sleep) => clock.sleep(sleep),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Async equivalent of [monitor_sync]. Each sample still blocks while it runs.
+#[cfg(feature = "async")]
+pub(crate) async fn monitor_async(
+    clock: &dyn Clock,
+    duration: Duration,
+    interval: Duration,
+    mut sample: impl FnMut() -> ProcCtlResult<()>,
+) -> ProcCtlResult<()> {
+    let deadline = Deadline::start(clock, duration);
+    loop {
+        sample()?;
+
+        match deadline.next_sleep(clock, interval) {
+            Some(sleep) => clock.sleep_async(sleep).await,
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::error::ProcCtlError;
+
+    #[test]
+    fn samples_until_the_duration_has_passed() {
+        let clock = ManualClock::new();
+        let mut samples = 0;
+
+        monitor_sync(
+            &clock,
+            Duration::from_millis(250),
+            Duration::from_millis(100),
+            || {
+                samples += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(4, samples);
+        assert_eq!(
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(100),
+                Duration::from_millis(50)
+            ],
+            clock.sleeps()
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let clock = ManualClock::new();
+        let mut samples = 0;
+
+        let result = monitor_sync(
+            &clock,
+            Duration::from_secs(60),
+            Duration::from_secs(1),
+            || {
+                samples += 1;
+                if samples == 3 {
+                    return Err(ProcCtlError::ProcessExited(1));
+                }
+                Ok(())
+            },
+        );
+
+        assert!(matches!(result, Err(ProcCtlError::ProcessExited(1))));
+        assert_eq!(2, clock.sleeps().len());
+    }
+}
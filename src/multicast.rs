@@ -0,0 +1,129 @@
+//! The multicast groups joined in a network namespace, read from `/proc/<pid>/net/igmp` and `igmp6` on Linux, for
+//! [crate::PortInfo::multicast_groups].
+//!
+//! The kernel keeps group memberships per interface rather than per socket, and doesn't say which sockets joined a
+//! group. So the groups of a socket are every group joined on any interface of the namespace of the process holding
+//! it, including those the kernel joins itself, such as `224.0.0.1`.
+
+use crate::types::{AddressFamily, Pid};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The multicast groups of each address family joined in a network namespace
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct JoinedGroups {
+    v4: Vec<IpAddr>,
+    v6: Vec<IpAddr>,
+}
+
+impl JoinedGroups {
+    /// Read the groups joined in the network namespace of `pid`. A missing table, as when IPv6 is disabled, has no
+    /// groups.
+    pub(crate) fn of_pid(pid: Pid) -> std::io::Result<Self> {
+        let read =
+            |table: &str| match std::fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) {
+                Ok(contents) => Ok(contents),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+                Err(e) => Err(e),
+            };
+
+        Ok(JoinedGroups {
+            v4: parse_igmp(&read("igmp")?),
+            v6: parse_igmp6(&read("igmp6")?),
+        })
+    }
+
+    /// The groups of one address family, in ascending order
+    pub(crate) fn of_family(&self, family: AddressFamily) -> &[IpAddr] {
+        match family {
+            AddressFamily::Ipv4 => &self.v4,
+            AddressFamily::Ipv6 => &self.v6,
+        }
+    }
+}
+
+/// Parse the groups from `/proc/net/igmp`, where each interface is followed by an indented line for each group it has
+/// joined. The group is a 32 bit word in hex in the byte order of the machine.
+fn parse_igmp(contents: &str) -> Vec<IpAddr> {
+    let mut groups = contents
+        .lines()
+        .skip(1)
+        .filter(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let group = u32::from_str_radix(line.split_whitespace().next()?, 16).ok()?;
+            Some(Ipv4Addr::from(group.to_ne_bytes()).into())
+        })
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+    groups.dedup();
+
+    groups
+}
+
+/// Parse the groups from `/proc/net/igmp6`, which has a line for each group joined on each interface. The group is 16
+/// bytes in hex in network order.
+fn parse_igmp6(contents: &str) -> Vec<IpAddr> {
+    let mut groups = contents
+        .lines()
+        .filter_map(|line| {
+            let hex = line.split_whitespace().nth(2)?;
+            if hex.len() != 32 {
+                return None;
+            }
+
+            let mut octets = [0; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+            }
+            Some(Ipv6Addr::from(octets).into())
+        })
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+    groups.dedup();
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn igmp_groups_of_every_interface() {
+        let shown = |group: Ipv4Addr| format!("{:08X}", u32::from_ne_bytes(group.octets()));
+        let contents = format!(
+            "Idx\tDevice    : Count Querier\tGroup    Users Timer\tReporter\n\
+             1\tlo        :     1      V3\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             4\teth0      :     2      V3\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n",
+            shown(Ipv4Addr::new(224, 0, 0, 1)),
+            shown(Ipv4Addr::new(224, 0, 0, 251)),
+            shown(Ipv4Addr::new(224, 0, 0, 1)),
+        );
+
+        assert_eq!(
+            vec![
+                IpAddr::from(Ipv4Addr::new(224, 0, 0, 1)),
+                IpAddr::from(Ipv4Addr::new(224, 0, 0, 251)),
+            ],
+            parse_igmp(&contents)
+        );
+    }
+
+    #[test]
+    fn igmp6_groups_of_every_interface() {
+        let contents = "1    lo              ff020000000000000000000000000001     1 0000000C 0\n\
+                        4    eth0            ff0200000000000000000000000000fb     1 00000004 0\n\
+                        4    eth0            ff020000000000000000000000000001     1 0000000C 0\n\
+                        5    bad             ff02\n";
+
+        assert_eq!(
+            vec![
+                IpAddr::from(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1)),
+                IpAddr::from(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)),
+            ],
+            parse_igmp6(contents)
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! The canonical name of a process, which is the same for the same program on every platform, see
+//! [crate::ProcInfo::canonical_name] and [crate::ProcQuery::match_canonical_names].
+//!
+//! The name reported for a process differs between platforms: Linux gives the kernel's short name, truncated to 15
+//! characters, Windows includes the `.exe` extension, and macOS can give part of a path. The canonical name is made
+//! by these rules, in order:
+//!
+//! 1. It starts from the file name of the executable when that is known. Otherwise it is the process name, unless that
+//!    is 15 characters long and so may have been truncated, in which case the file name of the first argument of the
+//!    command line is used if it starts with the process name.
+//! 2. Anything up to the last `/` or `\` is dropped, whichever platform the name came from.
+//! 3. The ` (deleted)` which Linux adds to the executable of a process whose binary has since been deleted or
+//!    replaced is dropped.
+//! 4. A `.exe` extension is dropped, in any case. Other extensions are kept, since on Unix they are part of the name,
+//!    as in `python3.12`.
+//!
+//! The case of the name is kept. A program which is run by an interpreter, such as a script with a `#!` line, has the
+//! interpreter as its executable, so its canonical name is the name of the interpreter.
+
+/// The length the Linux kernel truncates process names to
+const TRUNCATED_NAME_LEN: usize = 15;
+
+/// The canonical name of a process with the given executable, first argument and process name
+pub(crate) fn canonical_name(exe: Option<&str>, argv0: Option<&str>, name: &str) -> String {
+    if let Some(exe) = exe.filter(|exe| !exe.is_empty()) {
+        return canonical(exe);
+    }
+
+    let name = canonical(name);
+    match argv0.map(canonical) {
+        Some(argv0) if name.len() == TRUNCATED_NAME_LEN && argv0.starts_with(&name) => argv0,
+        _ => name,
+    }
+}
+
+/// Apply rules 2 to 4 to a name, as is done to a name to match with [crate::ProcQuery::match_canonical_names]
+pub(crate) fn canonical(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let name = name.strip_suffix(" (deleted)").unwrap_or(name);
+
+    match name.len().checked_sub(4) {
+        Some(stem) if name.is_char_boundary(stem) && name[stem..].eq_ignore_ascii_case(".exe") => {
+            name[..stem].to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_shaped_names() {
+        let cases: &[(Option<&str>, Option<&str>, &str, &str)] = &[
+            // Linux
+            (
+                Some("/usr/bin/port-binder"),
+                Some("port-binder"),
+                "port-binder",
+                "port-binder",
+            ),
+            (None, None, "port-binder", "port-binder"),
+            (
+                Some("/opt/app/a-very-long-server-name"),
+                None,
+                "a-very-long-ser",
+                "a-very-long-server-name",
+            ),
+            (
+                None,
+                Some("/opt/app/a-very-long-server-name"),
+                "a-very-long-ser",
+                "a-very-long-server-name",
+            ),
+            (
+                None,
+                Some("something-else"),
+                "a-very-long-ser",
+                "a-very-long-ser",
+            ),
+            (None, Some("port-binder-2"), "port-binder", "port-binder"),
+            (Some("/usr/bin/server (deleted)"), None, "server", "server"),
+            (
+                Some("/usr/bin/python3.12"),
+                Some("python3"),
+                "script",
+                "python3.12",
+            ),
+            // Windows
+            (
+                Some(r"C:\tools\port-binder.exe"),
+                Some(r"C:\tools\port-binder.exe"),
+                "port-binder.exe",
+                "port-binder",
+            ),
+            (None, None, "port-binder.exe", "port-binder"),
+            (
+                Some(r"C:\Windows\System32\SVCHOST.EXE"),
+                None,
+                "svchost.exe",
+                "SVCHOST",
+            ),
+            (
+                Some(r"C:\tools\server.exe.bak"),
+                None,
+                "server.exe.bak",
+                "server.exe.bak",
+            ),
+            // macOS
+            (
+                Some("/Applications/Server.app/Contents/MacOS/Server"),
+                None,
+                "Server",
+                "Server",
+            ),
+            (None, None, "Contents/MacOS/port-binder", "port-binder"),
+            // An empty executable path is as good as none
+            (Some(""), None, "port-binder", "port-binder"),
+        ];
+
+        for (exe, argv0, name, expected) in cases {
+            assert_eq!(
+                *expected,
+                canonical_name(*exe, *argv0, name),
+                "exe {:?}, argv0 {:?}, name {:?}",
+                exe,
+                argv0,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn names_to_match_are_made_canonical() {
+        assert_eq!("port-binder", canonical("port-binder.exe"));
+        assert_eq!("port-binder", canonical("port-binder.EXE"));
+        assert_eq!("port-binder", canonical("/usr/bin/port-binder"));
+        assert_eq!("port-binder", canonical(r"bin\port-binder.exe"));
+        assert_eq!(".exe", canonical(".exe.exe"));
+        assert_eq!("é", canonical("é.exe"));
+        assert_eq!("", canonical(""));
+    }
+}
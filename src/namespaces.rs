@@ -0,0 +1,228 @@
+//! Which namespaces and container a process belongs to, and its root directory, read from `/proc` on Linux.
+//!
+//! The container ID is found by looking for the ID of a known container runtime in the cgroup path of the process.
+//! This covers Docker, containerd (including Kubernetes), CRI-O and Podman with both cgroup v1 and v2, but it is best
+//! effort: a runtime which names its cgroups differently is not recognised.
+
+use crate::types::Pid;
+use std::path::{Path, PathBuf};
+
+/// The namespaces and container of a process, where they could be read
+#[derive(Debug, Default)]
+pub(crate) struct Namespaces {
+    pub(crate) net: Option<u64>,
+    pub(crate) pid: Option<u64>,
+    pub(crate) mnt: Option<u64>,
+    pub(crate) root: Option<PathBuf>,
+    pub(crate) container_id: Option<String>,
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn read(pid: Pid) -> Namespaces {
+    Namespaces {
+        net: namespace_inode(pid, "net"),
+        pid: namespace_inode(pid, "pid"),
+        mnt: namespace_inode(pid, "mnt"),
+        root: root(pid),
+        container_id: container_id(pid),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read(_pid: Pid) -> Namespaces {
+    Namespaces::default()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn container_id(pid: Pid) -> Option<String> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    parse_container_id(&cgroup)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn container_id(_pid: Pid) -> Option<String> {
+    None
+}
+
+/// The root directory of a process, which is not `/` for a chrooted process. Reading the link of a process belonging
+/// to another user needs the same privileges as tracing it, so without them this is `None`.
+#[cfg(target_os = "linux")]
+pub(crate) fn root(pid: Pid) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/root", pid)).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn root(_pid: Pid) -> Option<PathBuf> {
+    None
+}
+
+/// Rewrite `path` as it is seen by a process with the root directory `root`, so `/srv/jail/bin/sh` becomes `/bin/sh`
+/// for a process chrooted to `/srv/jail`. A path outside the root is left as it is.
+pub(crate) fn make_relative_to_root(path: &mut Option<PathBuf>, root: &Path) {
+    let relative = path
+        .as_deref()
+        .and_then(|p| p.strip_prefix(root).ok())
+        .map(|rest| Path::new("/").join(rest));
+    if let Some(relative) = relative {
+        *path = Some(relative);
+    }
+}
+
+/// The namespace links in `/proc/<pid>/ns` point to names like `net:[4026531840]`, where the number is the inode
+#[cfg(target_os = "linux")]
+fn namespace_inode(pid: Pid, namespace: &str) -> Option<u64> {
+    let target = std::fs::read_link(format!("/proc/{}/ns/{}", pid, namespace)).ok()?;
+    target
+        .to_str()?
+        .strip_prefix(namespace)?
+        .strip_prefix(":[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+/// Find a container ID in the contents of `/proc/<pid>/cgroup`.
+///
+/// Each line is `hierarchy:controllers:path`, with a single `0::path` line on cgroup v2. Runtimes name the cgroup of a
+/// container after its 64 character hex ID, either on its own (`/docker/<id>`) or as a systemd scope with a prefix
+/// (`/system.slice/docker-<id>.scope`). The deepest matching path segment is used, since a container's cgroup is
+/// nested inside those of its pod or host.
+#[cfg(any(target_os = "linux", test))]
+fn parse_container_id(cgroup: &str) -> Option<String> {
+    const PREFIXES: &[&str] = &[
+        "docker-",
+        "cri-containerd-",
+        "crio-",
+        "libpod-",
+        "containerd-",
+    ];
+
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        path.rsplit('/').find_map(|segment| {
+            let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+            let id = PREFIXES
+                .iter()
+                .find_map(|prefix| segment.strip_prefix(prefix))
+                .unwrap_or(segment);
+
+            (id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())).then(|| id.to_string())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4b2c1a9e8d7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a";
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!("../tests/fixtures/cgroup/", $name, ".txt"))
+        };
+    }
+
+    #[test]
+    fn docker() {
+        for cgroup in [
+            fixture!("docker_v1"),
+            fixture!("docker_v2_systemd"),
+            fixture!("docker_v2_cgroupfs"),
+        ] {
+            assert_eq!(
+                Some(ID),
+                parse_container_id(cgroup).as_deref(),
+                "{}",
+                cgroup
+            );
+        }
+    }
+
+    #[test]
+    fn kubernetes() {
+        for cgroup in [
+            fixture!("kubernetes_containerd_v1"),
+            fixture!("kubernetes_containerd_v2"),
+            fixture!("crio_v2"),
+        ] {
+            assert_eq!(
+                Some(ID),
+                parse_container_id(cgroup).as_deref(),
+                "{}",
+                cgroup
+            );
+        }
+    }
+
+    #[test]
+    fn podman() {
+        assert_eq!(
+            Some(ID),
+            parse_container_id(fixture!("podman_v2")).as_deref()
+        );
+    }
+
+    #[test]
+    fn paths_relative_to_a_chroot() {
+        let root = Path::new("/srv/jail");
+
+        let mut exe = Some(PathBuf::from("/srv/jail/usr/bin/server"));
+        make_relative_to_root(&mut exe, root);
+        assert_eq!(Some(PathBuf::from("/usr/bin/server")), exe);
+
+        let mut cwd = Some(PathBuf::from("/srv/jail"));
+        make_relative_to_root(&mut cwd, root);
+        assert_eq!(Some(PathBuf::from("/")), cwd);
+
+        // Only whole components are stripped, and paths outside the root are kept
+        for outside in ["/srv/jailbreak/bin/sh", "/usr/bin/server"] {
+            let mut path = Some(PathBuf::from(outside));
+            make_relative_to_root(&mut path, root);
+            assert_eq!(Some(PathBuf::from(outside)), path);
+        }
+
+        let mut missing = None;
+        make_relative_to_root(&mut missing, root);
+        assert_eq!(None, missing);
+    }
+
+    #[test]
+    fn paths_relative_to_the_real_root_are_unchanged() {
+        let mut exe = Some(PathBuf::from("/usr/bin/server"));
+        make_relative_to_root(&mut exe, Path::new("/"));
+        assert_eq!(Some(PathBuf::from("/usr/bin/server")), exe);
+    }
+
+    #[test]
+    fn not_in_a_container() {
+        for cgroup in [
+            fixture!("host_v1"),
+            fixture!("host_v2"),
+            fixture!("namespaced_root_v2"),
+            fixture!("docker_daemon_v2"),
+            "",
+        ] {
+            assert_eq!(None, parse_container_id(cgroup), "{}", cgroup);
+        }
+    }
+
+    #[test]
+    fn ids_must_be_full_length_hex() {
+        assert_eq!(
+            None,
+            parse_container_id(&format!("0::/docker/{}", &ID[..63]))
+        );
+        assert_eq!(
+            None,
+            parse_container_id(&format!("0::/docker/{}", ID.replace('a', "g")))
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parser_never_panics(cgroup in "\\PC{0,200}") {
+            let _ = parse_container_id(&cgroup);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Conversions between [Pid] and the pid types used by the platform backends.
+//!
+//! Every conversion which can fail is checked here, so that an out of range pid is reported as an error rather than
+//! silently wrapping around to a different process.
+
+#[cfg(any(target_os = "linux", feature = "proc"))]
+use crate::error::{ProcCtlError, ProcCtlResult};
+#[cfg(any(target_os = "linux", feature = "proc"))]
+use crate::types::Pid;
+
+/// Convert to the signed pid used by procfs. Linux pids never exceed `i32::MAX`, so a larger value can not refer to a
+/// real process.
+#[cfg(target_os = "linux")]
+pub(crate) fn to_procfs(pid: Pid) -> ProcCtlResult<i32> {
+    i32::try_from(pid).map_err(|_| {
+        ProcCtlError::ConfigurationError(format!(
+            "pid {} is out of range, it must be at most {}",
+            pid,
+            i32::MAX
+        ))
+    })
+}
+
+/// Convert to a sysinfo pid. Depending on the platform sysinfo stores pids as a signed `pid_t` or as a `usize`, so a
+/// pid which does not survive the round trip through that type is rejected.
+#[cfg(feature = "proc")]
+pub(crate) fn to_sysinfo(pid: Pid) -> ProcCtlResult<sysinfo::Pid> {
+    let out_of_range = || {
+        ProcCtlError::ConfigurationError(format!("pid {} is out of range for this platform", pid))
+    };
+
+    let raw = usize::try_from(pid).map_err(|_| out_of_range())?;
+    let sys_pid = sysinfo::Pid::from(raw);
+    if usize::from(sys_pid) != raw {
+        return Err(out_of_range());
+    }
+
+    Ok(sys_pid)
+}
+
+/// Convert from a sysinfo pid. Pids reported by the operating system are never negative and always fit in 32 bits,
+/// so this can not truncate.
+#[cfg(feature = "proc")]
+pub(crate) fn from_sysinfo(pid: sysinfo::Pid) -> Pid {
+    pid.as_u32()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod procfs_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn procfs_pids_in_range_round_trip(pid in 0..=i32::MAX as Pid) {
+            prop_assert_eq!(pid, to_procfs(pid).unwrap() as Pid);
+        }
+
+        #[test]
+        fn procfs_pids_out_of_range_are_rejected(pid in (i32::MAX as Pid + 1)..=Pid::MAX) {
+            prop_assert!(matches!(to_procfs(pid), Err(ProcCtlError::ConfigurationError(_))));
+        }
+    }
+
+    #[test]
+    fn procfs_boundary_values() {
+        assert_eq!(0, to_procfs(0).unwrap());
+        assert_eq!(i32::MAX, to_procfs(i32::MAX as Pid).unwrap());
+        assert!(to_procfs(i32::MAX as Pid + 1).is_err());
+        assert!(to_procfs(Pid::MAX).is_err());
+    }
+}
+
+// These only convert values, so they are safe to run on any target, including 32-bit ones
+#[cfg(all(test, feature = "proc"))]
+mod sysinfo_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn sysinfo_pids_in_range_round_trip(pid in 0..=i32::MAX as Pid) {
+            prop_assert_eq!(pid, from_sysinfo(to_sysinfo(pid).unwrap()));
+        }
+    }
+
+    #[test]
+    fn sysinfo_boundary_values() {
+        for pid in [0, 1, i32::MAX as Pid] {
+            assert_eq!(pid, from_sysinfo(to_sysinfo(pid).unwrap()));
+        }
+    }
+
+    // Where sysinfo uses a signed pid_t, larger pids would wrap around to negative values
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn sysinfo_pids_above_pid_t_are_rejected() {
+        for pid in [i32::MAX as Pid + 1, Pid::MAX] {
+            assert!(matches!(
+                to_sysinfo(pid),
+                Err(ProcCtlError::ConfigurationError(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Process file descriptors on Linux.
+//!
+//! A pidfd refers to one process for as long as it is open, even after that process exits and its pid is reused, so
+//! checking a pidfd after reading from `/proc/<pid>` confirms that what was read belonged to the right process. Pidfds
+//! were added in Linux 5.3, and reading the pid back from one needs Linux 5.4.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::Pid;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::OnceLock;
+
+/// A pidfd given to a query, with its pid read on first use
+#[derive(Debug)]
+pub(crate) struct PidFd {
+    fd: OwnedFd,
+    pid: OnceLock<Result<Pid, Unresolved>>,
+}
+
+/// Why the pid of a pidfd could not be read. Both are permanent, so they are kept with the pidfd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unresolved {
+    /// The process has exited and been reaped, so the kernel no longer reports its pid
+    Exited,
+    /// The fd is not a pidfd, or the kernel is too old to report the pid of one
+    Unsupported,
+}
+
+impl PidFd {
+    pub(crate) fn new(fd: OwnedFd) -> Self {
+        PidFd {
+            fd,
+            pid: OnceLock::new(),
+        }
+    }
+
+    /// The pid of the process, which never changes for as long as the pidfd is open
+    pub(crate) fn pid(&self) -> ProcCtlResult<Pid> {
+        let pid = self.pid.get_or_init(|| {
+            let fdinfo =
+                std::fs::read_to_string(format!("/proc/self/fdinfo/{}", self.fd.as_raw_fd()))
+                    .map_err(|_| Unresolved::Unsupported)?;
+            parse_fdinfo_pid(&fdinfo)
+        });
+
+        match pid {
+            Ok(pid) => Ok(*pid),
+            Err(Unresolved::Exited) => Err(ProcCtlError::NoMatchingProcess(format!(
+                "pidfd {}, the process has exited",
+                self.fd.as_raw_fd()
+            ))),
+            Err(Unresolved::Unsupported) => Err(ProcCtlError::ConfigurationError(format!(
+                "fd {} is not a pidfd, or this kernel can't report its pid which needs Linux 5.4 or later",
+                self.fd.as_raw_fd()
+            ))),
+        }
+    }
+
+    /// Whether the process has exited. A process which has exited but not yet been waited on counts as exited.
+    pub(crate) fn has_exited(&self) -> bool {
+        has_exited(&self.fd)
+    }
+
+    /// Fail with [ProcCtlError::ProcessExited] if the process has exited, which means that anything read by pid may
+    /// have come from another process which reused it
+    pub(crate) fn ensure_running(&self) -> ProcCtlResult<()> {
+        let pid = self.pid()?;
+        if self.has_exited() {
+            return Err(ProcCtlError::ProcessExited(pid));
+        }
+
+        Ok(())
+    }
+}
+
+/// Open a pidfd for a running process
+#[cfg(any(feature = "proc", test))]
+pub(crate) fn open(pid: Pid) -> ProcCtlResult<OwnedFd> {
+    let raw_pid = crate::pid::to_procfs(pid)?;
+
+    // SAFETY: pidfd_open takes a pid and flags and has no memory safety requirements. The result is checked before it
+    // is used as an fd.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, raw_pid, 0) };
+    if fd < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::ENOSYS) => ProcCtlError::ConfigurationError(
+                "pidfds are not supported by this kernel, they need Linux 5.3 or later".to_string(),
+            ),
+            Some(libc::ESRCH) => ProcCtlError::ProcessNotFound(pid),
+            _ => ProcCtlError::IoError(e),
+        });
+    }
+
+    // SAFETY: the fd was just opened and nothing else owns it
+    Ok(unsafe { std::os::fd::FromRawFd::from_raw_fd(fd as i32) })
+}
+
+/// A pidfd becomes readable once its process has exited
+pub(crate) fn has_exited(fd: &OwnedFd) -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // SAFETY: the pointer is to a single pollfd which lives for the duration of the call
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, 0) };
+    // A pidfd can't be polled in error, so an error means the fd is unusable and the process can't be relied on
+    ready < 0 || poll_fd.revents & libc::POLLIN != 0
+}
+
+/// Read the pid from `/proc/self/fdinfo/<fd>` for a pidfd, which has a `Pid:` line that is -1 once the process has
+/// been reaped
+fn parse_fdinfo_pid(fdinfo: &str) -> Result<Pid, Unresolved> {
+    let pid = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))
+        .ok_or(Unresolved::Unsupported)?
+        .trim();
+
+    match pid.parse::<i64>() {
+        Ok(-1) => Err(Unresolved::Exited),
+        Ok(pid) => Pid::try_from(pid).map_err(|_| Unresolved::Unsupported),
+        Err(_) => Err(Unresolved::Unsupported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_is_read_from_fdinfo() {
+        let fdinfo =
+            "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nino:\t1057\nPid:\t4242\nNSpid:\t4242\n";
+        assert_eq!(Ok(4242), parse_fdinfo_pid(fdinfo));
+        assert_eq!(
+            Err(Unresolved::Exited),
+            parse_fdinfo_pid("Pid:\t-1\nNSpid:\t-1\n")
+        );
+        assert_eq!(
+            Err(Unresolved::Unsupported),
+            parse_fdinfo_pid("pos:\t0\nflags:\t02000002\n")
+        );
+    }
+
+    #[test]
+    fn pidfd_of_the_current_process() {
+        let pidfd = PidFd::new(open(std::process::id()).unwrap());
+
+        assert_eq!(std::process::id(), pidfd.pid().unwrap());
+        assert!(!pidfd.has_exited());
+        pidfd.ensure_running().unwrap();
+    }
+}
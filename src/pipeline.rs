@@ -0,0 +1,280 @@
+//! Checking a process, its children and their ports together, see [Pipeline].
+//!
+//! A readiness check often spans several levels, such as a supervisor which must have two workers which must each
+//! have a port. Each level is a stage of the pipeline, and every stage is checked against the same view of the system:
+//! the processes and their children come from one refresh of the process list, and the ports of all of them from one
+//! snapshot of the sockets, taken straight after.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::port_query::PortQuery;
+use crate::proc_query::{ProcInfo, ProcQuery};
+use crate::types::ProtocolPort;
+
+/// A stage of a [Pipeline]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PipelineStage {
+    /// Finding the processes matching the first query
+    Processes,
+    /// Finding the children of each process, see [ProcQuery::children_matching]
+    Children,
+    /// Listing the ports of each process of the last stage, see [Pipeline::each_with_ports]
+    Ports,
+}
+
+impl std::fmt::Display for PipelineStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineStage::Processes => write!(f, "processes"),
+            PipelineStage::Children => write!(f, "children"),
+            PipelineStage::Ports => write!(f, "ports"),
+        }
+    }
+}
+
+/// A process found by a [Pipeline], with what the later stages found for it
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PipelineMatch {
+    /// The process
+    pub process: ProcInfo,
+    /// The children of the process which matched, in order of pid. Empty when the pipeline has no children stage, or
+    /// for the children themselves.
+    pub children: Vec<PipelineMatch>,
+    /// The ports of the process, when the ports stage applies to it
+    pub ports: Option<Vec<ProtocolPort>>,
+}
+
+/// One failure of a stage of a [Pipeline]
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct StageFailure {
+    /// The stage which failed
+    pub stage: PipelineStage,
+    /// The process the stage was checked for, such as the parent which had too few children. `None` for
+    /// [PipelineStage::Processes].
+    pub process: Option<ProcInfo>,
+    /// Why the stage failed
+    pub error: ProcCtlError,
+}
+
+impl std::fmt::Display for StageFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.process {
+            Some(process) => write!(
+                f,
+                "{} of {} (pid {}): {}",
+                self.stage, process.name, process.pid, self.error
+            ),
+            None => write!(f, "{}: {}", self.stage, self.error),
+        }
+    }
+}
+
+/// The details of a [ProcCtlError::PipelineFailed] failure
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PipelineFailure {
+    /// Every stage which failed, for every process, in the order the stages run. There is at least one.
+    pub failures: Vec<StageFailure>,
+    /// Everything the pipeline found, including the processes which failed a later stage
+    pub found: Vec<PipelineMatch>,
+}
+
+impl std::fmt::Display for PipelineFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} pipeline checks failed:", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, " {};", failure)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A check of processes, their children and the ports of either, which passes only if every stage does. Start one
+/// with [ProcQuery::children_matching] or [ProcQuery::with_ports].
+///
+/// The first stage finds the processes matching the query, and fails if there are none. The children stage finds the
+/// children of each process matching a second query, and fails for each process which has fewer than its
+/// [ProcQuery::expect_min_num_children]. The ports stage lists the ports of each process of the last stage, which is
+/// the children when there is a children stage, and fails for each process which doesn't meet the expectations of its
+/// [PortQuery], such as [PortQuery::expect_min_num_ports].
+///
+/// Every stage is checked against one view of the system, see the module docs, and a failure lists every stage which
+/// failed for every process rather than only the first.
+///
+/// ```rust no_run
+/// use proc_ctl::ProcQuery;
+///
+/// let found = ProcQuery::new()
+///     .process_name("supervisor")
+///     .children_matching(|q| q.process_name("worker").expect_min_num_children(2))
+///     .each_with_ports(|p| p.tcp_only().expect_min_num_ports(1))
+///     .check()
+///     .unwrap();
+///
+/// for worker in &found[0].children {
+///     println!("worker {} has {:?}", worker.process.pid, worker.ports);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Pipeline {
+    processes: ProcQuery,
+    children: Option<ProcQuery>,
+    ports: Option<PortQuery>,
+}
+
+impl ProcQuery {
+    /// Start a [Pipeline] which also finds the children of each matching process. `children` is given a new query
+    /// to set the filters and [ProcQuery::expect_min_num_children] for the children of each process.
+    pub fn children_matching(self, children: impl FnOnce(ProcQuery) -> ProcQuery) -> Pipeline {
+        Pipeline {
+            processes: self,
+            children: Some(children(ProcQuery::new())),
+            ports: None,
+        }
+    }
+
+    /// Start a [Pipeline] which also lists the ports of each matching process. `ports` is given a new query to set
+    /// the filters and expectations for the ports of each process. Any process it selects is ignored.
+    pub fn with_ports(self, ports: impl FnOnce(PortQuery) -> PortQuery) -> Pipeline {
+        Pipeline {
+            processes: self,
+            children: None,
+            ports: Some(ports(PortQuery::new())),
+        }
+    }
+}
+
+impl Pipeline {
+    /// List the ports of each process of the last stage, which is each child when there is a children stage. `ports`
+    /// is given a new query to set the filters and expectations for the ports of each process.
+    pub fn each_with_ports(mut self, ports: impl FnOnce(PortQuery) -> PortQuery) -> Self {
+        self.ports = Some(ports(PortQuery::new()));
+        self
+    }
+
+    /// Run every stage once, returning what was found for each process of the first stage.
+    ///
+    /// Fails with [ProcCtlError::PipelineFailed] listing every stage which failed. An error which isn't about any one
+    /// process, such as a [ProcCtlError::ConfigurationError] or failing to read the sockets, is returned as it is.
+    pub fn check(&self) -> ProcCtlResult<Vec<PipelineMatch>> {
+        let mut failures = Vec::new();
+
+        let listed = match self.processes.list_with_children(self.children.as_ref()) {
+            Ok(listed) if listed.is_empty() => {
+                let selection = self.processes.describe_selection();
+                return Err(processes_failed(ProcCtlError::NoMatchingProcess(selection)));
+            }
+            Ok(listed) => listed,
+            Err(e @ ProcCtlError::ConfigurationError(_)) => return Err(e),
+            Err(e) => return Err(processes_failed(e)),
+        };
+
+        let mut found = Vec::with_capacity(listed.len());
+        for (process, children) in listed {
+            let children = match children {
+                Some(Ok(children)) => children,
+                Some(Err(e)) => {
+                    failures.push(StageFailure {
+                        stage: PipelineStage::Children,
+                        process: Some(process.clone()),
+                        error: e,
+                    });
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+
+            found.push(PipelineMatch {
+                process,
+                children: children.into_iter().map(PipelineMatch::of).collect(),
+                ports: None,
+            });
+        }
+
+        if let Some(port_query) = &self.ports {
+            let mut last_stage: Vec<&mut PipelineMatch> = match self.children {
+                Some(_) => found.iter_mut().flat_map(|m| &mut m.children).collect(),
+                None => found.iter_mut().collect(),
+            };
+            let processes = last_stage
+                .iter()
+                .map(|m| m.process.clone())
+                .collect::<Vec<_>>();
+            let pids = processes.iter().map(|p| p.pid).collect::<Vec<_>>();
+
+            let mut ports = port_query.ports_of_each(&pids)?;
+            crate::ports_for::fail_exited(&processes, &mut ports)?;
+
+            for (m, ports) in last_stage.iter_mut().zip(ports) {
+                match ports {
+                    Ok(ports) => m.ports = Some(ports),
+                    Err(e) => failures.push(StageFailure {
+                        stage: PipelineStage::Ports,
+                        process: Some(m.process.clone()),
+                        error: e,
+                    }),
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(found)
+        } else {
+            Err(ProcCtlError::PipelineFailed(Box::new(PipelineFailure {
+                failures,
+                found,
+            })))
+        }
+    }
+
+    /// Run [Pipeline::check] until every stage passes, making at most `count` attempts with `delay` between them.
+    /// Delays use the clock of the first query.
+    #[cfg(feature = "resilience")]
+    pub fn check_with_retry_sync(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<PipelineMatch>> {
+        crate::retrying::retry_sync(self.processes.clock().as_ref(), delay, count, || {
+            self.check()
+        })
+    }
+
+    /// Async equivalent of [Pipeline::check_with_retry_sync], with the same number of attempts
+    #[cfg(feature = "async")]
+    pub async fn check_with_retry(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<PipelineMatch>> {
+        crate::retrying::retry_async(self.processes.clock().as_ref(), delay, count, || {
+            self.check()
+        })
+        .await
+    }
+}
+
+impl PipelineMatch {
+    fn of(process: ProcInfo) -> Self {
+        PipelineMatch {
+            process,
+            children: Vec::new(),
+            ports: None,
+        }
+    }
+}
+
+/// The first stage failed, so nothing was found
+fn processes_failed(error: ProcCtlError) -> ProcCtlError {
+    ProcCtlError::PipelineFailed(Box::new(PipelineFailure {
+        failures: vec![StageFailure {
+            stage: PipelineStage::Processes,
+            process: None,
+            error,
+        }],
+        found: Vec::new(),
+    }))
+}
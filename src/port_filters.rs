@@ -0,0 +1,736 @@
+//! The filters and expectations of a [crate::PortQuery], applied to ports which have already been found.
+//!
+//! Every way of running a query, whether against the live system, a [crate::Snapshot] or each process of a
+//! [crate::Pipeline], filters and checks the ports it finds with the code in this module, so [apply] gives the same
+//! answer for ports captured elsewhere as the query would have given on the machine they were captured on.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::{AddressFamily, Port, PortInfo, ProtocolPort, TcpState};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+/// Filter `ports` and check them against the expectations of `config`, as [crate::PortQuery::execute_detailed_on]
+/// does for the ports of a [crate::Snapshot].
+///
+/// The ports are filtered by protocol, address family, [crate::PortQuery::tcp_states], [crate::PortQuery::port_range],
+/// whether they are bound to a loopback address and [crate::PortQuery::joined_group], then each address family of a port is merged into one [PortInfo] unless
+/// `split_families` is set, and then the expectations, such as `expect_min_num_ports`, are checked against what is
+/// left. The processes the config selects are not applied, since a port alone doesn't say which process has which
+/// name. Select the ports of the right processes first, or run the query against a whole snapshot with
+/// [crate::PortQuery::execute_detailed_on]. A TCP port without a [PortInfo::tcp_state] is taken to be a listener, as
+/// every TCP port captured by an earlier release was.
+///
+/// Fails with [ProcCtlError::ConfigurationError] if the filters can't match anything, or if the config verifies that
+/// ports are accepting connections, which can't be done for captured ports. Otherwise fails as the query would, for
+/// example with [ProcCtlError::TooFewPorts].
+///
+/// ```rust
+/// use proc_ctl::{AddressFamily, PortInfo, PortQueryConfig, ProtocolPort};
+///
+/// let captured = vec![
+///     PortInfo::builder().port(ProtocolPort::Tcp(8080)).family(AddressFamily::Ipv4).build(),
+///     PortInfo::builder().port(ProtocolPort::Tcp(8080)).family(AddressFamily::Ipv6).build(),
+///     PortInfo::builder().port(ProtocolPort::Udp(5353)).family(AddressFamily::Ipv4).build(),
+/// ];
+///
+/// let config: PortQueryConfig = serde_json::from_str(r#"{"protocol": "tcp"}"#).unwrap();
+/// let ports = proc_ctl::port_filters::apply(&config, captured).unwrap();
+///
+/// assert_eq!(1, ports.len());
+/// assert_eq!(vec![AddressFamily::Ipv4, AddressFamily::Ipv6], ports[0].families);
+/// ```
+#[cfg(feature = "serde")]
+pub fn apply(
+    config: &crate::PortQueryConfig,
+    ports: impl IntoIterator<Item = PortInfo>,
+) -> ProcCtlResult<Vec<PortInfo>> {
+    crate::PortQuery::from_config(config).filter_captured(ports)
+}
+
+/// The filters of a query which are applied to each port found
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PortFilter<'a> {
+    pub(crate) tcp: bool,
+    pub(crate) udp: bool,
+    pub(crate) tcp_states: &'a [TcpState],
+    pub(crate) port_range: Option<(Port, Port)>,
+    pub(crate) loopback: Option<bool>,
+    pub(crate) ipv4: bool,
+    pub(crate) ipv6: bool,
+    pub(crate) bound_after: Option<SystemTime>,
+    pub(crate) joined_group: Option<IpAddr>,
+    pub(crate) split_families: bool,
+}
+
+impl PortFilter<'_> {
+    /// Whether a port passes every filter. A port whose bind time isn't known passes the bind time filter, and a TCP
+    /// port whose state isn't known is a listener.
+    pub(crate) fn matches(&self, info: &PortInfo) -> bool {
+        let protocol = match info.port {
+            ProtocolPort::Tcp(_) => {
+                self.tcp
+                    && self
+                        .tcp_states
+                        .contains(&info.tcp_state.unwrap_or(TcpState::Listen))
+            }
+            ProtocolPort::Udp(_) => self.udp,
+        };
+        let in_range = self
+            .port_range
+            .map_or(true, |(min, max)| (min..=max).contains(&info.port.port()));
+        let loopback = self
+            .loopback
+            .map_or(true, |loopback| info.is_loopback() == loopback);
+        let family = match info.family {
+            AddressFamily::Ipv4 => self.ipv4,
+            AddressFamily::Ipv6 => self.ipv6,
+        };
+        let bound = match (&self.bound_after, &info.bound_since) {
+            (Some(after), Some(since)) => since >= after,
+            _ => true,
+        };
+
+        protocol && in_range && loopback && family && bound && self.has_joined_group(info)
+    }
+
+    /// Whether a port passes the [crate::PortQuery::joined_group] filter
+    pub(crate) fn has_joined_group(&self, info: &PortInfo) -> bool {
+        match self.joined_group {
+            Some(group) => {
+                matches!(info.port, ProtocolPort::Udp(_))
+                    && info
+                        .multicast_groups
+                        .as_ref()
+                        .is_some_and(|groups| groups.contains(&group))
+            }
+            None => true,
+        }
+    }
+
+    /// Keep the ports which pass every filter, merging the address families of each port unless they are kept apart
+    pub(crate) fn apply(&self, ports: impl IntoIterator<Item = PortInfo>) -> Vec<PortInfo> {
+        let ports = ports
+            .into_iter()
+            .filter(|info| self.matches(info))
+            .collect::<Vec<_>>();
+
+        if self.split_families {
+            ports
+        } else {
+            crate::types::merge_families(ports)
+        }
+    }
+}
+
+/// Whether a port is a listening TCP socket, which is taken to be the case for a TCP port whose state isn't known
+pub(crate) fn is_tcp_listener(info: &PortInfo) -> bool {
+    matches!(info.port, ProtocolPort::Tcp(_))
+        && info
+            .tcp_state
+            .map_or(true, |state| state == TcpState::Listen)
+}
+
+/// The expectations of a query, checked against the ports which passed its filters
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PortExpectations<'a> {
+    pub(crate) min_num_ports: Option<usize>,
+    pub(crate) max_num_ports: Option<usize>,
+    pub(crate) min_backlog: Option<u32>,
+    pub(crate) allowed_ports: Option<&'a BTreeSet<ProtocolPort>>,
+    pub(crate) expected_ports: Option<&'a BTreeSet<ProtocolPort>>,
+    pub(crate) expect_accepting: bool,
+}
+
+impl PortExpectations<'_> {
+    /// Fail with the first expectation the ports don't meet, or give the ports back if they meet them all
+    pub(crate) fn check(&self, ports: Vec<PortInfo>) -> ProcCtlResult<Vec<PortInfo>> {
+        if let Some(num) = self.min_num_ports {
+            if ports.len() < num {
+                return Err(ProcCtlError::TooFewPorts(
+                    ports.into_iter().map(|p| p.port).collect(),
+                    num,
+                ));
+            }
+        }
+
+        if let Some(num) = self.max_num_ports {
+            if ports.len() > num {
+                return Err(ProcCtlError::TooManyPorts(
+                    ports.into_iter().map(|p| p.port).collect(),
+                    num,
+                ));
+            }
+        }
+
+        if let Some(expected) = self.expected_ports {
+            let missing = expected
+                .iter()
+                .filter(|port| !ports.iter().any(|p| p.port == **port))
+                .copied()
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(ProcCtlError::MissingPorts(
+                    missing,
+                    ports.into_iter().map(|p| p.port).collect(),
+                ));
+            }
+        }
+
+        if let Some(min_backlog) = self.min_backlog {
+            let too_small = ports
+                .iter()
+                .find(|p| is_tcp_listener(p) && !p.backlog.is_some_and(|b| b >= min_backlog));
+            if let Some(info) = too_small {
+                return Err(ProcCtlError::BacklogTooSmall(
+                    info.port,
+                    info.backlog,
+                    min_backlog,
+                ));
+            }
+        }
+
+        self.check_allowed(&ports)?;
+
+        if self.expect_accepting {
+            let refused = ports
+                .iter()
+                .find(|p| is_tcp_listener(p) && p.accepting != Some(true));
+            if let Some(info) = refused {
+                return Err(ProcCtlError::NotAccepting(info.port));
+            }
+        }
+
+        Ok(ports)
+    }
+
+    /// Fail with [ProcCtlError::ForbiddenPorts] if any of the ports isn't in the allowlist
+    pub(crate) fn check_allowed(&self, ports: &[PortInfo]) -> ProcCtlResult<()> {
+        let Some(allowed) = self.allowed_ports else {
+            return Ok(());
+        };
+
+        let unexpected = ports
+            .iter()
+            .filter(|p| !allowed.contains(&p.port))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !unexpected.is_empty() {
+            return Err(ProcCtlError::ForbiddenPorts(Box::new(
+                crate::monitor::ForbiddenPorts {
+                    at: SystemTime::now(),
+                    unexpected,
+                    snapshot: ports.to_vec(),
+                },
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PortInfoBuilder;
+    use std::time::Duration;
+
+    const ALL: PortFilter<'static> = PortFilter {
+        tcp: true,
+        udp: true,
+        tcp_states: &[TcpState::Listen],
+        port_range: None,
+        loopback: None,
+        ipv4: true,
+        ipv6: true,
+        bound_after: None,
+        joined_group: None,
+        split_families: false,
+    };
+
+    const NONE_EXPECTED: PortExpectations<'static> = PortExpectations {
+        min_num_ports: None,
+        max_num_ports: None,
+        min_backlog: None,
+        allowed_ports: None,
+        expected_ports: None,
+        expect_accepting: false,
+    };
+
+    fn port(port: ProtocolPort, family: AddressFamily) -> PortInfoBuilder {
+        PortInfo::builder().port(port).family(family).pid(10)
+    }
+
+    fn captured() -> Vec<PortInfo> {
+        vec![
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv4).build(),
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv6).build(),
+            port(ProtocolPort::Tcp(9090), AddressFamily::Ipv6).build(),
+            port(ProtocolPort::Udp(5353), AddressFamily::Ipv4).build(),
+        ]
+    }
+
+    fn ports_of(ports: &[PortInfo]) -> Vec<(ProtocolPort, AddressFamily)> {
+        ports.iter().map(|p| (p.port, p.family)).collect()
+    }
+
+    #[test]
+    fn protocols_are_filtered() {
+        let tcp = PortFilter { udp: false, ..ALL }.apply(captured());
+        let udp = PortFilter { tcp: false, ..ALL }.apply(captured());
+
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv4),
+                (ProtocolPort::Tcp(9090), AddressFamily::Ipv6)
+            ],
+            ports_of(&tcp)
+        );
+        assert_eq!(
+            vec![(ProtocolPort::Udp(5353), AddressFamily::Ipv4)],
+            ports_of(&udp)
+        );
+    }
+
+    #[test]
+    fn ports_outside_the_range_are_not_counted() {
+        let in_range = PortFilter {
+            port_range: Some((5000, 8080)),
+            ..ALL
+        };
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv4),
+                (ProtocolPort::Udp(5353), AddressFamily::Ipv4)
+            ],
+            ports_of(&in_range.apply(captured()))
+        );
+
+        let tcp_exact = PortFilter {
+            udp: false,
+            port_range: Some((9090, 9090)),
+            ..ALL
+        };
+        let expectations = PortExpectations {
+            min_num_ports: Some(2),
+            ..NONE_EXPECTED
+        };
+        match expectations.check(tcp_exact.apply(captured())) {
+            Err(ProcCtlError::TooFewPorts(found, 2)) => {
+                assert_eq!(vec![ProtocolPort::Tcp(9090)], found)
+            }
+            other => panic!("expected too few ports, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loopback_ports_are_filtered_before_they_are_merged() {
+        let at = |port, addr: &str| {
+            let addr = addr.parse::<std::net::SocketAddr>().unwrap();
+            PortInfo::builder()
+                .port(port)
+                .local_addr(addr)
+                .pid(10)
+                .build()
+        };
+        let ports = vec![
+            at(ProtocolPort::Tcp(8080), "127.0.0.1:8080"),
+            at(ProtocolPort::Tcp(8080), "[::]:8080"),
+            at(ProtocolPort::Tcp(9090), "0.0.0.0:9090"),
+            at(ProtocolPort::Udp(5353), "[::1]:5353"),
+        ];
+
+        let reachable = PortFilter {
+            loopback: Some(false),
+            ..ALL
+        };
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv6),
+                (ProtocolPort::Tcp(9090), AddressFamily::Ipv4)
+            ],
+            ports_of(&reachable.apply(ports.clone()))
+        );
+
+        let loopback = PortFilter {
+            loopback: Some(true),
+            ..ALL
+        };
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv4),
+                (ProtocolPort::Udp(5353), AddressFamily::Ipv6)
+            ],
+            ports_of(&loopback.apply(ports.clone()))
+        );
+
+        assert_eq!(3, ALL.apply(ports).len());
+    }
+
+    #[test]
+    fn families_are_filtered_before_they_are_merged() {
+        let v6 = PortFilter { ipv4: false, ..ALL }.apply(captured());
+
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv6),
+                (ProtocolPort::Tcp(9090), AddressFamily::Ipv6)
+            ],
+            ports_of(&v6)
+        );
+        assert_eq!(vec![AddressFamily::Ipv6], v6[0].families);
+    }
+
+    #[test]
+    fn families_are_merged_unless_split() {
+        let merged = ALL.apply(captured());
+        assert_eq!(3, merged.len());
+        assert_eq!(
+            vec![AddressFamily::Ipv4, AddressFamily::Ipv6],
+            merged[0].families
+        );
+
+        let split = PortFilter {
+            split_families: true,
+            ..ALL
+        }
+        .apply(captured());
+        assert_eq!(ports_of(&captured()), ports_of(&split));
+    }
+
+    #[test]
+    fn tcp_ports_are_filtered_by_state() {
+        let ports = vec![
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv4)
+                .tcp_state(TcpState::Listen)
+                .build(),
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv4)
+                .tcp_state(TcpState::Established)
+                .build(),
+            port(ProtocolPort::Tcp(9090), AddressFamily::Ipv4)
+                .tcp_state(TcpState::TimeWait)
+                .build(),
+            port(ProtocolPort::Tcp(7070), AddressFamily::Ipv4).build(),
+            port(ProtocolPort::Udp(5353), AddressFamily::Ipv4).build(),
+        ];
+        let states = |found: &[PortInfo]| {
+            found
+                .iter()
+                .map(|p| (p.port, p.tcp_state))
+                .collect::<Vec<_>>()
+        };
+
+        // A port without a state was found by an earlier release, which only found listeners
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), Some(TcpState::Listen)),
+                (ProtocolPort::Tcp(7070), None),
+                (ProtocolPort::Udp(5353), None)
+            ],
+            states(&ALL.apply(ports.clone()))
+        );
+
+        let connections = PortFilter {
+            tcp_states: &[TcpState::Established, TcpState::TimeWait],
+            ..ALL
+        };
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), Some(TcpState::Established)),
+                (ProtocolPort::Tcp(9090), Some(TcpState::TimeWait)),
+                (ProtocolPort::Udp(5353), None)
+            ],
+            states(&connections.apply(ports))
+        );
+    }
+
+    #[test]
+    fn sockets_in_different_states_are_not_merged() {
+        let ports = vec![
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv4)
+                .tcp_state(TcpState::Listen)
+                .build(),
+            port(ProtocolPort::Tcp(8080), AddressFamily::Ipv6)
+                .tcp_state(TcpState::Established)
+                .build(),
+        ];
+
+        let found = PortFilter {
+            tcp_states: &[TcpState::Listen, TcpState::Established],
+            ..ALL
+        }
+        .apply(ports);
+
+        assert_eq!(2, found.len());
+        assert_eq!(vec![AddressFamily::Ipv4], found[0].families);
+    }
+
+    #[test]
+    fn ports_bound_too_early_are_left_out() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let ports = vec![
+            port(ProtocolPort::Tcp(1), AddressFamily::Ipv4)
+                .bound_since(start - Duration::from_secs(1))
+                .build(),
+            port(ProtocolPort::Tcp(2), AddressFamily::Ipv4)
+                .bound_since(start)
+                .build(),
+            port(ProtocolPort::Tcp(3), AddressFamily::Ipv4).build(),
+        ];
+
+        let found = PortFilter {
+            bound_after: Some(start),
+            ..ALL
+        }
+        .apply(ports);
+
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(2), AddressFamily::Ipv4),
+                (ProtocolPort::Tcp(3), AddressFamily::Ipv4)
+            ],
+            ports_of(&found)
+        );
+    }
+
+    #[test]
+    fn only_udp_ports_in_the_group_have_joined_it() {
+        let group: IpAddr = "239.1.2.3".parse().unwrap();
+        let other: IpAddr = "239.1.2.4".parse().unwrap();
+        let ports = vec![
+            port(ProtocolPort::Udp(1), AddressFamily::Ipv4)
+                .multicast_groups([group])
+                .build(),
+            port(ProtocolPort::Udp(2), AddressFamily::Ipv4)
+                .multicast_groups([other])
+                .build(),
+            port(ProtocolPort::Udp(3), AddressFamily::Ipv4).build(),
+            port(ProtocolPort::Tcp(4), AddressFamily::Ipv4)
+                .multicast_groups([group])
+                .build(),
+        ];
+
+        let found = PortFilter {
+            joined_group: Some(group),
+            ..ALL
+        }
+        .apply(ports);
+
+        assert_eq!(
+            vec![(ProtocolPort::Udp(1), AddressFamily::Ipv4)],
+            ports_of(&found)
+        );
+    }
+
+    #[test]
+    fn too_few_ports_fails() {
+        let expectations = PortExpectations {
+            min_num_ports: Some(4),
+            ..NONE_EXPECTED
+        };
+
+        match expectations.check(ALL.apply(captured())) {
+            Err(ProcCtlError::TooFewPorts(found, 4)) => assert_eq!(3, found.len()),
+            other => panic!("expected too few ports, got {:?}", other),
+        }
+
+        let expectations = PortExpectations {
+            min_num_ports: Some(3),
+            ..NONE_EXPECTED
+        };
+        assert_eq!(3, expectations.check(ALL.apply(captured())).unwrap().len());
+    }
+
+    #[test]
+    fn too_many_ports_fails() {
+        let expectations = PortExpectations {
+            max_num_ports: Some(2),
+            ..NONE_EXPECTED
+        };
+
+        match expectations.check(ALL.apply(captured())) {
+            Err(ProcCtlError::TooManyPorts(found, 2)) => assert_eq!(3, found.len()),
+            other => panic!("expected too many ports, got {:?}", other),
+        }
+
+        let exact = |num| PortExpectations {
+            min_num_ports: Some(num),
+            max_num_ports: Some(num),
+            ..NONE_EXPECTED
+        };
+        assert_eq!(3, exact(3).check(ALL.apply(captured())).unwrap().len());
+        assert!(matches!(
+            exact(4).check(ALL.apply(captured())),
+            Err(ProcCtlError::TooFewPorts(_, 4))
+        ));
+        assert!(matches!(
+            exact(2).check(ALL.apply(captured())),
+            Err(ProcCtlError::TooManyPorts(_, 2))
+        ));
+    }
+
+    #[test]
+    fn small_or_unknown_backlogs_of_tcp_ports_fail() {
+        let expectations = PortExpectations {
+            min_backlog: Some(128),
+            ..NONE_EXPECTED
+        };
+
+        let big = port(ProtocolPort::Tcp(1), AddressFamily::Ipv4)
+            .backlog(128)
+            .build();
+        let small = port(ProtocolPort::Tcp(2), AddressFamily::Ipv4)
+            .backlog(16)
+            .build();
+        let unknown = port(ProtocolPort::Tcp(3), AddressFamily::Ipv4).build();
+        let udp = port(ProtocolPort::Udp(4), AddressFamily::Ipv4).build();
+
+        assert!(expectations.check(vec![big.clone(), udp]).is_ok());
+        assert!(matches!(
+            expectations.check(vec![big.clone(), small]),
+            Err(ProcCtlError::BacklogTooSmall(
+                ProtocolPort::Tcp(2),
+                Some(16),
+                128
+            ))
+        ));
+        assert!(matches!(
+            expectations.check(vec![big, unknown]),
+            Err(ProcCtlError::BacklogTooSmall(
+                ProtocolPort::Tcp(3),
+                None,
+                128
+            ))
+        ));
+    }
+
+    #[test]
+    fn ports_outside_the_allowlist_fail() {
+        let allowed = BTreeSet::from([ProtocolPort::Tcp(8080), ProtocolPort::Tcp(9090)]);
+        let expectations = PortExpectations {
+            allowed_ports: Some(&allowed),
+            ..NONE_EXPECTED
+        };
+
+        match expectations.check(ALL.apply(captured())) {
+            Err(ProcCtlError::ForbiddenPorts(forbidden)) => {
+                assert_eq!(
+                    vec![ProtocolPort::Udp(5353)],
+                    forbidden
+                        .unexpected
+                        .iter()
+                        .map(|p| p.port)
+                        .collect::<Vec<_>>()
+                );
+                assert_eq!(3, forbidden.snapshot.len());
+            }
+            other => panic!("expected forbidden ports, got {:?}", other),
+        }
+
+        let tcp = PortFilter { udp: false, ..ALL }.apply(captured());
+        assert!(expectations.check(tcp).is_ok());
+    }
+
+    #[test]
+    fn expected_ports_must_pass_the_filters() {
+        let expected = BTreeSet::from([ProtocolPort::Tcp(9090), ProtocolPort::Udp(5353)]);
+        let expectations = PortExpectations {
+            expected_ports: Some(&expected),
+            ..NONE_EXPECTED
+        };
+        assert_eq!(3, expectations.check(ALL.apply(captured())).unwrap().len());
+
+        // A UDP port with the number of a TCP port doesn't count
+        let expected = BTreeSet::from([ProtocolPort::Tcp(5353), ProtocolPort::Tcp(8080)]);
+        let expectations = PortExpectations {
+            expected_ports: Some(&expected),
+            ..NONE_EXPECTED
+        };
+        let tcp = PortFilter { udp: false, ..ALL }.apply(captured());
+        match expectations.check(tcp) {
+            Err(ProcCtlError::MissingPorts(missing, found)) => {
+                assert_eq!(vec![ProtocolPort::Tcp(5353)], missing);
+                assert_eq!(
+                    vec![ProtocolPort::Tcp(8080), ProtocolPort::Tcp(9090)],
+                    found
+                );
+            }
+            other => panic!("expected missing ports, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tcp_ports_must_be_known_to_accept() {
+        let expectations = PortExpectations {
+            expect_accepting: true,
+            ..NONE_EXPECTED
+        };
+
+        let accepting = port(ProtocolPort::Tcp(1), AddressFamily::Ipv4)
+            .accepting(true)
+            .build();
+        let unknown = port(ProtocolPort::Tcp(2), AddressFamily::Ipv4).build();
+        let udp = port(ProtocolPort::Udp(3), AddressFamily::Ipv4).build();
+
+        assert!(expectations.check(vec![accepting.clone(), udp]).is_ok());
+        assert!(matches!(
+            expectations.check(vec![accepting, unknown]),
+            Err(ProcCtlError::NotAccepting(ProtocolPort::Tcp(2)))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn config_is_applied_as_a_query_would() {
+        let config: crate::PortQueryConfig = serde_json::from_str(
+            r#"{"family": "ipv6", "split_families": true, "expect_min_num_ports": 2}"#,
+        )
+        .unwrap();
+
+        let found = apply(&config, captured()).unwrap();
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), AddressFamily::Ipv6),
+                (ProtocolPort::Tcp(9090), AddressFamily::Ipv6)
+            ],
+            ports_of(&found)
+        );
+
+        let config: crate::PortQueryConfig =
+            serde_json::from_str(r#"{"protocol": "udp", "expect_min_num_ports": 2}"#).unwrap();
+        assert!(matches!(
+            apply(&config, captured()),
+            Err(ProcCtlError::TooFewPorts(_, 2))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn config_which_cant_apply_to_captured_ports_is_rejected() {
+        let config: crate::PortQueryConfig =
+            serde_json::from_str(r#"{"verify_accepting": true}"#).unwrap();
+        assert!(matches!(
+            apply(&config, captured()),
+            Err(ProcCtlError::ConfigurationError(_))
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "proptest"))]
+mod property_tests {
+    use crate::invariants::{check_narrowing, check_port_filters};
+    use crate::strategies::{narrowed_port_query_config, port_infos, port_query_config};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn filters_are_consistent(config in port_query_config(), ports in port_infos()) {
+            check_port_filters(&config, &ports)?;
+        }
+
+        #[test]
+        fn narrower_filters_find_fewer_ports((wide, narrow) in narrowed_port_query_config(), ports in port_infos()) {
+            check_narrowing(&wide, &narrow, &ports)?;
+        }
+    }
+}
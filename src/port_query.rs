@@ -1,6 +1,25 @@
+use crate::clock::Clock;
 use crate::error::{ProcCtlError, ProcCtlResult};
-use crate::types::{Pid, ProtocolPort};
+use crate::time::Deadline;
+use crate::types::{
+    AddressFamily, Observed, OwnerKind, Pid, Port, PortInfo, PortOwner, PortSummary, ProtocolPort,
+    TcpState,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
 use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// What a [PortQuery] should do when the process it is tracking matches more than one running process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MultipleMatchPolicy {
+    /// Fail with [ProcCtlError::MultipleMatchingProcesses]
+    #[default]
+    Error,
+    /// Combine the ports of every matching process
+    Aggregate,
+}
 
 /// Find the ports used by a process
 #[derive(Debug)]
@@ -9,8 +28,40 @@ pub struct PortQuery {
     ipv6_addresses: bool,
     tcp_addresses: bool,
     udp_addresses: bool,
+    tcp_states: Vec<TcpState>,
+    port_range: Option<(Port, Port)>,
+    loopback: Option<bool>,
+    split_families: bool,
+    sorted: bool,
     process_id: Option<Pid>,
+    process_ids: Vec<Pid>,
+    #[cfg(target_os = "linux")]
+    process_fd: Option<crate::pidfd::PidFd>,
+    #[cfg(feature = "proc")]
+    track: Option<crate::proc_query::ProcSelector>,
+    process_name: Option<String>,
+    multiple_matches: MultipleMatchPolicy,
+    include_children: bool,
+    re_resolve_attempts: usize,
     min_num_ports: Option<usize>,
+    max_num_ports: Option<usize>,
+    #[cfg(any(feature = "resilience", feature = "async"))]
+    stall_attempts: Option<usize>,
+    bound_after: Option<SystemTime>,
+    joined_group: Option<IpAddr>,
+    include_system_owned: bool,
+    min_backlog: Option<u32>,
+    allowed_ports: Option<std::collections::BTreeSet<ProtocolPort>>,
+    expected_ports: std::collections::BTreeSet<ProtocolPort>,
+    verify_accepting: bool,
+    expect_accepting: bool,
+    probe_address: Option<IpAddr>,
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    max_tool_concurrency: usize,
+    tool_runner: Arc<dyn crate::tool::ToolRunner>,
+    timeout: Option<Duration>,
+    last_observed: Mutex<Option<Observed<Vec<PortInfo>>>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl PortQuery {
@@ -21,8 +72,155 @@ impl PortQuery {
             ipv6_addresses: true,
             tcp_addresses: true,
             udp_addresses: true,
+            tcp_states: vec![TcpState::Listen],
+            port_range: None,
+            loopback: None,
+            split_families: false,
+            sorted: false,
             process_id: None,
+            process_ids: Vec::new(),
+            #[cfg(target_os = "linux")]
+            process_fd: None,
+            #[cfg(feature = "proc")]
+            track: None,
+            process_name: None,
+            multiple_matches: MultipleMatchPolicy::Error,
+            include_children: false,
+            re_resolve_attempts: crate::re_resolve::DEFAULT_RE_RESOLVE_ATTEMPTS,
             min_num_ports: None,
+            max_num_ports: None,
+            #[cfg(any(feature = "resilience", feature = "async"))]
+            stall_attempts: None,
+            bound_after: None,
+            joined_group: None,
+            include_system_owned: false,
+            min_backlog: None,
+            allowed_ports: None,
+            expected_ports: std::collections::BTreeSet::new(),
+            verify_accepting: false,
+            expect_accepting: false,
+            probe_address: None,
+            max_tool_concurrency: DEFAULT_MAX_TOOL_CONCURRENCY,
+            tool_runner: Arc::new(crate::tool::SystemToolRunner),
+            timeout: None,
+            last_observed: Mutex::new(None),
+            clock: crate::clock::system(),
+        }
+    }
+
+    /// Create a query from its definition in config.
+    ///
+    /// The retry policy of the config is not part of the query, so pass it to a retry function when the query is
+    /// executed.
+    #[cfg(feature = "serde")]
+    pub fn from_config(config: &crate::config::PortQueryConfig) -> Self {
+        let mut query = PortQuery::new();
+        if let Some(pid) = config.process_id {
+            query = query.process_id(pid);
+        }
+        if let Some(pids) = &config.process_ids {
+            query = query.process_ids(pids);
+        }
+        query.process_name = config.process_name.clone();
+        query.include_children = config.include_children;
+        query.multiple_matches = config.on_multiple_matches;
+        query = match config.protocol {
+            Some(crate::config::Protocol::Tcp) => query.tcp_only(),
+            Some(crate::config::Protocol::Udp) => query.udp_only(),
+            None => query,
+        };
+        query = match config.family {
+            Some(AddressFamily::Ipv4) => query.ip_v4_only(),
+            Some(AddressFamily::Ipv6) => query.ip_v6_only(),
+            None => query,
+        };
+        if let Some(states) = &config.tcp_states {
+            query = query.tcp_states(states);
+        }
+        if config.min_port.is_some() || config.max_port.is_some() {
+            query = query.port_range(
+                config.min_port.unwrap_or(Port::MIN),
+                config.max_port.unwrap_or(Port::MAX),
+            );
+        }
+        query.loopback = config.loopback;
+        query.min_num_ports = config.expect_min_num_ports;
+        query.max_num_ports = config.expect_max_num_ports;
+        query.min_backlog = config.expect_backlog_at_least;
+        query.expected_ports = config.expect_ports.iter().flatten().copied().collect();
+        query.allowed_ports = config
+            .forbid_ports_except
+            .as_ref()
+            .map(|allowed| allowed.iter().copied().collect());
+        query.verify_accepting = config.verify_accepting || config.expect_accepting;
+        query.expect_accepting = config.expect_accepting;
+        query.probe_address = config.probe_address;
+        query.include_system_owned = config.include_system_owned;
+        query.split_families = config.split_families;
+        query.sorted = config.sorted;
+        query.max_tool_concurrency = config
+            .max_tool_concurrency
+            .unwrap_or(DEFAULT_MAX_TOOL_CONCURRENCY);
+        query.timeout = config.timeout_ms.map(Duration::from_millis);
+
+        query
+    }
+
+    /// The definition of this query, as it would be written in config.
+    ///
+    /// Only what can be written in config is included, so a clock, tool runner, pidfd, tracked process or
+    /// [PortQuery::bound_after] time set on the query is left out, and so is the retry policy.
+    #[cfg(feature = "serde")]
+    pub fn to_config(&self) -> crate::config::PortQueryConfig {
+        crate::config::PortQueryConfig {
+            process_id: self.process_id,
+            process_ids: (!self.process_ids.is_empty()).then(|| self.process_ids.clone()),
+            process_name: self.process_name.clone(),
+            include_children: self.include_children,
+            on_multiple_matches: self.multiple_matches,
+            protocol: only_one(
+                self.tcp_addresses,
+                self.udp_addresses,
+                crate::config::Protocol::Tcp,
+                crate::config::Protocol::Udp,
+            ),
+            family: only_one(
+                self.ipv4_addresses,
+                self.ipv6_addresses,
+                AddressFamily::Ipv4,
+                AddressFamily::Ipv6,
+            ),
+            tcp_states: (self.tcp_states != [TcpState::Listen]).then(|| self.tcp_states.clone()),
+            min_port: self
+                .port_range
+                .map(|(min, _)| min)
+                .filter(|min| *min != Port::MIN),
+            max_port: self
+                .port_range
+                .map(|(_, max)| max)
+                .filter(|max| *max != Port::MAX),
+            loopback: self.loopback,
+            expect_min_num_ports: self.min_num_ports,
+            expect_max_num_ports: self.max_num_ports,
+            expect_backlog_at_least: self.min_backlog,
+            expect_ports: (!self.expected_ports.is_empty())
+                .then(|| self.expected_ports.iter().copied().collect()),
+            forbid_ports_except: self
+                .allowed_ports
+                .as_ref()
+                .map(|allowed| allowed.iter().copied().collect()),
+            verify_accepting: self.verify_accepting,
+            expect_accepting: self.expect_accepting,
+            probe_address: self.probe_address,
+            include_system_owned: self.include_system_owned,
+            split_families: self.split_families,
+            sorted: self.sorted,
+            max_tool_concurrency: (self.max_tool_concurrency != DEFAULT_MAX_TOOL_CONCURRENCY)
+                .then_some(self.max_tool_concurrency),
+            timeout_ms: self
+                .timeout
+                .map(|timeout| u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX)),
+            retry: None,
         }
     }
 
@@ -54,12 +252,331 @@ impl PortQuery {
         self
     }
 
+    /// Only consider TCP sockets in one of `states`, rather than only listeners. UDP ports are not affected.
+    ///
+    /// This is useful for finding connections left behind by a test, such as those in [TcpState::TimeWait] or
+    /// [TcpState::CloseWait]. The state of each socket found is in [PortInfo::tcp_state]. A connection has the same
+    /// local port as the listener which accepted it, so the same port can be found more than once in different states.
+    /// A [crate::Snapshot] only captures listeners, so a query run against one finds no other states.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortQuery, ProtocolPort, TcpState};
+    /// use std::net::{TcpListener, TcpStream};
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let port = listener.local_addr().unwrap().port();
+    /// let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    /// let (_server, _) = listener.accept().unwrap();
+    ///
+    /// let ports = PortQuery::new()
+    ///     .process_id(std::process::id())
+    ///     .tcp_only()
+    ///     .tcp_states(&[TcpState::Listen, TcpState::Established])
+    ///     .execute_detailed()
+    ///     .unwrap();
+    ///
+    /// let mut states = ports
+    ///     .iter()
+    ///     .filter(|p| p.port == ProtocolPort::Tcp(port))
+    ///     .map(|p| p.tcp_state)
+    ///     .collect::<Vec<_>>();
+    /// states.sort_by_key(|s| *s != Some(TcpState::Listen));
+    /// assert_eq!(vec![Some(TcpState::Listen), Some(TcpState::Established)], states);
+    /// ```
+    pub fn tcp_states(mut self, states: &[TcpState]) -> Self {
+        self.tcp_states = states.to_vec();
+        self
+    }
+
+    /// Only consider ports from `min` to `max`, including both. Other ports are dropped before the expectations are
+    /// checked, so that [PortQuery::expect_min_num_ports] only counts the ports in the range. The range applies to
+    /// both protocols and address families, and can be combined with their filters.
+    ///
+    /// Fails with [ProcCtlError::ConfigurationError] when the query runs if `min` is greater than `max`.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortQuery, ProtocolPort};
+    /// use std::net::TcpListener;
+    ///
+    /// let admin = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let admin_port = admin.local_addr().unwrap().port();
+    /// let _data = TcpListener::bind("127.0.0.1:0").unwrap();
+    ///
+    /// let ports = PortQuery::new()
+    ///     .process_id(std::process::id())
+    ///     .tcp_only()
+    ///     .port_range(admin_port, admin_port)
+    ///     .expect_min_num_ports(1)
+    ///     .execute()
+    ///     .unwrap();
+    /// assert_eq!(vec![ProtocolPort::Tcp(admin_port)], ports);
+    /// ```
+    pub fn port_range(mut self, min: Port, max: Port) -> Self {
+        self.port_range = Some((min, max));
+        self
+    }
+
+    /// Only consider `port`, on either protocol. This is [PortQuery::port_range] with a range of one port.
+    pub fn port(self, port: Port) -> Self {
+        self.port_range(port, port)
+    }
+
+    /// Only consider ports which can be reached from other machines, leaving out those bound to a loopback address such
+    /// as `127.0.0.1` or `::1`. A port bound to the unspecified address, `0.0.0.0` or `::`, is kept. See
+    /// [PortInfo::is_loopback].
+    ///
+    /// This is checked for each address family before they are merged, so a port bound to `127.0.0.1` and `::` is kept
+    /// with only IPv6.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortQuery, ProtocolPort};
+    /// use std::net::TcpListener;
+    ///
+    /// let admin = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let public = TcpListener::bind("0.0.0.0:0").unwrap();
+    ///
+    /// let ports = PortQuery::new()
+    ///     .process_id(std::process::id())
+    ///     .tcp_only()
+    ///     .exclude_loopback()
+    ///     .execute()
+    ///     .unwrap();
+    /// assert!(ports.contains(&ProtocolPort::Tcp(public.local_addr().unwrap().port())));
+    /// assert!(!ports.contains(&ProtocolPort::Tcp(admin.local_addr().unwrap().port())));
+    /// ```
+    pub fn exclude_loopback(mut self) -> Self {
+        self.loopback = Some(false);
+        self
+    }
+
+    /// Only consider ports bound to a loopback address, the opposite of [PortQuery::exclude_loopback]
+    pub fn loopback_only(mut self) -> Self {
+        self.loopback = Some(true);
+        self
+    }
+
+    /// Report a port which a process has bound on both IPv4 and IPv6 once for each family, rather than once.
+    ///
+    /// By default the same port and protocol bound by a process on both families, such as by a server listening on
+    /// `0.0.0.0:8080` and `[::]:8080`, is reported as one [PortInfo] with both in [PortInfo::families], and
+    /// [PortQuery::execute] returns the port once. This makes a dual-stack listener count as one port on every
+    /// platform. Windows lists it in the table of each family, while Linux and macOS usually have a single IPv6 socket
+    /// which also accepts IPv4 connections. That single socket is only reported with IPv6, since whether it accepts
+    /// IPv4 can't be told from outside the process.
+    ///
+    /// Set this to report each socket on its own, as earlier releases did, so that the two families count as two ports
+    /// for [PortQuery::expect_min_num_ports].
+    pub fn split_families(mut self, split: bool) -> Self {
+        self.split_families = split;
+        self
+    }
+
+    /// Sort the ports found by protocol then port number, with TCP ports first, so that they are in the same order
+    /// every time and on every platform.
+    ///
+    /// By default ports are in the order the operating system lists them, which differs between platforms and, on
+    /// Windows, between runs. Ports with the same protocol and number, such as one bound by two processes found with
+    /// [PortQuery::include_children], are sorted by address family and then pid. This applies to every way of running
+    /// the query, including the ports listed by an error such as [ProcCtlError::TooFewPorts].
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
+
     /// Require at least `num_ports` ports to be bound by the matched process for the query to succeed.
     pub fn expect_min_num_ports(mut self, num_ports: usize) -> Self {
         self.min_num_ports = Some(num_ports);
         self
     }
 
+    /// Require at most `num_ports` ports to be bound by the matched process for the query to succeed, failing with
+    /// [ProcCtlError::TooManyPorts] otherwise. This catches a process which binds a port twice by accident.
+    ///
+    /// Ports are counted as for [PortQuery::expect_min_num_ports], so a port bound on both address families counts
+    /// once unless [PortQuery::split_families] is set. A retry stops as soon as too many ports are found, since
+    /// retrying can't unbind them. Fails with [ProcCtlError::ConfigurationError] when the query runs if `num_ports` is
+    /// less than the minimum.
+    pub fn expect_max_num_ports(mut self, num_ports: usize) -> Self {
+        self.max_num_ports = Some(num_ports);
+        self
+    }
+
+    /// Require exactly `num_ports` ports to be bound by the matched process for the query to succeed. This is the same
+    /// as setting both [PortQuery::expect_min_num_ports] and [PortQuery::expect_max_num_ports] to `num_ports`.
+    ///
+    /// A retry keeps going while too few ports are found, since the process may still be starting, and stops as soon
+    /// as too many are found.
+    pub fn expect_exact_num_ports(self, num_ports: usize) -> Self {
+        self.expect_min_num_ports(num_ports)
+            .expect_max_num_ports(num_ports)
+    }
+
+    /// Stop [PortQuery::execute_with_retry_sync] and [PortQuery::execute_with_retry] early when retrying looks like it
+    /// can never succeed, rather than using up every attempt.
+    ///
+    /// A process selected by [PortQuery::process_id] which has gone can never bind a port, so the retry stops with
+    /// [ProcCtlError::ProcessExited]. When `attempts` in a row find the same too few ports, and every socket of the
+    /// process stayed the same between them, the process is taken to have finished starting and the retry stops with
+    /// [ProcCtlError::StalledExpectation]. This catches filters which exclude every socket the process binds, such as
+    /// [PortQuery::udp_only] for a server which only uses TCP. A process which hasn't bound any socket yet is never
+    /// taken to have finished starting.
+    ///
+    /// Set `attempts` higher than the number of attempts a step of the process's startup can take, or a process which
+    /// pauses between binding its ports is given up on too soon. Off by default.
+    #[cfg(any(feature = "resilience", feature = "async"))]
+    pub fn fail_fast_on_stall(mut self, attempts: usize) -> Self {
+        self.stall_attempts = Some(attempts);
+        self
+    }
+
+    /// Require `port` to be bound by the matched process for the query to succeed, failing with
+    /// [ProcCtlError::MissingPorts] otherwise. Call this again to require more ports.
+    ///
+    /// Use this when the port the process should bind is known, such as when it was passed on the command line, so
+    /// that a retry waits for that port rather than for any port. The port has to pass the other filters of the query
+    /// to count, so a [PortQuery::tcp_only] query is never satisfied by a UDP port with the same number.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortQuery, ProtocolPort};
+    ///
+    /// let query = PortQuery::new()
+    ///     .process_name("my-server")
+    ///     .tcp_only()
+    ///     .expect_port(ProtocolPort::Tcp(8080))
+    ///     .expect_port(ProtocolPort::Tcp(8443));
+    /// ```
+    pub fn expect_port(mut self, port: ProtocolPort) -> Self {
+        self.expected_ports.insert(port);
+        self
+    }
+
+    /// Require every TCP listener found to have a backlog of at least `backlog` for the query to succeed.
+    ///
+    /// This uses [PortInfo::backlog], so it is only supported on Linux. Where the backlog is not known the
+    /// expectation fails. It places no requirement on the number of listeners, so combine it with
+    /// [PortQuery::expect_min_num_ports] to require that there is one.
+    pub fn expect_backlog_at_least(mut self, backlog: u32) -> Self {
+        self.min_backlog = Some(backlog);
+        self
+    }
+
+    /// Expect that the process is bound to no ports other than `allowed`, failing with
+    /// [ProcCtlError::ForbiddenPorts] if it is. The allowed ports don't all have to be bound.
+    ///
+    /// Use [PortQuery::monitor_for] to check this repeatedly over the course of a test.
+    pub fn forbid_ports_except(mut self, allowed: impl IntoIterator<Item = ProtocolPort>) -> Self {
+        self.allowed_ports = Some(allowed.into_iter().collect());
+        self
+    }
+
+    /// Check that each TCP listener found is accepting connections, by connecting to it and closing the connection
+    /// straight away. The result is reported in [PortInfo::accepting].
+    ///
+    /// This makes real connections to the process, which it may see and log, so it is off by default. A process
+    /// which only accepts a single connection, for example, would be disturbed by it. Each connection attempt gives
+    /// up after 200ms. A listener whose accept queue is full does not complete new connections, so it is reported as
+    /// not accepting.
+    ///
+    /// Connections are made to the loopback address, trying IPv4 then IPv6. Use [PortQuery::probe_address] for
+    /// listeners which are not bound to loopback. UDP ports are never probed.
+    pub fn verify_accepting(mut self, verify: bool) -> Self {
+        self.verify_accepting = verify;
+        self
+    }
+
+    /// Require every TCP listener found to accept a test connection for the query to succeed.
+    ///
+    /// This enables [PortQuery::verify_accepting], so read the caveats there first. It is useful with the retry
+    /// functions, to wait for a process which binds its ports before it is ready to serve them.
+    pub fn expect_accepting(mut self) -> Self {
+        self.verify_accepting = true;
+        self.expect_accepting = true;
+        self
+    }
+
+    /// The address to connect to when checking that listeners are accepting, instead of loopback
+    pub fn probe_address(mut self, address: IpAddr) -> Self {
+        self.probe_address = Some(address);
+        self
+    }
+
+    /// Only consider ports which were bound after `time`
+    ///
+    /// This is useful for ignoring stale listeners left over from a previous run. It is based on
+    /// [PortInfo::bound_since], which is an approximation, so read the caveats there before relying on it. Ports
+    /// where the bind time is not known are not filtered out.
+    ///
+    /// Both times are read from the system clock. If it is set back after `time` was taken, a port bound in between
+    /// can appear to have been bound before `time` and be filtered out, so use this to tell apart runs which are
+    /// seconds apart rather than to order events within a run.
+    pub fn bound_after(mut self, time: SystemTime) -> Self {
+        self.bound_after = Some(time);
+        self
+    }
+
+    /// Only consider UDP ports whose network namespace has joined the multicast group `group`, such as `224.0.0.251`
+    /// for mDNS. TCP ports never match.
+    ///
+    /// This uses [PortInfo::multicast_groups], so read the caveats there: the group may have been joined by another
+    /// socket. It is only supported on Linux, and elsewhere the query fails with [ProcCtlError::UnsupportedPlatform].
+    /// A `group` which is not a multicast address fails with [ProcCtlError::ConfigurationError].
+    pub fn joined_group(mut self, group: IpAddr) -> Self {
+        self.joined_group = Some(group);
+        self
+    }
+
+    /// Include ports owned by the operating system rather than a normal process
+    ///
+    /// On Windows, sockets owned by the System process (pid 4) are excluded unless this is enabled. Sockets with an
+    /// owning pid of 0 have been released or were created at boot and are never attributed to a process, so a query
+    /// for pid 0 fails with [ProcCtlError::ConfigurationError]. See [crate::OwnerKind]. This has no effect on other
+    /// platforms.
+    pub fn include_system_owned(mut self, include: bool) -> Self {
+        self.include_system_owned = include;
+        self
+    }
+
+    /// Limit how many external tools, such as `lsof` on macOS, may run at once while this query is executing.
+    ///
+    /// The limit is checked against every tool started by this crate, not just those started by this query, so
+    /// queries running in parallel wait for each other. Defaults to 4. This has no effect on platforms where ports
+    /// are listed without running a tool.
+    pub fn max_tool_concurrency(mut self, max: usize) -> Self {
+        self.max_tool_concurrency = max;
+        self
+    }
+
+    /// Run external tools, such as `lsof` on macOS and `systemctl` for [PortQuery::systemd_unit], with `runner` rather
+    /// than directly. See [crate::ToolRunner] for what this is useful for. Tools are still run within the limit set by
+    /// [PortQuery::max_tool_concurrency].
+    pub fn with_tool_runner(mut self, runner: Arc<dyn crate::tool::ToolRunner>) -> Self {
+        self.tool_runner = runner;
+        self
+    }
+
+    /// Limit the time each execution of the query can take, failing with [ProcCtlError::Timeout] once it has run for
+    /// longer than `timeout`.
+    ///
+    /// On macOS, lsof is killed if it is still running when the time is up, including when it was run by a runner set
+    /// with [PortQuery::with_tool_runner] which implements [crate::ToolRunner::run_before]. On Linux and Windows the
+    /// sockets are read from the system without waiting on another process, so the time is checked between each stage
+    /// of the query, such as after reading the sockets of each process, and a query can overrun by as long as one
+    /// stage takes. The error names the stage which was running out of time.
+    ///
+    /// This applies to each attempt of a retry on its own, and a timeout is retried like any other failure. The time
+    /// is always the real time, even with [PortQuery::with_clock].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Use `clock` rather than the real time for the delays and timeouts of retries, waits and watchers started from
+    /// this query, such as a [crate::ManualClock] in tests. The query itself always runs against the real system.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Set the process ID to match
     ///
     /// Either this function or `process_id_from_child` are required to be called before the query is usable.
@@ -68,6 +585,44 @@ impl PortQuery {
         self
     }
 
+    /// Select several processes by pid, such as the workers of a pool, listing the sockets of the system once for all
+    /// of them rather than once per process. On Linux the sockets are listed once for each network namespace the
+    /// processes are in.
+    ///
+    /// [PortQuery::execute] returns the ports of every process together, and a port which more than one of them holds
+    /// is listed once for each. Use [PortQuery::execute_grouped] to see which process bound which port. Expectations
+    /// such as [PortQuery::expect_min_num_ports] apply to each process on its own, so a query expecting one port fails
+    /// with the error for the first process which has none, even if the others have several. A pid which is given more
+    /// than once is only selected once, and an empty list selects nothing, as if this wasn't called.
+    ///
+    /// This takes precedence over [PortQuery::process_id]. [PortQuery::track], [PortQuery::process_name] and a pidfd
+    /// take precedence over this. It can't be used with [PortQuery::include_children], since the ports of a child
+    /// belong to no selected process, and the query fails with [ProcCtlError::ConfigurationError] if both are set.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    ///
+    /// let workers = [55932, 55933, 55934]; // Get the process IDs from somewhere
+    /// let ports = PortQuery::new()
+    ///     .process_ids(&workers)
+    ///     .expect_min_num_ports(1)
+    ///     .execute_grouped()
+    ///     .unwrap();
+    ///
+    /// for (pid, ports) in ports {
+    ///     println!("{}: {:?}", pid, ports);
+    /// }
+    /// ```
+    pub fn process_ids(mut self, pids: &[Pid]) -> Self {
+        self.process_ids.clear();
+        for pid in pids {
+            if !self.process_ids.contains(pid) {
+                self.process_ids.push(*pid);
+            }
+        }
+        self
+    }
+
     /// Get the process ID of a child process
     ///
     /// Either this function or `process_id` are required to be called before the query is usable.
@@ -75,103 +630,2098 @@ impl PortQuery {
         self.process_id(child.id())
     }
 
+    /// Select the main process of a systemd unit, such as `myapp.service`, see [crate::systemd_unit_pid].
+    ///
+    /// The main pid is looked up once, when this is called, so create the query again after the unit restarts. Fails
+    /// if systemd is not running or the unit has no main process running. `systemctl` is run by the runner and within
+    /// the limit set by [PortQuery::with_tool_runner] and [PortQuery::max_tool_concurrency], so set those first if
+    /// they are needed.
+    #[cfg(target_os = "linux")]
+    pub fn systemd_unit(self, unit: &str) -> ProcCtlResult<Self> {
+        let pid = crate::service::systemd_main_pid(
+            unit,
+            self.tool_runner.as_ref(),
+            self.max_tool_concurrency,
+        )?;
+        Ok(self.process_id(pid))
+    }
+
+    /// Select the process of a Windows service by its service name, such as `MyAppSvc`, see
+    /// [crate::windows_service_pid].
+    ///
+    /// The pid is looked up once, when this is called, so create the query again after the service restarts. Fails if
+    /// there is no such service or it is not running.
+    #[cfg(target_os = "windows")]
+    pub fn windows_service(self, name: &str) -> ProcCtlResult<Self> {
+        Ok(self.process_id(crate::service::windows_service_pid(name)?))
+    }
+
+    /// Select the process by a pidfd, such as one from [crate::ProcInfo::pidfd], taking ownership of it.
+    ///
+    /// The pid is read from the pidfd when the query is executed, and once the ports have been read the pidfd is
+    /// checked to confirm that the process is still running. If it has exited the query fails with
+    /// [ProcCtlError::ProcessExited] rather than returning ports which may belong to another process that has reused
+    /// the pid. This takes precedence over [PortQuery::process_id]. Reading the pid of a pidfd needs Linux 5.4 or
+    /// later, and the query fails with [ProcCtlError::ConfigurationError] on older kernels.
+    #[cfg(target_os = "linux")]
+    pub fn process_fd(mut self, pidfd: std::os::fd::OwnedFd) -> Self {
+        self.process_fd = Some(crate::pidfd::PidFd::new(pidfd));
+        self
+    }
+
+    /// Track a process by its identity rather than a fixed pid
+    ///
+    /// The pid is looked up again every time the query is executed, including on each retry, so the query keeps
+    /// working when the process is restarted. Use [PortQuery::execute_detailed] to see which pid was found. This
+    /// takes precedence over [PortQuery::process_id].
+    #[cfg(feature = "proc")]
+    pub fn track(mut self, selector: crate::proc_query::ProcSelector) -> Self {
+        self.track = Some(selector);
+        self
+    }
+
+    /// Find the process by its name, looking it up again every time the query is executed.
+    ///
+    /// On Linux the name is the kernel's short name for the process, which is read from `/proc`, so this works with or
+    /// without the `proc` feature. On Windows and macOS names are matched like `ProcSelector::Name`, which needs the
+    /// `proc` feature, and without it the query fails with [ProcCtlError::UnsupportedWithoutFeature].
+    /// [PortQuery::track] takes precedence over this, and this takes precedence over [PortQuery::process_id].
+    pub fn process_name(mut self, name: impl Into<String>) -> Self {
+        self.process_name = Some(name.into());
+        self
+    }
+
+    /// Also include the ports of the children of the process, their children and so on, such as the workers of a server
+    /// which forks. A socket which more than one process in the tree holds, such as a listener bound before forking, is
+    /// listed once, and [PortQuery::expect_min_num_ports] counts the ports of the whole tree.
+    ///
+    /// On Linux the children are read from `/proc`, so this works with or without the `proc` feature. On Windows and
+    /// macOS they are found from the process list, which needs the `proc` feature, and without it the query fails with
+    /// [ProcCtlError::UnsupportedWithoutFeature] rather than leaving the children out.
+    pub fn include_children(mut self, include: bool) -> Self {
+        self.include_children = include;
+        self
+    }
+
+    /// Set how many times the process is looked up again when it exits between being found and having its ports
+    /// read, such as a server being restarted just as the query runs. Defaults to 3, and 0 turns it off.
+    ///
+    /// This applies to a process selected by [PortQuery::process_name] or [PortQuery::track], including when one of
+    /// its children exits with [PortQuery::include_children]. A process selected by pid is never looked up again. Once
+    /// there are no attempts left the query fails with [ProcCtlError::ResolvedProcessExited], which lists what was
+    /// found each time. [crate::ports_for] uses this setting too, for a [crate::ProcQuery] which doesn't select a pid.
+    pub fn re_resolve_attempts(mut self, attempts: usize) -> Self {
+        self.re_resolve_attempts = attempts;
+        self
+    }
+
+    /// Choose what happens when a tracked process matches more than one running process. Defaults to
+    /// [MultipleMatchPolicy::Error].
+    pub fn on_multiple_matches(mut self, policy: MultipleMatchPolicy) -> Self {
+        self.multiple_matches = policy;
+        self
+    }
+
     /// Execute the query
+    ///
+    /// Ports can only be listed on Linux, Windows and macOS. Elsewhere this fails with
+    /// [ProcCtlError::UnsupportedPlatform], where earlier releases returned no ports.
     pub fn execute(&self) -> ProcCtlResult<Vec<ProtocolPort>> {
+        let mut ports = Vec::new();
+        self.execute_into(&mut ports)?;
+        Ok(ports)
+    }
+
+    /// Execute the query like [PortQuery::execute], returning the ports of each process separately.
+    ///
+    /// Every process selected by [PortQuery::process_ids] has an entry, which is empty if it has no ports. Otherwise
+    /// only the processes with ports are listed, so a query selecting one process which has none returns an empty
+    /// map. With [PortQuery::include_children], a socket held by several processes of the tree is listed for one of
+    /// them.
+    pub fn execute_grouped(
+        &self,
+    ) -> ProcCtlResult<std::collections::HashMap<Pid, Vec<ProtocolPort>>> {
+        let ports = self.check_expectations(self.list_ports(false)?)?;
+
+        let mut grouped = self
+            .selected_process_ids()
+            .unwrap_or_default()
+            .iter()
+            .map(|pid| (*pid, Vec::new()))
+            .collect::<std::collections::HashMap<_, _>>();
+        for info in ports {
+            grouped.entry(info.pid).or_default().push(info.port);
+        }
+
+        Ok(grouped)
+    }
+
+    /// Execute the query like [PortQuery::execute], filling `ports` rather than returning a new list.
+    ///
+    /// `ports` is cleared first and keeps its capacity, so a loop which executes the query many times, such as a
+    /// monitor sampling ten times a second, can use the same buffer every time. The ports are the same, in the same
+    /// order, as [PortQuery::execute] would return. If the query fails then `ports` is left empty.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    ///
+    /// let query = PortQuery::new().process_id(55932); // Get a process ID from somewhere
+    /// let mut ports = Vec::new();
+    /// loop {
+    ///     query.execute_into(&mut ports).unwrap();
+    ///     println!("{:?}", ports);
+    ///     std::thread::sleep(std::time::Duration::from_millis(100));
+    /// }
+    /// ```
+    pub fn execute_into(&self, ports: &mut Vec<ProtocolPort>) -> ProcCtlResult<()> {
+        ports.clear();
+        let found = self.list_ports(false)?;
+        ports.extend(self.check_expectations(found)?.into_iter().map(|p| p.port));
+        Ok(())
+    }
+
+    /// Execute the query like [PortQuery::execute], but fail straight away with [ProcCtlError::WouldBlock] rather than
+    /// waiting if resources shared with other queries are in use, such as the process list or the limit set by
+    /// [PortQuery::max_tool_concurrency].
+    ///
+    /// The query itself still reads from the operating system, which is quick but not asynchronous. This is meant for
+    /// cleanup code which must not wait on other queries, such as a `Drop` implementation in an async test. Fall back
+    /// to [PortQuery::last_observed] when the query would block:
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::{PortQuery, ProcCtlError};
+    ///
+    /// struct Server {
+    ///     ports: PortQuery,
+    /// }
+    ///
+    /// impl Drop for Server {
+    ///     fn drop(&mut self) {
+    ///         let ports = match self.ports.try_execute_nonblocking() {
+    ///             Ok(ports) => ports,
+    ///             Err(ProcCtlError::WouldBlock(_)) => self
+    ///                 .ports
+    ///                 .last_observed()
+    ///                 .map(|o| o.value.into_iter().map(|p| p.port).collect())
+    ///                 .unwrap_or_default(),
+    ///             Err(_) => return,
+    ///         };
+    ///         println!("Server stopped while bound to {:?}", ports);
+    ///     }
+    /// }
+    /// ```
+    pub fn try_execute_nonblocking(&self) -> ProcCtlResult<Vec<ProtocolPort>> {
+        let ports = self.list_ports_with(false, false)?;
+        Ok(self
+            .check_expectations(ports)?
+            .into_iter()
+            .map(|p| p.port)
+            .collect())
+    }
+
+    /// The ports found by the most recent successful execution of this query, and when they were found. This never
+    /// runs a query, so it never waits.
+    ///
+    /// Expectations such as [PortQuery::expect_min_num_ports] are not applied, so this is also set by an execution
+    /// which found the process but failed its expectations.
+    pub fn last_observed(&self) -> Option<Observed<Vec<PortInfo>>> {
+        self.last_observed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Execute the query, returning detailed information about each port
+    ///
+    /// Like [PortQuery::execute], this fails with [ProcCtlError::UnsupportedPlatform] on platforms other than Linux,
+    /// Windows and macOS.
+    pub fn execute_detailed(&self) -> ProcCtlResult<Vec<PortInfo>> {
+        let ports = self.list_ports(true)?;
+        self.check_expectations(ports)
+    }
+
+    /// Execute the query, returning the ports grouped by protocol and address family.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    ///
+    /// let summary = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .summary()
+    ///     .unwrap();
+    ///
+    /// println!("{}", summary); // For example, tcp4: 8080,8081 udp6: 5353
+    /// ```
+    pub fn summary(&self) -> ProcCtlResult<PortSummary> {
+        let ports = self.list_ports(false)?;
+        Ok(PortSummary::from_ports(&self.check_expectations(ports)?))
+    }
+
+    /// Find who is using `port`, for example to see which process took a port that something else failed to bind.
+    ///
+    /// Instead of listing the ports of the selected processes, the port is looked up in the socket tables of the whole
+    /// system. Only the protocol, address family and [PortQuery::tcp_states] filters of the query are used, and the
+    /// processes it selects and its expectations are ignored. As for a query, TCP sockets are by default only found
+    /// while they are listening. The owners are sorted by protocol, address family and pid, and nothing is returned
+    /// when the port isn't in use.
+    ///
+    /// On Linux the tables are those of the network namespace of this process, and a socket which no readable process
+    /// holds, such as one of another user's process, is owned by [OwnerKind::Unknown]. On Windows, sockets of the
+    /// System process and unowned sockets are returned as such, whatever [PortQuery::include_system_owned] is set to.
+    /// On macOS, sockets which lsof can't see are not found at all.
+    ///
+    /// ```rust
+    /// use proc_ctl::{OwnerKind, PortQuery};
+    ///
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let port = listener.local_addr().unwrap().port();
+    ///
+    /// let owners = PortQuery::new().tcp_only().owners_of(port).unwrap();
+    /// assert_eq!(OwnerKind::Process(std::process::id()), owners[0].owner);
+    /// ```
+    pub fn owners_of(&self, port: Port) -> ProcCtlResult<Vec<PortOwner>> {
+        self.validate_filters()?;
+
         #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
-        let ports = list_ports_for_pid(self, crate::common::resolve_pid(self)?)?;
-        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
-        let ports = Vec::with_capacity(0);
+        {
+            let mut owners = owners_of_port(self, port)?;
+            owners.sort_by_key(|o| {
+                let pid = match o.owner {
+                    OwnerKind::Process(pid) => Some(pid),
+                    _ => None,
+                };
+                (o.port, o.family, pid)
+            });
 
-        if let Some(num) = &self.min_num_ports {
-            if ports.len() < *num {
-                return Err(ProcCtlError::TooFewPorts(ports, *num));
+            #[cfg(feature = "proc")]
+            {
+                let pids = owners
+                    .iter()
+                    .filter_map(|o| match o.owner {
+                        OwnerKind::Process(pid) => Some(pid),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let names = crate::proc_query::process_names(&pids);
+                for o in &mut owners {
+                    if let OwnerKind::Process(pid) = o.owner {
+                        o.name = names.get(&pid).cloned();
+                    }
+                }
             }
+
+            Ok(owners)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        {
+            let _ = port;
+            Err(ProcCtlError::UnsupportedPlatform(
+                "looking up ports is only supported on Linux, Windows and macOS".to_string(),
+            ))
         }
+    }
 
-        Ok(ports)
+    /// Find the unix domain sockets which the selected processes are listening on, by path or, on Linux, by name in
+    /// the abstract namespace.
+    ///
+    /// Many daemons are reached through a unix socket rather than a port. The processes are selected as for
+    /// [PortQuery::execute], including [PortQuery::include_children], and a socket which several of them hold is listed
+    /// once. The port filters and expectations of the query don't apply to unix sockets, so they are ignored.
+    ///
+    /// On Linux the sockets are read from `/proc`, and only sockets which are bound but not connected are found, which
+    /// includes datagram sockets which are bound to a path. On macOS they are listed by lsof, and every socket bound to
+    /// a path is found. On Windows this fails with [ProcCtlError::UnsupportedPlatform].
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    ///
+    /// let sockets = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .unix_sockets()
+    ///     .unwrap();
+    ///
+    /// for socket in sockets {
+    ///     println!("{}", socket); // For example, unix /run/my-server.sock (pid 55932)
+    /// }
+    /// ```
+    pub fn unix_sockets(&self) -> ProcCtlResult<Vec<crate::unix_sockets::UnixSocket>> {
+        self.validate()?;
+
+        #[cfg(target_os = "linux")]
+        {
+            crate::re_resolve::resolve_and_query(
+                self.re_resolve_limit(),
+                || self.describe_selection(),
+                || self.resolve_pids(true),
+                |pids| {
+                    let sockets =
+                        crate::unix_sockets::of_pids(pids, crate::unix_sockets::of_linux_pid)?;
+
+                    // Checked after reading, as for ports
+                    if let Some(pidfd) = &self.process_fd {
+                        pidfd.ensure_running()?;
+                    }
+
+                    Ok(sockets)
+                },
+            )
+        }
+        #[cfg(target_os = "macos")]
+        {
+            crate::re_resolve::resolve_and_query(
+                self.re_resolve_limit(),
+                || self.describe_selection(),
+                || self.resolve_pids(true),
+                |pids| {
+                    let listed = crate::unix_sockets::lsof_unix_sockets(
+                        self.tool_runner.as_ref(),
+                        self.max_tool_concurrency,
+                    )?;
+                    crate::unix_sockets::of_pids(pids, |pid| {
+                        Ok(listed.iter().filter(|s| s.pid == pid).cloned().collect())
+                    })
+                },
+            )
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        {
+            Err(ProcCtlError::UnsupportedPlatform(
+                "unix domain sockets are only supported on Linux and macOS".to_string(),
+            ))
+        }
     }
 
-    /// Execute the query and retry until it succeeds or exhausts the configured retries
-    #[cfg(feature = "resilience")]
-    pub fn execute_with_retry_sync(
-        &self,
-        delay: std::time::Duration,
-        count: usize,
-    ) -> ProcCtlResult<Vec<ProtocolPort>> {
-        retry::retry(retry::delay::Fixed::from(delay).take(count), || {
-            self.execute()
-        })
-        .map_err(|e| e.error)
+    /// Execute the query, returning the detailed ports together with their summary and when and how quickly they were
+    /// found. See [crate::results] for how this relates to the other forms of result.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    ///
+    /// let report = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .execute_report()
+    ///     .unwrap();
+    ///
+    /// println!("{}", report); // For example, tcp4: 8080 from pid 55932 in 3.20ms
+    /// ```
+    pub fn execute_report(&self) -> ProcCtlResult<crate::results::QueryReport> {
+        let start = self.clock.now();
+        let ports = self.check_expectations(self.list_ports(true)?)?;
+
+        Ok(crate::results::QueryReport::builder()
+            .ports(ports)
+            .elapsed(crate::time::elapsed_since(self.clock.as_ref(), start))
+            .build())
     }
 
-    /// Async equivalent of `execute_with_retry_sync`
-    #[cfg(feature = "async")]
-    #[async_recursion::async_recursion]
-    pub async fn execute_with_retry(
+    /// Execute the query and write each port to `writer`, rather than returning them.
+    ///
+    /// This does not stream the ports as they are read. They are collected first, because the ports of each process are
+    /// merged, sorted and checked against the expectations together. Only the serialized output is never held in full,
+    /// because each record is written and the writer flushed before the next one is serialized.
+    ///
+    /// If the query fails then, in [crate::ExportFormat::JsonLines] mode, a final record is written before the error is
+    /// returned, with an `error` field holding the serialized error.
+    #[cfg(feature = "serde")]
+    pub fn execute_to_writer(
         &self,
-        delay: std::time::Duration,
-        count: usize,
-    ) -> ProcCtlResult<Vec<ProtocolPort>> {
-        match self.execute() {
-            Ok(ports) => Ok(ports),
+        writer: &mut impl std::io::Write,
+        format: crate::export::ExportFormat,
+    ) -> ProcCtlResult<()> {
+        let mut records = crate::export::RecordWriter::new(writer, format);
+
+        let ports = match self.list_ports(false) {
+            Ok(ports) => ports,
             Err(e) => {
-                if count == 0 {
-                    Err(e)
-                } else {
-                    tokio::time::sleep(delay).await;
-                    self.execute_with_retry(delay, count - 1).await
-                }
+                records.write_error(&e)?;
+                return Err(e);
             }
+        };
+
+        for port in &ports {
+            records.write(&port.port)?;
+        }
+
+        if let Err(e) = self.check_expectations(ports) {
+            records.write_error(&e)?;
+            return Err(e);
+        }
+
+        records.finish::<ProtocolPort>()
+    }
+
+    /// Run the query against a [crate::Snapshot] rather than the running system, for example one loaded from a
+    /// capture sent with a bug report.
+    ///
+    /// The processes are selected from [crate::Snapshot::processes] and their ports from
+    /// [crate::Snapshot::sockets], then filtered and checked just as [PortQuery::execute] would. A process name
+    /// matches the name of a process, with or without `.exe`, or the file name of its first argument, so a snapshot
+    /// taken on one platform can be queried on another.
+    ///
+    /// Nothing can be connected to, so [PortQuery::verify_accepting] is not supported, and nor are
+    /// [PortQuery::track] and [PortQuery::process_fd], which need the running process. These fail with
+    /// [ProcCtlError::ConfigurationError].
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::{PortQuery, Snapshot};
+    ///
+    /// let snapshot = Snapshot::load("captured.json").unwrap();
+    /// let ports = PortQuery::new()
+    ///     .process_name("my-server")
+    ///     .execute_on(&snapshot)
+    ///     .unwrap();
+    /// ```
+    #[cfg(all(feature = "serde", feature = "proc"))]
+    pub fn execute_on(&self, snapshot: &crate::Snapshot) -> ProcCtlResult<Vec<ProtocolPort>> {
+        Ok(self
+            .execute_detailed_on(snapshot)?
+            .into_iter()
+            .map(|p| p.port)
+            .collect())
+    }
+
+    /// Run the query against a [crate::Snapshot] like [PortQuery::execute_on], returning detailed information about
+    /// each port
+    #[cfg(all(feature = "serde", feature = "proc"))]
+    pub fn execute_detailed_on(&self, snapshot: &crate::Snapshot) -> ProcCtlResult<Vec<PortInfo>> {
+        self.validate()?;
+        if self.verify_accepting {
+            return Err(ProcCtlError::ConfigurationError(
+                "ports in a snapshot can't be checked for accepting connections".to_string(),
+            ));
+        }
+
+        let pids = self.resolve_pids_in(snapshot)?;
+        let ports = self.port_filter().apply(
+            snapshot
+                .sockets
+                .iter()
+                .filter(|info| pids.contains(&info.pid))
+                .cloned(),
+        );
+        self.check_expectations(self.sort(self.merge_tree(ports)))
+    }
+
+    /// Apply the filters and expectations of this query to ports which were found earlier, for
+    /// [crate::port_filters::apply]
+    #[cfg(feature = "serde")]
+    pub(crate) fn filter_captured(
+        &self,
+        ports: impl IntoIterator<Item = PortInfo>,
+    ) -> ProcCtlResult<Vec<PortInfo>> {
+        self.validate_filters()?;
+        self.validate_expectations()?;
+        if self.verify_accepting {
+            return Err(ProcCtlError::ConfigurationError(
+                "captured ports can't be checked for accepting connections".to_string(),
+            ));
+        }
+
+        self.check_expectations(self.sort(self.port_filter().apply(ports)))
+    }
+
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub(crate) fn list_ports(&self, detailed: bool) -> ProcCtlResult<Vec<PortInfo>> {
+        self.list_ports_with(detailed, true)
+    }
+
+    /// List the ports, waiting for shared resources if `wait` is set or failing with [ProcCtlError::WouldBlock] if not
+    fn list_ports_with(&self, detailed: bool, wait: bool) -> ProcCtlResult<Vec<PortInfo>> {
+        self.validate()?;
+
+        let deadline = self.deadline();
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        let ports = crate::re_resolve::resolve_and_query(
+            self.re_resolve_limit(),
+            || self.describe_selection(),
+            || {
+                let pids = self.resolve_pids(wait)?;
+                self.check_deadline(deadline, "resolving the processes")?;
+                Ok(pids)
+            },
+            |pids| {
+                let backend = BackendState::load(self, pids, detailed, wait, deadline)?;
+                self.check_deadline(deadline, "reading the sockets")?;
+
+                let mut ports = Vec::new();
+                for pid in pids {
+                    ports.extend(self.ports_of_pid(*pid, &backend, detailed)?);
+                    self.check_deadline(deadline, "reading the ports of each process")?;
+                }
+                let ports = self.sort(self.merge_tree(ports));
+
+                // Checked after reading, so that the ports can't have come from a process which reused the pid
+                #[cfg(target_os = "linux")]
+                if let Some(pidfd) = &self.process_fd {
+                    pidfd.ensure_running()?;
+                }
+
+                Ok(ports)
+            },
+        )?;
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        let ports: Vec<PortInfo> = {
+            let _ = (detailed, wait, deadline);
+            return Err(ProcCtlError::UnsupportedPlatform(
+                "listing ports is only supported on Linux, Windows and macOS".to_string(),
+            ));
+        };
+
+        let ports = self.probe_accepting(ports);
+        self.check_deadline(deadline, "checking that the ports accept connections")?;
+        // The lock is never held while querying, so this never waits on another execution
+        Observed::record(
+            &mut self.last_observed.lock().unwrap_or_else(|e| e.into_inner()),
+            &ports,
+        );
+
+        Ok(ports)
+    }
+
+    /// List the ports of one process from a snapshot of the sockets, before they are probed or checked
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn ports_of_pid(
+        &self,
+        pid: Pid,
+        backend: &BackendState,
+        detailed: bool,
+    ) -> ProcCtlResult<Vec<PortInfo>> {
+        let bound_since = if detailed || self.bound_after.is_some() {
+            process_start_time(pid)
+        } else {
+            None
+        };
+
+        let ports = list_ports_for_pid(self, pid, backend)?
+            .into_iter()
+            .map(|found| PortInfo {
+                port: found.port,
+                family: found.family,
+                families: vec![found.family],
+                pid,
+                local_addr: found.local_addr,
+                tcp_state: found.tcp_state,
+                bound_since,
+                backlog: found.backlog,
+                current_queue: found.current_queue,
+                accepting: None,
+                via_socket_activation: found.via_socket_activation,
+                shared_with: found.shared_with,
+                primary_owner: found.primary_owner,
+                multicast_groups: found.multicast_groups,
+                firewall_allowed: found.firewall_allowed,
+                socket_inode: found.socket_inode,
+                fd: found.fd,
+            });
+
+        Ok(self.port_filter().apply(ports))
+    }
+
+    /// List the ports of each of `pids` from one snapshot of the sockets, for [crate::ports_for]. Only the filters and
+    /// expectations of this query are used, not the processes it selects, and each process succeeds or fails alone.
+    #[cfg(all(
+        feature = "proc",
+        any(target_os = "linux", target_os = "windows", target_os = "macos")
+    ))]
+    pub(crate) fn ports_of_each(
+        &self,
+        pids: &[Pid],
+    ) -> ProcCtlResult<Vec<ProcCtlResult<Vec<ProtocolPort>>>> {
+        self.validate_filters()?;
+        let backend = BackendState::load(self, pids, false, true, self.deadline())?;
+
+        Ok(pids
+            .iter()
+            .map(|pid| {
+                let ports =
+                    self.sort(self.probe_accepting(self.ports_of_pid(*pid, &backend, false)?));
+                Ok(self
+                    .check_expectations(ports)?
+                    .into_iter()
+                    .map(|info| info.port)
+                    .collect())
+            })
+            .collect())
+    }
+
+    /// List every port of each of `pids` from one snapshot of the sockets, for [crate::Snapshot::capture]. Each
+    /// address family is listed on its own, and processes which can't be read, such as those of other users without
+    /// the privileges to read them, are left out.
+    ///
+    /// The ports of each process are only listed when the iterator reaches it, so a caller which stops early doesn't
+    /// pay for the rest.
+    #[cfg(all(
+        feature = "serde",
+        feature = "proc",
+        any(target_os = "linux", target_os = "windows", target_os = "macos")
+    ))]
+    pub(crate) fn ports_of_all(
+        pids: Vec<Pid>,
+    ) -> ProcCtlResult<Box<dyn Iterator<Item = PortInfo>>> {
+        let query = PortQuery::new()
+            .split_families(true)
+            .include_system_owned(true);
+        let backend = BackendState::load(&query, &pids, true, true, None)?;
+
+        Ok(Box::new(pids.into_iter().flat_map(move |pid| {
+            query.ports_of_pid(pid, &backend, true).unwrap_or_default()
+        })))
+    }
+
+    fn probe_accepting(&self, mut ports: Vec<PortInfo>) -> Vec<PortInfo> {
+        if self.verify_accepting {
+            for info in &mut ports {
+                if crate::port_filters::is_tcp_listener(info) {
+                    info.accepting =
+                        Some(accepts_connections(self.probe_address, info.port.port()));
+                }
+            }
+        }
+
+        ports
+    }
+
+    /// When an execution starting now must finish by, see [PortQuery::timeout]
+    fn deadline(&self) -> Option<Deadline> {
+        self.timeout
+            .map(|timeout| Deadline::start(&crate::clock::SystemClock, timeout))
+    }
+
+    /// Fail with [ProcCtlError::Timeout] if `deadline` has passed while the query was `stage`
+    fn check_deadline(&self, deadline: Option<Deadline>, stage: &str) -> ProcCtlResult<()> {
+        match deadline {
+            Some(deadline) if deadline.remaining(&crate::clock::SystemClock).is_zero() => {
+                Err(self.timed_out(stage))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn timed_out(&self, stage: &str) -> ProcCtlError {
+        ProcCtlError::Timeout(self.timeout.unwrap_or_default(), stage.to_string())
+    }
+
+    /// Reject configurations which could never find a port, rather than returning an empty list which a retry loop
+    /// would wait on forever
+    fn validate(&self) -> ProcCtlResult<()> {
+        self.validate_filters()?;
+        self.validate_expectations()?;
+        if self.process_id == Some(0) || self.process_ids.contains(&0) {
+            return Err(ProcCtlError::ConfigurationError(
+                "pid 0 is not a process, so it never has any ports".to_string(),
+            ));
+        }
+        if self.include_children && self.selected_process_ids().is_some() {
+            return Err(ProcCtlError::ConfigurationError(
+                "the children of processes selected by process_ids can't be included".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the protocol and address family filters leave something which can match
+    fn validate_filters(&self) -> ProcCtlResult<()> {
+        if !self.tcp_addresses && !self.udp_addresses {
+            return Err(ProcCtlError::ConfigurationError(
+                "both TCP and UDP are excluded, so no ports can match".to_string(),
+            ));
+        }
+        if !self.ipv4_addresses && !self.ipv6_addresses {
+            return Err(ProcCtlError::ConfigurationError(
+                "both IPv4 and IPv6 are excluded, so no ports can match".to_string(),
+            ));
+        }
+        if !self.udp_addresses && self.tcp_states.is_empty() {
+            return Err(ProcCtlError::ConfigurationError(
+                "no TCP states are included and UDP is excluded, so no ports can match".to_string(),
+            ));
+        }
+        if let Some((min, max)) = self.port_range.filter(|(min, max)| min > max) {
+            return Err(ProcCtlError::ConfigurationError(format!(
+                "the port range {} to {} is empty, so no ports can match",
+                min, max
+            )));
+        }
+        if let Some(group) = self.joined_group.filter(|group| !group.is_multicast()) {
+            return Err(ProcCtlError::ConfigurationError(format!(
+                "{} is not a multicast group, so no ports can have joined it",
+                group
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the expectations can all be met at once
+    fn validate_expectations(&self) -> ProcCtlResult<()> {
+        if let (Some(min), Some(max)) = (self.min_num_ports, self.max_num_ports) {
+            if min > max {
+                return Err(ProcCtlError::ConfigurationError(format!(
+                    "at least {} and at most {} ports are expected, so the expectation can never be met",
+                    min, max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn resolve_pids(&self, wait: bool) -> ProcCtlResult<Vec<Pid>> {
+        let pids = self.resolve_selected_pids(wait)?;
+
+        if self.include_children {
+            let mut with_children = pids.clone();
+            for pid in &pids {
+                with_children.extend(descendant_pids(*pid, wait)?);
+            }
+            with_children.sort_unstable();
+            with_children.dedup();
+
+            return Ok(with_children);
+        }
+
+        Ok(pids)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn resolve_selected_pids(&self, wait: bool) -> ProcCtlResult<Vec<Pid>> {
+        #[cfg(feature = "proc")]
+        if let Some(selector) = &self.track {
+            let pids = if wait {
+                selector.resolve()
+            } else {
+                selector.try_resolve()?
+            };
+            return self.select_matches(pids, || format!("{:?}", selector));
+        }
+
+        if let Some(name) = &self.process_name {
+            let pids = pids_by_name(name, wait)?;
+            return self.select_matches(pids, || format!("name {}", name));
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.process_fd {
+            return Ok(vec![pidfd.pid()?]);
+        }
+
+        let _ = wait;
+        if !self.process_ids.is_empty() {
+            return Ok(self.process_ids.clone());
+        }
+
+        Ok(vec![crate::common::resolve_pid(self)?])
+    }
+
+    /// The pids selected by [PortQuery::process_ids], if they are how this query selects its processes
+    fn selected_process_ids(&self) -> Option<&[Pid]> {
+        #[cfg(feature = "proc")]
+        if self.track.is_some() {
+            return None;
+        }
+        #[cfg(target_os = "linux")]
+        if self.process_fd.is_some() {
+            return None;
+        }
+
+        (self.process_name.is_none() && !self.process_ids.is_empty())
+            .then_some(self.process_ids.as_slice())
+    }
+
+    /// How many times the selected processes can be resolved again, which is never for a process selected by pid
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn re_resolve_limit(&self) -> usize {
+        #[cfg(feature = "proc")]
+        let tracked = self.track.is_some();
+        #[cfg(not(feature = "proc"))]
+        let tracked = false;
+
+        if tracked || self.process_name.is_some() {
+            self.re_resolve_attempts
+        } else {
+            0
+        }
+    }
+
+    /// Describe how the processes are selected, for errors
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn describe_selection(&self) -> String {
+        #[cfg(feature = "proc")]
+        if let Some(selector) = &self.track {
+            return format!("{:?}", selector);
+        }
+
+        match &self.process_name {
+            Some(name) => format!("name {}", name),
+            None if !self.process_ids.is_empty() => format!("pids {:?}", self.process_ids),
+            None => format!("pid {:?}", self.process_id),
+        }
+    }
+
+    /// How many times [crate::ports_for] can list the processes again, see [PortQuery::re_resolve_attempts]
+    #[cfg(all(
+        feature = "proc",
+        any(target_os = "linux", target_os = "windows", target_os = "macos")
+    ))]
+    pub(crate) fn re_resolve_attempts_for_each(&self) -> usize {
+        self.re_resolve_attempts
+    }
+
+    fn select_matches(
+        &self,
+        pids: Vec<Pid>,
+        describe: impl FnOnce() -> String,
+    ) -> ProcCtlResult<Vec<Pid>> {
+        match pids.len() {
+            0 => Err(ProcCtlError::NoMatchingProcess(describe())),
+            1 => Ok(pids),
+            _ if self.multiple_matches == MultipleMatchPolicy::Aggregate => Ok(pids),
+            _ => Err(ProcCtlError::MultipleMatchingProcesses(pids)),
+        }
+    }
+
+    /// Select the processes of a [crate::Snapshot], as [PortQuery::resolve_pids] does for the running system
+    #[cfg(all(feature = "serde", feature = "proc"))]
+    fn resolve_pids_in(&self, snapshot: &crate::Snapshot) -> ProcCtlResult<Vec<Pid>> {
+        #[cfg(target_os = "linux")]
+        let needs_running = self.track.is_some() || self.process_fd.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let needs_running = self.track.is_some();
+        if needs_running {
+            return Err(ProcCtlError::ConfigurationError(
+                "a tracked process can't be found in a snapshot, select it by pid or name"
+                    .to_string(),
+            ));
+        }
+
+        let mut pids = match &self.process_name {
+            Some(name) => {
+                self.select_matches(snapshot.pids_by_name(name), || format!("name {}", name))?
+            }
+            None => {
+                let pids = if self.process_ids.is_empty() {
+                    vec![crate::common::resolve_pid(self)?]
+                } else {
+                    self.process_ids.clone()
+                };
+                if let Some(pid) = pids.iter().find(|pid| snapshot.process(**pid).is_none()) {
+                    return Err(ProcCtlError::ProcessNotFound(*pid));
+                }
+                pids
+            }
+        };
+
+        if self.include_children {
+            let children = pids
+                .iter()
+                .flat_map(|pid| snapshot.descendant_pids(*pid))
+                .collect::<Vec<_>>();
+            pids.extend(children);
+            pids.sort_unstable();
+            pids.dedup();
+        }
+
+        Ok(pids)
+    }
+
+    /// List a socket shared by the processes of a tree once, see [PortQuery::include_children]
+    fn merge_tree(&self, ports: Vec<PortInfo>) -> Vec<PortInfo> {
+        if self.include_children {
+            crate::types::merge_shared(ports)
+        } else {
+            ports
+        }
+    }
+
+    /// The filters of this query which are applied to each port found
+    pub(crate) fn port_filter(&self) -> crate::port_filters::PortFilter<'_> {
+        crate::port_filters::PortFilter {
+            tcp: self.tcp_addresses,
+            udp: self.udp_addresses,
+            tcp_states: &self.tcp_states,
+            port_range: self.port_range,
+            loopback: self.loopback,
+            ipv4: self.ipv4_addresses,
+            ipv6: self.ipv6_addresses,
+            bound_after: self.bound_after,
+            joined_group: self.joined_group,
+            split_families: self.split_families,
+        }
+    }
+
+    fn expectations(&self) -> crate::port_filters::PortExpectations<'_> {
+        crate::port_filters::PortExpectations {
+            min_num_ports: self.min_num_ports,
+            max_num_ports: self.max_num_ports,
+            min_backlog: self.min_backlog,
+            allowed_ports: self.allowed_ports.as_ref(),
+            expected_ports: (!self.expected_ports.is_empty()).then_some(&self.expected_ports),
+            expect_accepting: self.expect_accepting,
+        }
+    }
+
+    /// Sort the ports if [PortQuery::sorted] is set
+    fn sort(&self, mut ports: Vec<PortInfo>) -> Vec<PortInfo> {
+        if self.sorted {
+            crate::types::sort_ports(&mut ports);
+        }
+        ports
+    }
+
+    /// Check the expectations against the ports, or against the ports of each process selected by
+    /// [PortQuery::process_ids]
+    fn check_expectations(&self, ports: Vec<PortInfo>) -> ProcCtlResult<Vec<PortInfo>> {
+        let Some(pids) = self.selected_process_ids() else {
+            return self.expectations().check(ports);
+        };
+
+        for pid in pids {
+            let own = ports.iter().filter(|p| p.pid == *pid).cloned().collect();
+            self.expectations().check(own)?;
+        }
+        Ok(ports)
+    }
+
+    fn check_allowed(&self, ports: &[PortInfo]) -> ProcCtlResult<()> {
+        self.expectations().check_allowed(ports)
+    }
+
+    /// Check that the process never binds a port outside the allowlist set with [PortQuery::forbid_ports_except],
+    /// running the query straight away and then once per `interval` until `duration` has passed.
+    ///
+    /// Fails with [ProcCtlError::ForbiddenPorts] as soon as a forbidden port is found, or with the error of the query
+    /// if it fails, for example because the process has exited. Other expectations, such as
+    /// [PortQuery::expect_min_num_ports], are ignored.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::{PortQuery, ProtocolPort};
+    /// use std::time::Duration;
+    ///
+    /// let query = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .forbid_ports_except([ProtocolPort::Tcp(8080)]);
+    ///
+    /// // Run the scenario under test on another thread, then
+    /// query
+    ///     .monitor_for(Duration::from_secs(60), Duration::from_millis(500))
+    ///     .unwrap();
+    /// ```
+    pub fn monitor_for(&self, duration: Duration, interval: Duration) -> ProcCtlResult<()> {
+        self.validate_monitor()?;
+        crate::monitor::monitor_sync(self.clock.as_ref(), duration, interval, || {
+            self.check_allowed(&self.list_ports(false)?)
+        })
+    }
+
+    /// Async equivalent of [PortQuery::monitor_for]
+    #[cfg(feature = "async")]
+    pub async fn monitor_for_async(
+        &self,
+        duration: Duration,
+        interval: Duration,
+    ) -> ProcCtlResult<()> {
+        self.validate_monitor()?;
+        crate::monitor::monitor_async(self.clock.as_ref(), duration, interval, || {
+            self.check_allowed(&self.list_ports(false)?)
+        })
+        .await
+    }
+
+    fn validate_monitor(&self) -> ProcCtlResult<()> {
+        if self.allowed_ports.is_none() {
+            return Err(ProcCtlError::ConfigurationError(
+                "monitoring needs the allowed ports to be set with forbid_ports_except".to_string(),
+            ));
+        }
+
+        self.validate()
+    }
+
+    /// Wait until the process no longer holds `port`, checking straight away and then once per `delay` until `timeout`
+    /// has passed.
+    ///
+    /// The process exiting counts as releasing the port rather than failing the wait, and the result says which
+    /// happened. The processes are selected when the wait starts and identified by their start time, so if one exits
+    /// and its pid is reused by a new process, even one which binds the same port, it still counts as exited. If no
+    /// process matches when the wait starts then it has already exited.
+    ///
+    /// Fails with [ProcCtlError::PortNotReleased] if the port is still held when the timeout passes, or with the error
+    /// of the query if it fails for another reason. Expectations such as [PortQuery::expect_min_num_ports] are ignored.
+    /// On Windows and macOS this needs the `proc` feature to identify the processes.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::{PortQuery, PortRelease, ProtocolPort};
+    /// use std::time::Duration;
+    ///
+    /// let query = PortQuery::new().process_id(55932); // Get a process ID from somewhere
+    ///
+    /// // Ask the process to shut down, then
+    /// match query
+    ///     .wait_for_release(ProtocolPort::Tcp(8080), Duration::from_millis(100), Duration::from_secs(30))
+    ///     .unwrap()
+    /// {
+    ///     PortRelease::PortClosed => println!("still running, but the port is free"),
+    ///     PortRelease::ProcessExited => println!("exited"),
+    /// }
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub fn wait_for_release(
+        &self,
+        port: ProtocolPort,
+        delay: Duration,
+        timeout: Duration,
+    ) -> ProcCtlResult<crate::release::PortRelease> {
+        let targets = self.release_targets(port)?;
+        let released = crate::release::wait_sync(self.clock.as_ref(), delay, timeout, || {
+            self.release_status(&targets, port)
+        })?;
+
+        released.ok_or_else(|| Self::not_released(&targets, port))
+    }
+
+    /// Async equivalent of [PortQuery::wait_for_release]
+    #[cfg(all(
+        feature = "async",
+        any(target_os = "linux", target_os = "windows", target_os = "macos")
+    ))]
+    pub async fn wait_for_release_async(
+        &self,
+        port: ProtocolPort,
+        delay: Duration,
+        timeout: Duration,
+    ) -> ProcCtlResult<crate::release::PortRelease> {
+        let targets = self.release_targets(port)?;
+        let released = crate::release::wait_async(self.clock.as_ref(), delay, timeout, || {
+            self.release_status(&targets, port)
+        })
+        .await?;
+
+        released.ok_or_else(|| Self::not_released(&targets, port))
+    }
+
+    /// Select and identify the processes to wait on, which is none if they have already exited
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn release_targets(&self, port: ProtocolPort) -> ProcCtlResult<Vec<crate::release::Target>> {
+        self.validate()?;
+        let included = match port {
+            ProtocolPort::Tcp(_) => self.tcp_addresses,
+            ProtocolPort::Udp(_) => self.udp_addresses,
+        };
+        if !included {
+            return Err(ProcCtlError::ConfigurationError(format!(
+                "can't wait for {:?} to be released with a query which leaves out its protocol",
+                port
+            )));
+        }
+
+        let pids = match self.resolve_pids(true) {
+            Ok(pids) => pids,
+            Err(e) if e.kind() == crate::error::ErrorKind::ProcessNotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut targets = Vec::with_capacity(pids.len());
+        for pid in pids {
+            if let Some(start) = process_identity(pid)? {
+                targets.push(crate::release::Target { pid, start });
+            }
+        }
+
+        Ok(targets)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn release_status(
+        &self,
+        targets: &[crate::release::Target],
+        port: ProtocolPort,
+    ) -> ProcCtlResult<Option<crate::release::PortRelease>> {
+        crate::release::release_status(targets, process_identity, |pids| {
+            let backend = BackendState::load(self, pids, false, true, self.deadline())?;
+            for pid in pids {
+                match list_ports_for_pid(self, *pid, &backend) {
+                    Ok(found) if found.iter().any(|f| f.port == port) => return Ok(true),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == crate::error::ErrorKind::ProcessNotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Ok(false)
+        })
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn not_released(targets: &[crate::release::Target], port: ProtocolPort) -> ProcCtlError {
+        ProcCtlError::PortNotReleased(port, targets.iter().map(|t| t.pid).collect())
+    }
+
+    /// Watch for changes to the ports of the process, running the query once per `interval` and blocking until each
+    /// change is found.
+    ///
+    /// Expectations such as [PortQuery::expect_min_num_ports] are ignored. The watcher ends with
+    /// [crate::PortEvent::ProcessExited] once the process has exited, see [crate::PortWatcher].
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::{PortEvent, PortQuery};
+    /// use std::time::Duration;
+    ///
+    /// let query = PortQuery::new().process_id(55932); // Get a process ID from somewhere
+    /// for event in query.watch(Duration::from_millis(500)) {
+    ///     match event.unwrap() {
+    ///         PortEvent::PortBound(port) => println!("bound {:?}", port),
+    ///         PortEvent::PortReleased(port) => println!("released {:?}", port),
+    ///         PortEvent::ProcessExited => println!("exited"),
+    ///     }
+    /// }
+    /// ```
+    pub fn watch(self, interval: Duration) -> crate::watch::PortWatcher {
+        crate::watch::PortWatcher::new(self, interval)
+    }
+
+    /// Watch for changes to the ports of the process, running the query once per `interval`.
+    ///
+    /// Expectations such as [PortQuery::expect_min_num_ports] are ignored. See [crate::PortEvents] for how changes
+    /// are reported.
+    #[cfg(feature = "async")]
+    pub fn events(self, interval: std::time::Duration) -> crate::watch::PortEvents {
+        crate::watch::PortEvents::new(self, interval)
+    }
+
+    /// Wait until the query succeeds, including any expectations such as [PortQuery::expect_min_num_ports].
+    ///
+    /// Fails with [ProcCtlError::WaitTimedOut] if the query is still failing when the timeout passes.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_port(
+        &self,
+        options: &crate::wait::WaitOptions,
+    ) -> ProcCtlResult<crate::wait::WaitOutcome<Vec<ProtocolPort>>> {
+        self.validate()?;
+
+        crate::wait::wait_until(options, self.clock.as_ref(), || self.wait_check()).await
+    }
+
+    /// Blocking equivalent of [PortQuery::wait_for_port]
+    pub fn wait_for_port_sync(
+        &self,
+        options: &crate::wait::WaitOptions,
+    ) -> ProcCtlResult<crate::wait::WaitOutcome<Vec<ProtocolPort>>> {
+        self.validate()?;
+
+        crate::wait::wait_until_sync(options, self.clock.as_ref(), || self.wait_check())
+    }
+
+    /// Execute the query once for a wait, describing the result for its history
+    fn wait_check(&self) -> (Option<Vec<ProtocolPort>>, String) {
+        let result = self.execute();
+        let seen = crate::wait::describe(result.as_ref());
+        (result.ok(), seen)
+    }
+
+    /// Execute the query until it succeeds, making at most `count` attempts with `delay` between them.
+    ///
+    /// In earlier releases `count` was the number of retries after the first attempt, so one more attempt was made
+    /// than requested. At least one attempt is always made, and [PortQuery::fail_fast_on_stall] can stop before the
+    /// last.
+    #[cfg(feature = "resilience")]
+    pub fn execute_with_retry_sync(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<ProtocolPort>> {
+        let mut detector = self.stall_attempts.map(crate::stall::StallDetector::new);
+        crate::retrying::retry_sync_or_give_up(
+            self.clock.as_ref(),
+            delay,
+            count,
+            || self.execute(),
+            |e| self.give_up(e, detector.as_mut()?),
+        )
+    }
+
+    /// Async equivalent of `execute_with_retry_sync`, with the same number of attempts
+    #[cfg(feature = "async")]
+    pub async fn execute_with_retry(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<ProtocolPort>> {
+        let mut detector = self.stall_attempts.map(crate::stall::StallDetector::new);
+        crate::retrying::retry_async_or_give_up(
+            self.clock.as_ref(),
+            delay,
+            count,
+            || self.execute(),
+            |e| self.give_up(e, detector.as_mut()?),
+        )
+        .await
+    }
+
+    /// Execute the query until one of the TCP listeners it finds accepts a connection, making at most `count` attempts
+    /// with `delay` between them, and return that port.
+    ///
+    /// A socket can be listed before the process has started accepting on it, so this waits for a process to be
+    /// ready to serve rather than just to have bound its port. Connections are made to [PortQuery::probe_address] if
+    /// it is set, otherwise to the [PortInfo::connectable_addr] of the listener, which is loopback for a listener bound
+    /// to every address. Each connection is closed straight away, and each attempt to connect gives up after 200ms.
+    ///
+    /// Fails with the error of the last attempt when every attempt has failed: [ProcCtlError::TooFewPorts] if no TCP
+    /// listener was found, or [ProcCtlError::NotAccepting] for the first listener found if none accepted a
+    /// connection. Expectations such as [PortQuery::expect_min_num_ports] must be met as well.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::PortQuery;
+    /// use std::time::Duration;
+    ///
+    /// let port = PortQuery::new()
+    ///     .process_id(55932) // Get a process ID from somewhere
+    ///     .tcp_only()
+    ///     .wait_for_connectable(Duration::from_millis(100), 50)
+    ///     .unwrap();
+    ///
+    /// println!("{:?} is accepting connections", port);
+    /// ```
+    #[cfg(feature = "resilience")]
+    pub fn wait_for_connectable(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<ProtocolPort> {
+        let mut detector = self.stall_attempts.map(crate::stall::StallDetector::new);
+        crate::retrying::retry_sync_or_give_up(
+            self.clock.as_ref(),
+            delay,
+            count,
+            || {
+                let listeners = self.connect_targets()?;
+                for (port, address) in &listeners {
+                    if TcpStream::connect_timeout(address, PROBE_TIMEOUT).is_ok() {
+                        return Ok(*port);
+                    }
+                }
+                Err(ProcCtlError::NotAccepting(listeners[0].0))
+            },
+            |e| self.give_up(e, detector.as_mut()?),
+        )
+    }
+
+    /// Async equivalent of [PortQuery::wait_for_connectable], connecting without blocking the async runtime
+    #[cfg(feature = "async")]
+    pub async fn wait_for_connectable_async(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<ProtocolPort> {
+        let mut detector = self.stall_attempts.map(crate::stall::StallDetector::new);
+        crate::retrying::retry_future_or_give_up(
+            self.clock.as_ref(),
+            delay,
+            count,
+            || async {
+                let listeners = self.connect_targets()?;
+                for (port, address) in &listeners {
+                    let connect = tokio::net::TcpStream::connect(address);
+                    if let Ok(Ok(_)) = tokio::time::timeout(PROBE_TIMEOUT, connect).await {
+                        return Ok(*port);
+                    }
+                }
+                Err(ProcCtlError::NotAccepting(listeners[0].0))
+            },
+            |e| self.give_up(e, detector.as_mut()?),
+        )
+        .await
+    }
+
+    /// Find the TCP listeners for [PortQuery::wait_for_connectable], with the address to connect to each of them.
+    /// Fails with [ProcCtlError::TooFewPorts] rather than returning no listeners.
+    #[cfg(any(feature = "resilience", feature = "async"))]
+    fn connect_targets(&self) -> ProcCtlResult<Vec<(ProtocolPort, SocketAddr)>> {
+        // Only the address and state of each port are needed, which are found without the details
+        let ports = self.check_expectations(self.list_ports(false)?)?;
+        let listeners = ports
+            .iter()
+            .filter(|info| crate::port_filters::is_tcp_listener(info))
+            .map(|info| match self.probe_address {
+                Some(address) => (info.port, SocketAddr::new(address, info.port.port())),
+                None => (info.port, info.connectable_addr()),
+            })
+            .collect::<Vec<_>>();
+
+        if listeners.is_empty() {
+            return Err(ProcCtlError::TooFewPorts(
+                ports.into_iter().map(|p| p.port).collect(),
+                1,
+            ));
+        }
+        Ok(listeners)
+    }
+
+    /// Whether a retry should stop after `e`, see [PortQuery::fail_fast_on_stall]
+    #[cfg(any(feature = "resilience", feature = "async"))]
+    fn give_up(
+        &self,
+        e: &ProcCtlError,
+        detector: &mut crate::stall::StallDetector,
+    ) -> Option<ProcCtlError> {
+        #[cfg(feature = "proc")]
+        let tracked = self.track.is_some();
+        #[cfg(not(feature = "proc"))]
+        let tracked = false;
+        let by_pid = self
+            .process_id
+            .filter(|_| !tracked && self.process_name.is_none() && self.process_ids.is_empty());
+
+        crate::stall::give_up(e, by_pid, detector, || self.all_sockets())
+    }
+
+    /// Every listening TCP socket and UDP socket of the selected processes, whether or not it passes the filters
+    #[cfg(any(feature = "resilience", feature = "async"))]
+    fn all_sockets(&self) -> ProcCtlResult<Vec<(ProtocolPort, AddressFamily)>> {
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        {
+            let all = PortQuery::new()
+                .split_families(true)
+                .max_tool_concurrency(self.max_tool_concurrency)
+                .with_tool_runner(self.tool_runner.clone());
+            let pids = self.resolve_pids(true)?;
+            let backend = BackendState::load(&all, &pids, false, true, self.deadline())?;
+
+            let mut sockets = Vec::new();
+            for pid in pids {
+                sockets.extend(
+                    all.ports_of_pid(pid, &backend, false)?
+                        .into_iter()
+                        .map(|info| (info.port, info.family)),
+                );
+            }
+            Ok(sockets)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        Err(ProcCtlError::UnsupportedPlatform(
+            "listing ports is only supported on Linux, Windows and macOS".to_string(),
+        ))
+    }
+}
+
+pub(crate) const DEFAULT_MAX_TOOL_CONCURRENCY: usize = 4;
+
+/// Which of two options a query is restricted to, if it is restricted to one of them
+#[cfg(feature = "serde")]
+fn only_one<T>(first: bool, second: bool, first_value: T, second_value: T) -> Option<T> {
+    match (first, second) {
+        (true, false) => Some(first_value),
+        (false, true) => Some(second_value),
+        _ => None,
+    }
+}
+
+/// How long to wait for each connection made by [PortQuery::verify_accepting]
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// On Linux, processes are looked up from `/proc` whether or not the `proc` feature is enabled, so that a query
+/// behaves the same with either set of features
+#[cfg(target_os = "linux")]
+fn pids_by_name(name: &str, _wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    crate::proc_scan::pids_by_name(name)
+}
+
+#[cfg(all(feature = "proc", any(target_os = "windows", target_os = "macos")))]
+fn pids_by_name(name: &str, wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    let selector = crate::proc_query::ProcSelector::Name(name.to_string());
+    if wait {
+        Ok(selector.resolve())
+    } else {
+        selector.try_resolve()
+    }
+}
+
+/// Find the children of `pid`, their children and so on, in ascending order. A descendant which exits while the tree
+/// is being walked is left out, along with its children.
+#[cfg(target_os = "linux")]
+fn descendant_pids(pid: Pid, _wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    let mut pids = crate::proc_scan::child_pids(pid)?;
+    let mut parents = pids.clone();
+    while let Some(parent) = parents.pop() {
+        let children = match crate::proc_scan::child_pids(parent) {
+            Ok(children) => children,
+            Err(ProcCtlError::ProcessNotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        for child in children {
+            if child != pid && !pids.contains(&child) {
+                pids.push(child);
+                parents.push(child);
+            }
+        }
+    }
+    pids.sort_unstable();
+
+    Ok(pids)
+}
+
+#[cfg(all(feature = "proc", any(target_os = "windows", target_os = "macos")))]
+fn descendant_pids(pid: Pid, wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    crate::proc_query::descendant_pids(pid, wait)
+}
+
+/// Elsewhere processes can only be looked up through sysinfo, so a query which needs to fails without the `proc`
+/// feature rather than quietly finding nothing
+#[cfg(all(not(feature = "proc"), any(target_os = "windows", target_os = "macos")))]
+fn pids_by_name(_name: &str, _wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    Err(ProcCtlError::UnsupportedWithoutFeature("proc"))
+}
+
+#[cfg(all(not(feature = "proc"), any(target_os = "windows", target_os = "macos")))]
+fn descendant_pids(_pid: Pid, _wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    Err(ProcCtlError::UnsupportedWithoutFeature("proc"))
+}
+
+/// Connect to a TCP port and close the connection straight away, trying loopback if no address is given
+fn accepts_connections(address: Option<IpAddr>, port: Port) -> bool {
+    let addresses = match address {
+        Some(address) => vec![address],
+        None => vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+    };
+
+    addresses
+        .into_iter()
+        .any(|ip| TcpStream::connect_timeout(&SocketAddr::new(ip, port), PROBE_TIMEOUT).is_ok())
+}
+
+/// Multicast group memberships are only read from `/proc`, so [PortQuery::joined_group] can't be checked elsewhere
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn multicast_unsupported() -> ProcCtlError {
+    ProcCtlError::UnsupportedPlatform(
+        "multicast group memberships are only found on Linux".to_string(),
+    )
+}
+
+/// A port found by one of the platform backends, before it is combined with process level details
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[derive(Clone)]
+struct FoundPort {
+    port: ProtocolPort,
+    family: AddressFamily,
+    local_addr: Option<SocketAddr>,
+    tcp_state: Option<TcpState>,
+    backlog: Option<u32>,
+    current_queue: Option<u32>,
+    via_socket_activation: Option<bool>,
+    shared_with: Vec<Pid>,
+    primary_owner: Option<Pid>,
+    multicast_groups: Option<Vec<IpAddr>>,
+    firewall_allowed: Option<bool>,
+    socket_inode: Option<u64>,
+    fd: Option<i32>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+impl FoundPort {
+    fn new(port: ProtocolPort, family: AddressFamily) -> Self {
+        FoundPort {
+            port,
+            family,
+            local_addr: None,
+            tcp_state: None,
+            backlog: None,
+            current_queue: None,
+            via_socket_activation: None,
+            shared_with: Vec::new(),
+            primary_owner: None,
+            multicast_groups: None,
+            firewall_allowed: None,
+            socket_inode: None,
+            fd: None,
+        }
+    }
+
+    /// A port bound to `local_addr`, with the address family to match
+    #[cfg(any(target_os = "windows", target_os = "macos"))]
+    fn at(port: ProtocolPort, local_addr: SocketAddr) -> Self {
+        let family = if local_addr.is_ipv6() {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Ipv4
+        };
+        FoundPort {
+            local_addr: Some(local_addr),
+            ..FoundPort::new(port, family)
+        }
+    }
+}
+
+/// Anything a backend loads once per execution and shares between the processes being queried
+#[cfg(target_os = "linux")]
+struct BackendState {
+    queues: std::collections::HashMap<u64, crate::sock_diag::ListenQueue>,
+    shared: std::collections::HashMap<u64, crate::socket_owners::SharedSocket>,
+    multicast: bool,
+    /// The sockets of each network namespace read so far, keyed by the target of `/proc/<pid>/ns/net`, so that
+    /// processes which share a namespace only read its tables once
+    namespaces: std::cell::RefCell<
+        std::collections::HashMap<std::path::PathBuf, std::rc::Rc<NamespaceSockets>>,
+    >,
+}
+
+#[cfg(target_os = "linux")]
+impl BackendState {
+    fn load(
+        query: &PortQuery,
+        pids: &[Pid],
+        detailed: bool,
+        _wait: bool,
+        _deadline: Option<Deadline>,
+    ) -> ProcCtlResult<Self> {
+        // The backlog is not available from /proc, so ask sock_diag. This is best effort for detailed results since
+        // the interface may not be available, for example in a restricted sandbox, but a backlog expectation can't be
+        // checked without it.
+        let queues = if (detailed || query.min_backlog.is_some()) && query.tcp_addresses {
+            match crate::sock_diag::tcp_listen_queues() {
+                Ok(queues) => queues,
+                Err(e) if query.min_backlog.is_some() => {
+                    return Err(ProcCtlError::from_restricted_io(
+                        "reading listen queues with sock_diag",
+                        e,
+                    ))
+                }
+                Err(_) => Default::default(),
+            }
+        } else {
+            Default::default()
+        };
+        // Finding the other holders of a socket means reading the descriptors of every process, so it is only done
+        // for detailed results, and only the sockets of the queried processes are looked for
+        let shared = if detailed {
+            crate::socket_owners::shared_sockets(&crate::socket_owners::socket_inodes_of(pids))
+        } else {
+            Default::default()
+        };
+
+        // Multicast groups are read for each process, since processes may be in different network namespaces
+        let multicast = (detailed || query.joined_group.is_some()) && query.udp_addresses;
+
+        // The sockets of this process's namespace are read with sock_diag when it is available. Otherwise, for example
+        // in a restricted container, they are read from /proc like those of any other namespace.
+        #[cfg_attr(not(feature = "netlink"), allow(unused_mut))]
+        let mut namespaces = std::collections::HashMap::new();
+        #[cfg(feature = "netlink")]
+        if let (Ok(namespace), Ok(sockets)) = (
+            net_namespace("self"),
+            NamespaceSockets::from_sock_diag(query),
+        ) {
+            namespaces.insert(namespace, std::rc::Rc::new(sockets));
+        }
+
+        Ok(BackendState {
+            queues,
+            shared,
+            multicast,
+            namespaces: std::cell::RefCell::new(namespaces),
+        })
+    }
+}
+
+/// The TCP and UDP sockets of one network namespace which pass the protocol and address family filters of a query
+#[cfg(target_os = "linux")]
+struct NamespaceSockets {
+    tcp: Vec<crate::sock_diag::InetSocket>,
+    udp: Vec<crate::sock_diag::InetSocket>,
+}
+
+#[cfg(target_os = "linux")]
+impl NamespaceSockets {
+    /// Read the sockets from `/proc/<pid>/net`, which lists those of the namespace `proc` is in
+    fn from_proc(query: &PortQuery, proc: &procfs::process::Process) -> ProcCtlResult<Self> {
+        use crate::sock_diag::InetSocket;
+
+        #[cfg(test)]
+        SOCKET_TABLE_READS.with(|reads| reads.set(reads.get() + 1));
+
+        let mut tcp = Vec::new();
+        if query.tcp_addresses {
+            let mut tcp_entries = Vec::new();
+            if query.ipv4_addresses {
+                tcp_entries.extend(proc.tcp()?);
+            }
+            if query.ipv6_addresses {
+                tcp_entries.extend(proc.tcp6()?);
+            }
+
+            tcp.extend(tcp_entries.into_iter().map(|entry| InetSocket {
+                local_address: entry.local_address,
+                state: entry.state.to_u8(),
+                rx_queue: entry.rx_queue,
+                inode: entry.inode,
+            }));
+        }
+
+        let mut udp = Vec::new();
+        if query.udp_addresses {
+            let mut udp_entries = Vec::new();
+            if query.ipv4_addresses {
+                udp_entries.extend(proc.udp()?);
+            }
+            if query.ipv6_addresses {
+                udp_entries.extend(proc.udp6()?);
+            }
+
+            udp.extend(udp_entries.into_iter().map(|entry| InetSocket {
+                local_address: entry.local_address,
+                state: entry.state.to_u8(),
+                rx_queue: entry.rx_queue,
+                inode: entry.inode,
+            }));
+        }
+
+        Ok(NamespaceSockets { tcp, udp })
+    }
+
+    /// Read the sockets of this process's namespace with sock_diag, which is much cheaper than parsing `/proc` when
+    /// there are many sockets since the kernel only returns the TCP states the query is looking for
+    #[cfg(feature = "netlink")]
+    fn from_sock_diag(query: &PortQuery) -> std::io::Result<Self> {
+        #[cfg(test)]
+        SOCKET_TABLE_READS.with(|reads| reads.set(reads.get() + 1));
+
+        let tcp_states = (1..=12u8)
+            .filter(|n| {
+                procfs::net::TcpState::from_u8(*n)
+                    .is_some_and(|state| query.tcp_states.contains(&linux_tcp_state(&state)))
+            })
+            .fold(0u32, |states, n| states | 1 << n);
+        let read = |protocol, states| -> std::io::Result<Vec<_>> {
+            let mut sockets = Vec::new();
+            if query.ipv4_addresses {
+                sockets.extend(crate::sock_diag::inet_sockets(
+                    libc::AF_INET,
+                    protocol,
+                    states,
+                )?);
+            }
+            if query.ipv6_addresses {
+                sockets.extend(crate::sock_diag::inet_sockets(
+                    libc::AF_INET6,
+                    protocol,
+                    states,
+                )?);
+            }
+            Ok(sockets)
+        };
+
+        Ok(NamespaceSockets {
+            tcp: if query.tcp_addresses {
+                read(libc::IPPROTO_TCP, tcp_states)?
+            } else {
+                Vec::new()
+            },
+            // /proc lists UDP sockets in every state
+            udp: if query.udp_addresses {
+                read(libc::IPPROTO_UDP, u32::MAX)?
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
+
+// How many times the current thread has read the socket tables of a namespace, or of the system on Windows, so that
+// tests can check a query over several processes reads them once
+#[cfg(all(test, any(target_os = "linux", target_os = "windows")))]
+thread_local! {
+    static SOCKET_TABLE_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// The network namespace of a process, as the target of its `/proc/<pid>/ns/net` link such as `net:[4026531840]`
+#[cfg(target_os = "linux")]
+fn net_namespace(pid: impl std::fmt::Display) -> std::io::Result<std::path::PathBuf> {
+    std::fs::read_link(format!("/proc/{}/ns/net", pid))
+}
+
+/// The TCP and UDP sockets in the network namespace of `proc`, read once for each namespace. A process whose namespace
+/// can't be identified, such as one owned by another user, has its sockets read on their own.
+#[cfg(target_os = "linux")]
+fn linux_sockets(
+    query: &PortQuery,
+    proc: &procfs::process::Process,
+    pid: Pid,
+    backend: &BackendState,
+) -> ProcCtlResult<std::rc::Rc<NamespaceSockets>> {
+    let namespace = net_namespace(pid).ok();
+    if let Some(sockets) = namespace
+        .as_ref()
+        .and_then(|namespace| backend.namespaces.borrow().get(namespace).cloned())
+    {
+        return Ok(sockets);
+    }
+
+    let sockets = std::rc::Rc::new(NamespaceSockets::from_proc(query, proc)?);
+    if let Some(namespace) = namespace {
+        backend
+            .namespaces
+            .borrow_mut()
+            .insert(namespace, std::rc::Rc::clone(&sockets));
+    }
+
+    Ok(sockets)
+}
+
+#[cfg(target_os = "linux")]
+fn list_ports_for_pid(
+    query: &PortQuery,
+    pid: Pid,
+    backend: &BackendState,
+) -> ProcCtlResult<Vec<FoundPort>> {
+    let proc = procfs::process::Process::new(crate::pid::to_procfs(pid)?)
+        .map_err(|e| classify_proc_error(pid, e))?;
+    let fds = proc.fd().map_err(|e| classify_proc_error(pid, e))?;
+    let socket_nodes = fds
+        .filter_map(|fd| {
+            if let Ok(fd) = fd {
+                match fd.target {
+                    procfs::process::FDTarget::Socket(inode) => Some((inode, fd.fd)),
+                    _ => None,
+                }
+            } else {
+                None
+            }
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+
+    // The environment of another user's process can't be read, in which case it is unknown whether sockets were
+    // passed in
+    let activation_fds = proc
+        .environ()
+        .ok()
+        .map(|environ| socket_activation_fds(&environ, pid));
+    let via_socket_activation = |inode: &u64| {
+        let fd = socket_nodes.get(inode)?;
+        Some(activation_fds.as_ref()?.contains(fd))
+    };
+    let found = |port: ProtocolPort, address: &SocketAddr, inode: &u64| {
+        let family = if address.is_ipv6() {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Ipv4
+        };
+        let shared = backend.shared.get(inode);
+        FoundPort {
+            local_addr: Some(*address),
+            via_socket_activation: via_socket_activation(inode),
+            shared_with: shared.map_or_else(Vec::new, |s| {
+                s.pids.iter().copied().filter(|p| *p != pid).collect()
+            }),
+            primary_owner: shared.map(|s| s.primary),
+            socket_inode: Some(*inode),
+            fd: socket_nodes.get(inode).copied(),
+            ..FoundPort::new(port, family)
+        }
+    };
+
+    let sockets = linux_sockets(query, &proc, pid, backend)?;
+    let mut out = Vec::new();
+
+    if query.tcp_addresses {
+        for entry in &sockets.tcp {
+            let Some(state) = procfs::net::TcpState::from_u8(entry.state) else {
+                continue;
+            };
+            let state = linux_tcp_state(&state);
+            if query.tcp_states.contains(&state) && socket_nodes.contains_key(&entry.inode) {
+                let queue = backend.queues.get(&entry.inode);
+                let listening = state == TcpState::Listen;
+                out.push(FoundPort {
+                    tcp_state: Some(state),
+                    backlog: queue.map(|q| q.backlog),
+                    // For listening sockets, the receive queue in /proc is the number of connections waiting to be
+                    // accepted
+                    current_queue: listening.then(|| queue.map_or(entry.rx_queue, |q| q.current)),
+                    ..found(
+                        ProtocolPort::Tcp(entry.local_address.port()),
+                        &entry.local_address,
+                        &entry.inode,
+                    )
+                });
+            }
+        }
+    }
+
+    if query.udp_addresses {
+        // Best effort for detailed results, but a group filter can't be checked without them
+        let groups = if backend.multicast {
+            match crate::multicast::JoinedGroups::of_pid(pid) {
+                Ok(groups) => Some(groups),
+                Err(e) if query.joined_group.is_some() => return Err(e.into()),
+                Err(_) => None,
+            }
+        } else {
+            None
+        };
+
+        for entry in &sockets.udp {
+            if socket_nodes.contains_key(&entry.inode) {
+                let mut port = found(
+                    ProtocolPort::Udp(entry.local_address.port()),
+                    &entry.local_address,
+                    &entry.inode,
+                );
+                port.multicast_groups = groups
+                    .as_ref()
+                    .map(|groups| groups.of_family(port.family).to_vec());
+                out.push(port);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Find every socket using `port` in the tables of this network namespace, with the processes holding it
+#[cfg(target_os = "linux")]
+fn owners_of_port(query: &PortQuery, port: Port) -> ProcCtlResult<Vec<PortOwner>> {
+    let myself = procfs::process::Process::myself()?;
+
+    let mut sockets = Vec::new();
+    if query.tcp_addresses {
+        let mut tcp_entries = Vec::new();
+        if query.ipv4_addresses {
+            tcp_entries.extend(myself.tcp()?);
+        }
+        if query.ipv6_addresses {
+            tcp_entries.extend(myself.tcp6()?);
+        }
+
+        sockets.extend(
+            tcp_entries
+                .into_iter()
+                .filter(|e| {
+                    query.tcp_states.contains(&linux_tcp_state(&e.state))
+                        && e.local_address.port() == port
+                })
+                .map(|e| (ProtocolPort::Tcp(port), e.local_address, e.inode)),
+        );
+    }
+    if query.udp_addresses {
+        let mut udp_entries = Vec::new();
+        if query.ipv4_addresses {
+            udp_entries.extend(myself.udp()?);
+        }
+        if query.ipv6_addresses {
+            udp_entries.extend(myself.udp6()?);
+        }
+
+        sockets.extend(
+            udp_entries
+                .into_iter()
+                .filter(|e| e.local_address.port() == port)
+                .map(|e| (ProtocolPort::Udp(port), e.local_address, e.inode)),
+        );
+    }
+
+    let inodes = sockets.iter().map(|(_, _, inode)| *inode).collect();
+    let holders = crate::socket_owners::holders_of(&inodes);
+
+    Ok(sockets
+        .into_iter()
+        .flat_map(|(port, address, inode)| {
+            let owners = match holders.get(&inode) {
+                Some(pids) => pids.iter().map(|pid| OwnerKind::Process(*pid)).collect(),
+                None => vec![OwnerKind::Unknown(
+                    crate::types::UnknownOwnerReason::VisibilityRestricted,
+                )],
+            };
+            let family = if address.is_ipv6() {
+                AddressFamily::Ipv6
+            } else {
+                AddressFamily::Ipv4
+            };
+
+            owners.into_iter().map(move |owner| PortOwner {
+                owner,
+                name: None,
+                port,
+                family,
+                local_addr: Some(address),
+            })
+        })
+        .collect())
+}
+
+/// The state of a TCP socket read from `/proc/net/tcp`
+#[cfg(target_os = "linux")]
+pub(crate) fn linux_tcp_state(state: &procfs::net::TcpState) -> TcpState {
+    use procfs::net::TcpState as Linux;
+
+    match state {
+        Linux::Listen => TcpState::Listen,
+        Linux::SynSent => TcpState::SynSent,
+        // A request which has not been answered yet shows as NEW_SYN_RECV on newer kernels
+        Linux::SynRecv | Linux::NewSynRecv => TcpState::SynReceived,
+        Linux::Established => TcpState::Established,
+        Linux::FinWait1 => TcpState::FinWait1,
+        Linux::FinWait2 => TcpState::FinWait2,
+        Linux::CloseWait => TcpState::CloseWait,
+        Linux::Closing => TcpState::Closing,
+        Linux::LastAck => TcpState::LastAck,
+        Linux::TimeWait => TcpState::TimeWait,
+        Linux::Close => TcpState::Closed,
+    }
+}
+
+/// The file descriptors passed to a process by a service manager using socket activation, as in `sd_listen_fds`.
+///
+/// The sockets start at fd 3 and are counted by `LISTEN_FDS`. `LISTEN_PID` must name the process, since the variables
+/// may have been inherited from a parent which was activated itself.
+#[cfg(any(target_os = "linux", test))]
+fn socket_activation_fds(
+    environ: &std::collections::HashMap<std::ffi::OsString, std::ffi::OsString>,
+    pid: Pid,
+) -> std::ops::Range<i32> {
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let var = |name: &str| environ.get(std::ffi::OsStr::new(name))?.to_str();
+    let listen_pid = var("LISTEN_PID").and_then(|v| v.parse::<Pid>().ok());
+    let count = var("LISTEN_FDS").and_then(|v| v.parse::<i32>().ok());
+
+    match (listen_pid, count) {
+        (Some(listen_pid), Some(count)) if listen_pid == pid && count > 0 => {
+            SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count)
         }
+        _ => 0..0,
     }
 }
 
+/// Errors from opening the process itself are common enough to get their own variants, since they usually mean the
+/// process has exited or belongs to another user.
 #[cfg(target_os = "linux")]
-fn list_ports_for_pid(query: &PortQuery, pid: Pid) -> ProcCtlResult<Vec<ProtocolPort>> {
-    let proc = procfs::process::Process::new(pid as i32)?;
-    let fds = proc.fd()?;
-    let socket_nodes = fds
-        .filter_map(|fd| {
-            if let Ok(fd) = fd {
-                match fd.target {
-                    procfs::process::FDTarget::Socket(inode) => Some(inode),
-                    _ => None,
-                }
-            } else {
-                None
-            }
-        })
-        .collect::<std::collections::HashSet<_>>();
-
-    let mut out = Vec::new();
+pub(crate) fn classify_proc_error(pid: Pid, e: procfs::ProcError) -> ProcCtlError {
+    match e {
+        procfs::ProcError::NotFound(_) => ProcCtlError::ProcessNotFound(pid),
+        procfs::ProcError::PermissionDenied(path) => ProcCtlError::PermissionDenied(format!(
+            "cannot read {}",
+            path.map(|p| p.display().to_string())
+                .unwrap_or_else(|| format!("process {}", pid))
+        )),
+        e => ProcCtlError::ProcessError(e),
+    }
+}
 
-    if query.tcp_addresses {
-        let mut tcp_entries = proc.tcp()?;
+/// Approximate when a process started, rounded up to the next clock tick so that a process which started after a
+/// given time is not reported as having started before it.
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: Pid) -> Option<SystemTime> {
+    use procfs::Current;
+
+    let stat = procfs::process::Process::new(crate::pid::to_procfs(pid).ok()?)
+        .ok()?
+        .stat()
+        .ok()?;
+    let uptime = procfs::Uptime::current().ok()?.uptime_duration();
+
+    let ticks_per_second = procfs::ticks_per_second();
+    if ticks_per_second == 0 {
+        return None;
+    }
+    let tick = std::time::Duration::from_nanos(1_000_000_000 / ticks_per_second);
+    let started_after_boot = std::time::Duration::from_secs(stat.starttime / ticks_per_second)
+        + tick * (stat.starttime % ticks_per_second) as u32;
 
-        if query.ipv6_addresses {
-            let tcp6_entries = proc.tcp6()?;
+    let age = uptime.saturating_sub(started_after_boot);
+    SystemTime::now().checked_sub(age)?.checked_add(tick)
+}
 
-            tcp_entries.extend(tcp6_entries);
-        }
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn process_start_time(_pid: Pid) -> Option<SystemTime> {
+    None
+}
 
-        for entry in tcp_entries {
-            if entry.state == procfs::net::TcpState::Listen && socket_nodes.contains(&entry.inode) {
-                out.push(ProtocolPort::Tcp(entry.local_address.port()));
-            }
-        }
+/// When the process running with `pid` started, in clock ticks since boot, or `None` if there is none. A process which
+/// has exited but not been reaped counts as having none.
+#[cfg(target_os = "linux")]
+fn process_identity(pid: Pid) -> ProcCtlResult<Option<u64>> {
+    let stat = procfs::process::Process::new(crate::pid::to_procfs(pid)?).and_then(|p| p.stat());
+    match stat {
+        Ok(stat) => Ok((stat.state != 'Z' && stat.state != 'X').then_some(stat.starttime)),
+        Err(procfs::ProcError::NotFound(_)) => Ok(None),
+        Err(e) => Err(classify_proc_error(pid, e)),
     }
+}
 
-    if query.udp_addresses {
-        let mut udp_entries = proc.udp()?;
+/// When the process running with `pid` started, in seconds since the epoch, or `None` if there is none
+#[cfg(all(feature = "proc", any(target_os = "windows", target_os = "macos")))]
+fn process_identity(pid: Pid) -> ProcCtlResult<Option<u64>> {
+    crate::proc_query::running_start_time(pid)
+}
 
-        if query.ipv6_addresses {
-            let udp6_entries = proc.udp6()?;
-            udp_entries.extend(udp6_entries);
+#[cfg(all(not(feature = "proc"), any(target_os = "windows", target_os = "macos")))]
+fn process_identity(_pid: Pid) -> ProcCtlResult<Option<u64>> {
+    Err(ProcCtlError::UnsupportedPlatform(
+        "identifying processes on this platform needs the proc feature".to_string(),
+    ))
+}
+
+#[cfg(target_os = "windows")]
+struct BackendState {
+    /// Every socket in the owner-pid tables with the pid of its owner, read once and shared by every process
+    sockets: Vec<(u32, FoundPort)>,
+    /// The firewall policy, read once for each query with detailed TCP results. `None` when it couldn't be read.
+    #[cfg(feature = "windows-firewall")]
+    firewall: Option<crate::firewall::FirewallPolicy>,
+}
+
+#[cfg(target_os = "windows")]
+impl BackendState {
+    fn load(
+        query: &PortQuery,
+        _pids: &[Pid],
+        #[cfg_attr(not(feature = "windows-firewall"), allow(unused_variables))] detailed: bool,
+        _wait: bool,
+        _deadline: Option<Deadline>,
+    ) -> ProcCtlResult<Self> {
+        if query.joined_group.is_some() {
+            return Err(multicast_unsupported());
         }
 
-        for entry in udp_entries {
-            if socket_nodes.contains(&entry.inode) {
-                out.push(ProtocolPort::Udp(entry.local_address.port()));
+        Ok(BackendState {
+            sockets: windows_sockets(query)?,
+            #[cfg(feature = "windows-firewall")]
+            firewall: if detailed && query.tcp_addresses {
+                crate::firewall::load().ok()
+            } else {
+                None
+            },
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn list_ports_for_pid(
+    query: &PortQuery,
+    pid: Pid,
+    backend: &BackendState,
+) -> ProcCtlResult<Vec<FoundPort>> {
+    #[cfg_attr(not(feature = "windows-firewall"), allow(unused_mut))]
+    let mut out = backend
+        .sockets
+        .iter()
+        .filter(|(owning_pid, _)| owner_matches(*owning_pid, pid, query.include_system_owned))
+        .map(|(_, found)| found.clone())
+        .collect::<Vec<_>>();
+
+    #[cfg(feature = "windows-firewall")]
+    if let Some(policy) = &backend.firewall {
+        let has_tcp = out
+            .iter()
+            .any(|found| matches!(found.port, ProtocolPort::Tcp(_)));
+        if let Some(exe) = has_tcp.then(|| crate::firewall::exe_path(pid)).flatten() {
+            for found in &mut out {
+                if let ProtocolPort::Tcp(port) = found.port {
+                    found.firewall_allowed = Some(policy.allows_tcp(&exe, port));
+                }
             }
         }
     }
@@ -179,76 +2729,221 @@ fn list_ports_for_pid(query: &PortQuery, pid: Pid) -> ProcCtlResult<Vec<Protocol
     Ok(out)
 }
 
+/// Find every socket using `port` in the Windows owner-pid tables
+#[cfg(target_os = "windows")]
+fn owners_of_port(query: &PortQuery, port: Port) -> ProcCtlResult<Vec<PortOwner>> {
+    Ok(windows_sockets(query)?
+        .into_iter()
+        .filter(|(_, found)| found.port.port() == port)
+        .map(|(owning_pid, found)| PortOwner {
+            owner: OwnerKind::from_windows_pid(owning_pid),
+            name: None,
+            port: found.port,
+            family: found.family,
+            local_addr: found.local_addr,
+        })
+        .collect())
+}
+
+/// Every socket in the Windows owner-pid tables which passes the protocol and address family filters of `query`,
+/// with the pid of its owner
 #[cfg(target_os = "windows")]
-fn list_ports_for_pid(query: &PortQuery, pid: Pid) -> ProcCtlResult<Vec<ProtocolPort>> {
+fn windows_sockets(query: &PortQuery) -> ProcCtlResult<Vec<(u32, FoundPort)>> {
+    use std::mem::offset_of;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        MIB_TCP6ROW_OWNER_PID, MIB_TCP6TABLE_OWNER_PID, MIB_TCPROW_OWNER_PID,
+        MIB_TCPTABLE_OWNER_PID, MIB_UDP6ROW_OWNER_PID, MIB_UDP6TABLE_OWNER_PID,
+        MIB_UDPROW_OWNER_PID, MIB_UDPTABLE_OWNER_PID,
+    };
+    use windows::Win32::Networking::WinSock::{AF_INET, AF_INET6};
+
+    #[cfg(test)]
+    SOCKET_TABLE_READS.with(|reads| reads.set(reads.get() + 1));
+
     let mut out = Vec::new();
 
+    // SAFETY for each call to table_rows: the rows only contain integers and byte arrays, so any bytes are a valid row
     if query.tcp_addresses {
+        let tcp_state =
+            |state: u32| windows_tcp_state(state).filter(|state| query.tcp_states.contains(state));
         if query.ipv4_addresses {
-            let mut table = load_tcp_table(windows::Win32::Networking::WinSock::AF_INET)?;
-            let table: &mut windows::Win32::NetworkManagement::IpHelper::MIB_TCPTABLE_OWNER_PID = unsafe {
-                &mut *(table.as_mut_ptr()
-                    as *mut windows::Win32::NetworkManagement::IpHelper::MIB_TCPTABLE_OWNER_PID)
-            };
-
-            for i in 0..table.dwNumEntries as usize {
-                let row = unsafe { &*table.table.as_mut_ptr().add(i) };
-                if row.dwOwningPid == pid {
-                    out.push(ProtocolPort::Tcp(row.dwLocalPort as u16));
-                }
-            }
+            let table = load_tcp_table(AF_INET)?;
+            let rows: Vec<MIB_TCPROW_OWNER_PID> =
+                unsafe { table_rows(&table, offset_of!(MIB_TCPTABLE_OWNER_PID, table))? };
+
+            push_windows_rows(&mut out, &rows, ProtocolPort::Tcp, |row| {
+                let port = windows_port(row.dwLocalPort);
+                Some((
+                    row.dwOwningPid,
+                    windows_v4_addr(row.dwLocalAddr, port),
+                    Some(tcp_state(row.dwState)?),
+                ))
+            });
         }
         if query.ipv6_addresses {
-            let mut table = load_tcp_table(windows::Win32::Networking::WinSock::AF_INET6)?;
-            let table: &mut windows::Win32::NetworkManagement::IpHelper::MIB_TCP6TABLE_OWNER_PID = unsafe {
-                &mut *(table.as_mut_ptr()
-                    as *mut windows::Win32::NetworkManagement::IpHelper::MIB_TCP6TABLE_OWNER_PID)
-            };
-
-            for i in 0..table.dwNumEntries as usize {
-                let row = unsafe { &*table.table.as_mut_ptr().add(i) };
-                if row.dwOwningPid == pid {
-                    out.push(ProtocolPort::Tcp(row.dwLocalPort as u16));
-                }
-            }
+            let table = load_tcp_table(AF_INET6)?;
+            let rows: Vec<MIB_TCP6ROW_OWNER_PID> =
+                unsafe { table_rows(&table, offset_of!(MIB_TCP6TABLE_OWNER_PID, table))? };
+
+            push_windows_rows(&mut out, &rows, ProtocolPort::Tcp, |row| {
+                let port = windows_port(row.dwLocalPort);
+                Some((
+                    row.dwOwningPid,
+                    windows_v6_addr(row.ucLocalAddr, row.dwLocalScopeId, port),
+                    Some(tcp_state(row.dwState)?),
+                ))
+            });
         }
     }
     if query.udp_addresses {
         if query.ipv4_addresses {
-            let mut table = load_udp_table(windows::Win32::Networking::WinSock::AF_INET)?;
-            let table: &mut windows::Win32::NetworkManagement::IpHelper::MIB_UDPTABLE_OWNER_PID = unsafe {
-                &mut *(table.as_mut_ptr()
-                    as *mut windows::Win32::NetworkManagement::IpHelper::MIB_UDPTABLE_OWNER_PID)
-            };
-
-            for i in 0..table.dwNumEntries as usize {
-                let row = unsafe { &*table.table.as_mut_ptr().add(i) };
-                if row.dwOwningPid == pid {
-                    out.push(ProtocolPort::Tcp(row.dwLocalPort as u16));
-                }
-            }
+            let table = load_udp_table(AF_INET)?;
+            let rows: Vec<MIB_UDPROW_OWNER_PID> =
+                unsafe { table_rows(&table, offset_of!(MIB_UDPTABLE_OWNER_PID, table))? };
+
+            push_windows_rows(&mut out, &rows, ProtocolPort::Udp, |row| {
+                let port = windows_port(row.dwLocalPort);
+                Some((
+                    row.dwOwningPid,
+                    windows_v4_addr(row.dwLocalAddr, port),
+                    None,
+                ))
+            });
         }
         if query.ipv6_addresses {
-            let mut table = load_udp_table(windows::Win32::Networking::WinSock::AF_INET6)?;
-            let table: &mut windows::Win32::NetworkManagement::IpHelper::MIB_UDP6TABLE_OWNER_PID = unsafe {
-                &mut *(table.as_mut_ptr()
-                    as *mut windows::Win32::NetworkManagement::IpHelper::MIB_UDP6TABLE_OWNER_PID)
-            };
-
-            for i in 0..table.dwNumEntries as usize {
-                let row = unsafe { &*table.table.as_mut_ptr().add(i) };
-                if row.dwOwningPid == pid {
-                    out.push(ProtocolPort::Tcp(row.dwLocalPort as u16));
-                }
-            }
+            let table = load_udp_table(AF_INET6)?;
+            let rows: Vec<MIB_UDP6ROW_OWNER_PID> =
+                unsafe { table_rows(&table, offset_of!(MIB_UDP6TABLE_OWNER_PID, table))? };
+
+            push_windows_rows(&mut out, &rows, ProtocolPort::Udp, |row| {
+                let port = windows_port(row.dwLocalPort);
+                Some((
+                    row.dwOwningPid,
+                    windows_v6_addr(row.ucLocalAddr, row.dwLocalScopeId, port),
+                    None,
+                ))
+            });
         }
     }
 
     Ok(out)
 }
 
+/// Add the rows of one of the Windows owner-pid tables to `out` as sockets of `protocol`. `socket` gives the owning
+/// pid, local address and TCP state of a row, or None to skip it.
 #[cfg(target_os = "windows")]
-fn load_tcp_table(
+fn push_windows_rows<R>(
+    out: &mut Vec<(u32, FoundPort)>,
+    rows: &[R],
+    protocol: fn(Port) -> ProtocolPort,
+    socket: impl Fn(&R) -> Option<(u32, SocketAddr, Option<TcpState>)>,
+) {
+    for (owning_pid, local_addr, tcp_state) in rows.iter().filter_map(socket) {
+        out.push((
+            owning_pid,
+            FoundPort {
+                tcp_state,
+                ..FoundPort::at(protocol(local_addr.port()), local_addr)
+            },
+        ));
+    }
+}
+
+/// The state of a socket from one of the Windows owner-pid tables, as a `MIB_TCP_STATE`
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn windows_tcp_state(state: u32) -> Option<TcpState> {
+    Some(match state {
+        // MIB_TCP_STATE_DELETE_TCB is a connection being deleted
+        1 | 12 => TcpState::Closed,
+        2 => TcpState::Listen,
+        3 => TcpState::SynSent,
+        4 => TcpState::SynReceived,
+        5 => TcpState::Established,
+        6 => TcpState::FinWait1,
+        7 => TcpState::FinWait2,
+        8 => TcpState::CloseWait,
+        9 => TcpState::Closing,
+        10 => TcpState::LastAck,
+        11 => TcpState::TimeWait,
+        _ => return None,
+    })
+}
+
+/// A port from one of the Windows owner-pid tables, which give it in network byte order in the low 16 bits
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn windows_port(port: u32) -> Port {
+    u16::from_be(port as u16)
+}
+
+/// The local address of a row from one of the Windows IPv4 tables, which give the address in network byte order
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn windows_v4_addr(addr: u32, port: Port) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::from(addr.to_ne_bytes()).into(), port)
+}
+
+/// The local address of a row from one of the Windows IPv6 tables. The address is in bytes, but the scope ID is in
+/// network byte order like the IPv4 addresses are.
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn windows_v6_addr(addr: [u8; 16], scope_id: u32, port: Port) -> SocketAddr {
+    std::net::SocketAddrV6::new(Ipv6Addr::from(addr), port, 0, u32::from_be(scope_id)).into()
+}
+
+/// Copy the rows out of one of the Windows owner-pid tables, as loaded by `load_tcp_table` or `load_udp_table`.
+///
+/// Each table is a `u32` row count followed by the rows, starting at `rows_offset`. The count is checked against the
+/// size of the buffer, so a short or corrupt table is reported as an error instead of being read past its end. The
+/// buffer has no particular alignment, so the rows are copied out rather than referenced in place.
+///
+/// # Safety
+///
+/// Any bytes must be a valid value of `Row`, which holds for plain structs of integers.
+#[cfg(any(target_os = "windows", test))]
+pub(crate) unsafe fn table_rows<Row: Copy>(
+    table: &[u8],
+    rows_offset: usize,
+) -> ProcCtlResult<Vec<Row>> {
+    let malformed = || {
+        ProcCtlError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "socket table of {} bytes is too short for its rows",
+                table.len()
+            ),
+        ))
+    };
+
+    let count = table
+        .get(..4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(malformed)?;
+    let row_size = std::mem::size_of::<Row>();
+    if row_size == 0 || rows_offset < 4 {
+        return Err(malformed());
+    }
+    let end = count
+        .checked_mul(row_size)
+        .and_then(|len| len.checked_add(rows_offset))
+        .filter(|end| *end <= table.len())
+        .ok_or_else(malformed)?;
+    Ok(table[rows_offset..end]
+        .chunks_exact(row_size)
+        // SAFETY: each chunk is exactly one row long, and the caller guarantees any bytes are a valid row
+        .map(|row| unsafe { std::ptr::read_unaligned(row.as_ptr() as *const Row) })
+        .collect())
+}
+
+/// Whether a row from one of the Windows owner-pid tables should be attributed to the process being queried
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn owner_matches(owning_pid: u32, pid: Pid, include_system_owned: bool) -> bool {
+    match crate::types::OwnerKind::from_windows_pid(owning_pid) {
+        crate::types::OwnerKind::Process(owner) => owner == pid,
+        crate::types::OwnerKind::System => include_system_owned && owning_pid == pid,
+        crate::types::OwnerKind::Unowned | crate::types::OwnerKind::Unknown(_) => false,
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn load_tcp_table(
     family: windows::Win32::Networking::WinSock::ADDRESS_FAMILY,
 ) -> ProcCtlResult<Vec<u8>> {
     let mut table = Vec::<u8>::with_capacity(0);
@@ -270,6 +2965,10 @@ fn load_tcp_table(
         if err_code == windows::Win32::Foundation::ERROR_INSUFFICIENT_BUFFER {
             table.resize(table_size as usize, 0);
             continue;
+        } else if err_code == windows::Win32::Foundation::ERROR_ACCESS_DENIED {
+            return Err(ProcCtlError::SandboxRestricted(
+                "GetExtendedTcpTable".to_string(),
+            ));
         } else if err_code != windows::Win32::Foundation::NO_ERROR {
             return Err(ProcCtlError::ProcessError(format!(
                 "Failed to get TCP table: {:?}",
@@ -308,6 +3007,10 @@ fn load_udp_table(
         if err_code == windows::Win32::Foundation::ERROR_INSUFFICIENT_BUFFER {
             table.resize(table_size as usize, 0);
             continue;
+        } else if err_code == windows::Win32::Foundation::ERROR_ACCESS_DENIED {
+            return Err(ProcCtlError::SandboxRestricted(
+                "GetExtendedUdpTable".to_string(),
+            ));
         } else if err_code != windows::Win32::Foundation::NO_ERROR {
             return Err(ProcCtlError::ProcessError(format!(
                 "Failed to get UDP table: {:?}",
@@ -324,223 +3027,332 @@ fn load_udp_table(
 }
 
 #[cfg(target_os = "macos")]
-fn list_ports_for_pid(query: &PortQuery, pid: Pid) -> ProcCtlResult<Vec<ProtocolPort>> {
-    let mut out = Vec::new();
+struct BackendState {
+    sockets: Vec<LsofSocket>,
+}
 
-    if query.ipv4_addresses {
-        if query.tcp_addresses {
-            match std::process::Command::new("lsof")
-                .arg("-a")
-                .arg("-iTCP")
-                .arg("-i4")
-                .arg("-sTCP:LISTEN")
-                .arg("-nP")
-                .arg("-F0pn")
-                .output()
-            {
-                Ok(output) => out.extend(
-                    find_ports_v4(output.stdout.clone(), pid)
-                        .into_iter()
-                        .map(ProtocolPort::Tcp),
-                ),
-                Err(e) => return Err(ProcCtlError::ProcessError(e.to_string())),
-            }
-        }
-        if query.udp_addresses {
-            match std::process::Command::new("lsof")
-                .arg("-a")
-                .arg("-iUDP")
-                .arg("-i4")
-                .arg("-nP")
-                .arg("-F0pn")
-                .output()
-            {
-                Ok(output) => out.extend(
-                    find_ports_v4(output.stdout.clone(), pid)
-                        .into_iter()
-                        .map(ProtocolPort::Udp),
-                ),
-                Err(e) => return Err(ProcCtlError::ProcessError(e.to_string())),
-            }
+#[cfg(target_os = "macos")]
+impl BackendState {
+    /// Run lsof once for every protocol and address family, so that each process and each filter is answered from
+    /// the same output
+    fn load(
+        query: &PortQuery,
+        _pids: &[Pid],
+        _detailed: bool,
+        wait: bool,
+        deadline: Option<Deadline>,
+    ) -> ProcCtlResult<Self> {
+        if query.joined_group.is_some() {
+            return Err(multicast_unsupported());
         }
+
+        Ok(BackendState {
+            sockets: lsof_sockets(query, None, wait, deadline.and_then(|d| d.end()))?,
+        })
     }
-    if query.ipv6_addresses {
-        if query.tcp_addresses {
-            match std::process::Command::new("lsof")
-                .arg("-a")
-                .arg("-iTCP")
-                .arg("-i6")
-                .arg("-sTCP:LISTEN")
-                .arg("-nP")
-                .arg("-F0pn")
-                .output()
-            {
-                Ok(output) => out.extend(
-                    find_ports_v6(output.stdout.clone(), pid)
-                        .into_iter()
-                        .map(ProtocolPort::Tcp),
-                ),
-                Err(e) => return Err(ProcCtlError::ProcessError(e.to_string())),
-            }
-        }
-        if query.udp_addresses {
-            match std::process::Command::new("lsof")
-                .arg("-a")
-                .arg("-iUDP")
-                .arg("-i6")
-                .arg("-nP")
-                .arg("-F0pn")
-                .output()
-            {
-                Ok(output) => out.extend(
-                    find_ports_v6(output.stdout.clone(), pid)
-                        .into_iter()
-                        .map(ProtocolPort::Udp),
-                ),
-                Err(e) => return Err(ProcCtlError::ProcessError(e.to_string())),
-            }
-        }
+}
+
+/// Run lsof for the TCP sockets in the states of `query` and the UDP sockets of every process, or only those using
+/// `port`. lsof is killed if it is still running at `deadline`.
+#[cfg(any(target_os = "macos", test))]
+fn lsof_sockets(
+    query: &PortQuery,
+    port: Option<Port>,
+    wait: bool,
+    deadline: Option<std::time::Instant>,
+) -> ProcCtlResult<Vec<LsofSocket>> {
+    let port = port.map_or_else(String::new, |port| format!(":{}", port));
+    let mut args = Vec::new();
+    if !query.tcp_states.is_empty() {
+        let states = query
+            .tcp_states
+            .iter()
+            .map(|state| lsof_state_name(*state))
+            .collect::<Vec<_>>();
+        args.push(format!("-iTCP{}", port));
+        args.push(format!("-sTCP:{}", states.join(",")));
+    }
+    args.push(format!("-iUDP{}", port));
+    args.push("-nP".to_string());
+    args.push("-F0tPnT".to_string());
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let runner = query.tool_runner.as_ref();
+    let max = query.max_tool_concurrency;
+    let output = match deadline {
+        _ if !wait => crate::tool::try_output(runner, "lsof", &args, max).ok_or_else(|| {
+            ProcCtlError::WouldBlock("too many tools are already running".to_string())
+        })?,
+        Some(deadline) => crate::tool::output_before(runner, "lsof", &args, max, deadline),
+        None => crate::tool::output(runner, "lsof", &args, max),
+    }
+    .map_err(|e| match e.kind() {
+        std::io::ErrorKind::TimedOut => query.timed_out("running lsof"),
+        _ => ProcCtlError::from_restricted_io("running lsof", e),
+    })?;
+
+    // lsof exits with an error when it finds nothing, so a failure only matters if it was denied access
+    if !output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains("Operation not permitted")
+    {
+        return Err(ProcCtlError::SandboxRestricted(
+            "reading sockets with lsof".to_string(),
+        ));
     }
 
-    Ok(out)
+    Ok(parse_lsof(&output.stdout))
 }
 
 #[cfg(target_os = "macos")]
-fn find_ports_v4(output: Vec<u8>, find_pid: Pid) -> Vec<u16> {
-    let mut out = Vec::new();
+fn list_ports_for_pid(
+    query: &PortQuery,
+    pid: Pid,
+    backend: &BackendState,
+) -> ProcCtlResult<Vec<FoundPort>> {
+    Ok(backend
+        .sockets
+        .iter()
+        .filter(|s| s.pid == pid && s.passes_filters(query))
+        .map(LsofSocket::found)
+        .collect())
+}
 
-    let mut index = 0;
-    let len = output.len();
-    while index < len {
-        if output[index] != b'p' {
-            break;
-        }
-        index += 1;
+/// Find every socket using `port` which lsof can see
+#[cfg(target_os = "macos")]
+fn owners_of_port(query: &PortQuery, port: Port) -> ProcCtlResult<Vec<PortOwner>> {
+    let deadline = query.deadline().and_then(|d| d.end());
+    Ok(lsof_sockets(query, Some(port), true, deadline)?
+        .iter()
+        .filter(|s| s.port.port() == port && s.passes_filters(query))
+        .map(|s| {
+            let found = s.found();
+            PortOwner {
+                owner: OwnerKind::Process(s.pid),
+                name: None,
+                port: found.port,
+                family: found.family,
+                local_addr: found.local_addr,
+            }
+        })
+        .collect())
+}
 
-        let start_pid = index;
-        while index < len && output[index] != 0 {
-            index += 1;
-        }
+/// A socket listed by lsof
+#[cfg(any(target_os = "macos", test))]
+#[derive(Debug, PartialEq, Eq)]
+struct LsofSocket {
+    pid: Pid,
+    ipv6: bool,
+    port: ProtocolPort,
+    local_addr: Option<SocketAddr>,
+    tcp_state: Option<TcpState>,
+    fd: Option<i32>,
+}
 
-        let Some(pid) = String::from_utf8_lossy(&output[start_pid..index])
-            .parse::<u32>()
-            .ok()
-        else {
-            break;
+#[cfg(target_os = "macos")]
+impl LsofSocket {
+    /// Whether the socket passes the protocol and address family filters of `query`
+    fn passes_filters(&self, query: &PortQuery) -> bool {
+        let family = if self.ipv6 {
+            query.ipv6_addresses
+        } else {
+            query.ipv4_addresses
+        };
+        let protocol = match self.port {
+            ProtocolPort::Tcp(_) => query.tcp_addresses,
+            ProtocolPort::Udp(_) => query.udp_addresses,
         };
-        index += 1; // 0
-        index += 1; // NL
 
-        loop {
-            if pid == find_pid && index < len && output[index] == b'n' {
-                while index < len && output[index] != b':' {
-                    index += 1;
-                }
-                index += 1; // :
+        family && protocol
+    }
 
-                let start_port = index;
-                while index < len && output[index] != 0 {
-                    index += 1;
-                }
+    fn found(&self) -> FoundPort {
+        let found = match self.local_addr {
+            Some(local_addr) => FoundPort::at(self.port, local_addr),
+            None if self.ipv6 => FoundPort::new(self.port, AddressFamily::Ipv6),
+            None => FoundPort::new(self.port, AddressFamily::Ipv4),
+        };
+        FoundPort {
+            tcp_state: self.tcp_state,
+            fd: self.fd,
+            ..found
+        }
+    }
+}
 
-                if index >= len {
-                    break;
-                }
+/// Parse the output of `lsof -F0tPnT`.
+///
+/// Each field is a single character identifier followed by its value and a NUL. A set of process fields, starting
+/// with `p`, is followed by a set of fields for each of its files, starting with `f` and the file descriptor. The name
+/// of a socket is its local address, followed by `->` and the remote address if it is connected. The state of a TCP
+/// socket follows its name in a `T` field, as `ST=LISTEN`.
+#[cfg(any(target_os = "macos", test))]
+fn parse_lsof(output: &[u8]) -> Vec<LsofSocket> {
+    let mut out: Vec<LsofSocket> = Vec::new();
+
+    let mut pid = None;
+    let mut fd = None;
+    let mut ipv6 = false;
+    let mut protocol = None;
+    // Whether the last socket found belongs to the current file, so that its state can be set
+    let mut in_socket = false;
+    for field in output.split(|b| *b == 0 || *b == b'\n') {
+        let Some((&id, value)) = field.split_first() else {
+            continue;
+        };
+        let value = String::from_utf8_lossy(value);
 
-                if let Ok(port) = String::from_utf8_lossy(&output[start_port..index]).parse::<u16>()
-                {
-                    out.push(port);
+        match id {
+            b'p' => {
+                pid = value.parse::<Pid>().ok();
+                in_socket = false;
+            }
+            b'f' => {
+                fd = value.parse::<i32>().ok();
+                protocol = None;
+                in_socket = false;
+            }
+            b't' => ipv6 = value == "IPv6",
+            b'P' => protocol = Some(value.into_owned()),
+            b'T' => {
+                let Some(state) = value.strip_prefix("ST=") else {
+                    continue;
                 };
-                index += 1; // 0
-            } else {
-                while index < len && output[index] != 0 {
-                    index += 1;
+                if let Some(socket) = out.last_mut().filter(|_| in_socket) {
+                    if matches!(socket.port, ProtocolPort::Tcp(_)) {
+                        socket.tcp_state = lsof_tcp_state(state);
+                    }
                 }
-                index += 1; // 0
-            }
-
-            if index < len && output[index] == 10 {
-                // NL
-                index += 1;
             }
-
-            if index >= len || output[index] == b'p' {
-                break;
+            b'n' => {
+                let local = value.split("->").next().unwrap_or_default();
+                let Some((host, port)) = local
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                else {
+                    continue;
+                };
+                let local_addr = lsof_ip(host, ipv6).map(|(ip, scope_id)| match ip {
+                    IpAddr::V4(ip) => SocketAddr::from((ip, port)),
+                    IpAddr::V6(ip) => std::net::SocketAddrV6::new(ip, port, 0, scope_id).into(),
+                });
+
+                let port = match protocol.as_deref() {
+                    Some("TCP") => ProtocolPort::Tcp(port),
+                    Some("UDP") => ProtocolPort::Udp(port),
+                    _ => continue,
+                };
+                if let Some(pid) = pid {
+                    out.push(LsofSocket {
+                        pid,
+                        ipv6,
+                        port,
+                        local_addr,
+                        tcp_state: None,
+                        fd,
+                    });
+                    in_socket = true;
+                }
             }
+            _ => {}
         }
     }
 
     out
 }
 
-#[cfg(target_os = "macos")]
-fn find_ports_v6(output: Vec<u8>, find_pid: Pid) -> Vec<u16> {
-    let mut out = Vec::new();
-
-    let mut index = 0;
-    let len = output.len();
-    while index < len {
-        if output[index] != b'p' {
-            break;
-        }
-        index += 1;
+/// The state of a TCP socket as lsof names it
+#[cfg(any(target_os = "macos", test))]
+pub(crate) fn lsof_tcp_state(state: &str) -> Option<TcpState> {
+    Some(match state {
+        "LISTEN" => TcpState::Listen,
+        "SYN_SENT" => TcpState::SynSent,
+        "SYN_RCVD" => TcpState::SynReceived,
+        "ESTABLISHED" => TcpState::Established,
+        "FIN_WAIT_1" => TcpState::FinWait1,
+        "FIN_WAIT_2" => TcpState::FinWait2,
+        "CLOSE_WAIT" => TcpState::CloseWait,
+        "CLOSING" => TcpState::Closing,
+        "LAST_ACK" => TcpState::LastAck,
+        "TIME_WAIT" => TcpState::TimeWait,
+        "CLOSED" => TcpState::Closed,
+        _ => return None,
+    })
+}
 
-        let start_pid = index;
-        while index < len && output[index] != 0 {
-            index += 1;
-        }
+/// The name lsof uses for a TCP state, as in its `-sTCP:` filter
+#[cfg(any(target_os = "macos", test))]
+fn lsof_state_name(state: TcpState) -> &'static str {
+    match state {
+        TcpState::Listen => "LISTEN",
+        TcpState::SynSent => "SYN_SENT",
+        TcpState::SynReceived => "SYN_RCVD",
+        TcpState::Established => "ESTABLISHED",
+        TcpState::FinWait1 => "FIN_WAIT_1",
+        TcpState::FinWait2 => "FIN_WAIT_2",
+        TcpState::CloseWait => "CLOSE_WAIT",
+        TcpState::Closing => "CLOSING",
+        TcpState::LastAck => "LAST_ACK",
+        TcpState::TimeWait => "TIME_WAIT",
+        TcpState::Closed => "CLOSED",
+    }
+}
 
-        let Ok(pid) = String::from_utf8_lossy(&output[start_pid..index]).parse::<u32>() else {
-            break;
+/// Parse the host part of a socket name from lsof, giving the address and its IPv6 scope ID.
+///
+/// The host is `*` for the unspecified address, and an IPv6 address is in brackets, optionally with its scope after a
+/// `%` as an interface name or number. macOS keeps the scope of a link-local address embedded in its second 16 bit
+/// group, as the other BSDs do, and lsof prints it that way, so it is moved out into the scope ID.
+#[cfg(any(target_os = "macos", test))]
+pub(crate) fn lsof_ip(host: &str, ipv6: bool) -> Option<(IpAddr, u32)> {
+    if host == "*" {
+        let ip: IpAddr = if ipv6 {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
         };
-        index += 1; // 0
-        index += 1; // NL
-
-        loop {
-            if pid == find_pid && index < len && output[index] == b'n' {
-                while index < len && output[index] != b']' {
-                    index += 1;
-                }
-                index += 1; // ]
-
-                if index < len && output[index] != b':' {
-                    break;
-                }
-                index += 1;
-
-                let start_port = index;
-                while index < len && output[index] != 0 {
-                    index += 1;
-                }
-
-                if index >= len {
-                    break;
-                }
+        return Some((ip, 0));
+    }
 
-                if let Ok(port) = String::from_utf8_lossy(&output[start_port..index]).parse::<u16>()
-                {
-                    out.push(port);
-                };
-                index += 1; // 0
-            } else {
-                while index < len && output[index] != 0 {
-                    index += 1;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => Some((ip.into(), 0)),
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            let mut scope_id = scope.map_or(0, |scope| {
+                scope.parse().unwrap_or_else(|_| interface_index(scope))
+            });
+            // Only unicast link-local addresses have an embedded scope, fe80::/10
+            if segments[0] & 0xffc0 == 0xfe80 && segments[1] != 0 {
+                if scope_id == 0 {
+                    scope_id = u32::from(segments[1]);
                 }
-                index += 1; // 0
-            }
-
-            if index < len && output[index] == 10 {
-                // NL
-                index += 1;
-            }
-
-            if index >= len || output[index] == b'p' {
-                break;
+                segments[1] = 0;
             }
+            Some((Ipv6Addr::from(segments).into(), scope_id))
         }
     }
+}
 
-    out
+/// The index of the network interface called `name`, or 0 if there is no such interface
+#[cfg(any(target_os = "macos", test))]
+fn interface_index(name: &str) -> u32 {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if let Ok(name) = std::ffi::CString::new(name) {
+        // SAFETY: the name is a valid NUL terminated string, which is only read
+        return unsafe { libc::if_nametoindex(name.as_ptr()) };
+    }
+
+    let _ = name;
+    0
 }
 
 #[cfg(any(
@@ -560,3 +3372,585 @@ impl Default for PortQuery {
         PortQuery::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(target_os = "linux", feature = "netlink"))]
+    #[test]
+    fn netlink_and_proc_find_the_same_ports() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        let _accepted = tcp.accept().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut ours = vec![
+            tcp.local_addr().unwrap(),
+            client.local_addr().unwrap(),
+            udp.local_addr().unwrap(),
+        ];
+        // IPv6 may not be available in a sandbox
+        let tcp6 = std::net::TcpListener::bind("[::1]:0");
+        let udp6 = std::net::UdpSocket::bind("[::1]:0");
+        ours.extend(tcp6.iter().filter_map(|s| s.local_addr().ok()));
+        ours.extend(udp6.iter().filter_map(|s| s.local_addr().ok()));
+
+        // Other tests bind sockets in this process while this one runs, and the bind time is estimated for each read
+        let only_ours = |ports: Vec<PortInfo>| {
+            let mut ports = ports
+                .into_iter()
+                .filter(|p| p.local_addr.is_some_and(|addr| ours.contains(&addr)))
+                .map(|p| PortInfo {
+                    bound_since: None,
+                    ..p
+                })
+                .collect::<Vec<_>>();
+            ports.sort_by_key(|p| format!("{:?}", p));
+            ports
+        };
+
+        for query in [
+            PortQuery::new(),
+            PortQuery::new().tcp_states(&[TcpState::Listen, TcpState::Established]),
+        ] {
+            let query = query.process_id(std::process::id());
+            let backend =
+                BackendState::load(&query, &[std::process::id()], true, false, None).unwrap();
+            assert!(
+                !backend.namespaces.borrow().is_empty(),
+                "sock_diag should be available"
+            );
+
+            let from_netlink = only_ours(
+                query
+                    .ports_of_pid(std::process::id(), &backend, true)
+                    .unwrap(),
+            );
+            backend.namespaces.borrow_mut().clear();
+            let from_proc = only_ours(
+                query
+                    .ports_of_pid(std::process::id(), &backend, true)
+                    .unwrap(),
+            );
+
+            assert!(!from_proc.is_empty());
+            assert_eq!(from_proc, from_netlink);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn several_pids_read_the_socket_tables_once() {
+        let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut children = (0..2)
+            .map(|_| {
+                std::process::Command::new("sleep")
+                    .arg("30")
+                    .spawn()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut pids = vec![std::process::id()];
+        pids.extend(children.iter().map(|child| child.id()));
+
+        SOCKET_TABLE_READS.with(|reads| reads.set(0));
+        let ports = PortQuery::new().process_ids(&pids).execute_grouped();
+        let reads = SOCKET_TABLE_READS.with(|reads| reads.get());
+        for child in &mut children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+
+        assert_eq!(3, ports.unwrap().len());
+        assert_eq!(1, reads);
+    }
+
+    /// Port queries read `/proc` directly on Linux, so they must never pay for filling the process list of sysinfo
+    #[cfg(all(feature = "proc", target_os = "linux"))]
+    #[test]
+    fn linux_port_queries_do_not_use_the_process_list() {
+        let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let own_name = std::fs::read_to_string("/proc/self/comm").unwrap();
+
+        crate::proc_query::SHARED_SYSTEM_USES.with(|uses| uses.set(0));
+        PortQuery::new()
+            .process_id(std::process::id())
+            .tcp_only()
+            .execute()
+            .unwrap();
+        PortQuery::new()
+            .process_name(own_name.trim())
+            .tcp_only()
+            .execute()
+            .unwrap();
+
+        assert_eq!(
+            0,
+            crate::proc_query::SHARED_SYSTEM_USES.with(|uses| uses.get())
+        );
+    }
+
+    #[test]
+    fn windows_unowned_rows_never_match() {
+        assert!(!owner_matches(0, 0, false));
+        assert!(!owner_matches(0, 0, true));
+        assert!(!owner_matches(0, 1234, true));
+    }
+
+    #[test]
+    fn windows_system_rows_only_match_when_included() {
+        assert!(!owner_matches(4, 4, false));
+        assert!(owner_matches(4, 4, true));
+        assert!(!owner_matches(4, 1234, true));
+    }
+
+    #[test]
+    fn windows_process_rows_match_their_pid() {
+        assert!(owner_matches(1234, 1234, false));
+        assert!(owner_matches(1234, 1234, true));
+        assert!(!owner_matches(1234, 4, true));
+        assert!(!owner_matches(1234, 0, true));
+    }
+
+    #[test]
+    fn socket_activation_fds_need_a_matching_pid() {
+        let environ = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect::<std::collections::HashMap<_, _>>()
+        };
+
+        assert_eq!(
+            3..5,
+            socket_activation_fds(&environ(&[("LISTEN_PID", "42"), ("LISTEN_FDS", "2")]), 42)
+        );
+        assert!(
+            socket_activation_fds(&environ(&[("LISTEN_PID", "41"), ("LISTEN_FDS", "2")]), 42)
+                .is_empty()
+        );
+        assert!(socket_activation_fds(&environ(&[("LISTEN_FDS", "2")]), 42).is_empty());
+        assert!(
+            socket_activation_fds(&environ(&[("LISTEN_PID", "42"), ("LISTEN_FDS", "-1")]), 42)
+                .is_empty()
+        );
+        assert!(socket_activation_fds(&environ(&[]), 42).is_empty());
+    }
+
+    #[test]
+    fn no_protocols_is_a_configuration_error() {
+        let mut query = PortQuery::new().process_id(std::process::id());
+        query.tcp_addresses = false;
+        query.udp_addresses = false;
+
+        let err = query.execute().unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("TCP and UDP")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn no_address_families_is_a_configuration_error() {
+        let mut query = PortQuery::new().process_id(std::process::id());
+        query.ipv4_addresses = false;
+        query.ipv6_addresses = false;
+
+        let err = query.execute().unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("IPv4 and IPv6")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn empty_port_range_is_a_configuration_error() {
+        let err = PortQuery::new()
+            .process_id(std::process::id())
+            .port_range(9100, 9000)
+            .execute()
+            .unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("9100 to 9000")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn more_ports_than_the_maximum_is_a_configuration_error() {
+        let err = PortQuery::new()
+            .process_id(std::process::id())
+            .expect_min_num_ports(3)
+            .expect_max_num_ports(2)
+            .execute()
+            .unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("at least 3 and at most 2")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_out_of_time_is_a_timeout() {
+        let err = PortQuery::new()
+            .process_id(std::process::id())
+            .timeout(Duration::ZERO)
+            .execute()
+            .unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::Timeout(timeout, stage) if timeout.is_zero() && stage == "resolving the processes"),
+            "{:?}",
+            err
+        );
+        assert_eq!("timeout", err.code());
+
+        assert!(PortQuery::new()
+            .process_id(std::process::id())
+            .timeout(Duration::from_secs(30))
+            .execute()
+            .is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timeout_too_long_to_reach_never_runs_out() {
+        assert!(PortQuery::new()
+            .process_id(std::process::id())
+            .timeout(Duration::MAX)
+            .execute()
+            .is_ok());
+    }
+
+    #[test]
+    fn children_of_several_pids_are_a_configuration_error() {
+        let err = PortQuery::new()
+            .process_ids(&[std::process::id(), 1])
+            .include_children(true)
+            .execute()
+            .unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("process_ids")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn expectations_apply_to_each_of_several_pids() {
+        let query = PortQuery::new()
+            .process_ids(&[10, 11, 10])
+            .expect_min_num_ports(1);
+        let on = |port, pid| PortInfo::builder().port(port).pid(pid).build();
+
+        let err = query
+            .check_expectations(vec![
+                on(ProtocolPort::Tcp(8080), 10),
+                on(ProtocolPort::Tcp(8081), 10),
+            ])
+            .unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::TooFewPorts(found, 1) if found.is_empty()),
+            "{:?}",
+            err
+        );
+
+        let ports = query
+            .check_expectations(vec![
+                on(ProtocolPort::Tcp(8080), 10),
+                on(ProtocolPort::Tcp(8080), 11),
+            ])
+            .unwrap();
+        assert_eq!(2, ports.len());
+    }
+
+    #[test]
+    fn no_tcp_states_without_udp_is_a_configuration_error() {
+        let query = PortQuery::new()
+            .process_id(std::process::id())
+            .tcp_only()
+            .tcp_states(&[]);
+
+        let err = query.execute().unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("TCP states")),
+            "{:?}",
+            err
+        );
+
+        // UDP ports can still be found
+        assert!(PortQuery::new()
+            .process_id(std::process::id())
+            .tcp_states(&[])
+            .execute()
+            .is_ok());
+    }
+
+    #[test]
+    fn pid_zero_is_a_configuration_error() {
+        let err = PortQuery::new().process_id(0).execute().unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("pid 0")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn windows_table_rows_are_bounds_checked() {
+        let mut table = 2u32.to_ne_bytes().to_vec();
+        table.extend([1u32, 2, 3, 4].iter().flat_map(|v| v.to_ne_bytes()));
+
+        let rows: Vec<[u32; 2]> = unsafe { table_rows(&table, 4) }.unwrap();
+        assert_eq!(vec![[1, 2], [3, 4]], rows);
+
+        // Claims more rows than the buffer holds
+        table[..4].copy_from_slice(&3u32.to_ne_bytes());
+        assert!(unsafe { table_rows::<[u32; 2]>(&table, 4) }.is_err());
+        table[..4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(unsafe { table_rows::<[u32; 2]>(&table, 4) }.is_err());
+
+        assert!(unsafe { table_rows::<[u32; 2]>(&[0, 0], 4) }.is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn windows_table_rows_never_panic(table in proptest::collection::vec(proptest::num::u8::ANY, 0..64)) {
+            let _ = unsafe { table_rows::<[u32; 3]>(&table, 4) };
+        }
+
+        #[test]
+        fn lsof_parser_never_panics(output in proptest::collection::vec(proptest::num::u8::ANY, 0..256)) {
+            let _ = parse_lsof(&output);
+        }
+
+        #[test]
+        fn lsof_parser_never_panics_on_field_soup(
+            fields in proptest::collection::vec("[pftPn][0-9a-zA-Z:*\\[\\]>-]{0,12}", 0..16)
+        ) {
+            let _ = parse_lsof(fields.join("\0").as_bytes());
+        }
+    }
+
+    #[test]
+    fn lsof_output_is_split_by_process_family_and_protocol() {
+        let output = b"p100\0\nf5\0tIPv4\0PTCP\0n*:8080\0\nf6\0tIPv6\0PTCP\0n[::1]:8081\0\n\
+            p200\0\nf7\0tIPv4\0PUDP\0n127.0.0.1:5353->127.0.0.1:9999\0\nf8\0tIPv6\0PUDP\0n*:*\0\n";
+
+        assert_eq!(
+            vec![
+                LsofSocket {
+                    pid: 100,
+                    ipv6: false,
+                    port: ProtocolPort::Tcp(8080),
+                    local_addr: Some("0.0.0.0:8080".parse().unwrap()),
+                    tcp_state: None,
+                    fd: Some(5),
+                },
+                LsofSocket {
+                    pid: 100,
+                    ipv6: true,
+                    port: ProtocolPort::Tcp(8081),
+                    local_addr: Some("[::1]:8081".parse().unwrap()),
+                    tcp_state: None,
+                    fd: Some(6),
+                },
+                LsofSocket {
+                    pid: 200,
+                    ipv6: false,
+                    port: ProtocolPort::Udp(5353),
+                    local_addr: Some("127.0.0.1:5353".parse().unwrap()),
+                    tcp_state: None,
+                    fd: Some(7),
+                },
+            ],
+            parse_lsof(output)
+        );
+    }
+
+    #[test]
+    fn lsof_tcp_sockets_have_their_state() {
+        let output = b"p100\0\nf5\0tIPv4\0PTCP\0n*:8080\0TST=LISTEN\0TQR=0\0\n\
+            f6\0tIPv4\0PTCP\0n127.0.0.1:8080->127.0.0.1:50000\0TST=ESTABLISHED\0\n\
+            f7\0tIPv4\0PUDP\0n*:5353\0\nf8\0tIPv4\0PTCP\0n*:9090\0TST=UNKNOWN\0\n";
+
+        let states = parse_lsof(output)
+            .into_iter()
+            .map(|s| (s.port, s.tcp_state))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (ProtocolPort::Tcp(8080), Some(TcpState::Listen)),
+                (ProtocolPort::Tcp(8080), Some(TcpState::Established)),
+                (ProtocolPort::Udp(5353), None),
+                (ProtocolPort::Tcp(9090), None),
+            ],
+            states
+        );
+    }
+
+    #[test]
+    fn tcp_states_map_to_each_platform() {
+        for state in [
+            TcpState::Listen,
+            TcpState::SynSent,
+            TcpState::SynReceived,
+            TcpState::Established,
+            TcpState::FinWait1,
+            TcpState::FinWait2,
+            TcpState::CloseWait,
+            TcpState::Closing,
+            TcpState::LastAck,
+            TcpState::TimeWait,
+            TcpState::Closed,
+        ] {
+            assert_eq!(Some(state), lsof_tcp_state(lsof_state_name(state)));
+        }
+
+        assert_eq!(Some(TcpState::Listen), windows_tcp_state(2));
+        assert_eq!(Some(TcpState::TimeWait), windows_tcp_state(11));
+        assert_eq!(None, windows_tcp_state(13));
+    }
+
+    #[test]
+    fn lsof_ipv6_addresses_keep_their_scope() {
+        let ip = |host| lsof_ip(host, true).map(|(ip, scope)| (ip.to_string(), scope));
+
+        assert_eq!(Some(("::".to_string(), 0)), ip("*"));
+        assert_eq!(Some(("fe80::1".to_string(), 4)), ip("[fe80::1%4]"));
+        // The scope embedded by the kernel is moved out of the address
+        assert_eq!(Some(("fe80::1".to_string(), 4)), ip("[fe80:4::1]"));
+        assert_eq!(Some(("fe80::1".to_string(), 7)), ip("[fe80:4::1%7]"));
+        // Only link-local addresses have a scope embedded
+        assert_eq!(Some(("2001:db8::1".to_string(), 0)), ip("[2001:db8::1]"));
+        assert_eq!(
+            Some(("::ffff:127.0.0.1".to_string(), 0)),
+            ip("[::ffff:127.0.0.1]")
+        );
+        assert_eq!(None, ip("localhost"));
+    }
+
+    #[test]
+    fn windows_addresses_are_in_network_byte_order() {
+        assert_eq!(
+            "127.0.0.1:80".parse::<SocketAddr>().unwrap(),
+            windows_v4_addr(u32::from_ne_bytes([127, 0, 0, 1]), 80)
+        );
+
+        let link_local = "fe80::1".parse::<Ipv6Addr>().unwrap();
+        assert_eq!(
+            SocketAddr::from(std::net::SocketAddrV6::new(link_local, 80, 0, 4)),
+            windows_v6_addr(link_local.octets(), 4u32.to_be(), 80)
+        );
+    }
+
+    #[test]
+    fn windows_ports_are_in_network_byte_order() {
+        // 8080 is 0x1f90, which the tables store as the bytes 0x1f 0x90 in the low 16 bits
+        assert_eq!(8080, windows_port(u32::from_ne_bytes([0x1f, 0x90, 0, 0])));
+        #[cfg(target_endian = "little")]
+        assert_eq!(8080, windows_port(0x901f));
+        assert_eq!(80, windows_port(u32::from_ne_bytes([0, 80, 0, 0])));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn lsof_runs_once_per_execute() {
+        let _listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _socket = std::net::UdpSocket::bind("[::1]:0").unwrap();
+
+        let before = crate::tool::spawn_count();
+        let ports = PortQuery::new()
+            .process_id(std::process::id())
+            .execute()
+            .unwrap();
+
+        assert_eq!(before + 1, crate::tool::spawn_count());
+        assert!(ports.len() >= 2);
+    }
+
+    #[test]
+    fn lsof_is_run_by_the_tool_runner() {
+        let runner = Arc::new(crate::tool::CannedRunner::new(
+            0,
+            b"p100\0\nf5\0tIPv4\0PTCP\0n*:8080\0TST=LISTEN\0\n\
+            p200\0\nf7\0tIPv6\0PUDP\0n[::1]:5353\0\n",
+            b"",
+        ));
+        let query = PortQuery::new()
+            .tcp_states(&[TcpState::Listen, TcpState::Established])
+            .with_tool_runner(runner.clone());
+
+        let sockets = lsof_sockets(&query, None, true, None).unwrap();
+        assert_eq!(
+            vec![
+                (100, ProtocolPort::Tcp(8080), Some(TcpState::Listen)),
+                (200, ProtocolPort::Udp(5353), None)
+            ],
+            sockets
+                .iter()
+                .map(|s| (s.pid, s.port, s.tcp_state))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![vec![
+                "lsof",
+                "-iTCP",
+                "-sTCP:LISTEN,ESTABLISHED",
+                "-iUDP",
+                "-nP",
+                "-F0tPnT"
+            ]],
+            runner.runs()
+        );
+
+        lsof_sockets(&query.udp_only().tcp_states(&[]), Some(5353), true, None).unwrap();
+        assert_eq!(
+            vec!["lsof", "-iUDP:5353", "-nP", "-F0tPnT"],
+            runner.runs()[1]
+        );
+    }
+
+    #[test]
+    fn lsof_which_finds_nothing_is_not_an_error() {
+        // lsof exits with 1 when no files match
+        let query = PortQuery::new()
+            .with_tool_runner(Arc::new(crate::tool::CannedRunner::new(1, b"", b"")));
+
+        assert!(lsof_sockets(&query, None, true, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lsof_which_is_denied_access_is_sandbox_restricted() {
+        let query = PortQuery::new().with_tool_runner(Arc::new(crate::tool::CannedRunner::new(
+            1,
+            b"",
+            b"lsof: Operation not permitted\n",
+        )));
+
+        let err = lsof_sockets(&query, None, true, None).unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::SandboxRestricted(op) if op.contains("lsof")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn lsof_which_finishes_after_the_deadline_is_a_timeout() {
+        let query = PortQuery::new()
+            .timeout(Duration::from_secs(2))
+            .with_tool_runner(Arc::new(crate::tool::CannedRunner::new(0, b"", b"")));
+        let deadline = std::time::Instant::now().checked_sub(Duration::from_millis(1));
+
+        let err = lsof_sockets(&query, None, true, deadline).unwrap_err();
+        assert!(
+            matches!(&err, ProcCtlError::Timeout(timeout, stage) if timeout.as_secs() == 2 && stage == "running lsof"),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Finding processes together with their ports, see [ports_for].
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::port_query::PortQuery;
+use crate::proc_query::{check_pids, PidStatus, ProcInfo, ProcQuery};
+use crate::types::{Pid, ProtocolPort};
+use std::time::{Duration, SystemTime};
+
+/// The ports of each process found by [ports_for], or why they could not be listed
+pub type ProcessPorts = (ProcInfo, ProcCtlResult<Vec<ProtocolPort>>);
+
+/// Find the processes matching `query` and list the ports of each, in one call.
+///
+/// The processes are listed from one refresh of the process list and their ports from one snapshot of the sockets.
+/// `port_opts` gives the protocol and address filters and the expectations, such as
+/// [PortQuery::expect_min_num_ports], which are checked for each process on its own. Any process it selects is
+/// ignored in favour of those matched by `query`.
+///
+/// This needs the `proc` feature, since the processes are found by a [ProcQuery], and is available on Linux, Windows and
+/// macOS. To find the ports of a process and its children without the `proc` feature, use
+/// [PortQuery::include_children], which reads the children from `/proc` on Linux.
+///
+/// A failure for one process does not fail the call, but is reported in its entry instead. When a process exits between
+/// being listed and its ports being read, or while they are read, the processes are listed again, up to
+/// [PortQuery::re_resolve_attempts] times, so that a process which was restarted is found under its new pid. A process
+/// which still exits has [ProcCtlError::ResolvedProcessExited], since its ports may be missing or may belong to a new
+/// process which reused its pid. A `query` which selects a pid is listed once, and a process which exits has
+/// [ProcCtlError::ProcessExited].
+///
+/// ```rust no_run
+/// use proc_ctl::{PortQuery, ProcQuery};
+///
+/// let processes = ProcQuery::new().process_name("server");
+/// for (info, ports) in proc_ctl::ports_for(&processes, &PortQuery::new().tcp_only()).unwrap() {
+///     match ports {
+///         Ok(ports) => println!("{} (pid {}) has {:?}", info.name, info.pid, ports),
+///         Err(e) => println!("{} (pid {}) failed: {}", info.name, info.pid, e),
+///     }
+/// }
+/// ```
+pub fn ports_for(query: &ProcQuery, port_opts: &PortQuery) -> ProcCtlResult<Vec<ProcessPorts>> {
+    let re_resolve = if query.selects_pid() {
+        0
+    } else {
+        port_opts.re_resolve_attempts_for_each()
+    };
+
+    crate::re_resolve::query_each_until_running(
+        re_resolve,
+        || query.describe_selection(),
+        || list_once(query, port_opts),
+    )
+}
+
+/// The ports of a process listed by one attempt, alongside its pid
+type ListedPorts = (ProcInfo, Pid, ProcCtlResult<Vec<ProtocolPort>>);
+
+/// List the processes and their ports once
+fn list_once(query: &ProcQuery, port_opts: &PortQuery) -> ProcCtlResult<Vec<ListedPorts>> {
+    let processes = query.list_processes()?;
+    let pids = processes.iter().map(|p| p.pid).collect::<Vec<_>>();
+    let mut ports = port_opts.ports_of_each(&pids)?;
+    fail_exited(&processes, &mut ports)?;
+
+    Ok(processes
+        .into_iter()
+        .zip(pids)
+        .zip(ports)
+        .map(|((info, pid), ports)| (info, pid, ports))
+        .collect())
+}
+
+/// Fail the ports of each process which exited or was replaced after it was listed, since its ports may be missing or
+/// may belong to another process which reused its pid
+pub(crate) fn fail_exited(
+    processes: &[ProcInfo],
+    ports: &mut [ProcCtlResult<Vec<ProtocolPort>>],
+) -> ProcCtlResult<()> {
+    let listed = processes
+        .iter()
+        .map(|p| {
+            let started = SystemTime::UNIX_EPOCH + Duration::from_secs(p.start_time);
+            (p.pid, Some(started))
+        })
+        .collect::<Vec<_>>();
+    for ((process, status), ports) in processes.iter().zip(check_pids(&listed)?).zip(ports) {
+        if ports.is_ok() && status != PidStatus::Alive {
+            *ports = Err(ProcCtlError::ProcessExited(process.pid));
+        }
+    }
+
+    Ok(())
+}
+
+/// Call [ports_for] until at least one process matches and the ports of every process are listed, making at most
+/// `count` attempts with `delay` between them.
+///
+/// Fails with the error of the first process which failed on the last attempt, or with
+/// [ProcCtlError::NoMatchingProcess] if nothing matched. Delays use the clock of `query`.
+#[cfg(feature = "resilience")]
+pub fn ports_for_with_retry_sync(
+    query: &ProcQuery,
+    port_opts: &PortQuery,
+    delay: Duration,
+    count: usize,
+) -> ProcCtlResult<Vec<(ProcInfo, Vec<ProtocolPort>)>> {
+    crate::retrying::retry_sync(query.clock().as_ref(), delay, count, || {
+        all_listed(ports_for(query, port_opts)?)
+    })
+}
+
+/// Async equivalent of [ports_for_with_retry_sync], with the same number of attempts
+#[cfg(feature = "async")]
+pub async fn ports_for_with_retry(
+    query: &ProcQuery,
+    port_opts: &PortQuery,
+    delay: Duration,
+    count: usize,
+) -> ProcCtlResult<Vec<(ProcInfo, Vec<ProtocolPort>)>> {
+    crate::retrying::retry_async(query.clock().as_ref(), delay, count, || {
+        all_listed(ports_for(query, port_opts)?)
+    })
+    .await
+}
+
+/// Succeed only if something matched and every process had its ports listed
+#[cfg(any(feature = "resilience", feature = "async"))]
+fn all_listed(found: Vec<ProcessPorts>) -> ProcCtlResult<Vec<(ProcInfo, Vec<ProtocolPort>)>> {
+    if found.is_empty() {
+        return Err(ProcCtlError::NoMatchingProcess(
+            "the process query".to_string(),
+        ));
+    }
+
+    found
+        .into_iter()
+        .map(|(info, ports)| Ok((info, ports?)))
+        .collect()
+}
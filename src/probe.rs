@@ -0,0 +1,40 @@
+//! Checking whether a port is in use by trying to bind it.
+//!
+//! This needs no permission to see other processes, so it still works where the sockets of other processes are
+//! hidden, such as for other users' processes on macOS. It only tells that a port is taken, not who has it.
+
+use crate::types::{AddressFamily, ProtocolPort};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+
+/// Check whether a port is in use, by trying to bind it on the loopback and unspecified addresses of `family`.
+///
+/// The port is only reported as in use if a bind fails because the address is taken, so ports which this process
+/// isn't allowed to bind, such as those below 1024 without privileges, are not reported. This is best effort: on
+/// platforms where a socket can be bound to the unspecified address while another is bound to a specific address,
+/// such as macOS, a port used only on a non-loopback address is not found.
+///
+/// ```rust
+/// use proc_ctl::{is_port_in_use, AddressFamily, ProtocolPort};
+///
+/// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// let port = ProtocolPort::Tcp(listener.local_addr().unwrap().port());
+///
+/// assert!(is_port_in_use(port, AddressFamily::Ipv4));
+/// drop(listener);
+/// assert!(!is_port_in_use(port, AddressFamily::Ipv4));
+/// ```
+pub fn is_port_in_use(port: ProtocolPort, family: AddressFamily) -> bool {
+    let addresses: [IpAddr; 2] = match family {
+        AddressFamily::Ipv4 => [Ipv4Addr::LOCALHOST.into(), Ipv4Addr::UNSPECIFIED.into()],
+        AddressFamily::Ipv6 => [Ipv6Addr::LOCALHOST.into(), Ipv6Addr::UNSPECIFIED.into()],
+    };
+
+    addresses.into_iter().any(|ip| {
+        let result = match port {
+            ProtocolPort::Tcp(port) => TcpListener::bind(SocketAddr::new(ip, port)).map(drop),
+            ProtocolPort::Udp(port) => UdpSocket::bind(SocketAddr::new(ip, port)).map(drop),
+        };
+
+        matches!(result, Err(e) if e.kind() == std::io::ErrorKind::AddrInUse)
+    })
+}
@@ -0,0 +1,360 @@
+//! The filters of a [crate::ProcQuery], applied to processes which have already been found.
+//!
+//! A query matches a running process and a [ProcInfo] found earlier with the same code in this module, so [apply]
+//! gives the same answer for processes captured elsewhere, such as in a [crate::Snapshot], as the query would have
+//! given on the machine they were captured on.
+
+use crate::capabilities::Capabilities;
+use crate::limits::ProcLimits;
+use crate::proc_query::{MatchField, ProcInfo};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Filter `processes` and check them against the expectations of `config`, as a [crate::ProcQuery] made with
+/// [crate::ProcQuery::from_config] does for running processes.
+///
+/// Processes are filtered by pid, name, parent name, container, capabilities and open file limit. The parent of a
+/// process is looked for among `processes`, so a process whose parent wasn't captured doesn't match a parent name.
+/// A filter needs the process to have been captured with the field it reads, such as [ProcInfo::container_id] or
+/// [ProcInfo::capabilities], and a process without it is treated as it would be when the field can't be read, so
+/// for example doesn't have any capabilities. On Linux, [MatchField::Comm] is matched against [ProcInfo::name], since
+/// the current short name isn't captured. `expect_nofile_at_least` is checked against the processes which match, and
+/// `expect_min_num_children` is not checked, since it is about the children found by [crate::ProcQuery::children].
+///
+/// Names are given as they are matched on this platform, as for a query. Use `match_canonical_names` to match
+/// processes captured on another platform.
+///
+/// Fails with [crate::ProcCtlError::LimitTooLow] if a process which matches has a lower open file limit than
+/// `expect_nofile_at_least`.
+///
+/// ```rust
+/// use proc_ctl::{ProcInfo, ProcQueryConfig};
+///
+/// let captured = vec![
+///     ProcInfo::builder().pid(10).name("supervisor").build(),
+///     ProcInfo::builder().pid(11).parent(10).name("worker").build(),
+///     ProcInfo::builder().pid(12).name("worker").build(),
+/// ];
+///
+/// let config: ProcQueryConfig = serde_json::from_str(
+///     r#"{"process_name": "worker", "parent_name": "supervisor", "match_canonical_names": true}"#,
+/// )
+/// .unwrap();
+/// let found = proc_ctl::proc_filters::apply(&config, captured).unwrap();
+///
+/// assert_eq!(vec![11], found.iter().map(|p| p.pid).collect::<Vec<_>>());
+/// ```
+#[cfg(feature = "serde")]
+pub fn apply(
+    config: &crate::ProcQueryConfig,
+    processes: impl IntoIterator<Item = ProcInfo>,
+) -> crate::ProcCtlResult<Vec<ProcInfo>> {
+    crate::ProcQuery::from_config(config).filter_captured(processes.into_iter().collect())
+}
+
+/// The parts of a process which a name is matched against, which are read from a running process or a [ProcInfo]
+pub(crate) trait NamedProcess {
+    /// The name the platform gives the process
+    fn name(&self) -> Cow<'_, str>;
+    /// The current short name of the process on Linux, or `None` if it can't be read or isn't known
+    fn comm(&self) -> Option<String>;
+    /// The first element of the command line
+    fn argv0(&self) -> Option<Cow<'_, str>>;
+    /// The path of the executable
+    fn exe(&self) -> Option<&Path>;
+    /// See [ProcInfo::canonical_name]
+    fn canonical_name(&self) -> String;
+}
+
+impl NamedProcess for ProcInfo {
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.name)
+    }
+
+    fn comm(&self) -> Option<String> {
+        None
+    }
+
+    fn argv0(&self) -> Option<Cow<'_, str>> {
+        self.argv0.as_deref().map(Cow::Borrowed)
+    }
+
+    fn exe(&self) -> Option<&Path> {
+        self.exe.as_deref()
+    }
+
+    fn canonical_name(&self) -> String {
+        ProcInfo::canonical_name(self)
+    }
+}
+
+/// Whether the `field` of a process matches `name`, or its canonical name does if `canonical` is set. `name` is
+/// already normalized for the platform, as the names given to a query are.
+pub(crate) fn name_matches(
+    p: &impl NamedProcess,
+    name: &str,
+    field: MatchField,
+    canonical: bool,
+) -> bool {
+    if canonical {
+        return p.canonical_name() == crate::names::canonical(name);
+    }
+
+    match field {
+        MatchField::Name => p.name() == name,
+        MatchField::Comm => match p.comm() {
+            Some(comm) => comm == name,
+            None => p.name() == name,
+        },
+        MatchField::Argv0 => p.argv0().is_some_and(|argv0| {
+            argv0 == name
+                || Path::new(argv0.as_ref())
+                    .file_name()
+                    .is_some_and(|f| crate::proc_query::normalize_name(f.to_string_lossy()) == name)
+        }),
+        MatchField::Exe => p
+            .exe()
+            .and_then(|exe| exe.file_name())
+            .is_some_and(|f| f.to_string_lossy() == name),
+    }
+}
+
+/// Whether the field a name is matched against is known for a process, so that a process which doesn't match can be
+/// told apart from one which can't be matched
+pub(crate) fn name_available(p: &impl NamedProcess, field: MatchField, canonical: bool) -> bool {
+    match field {
+        _ if canonical => true,
+        MatchField::Name | MatchField::Comm => true,
+        MatchField::Argv0 => p.argv0().is_some(),
+        MatchField::Exe => p.exe().is_some(),
+    }
+}
+
+/// Whether a process with the container id `container_id`, or `None` if it isn't in a container, passes the
+/// [crate::ProcQuery::in_container] and [crate::ProcQuery::container_id_prefix] filters. The prefix is lowercase.
+pub(crate) fn container_matches(
+    in_container: Option<bool>,
+    prefix: Option<&str>,
+    container_id: Option<&str>,
+) -> bool {
+    let in_container = in_container.map_or(true, |want| container_id.is_some() == want);
+    let prefix_matches = prefix.map_or(true, |prefix| {
+        container_id.is_some_and(|id| id.starts_with(prefix))
+    });
+
+    in_container && prefix_matches
+}
+
+/// Whether a process has every capability in `required` in its effective set. A process whose capabilities aren't
+/// known has none.
+pub(crate) fn has_capabilities(required: &[String], capabilities: Option<&Capabilities>) -> bool {
+    required
+        .iter()
+        .all(|name| capabilities.is_some_and(|caps| caps.has_effective(name)))
+}
+
+/// Whether a process can open at least `num_files` files. A process whose limits aren't known can't.
+pub(crate) fn has_open_files(num_files: u64, limits: Option<&ProcLimits>) -> bool {
+    limits.is_some_and(|limits| limits.open_files.is_at_least(num_files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limit;
+
+    fn worker() -> ProcInfo {
+        ProcInfo::builder()
+            .pid(20)
+            .parent(10)
+            .name("wrk")
+            .cmd(["/opt/app/worker", "--id", "1"])
+            .argv0("/opt/app/worker")
+            .exe("/opt/app/bin/worker-bin")
+            .build()
+    }
+
+    // The names given to a query have a `.exe` extension added on Windows
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn names_match_on_the_chosen_field() {
+        let p = worker();
+
+        assert!(name_matches(&p, "wrk", MatchField::Name, false));
+        assert!(!name_matches(&p, "worker", MatchField::Name, false));
+
+        // The current short name isn't captured, so the name is used
+        assert!(name_matches(&p, "wrk", MatchField::Comm, false));
+
+        assert!(name_matches(&p, "worker", MatchField::Argv0, false));
+        assert!(name_matches(
+            &p,
+            "/opt/app/worker",
+            MatchField::Argv0,
+            false
+        ));
+        assert!(!name_matches(&p, "wrk", MatchField::Argv0, false));
+
+        assert!(name_matches(&p, "worker-bin", MatchField::Exe, false));
+        assert!(!name_matches(
+            &p,
+            "/opt/app/bin/worker-bin",
+            MatchField::Exe,
+            false
+        ));
+    }
+
+    #[test]
+    fn canonical_names_match_whatever_the_field() {
+        let p = ProcInfo::builder()
+            .name("server.exe")
+            .exe(r"C:\tools\server.exe")
+            .build();
+
+        for field in [MatchField::Name, MatchField::Argv0, MatchField::Exe] {
+            assert!(name_matches(&p, "server", field, true));
+            assert!(name_matches(&p, "server.exe", field, true));
+            assert!(name_matches(&p, "/usr/bin/server", field, true));
+            assert!(!name_matches(&p, "Server", field, true));
+        }
+    }
+
+    #[test]
+    fn missing_fields_are_unavailable_rather_than_different() {
+        let p = ProcInfo::builder().name("wrk").build();
+
+        assert!(!name_matches(&p, "worker", MatchField::Argv0, false));
+        assert!(!name_matches(&p, "worker", MatchField::Exe, false));
+
+        assert!(name_available(&p, MatchField::Name, false));
+        assert!(name_available(&p, MatchField::Comm, false));
+        assert!(!name_available(&p, MatchField::Argv0, false));
+        assert!(!name_available(&p, MatchField::Exe, false));
+        assert!(name_available(&p, MatchField::Exe, true));
+
+        assert!(name_available(&worker(), MatchField::Argv0, false));
+        assert!(name_available(&worker(), MatchField::Exe, false));
+    }
+
+    #[test]
+    fn containers_match_by_presence_and_prefix() {
+        let id = Some("4f2a9c");
+
+        assert!(container_matches(None, None, None));
+        assert!(container_matches(Some(true), None, id));
+        assert!(!container_matches(Some(true), None, None));
+        assert!(container_matches(Some(false), None, None));
+        assert!(!container_matches(Some(false), None, id));
+
+        assert!(container_matches(None, Some("4f2"), id));
+        assert!(!container_matches(None, Some("4f3"), id));
+        assert!(!container_matches(None, Some("4f2"), None));
+        assert!(!container_matches(Some(false), Some("4f2"), id));
+    }
+
+    #[test]
+    fn every_required_capability_must_be_effective() {
+        // CAP_NET_BIND_SERVICE is bit 10 and CAP_NET_RAW is bit 13
+        let caps = Capabilities::new(1 << 10, (1 << 10) | (1 << 13));
+        let bind = ["CAP_NET_BIND_SERVICE".to_string()];
+        let both = [
+            "CAP_NET_BIND_SERVICE".to_string(),
+            "CAP_NET_RAW".to_string(),
+        ];
+
+        assert!(has_capabilities(&[], None));
+        assert!(has_capabilities(&bind, Some(&caps)));
+        assert!(!has_capabilities(&both, Some(&caps)));
+        assert!(!has_capabilities(&bind, None));
+    }
+
+    #[test]
+    fn open_file_limits_must_be_known_and_high_enough() {
+        let limits = |soft| {
+            ProcLimits::new(
+                Limit::new(soft, None),
+                Limit::new(None, None),
+                Limit::new(None, None),
+            )
+        };
+
+        assert!(has_open_files(1024, Some(&limits(Some(1024)))));
+        assert!(!has_open_files(1025, Some(&limits(Some(1024)))));
+        assert!(has_open_files(1 << 20, Some(&limits(None))));
+        assert!(!has_open_files(1, None));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn config_is_applied_as_a_query_would() {
+        let supervisor = ProcInfo::builder().pid(10).name("supervisor").build();
+        let orphan = ProcInfo::builder().pid(30).parent(99).name("wrk").build();
+        let captured = vec![supervisor, worker(), orphan];
+
+        let config: crate::ProcQueryConfig = serde_json::from_str(
+            r#"{"process_name": "worker-bin", "match_canonical_names": true}"#,
+        )
+        .unwrap();
+        let found = apply(&config, captured.clone()).unwrap();
+        assert_eq!(vec![20], found.iter().map(|p| p.pid).collect::<Vec<_>>());
+
+        let config: crate::ProcQueryConfig =
+            serde_json::from_str(r#"{"parent_name": "supervisor", "match_canonical_names": true}"#)
+                .unwrap();
+        let found = apply(&config, captured.clone()).unwrap();
+        assert_eq!(vec![20], found.iter().map(|p| p.pid).collect::<Vec<_>>());
+
+        let config: crate::ProcQueryConfig = serde_json::from_str(r#"{"process_id": 30}"#).unwrap();
+        let found = apply(&config, captured.clone()).unwrap();
+        assert_eq!(vec![30], found.iter().map(|p| p.pid).collect::<Vec<_>>());
+
+        let config: crate::ProcQueryConfig =
+            serde_json::from_str(r#"{"in_container": true}"#).unwrap();
+        assert!(apply(&config, captured).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn limits_are_filtered_and_expected() {
+        let limits = |soft| {
+            ProcLimits::new(
+                Limit::new(Some(soft), None),
+                Limit::new(None, None),
+                Limit::new(None, None),
+            )
+        };
+        let captured = vec![
+            ProcInfo::builder().pid(1).limits(limits(1024)).build(),
+            ProcInfo::builder().pid(2).limits(limits(65536)).build(),
+            ProcInfo::builder().pid(3).build(),
+        ];
+
+        let config: crate::ProcQueryConfig =
+            serde_json::from_str(r#"{"nofile_at_least": 4096}"#).unwrap();
+        let found = apply(&config, captured.clone()).unwrap();
+        assert_eq!(vec![2], found.iter().map(|p| p.pid).collect::<Vec<_>>());
+
+        let config: crate::ProcQueryConfig =
+            serde_json::from_str(r#"{"expect_nofile_at_least": 4096}"#).unwrap();
+        match apply(&config, captured) {
+            Err(crate::ProcCtlError::LimitTooLow(shortfall)) => {
+                assert_eq!(1, shortfall.process.pid)
+            }
+            other => panic!("expected the limit to be too low, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "proptest"))]
+mod property_tests {
+    use crate::invariants::check_proc_filters;
+    use crate::strategies::{proc_infos, proc_query_config};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn filters_are_consistent(config in proc_query_config(), processes in proc_infos()) {
+            check_proc_filters(&config, &processes)?;
+        }
+    }
+}
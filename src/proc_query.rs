@@ -1,177 +1,2274 @@
+use crate::clock::Clock;
 use crate::common::{resolve_pid, MaybeHasPid};
-use crate::{Pid, ProcCtlError, ProcCtlResult};
+use crate::pid::{from_sysinfo, to_sysinfo};
+use crate::time::wall_clock_since;
+use crate::{Observed, Pid, ProcCtlError, ProcCtlResult};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::path::PathBuf;
 use std::process::Child;
-use std::sync::Mutex;
 use std::sync::OnceLock;
-use sysinfo::{Process, ProcessRefreshKind, ProcessesToUpdate, RefreshKind, System};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::SystemTime;
+use sysinfo::{Process, ProcessRefreshKind, ProcessesToUpdate, RefreshKind, System, UpdateKind};
 
 /// Information about a process
-#[derive(Debug, Clone)]
+///
+/// New fields are added as more information is collected, so outside this crate values are created with
+/// [ProcInfo::new] or [ProcInfo::builder] rather than a struct literal.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct ProcInfo {
     /// The name
     pub name: String,
-    /// The command used to launch the process
+    /// The command used to launch the process.
+    ///
+    /// Some processes rewrite their command line after starting, to show their status. This is the command line as
+    /// it was when it was first read, unless [ProcQuery::refresh_cmd] is used.
+    ///
+    /// Some launchers, such as JVM wrappers, pad or scrub their command line in place, which leaves runs of NULs that
+    /// are read as empty arguments. Empty arguments at the end are always left out, and so are those between other
+    /// arguments unless [ProcQuery::keep_empty_args] is enabled, so `cmd.join(" ")` has no doubled spaces.
     pub cmd: Vec<String>,
+    /// The first non-empty element of the command line, usually the program as it was invoked
+    pub argv0: Option<String>,
     /// The path to the executable the process is running
     pub exe: Option<PathBuf>,
     /// The process ID
     pub pid: Pid,
     /// Parent process ID if relevant
     pub parent: Option<Pid>,
-    /// Environment variables available to the process
+    /// Environment variables available to the process, each as `NAME=value`.
+    ///
+    /// Empty entries, which are left by a process that scrubs its environment in place, are left out. Any other entry
+    /// is kept as it was read, even without a `=`, and is skipped by [ProcInfo::env_var] and [ProcInfo::env_map].
+    ///
+    /// This is empty when the environment was not collected, see [ProcInfo::env_collected]. Read it with
+    /// [ProcInfo::env_var] or [ProcInfo::env_map] to tell the two apart.
     pub env: Vec<String>,
+    /// Whether [ProcInfo::env] was collected, which it is unless [ProcQuery::with_env] is disabled
+    pub env_collected: bool,
     /// The current working directory of the process
     pub cwd: Option<PathBuf>,
+    /// When the process started, in seconds since the Unix epoch
+    ///
+    /// Together with [ProcInfo::pid] this identifies a process, even if its pid is later reused.
+    pub start_time: u64,
+    /// The inode of the network namespace of the process. Only collected on Linux when
+    /// [ProcQuery::with_namespaces] is enabled.
+    pub net_ns: Option<u64>,
+    /// The inode of the pid namespace of the process. Only collected on Linux when [ProcQuery::with_namespaces] is
+    /// enabled.
+    pub pid_ns: Option<u64>,
+    /// The ID of the container the process is running in, found from its cgroup. Only collected on Linux when
+    /// [ProcQuery::with_namespaces] is enabled.
+    ///
+    /// This is best effort. Docker, containerd, CRI-O and Podman are recognised.
+    pub container_id: Option<String>,
+    /// The effective and permitted capabilities of the process. Only collected on Linux when
+    /// [ProcQuery::with_capabilities] is enabled.
+    pub capabilities: Option<crate::capabilities::Capabilities>,
+    /// The inode of the mount namespace of the process. Only collected on Linux when [ProcQuery::with_namespaces] is
+    /// enabled.
+    ///
+    /// A process in a different mount namespace to the one running the query sees a different filesystem, so its
+    /// [ProcInfo::exe] and [ProcInfo::cwd] may not refer to the same files here.
+    pub mnt_ns: Option<u64>,
+    /// The root directory of the process, which is not `/` for a chrooted process. Only collected on Linux when
+    /// [ProcQuery::with_namespaces] or [ProcQuery::paths_relative_to_proc_root] is enabled.
+    ///
+    /// Reading the root of a process belonging to another user needs the same privileges as tracing it, such as
+    /// `CAP_SYS_PTRACE`, and without them this is `None`.
+    pub root: Option<PathBuf>,
+    /// The resource limits of the process, such as how many files it can have open. Only collected on Linux when
+    /// [ProcQuery::with_limits] is enabled.
+    pub limits: Option<crate::limits::ProcLimits>,
+}
+
+/// Written out so that [Clone::clone_from] reuses the allocations of each field, which is what lets
+/// [ProcQuery::last_observed] be recorded without allocating on every refresh. New fields must be added to both.
+impl Clone for ProcInfo {
+    fn clone(&self) -> Self {
+        ProcInfo {
+            name: self.name.clone(),
+            cmd: self.cmd.clone(),
+            argv0: self.argv0.clone(),
+            exe: self.exe.clone(),
+            pid: self.pid,
+            parent: self.parent,
+            env: self.env.clone(),
+            env_collected: self.env_collected,
+            cwd: self.cwd.clone(),
+            start_time: self.start_time,
+            net_ns: self.net_ns,
+            pid_ns: self.pid_ns,
+            container_id: self.container_id.clone(),
+            capabilities: self.capabilities.clone(),
+            mnt_ns: self.mnt_ns,
+            root: self.root.clone(),
+            limits: self.limits,
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.name.clone_from(&source.name);
+        self.cmd.clone_from(&source.cmd);
+        self.argv0.clone_from(&source.argv0);
+        self.exe.clone_from(&source.exe);
+        self.pid = source.pid;
+        self.parent = source.parent;
+        self.env.clone_from(&source.env);
+        self.env_collected = source.env_collected;
+        self.cwd.clone_from(&source.cwd);
+        self.start_time = source.start_time;
+        self.net_ns = source.net_ns;
+        self.pid_ns = source.pid_ns;
+        self.container_id.clone_from(&source.container_id);
+        self.capabilities.clone_from(&source.capabilities);
+        self.mnt_ns = source.mnt_ns;
+        self.root.clone_from(&source.root);
+        self.limits = source.limits;
+    }
+}
+
+impl ProcInfo {
+    /// Create the information for a process with only a pid and name, for use as a test fixture.
+    ///
+    /// Every other field is empty or `None`.
+    ///
+    /// ```rust
+    /// let info = proc_ctl::ProcInfo::new(1234, "server");
+    /// assert_eq!(1234, info.pid);
+    /// assert!(info.cmd.is_empty());
+    /// ```
+    pub fn new(pid: Pid, name: impl Into<String>) -> Self {
+        ProcInfo::builder().pid(pid).name(name).build()
+    }
+
+    /// Start building the information for a process, for use as a test fixture.
+    ///
+    /// ```rust
+    /// let parent = proc_ctl::ProcInfo::new(1, "init");
+    /// let child = proc_ctl::ProcInfo::builder()
+    ///     .pid(1234)
+    ///     .name("server")
+    ///     .cmd(["server", "--port", "8080"])
+    ///     .parent(parent.pid)
+    ///     .build();
+    ///
+    /// assert_eq!(Some("server"), child.argv0.as_deref());
+    /// assert_eq!(Some(1), child.parent);
+    /// ```
+    pub fn builder() -> ProcInfoBuilder {
+        ProcInfoBuilder::default()
+    }
+
+    /// The name of the process as it would be on any platform, made from the file name of [ProcInfo::exe] when it is
+    /// known and [ProcInfo::name] otherwise, without a `.exe` extension.
+    ///
+    /// The same program has the same canonical name on Linux, Windows and macOS, so assertions on it don't need a
+    /// branch for each platform. The name is not truncated on Linux when the executable or command line can be read.
+    /// See [ProcQuery::match_canonical_names] to match processes by this name.
+    ///
+    /// ```rust
+    /// let info = proc_ctl::ProcInfo::builder()
+    ///     .name("server.exe")
+    ///     .exe(r"C:\tools\server.exe")
+    ///     .build();
+    /// assert_eq!("server", info.canonical_name());
+    ///
+    /// let truncated = proc_ctl::ProcInfo::builder()
+    ///     .name("a-very-long-ser")
+    ///     .cmd(["/opt/a-very-long-server-name", "--port", "8080"])
+    ///     .build();
+    /// assert_eq!("a-very-long-server-name", truncated.canonical_name());
+    /// ```
+    pub fn canonical_name(&self) -> String {
+        let exe = self.exe.as_deref().map(|exe| exe.to_string_lossy());
+        crate::names::canonical_name(exe.as_deref(), self.argv0.as_deref(), &self.name)
+    }
+
+    /// Whether this process has the fields set on `expectation`, ignoring the others. Use this rather than comparing
+    /// whole processes, whose environment and start time differ from run to run.
+    ///
+    /// ```rust
+    /// use proc_ctl::expectations::expect;
+    ///
+    /// let info = proc_ctl::ProcInfo::builder()
+    ///     .pid(11)
+    ///     .name("worker")
+    ///     .cmd(["worker", "--shard", "1"])
+    ///     .build();
+    /// assert!(info.matches(&expect().name("worker").cmd_contains("--shard")));
+    /// assert!(!info.matches(&expect().pid(12)));
+    /// ```
+    pub fn matches(&self, expectation: &crate::expectations::ProcInfoExpectation) -> bool {
+        expectation.matches(self)
+    }
+
+    /// Get the details of the running process `pid`, as a [ProcQuery] selecting it by [ProcQuery::process_id] would.
+    ///
+    /// Fails with [ProcCtlError::ProcessNotFound] if there is no such process. On Linux a thread id is not a process,
+    /// so it is not found either.
+    ///
+    /// ```rust
+    /// let info = proc_ctl::ProcInfo::from_pid(std::process::id()).unwrap();
+    /// assert_eq!(std::process::id(), info.pid);
+    /// ```
+    pub fn from_pid(pid: Pid) -> ProcCtlResult<ProcInfo> {
+        let query = ProcQuery::new().process_id(pid);
+        let sys_pid = to_sysinfo(pid)?;
+
+        let mut sys_handle = sys_handle();
+        sys_handle.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[sys_pid]),
+            true,
+            query.refresh_kind(),
+        );
+        match sys_handle.process(sys_pid).filter(|p| !is_thread(p)) {
+            Some(p) => Ok(query.info(p)),
+            None => Err(ProcCtlError::ProcessNotFound(pid)),
+        }
+    }
+
+    /// The environment variables of the process by name.
+    ///
+    /// Fails with [ProcCtlError::EnvNotCollected] if the environment was not collected, rather than giving an empty
+    /// map. A variable which appears more than once has the last of its values, and any entry without a `=` is left
+    /// out.
+    ///
+    /// ```rust
+    /// let info = proc_ctl::ProcInfo::builder()
+    ///     .pid(1234)
+    ///     .env(["PORT=8080", "RUST_LOG=info"])
+    ///     .build();
+    ///
+    /// assert_eq!("8080", info.env_map().unwrap()["PORT"]);
+    /// ```
+    pub fn env_map(&self) -> ProcCtlResult<HashMap<String, String>> {
+        self.ensure_env_collected()?;
+        Ok(self
+            .env
+            .iter()
+            .filter_map(|var| split_env_var(var))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect())
+    }
+
+    /// The value of the environment variable `name`, or `None` if the process does not have it.
+    ///
+    /// Fails with [ProcCtlError::EnvNotCollected] if the environment was not collected, rather than giving `None`.
+    pub fn env_var(&self, name: &str) -> ProcCtlResult<Option<&str>> {
+        self.ensure_env_collected()?;
+        Ok(self
+            .env
+            .iter()
+            .rev()
+            .filter_map(|var| split_env_var(var))
+            .find(|(var_name, _)| *var_name == name)
+            .map(|(_, value)| value))
+    }
+
+    fn ensure_env_collected(&self) -> ProcCtlResult<()> {
+        if self.env_collected {
+            Ok(())
+        } else {
+            Err(ProcCtlError::EnvNotCollected(self.pid))
+        }
+    }
+
+    /// Open a pidfd for this process, which keeps referring to it even after its pid is reused.
+    ///
+    /// The process is identified by its pid and [ProcInfo::start_time]. Once the pidfd is open the process with the pid
+    /// is checked to have the same start time, so the pidfd can't refer to a process which reused the pid after this
+    /// information was collected. Fails with [ProcCtlError::ProcessExited] if this process has exited, and with
+    /// [ProcCtlError::ConfigurationError] on kernels older than Linux 5.3, which don't support pidfds.
+    ///
+    /// The pidfd can be given to [ProcQuery::process_fd] or [crate::PortQuery::process_fd], or to other tools which
+    /// use pidfds.
+    #[cfg(target_os = "linux")]
+    pub fn pidfd(&self) -> ProcCtlResult<std::os::fd::OwnedFd> {
+        let pidfd = match crate::pidfd::open(self.pid) {
+            Ok(pidfd) => pidfd,
+            Err(ProcCtlError::ProcessNotFound(pid)) => {
+                return Err(ProcCtlError::ProcessExited(pid))
+            }
+            Err(e) => return Err(e),
+        };
+
+        let sys_pid = to_sysinfo(self.pid)?;
+        let mut sys_handle = sys_handle();
+        sys_handle.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[sys_pid]),
+            true,
+            ProcessRefreshKind::new(),
+        );
+        let same_process = sys_handle
+            .process(sys_pid)
+            .is_some_and(|p| p.start_time() == self.start_time);
+        drop(sys_handle);
+
+        if !same_process || crate::pidfd::has_exited(&pidfd) {
+            return Err(ProcCtlError::ProcessExited(self.pid));
+        }
+
+        Ok(pidfd)
+    }
+}
+
+/// Split an environment variable into its name and value.
+///
+/// The name is everything before the first `=` after its first character, since Windows gives each process hidden
+/// variables whose names start with `=`, such as `=C:=C:\work`.
+fn split_env_var(var: &str) -> Option<(&str, &str)> {
+    let split = var.get(1..)?.find('=')? + 1;
+    Some((&var[..split], &var[split + 1..]))
+}
+
+/// Builds a [ProcInfo], see [ProcInfo::builder].
+///
+/// Fields which are not set are empty, `None` or zero, except that the environment is treated as collected. A process
+/// with an empty environment is a valid fixture, use [ProcInfoBuilder::env_collected] for one whose environment was not
+/// collected.
+#[derive(Debug, Clone)]
+pub struct ProcInfoBuilder {
+    name: String,
+    cmd: Vec<String>,
+    argv0: Option<String>,
+    exe: Option<PathBuf>,
+    pid: Pid,
+    parent: Option<Pid>,
+    env: Vec<String>,
+    env_collected: bool,
+    cwd: Option<PathBuf>,
+    start_time: u64,
+    net_ns: Option<u64>,
+    pid_ns: Option<u64>,
+    container_id: Option<String>,
+    capabilities: Option<crate::capabilities::Capabilities>,
+    mnt_ns: Option<u64>,
+    root: Option<PathBuf>,
+    limits: Option<crate::limits::ProcLimits>,
+}
+
+impl Default for ProcInfoBuilder {
+    fn default() -> Self {
+        ProcInfoBuilder {
+            name: String::new(),
+            cmd: Vec::new(),
+            argv0: None,
+            exe: None,
+            pid: 0,
+            parent: None,
+            env: Vec::new(),
+            env_collected: true,
+            cwd: None,
+            start_time: 0,
+            net_ns: None,
+            pid_ns: None,
+            container_id: None,
+            capabilities: None,
+            mnt_ns: None,
+            root: None,
+            limits: None,
+        }
+    }
+}
+
+impl ProcInfoBuilder {
+    /// Set [ProcInfo::name]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set [ProcInfo::cmd]. Unless [ProcInfoBuilder::argv0] is also set, [ProcInfo::argv0] is the first element.
+    pub fn cmd(mut self, cmd: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.cmd = cmd.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set [ProcInfo::argv0]
+    pub fn argv0(mut self, argv0: impl Into<String>) -> Self {
+        self.argv0 = Some(argv0.into());
+        self
+    }
+
+    /// Set [ProcInfo::exe]
+    pub fn exe(mut self, exe: impl Into<PathBuf>) -> Self {
+        self.exe = Some(exe.into());
+        self
+    }
+
+    /// Set [ProcInfo::pid]
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Set [ProcInfo::parent]
+    pub fn parent(mut self, parent: Pid) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    /// Set [ProcInfo::env], with each variable as `NAME=value`
+    pub fn env(mut self, env: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.env = env.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set [ProcInfo::env_collected]
+    pub fn env_collected(mut self, collected: bool) -> Self {
+        self.env_collected = collected;
+        self
+    }
+
+    /// Set [ProcInfo::cwd]
+    pub fn cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Set [ProcInfo::start_time]
+    pub fn start_time(mut self, start_time: u64) -> Self {
+        self.start_time = start_time;
+        self
+    }
+
+    /// Set [ProcInfo::net_ns]
+    pub fn net_ns(mut self, net_ns: u64) -> Self {
+        self.net_ns = Some(net_ns);
+        self
+    }
+
+    /// Set [ProcInfo::pid_ns]
+    pub fn pid_ns(mut self, pid_ns: u64) -> Self {
+        self.pid_ns = Some(pid_ns);
+        self
+    }
+
+    /// Set [ProcInfo::container_id]
+    pub fn container_id(mut self, container_id: impl Into<String>) -> Self {
+        self.container_id = Some(container_id.into());
+        self
+    }
+
+    /// Set [ProcInfo::capabilities]
+    pub fn capabilities(mut self, capabilities: crate::capabilities::Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Set [ProcInfo::mnt_ns]
+    pub fn mnt_ns(mut self, mnt_ns: u64) -> Self {
+        self.mnt_ns = Some(mnt_ns);
+        self
+    }
+
+    /// Set [ProcInfo::root]
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Set [ProcInfo::limits]
+    pub fn limits(mut self, limits: crate::limits::ProcLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Create the [ProcInfo]
+    pub fn build(self) -> ProcInfo {
+        ProcInfo {
+            argv0: self.argv0.or_else(|| self.cmd.first().cloned()),
+            name: self.name,
+            cmd: self.cmd,
+            exe: self.exe,
+            pid: self.pid,
+            parent: self.parent,
+            env: self.env,
+            env_collected: self.env_collected,
+            cwd: self.cwd,
+            start_time: self.start_time,
+            net_ns: self.net_ns,
+            pid_ns: self.pid_ns,
+            container_id: self.container_id,
+            capabilities: self.capabilities,
+            mnt_ns: self.mnt_ns,
+            root: self.root,
+            limits: self.limits,
+        }
+    }
+}
+
+/// Which property of a process is compared against the names given to a [ProcQuery]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MatchField {
+    /// The process name, [ProcInfo::name].
+    ///
+    /// On Linux this is the kernel's short name for the process (`comm`), which is truncated to 15 characters and
+    /// is only read the first time a process is seen. On Windows it includes the `.exe` extension.
+    #[default]
+    Name,
+    /// On Linux, the current value of `/proc/<pid>/comm`, which a process can change after it starts. Other platforms
+    /// do not have a separate short name, so this behaves like [MatchField::Name].
+    Comm,
+    /// The first element of the command line, [ProcInfo::argv0]. This matches either the full value or its file name.
+    Argv0,
+    /// The file name of the executable, [ProcInfo::exe]. This is not affected by a process rewriting its command line.
+    Exe,
+}
+
+/// The result of [ProcQuery::list_processes_report]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProcReport {
+    /// The processes which matched
+    pub matches: Vec<ProcInfo>,
+    /// The processes which did not match and why. Only populated when [ProcQuery::explain] is enabled.
+    pub skipped: Vec<SkippedProcess>,
+}
+
+impl ProcReport {
+    /// Create a report with the given matches and nothing skipped, for use as a test fixture
+    pub fn new(matches: Vec<ProcInfo>) -> Self {
+        ProcReport {
+            matches,
+            skipped: Vec::new(),
+        }
+    }
+}
+
+/// A process which was not included in the results of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SkippedProcess {
+    /// The process ID
+    pub pid: Pid,
+    /// Why the process was skipped
+    pub reason: SkipReason,
+}
+
+/// Why a process was not included in the results of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SkipReason {
+    /// The field a filter needed could not be read because access was denied, usually because the process belongs
+    /// to another user or is setuid. For [FilterKind::ParentName], this is the parent's field.
+    PermissionDenied(MatchField),
+    /// The field a filter needed was not available for another reason, such as a kernel thread having no executable
+    /// or an exiting process
+    FieldUnavailable(MatchField),
+    /// The process was inspected and did not match this filter
+    FilteredBy(FilterKind),
+    /// The executable of the process has been deleted or replaced since it started, so a filter on the executable
+    /// file can't check it. Only found on Linux.
+    ExeDeleted,
+    /// The executable file of the process could not be read for a filter on it, for a reason other than access
+    /// being denied
+    ExeUnreadable,
+}
+
+/// One of the filters of a [ProcQuery]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FilterKind {
+    /// [ProcQuery::process_id]
+    ProcessId,
+    /// [ProcQuery::process_name]
+    Name,
+    /// [ProcQuery::parent_name], including when the process has no parent
+    ParentName,
+    /// [ProcQuery::in_container] or [ProcQuery::container_id_prefix]
+    Container,
+    /// [ProcQuery::has_capability]
+    Capability,
+    /// [ProcQuery::nofile_at_least]
+    Limits,
+    /// [ProcQuery::exe_matches], [ProcQuery::exe_file_size] or [ProcQuery::exe_sha256]
+    Exe,
+    /// [ProcQuery::from_sysinfo_filter]
+    Sysinfo,
+}
+
+/// The number of children listed in a [ChildrenShortfall], to keep the error a manageable size
+const MAX_REPORTED_CHILDREN: usize = 20;
+
+/// The details of a [ProcCtlError::TooFewChildren] failure, taken from the same refresh of the process list as the
+/// count of children
+#[derive(Debug)]
+pub struct ChildrenShortfall {
+    /// The number of children required by [ProcQuery::expect_min_num_children]
+    pub expected: usize,
+    /// The number of children found
+    pub found_count: usize,
+    /// The children found, up to the first 20
+    pub found: Vec<ProcInfo>,
+    /// The parent process, when exactly one process was selected as the parent and it is still listed
+    pub parent: Option<ProcInfo>,
+    /// The state of the parent as reported by the operating system, such as `Zombie` for a process which has exited
+    /// but not been waited on
+    pub parent_status: Option<String>,
+}
+
+impl std::fmt::Display for ChildrenShortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "too few children, got {} but expected {}",
+            self.found_count, self.expected
+        )?;
+
+        match &self.parent {
+            Some(parent) => write!(
+                f,
+                "\n  parent: {} (pid {}, {}): {}",
+                parent.name,
+                parent.pid,
+                self.parent_status.as_deref().unwrap_or("unknown state"),
+                parent.cmd.join(" ")
+            )?,
+            None => write!(f, "\n  parent: not found")?,
+        }
+
+        for child in &self.found {
+            write!(f, "\n  child: {} (pid {})", child.name, child.pid)?;
+        }
+        if self.found_count > self.found.len() {
+            write!(
+                f,
+                "\n  ... and {} more",
+                self.found_count - self.found.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Identifies a process by something which stays the same when it is restarted, rather than by its pid
+#[derive(Debug, Clone)]
+pub enum ProcSelector {
+    /// Processes running the executable at this path
+    ExePath(PathBuf),
+    /// Processes with this name, see [MatchField::Name]
+    Name(String),
+    /// Processes whose command line, with arguments joined by a space, contains this string
+    CmdContains(String),
+}
+
+impl ProcSelector {
+    /// Find the pids of every running process which matches, in ascending order
+    pub(crate) fn resolve(&self) -> Vec<Pid> {
+        self.resolve_with(sys_handle())
+    }
+
+    /// Like [ProcSelector::resolve], but fails with [ProcCtlError::WouldBlock] if the process list is in use
+    pub(crate) fn try_resolve(&self) -> ProcCtlResult<Vec<Pid>> {
+        Ok(self.resolve_with(try_sys_handle()?))
+    }
+
+    fn resolve_with(&self, mut sys_handle: MutexGuard<System>) -> Vec<Pid> {
+        sys_handle.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::new()
+                .with_exe(UpdateKind::OnlyIfNotSet)
+                .with_cmd(UpdateKind::OnlyIfNotSet),
+        );
+
+        let mut pids = sys_handle
+            .processes()
+            .values()
+            .filter(|p| !is_thread(p))
+            .filter(|p| self.matches(p))
+            .map(|p| from_sysinfo(p.pid()))
+            .collect::<Vec<_>>();
+        pids.sort_unstable();
+
+        pids
+    }
+
+    fn matches(&self, p: &Process) -> bool {
+        match self {
+            ProcSelector::ExePath(path) => p.exe() == Some(path.as_path()),
+            ProcSelector::Name(name) => p.name().to_string_lossy() == normalize_name(name),
+            ProcSelector::CmdContains(s) => normalized_cmd(p.cmd(), false)
+                .collect::<Vec<_>>()
+                .join(" ")
+                .contains(s.as_str()),
+        }
+    }
+}
+
+/// Find the pids of the children of a process, their children and so on, in ascending order. The process list is
+/// read once for the whole tree. On Linux, [crate::PortQuery] reads these from `/proc` instead.
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub(crate) fn descendant_pids(pid: Pid, wait: bool) -> ProcCtlResult<Vec<Pid>> {
+    let parent = to_sysinfo(pid)?;
+    let mut sys_handle = if wait {
+        sys_handle()
+    } else {
+        try_sys_handle()?
+    };
+    sys_handle.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::new());
+
+    let processes = sys_handle.processes();
+    if !processes.contains_key(&parent) {
+        return Err(ProcCtlError::ProcessNotFound(pid));
+    }
+
+    // A pid which was reused can appear to be its own ancestor, so each process is only visited once
+    let mut pids = Vec::new();
+    let mut visited = std::collections::HashSet::from([parent]);
+    let mut parents = vec![parent];
+    while let Some(parent) = parents.pop() {
+        for child in processes.values().filter(|p| p.parent() == Some(parent)) {
+            if visited.insert(child.pid()) {
+                pids.push(from_sysinfo(child.pid()));
+                parents.push(child.pid());
+            }
+        }
+    }
+    pids.sort_unstable();
+
+    Ok(pids)
+}
+
+/// Get information about a process
+#[derive(Debug)]
+pub struct ProcQuery {
+    process_id: Option<Pid>,
+    #[cfg(target_os = "linux")]
+    process_fd: Option<crate::pidfd::PidFd>,
+    name: Option<String>,
+    parent_name: Option<String>,
+    match_field: MatchField,
+    match_canonical_names: bool,
+    refresh_cmd: bool,
+    keep_empty_args: bool,
+    explain: bool,
+    min_num_children: Option<usize>,
+    last_observed: Mutex<Option<Observed<Vec<ProcInfo>>>>,
+    with_env: bool,
+    with_namespaces: bool,
+    in_container: Option<bool>,
+    container_id_prefix: Option<String>,
+    with_capabilities: bool,
+    required_capabilities: Vec<String>,
+    with_limits: bool,
+    min_open_files: Option<u64>,
+    expected_open_files: Option<u64>,
+    exe_filters: Vec<crate::exe_filter::ExeFilter>,
+    sysinfo_filter: Option<SysinfoFilter>,
+    paths_relative_to_proc_root: bool,
+    oneshot: bool,
+    clock: Arc<dyn Clock>,
+}
+
+/// A predicate on a sysinfo process, as given to [ProcQuery::from_sysinfo_filter]
+struct SysinfoFilter(
+    Arc<dyn Fn(&Process) -> bool + Send + Sync + std::panic::RefUnwindSafe + 'static>,
+);
+
+impl std::fmt::Debug for SysinfoFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SysinfoFilter(..)")
+    }
+}
+
+impl ProcQuery {
+    /// Create a new process query
+    pub fn new() -> Self {
+        ProcQuery {
+            process_id: None,
+            #[cfg(target_os = "linux")]
+            process_fd: None,
+            name: None,
+            parent_name: None,
+            match_field: MatchField::Name,
+            match_canonical_names: false,
+            refresh_cmd: false,
+            keep_empty_args: false,
+            explain: false,
+            min_num_children: None,
+            last_observed: Mutex::new(None),
+            with_env: true,
+            with_namespaces: false,
+            in_container: None,
+            container_id_prefix: None,
+            with_capabilities: false,
+            required_capabilities: Vec::new(),
+            with_limits: false,
+            min_open_files: None,
+            expected_open_files: None,
+            exe_filters: Vec::new(),
+            sysinfo_filter: None,
+            paths_relative_to_proc_root: false,
+            oneshot: false,
+            clock: crate::clock::system(),
+        }
+    }
+
+    /// Create a query which reads the processes it needs each time it runs, rather than using the process list shared
+    /// by every other query.
+    ///
+    /// The shared list is filled with every process the first time any query uses it, and after that each query only
+    /// refreshes it, which makes later queries cheaper. A oneshot query skips that first fill and never keeps what it
+    /// reads, so it suits a program which runs a single query, such as a build script checking on one process. When
+    /// the query selects a process with [ProcQuery::process_id] and has no [ProcQuery::parent_name], only that
+    /// process is read. Otherwise every process is read on each run, so a query which runs many times is better off
+    /// with [ProcQuery::new].
+    ///
+    /// ```rust
+    /// let me = proc_ctl::ProcQuery::oneshot()
+    ///     .process_id(std::process::id())
+    ///     .list_processes()
+    ///     .unwrap();
+    /// assert_eq!(1, me.len());
+    /// ```
+    pub fn oneshot() -> Self {
+        let mut query = ProcQuery::new();
+        query.oneshot = true;
+        query
+    }
+
+    /// Create a query which matches the processes accepted by `predicate`, so that a predicate written against
+    /// [sysinfo::Process] can be reused while moving to this crate.
+    ///
+    /// The predicate sees each process as listed by sysinfo, refreshed as this query would refresh it, so its
+    /// environment is empty when [ProcQuery::with_env] is disabled. It is checked after every other filter, and like
+    /// [ProcQuery::exe_matches] it only selects processes, so it does not apply to their children. Other filters can
+    /// be added to the query as usual, but a predicate can't be written in config so [ProcQuery::to_config] leaves it
+    /// out.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::ProcQuery;
+    ///
+    /// let query = ProcQuery::from_sysinfo_filter(|p| p.name() == "server" && p.parent().is_some());
+    /// let processes = query.list_processes().unwrap();
+    /// ```
+    pub fn from_sysinfo_filter(
+        predicate: impl Fn(&Process) -> bool + Send + Sync + std::panic::RefUnwindSafe + 'static,
+    ) -> Self {
+        let mut query = ProcQuery::new();
+        query.sysinfo_filter = Some(SysinfoFilter(Arc::new(predicate)));
+        query
+    }
+
+    /// Create a query from its definition in config.
+    ///
+    /// The retry policy of the config is not part of the query, so pass it to a retry function when the query is
+    /// executed.
+    #[cfg(feature = "serde")]
+    pub fn from_config(config: &crate::config::ProcQueryConfig) -> Self {
+        let mut query = ProcQuery::new()
+            .match_on(config.match_on)
+            .match_canonical_names(config.match_canonical_names)
+            .refresh_cmd(config.refresh_cmd)
+            .keep_empty_args(config.keep_empty_args)
+            .explain(config.explain)
+            .with_env(config.with_env.unwrap_or(true))
+            .with_namespaces(config.with_namespaces);
+        query.process_id = config.process_id;
+        query.name = config.process_name.as_deref().map(normalize_name);
+        query.parent_name = config.parent_name.as_deref().map(normalize_name);
+        query.in_container = config.in_container;
+        query.container_id_prefix = config
+            .container_id_prefix
+            .as_deref()
+            .map(str::to_ascii_lowercase);
+        query.with_capabilities = config.with_capabilities;
+        query.required_capabilities = config.has_capability.clone();
+        query.with_limits = config.with_limits;
+        query.min_open_files = config.nofile_at_least;
+        query.expected_open_files = config.expect_nofile_at_least;
+        query.paths_relative_to_proc_root = config.paths_relative_to_proc_root;
+        query.min_num_children = config.expect_min_num_children;
+
+        query
+    }
+
+    /// The definition of this query, as it would be written in config.
+    ///
+    /// Only what can be written in config is included, so a clock, pidfd or predicate set on the query is left out, and
+    /// so is the retry policy. Names are given as they are matched, which on
+    /// Windows includes the `.exe` extension.
+    #[cfg(feature = "serde")]
+    pub fn to_config(&self) -> crate::config::ProcQueryConfig {
+        crate::config::ProcQueryConfig {
+            process_id: self.process_id,
+            process_name: self.name.clone(),
+            parent_name: self.parent_name.clone(),
+            match_on: self.match_field,
+            match_canonical_names: self.match_canonical_names,
+            refresh_cmd: self.refresh_cmd,
+            keep_empty_args: self.keep_empty_args,
+            explain: self.explain,
+            with_env: (!self.with_env).then_some(false),
+            with_namespaces: self.with_namespaces,
+            in_container: self.in_container,
+            container_id_prefix: self.container_id_prefix.clone(),
+            with_capabilities: self.with_capabilities,
+            has_capability: self.required_capabilities.clone(),
+            with_limits: self.with_limits,
+            nofile_at_least: self.min_open_files,
+            expect_nofile_at_least: self.expected_open_files,
+            paths_relative_to_proc_root: self.paths_relative_to_proc_root,
+            expect_min_num_children: self.min_num_children,
+            retry: None,
+        }
+    }
+
+    /// Set the process ID to match
+    ///
+    /// One of this, [ProcQuery::process_name] or [ProcQuery::process_id_from_child] must be called before the query is usable.
+    pub fn process_id(mut self, pid: Pid) -> Self {
+        self.process_id = Some(pid);
+        self
+    }
+
+    /// Select the process by a pidfd, such as one from [ProcInfo::pidfd], taking ownership of it.
+    ///
+    /// The pid is read from the pidfd when the query is executed. A process only matches while the pidfd shows that
+    /// it is still running, so once it exits another process which reuses its pid never matches in its place. This
+    /// makes [ProcQuery::wait_for_exit] reliable even if the pid is reused straight away. Reading the pid of a pidfd
+    /// needs Linux 5.4 or later, and nothing matches on older kernels.
+    #[cfg(target_os = "linux")]
+    pub fn process_fd(mut self, pidfd: std::os::fd::OwnedFd) -> Self {
+        self.process_fd = Some(crate::pidfd::PidFd::new(pidfd));
+        self
+    }
+
+    /// Select the main process of a systemd unit, such as `myapp.service`, see [crate::systemd_unit_pid].
+    ///
+    /// The main pid is looked up once, when this is called, so create the query again after the unit restarts. Fails
+    /// if systemd is not running or the unit has no main process running.
+    #[cfg(target_os = "linux")]
+    pub fn systemd_unit(self, unit: &str) -> ProcCtlResult<Self> {
+        Ok(self.process_id(crate::service::systemd_unit_pid(unit)?))
+    }
+
+    /// Select the process of a Windows service by its service name, such as `MyAppSvc`, see
+    /// [crate::windows_service_pid].
+    ///
+    /// The pid is looked up once, when this is called, so create the query again after the service restarts. Fails if
+    /// there is no such service or it is not running.
+    #[cfg(target_os = "windows")]
+    pub fn windows_service(self, name: &str) -> ProcCtlResult<Self> {
+        Ok(self.process_id(crate::service::windows_service_pid(name)?))
+    }
+
+    /// Set the process name to match
+    ///
+    /// One of this, [ProcQuery::process_id] or [ProcQuery::process_id_from_child] must be called before the query is usable.
+    ///
+    /// When finding children, this filters the children by name rather than selecting the parent.
+    pub fn process_name(mut self, name: impl AsRef<str>) -> Self {
+        self.name = Some(normalize_name(name));
+        self
+    }
+
+    /// Set the name of the parent process to match
+    ///
+    /// When listing processes, only processes whose parent has this name are matched. When finding children, every
+    /// process with this name is treated as a parent and their children are combined. This can be used instead of
+    /// [ProcQuery::process_id] to select the parent, or together with it to require the parent has this name.
+    pub fn parent_name(mut self, name: impl AsRef<str>) -> Self {
+        self.parent_name = Some(normalize_name(name));
+        self
+    }
+
+    /// Choose which property of a process [ProcQuery::process_name] and [ProcQuery::parent_name] are compared against
+    ///
+    /// Defaults to [MatchField::Name]. Daemons which rewrite their command line to show their status are easiest to
+    /// find reliably with [MatchField::Exe].
+    pub fn match_on(mut self, field: MatchField) -> Self {
+        self.match_field = field;
+        self
+    }
+
+    /// Compare [ProcQuery::process_name] and [ProcQuery::parent_name] against [ProcInfo::canonical_name], after making
+    /// the names to match canonical in the same way.
+    ///
+    /// This takes the place of [ProcQuery::match_on], so that `server`, `server.exe` and `/opt/app/server` all match
+    /// the same processes on every platform. Off by default.
+    pub fn match_canonical_names(mut self, canonical: bool) -> Self {
+        self.match_canonical_names = canonical;
+        self
+    }
+
+    /// Re-read the command line of each process on every query
+    ///
+    /// By default, the command line is read once when a process is first seen. Enable this to see the current value
+    /// for processes which rewrite their command line, at the cost of reading it again for every process.
+    pub fn refresh_cmd(mut self, refresh: bool) -> Self {
+        self.refresh_cmd = refresh;
+        self
+    }
+
+    /// Keep empty arguments between the other arguments of [ProcInfo::cmd], which are left out by default.
+    ///
+    /// An empty argument is usually left by a launcher which padded or scrubbed its command line, but can be meaningful,
+    /// such as the empty script of `sh -c ""`. Empty arguments at the end of the command line are left out either way.
+    pub fn keep_empty_args(mut self, keep: bool) -> Self {
+        self.keep_empty_args = keep;
+        self
+    }
+
+    /// Record why each process which did not match was skipped, in [ProcQuery::list_processes_report]
+    ///
+    /// This tells apart processes which were inspected and filtered out from processes which could not be inspected,
+    /// for example because a filter needs the executable of a process belonging to another user.
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    /// Collect [ProcInfo::env] for each process, which is enabled by default.
+    ///
+    /// Reading the environment of every process is a large part of the cost of listing them, so disable this when it
+    /// is not needed. [ProcInfo::env_collected] is then `false`, and [ProcInfo::env_var] and [ProcInfo::env_map] fail
+    /// with [ProcCtlError::EnvNotCollected] rather than reporting an empty environment. Code which reads the
+    /// environment should enable this explicitly, since a future release may collect it only on request.
+    pub fn with_env(mut self, with: bool) -> Self {
+        self.with_env = with;
+        self
+    }
+
+    /// Collect [ProcInfo::net_ns], [ProcInfo::pid_ns], [ProcInfo::mnt_ns], [ProcInfo::root] and
+    /// [ProcInfo::container_id] for each process.
+    ///
+    /// These are only available on Linux, and reading them costs a few extra reads of `/proc` for each process, so
+    /// they are not collected by default.
+    pub fn with_namespaces(mut self, with: bool) -> Self {
+        self.with_namespaces = with;
+        self
+    }
+
+    /// Only match processes which are, or are not, running in a container. See [ProcInfo::container_id] for how
+    /// containers are recognised.
+    ///
+    /// On platforms other than Linux no process is considered to be in a container.
+    pub fn in_container(mut self, in_container: bool) -> Self {
+        self.in_container = Some(in_container);
+        self
+    }
+
+    /// Only match processes running in a container whose ID starts with `prefix`, such as the short ID shown by
+    /// `docker ps`
+    pub fn container_id_prefix(mut self, prefix: impl AsRef<str>) -> Self {
+        self.container_id_prefix = Some(prefix.as_ref().to_ascii_lowercase());
+        self
+    }
+
+    /// Collect [ProcInfo::capabilities] for each process.
+    ///
+    /// These are only available on Linux, where they cost an extra read of `/proc` for each process, so they are not
+    /// collected by default.
+    pub fn with_capabilities(mut self, with: bool) -> Self {
+        self.with_capabilities = with;
+        self
+    }
+
+    /// Only match processes which have the capability called `name` in their effective set, such as
+    /// `CAP_NET_BIND_SERVICE` for binding ports below 1024. Call this more than once to require several capabilities.
+    ///
+    /// The name is not case sensitive and the `CAP_` prefix is optional. A name which is not known never matches. On
+    /// platforms other than Linux no process has any capabilities, so nothing matches.
+    pub fn has_capability(mut self, name: impl AsRef<str>) -> Self {
+        self.required_capabilities.push(name.as_ref().to_string());
+        self
+    }
+
+    /// Collect [ProcInfo::limits] for each process.
+    ///
+    /// These are only available on Linux, where they cost an extra read of `/proc` for each process, so they are not
+    /// collected by default.
+    pub fn with_limits(mut self, with: bool) -> Self {
+        self.with_limits = with;
+        self
+    }
+
+    /// Only match processes whose soft limit on open files is unlimited or at least `num_files`.
+    ///
+    /// A process whose limits can't be read never matches, which on platforms other than Linux is every process.
+    pub fn nofile_at_least(mut self, num_files: u64) -> Self {
+        self.min_open_files = Some(num_files);
+        self
+    }
+
+    /// Require every matched process to have a soft limit on open files of at least `num_files`, failing with
+    /// [ProcCtlError::LimitTooLow] otherwise. This also collects [ProcInfo::limits].
+    ///
+    /// Unlike [ProcQuery::nofile_at_least] a process with a lower limit is an error rather than skipped, which suits
+    /// tests that check their environment before they start, so that a fixture which can't open enough files fails
+    /// the suite straight away with its limit rather than part way through. It is checked by
+    /// [ProcQuery::list_processes], [ProcQuery::list_processes_into] and [ProcQuery::try_list_processes_nonblocking].
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::ProcQuery;
+    ///
+    /// ProcQuery::new()
+    ///     .process_name("server")
+    ///     .expect_nofile_at_least(4096)
+    ///     .list_processes()
+    ///     .expect("the server can't open enough files");
+    /// ```
+    pub fn expect_nofile_at_least(mut self, num_files: u64) -> Self {
+        self.expected_open_files = Some(num_files);
+        self
+    }
+
+    /// Only match processes whose executable file passes `predicate`, which is given its path. Call this, or the
+    /// other filters on the executable, more than once to require them all.
+    ///
+    /// This tells apart builds which share a name, such as two versions of a binary installed side by side. The path
+    /// is read from the process on every query, and a process whose executable can't be found, or on Linux has been
+    /// deleted or replaced since it started, never matches. [ProcQuery::explain] records why such processes were
+    /// skipped.
+    ///
+    /// ```rust no_run
+    /// use proc_ctl::ProcQuery;
+    ///
+    /// let query = ProcQuery::new()
+    ///     .process_name("server")
+    ///     .exe_matches(|exe| exe.starts_with("/opt/server/2.1"));
+    /// ```
+    pub fn exe_matches(
+        mut self,
+        predicate: impl Fn(&std::path::Path) -> bool + Send + Sync + std::panic::RefUnwindSafe + 'static,
+    ) -> Self {
+        self.exe_filters
+            .push(crate::exe_filter::ExeFilter::Matches(Arc::new(predicate)));
+        self
+    }
+
+    /// Only match processes whose executable file is exactly `bytes` long. See [ProcQuery::exe_matches] for which
+    /// processes can be checked, and a process whose executable can't be read never matches.
+    pub fn exe_file_size(mut self, bytes: u64) -> Self {
+        self.exe_filters
+            .push(crate::exe_filter::ExeFilter::FileSize(bytes));
+        self
+    }
+
+    /// Only match processes running the exact build whose SHA-256 is `hex`, as printed by `sha256sum` or
+    /// [crate::file_sha256]. Case does not matter. See [ProcQuery::exe_matches] for which processes can be checked,
+    /// and a process whose executable can't be read never matches.
+    ///
+    /// Each executable is hashed once and the hash kept by the query, until the size or modification time of the file
+    /// changes. Only available with the `exe-hash` feature.
+    #[cfg(feature = "exe-hash")]
+    pub fn exe_sha256(mut self, hex: impl AsRef<str>) -> Self {
+        self.exe_filters.push(crate::exe_filter::ExeFilter::Sha256(
+            hex.as_ref().to_ascii_lowercase(),
+            Arc::default(),
+        ));
+        self
+    }
+
+    /// Give [ProcInfo::exe] and [ProcInfo::cwd] as they are seen by each process from its own root directory, rather
+    /// than from the root of the process running the query. For a process chrooted to `/srv/jail` running
+    /// `/srv/jail/bin/sh`, the exe is then `/bin/sh`. This also collects [ProcInfo::root].
+    ///
+    /// Only Linux has per-process roots. Where the root can't be read, because it belongs to another user and the
+    /// query lacks the privileges to trace it, the paths are left as they are and [ProcInfo::root] is `None`.
+    pub fn paths_relative_to_proc_root(mut self, relative: bool) -> Self {
+        self.paths_relative_to_proc_root = relative;
+        self
+    }
+
+    /// Get the process ID of a child process
+    ///
+    /// Either this function or `process_id` are required to be called before the query is usable.
+    pub fn process_id_from_child(self, child: &Child) -> Self {
+        self.process_id(child.id())
+    }
+
+    /// Require at least `num_children` children to have been started by the matched process for the query to succeed.
+    pub fn expect_min_num_children(mut self, num_children: usize) -> Self {
+        self.min_num_children = Some(num_children);
+        self
+    }
+
+    /// Use `clock` rather than the real time for the delays and timeouts of retries, waits and watchers started from
+    /// this query, such as a [crate::ManualClock] in tests. The query itself always runs against the real system.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    #[cfg(any(test, feature = "async", feature = "resilience", feature = "test-util"))]
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Whether this query selects a fixed process, by pid or pidfd, rather than whatever matches its filters
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub(crate) fn selects_pid(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.process_fd.is_some() {
+            return true;
+        }
+
+        self.process_id.is_some()
+    }
+
+    /// Describe how the processes are selected, for errors
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub(crate) fn describe_selection(&self) -> String {
+        match (&self.name, &self.parent_name) {
+            (Some(name), _) => format!("name {}", name),
+            (None, Some(parent_name)) => format!("parent name {}", parent_name),
+            (None, None) => "the process query".to_string(),
+        }
+    }
+
+    /// List all processes matching the current filters.
+    pub fn list_processes(&self) -> ProcCtlResult<Vec<ProcInfo>> {
+        let mut processes = Vec::new();
+        self.list_processes_into(&mut processes)?;
+        Ok(processes)
+    }
+
+    /// List all processes matching the current filters like [ProcQuery::list_processes], filling `processes` rather
+    /// than returning a new list.
+    ///
+    /// The entries already in `processes` are overwritten in place, and the strings and lists inside them keep their
+    /// capacity, so a loop which lists processes many times allocates little once the buffer has grown to fit. The
+    /// processes are the same, in the same order, as [ProcQuery::list_processes] would return.
+    pub fn list_processes_into(&self, processes: &mut Vec<ProcInfo>) -> ProcCtlResult<()> {
+        self.list_processes_with(self.sys_handle(), processes);
+        self.check_expected_limits(processes)
+    }
+
+    /// Like [ProcQuery::list_processes], but fails straight away with [ProcCtlError::WouldBlock] rather than waiting
+    /// if another query is using the process list.
+    ///
+    /// The query itself still reads from the operating system, which is quick but not asynchronous. This is meant
+    /// for cleanup code, such as `Drop` implementations in async tests, which must not wait on other queries.
+    pub fn try_list_processes_nonblocking(&self) -> ProcCtlResult<Vec<ProcInfo>> {
+        let mut processes = Vec::new();
+        self.list_processes_with(self.try_sys_handle()?, &mut processes);
+        self.check_expected_limits(&processes)?;
+        Ok(processes)
+    }
+
+    /// Fail if any of `processes` has a lower limit than [ProcQuery::expect_nofile_at_least] requires
+    fn check_expected_limits(&self, processes: &[ProcInfo]) -> ProcCtlResult<()> {
+        let Some(required) = self.expected_open_files else {
+            return Ok(());
+        };
+
+        for process in processes {
+            let found = process.limits.map(|limits| limits.open_files);
+            if !found.is_some_and(|limit| limit.is_at_least(required)) {
+                return Err(ProcCtlError::LimitTooLow(Box::new(
+                    crate::limits::LimitShortfall {
+                        process: process.clone(),
+                        resource: crate::limits::OPEN_FILES.to_string(),
+                        required,
+                        found,
+                    },
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The most recent result of [ProcQuery::list_processes] or [ProcQuery::try_list_processes_nonblocking], if
+    /// either has succeeded. This never runs a query, so it never waits.
+    pub fn last_observed(&self) -> Option<Observed<Vec<ProcInfo>>> {
+        lock_observed(&self.last_observed).clone()
+    }
+
+    /// Refresh the process list and fill `infos` with the matching processes, reusing the entries already in it
+    fn list_processes_with(&self, mut sys_handle: SysHandle, infos: &mut Vec<ProcInfo>) {
+        self.refresh_selected(&mut sys_handle, self.refresh_kind());
+        let processes = sys_handle.processes();
+
+        let mut found = 0;
+        for p in processes
+            .values()
+            .filter(|p| !is_thread(p) && self.matches(p, processes))
+        {
+            match infos.get_mut(found) {
+                Some(info) => self.fill_info(p, info),
+                None => infos.push(self.info(p)),
+            }
+            found += 1;
+        }
+        infos.truncate(found);
+        drop(sys_handle);
+
+        Observed::record(&mut lock_observed(&self.last_observed), infos);
+    }
+
+    /// List all processes matching the current filters, along with the processes which were skipped if
+    /// [ProcQuery::explain] is enabled.
+    pub fn list_processes_report(&self) -> ProcCtlResult<ProcReport> {
+        let mut sys_handle = self.sys_handle();
+        self.refresh_selected(&mut sys_handle, self.refresh_kind());
+        let processes = sys_handle.processes();
+
+        let mut report = ProcReport {
+            matches: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for p in processes.values().filter(|p| !is_thread(p)) {
+            match self.check(p, processes) {
+                Ok(()) => report.matches.push(self.info(p)),
+                Err(reason) if self.explain => report.skipped.push(SkippedProcess {
+                    pid: from_sysinfo(p.pid()),
+                    reason,
+                }),
+                Err(_) => {}
+            }
+        }
+        report.skipped.sort_by_key(|s| s.pid);
+
+        Ok(report)
+    }
+
+    /// List all processes matching the current filters, writing each one to `writer` as it is serialized.
+    ///
+    /// The writer is flushed after each record, so the full list of processes is never held in memory.
+    #[cfg(feature = "serde")]
+    pub fn list_to_writer(
+        &self,
+        writer: &mut impl std::io::Write,
+        format: crate::export::ExportFormat,
+    ) -> ProcCtlResult<()> {
+        let mut records = crate::export::RecordWriter::new(writer, format);
+
+        let mut sys_handle = self.sys_handle();
+        self.refresh_selected(&mut sys_handle, self.refresh_kind());
+
+        let processes = sys_handle.processes();
+        for process in processes.values().filter(|p| !is_thread(p)) {
+            if self.matches(process, processes) {
+                records.write(&self.info(process))?;
+            }
+        }
+
+        records.finish::<ProcInfo>()
+    }
+
+    /// Find the children of the selected process
+    ///
+    /// If a [ProcQuery::parent_name] is set then the children of every matching parent are returned together, and
+    /// any expected number of children applies to the combined list.
+    pub fn children(&self) -> ProcCtlResult<Vec<ProcInfo>> {
+        if self.parent_name.is_none() {
+            resolve_pid(self)?;
+        }
+
+        let mut sys_handle = self.sys_handle();
+        self.refresh_for_children(&mut sys_handle);
+        let processes = sys_handle.processes();
+
+        let parents = processes
+            .values()
+            .filter(|p| !is_thread(p) && self.is_selected_parent(p))
+            .map(|p| p.pid())
+            .collect::<HashSet<_>>();
+
+        self.children_of(&parents, processes)
+    }
+
+    /// List the matching processes together with the children of each which match `children`, all from one refresh
+    /// of the process list, for [crate::Pipeline]. The children of each process succeed or fail alone.
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub(crate) fn list_with_children(
+        &self,
+        children: Option<&ProcQuery>,
+    ) -> ProcCtlResult<Vec<WithChildren>> {
+        let cmd_update_kind = match children {
+            Some(children) if children.cmd_update_kind() == UpdateKind::Always => {
+                UpdateKind::Always
+            }
+            _ => self.cmd_update_kind(),
+        };
+
+        let mut sys_handle = self.sys_handle();
+        sys_handle.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            self.refresh_kind().with_cmd(cmd_update_kind),
+        );
+        let processes = sys_handle.processes();
+
+        let mut found = processes
+            .values()
+            .filter(|p| !is_thread(p) && p.status() != sysinfo::ProcessStatus::Zombie)
+            .filter(|p| self.matches(p, processes))
+            .map(|p| {
+                let children =
+                    children.map(|c| c.children_of(&HashSet::from([p.pid()]), processes));
+                (self.info(p), children)
+            })
+            .collect::<Vec<_>>();
+        drop(sys_handle);
+        found.sort_by_key(|(info, _)| info.pid);
+
+        let infos = found
+            .iter()
+            .map(|(info, _)| info.clone())
+            .collect::<Vec<_>>();
+        self.check_expected_limits(&infos)?;
+
+        Ok(found)
+    }
+
+    /// Find the selected process together with its children.
+    ///
+    /// Both come from the same refresh of the process list, so the parent is never reported as gone while its
+    /// children are found, or the other way around. A process must be selected with [ProcQuery::process_id], and it
+    /// fails with [ProcCtlError::ProcessNotFound] if that process has exited or does not match the
+    /// [ProcQuery::parent_name]. The process name filter applies to the children, as for [ProcQuery::children].
+    pub fn children_with_self(&self) -> ProcCtlResult<(ProcInfo, Vec<ProcInfo>)> {
+        let pid = resolve_pid(self)?;
+        let sys_pid = to_sysinfo(pid)?;
+
+        let mut sys_handle = self.sys_handle();
+        self.refresh_for_children(&mut sys_handle);
+        let processes = sys_handle.processes();
+
+        let parent = processes
+            .get(&sys_pid)
+            .filter(|p| p.status() != sysinfo::ProcessStatus::Zombie)
+            .filter(|p| self.is_selected_parent(p))
+            .ok_or(ProcCtlError::ProcessNotFound(pid))?;
+        let children = self.children_of(&HashSet::from([sys_pid]), processes)?;
+
+        Ok((self.info(parent), children))
+    }
+
+    fn refresh_for_children(&self, sys_handle: &mut System) {
+        sys_handle.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::new()
+                .with_exe(UpdateKind::OnlyIfNotSet)
+                .with_cmd(self.cmd_update_kind()),
+        );
+    }
+
+    fn children_of(
+        &self,
+        parents: &HashSet<sysinfo::Pid>,
+        processes: &HashMap<sysinfo::Pid, Process>,
+    ) -> ProcCtlResult<Vec<ProcInfo>> {
+        // Processes are keyed by pid, so each child appears once even when several parents matched
+        #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+        let mut children: Vec<ProcInfo> = processes
+            .values()
+            .filter(|p| !is_thread(p))
+            .filter(|p| p.parent().is_some_and(|parent| parents.contains(&parent)))
+            .filter(|p| self.name_matches(p))
+            .map(|p| self.info(p))
+            .collect();
+
+        #[cfg(target_os = "windows")]
+        if self
+            .min_num_children
+            .is_some_and(|num| children.len() < num)
+        {
+            let links = crate::toolhelp::parent_links()?;
+            self.add_linked_children(&mut children, parents, processes, &links);
+        }
+
+        if let Some(num) = self.min_num_children {
+            if children.len() < num {
+                let parent = match parents.iter().collect::<Vec<_>>().as_slice() {
+                    [pid] => processes.get(pid),
+                    _ => None,
+                };
+
+                return Err(ProcCtlError::TooFewChildren(Box::new(ChildrenShortfall {
+                    expected: num,
+                    found_count: children.len(),
+                    parent: parent.map(|p| self.info(p)),
+                    parent_status: parent.map(|p| p.status().to_string()),
+                    found: children.into_iter().take(MAX_REPORTED_CHILDREN).collect(),
+                })));
+            }
+        }
+
+        Ok(children)
+    }
+
+    /// Add the children found from `links` which are missing from `children`, merging by pid.
+    ///
+    /// A child which sysinfo lists is only added if it started no earlier than its parent, since the link may be to
+    /// an earlier process which had the same pid. A child which sysinfo does not list yet started after the process
+    /// list was refreshed, and so after its parent, but only its name is known.
+    #[cfg(any(target_os = "windows", test))]
+    fn add_linked_children(
+        &self,
+        children: &mut Vec<ProcInfo>,
+        parents: &HashSet<sysinfo::Pid>,
+        processes: &HashMap<sysinfo::Pid, Process>,
+        links: &[ParentLink],
+    ) {
+        for link in links {
+            let (Ok(sys_pid), Ok(sys_parent)) = (to_sysinfo(link.pid), to_sysinfo(link.parent))
+            else {
+                continue;
+            };
+            if !parents.contains(&sys_parent) || children.iter().any(|c| c.pid == link.pid) {
+                continue;
+            }
+
+            match processes.get(&sys_pid) {
+                Some(p) => {
+                    let started_after_parent = processes
+                        .get(&sys_parent)
+                        .map_or(true, |parent| p.start_time() >= parent.start_time());
+                    if started_after_parent && self.name_matches(p) {
+                        let mut info = self.info(p);
+                        info.parent = Some(link.parent);
+                        children.push(info);
+                    }
+                }
+                None => {
+                    let name_matches = match (&self.name, self.match_field) {
+                        (None, _) => true,
+                        (Some(name), _) if self.match_canonical_names => {
+                            crate::names::canonical(&link.name) == crate::names::canonical(name)
+                        }
+                        (Some(name), MatchField::Name | MatchField::Comm | MatchField::Exe) => {
+                            link.name == *name
+                        }
+                        (Some(_), MatchField::Argv0) => false,
+                    };
+                    if name_matches {
+                        let mut info = ProcInfo::builder()
+                            .pid(link.pid)
+                            .parent(link.parent)
+                            .name(&link.name)
+                            .build();
+                        self.read_optional_fields(&mut info);
+                        children.push(info);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Watch for matching processes starting and exiting, running the query once per `interval`.
+    ///
+    /// See [crate::ProcEvents] for how changes are reported.
+    #[cfg(feature = "async")]
+    pub fn events(self, interval: std::time::Duration) -> crate::watch::ProcEvents {
+        crate::watch::ProcEvents::new(self, interval)
+    }
+
+    /// Wait until at least one process matches the query.
+    ///
+    /// Fails with [ProcCtlError::WaitTimedOut] if nothing matches when the timeout passes.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_match(
+        &self,
+        options: &crate::wait::WaitOptions,
+    ) -> ProcCtlResult<crate::wait::WaitOutcome<Vec<ProcInfo>>> {
+        crate::wait::wait_until(options, self.clock.as_ref(), || {
+            let result = self.list_processes();
+            let seen = crate::wait::describe(result.as_ref().map(|p| pids_of(p)));
+            (result.ok().filter(|p| !p.is_empty()), seen)
+        })
+        .await
+    }
+
+    /// Wait until no running process matches the query.
+    ///
+    /// A process which has exited but has not yet been waited on by its parent counts as exited.
+    /// Fails with [ProcCtlError::WaitTimedOut] if a process still matches when the timeout passes.
+    ///
+    /// Inside a tokio runtime on Linux and Windows, this waits for the matching processes to exit rather than checking
+    /// them every interval, so it finishes as soon as the last one exits. The query is run again then, in case another
+    /// matching process has started. See [crate::WaitStrategy] for how [crate::WaitOutcome::strategy] reports this.
+    /// Elsewhere, and for processes which can't be opened, such as those of other users on Windows, this polls.
+    #[cfg(feature = "async")]
+    pub async fn wait_for_exit(
+        &self,
+        options: &crate::wait::WaitOptions,
+    ) -> ProcCtlResult<crate::wait::WaitOutcome<()>> {
+        crate::wait::wait_until_woken(
+            options,
+            self.clock.as_ref(),
+            || {
+                let result = self.running_pids();
+                let seen = crate::wait::describe(result.as_ref());
+                match result {
+                    Ok(pids) if pids.is_empty() => (Some(()), seen, None),
+                    Ok(pids) => (None, seen, Some(pids)),
+                    Err(_) => (None, seen, None),
+                }
+            },
+            crate::exit_watch::all_exited,
+        )
+        .await
+    }
+
+    /// Execute the query until it succeeds, making at most `count` attempts with `delay` between them.
+    ///
+    /// In earlier releases `count` was the number of retries after the first attempt, so one more attempt was made
+    /// than requested. At least one attempt is always made.
+    #[cfg(feature = "resilience")]
+    pub fn children_with_retry_sync(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<ProcInfo>> {
+        crate::retrying::retry_sync(self.clock.as_ref(), delay, count, || self.children())
+    }
+
+    /// Async equivalent of `children_with_retry_sync`, with the same number of attempts
+    #[cfg(feature = "async")]
+    pub async fn children_with_retry(
+        &self,
+        delay: std::time::Duration,
+        count: usize,
+    ) -> ProcCtlResult<Vec<ProcInfo>> {
+        crate::retrying::retry_async(self.clock.as_ref(), delay, count, || self.children()).await
+    }
+
+    /// The pids of matching processes which are still running, leaving out any which have exited but not been reaped
+    #[cfg(feature = "async")]
+    fn running_pids(&self) -> ProcCtlResult<Vec<Pid>> {
+        let mut sys_handle = self.sys_handle();
+        self.refresh_selected(
+            &mut sys_handle,
+            ProcessRefreshKind::new()
+                .with_exe(UpdateKind::OnlyIfNotSet)
+                .with_cmd(self.cmd_update_kind()),
+        );
+        let processes = sys_handle.processes();
+
+        let mut pids = processes
+            .values()
+            .filter(|p| !is_thread(p) && p.status() != sysinfo::ProcessStatus::Zombie)
+            .filter(|p| self.matches(p, processes))
+            .map(|p| from_sysinfo(p.pid()))
+            .collect::<Vec<_>>();
+        pids.sort();
+
+        Ok(pids)
+    }
+
+    /// The process list this query reads, which is the shared one unless this is a [ProcQuery::oneshot] query
+    fn sys_handle(&self) -> SysHandle {
+        if self.oneshot {
+            SysHandle::Oneshot(Box::new(System::new()))
+        } else {
+            SysHandle::Shared(sys_handle())
+        }
+    }
+
+    /// Like [ProcQuery::sys_handle], but fails if the shared process list is in use. A oneshot query never waits.
+    fn try_sys_handle(&self) -> ProcCtlResult<SysHandle> {
+        if self.oneshot {
+            Ok(SysHandle::Oneshot(Box::new(System::new())))
+        } else {
+            Ok(SysHandle::Shared(try_sys_handle()?))
+        }
+    }
+
+    /// Refresh the processes this query could select, which for a oneshot query selecting a pid is only that process
+    fn refresh_selected(&self, sys_handle: &mut System, kind: ProcessRefreshKind) {
+        let only = match (self.oneshot, &self.parent_name) {
+            (true, None) => self.get_pid().and_then(|pid| to_sysinfo(pid).ok()),
+            _ => None,
+        };
+
+        match only {
+            Some(sys_pid) => sys_handle.refresh_processes_specifics(
+                ProcessesToUpdate::Some(&[sys_pid]),
+                true,
+                kind,
+            ),
+            None => sys_handle.refresh_processes_specifics(ProcessesToUpdate::All, true, kind),
+        };
+    }
+
+    fn info(&self, p: &Process) -> ProcInfo {
+        let mut info = ProcInfo::new(0, String::new());
+        info.refresh_from(p, self.keep_empty_args);
+        self.read_optional_fields(&mut info);
+        info
+    }
+
+    /// Overwrite `info` with the details of `p`, like [ProcQuery::info] but reusing its allocations
+    fn fill_info(&self, p: &Process, info: &mut ProcInfo) {
+        info.refresh_from(p, self.keep_empty_args);
+        self.read_optional_fields(info);
+    }
+
+    /// Read the fields which are only collected when the query asks for them
+    fn read_optional_fields(&self, info: &mut ProcInfo) {
+        // The process list is shared, so it may still have an environment from a query which collected it
+        if !self.with_env {
+            info.env.clear();
+            info.env_collected = false;
+        }
+        if self.with_namespaces {
+            let namespaces = crate::namespaces::read(info.pid);
+            info.net_ns = namespaces.net;
+            info.pid_ns = namespaces.pid;
+            info.mnt_ns = namespaces.mnt;
+            info.root = namespaces.root;
+            info.container_id = namespaces.container_id;
+        } else if self.paths_relative_to_proc_root {
+            info.root = crate::namespaces::root(info.pid);
+        }
+        if self.paths_relative_to_proc_root {
+            if let Some(root) = &info.root {
+                crate::namespaces::make_relative_to_root(&mut info.exe, root);
+                crate::namespaces::make_relative_to_root(&mut info.cwd, root);
+            }
+        }
+        if self.with_limits || self.expected_open_files.is_some() {
+            info.limits = crate::limits::read(info.pid);
+        }
+        if self.with_capabilities {
+            info.capabilities = crate::capabilities::read(info.pid);
+        }
+    }
+
+    fn matches(&self, p: &Process, processes: &HashMap<sysinfo::Pid, Process>) -> bool {
+        self.check(p, processes).is_ok()
+    }
+
+    fn check(
+        &self,
+        p: &Process,
+        processes: &HashMap<sysinfo::Pid, Process>,
+    ) -> Result<(), SkipReason> {
+        if !self.is_selected_pid(from_sysinfo(p.pid())) {
+            return Err(SkipReason::FilteredBy(FilterKind::ProcessId));
+        }
+
+        if let Some(name) = &self.name {
+            self.check_field(p, name, FilterKind::Name)?;
+        }
+
+        if let Some(parent_name) = &self.parent_name {
+            match p.parent().and_then(|parent| processes.get(&parent)) {
+                Some(parent) => self.check_field(parent, parent_name, FilterKind::ParentName)?,
+                None => return Err(SkipReason::FilteredBy(FilterKind::ParentName)),
+            }
+        }
+
+        if self.in_container.is_some() || self.container_id_prefix.is_some() {
+            let container_id = crate::namespaces::container_id(from_sysinfo(p.pid()));
+            if !crate::proc_filters::container_matches(
+                self.in_container,
+                self.container_id_prefix.as_deref(),
+                container_id.as_deref(),
+            ) {
+                return Err(SkipReason::FilteredBy(FilterKind::Container));
+            }
+        }
+
+        if !self.required_capabilities.is_empty() {
+            let capabilities = crate::capabilities::read(from_sysinfo(p.pid()));
+            if !crate::proc_filters::has_capabilities(
+                &self.required_capabilities,
+                capabilities.as_ref(),
+            ) {
+                return Err(SkipReason::FilteredBy(FilterKind::Capability));
+            }
+        }
+
+        if let Some(num_files) = self.min_open_files {
+            let limits = crate::limits::read(from_sysinfo(p.pid()));
+            if !crate::proc_filters::has_open_files(num_files, limits.as_ref()) {
+                return Err(SkipReason::FilteredBy(FilterKind::Limits));
+            }
+        }
+
+        if !self.exe_filters.is_empty() {
+            let exe = crate::exe_filter::exe_path(p)?;
+            for filter in &self.exe_filters {
+                filter.check(&exe)?;
+            }
+        }
+
+        if let Some(SysinfoFilter(predicate)) = &self.sysinfo_filter {
+            if !predicate(p) {
+                return Err(SkipReason::FilteredBy(FilterKind::Sysinfo));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_field(&self, p: &Process, name: &str, filter: FilterKind) -> Result<(), SkipReason> {
+        if self.field_matches(p, name) {
+            return Ok(());
+        }
+
+        let available =
+            crate::proc_filters::name_available(p, self.match_field, self.match_canonical_names);
+        if available {
+            Err(SkipReason::FilteredBy(filter))
+        } else {
+            Err(unavailable_reason(p, self.match_field))
+        }
+    }
+
+    fn name_matches(&self, p: &Process) -> bool {
+        match &self.name {
+            Some(name) => self.field_matches(p, name),
+            None => true,
+        }
+    }
+
+    fn field_matches(&self, p: &Process, name: &str) -> bool {
+        crate::proc_filters::name_matches(p, name, self.match_field, self.match_canonical_names)
+    }
+
+    /// Apply the filters and expectations of this query to processes which were found earlier, for
+    /// [crate::proc_filters::apply]
+    #[cfg(feature = "serde")]
+    pub(crate) fn filter_captured(&self, processes: Vec<ProcInfo>) -> ProcCtlResult<Vec<ProcInfo>> {
+        let by_pid = processes
+            .iter()
+            .map(|p| (p.pid, p))
+            .collect::<HashMap<_, _>>();
+
+        let matches = processes
+            .iter()
+            .filter(|p| self.captured_matches(p, &by_pid))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.check_expected_limits(&matches)?;
+
+        Ok(matches)
+    }
+
+    /// Whether a process found earlier passes the filters of this query which can be written in config, as
+    /// [ProcQuery::check] does for a running process
+    #[cfg(feature = "serde")]
+    fn captured_matches(&self, p: &ProcInfo, by_pid: &HashMap<Pid, &ProcInfo>) -> bool {
+        use crate::proc_filters::{
+            container_matches, has_capabilities, has_open_files, name_matches,
+        };
+
+        let field = self.match_field;
+        let canonical = self.match_canonical_names;
+
+        self.process_id.map_or(true, |pid| pid == p.pid)
+            && self
+                .name
+                .as_ref()
+                .map_or(true, |name| name_matches(p, name, field, canonical))
+            && self.parent_name.as_ref().map_or(true, |name| {
+                p.parent
+                    .and_then(|parent| by_pid.get(&parent))
+                    .is_some_and(|parent| name_matches(*parent, name, field, canonical))
+            })
+            && container_matches(
+                self.in_container,
+                self.container_id_prefix.as_deref(),
+                p.container_id.as_deref(),
+            )
+            && has_capabilities(&self.required_capabilities, p.capabilities.as_ref())
+            && self.min_open_files.map_or(true, |num_files| {
+                has_open_files(num_files, p.limits.as_ref())
+            })
+    }
+
+    fn is_selected_pid(&self, pid: Pid) -> bool {
+        // The pidfd is only polled for the process it refers to, so that this stays cheap when checking every process
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.process_fd {
+            if pidfd.pid().ok() != Some(pid) || pidfd.has_exited() {
+                return false;
+            }
+        }
+
+        self.process_id.map_or(true, |selected| selected == pid)
+    }
+
+    /// What to refresh for each process when listing them
+    fn refresh_kind(&self) -> ProcessRefreshKind {
+        let kind = ProcessRefreshKind::everything().with_cmd(self.cmd_update_kind());
+        if self.with_env {
+            kind
+        } else {
+            kind.without_environ()
+        }
+    }
+
+    fn cmd_update_kind(&self) -> UpdateKind {
+        if self.refresh_cmd || self.match_field == MatchField::Argv0 {
+            UpdateKind::Always
+        } else {
+            UpdateKind::OnlyIfNotSet
+        }
+    }
+
+    fn is_selected_parent(&self, p: &Process) -> bool {
+        if !self.is_selected_pid(from_sysinfo(p.pid())) {
+            return false;
+        }
+
+        match &self.parent_name {
+            Some(parent_name) => self.field_matches(p, parent_name),
+            None => true,
+        }
+    }
 }
 
-/// Get information about a process
-#[derive(Debug)]
-pub struct ProcQuery {
-    process_id: Option<Pid>,
-    name: Option<String>,
-    min_num_children: Option<usize>,
+#[cfg(feature = "async")]
+fn pids_of(processes: &[ProcInfo]) -> Vec<Pid> {
+    let mut pids = processes.iter().map(|p| p.pid).collect::<Vec<_>>();
+    pids.sort();
+    pids
 }
 
-impl ProcQuery {
-    /// Create a new process query
-    pub fn new() -> Self {
-        ProcQuery {
-            process_id: None,
-            name: None,
-            min_num_children: None,
-        }
+/// Whether this is one of the threads of a process rather than a process. On Linux, sysinfo lists each thread as well
+/// as the process, with the process as its parent, so every list of processes skips these.
+fn is_thread(p: &Process) -> bool {
+    p.thread_kind().is_some()
+}
+
+impl crate::proc_filters::NamedProcess for Process {
+    fn name(&self) -> std::borrow::Cow<'_, str> {
+        Process::name(self).to_string_lossy()
     }
 
-    /// Set the process ID to match
-    ///
-    /// One of this, [ProcQuery::process_name] or [ProcQuery::process_id_from_child] must be called before the query is usable.
-    pub fn process_id(mut self, pid: Pid) -> Self {
-        self.process_id = Some(pid);
-        self
+    fn comm(&self) -> Option<String> {
+        read_comm(self)
     }
 
-    /// Set the process name to match
-    ///
-    /// One of this, [ProcQuery::process_id] or [ProcQuery::process_id_from_child] must be called before the query is usable.
-    pub fn process_name(mut self, name: impl AsRef<str>) -> Self {
-        let name = name.as_ref().to_string();
-        #[cfg(target_os = "windows")]
-        let name = {
-            let mut name = name;
-            if !name.ends_with(".exe") {
-                name.push_str(".exe");
-            }
-            name
-        };
-        self.name = Some(name);
-        self
+    fn argv0(&self) -> Option<std::borrow::Cow<'_, str>> {
+        normalized_cmd(self.cmd(), false).next()
     }
 
-    /// Get the process ID of a child process
-    ///
-    /// Either this function or `process_id` are required to be called before the query is usable.
-    pub fn process_id_from_child(self, child: &Child) -> Self {
-        self.process_id(child.id())
+    fn exe(&self) -> Option<&std::path::Path> {
+        Process::exe(self)
     }
 
-    /// Require at least `num_children` children to have been started by the matched process for the query to succeed.
-    pub fn expect_min_num_children(mut self, num_children: usize) -> Self {
-        self.min_num_children = Some(num_children);
-        self
+    fn canonical_name(&self) -> String {
+        let exe = Process::exe(self).map(|exe| exe.to_string_lossy());
+        let argv0 = normalized_cmd(self.cmd(), false).next();
+        crate::names::canonical_name(
+            exe.as_deref(),
+            argv0.as_deref(),
+            &Process::name(self).to_string_lossy(),
+        )
     }
+}
 
-    /// List all processes matching the current filters.
-    pub fn list_processes(&self) -> ProcCtlResult<Vec<ProcInfo>> {
-        let mut sys_handle = sys_handle().lock().unwrap();
-        sys_handle.refresh_processes_specifics(
-            ProcessesToUpdate::All,
-            true,
-            ProcessRefreshKind::everything(),
-        );
-        let processes = sys_handle.processes();
+pub(crate) fn normalize_name(name: impl AsRef<str>) -> String {
+    let name = name.as_ref().to_string();
+    #[cfg(target_os = "windows")]
+    let name = {
+        let mut name = name;
+        if !name.ends_with(".exe") {
+            name.push_str(".exe");
+        }
+        name
+    };
+    name
+}
 
-        let infos: Vec<ProcInfo> = processes
-            .values()
-            .filter(|p| {
-                if let Some(pid) = self.process_id {
-                    if p.pid().as_u32() != pid {
-                        return false;
-                    }
-                }
+/// Work out why sysinfo could not read a field. It does not keep the error, so read it again to find out.
+#[cfg(target_os = "linux")]
+fn unavailable_reason(p: &Process, field: MatchField) -> SkipReason {
+    let path = match field {
+        MatchField::Exe => format!("/proc/{}/exe", p.pid()),
+        _ => return SkipReason::FieldUnavailable(field),
+    };
 
-                if let Some(name) = &self.name {
-                    if p.name().to_string_lossy().as_ref() != name {
-                        return false;
-                    }
-                }
+    match std::fs::read_link(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            SkipReason::PermissionDenied(field)
+        }
+        _ => SkipReason::FieldUnavailable(field),
+    }
+}
 
-                true
-            })
-            .map(|p| p.into())
-            .collect();
+#[cfg(not(target_os = "linux"))]
+fn unavailable_reason(_p: &Process, field: MatchField) -> SkipReason {
+    SkipReason::FieldUnavailable(field)
+}
+
+#[cfg(target_os = "linux")]
+fn read_comm(p: &Process) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/comm", p.pid()))
+        .ok()
+        .map(|comm| comm.trim_end_matches('\n').to_string())
+}
 
-        Ok(infos)
+#[cfg(not(target_os = "linux"))]
+fn read_comm(_p: &Process) -> Option<String> {
+    None
+}
+
+/// Get the details of a child process which has just been spawned.
+///
+/// A query run straight after spawning a process can miss it, so this looks up only the child's pid and tries again
+/// until `wait` has passed. This is the most reliable way to capture the identity of a child, including its
+/// [ProcInfo::start_time], before anything else can happen to it.
+///
+/// Fails with [ProcCtlError::ProcessExited] if the child has already exited, or with
+/// [ProcCtlError::ProcessNotFound] if it is still not visible once `wait` has passed.
+pub fn info_for_child(child: &Child, wait: std::time::Duration) -> ProcCtlResult<ProcInfo> {
+    let pid = child.id();
+    let sys_pid = to_sysinfo(pid)?;
+    let clock = crate::clock::SystemClock;
+    let deadline = crate::time::Deadline::start(&clock, wait);
+
+    loop {
+        {
+            let mut sys_handle = sys_handle();
+            sys_handle.refresh_processes_specifics(
+                ProcessesToUpdate::Some(&[sys_pid]),
+                true,
+                ProcessRefreshKind::everything(),
+            );
+            if let Some(p) = sys_handle.process(sys_pid) {
+                // A child which has exited but not been waited on is still listed until it is reaped
+                if p.status() == sysinfo::ProcessStatus::Zombie {
+                    return Err(ProcCtlError::ProcessExited(pid));
+                }
+                return Ok(p.into());
+            }
+        }
+
+        match deadline.next_sleep(&clock, std::time::Duration::from_millis(10)) {
+            Some(sleep) => clock.sleep(sleep),
+            None => return Err(ProcCtlError::ProcessNotFound(pid)),
+        }
     }
+}
 
-    /// Find the children of the selected process
-    pub fn children(&self) -> ProcCtlResult<Vec<ProcInfo>> {
-        let pid = resolve_pid(self)?;
+/// A process listed by [ProcQuery::list_with_children], with its children if they were asked for
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+pub(crate) type WithChildren = (ProcInfo, Option<ProcCtlResult<Vec<ProcInfo>>>);
 
-        let mut sys_handle = sys_handle().lock().unwrap();
-        sys_handle.refresh_processes(ProcessesToUpdate::All, true);
-        let processes = sys_handle.processes();
-        let children: Vec<ProcInfo> = processes
-            .values()
-            .filter(|p| p.parent() == Some(sysinfo::Pid::from(pid as usize)))
-            .map(|p| p.into())
-            .collect();
+/// Whether a process tracked by its pid is still running, see [check_pids]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PidStatus {
+    /// The process is running, and started at the recorded time if one was given
+    Alive,
+    /// No process is running with the pid. A process which has exited but not been waited on counts as exited.
+    Exited,
+    /// A process is running with the pid, but it started at a different time than recorded, so the tracked process
+    /// has exited and its pid has been reused
+    Replaced,
+}
 
-        if let Some(num) = &self.min_num_children {
-            if children.len() < *num {
-                return Err(ProcCtlError::TooFewChildren(children.len(), *num));
+/// Check which of a set of processes are still running, refreshing only those pids in a single pass over the process
+/// list. Returns one status for each entry, in the same order.
+///
+/// Each entry is a pid with the time its process was recorded as starting, if known. With a start time the process
+/// running with the pid must have started at the same time, or it is reported as [PidStatus::Replaced]. Start times
+/// are reported to the second by the operating system, so they are compared to within one second.
+///
+/// ```rust no_run
+/// use proc_ctl::{check_pids, PidStatus};
+/// use std::time::{Duration, SystemTime};
+///
+/// let child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+/// let info = proc_ctl::info_for_child(&child, Duration::from_secs(5)).unwrap();
+/// let started = SystemTime::UNIX_EPOCH + Duration::from_secs(info.start_time);
+///
+/// let statuses = check_pids(&[(info.pid, Some(started)), (1, None)]).unwrap();
+/// assert_eq!(PidStatus::Alive, statuses[0]);
+/// ```
+pub fn check_pids(pids: &[(Pid, Option<SystemTime>)]) -> ProcCtlResult<Vec<PidStatus>> {
+    check_pids_with(sys_handle(), pids)
+}
+
+/// Async equivalent of [check_pids], which waits for the shared process list without blocking if another query is
+/// using it. The check itself still blocks while it runs.
+#[cfg(feature = "async")]
+pub async fn check_pids_async(pids: &[(Pid, Option<SystemTime>)]) -> ProcCtlResult<Vec<PidStatus>> {
+    loop {
+        match try_sys_handle() {
+            Ok(sys_handle) => return check_pids_with(sys_handle, pids),
+            Err(ProcCtlError::WouldBlock(_)) => {
+                crate::clock::SystemClock
+                    .sleep_async(std::time::Duration::from_millis(10))
+                    .await
             }
+            Err(e) => return Err(e),
         }
+    }
+}
 
-        Ok(children)
+fn check_pids_with(
+    mut sys_handle: MutexGuard<'static, System>,
+    pids: &[(Pid, Option<SystemTime>)],
+) -> ProcCtlResult<Vec<PidStatus>> {
+    let sys_pids = pids
+        .iter()
+        .map(|(pid, _)| to_sysinfo(*pid))
+        .collect::<ProcCtlResult<Vec<_>>>()?;
+
+    sys_handle.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&sys_pids),
+        true,
+        ProcessRefreshKind::new(),
+    );
+
+    Ok(sys_pids
+        .iter()
+        .zip(pids)
+        .map(|(sys_pid, (_, recorded))| pid_status(running_start(&sys_handle, *sys_pid), *recorded))
+        .collect())
+}
+
+/// The names of those of `pids` which are running, for [crate::PortQuery::owners_of]
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+pub(crate) fn process_names(pids: &[Pid]) -> std::collections::HashMap<Pid, String> {
+    // sysinfo loses a process whose pid is given twice, and a process holding several sockets is listed for each
+    let sys_pids = pids
+        .iter()
+        .filter_map(|pid| to_sysinfo(*pid).ok())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if sys_pids.is_empty() {
+        return Default::default();
     }
 
-    /// Execute the query and retry until it succeeds or exhausts the configured retries
-    #[cfg(feature = "resilience")]
-    pub fn children_with_retry_sync(
-        &self,
-        delay: std::time::Duration,
-        count: usize,
-    ) -> ProcCtlResult<Vec<ProcInfo>> {
-        retry::retry(retry::delay::Fixed::from(delay).take(count), || {
-            self.children()
+    let mut sys_handle = sys_handle();
+    sys_handle.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&sys_pids),
+        true,
+        ProcessRefreshKind::new(),
+    );
+
+    sys_pids
+        .iter()
+        .filter_map(|sys_pid| {
+            let name = sys_handle.process(*sys_pid)?.name().to_string_lossy();
+            Some((from_sysinfo(*sys_pid), name.into_owned()))
         })
-        .map_err(|e| e.error)
+        .collect()
+}
+
+/// The start time of the process running with `pid`, or `None` if there is none
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub(crate) fn running_start_time(pid: Pid) -> ProcCtlResult<Option<u64>> {
+    let sys_pid = to_sysinfo(pid)?;
+    let mut sys_handle = sys_handle();
+    sys_handle.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[sys_pid]),
+        true,
+        ProcessRefreshKind::new(),
+    );
+
+    Ok(running_start(&sys_handle, sys_pid))
+}
+
+/// The start time in seconds since the epoch of the process with `sys_pid`, leaving out one which has exited but not
+/// been reaped
+fn running_start(sys_handle: &System, sys_pid: sysinfo::Pid) -> Option<u64> {
+    sys_handle
+        .process(sys_pid)
+        .filter(|p| p.status() != sysinfo::ProcessStatus::Zombie)
+        .map(|p| p.start_time())
+}
+
+/// The status of a tracked process, given the start time in seconds since the epoch of the process running with its
+/// pid, if there is one
+fn pid_status(running_start: Option<u64>, recorded: Option<SystemTime>) -> PidStatus {
+    let Some(running_start) = running_start else {
+        return PidStatus::Exited;
+    };
+
+    // A time before the epoch can't be the start of a running process, whatever the system clock has done since
+    match recorded.map(|r| wall_clock_since(SystemTime::UNIX_EPOCH, r)) {
+        None => PidStatus::Alive,
+        Some(since_epoch) if since_epoch.went_backwards_by.is_some() => PidStatus::Replaced,
+        Some(since_epoch) if since_epoch.elapsed.as_secs().abs_diff(running_start) <= 1 => {
+            PidStatus::Alive
+        }
+        Some(_) => PidStatus::Replaced,
     }
+}
 
-    /// Async equivalent of `children_with_retry_sync`
-    #[cfg(feature = "async")]
-    #[async_recursion::async_recursion]
-    pub async fn children_with_retry(
-        &self,
-        delay: std::time::Duration,
-        count: usize,
-    ) -> ProcCtlResult<Vec<ProcInfo>> {
-        match self.children() {
-            Ok(infos) => Ok(infos),
-            Err(e) => {
-                if count == 0 {
-                    Err(e)
-                } else {
-                    tokio::time::sleep(delay).await;
-                    self.children_with_retry(delay, count - 1).await
-                }
-            }
+/// A process and the pid of its parent, read straight from the operating system rather than from sysinfo
+#[cfg(any(target_os = "windows", test))]
+#[derive(Debug, Clone)]
+pub(crate) struct ParentLink {
+    pub(crate) pid: Pid,
+    pub(crate) parent: Pid,
+    pub(crate) name: String,
+}
+
+/// The process list a query reads, see [ProcQuery::oneshot]
+enum SysHandle {
+    /// The list shared by every query, locked for as long as this is held
+    Shared(MutexGuard<'static, System>),
+    /// A list of only the processes one query needs, dropped once it has run
+    Oneshot(Box<System>),
+}
+
+impl std::ops::Deref for SysHandle {
+    type Target = System;
+
+    fn deref(&self) -> &System {
+        match self {
+            SysHandle::Shared(sys) => sys,
+            SysHandle::Oneshot(sys) => sys,
         }
     }
 }
 
-fn sys_handle() -> &'static Mutex<System> {
+impl std::ops::DerefMut for SysHandle {
+    fn deref_mut(&mut self) -> &mut System {
+        match self {
+            SysHandle::Shared(sys) => sys,
+            SysHandle::Oneshot(sys) => sys,
+        }
+    }
+}
+
+/// Lock the process list shared by every query.
+///
+/// A panic while the lock was held can at worst have interrupted a refresh, and every user refreshes the list before
+/// reading it, so a poisoned lock is recovered rather than failing every query for the rest of the process.
+fn sys_handle() -> MutexGuard<'static, System> {
+    #[cfg(test)]
+    SHARED_SYSTEM_USES.with(|uses| uses.set(uses.get() + 1));
+    shared_system().lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Lock the process list shared by every query, failing if another query is using it
+fn try_sys_handle() -> ProcCtlResult<MutexGuard<'static, System>> {
+    #[cfg(test)]
+    SHARED_SYSTEM_USES.with(|uses| uses.set(uses.get() + 1));
+    match shared_system().try_lock() {
+        Ok(sys_handle) => Ok(sys_handle),
+        Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+        Err(TryLockError::WouldBlock) => Err(ProcCtlError::WouldBlock(
+            "the process list is in use by another query".to_string(),
+        )),
+    }
+}
+
+// How many times the current thread has used the shared process list, so that tests can check a query doesn't
+#[cfg(test)]
+thread_local! {
+    pub(crate) static SHARED_SYSTEM_USES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn shared_system() -> &'static Mutex<System> {
     static SYS_HANDLE: OnceLock<Mutex<System>> = OnceLock::new();
     SYS_HANDLE.get_or_init(|| {
         let mut sys = System::new_with_specifics(
@@ -183,30 +2280,204 @@ fn sys_handle() -> &'static Mutex<System> {
     })
 }
 
+/// The last observed result is only locked while it is copied in or out, never while a query runs, and it is always
+/// replaced as a whole, so a poisoned lock still holds a complete value
+fn lock_observed<T>(observed: &Mutex<T>) -> MutexGuard<T> {
+    observed.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Convert a process listed by sysinfo, for code which lists processes with sysinfo itself.
+///
+/// The result is what a [ProcQuery] with its defaults would give, with a few differences from the [sysinfo::Process] it
+/// came from:
+///
+/// - [ProcInfo::name] is the name as sysinfo reports it, which on Linux is cut to 15 bytes by the kernel. Match on
+///   [MatchField::Argv0] or [MatchField::Exe] to see the full name.
+/// - [ProcInfo::env] is whatever sysinfo collected, and is treated as collected even if the process was refreshed
+///   without its environment, so check that yours was refreshed with one before relying on [ProcInfo::env_map].
+/// - Arguments, the environment and paths which are not valid UTF-8 are converted lossily, and empty arguments and
+///   environment entries are left out as described on [ProcInfo::cmd] and [ProcInfo::env].
+/// - The fields read from the operating system rather than sysinfo, such as [ProcInfo::net_ns],
+///   [ProcInfo::container_id], [ProcInfo::capabilities], [ProcInfo::root] and [ProcInfo::limits], are
+///   `None`. Use a [ProcQuery] with [ProcQuery::with_namespaces], [ProcQuery::with_capabilities] or
+///   [ProcQuery::with_limits] to have them filled.
 impl From<&Process> for ProcInfo {
     fn from(value: &Process) -> Self {
-        ProcInfo {
-            name: value.name().to_string_lossy().to_string(),
-            cmd: value
-                .cmd()
+        let mut info = ProcInfo::new(0, String::new());
+        info.refresh_from(value, false);
+        info
+    }
+}
+
+/// The sysinfo pid of a process, failing with [ProcCtlError::ConfigurationError] if the pid can't be represented on
+/// this platform
+impl TryFrom<&ProcInfo> for sysinfo::Pid {
+    type Error = ProcCtlError;
+
+    fn try_from(value: &ProcInfo) -> Result<Self, Self::Error> {
+        to_sysinfo(value.pid)
+    }
+}
+
+/// See the conversion from `&ProcInfo`
+impl TryFrom<ProcInfo> for sysinfo::Pid {
+    type Error = ProcCtlError;
+
+    fn try_from(value: ProcInfo) -> Result<Self, Self::Error> {
+        to_sysinfo(value.pid)
+    }
+}
+
+impl ProcInfo {
+    /// Overwrite every field with the details of `value`, keeping the capacity of the strings and lists already here
+    fn refresh_from(&mut self, value: &Process, keep_empty_args: bool) {
+        assign_str(&mut self.name, &value.name().to_string_lossy());
+        assign_strings(&mut self.cmd, normalized_cmd(value.cmd(), keep_empty_args));
+        assign_option_str(
+            &mut self.argv0,
+            self.cmd
                 .iter()
+                .find(|arg| !arg.is_empty())
+                .map(String::as_str),
+        );
+        assign_path(&mut self.exe, value.exe());
+        self.pid = from_sysinfo(value.pid());
+        self.parent = value.parent().map(from_sysinfo);
+        assign_strings(&mut self.env, normalized_env(value.environ()));
+        self.env_collected = true;
+        assign_path(&mut self.cwd, value.cwd());
+        self.start_time = value.start_time();
+        self.net_ns = None;
+        self.pid_ns = None;
+        self.container_id = None;
+        self.capabilities = None;
+        self.mnt_ns = None;
+        self.root = None;
+        self.limits = None;
+    }
+}
+
+/// The arguments of a raw command line as [ProcInfo::cmd] holds them. Empty arguments at the end are dropped, and so
+/// are those between other arguments unless `keep_empty` is set.
+fn normalized_cmd(raw: &[OsString], keep_empty: bool) -> impl Iterator<Item = Cow<'_, str>> {
+    let len = raw
+        .iter()
+        .rposition(|arg| !arg.is_empty())
+        .map_or(0, |last| last + 1);
+    raw[..len]
+        .iter()
+        .filter(move |arg| keep_empty || !arg.is_empty())
+        .map(|arg| arg.to_string_lossy())
+}
+
+/// The entries of a raw environment as [ProcInfo::env] holds them, without empty entries
+fn normalized_env(raw: &[OsString]) -> impl Iterator<Item = Cow<'_, str>> {
+    raw.iter()
+        .filter(|var| !var.is_empty())
+        .map(|var| var.to_string_lossy())
+}
+
+fn assign_str(target: &mut String, value: &str) {
+    target.clear();
+    target.push_str(value);
+}
+
+fn assign_option_str(target: &mut Option<String>, value: Option<&str>) {
+    match (target.as_mut(), value) {
+        (Some(target), Some(value)) => assign_str(target, value),
+        (_, value) => *target = value.map(str::to_string),
+    }
+}
+
+fn assign_strings(target: &mut Vec<String>, values: impl IntoIterator<Item = impl AsRef<str>>) {
+    let mut len = 0;
+    for value in values {
+        match target.get_mut(len) {
+            Some(target) => assign_str(target, value.as_ref()),
+            None => target.push(value.as_ref().to_string()),
+        }
+        len += 1;
+    }
+    target.truncate(len);
+}
+
+fn assign_path(target: &mut Option<PathBuf>, value: Option<&std::path::Path>) {
+    match (target.as_mut(), value) {
+        (Some(target), Some(value)) => {
+            let target = target.as_mut_os_string();
+            target.clear();
+            target.push(value);
+        }
+        (_, value) => *target = value.map(|p| p.to_owned()),
+    }
+}
+
+#[cfg(feature = "serde")]
+impl crate::export::CsvRecord for ProcInfo {
+    fn csv_header() -> &'static [&'static str] {
+        &[
+            "name",
+            "cmd",
+            "argv0",
+            "exe",
+            "pid",
+            "parent",
+            "env",
+            "cwd",
+            "start_time",
+            "net_ns",
+            "pid_ns",
+            "container_id",
+            "capabilities",
+            "mnt_ns",
+            "root",
+            "open_files",
+        ]
+    }
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.cmd.join(" "),
+            self.argv0.clone().unwrap_or_default(),
+            self.exe
+                .as_ref()
                 .map(|p| p.to_string_lossy().to_string())
-                .collect(),
-            exe: value.exe().map(|p| p.to_owned()),
-            pid: value.pid().as_u32() as Pid,
-            parent: value.parent().map(|p| p.as_u32() as Pid),
-            env: value
-                .environ()
-                .iter()
+                .unwrap_or_default(),
+            self.pid.to_string(),
+            self.parent.map(|p| p.to_string()).unwrap_or_default(),
+            self.env.join(" "),
+            self.cwd
+                .as_ref()
                 .map(|p| p.to_string_lossy().to_string())
-                .collect(),
-            cwd: value.cwd().map(|p| p.to_owned()),
-        }
+                .unwrap_or_default(),
+            self.start_time.to_string(),
+            self.net_ns.map(|n| n.to_string()).unwrap_or_default(),
+            self.pid_ns.map(|n| n.to_string()).unwrap_or_default(),
+            self.container_id.clone().unwrap_or_default(),
+            self.capabilities
+                .as_ref()
+                .map(|c| c.names.join(" "))
+                .unwrap_or_default(),
+            self.mnt_ns.map(|n| n.to_string()).unwrap_or_default(),
+            self.root
+                .as_ref()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            self.limits
+                .map(|l| l.open_files.to_string())
+                .unwrap_or_default(),
+        ]
     }
 }
 
 impl MaybeHasPid for ProcQuery {
     fn get_pid(&self) -> Option<Pid> {
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.process_fd {
+            return pidfd.pid().ok();
+        }
+
         self.process_id
     }
 }
@@ -216,3 +2487,252 @@ impl Default for ProcQuery {
         ProcQuery::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_vars_are_split_at_the_first_equals() {
+        assert_eq!(Some(("PORT", "8080")), split_env_var("PORT=8080"));
+        assert_eq!(Some(("OPTS", "a=b")), split_env_var("OPTS=a=b"));
+        assert_eq!(Some(("EMPTY", "")), split_env_var("EMPTY="));
+        assert_eq!(Some(("=C:", "C:\\work")), split_env_var("=C:=C:\\work"));
+        assert_eq!(None, split_env_var("NO_VALUE"));
+        assert_eq!(None, split_env_var(""));
+    }
+
+    fn raw(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn padded_command_lines_are_normalized() {
+        // A JVM wrapper which rewrote its arguments in place, leaving the rest of the original buffer as NULs
+        let jvm = raw(&[
+            "java", "", "-Xmx512m", "", "", "-jar", "app.jar", "", "", "",
+        ]);
+
+        assert_eq!(
+            vec!["java", "-Xmx512m", "-jar", "app.jar"],
+            normalized_cmd(&jvm, false).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["java", "", "-Xmx512m", "", "", "-jar", "app.jar"],
+            normalized_cmd(&jvm, true).collect::<Vec<_>>()
+        );
+
+        // An empty script is a meaningful argument, but only kept when asked for
+        let sh = raw(&["sh", "-c", "", "name"]);
+        assert_eq!(
+            vec!["sh", "-c", "name"],
+            normalized_cmd(&sh, false).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["sh", "-c", "", "name"],
+            normalized_cmd(&sh, true).collect::<Vec<_>>()
+        );
+
+        assert_eq!(0, normalized_cmd(&raw(&["", ""]), true).count());
+        assert_eq!(0, normalized_cmd(&[], false).count());
+    }
+
+    #[test]
+    fn scrubbed_environments_are_normalized() {
+        // A process which cleared its secrets from its environment in place, leaving empty entries, and a launcher
+        // which left an entry without a value
+        let scrubbed = raw(&["PATH=/usr/bin", "", "", "DEBUG", "PORT=8080", ""]);
+        let env = normalized_env(&scrubbed).collect::<Vec<_>>();
+        assert_eq!(vec!["PATH=/usr/bin", "DEBUG", "PORT=8080"], env);
+
+        let info = ProcInfo::builder().pid(10).env(env).build();
+        let map = info.env_map().unwrap();
+        assert_eq!(2, map.len());
+        assert_eq!("8080", map["PORT"]);
+        assert!(!map.contains_key("DEBUG"));
+        assert!(!map.contains_key(""));
+        assert_eq!(None, info.env_var("DEBUG").unwrap());
+    }
+
+    #[test]
+    fn env_which_was_not_collected_is_an_error() {
+        let info = ProcInfo::builder()
+            .pid(10)
+            .env(["PORT=8080", "PORT=8081"])
+            .build();
+        assert_eq!(Some("8081"), info.env_var("PORT").unwrap());
+        assert_eq!(None, info.env_var("MISSING").unwrap());
+        assert_eq!("8081", info.env_map().unwrap()["PORT"]);
+
+        let info = ProcInfo::builder().pid(10).env_collected(false).build();
+        assert!(matches!(
+            info.env_var("PORT"),
+            Err(ProcCtlError::EnvNotCollected(10))
+        ));
+        assert!(matches!(
+            info.env_map(),
+            Err(ProcCtlError::EnvNotCollected(10))
+        ));
+    }
+
+    #[test]
+    fn linked_children_are_merged_by_pid() {
+        let link = |pid, parent, name: &str| ParentLink {
+            pid,
+            parent,
+            name: name.to_string(),
+        };
+        let links = [
+            link(10, 1, "server.exe"),
+            link(11, 1, "worker.exe"),
+            link(12, 2, "server.exe"),
+            link(13, 1, "server.exe"),
+        ];
+        let parents = HashSet::from([sysinfo::Pid::from(1)]);
+        let found = || {
+            vec![ProcInfo::builder()
+                .pid(13)
+                .parent(1)
+                .name("server.exe")
+                .build()]
+        };
+
+        let mut children = found();
+        ProcQuery::new().add_linked_children(&mut children, &parents, &HashMap::new(), &links);
+        assert_eq!(
+            vec![(13, Some(1)), (10, Some(1)), (11, Some(1))],
+            children
+                .iter()
+                .map(|c| (c.pid, c.parent))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("server.exe", children[1].name);
+
+        let mut children = found();
+        ProcQuery::new()
+            .process_name("server.exe")
+            .add_linked_children(&mut children, &parents, &HashMap::new(), &links);
+        assert_eq!(
+            vec![13, 10],
+            children.iter().map(|c| c.pid).collect::<Vec<_>>()
+        );
+
+        let mut children = found();
+        ProcQuery::new()
+            .process_name("server.exe")
+            .match_on(MatchField::Argv0)
+            .add_linked_children(&mut children, &parents, &HashMap::new(), &links);
+        assert_eq!(1, children.len());
+    }
+
+    #[test]
+    fn pid_status_compares_start_times_to_the_second() {
+        let started = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_500);
+
+        assert_eq!(PidStatus::Exited, pid_status(None, Some(started)));
+        assert_eq!(PidStatus::Exited, pid_status(None, None));
+        assert_eq!(PidStatus::Alive, pid_status(Some(1_700_000_000), None));
+        for running_start in [1_699_999_999, 1_700_000_000, 1_700_000_001] {
+            assert_eq!(
+                PidStatus::Alive,
+                pid_status(Some(running_start), Some(started))
+            );
+        }
+        assert_eq!(
+            PidStatus::Replaced,
+            pid_status(Some(1_700_000_002), Some(started))
+        );
+        assert_eq!(
+            PidStatus::Replaced,
+            pid_status(
+                Some(1_700_000_000),
+                Some(SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1))
+            )
+        );
+        // Clamping a time before the epoch to it must not make it match a process which started at the epoch
+        assert_eq!(
+            PidStatus::Replaced,
+            pid_status(
+                Some(0),
+                Some(SystemTime::UNIX_EPOCH - std::time::Duration::from_millis(500))
+            )
+        );
+    }
+
+    #[test]
+    fn oneshot_queries_do_not_use_the_shared_process_list() {
+        SHARED_SYSTEM_USES.with(|uses| uses.set(0));
+
+        let me = ProcQuery::oneshot()
+            .process_id(std::process::id())
+            .list_processes()
+            .unwrap();
+        let by_name = ProcQuery::oneshot()
+            .process_name(&me[0].name)
+            .try_list_processes_nonblocking()
+            .unwrap();
+
+        assert_eq!(std::process::id(), me[0].pid);
+        assert!(by_name.iter().any(|p| p.pid == std::process::id()));
+        assert_eq!(0, SHARED_SYSTEM_USES.with(|uses| uses.get()));
+
+        ProcQuery::new()
+            .process_id(std::process::id())
+            .list_processes()
+            .unwrap();
+        assert_eq!(1, SHARED_SYSTEM_USES.with(|uses| uses.get()));
+    }
+
+    #[test]
+    fn queries_recover_from_a_poisoned_process_list() {
+        let _ = std::thread::spawn(|| {
+            let _guard = sys_handle();
+            panic!("poison the process list");
+        })
+        .join();
+
+        let found = ProcQuery::new()
+            .process_id(std::process::id())
+            .list_processes()
+            .unwrap();
+        assert_eq!(1, found.len());
+    }
+
+    #[test]
+    fn nonblocking_list_fails_while_the_process_list_is_in_use() {
+        let query = ProcQuery::new().process_id(std::process::id());
+        assert!(query.last_observed().is_none());
+
+        let held = sys_handle();
+        assert!(matches!(
+            query.try_list_processes_nonblocking(),
+            Err(ProcCtlError::WouldBlock(_))
+        ));
+        drop(held);
+
+        assert_eq!(1, query.try_list_processes_nonblocking().unwrap().len());
+        assert_eq!(1, query.last_observed().unwrap().value.len());
+    }
+
+    #[test]
+    fn children_shortfall_truncates_children() {
+        let me = ProcQuery::new()
+            .process_id(std::process::id())
+            .list_processes()
+            .unwrap()
+            .remove(0);
+
+        let shortfall = ChildrenShortfall {
+            expected: 30,
+            found_count: 25,
+            found: vec![me.clone(); MAX_REPORTED_CHILDREN],
+            parent: Some(me),
+            parent_status: Some("Run".to_string()),
+        };
+        let message = shortfall.to_string();
+
+        assert!(message.starts_with("too few children, got 25 but expected 30\n  parent: "));
+        assert_eq!(MAX_REPORTED_CHILDREN, message.matches("child: ").count());
+        assert!(message.ends_with("... and 5 more"));
+    }
+}
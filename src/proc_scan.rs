@@ -0,0 +1,190 @@
+//! Process lookups read directly from `/proc` on Linux.
+//!
+//! These cover what a [crate::PortQuery] needs to find processes by name or by parent, without the `proc` feature and
+//! its sysinfo dependency. Each lookup reads only the files it needs, so it is also cheaper than refreshing the
+//! sysinfo process list.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::Pid;
+use std::path::Path;
+
+/// The longest name the kernel keeps for a process, see `TASK_COMM_LEN`
+const MAX_COMM_LEN: usize = 15;
+
+/// Find the pids of every running process with this name, in ascending order. Processes which have exited but not
+/// yet been waited on are not included.
+///
+/// The name is the kernel's short name for the process (`comm`), which is truncated to 15 characters. A longer name
+/// matches a process whose truncated name agrees and whose first argument, or executable, has the full name.
+pub(crate) fn pids_by_name(name: &str) -> ProcCtlResult<Vec<Pid>> {
+    let mut pids = Vec::new();
+    for pid in all_pids()? {
+        // Processes can exit while the list is being read, so anything which can't be read is skipped
+        let Some(stat) = read_stat(pid) else {
+            continue;
+        };
+
+        if stat.state != 'Z'
+            && (stat.comm == name
+                || (is_truncated(&stat.comm, name) && full_name(pid).as_deref() == Some(name)))
+        {
+            pids.push(pid);
+        }
+    }
+
+    Ok(pids)
+}
+
+/// Find the pids of the direct children of a process, in ascending order. Like [pids_by_name], children which have
+/// exited but not yet been waited on are not included.
+///
+/// The children are read from `/proc/<pid>/task/<tid>/children` where the kernel provides it, otherwise every process
+/// is checked for its parent.
+pub(crate) fn child_pids(pid: Pid) -> ProcCtlResult<Vec<Pid>> {
+    let tasks = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ProcCtlError::ProcessNotFound(pid))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut children = Vec::new();
+    for task in tasks {
+        let task = task?;
+        match std::fs::read_to_string(task.path().join("children")) {
+            Ok(contents) => children.extend(parse_pids(&contents)),
+            // The file is missing when the kernel was built without CONFIG_PROC_CHILDREN
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !task.path().exists() => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return children_by_parent(pid),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    children.retain(|child| read_stat(*child).is_some_and(|stat| stat.state != 'Z'));
+    children.sort_unstable();
+    children.dedup();
+
+    Ok(children)
+}
+
+fn children_by_parent(pid: Pid) -> ProcCtlResult<Vec<Pid>> {
+    Ok(all_pids()?
+        .into_iter()
+        .filter(|child| {
+            read_stat(*child).is_some_and(|stat| stat.parent == pid && stat.state != 'Z')
+        })
+        .collect())
+}
+
+fn all_pids() -> ProcCtlResult<Vec<Pid>> {
+    let mut pids = std::fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect::<Vec<Pid>>();
+    pids.sort_unstable();
+
+    Ok(pids)
+}
+
+/// The untruncated name of a process, from the file name of its first argument or else its executable
+fn full_name(pid: Pid) -> Option<String> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let argv0 = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+    let argv0 = Path::new(std::str::from_utf8(argv0).ok()?).file_name();
+
+    match argv0 {
+        Some(argv0) => Some(argv0.to_str()?.to_string()),
+        None => Some(
+            std::fs::read_link(format!("/proc/{}/exe", pid))
+                .ok()?
+                .file_name()?
+                .to_str()?
+                .to_string(),
+        ),
+    }
+}
+
+fn is_truncated(comm: &str, name: &str) -> bool {
+    comm.len() == MAX_COMM_LEN && name.len() > MAX_COMM_LEN && name.starts_with(comm)
+}
+
+fn parse_pids(contents: &str) -> impl Iterator<Item = Pid> + '_ {
+    contents
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok())
+}
+
+/// The fields of `/proc/<pid>/stat` used for lookups
+#[derive(Debug, PartialEq, Eq)]
+struct Stat {
+    comm: String,
+    state: char,
+    parent: Pid,
+}
+
+fn read_stat(pid: Pid) -> Option<Stat> {
+    parse_stat(&std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// Parse the contents of `/proc/<pid>/stat`. The name in brackets may itself contain spaces and brackets, so the
+/// fields are counted from the last closing bracket.
+fn parse_stat(stat: &str) -> Option<Stat> {
+    let (head, rest) = stat.rsplit_once(')')?;
+    let (_, comm) = head.split_once('(')?;
+    let mut fields = rest.split_whitespace();
+
+    Some(Stat {
+        comm: comm.to_string(),
+        state: fields.next()?.chars().next()?,
+        parent: fields.next()?.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_fields_are_read_after_the_name() {
+        assert_eq!(
+            Some(Stat {
+                comm: "my (odd) name".to_string(),
+                state: 'S',
+                parent: 1,
+            }),
+            parse_stat("1234 (my (odd) name) S 1 1234 1234 0 -1")
+        );
+        assert_eq!(None, parse_stat("1234 (truncated"));
+    }
+
+    #[test]
+    fn only_long_names_are_truncated() {
+        assert!(is_truncated("a-long-process-", "a-long-process-name"));
+        assert!(!is_truncated("short", "short-name"));
+        assert!(!is_truncated("a-long-process-", "a-long-process-"));
+        assert!(!is_truncated("another-process", "a-long-process-name"));
+    }
+
+    #[test]
+    fn finds_the_current_process() {
+        let pid = std::process::id();
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap();
+
+        assert!(pids_by_name(comm.trim_end()).unwrap().contains(&pid));
+    }
+
+    #[test]
+    fn finds_a_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let children = child_pids(std::process::id());
+        let by_parent = children_by_parent(std::process::id());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(children.unwrap().contains(&child.id()));
+        assert!(by_parent.unwrap().contains(&child.id()));
+    }
+}
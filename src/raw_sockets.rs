@@ -0,0 +1,176 @@
+//! Listing raw and ICMP sockets, read from `/proc/net` on Linux, for [crate::Snapshot::capture_with].
+//!
+//! These have no port, so they are never part of the results of a [crate::PortQuery]. The tables are those of the
+//! network namespace of the current process, so sockets in other namespaces, such as those of containers, are not
+//! found. Each socket is attributed to the processes holding it in the same way as shared ports are, see
+//! [crate::socket_owners].
+
+use crate::types::{AddressFamily, RawSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The ICMP protocol number, used for ICMP echo sockets
+const IPPROTO_ICMP: u8 = 1;
+/// The ICMPv6 protocol number, used for ICMPv6 echo sockets
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// One socket read from a table, before it is attributed to processes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RawEntry {
+    protocol: u8,
+    local_addr: IpAddr,
+    inode: u64,
+}
+
+/// List every raw and ICMP echo socket, once for each process holding it
+pub(crate) fn raw_sockets() -> std::io::Result<Vec<RawSocket>> {
+    let holders = crate::socket_owners::socket_holders();
+
+    let mut sockets = Vec::new();
+    for (table, family, ping) in [
+        ("raw", AddressFamily::Ipv4, false),
+        ("raw6", AddressFamily::Ipv6, false),
+        ("icmp", AddressFamily::Ipv4, true),
+        ("icmp6", AddressFamily::Ipv6, true),
+    ] {
+        let contents = match std::fs::read_to_string(format!("/proc/net/{}", table)) {
+            Ok(contents) => contents,
+            // IPv6 may be disabled, and kernels before 3.0 have no ICMP echo sockets
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        for entry in parse_table(&contents, family, ping) {
+            let Some(pids) = holders.get(&entry.inode) else {
+                continue;
+            };
+            let mut pids = pids.clone();
+            pids.sort_unstable();
+
+            for pid in &pids {
+                sockets.push(RawSocket {
+                    protocol: entry.protocol,
+                    ping,
+                    family,
+                    local_addr: entry.local_addr,
+                    pid: *pid,
+                    shared_with: pids.iter().copied().filter(|p| p != pid).collect(),
+                });
+            }
+        }
+    }
+
+    Ok(sockets)
+}
+
+/// Parse a table in the format of `/proc/net/raw`, skipping any line which can't be read.
+///
+/// For a raw socket the kernel shows the protocol where other tables show the port. An ICMP echo socket shows its
+/// identifier there instead, and its protocol is known from the table.
+fn parse_table(contents: &str, family: AddressFamily, ping: bool) -> Vec<RawEntry> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (address, number) = fields.get(1)?.split_once(':')?;
+            let protocol = match (ping, family) {
+                (true, AddressFamily::Ipv4) => IPPROTO_ICMP,
+                (true, AddressFamily::Ipv6) => IPPROTO_ICMPV6,
+                (false, _) => u8::try_from(u16::from_str_radix(number, 16).ok()?).ok()?,
+            };
+
+            Some(RawEntry {
+                protocol,
+                local_addr: parse_address(address, family)?,
+                inode: fields.get(9)?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Parse an address as the kernel shows it, as 32 bit words in hex each in the byte order of the machine
+fn parse_address(hex: &str, family: AddressFamily) -> Option<IpAddr> {
+    let word = |i: usize| {
+        let word = hex.get(i * 8..(i + 1) * 8)?;
+        Some(u32::from_str_radix(word, 16).ok()?.to_ne_bytes())
+    };
+
+    match (family, hex.len()) {
+        (AddressFamily::Ipv4, 8) => Some(Ipv4Addr::from(word(0)?).into()),
+        (AddressFamily::Ipv6, 32) => {
+            let mut octets = [0; 16];
+            for i in 0..4 {
+                octets[i * 4..(i + 1) * 4].copy_from_slice(&word(i)?);
+            }
+            Some(Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address as the kernel would show it on this machine
+    fn shown(octets: &[u8]) -> String {
+        use std::fmt::Write;
+
+        octets.chunks(4).fold(String::new(), |mut shown, word| {
+            let word = u32::from_ne_bytes([word[0], word[1], word[2], word[3]]);
+            let _ = write!(shown, "{:08X}", word);
+            shown
+        })
+    }
+
+    #[test]
+    fn raw_table_gives_the_protocol() {
+        let contents = format!(
+            "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+             \x20 1: {}:0001 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 41236 2 0000000000000000 0\n\
+             \x20255: 00000000:00FF 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 41237 2 0000000000000000 0\n\
+             \x20 2: 00000000:0100 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 41238 2 0000000000000000 0\n",
+            shown(&[127, 0, 0, 1])
+        );
+
+        assert_eq!(
+            vec![
+                RawEntry {
+                    protocol: 1,
+                    local_addr: Ipv4Addr::LOCALHOST.into(),
+                    inode: 41236,
+                },
+                RawEntry {
+                    protocol: 255,
+                    local_addr: Ipv4Addr::UNSPECIFIED.into(),
+                    inode: 41237,
+                },
+            ],
+            parse_table(&contents, AddressFamily::Ipv4, false)
+        );
+    }
+
+    #[test]
+    fn icmp6_table_gives_icmpv6() {
+        let contents = format!(
+            "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+             \x20 7: {}:1F90 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 52001 2 0000000000000000 0\n",
+            shown(&Ipv6Addr::LOCALHOST.octets())
+        );
+
+        assert_eq!(
+            vec![RawEntry {
+                protocol: 58,
+                local_addr: Ipv6Addr::LOCALHOST.into(),
+                inode: 52001,
+            }],
+            parse_table(&contents, AddressFamily::Ipv6, true)
+        );
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let contents = "header\n  1: 0100007F 00000000:0000 07\n  2: zz:0001\n";
+        assert!(parse_table(contents, AddressFamily::Ipv4, false).is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+//! Resolving a process again when it exits between being found and being queried, see
+//! [crate::PortQuery::re_resolve_attempts].
+//!
+//! A query which finds its process by name or identity does so in one step, and reads the ports or children in
+//! another. A process which exits in between fails the second step, even though running the query again straight
+//! away would find the process which replaced it. Only a process selected by name or identity is resolved again,
+//! since a process selected by pid has nothing to be resolved to.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::Pid;
+
+/// How many times a process which exited is resolved again by default
+pub(crate) const DEFAULT_RE_RESOLVE_ATTEMPTS: usize = 3;
+
+/// One resolution of the processes selected by a query, see [ExitedDuringResolution]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResolutionAttempt {
+    /// The processes the selection resolved to, in ascending order
+    pub resolved: Vec<Pid>,
+    /// The processes which had exited by the time they were queried
+    pub exited: Vec<Pid>,
+}
+
+/// Every resolution made by a query whose processes kept exiting before they could be queried
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExitedDuringResolution {
+    /// How the processes were selected, such as `name server`
+    pub selection: String,
+    /// Each resolution in order, starting with the original
+    pub attempts: Vec<ResolutionAttempt>,
+}
+
+impl std::fmt::Display for ExitedDuringResolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "processes matching {} exited before they could be queried, resolved {} times:",
+            self.selection,
+            self.attempts.len()
+        )?;
+        for attempt in &self.attempts {
+            write!(
+                f,
+                " {:?} with {:?} exited;",
+                attempt.resolved, attempt.exited
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `query` on the processes found by `resolve`, resolving them again at most `re_resolve` times when `query` fails
+/// because one of them has exited.
+///
+/// Once there are no attempts left this fails with [ProcCtlError::ResolvedProcessExited], unless `re_resolve` is 0, in
+/// which case the error of `query` is returned as it is. Any other failure is returned straight away.
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+pub(crate) fn resolve_and_query<T>(
+    re_resolve: usize,
+    describe: impl FnOnce() -> String,
+    mut resolve: impl FnMut() -> ProcCtlResult<Vec<Pid>>,
+    mut query: impl FnMut(&[Pid]) -> ProcCtlResult<T>,
+) -> ProcCtlResult<T> {
+    let mut attempts = Vec::new();
+    loop {
+        let resolved = resolve()?;
+        let e = match query(&resolved) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        let Some(pid) = exited_pid(&e).filter(|pid| resolved.contains(pid)) else {
+            return Err(e);
+        };
+        if re_resolve == 0 {
+            return Err(e);
+        }
+
+        attempts.push(ResolutionAttempt {
+            resolved,
+            exited: vec![pid],
+        });
+        if attempts.len() > re_resolve {
+            return Err(exited_error(describe(), attempts));
+        }
+    }
+}
+
+/// Run `query` until none of the processes it lists have exited, making at most `re_resolve` more attempts, for
+/// [crate::ports_for] where each process succeeds or fails alone.
+///
+/// Once there are no attempts left, each process which exited on the last attempt fails with
+/// [ProcCtlError::ResolvedProcessExited] instead, unless `re_resolve` is 0.
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+pub(crate) fn query_each_until_running<I, T>(
+    re_resolve: usize,
+    describe: impl FnOnce() -> String,
+    mut query: impl FnMut() -> ProcCtlResult<Vec<(I, Pid, ProcCtlResult<T>)>>,
+) -> ProcCtlResult<Vec<(I, ProcCtlResult<T>)>> {
+    let mut attempts = Vec::new();
+    loop {
+        let mut found = query()?;
+        let exited = found
+            .iter()
+            .filter_map(|(_, pid, result)| match result {
+                Err(e) if exited_pid(e) == Some(*pid) => Some(*pid),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !exited.is_empty() && re_resolve > 0 {
+            let mut resolved = found.iter().map(|(_, pid, _)| *pid).collect::<Vec<_>>();
+            resolved.sort_unstable();
+            attempts.push(ResolutionAttempt {
+                resolved,
+                exited: exited.clone(),
+            });
+
+            if attempts.len() > re_resolve {
+                let details = ExitedDuringResolution {
+                    selection: describe(),
+                    attempts,
+                };
+                for (_, pid, result) in &mut found {
+                    if exited.contains(pid) {
+                        *result = Err(ProcCtlError::ResolvedProcessExited(Box::new(
+                            details.clone(),
+                        )));
+                    }
+                }
+            } else {
+                continue;
+            }
+        }
+
+        return Ok(found
+            .into_iter()
+            .map(|(item, _, result)| (item, result))
+            .collect());
+    }
+}
+
+/// The process an error says has exited, if it is that kind of error
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+fn exited_pid(e: &ProcCtlError) -> Option<Pid> {
+    match e {
+        ProcCtlError::ProcessNotFound(pid) | ProcCtlError::ProcessExited(pid) => Some(*pid),
+        _ => None,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+fn exited_error(selection: String, attempts: Vec<ResolutionAttempt>) -> ProcCtlError {
+    ProcCtlError::ResolvedProcessExited(Box::new(ExitedDuringResolution {
+        selection,
+        attempts,
+    }))
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Resolve to each of `pids` in turn
+    fn scripted(pids: &[&[Pid]]) -> impl FnMut() -> ProcCtlResult<Vec<Pid>> {
+        let mut pids = pids.iter().map(|p| p.to_vec()).collect::<VecDeque<_>>();
+        move || Ok(pids.pop_front().expect("resolved too many times"))
+    }
+
+    /// A query which fails for processes in `exited`
+    fn ports(exited: &'static [Pid]) -> impl FnMut(&[Pid]) -> ProcCtlResult<Vec<Pid>> {
+        move |pids| match pids.iter().find(|pid| exited.contains(pid)) {
+            Some(pid) => Err(ProcCtlError::ProcessNotFound(*pid)),
+            None => Ok(pids.to_vec()),
+        }
+    }
+
+    #[test]
+    fn a_process_which_disappears_is_found_again() {
+        let found = resolve_and_query(
+            3,
+            || "name server".to_string(),
+            scripted(&[&[10], &[11]]),
+            ports(&[10]),
+        )
+        .unwrap();
+
+        assert_eq!(vec![11], found);
+    }
+
+    #[test]
+    fn gives_up_with_every_attempt() {
+        let err = resolve_and_query(
+            2,
+            || "name server".to_string(),
+            scripted(&[&[10], &[11], &[12]]),
+            ports(&[10, 11, 12]),
+        )
+        .unwrap_err();
+
+        match err {
+            ProcCtlError::ResolvedProcessExited(details) => {
+                assert_eq!("name server", details.selection);
+                assert_eq!(
+                    vec![
+                        (vec![10], vec![10]),
+                        (vec![11], vec![11]),
+                        (vec![12], vec![12])
+                    ],
+                    details
+                        .attempts
+                        .into_iter()
+                        .map(|a| (a.resolved, a.exited))
+                        .collect::<Vec<_>>()
+                );
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn no_attempts_keeps_the_original_error() {
+        let err = resolve_and_query(0, String::new, scripted(&[&[10]]), ports(&[10])).unwrap_err();
+        assert!(
+            matches!(err, ProcCtlError::ProcessNotFound(10)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn other_failures_are_not_retried() {
+        let err = resolve_and_query(
+            3,
+            String::new,
+            scripted(&[&[10]]),
+            |_| -> ProcCtlResult<()> { Err(ProcCtlError::ProcessNotFound(99)) },
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, ProcCtlError::ProcessNotFound(99)),
+            "{:?}",
+            err
+        );
+
+        let err = resolve_and_query(3, String::new, scripted(&[&[]]), |_| -> ProcCtlResult<()> {
+            Err(ProcCtlError::NoMatchingProcess("server".to_string()))
+        })
+        .unwrap_err();
+        assert!(
+            matches!(err, ProcCtlError::NoMatchingProcess(_)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[cfg(feature = "proc")]
+    #[test]
+    fn each_process_is_listed_again_until_none_have_exited() {
+        let mut lists = VecDeque::from([
+            vec![
+                ("a", 10, Ok(1)),
+                ("b", 20, Err(ProcCtlError::ProcessExited(20))),
+            ],
+            vec![("a", 10, Ok(1)), ("b", 21, Ok(2))],
+        ]);
+
+        let found =
+            query_each_until_running(3, String::new, || Ok(lists.pop_front().unwrap())).unwrap();
+
+        assert_eq!(
+            vec![("a", Some(1)), ("b", Some(2))],
+            found
+                .into_iter()
+                .map(|(item, result)| (item, result.ok()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "proc")]
+    #[test]
+    fn each_process_which_keeps_exiting_fails_alone() {
+        let found = query_each_until_running(
+            1,
+            || "name server".to_string(),
+            || {
+                Ok(vec![
+                    ("a", 10, Ok(1)),
+                    ("b", 20, Err(ProcCtlError::ProcessExited(20))),
+                ])
+            },
+        )
+        .unwrap();
+
+        assert!(found[0].1.is_ok());
+        match &found[1].1 {
+            Err(ProcCtlError::ResolvedProcessExited(details)) => {
+                assert_eq!(2, details.attempts.len());
+                assert_eq!(vec![10, 20], details.attempts[0].resolved);
+                assert_eq!(vec![20], details.attempts[0].exited);
+            }
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+}
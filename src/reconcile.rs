@@ -0,0 +1,265 @@
+//! Periodically compare the ports of a process against an allowlist.
+//!
+//! The [Reconciler] runs a [PortQuery] on an interval and calls back only when the result changes, so a process which
+//! stays out of line produces one report rather than one per interval.
+
+use crate::error::ProcCtlResult;
+use crate::port_query::PortQuery;
+use crate::types::{PortInfo, ProtocolPort};
+use std::collections::BTreeSet;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How the ports of a process differ from its allowlist
+#[derive(Debug)]
+pub struct Violation {
+    /// Ports which are bound but not allowed
+    pub unexpected: Vec<PortInfo>,
+    /// Ports which are allowed but not bound
+    pub missing: Vec<ProtocolPort>,
+}
+
+impl Violation {
+    /// Whether the process is bound to exactly its allowed ports
+    pub fn is_empty(&self) -> bool {
+        self.unexpected.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Checks that a process only ever listens on its allowed ports, see [Reconciler::start] and [Reconciler::run]
+///
+/// Expectations set on the query, such as [PortQuery::expect_min_num_ports], are ignored.
+#[derive(Debug)]
+pub struct Reconciler {
+    query: PortQuery,
+    interval: Duration,
+    state: ReconcileState,
+}
+
+impl Reconciler {
+    /// Create a reconciler which checks the ports found by `query` against `allowed` once per second
+    pub fn new(query: PortQuery, allowed: impl IntoIterator<Item = ProtocolPort>) -> Self {
+        Reconciler {
+            query,
+            interval: Duration::from_secs(1),
+            state: ReconcileState::new(allowed),
+        }
+    }
+
+    /// Set how long to wait between checks
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Run the checks on a new thread until the returned handle is stopped or dropped.
+    ///
+    /// `on_change` is called with the result of the first check, then again each time the result differs from the
+    /// previous one. An empty [Violation] means the process has come back in line. Errors from the query are
+    /// reported in the same way, so a process which exits is reported once rather than on every check.
+    pub fn start(
+        mut self,
+        mut on_change: impl FnMut(ProcCtlResult<Violation>) + Send + 'static,
+    ) -> ReconcilerHandle {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || loop {
+            if let Some(change) = self.check() {
+                on_change(change);
+            }
+
+            match stopped.recv_timeout(self.interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        });
+
+        ReconcilerHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Run the checks in the current task, forever. Stop by dropping the future, for example by aborting the task it
+    /// was spawned on.
+    ///
+    /// `on_change` is called in the same way as for [Reconciler::start].
+    #[cfg(feature = "async")]
+    pub async fn run(mut self, mut on_change: impl FnMut(ProcCtlResult<Violation>)) {
+        loop {
+            if let Some(change) = self.check() {
+                on_change(change);
+            }
+            self.query.clock().sleep_async(self.interval).await;
+        }
+    }
+
+    fn check(&mut self) -> Option<ProcCtlResult<Violation>> {
+        let ports = self.query.list_ports(false);
+        self.state.update(ports)
+    }
+}
+
+/// A running [Reconciler], which is stopped when this is dropped
+#[derive(Debug)]
+pub struct ReconcilerHandle {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReconcilerHandle {
+    /// Stop the reconciler and wait for any check in progress to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            // A panic in the callback has already been reported by the thread, so there is nothing to add here
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ReconcilerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// What was seen at the previous check, to tell whether anything changed
+#[derive(Debug, PartialEq, Eq)]
+enum Observation {
+    Ports {
+        unexpected: BTreeSet<ProtocolPort>,
+        missing: BTreeSet<ProtocolPort>,
+    },
+    Failed(String),
+}
+
+/// The comparison against the allowlist, kept apart from running the query so that it can be tested with any
+/// sequence of results
+#[derive(Debug)]
+struct ReconcileState {
+    allowed: BTreeSet<ProtocolPort>,
+    last: Option<Observation>,
+}
+
+impl ReconcileState {
+    fn new(allowed: impl IntoIterator<Item = ProtocolPort>) -> Self {
+        ReconcileState {
+            allowed: allowed.into_iter().collect(),
+            last: None,
+        }
+    }
+
+    /// Record the result of a check, returning it as a violation if it differs from the previous check
+    fn update(&mut self, ports: ProcCtlResult<Vec<PortInfo>>) -> Option<ProcCtlResult<Violation>> {
+        let (observation, result) = match ports {
+            Ok(ports) => {
+                let bound = ports.iter().map(|p| p.port).collect::<BTreeSet<_>>();
+                let missing = self
+                    .allowed
+                    .difference(&bound)
+                    .copied()
+                    .collect::<BTreeSet<_>>();
+                let unexpected = ports
+                    .into_iter()
+                    .filter(|p| !self.allowed.contains(&p.port))
+                    .collect::<Vec<_>>();
+
+                (
+                    Observation::Ports {
+                        unexpected: unexpected.iter().map(|p| p.port).collect(),
+                        missing: missing.clone(),
+                    },
+                    Ok(Violation {
+                        unexpected,
+                        missing: missing.into_iter().collect(),
+                    }),
+                )
+            }
+            Err(e) => (Observation::Failed(e.to_string()), Err(e)),
+        };
+
+        if self.last.as_ref() == Some(&observation) {
+            return None;
+        }
+        self.last = Some(observation);
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProcCtlError;
+
+    fn ports(ports: &[ProtocolPort]) -> ProcCtlResult<Vec<PortInfo>> {
+        Ok(ports.iter().map(|port| PortInfo::new(*port, 1)).collect())
+    }
+
+    /// The unexpected and missing ports of a change, or its error message
+    type Summary = Option<Result<(Vec<ProtocolPort>, Vec<ProtocolPort>), String>>;
+
+    fn summary(change: Option<ProcCtlResult<Violation>>) -> Summary {
+        change.map(|result| {
+            result
+                .map(|v| (v.unexpected.iter().map(|p| p.port).collect(), v.missing))
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    #[test]
+    fn only_changes_are_reported() {
+        use ProtocolPort::{Tcp, Udp};
+
+        let mut state = ReconcileState::new([Tcp(80), Tcp(443)]);
+
+        // The first check is always reported
+        assert_eq!(
+            Some(Ok((vec![], vec![Tcp(443)]))),
+            summary(state.update(ports(&[Tcp(80)])))
+        );
+        assert_eq!(None, summary(state.update(ports(&[Tcp(80)]))));
+
+        assert_eq!(
+            Some(Ok((vec![], vec![]))),
+            summary(state.update(ports(&[Tcp(443), Tcp(80)])))
+        );
+        assert_eq!(
+            Some(Ok((vec![Udp(53)], vec![]))),
+            summary(state.update(ports(&[Tcp(80), Tcp(443), Udp(53)])))
+        );
+        assert_eq!(
+            None,
+            summary(state.update(ports(&[Udp(53), Tcp(443), Tcp(80)])))
+        );
+    }
+
+    #[test]
+    fn errors_are_reported_once() {
+        use ProtocolPort::Tcp;
+
+        let mut state = ReconcileState::new([Tcp(80)]);
+
+        assert_eq!(
+            Some(Ok((vec![], vec![]))),
+            summary(state.update(ports(&[Tcp(80)])))
+        );
+        assert!(matches!(
+            summary(state.update(Err(ProcCtlError::ProcessNotFound(1)))),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            None,
+            summary(state.update(Err(ProcCtlError::ProcessNotFound(1))))
+        );
+        assert_eq!(
+            Some(Ok((vec![], vec![Tcp(80)]))),
+            summary(state.update(ports(&[])))
+        );
+    }
+}
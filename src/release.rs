@@ -0,0 +1,221 @@
+//! Waiting for a process to release a port, see [crate::PortQuery::wait_for_release].
+//!
+//! The processes holding the port are identified by their pid and start time when the wait starts. A process which
+//! exits counts as having released the port, and so does one whose pid has since been reused, even if the new process
+//! binds the same port, because it is not the process which was being waited on.
+
+use crate::clock::Clock;
+use crate::error::ProcCtlResult;
+use crate::time::Deadline;
+use crate::types::Pid;
+use std::time::Duration;
+
+/// How a port came to be released, see [crate::PortQuery::wait_for_release]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PortRelease {
+    /// The process is still running but no longer holds the port
+    PortClosed,
+    /// The process has exited, including when its pid has been reused by another process
+    ProcessExited,
+}
+
+/// A process being waited on, identified by its pid and when it started in whatever units the platform reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub(crate) pid: Pid,
+    pub(crate) start: u64,
+}
+
+/// Check whether the port has been released by every target which is still running.
+///
+/// `identity` gives the start time of the process currently running with a pid, or `None` if there is none, and
+/// `holds_port` whether any of the given processes holds the port.
+pub(crate) fn release_status(
+    targets: &[Target],
+    identity: impl Fn(Pid) -> ProcCtlResult<Option<u64>>,
+    holds_port: impl FnOnce(&[Pid]) -> ProcCtlResult<bool>,
+) -> ProcCtlResult<Option<PortRelease>> {
+    let running = || {
+        let mut running = Vec::with_capacity(targets.len());
+        for target in targets {
+            if identity(target.pid)? == Some(target.start) {
+                running.push(target.pid);
+            }
+        }
+        ProcCtlResult::Ok(running)
+    };
+
+    let before = running()?;
+    if before.is_empty() {
+        return Ok(Some(PortRelease::ProcessExited));
+    }
+    if holds_port(&before)? {
+        return Ok(None);
+    }
+
+    // A process which exited while its ports were being read no longer shows the port, but it did not close it
+    if running()?.is_empty() {
+        Ok(Some(PortRelease::ProcessExited))
+    } else {
+        Ok(Some(PortRelease::PortClosed))
+    }
+}
+
+/// Run `check` straight away and then once per `delay` until it finds the port released, or return `None` once
+/// `timeout` has passed
+pub(crate) fn wait_sync(
+    clock: &dyn Clock,
+    delay: Duration,
+    timeout: Duration,
+    mut check: impl FnMut() -> ProcCtlResult<Option<PortRelease>>,
+) -> ProcCtlResult<Option<PortRelease>> {
+    let deadline = Deadline::start(clock, timeout);
+    loop {
+        if let Some(released) = check()? {
+            return Ok(Some(released));
+        }
+
+        match deadline.next_sleep(clock, delay) {
+            Some(sleep) => clock.sleep(sleep),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Async equivalent of [wait_sync]. Each check still blocks while it runs.
+#[cfg(feature = "async")]
+pub(crate) async fn wait_async(
+    clock: &dyn Clock,
+    delay: Duration,
+    timeout: Duration,
+    mut check: impl FnMut() -> ProcCtlResult<Option<PortRelease>>,
+) -> ProcCtlResult<Option<PortRelease>> {
+    let deadline = Deadline::start(clock, timeout);
+    loop {
+        if let Some(released) = check()? {
+            return Ok(Some(released));
+        }
+
+        match deadline.next_sleep(clock, delay) {
+            Some(sleep) => clock.sleep_async(sleep).await,
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::cell::Cell;
+
+    const TARGET: Target = Target {
+        pid: 100,
+        start: 5000,
+    };
+
+    #[test]
+    fn still_held_by_the_same_process() {
+        let status = release_status(&[TARGET], |_| Ok(Some(5000)), |_| Ok(true)).unwrap();
+        assert_eq!(None, status);
+    }
+
+    #[test]
+    fn closed_by_a_running_process() {
+        let status = release_status(&[TARGET], |_| Ok(Some(5000)), |_| Ok(false)).unwrap();
+        assert_eq!(Some(PortRelease::PortClosed), status);
+    }
+
+    #[test]
+    fn process_exited() {
+        let status = release_status(
+            &[TARGET],
+            |_| Ok(None),
+            |_| panic!("ports of exited processes are not read"),
+        )
+        .unwrap();
+        assert_eq!(Some(PortRelease::ProcessExited), status);
+    }
+
+    #[test]
+    fn reused_pid_holding_the_same_port_is_not_the_target() {
+        let status = release_status(
+            &[TARGET],
+            |_| Ok(Some(7000)),
+            |_| panic!("ports of a process which reused the pid are not read"),
+        )
+        .unwrap();
+        assert_eq!(Some(PortRelease::ProcessExited), status);
+    }
+
+    #[test]
+    fn exit_while_reading_ports() {
+        let reads = Cell::new(0);
+        let identity = |_| {
+            reads.set(reads.get() + 1);
+            Ok((reads.get() == 1).then_some(5000))
+        };
+
+        let status = release_status(&[TARGET], identity, |_| Ok(false)).unwrap();
+        assert_eq!(Some(PortRelease::ProcessExited), status);
+    }
+
+    #[test]
+    fn only_running_targets_are_read() {
+        let exited = Target { pid: 200, start: 1 };
+        let status = release_status(
+            &[TARGET, exited],
+            |pid| Ok((pid == TARGET.pid).then_some(5000)),
+            |pids| {
+                assert_eq!(&[TARGET.pid], pids);
+                Ok(false)
+            },
+        )
+        .unwrap();
+        assert_eq!(Some(PortRelease::PortClosed), status);
+    }
+
+    #[test]
+    fn waits_until_released() {
+        let clock = ManualClock::new();
+        let mut checks = 0;
+
+        let released = wait_sync(
+            &clock,
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+            || {
+                checks += 1;
+                Ok((checks == 3).then_some(PortRelease::ProcessExited))
+            },
+        )
+        .unwrap();
+
+        assert_eq!(Some(PortRelease::ProcessExited), released);
+        assert_eq!(vec![Duration::from_millis(100); 2], clock.sleeps());
+    }
+
+    #[test]
+    fn gives_up_after_the_timeout() {
+        let clock = ManualClock::new();
+
+        let released = wait_sync(
+            &clock,
+            Duration::from_millis(400),
+            Duration::from_secs(1),
+            || Ok(None),
+        )
+        .unwrap();
+
+        assert_eq!(None, released);
+        assert_eq!(
+            vec![
+                Duration::from_millis(400),
+                Duration::from_millis(400),
+                Duration::from_millis(200)
+            ],
+            clock.sleeps()
+        );
+    }
+}
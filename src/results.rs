@@ -0,0 +1,240 @@
+//! The detailed results of a [crate::PortQuery], gathered in one place.
+//!
+//! [crate::PortQuery::execute] returns plain [ProtocolPort]s and always will. The richer forms are here:
+//!
+//! - [PortInfo], one for each socket, from [crate::PortQuery::execute_detailed]
+//! - [PortSummary], the ports grouped by protocol and address family, from [crate::PortQuery::summary]
+//! - [QueryReport], the detailed ports together with their summary and when and how quickly they were found, from
+//!   [crate::PortQuery::execute_report]
+//!
+//! Each of these can be converted down to the plain ports with [From], so code written against `execute` keeps
+//! working when it is handed a richer result. They are all `#[non_exhaustive]`, so fields can be added without
+//! breaking anyone, and are created outside this crate with their builders.
+//!
+//! [PortInfo], [PortInfoBuilder] and [PortSummary] are also exported from the crate root, where they have always been.
+
+pub use crate::types::{AddressFamily, PortInfo, PortInfoBuilder, PortSummary, ProtocolPort};
+use std::time::{Duration, SystemTime};
+
+/// Everything found by one execution of a query, see [crate::PortQuery::execute_report]
+///
+/// Its [std::fmt::Display] form is a single line such as `tcp4: 8080 udp6: 5353 from pid 1234 in 3.20ms`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct QueryReport {
+    /// Every port found, in the order the query found them
+    pub ports: Vec<PortInfo>,
+    /// The same ports grouped by protocol and address family
+    pub summary: PortSummary,
+    /// When the query finished, as read from the system clock
+    pub found_at: SystemTime,
+    /// How long the query took, measured on the clock of the query
+    pub elapsed: Duration,
+}
+
+impl QueryReport {
+    /// Start building a report, for use as a test fixture.
+    ///
+    /// ```rust
+    /// use proc_ctl::results::{PortInfo, ProtocolPort, QueryReport};
+    ///
+    /// let report = QueryReport::builder()
+    ///     .port(PortInfo::new(ProtocolPort::Tcp(8080), 1234))
+    ///     .build();
+    ///
+    /// assert_eq!(vec![8080], report.summary.tcp_v4);
+    /// assert_eq!(vec![ProtocolPort::Tcp(8080)], Vec::from(report));
+    /// ```
+    pub fn builder() -> QueryReportBuilder {
+        QueryReportBuilder::default()
+    }
+
+    /// The plain ports, in the order they were found
+    pub fn protocol_ports(&self) -> Vec<ProtocolPort> {
+        self.ports.iter().map(ProtocolPort::from).collect()
+    }
+
+    /// The processes which had ports, in ascending order
+    pub fn pids(&self) -> Vec<crate::Pid> {
+        let mut pids = self.ports.iter().map(|p| p.pid).collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids.dedup();
+        pids
+    }
+}
+
+impl std::fmt::Display for QueryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.summary)?;
+        match self.pids().as_slice() {
+            [] => {}
+            [pid] => write!(f, " from pid {}", pid)?,
+            pids => write!(f, " from pids {:?}", pids)?,
+        }
+        write!(f, " in {:.2?}", self.elapsed)
+    }
+}
+
+impl From<QueryReport> for Vec<ProtocolPort> {
+    fn from(report: QueryReport) -> Self {
+        report.protocol_ports()
+    }
+}
+
+impl From<QueryReport> for Vec<PortInfo> {
+    fn from(report: QueryReport) -> Self {
+        report.ports
+    }
+}
+
+impl From<QueryReport> for PortSummary {
+    fn from(report: QueryReport) -> Self {
+        report.summary
+    }
+}
+
+/// Builds a [QueryReport], see [QueryReport::builder].
+///
+/// The summary is always worked out from the ports. The time found defaults to when the report is built, and the
+/// elapsed time to zero.
+#[derive(Debug, Clone, Default)]
+pub struct QueryReportBuilder {
+    ports: Vec<PortInfo>,
+    found_at: Option<SystemTime>,
+    elapsed: Duration,
+}
+
+impl QueryReportBuilder {
+    /// Add a port to [QueryReport::ports]
+    pub fn port(mut self, port: PortInfo) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    /// Set [QueryReport::ports]
+    pub fn ports(mut self, ports: impl IntoIterator<Item = PortInfo>) -> Self {
+        self.ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Set [QueryReport::found_at]
+    pub fn found_at(mut self, found_at: SystemTime) -> Self {
+        self.found_at = Some(found_at);
+        self
+    }
+
+    /// Set [QueryReport::elapsed]
+    pub fn elapsed(mut self, elapsed: Duration) -> Self {
+        self.elapsed = elapsed;
+        self
+    }
+
+    /// Create the [QueryReport]
+    pub fn build(self) -> QueryReport {
+        QueryReport {
+            summary: PortSummary::from_ports(&self.ports),
+            ports: self.ports,
+            found_at: self.found_at.unwrap_or_else(SystemTime::now),
+            elapsed: self.elapsed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> QueryReport {
+        QueryReport::builder()
+            .port(PortInfo::new(ProtocolPort::Tcp(8081), 20))
+            .port(PortInfo::new(ProtocolPort::Tcp(8080), 10))
+            .port(
+                PortInfo::builder()
+                    .port(ProtocolPort::Udp(5353))
+                    .family(AddressFamily::Ipv6)
+                    .pid(10)
+                    .build(),
+            )
+            .found_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .elapsed(Duration::from_micros(3200))
+            .build()
+    }
+
+    #[test]
+    fn converts_down_to_plain_ports() {
+        let report = report();
+
+        assert_eq!(
+            vec![
+                ProtocolPort::Tcp(8081),
+                ProtocolPort::Tcp(8080),
+                ProtocolPort::Udp(5353)
+            ],
+            Vec::<ProtocolPort>::from(report.clone())
+        );
+        assert_eq!(
+            vec![
+                ProtocolPort::Tcp(8080),
+                ProtocolPort::Tcp(8081),
+                ProtocolPort::Udp(5353)
+            ],
+            Vec::<ProtocolPort>::from(PortSummary::from(report.clone()))
+        );
+        assert_eq!(report.ports.clone(), Vec::<PortInfo>::from(report));
+    }
+
+    #[test]
+    fn summary_follows_the_ports() {
+        let report = report();
+
+        assert_eq!(vec![8080, 8081], report.summary.tcp_v4);
+        assert_eq!(vec![5353], report.summary.udp_v6);
+        assert_eq!(vec![10, 20], report.pids());
+    }
+
+    #[test]
+    fn summary_lists_a_port_bound_on_both_families_once() {
+        let summary = PortSummary::from_ports(&[
+            PortInfo::new(ProtocolPort::Udp(53), 1),
+            PortInfo::builder()
+                .port(ProtocolPort::Tcp(8080))
+                .family(AddressFamily::Ipv6)
+                .build(),
+            PortInfo::new(ProtocolPort::Tcp(8080), 1),
+        ]);
+
+        assert_eq!(
+            vec![ProtocolPort::Tcp(8080), ProtocolPort::Udp(53)],
+            summary.protocol_ports()
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            "tcp4: 8080,8081 udp6: 5353 from pids [10, 20] in 3.20ms",
+            report().to_string()
+        );
+        assert_eq!(
+            "no ports in 0.00ns",
+            QueryReport::builder().build().to_string()
+        );
+        assert_eq!("udp6 5353 (pid 10)", report().ports[2].to_string());
+        assert_eq!(
+            "tcp4: 80 from pid 1 in 0.00ns",
+            QueryReport::builder()
+                .port(PortInfo::new(ProtocolPort::Tcp(80), 1))
+                .build()
+                .to_string()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let report = report();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(report, serde_json::from_str::<QueryReport>(&json).unwrap());
+    }
+}
@@ -0,0 +1,266 @@
+//! The retry loop shared by the `*_with_retry` functions and [crate::PortQuery::wait_for_connectable].
+//!
+//! `attempts` is the maximum number of times the operation is run, with `delay` between consecutive attempts. So `n`
+//! attempts sleep at most `n - 1` times. At least one attempt is always made, even if `attempts` is zero.
+//!
+//! A [ProcCtlError::ConfigurationError] is returned straight away, since running the same query again can not fix it,
+//! and so is [ProcCtlError::TooManyPorts], since waiting longer only gives a process the chance to bind more ports.
+//! A [ProcCtlError::Timeout] is retried like any other failure, since a loaded system may answer the next attempt in
+//! time.
+//! Callers which can tell that other failures won't be fixed either, such as [crate::PortQuery::fail_fast_on_stall],
+//! give up early through the `_or_give_up` variants.
+
+use crate::clock::Clock;
+use crate::error::{ProcCtlError, ProcCtlResult};
+use std::time::Duration;
+
+#[cfg(feature = "resilience")]
+pub(crate) fn retry_sync<T>(
+    clock: &dyn Clock,
+    delay: Duration,
+    attempts: usize,
+    f: impl FnMut() -> ProcCtlResult<T>,
+) -> ProcCtlResult<T> {
+    retry_sync_or_give_up(clock, delay, attempts, f, |_| None)
+}
+
+/// Retry like [retry_sync], showing each failure which would be retried to `give_up`, which can stop early with an
+/// error of its own
+#[cfg(feature = "resilience")]
+pub(crate) fn retry_sync_or_give_up<T>(
+    clock: &dyn Clock,
+    delay: Duration,
+    attempts: usize,
+    mut f: impl FnMut() -> ProcCtlResult<T>,
+    mut give_up: impl FnMut(&ProcCtlError) -> Option<ProcCtlError>,
+) -> ProcCtlResult<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= attempts || !is_retryable(&e) => return Err(e),
+            Err(e) => match give_up(&e) {
+                Some(e) => return Err(e),
+                None => clock.sleep(delay),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn retry_async<T>(
+    clock: &dyn Clock,
+    delay: Duration,
+    attempts: usize,
+    f: impl FnMut() -> ProcCtlResult<T>,
+) -> ProcCtlResult<T> {
+    retry_async_or_give_up(clock, delay, attempts, f, |_| None).await
+}
+
+/// Async equivalent of [retry_sync_or_give_up]
+#[cfg(feature = "async")]
+pub(crate) async fn retry_async_or_give_up<T>(
+    clock: &dyn Clock,
+    delay: Duration,
+    attempts: usize,
+    mut f: impl FnMut() -> ProcCtlResult<T>,
+    give_up: impl FnMut(&ProcCtlError) -> Option<ProcCtlError>,
+) -> ProcCtlResult<T> {
+    retry_future_or_give_up(clock, delay, attempts, || std::future::ready(f()), give_up).await
+}
+
+/// Retry like [retry_async_or_give_up], for an operation which is itself async
+#[cfg(feature = "async")]
+pub(crate) async fn retry_future_or_give_up<
+    T,
+    F: std::future::Future<Output = ProcCtlResult<T>>,
+>(
+    clock: &dyn Clock,
+    delay: Duration,
+    attempts: usize,
+    mut f: impl FnMut() -> F,
+    mut give_up: impl FnMut(&ProcCtlError) -> Option<ProcCtlError>,
+) -> ProcCtlResult<T> {
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= attempts || !is_retryable(&e) => return Err(e),
+            Err(e) => match give_up(&e) {
+                Some(e) => return Err(e),
+                None => clock.sleep_async(delay).await,
+            },
+        }
+        attempt += 1;
+    }
+}
+
+fn is_retryable(e: &ProcCtlError) -> bool {
+    !matches!(
+        e,
+        ProcCtlError::ConfigurationError(_) | ProcCtlError::TooManyPorts(_, _)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const DELAY: Duration = Duration::from_secs(1);
+
+    /// Fails until it has been called `succeed_on` times
+    fn counting(calls: &mut usize, succeed_on: usize) -> ProcCtlResult<usize> {
+        *calls += 1;
+        if *calls >= succeed_on {
+            Ok(*calls)
+        } else {
+            Err(ProcCtlError::TooFewPorts(vec![], 1))
+        }
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_makes_exactly_the_requested_attempts() {
+        for attempts in [0, 1, 2, 5] {
+            let clock = ManualClock::new();
+            let mut calls = 0;
+            assert!(
+                retry_sync(&clock, DELAY, attempts, || counting(&mut calls, usize::MAX)).is_err()
+            );
+            assert_eq!(attempts.max(1), calls);
+            assert_eq!(vec![DELAY; attempts.max(1) - 1], clock.sleeps());
+        }
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_stops_on_success() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        assert_eq!(
+            3,
+            retry_sync(&clock, DELAY, 5, || counting(&mut calls, 3)).unwrap()
+        );
+        assert_eq!(3, calls);
+        assert_eq!(2, clock.sleeps().len());
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_does_not_retry_configuration_errors() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        let result: ProcCtlResult<()> = retry_sync(&clock, DELAY, 5, || {
+            calls += 1;
+            Err(ProcCtlError::ConfigurationError("bad".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_does_not_retry_too_many_ports() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        let result: ProcCtlResult<()> = retry_sync(&clock, DELAY, 5, || {
+            calls += 1;
+            if calls == 1 {
+                Err(ProcCtlError::TooFewPorts(vec![], 1))
+            } else {
+                Err(ProcCtlError::TooManyPorts(vec![], 1))
+            }
+        });
+        assert!(matches!(result, Err(ProcCtlError::TooManyPorts(_, 1))));
+        assert_eq!(2, calls);
+        assert_eq!(1, clock.sleeps().len());
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_retries_timeouts() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        let result = retry_sync(&clock, DELAY, 5, || {
+            calls += 1;
+            if calls < 3 {
+                Err(ProcCtlError::Timeout(DELAY, "running lsof".to_string()))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(3, result.unwrap());
+        assert_eq!(2, clock.sleeps().len());
+    }
+
+    #[cfg(feature = "resilience")]
+    #[test]
+    fn sync_gives_up_early_when_asked() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        let result = retry_sync_or_give_up(
+            &clock,
+            DELAY,
+            5,
+            || counting(&mut calls, usize::MAX),
+            |e| match e {
+                ProcCtlError::TooFewPorts(_, _) if clock.sleeps().len() == 1 => {
+                    Some(ProcCtlError::ProcessExited(1))
+                }
+                _ => None,
+            },
+        );
+        assert!(matches!(result, Err(ProcCtlError::ProcessExited(1))));
+        assert_eq!(2, calls);
+        assert_eq!(1, clock.sleeps().len());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_makes_exactly_the_requested_attempts() {
+        for attempts in [0, 1, 2, 5] {
+            let clock = ManualClock::new();
+            let mut calls = 0;
+            assert!(
+                retry_async(&clock, DELAY, attempts, || counting(&mut calls, usize::MAX))
+                    .await
+                    .is_err()
+            );
+            assert_eq!(attempts.max(1), calls);
+            assert_eq!(vec![DELAY; attempts.max(1) - 1], clock.sleeps());
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_stops_on_success() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        assert_eq!(
+            3,
+            retry_async(&clock, DELAY, 5, || counting(&mut calls, 3))
+                .await
+                .unwrap()
+        );
+        assert_eq!(3, calls);
+        assert_eq!(2, clock.sleeps().len());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_does_not_retry_configuration_errors() {
+        let clock = ManualClock::new();
+        let mut calls = 0;
+        let result: ProcCtlResult<()> = retry_async(&clock, DELAY, 5, || {
+            calls += 1;
+            Err(ProcCtlError::ConfigurationError("bad".to_string()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+        assert!(clock.sleeps().is_empty());
+    }
+}
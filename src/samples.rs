@@ -0,0 +1,395 @@
+//! Finding the sample programs built from a crate, for tests which run them as child processes.
+//!
+//! Tests usually run in a debug build while the samples they start are built with `cargo build --release --bins`, so
+//! the samples are looked for in the target directory the test itself was built in, with these overrides:
+//!
+//! - `CARGO_TARGET_DIR` is used as the target directory when it is set, relative to the manifest directory.
+//! - The target triple is taken from the path of the running test, so samples built with `--target <triple>` are
+//!   found in `target/<triple>/release`.
+//! - `PROC_CTL_SAMPLE_PROFILE` names the profile the samples were built with, such as `debug`, instead of `release`.
+//! - When `PROC_CTL_BUILD_SAMPLES` is `1`, a sample which is missing is built with `cargo build` before it is run.
+//!   This is off by default so that a test run never depends on what happens to be built on demand.
+//!
+//! ```rust no_run
+//! use proc_ctl::samples::SampleBinaries;
+//!
+//! let mut binder = SampleBinaries::new(env!("CARGO_MANIFEST_DIR"))
+//!     .package(env!("CARGO_PKG_NAME"))
+//!     .command("port-binder");
+//! ```
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The variable naming the profile the samples were built with
+pub const PROFILE_VAR: &str = "PROC_CTL_SAMPLE_PROFILE";
+
+/// The variable which allows a missing sample to be built, when set to `1`
+pub const BUILD_VAR: &str = "PROC_CTL_BUILD_SAMPLES";
+
+/// The profile the samples are expected to be built with
+const DEFAULT_PROFILE: &str = "release";
+
+/// Where the sample programs of a crate are found, and how to build them when they are missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleBinaries {
+    manifest_dir: PathBuf,
+    target_dir: PathBuf,
+    target_triple: Option<String>,
+    profile: String,
+    package: Option<String>,
+    features: Vec<String>,
+    build_missing: bool,
+}
+
+impl SampleBinaries {
+    /// Find the samples of the crate in `manifest_dir`, usually `env!("CARGO_MANIFEST_DIR")`, from the environment of
+    /// the running test as described in the [module docs](self)
+    pub fn new(manifest_dir: impl Into<PathBuf>) -> Self {
+        SampleBinaries::from_env(
+            manifest_dir.into(),
+            std::env::var_os("CARGO_TARGET_DIR"),
+            std::env::current_exe().ok(),
+            std::env::var(PROFILE_VAR).ok(),
+            std::env::var(BUILD_VAR).is_ok_and(|v| v == "1"),
+        )
+    }
+
+    fn from_env(
+        manifest_dir: PathBuf,
+        cargo_target_dir: Option<OsString>,
+        current_exe: Option<PathBuf>,
+        profile: Option<String>,
+        build_missing: bool,
+    ) -> Self {
+        let built_in = current_exe.as_deref().and_then(build_dir_of);
+
+        let target_dir = match cargo_target_dir.filter(|dir| !dir.is_empty()) {
+            Some(dir) => manifest_dir.join(dir),
+            None => match &built_in {
+                Some((target_dir, _)) => target_dir.clone(),
+                None => manifest_dir.join("target"),
+            },
+        };
+        let target_triple = built_in
+            .filter(|(dir, _)| same_dir(dir, &target_dir))
+            .and_then(|(_, triple)| triple);
+
+        SampleBinaries {
+            manifest_dir,
+            target_dir,
+            target_triple,
+            profile: profile
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+            package: None,
+            features: Vec::new(),
+            build_missing,
+        }
+    }
+
+    /// Look in `dir` rather than the target directory found from the environment
+    pub fn target_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.target_dir = dir.into();
+        self
+    }
+
+    /// Look for samples built with `--target <triple>`, or for a build for the host when `None`
+    pub fn target_triple(mut self, triple: Option<String>) -> Self {
+        self.target_triple = triple;
+        self
+    }
+
+    /// Look for samples built with `profile`, such as `debug` or `release`
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// The package to build a missing sample from with `cargo build -p`, usually `env!("CARGO_PKG_NAME")`. Without it
+    /// the package in the manifest directory is built.
+    pub fn package(mut self, package: impl Into<String>) -> Self {
+        self.package = Some(package.into());
+        self
+    }
+
+    /// The features to enable when building a missing sample, which must include the `required-features` of the
+    /// sample
+    pub fn features(mut self, features: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.features = features.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether to build a sample which is missing, overriding `PROC_CTL_BUILD_SAMPLES`
+    pub fn build_missing(mut self, build_missing: bool) -> Self {
+        self.build_missing = build_missing;
+        self
+    }
+
+    /// The directory the samples are expected in, such as `target/release`
+    pub fn dir(&self) -> PathBuf {
+        let mut dir = self.target_dir.clone();
+        if let Some(triple) = &self.target_triple {
+            dir.push(triple);
+        }
+        dir.push(&self.profile);
+        dir
+    }
+
+    /// The path the sample `name` is expected at, whether or not it exists
+    pub fn path(&self, name: &str) -> PathBuf {
+        let is_windows = match &self.target_triple {
+            Some(triple) => triple.contains("windows"),
+            None => cfg!(target_os = "windows"),
+        };
+
+        let path = self.dir().join(name);
+        if is_windows {
+            path.with_extension("exe")
+        } else {
+            path
+        }
+    }
+
+    /// Find the sample `name`, building it first if it is missing and building is allowed.
+    ///
+    /// Fails with a message saying where the sample was looked for and how to build it.
+    pub fn locate(&self, name: &str) -> Result<PathBuf, String> {
+        let path = self.path(name);
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let mut build = self.build_command(name);
+        if !self.build_missing {
+            return Err(format!(
+                "{} does not exist, try running `{}` or set {}=1 to build it on demand",
+                path.display(),
+                describe(&build),
+                BUILD_VAR
+            ));
+        }
+
+        let status = build
+            .status()
+            .map_err(|e| format!("failed to run `{}`: {}", describe(&build), e))?;
+        if !status.success() {
+            return Err(format!("`{}` failed with {}", describe(&build), status));
+        }
+        if !path.exists() {
+            return Err(format!(
+                "`{}` succeeded but {} still does not exist",
+                describe(&build),
+                path.display()
+            ));
+        }
+
+        Ok(path)
+    }
+
+    /// A command which runs the sample `name`, see [SampleBinaries::locate].
+    ///
+    /// # Panics
+    ///
+    /// If the sample can't be found or built.
+    pub fn command(&self, name: &str) -> Command {
+        match self.locate(name) {
+            Ok(path) => Command::new(path),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// The `cargo build` which puts the sample `name` at [SampleBinaries::path]
+    pub fn build_command(&self, name: &str) -> Command {
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let mut cmd = Command::new(cargo);
+        cmd.arg("build")
+            .arg("--manifest-path")
+            .arg(self.manifest_dir.join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&self.target_dir);
+        if let Some(package) = &self.package {
+            cmd.args(["-p", package]);
+        }
+        cmd.args(["--bin", name]);
+        match self.profile.as_str() {
+            "debug" => {}
+            "release" => {
+                cmd.arg("--release");
+            }
+            profile => {
+                cmd.args(["--profile", profile]);
+            }
+        }
+        if let Some(triple) = &self.target_triple {
+            cmd.args(["--target", triple]);
+        }
+        if !self.features.is_empty() {
+            cmd.args(["--features", &self.features.join(",")]);
+        }
+
+        cmd
+    }
+}
+
+/// The target directory and target triple of an executable built by cargo at `<target>[/<triple>]/<profile>/deps`.
+///
+/// The target directory is recognised by the `CACHEDIR.TAG` cargo writes to it.
+fn build_dir_of(exe: &Path) -> Option<(PathBuf, Option<String>)> {
+    let deps = exe.parent().filter(|dir| dir.ends_with("deps"))?;
+    let above_profile = deps.parent()?.parent()?;
+    if is_target_dir(above_profile) {
+        return Some((above_profile.to_path_buf(), None));
+    }
+
+    let target_dir = above_profile.parent().filter(|dir| is_target_dir(dir))?;
+    let triple = above_profile.file_name()?.to_string_lossy().into_owned();
+    Some((target_dir.to_path_buf(), Some(triple)))
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn is_target_dir(dir: &Path) -> bool {
+    dir.join("CACHEDIR.TAG").is_file()
+}
+
+fn describe(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory laid out like a workspace, removed when the test ends
+    struct Layout(PathBuf);
+
+    impl Layout {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "proc-ctl-samples-{}-{}",
+                test,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Layout(dir)
+        }
+
+        fn file(&self, path: &str) -> PathBuf {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+            path
+        }
+    }
+
+    impl Drop for Layout {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn from_env(
+        layout: &Layout,
+        cargo_target_dir: Option<&str>,
+        exe: Option<&str>,
+        profile: Option<&str>,
+    ) -> SampleBinaries {
+        SampleBinaries::from_env(
+            layout.0.join("crates/server"),
+            cargo_target_dir.map(OsString::from),
+            exe.map(|exe| layout.0.join(exe)),
+            profile.map(str::to_string),
+            false,
+        )
+    }
+
+    #[test]
+    fn samples_are_found_in_the_target_dir_of_the_workspace() {
+        let layout = Layout::new("workspace");
+        layout.file("target/CACHEDIR.TAG");
+        let exe = layout.file("target/debug/deps/lib_test-0123");
+        let sample = layout.file("target/release/port-binder");
+
+        let samples = from_env(&layout, None, exe.to_str(), None);
+
+        assert_eq!(sample, samples.path("port-binder"));
+        assert_eq!(Ok(sample), samples.locate("port-binder"));
+    }
+
+    #[test]
+    fn samples_built_for_a_target_are_found_under_its_triple() {
+        let layout = Layout::new("triple");
+        layout.file("target/CACHEDIR.TAG");
+        let exe = layout.file("target/aarch64-unknown-linux-gnu/debug/deps/lib_test-0123");
+
+        let samples = from_env(&layout, None, exe.to_str(), None);
+
+        assert_eq!(
+            layout.0.join("target/aarch64-unknown-linux-gnu/release"),
+            samples.dir()
+        );
+        assert_eq!(
+            layout
+                .0
+                .join("target/x86_64-pc-windows-gnu/release/port-binder.exe"),
+            samples
+                .target_triple(Some("x86_64-pc-windows-gnu".to_string()))
+                .path("port-binder")
+        );
+    }
+
+    #[test]
+    fn cargo_target_dir_is_relative_to_the_manifest() {
+        let layout = Layout::new("cargo-target-dir");
+        layout.file("target/CACHEDIR.TAG");
+        let exe = layout.file("target/x86_64-unknown-linux-gnu/debug/deps/lib_test-0123");
+
+        let samples = from_env(&layout, Some("../../build"), exe.to_str(), None);
+
+        // The test was built somewhere else, so its triple says nothing about this target directory
+        assert_eq!(
+            layout.0.join("crates/server/../../build/release"),
+            samples.dir()
+        );
+    }
+
+    #[test]
+    fn profile_can_be_overridden() {
+        let layout = Layout::new("profile");
+        let samples = from_env(&layout, None, None, Some("debug"));
+
+        assert_eq!(layout.0.join("crates/server/target/debug"), samples.dir());
+        let args = samples.build_command("port-binder");
+        assert!(!args.get_args().any(|arg| arg == "--release"));
+    }
+
+    #[test]
+    fn missing_sample_says_how_to_build_it() {
+        let layout = Layout::new("missing");
+        let samples = from_env(&layout, None, None, None)
+            .package("server")
+            .features(["test-util"]);
+
+        let e = samples.locate("port-binder").unwrap_err();
+        assert!(e.contains("port-binder does not exist"), "{}", e);
+        assert!(
+            e.contains("build --manifest-path")
+                && e.contains("-p server --bin port-binder --release --features test-util"),
+            "{}",
+            e
+        );
+        assert!(e.contains(BUILD_VAR), "{}", e);
+    }
+}
@@ -0,0 +1,136 @@
+//! Checking which queries work in the current environment.
+//!
+//! Inside a restricted sandbox the operations the queries rely on may be denied, such as netlink sockets under
+//! seccomp, lsof under the macOS App Sandbox or the IP helper tables in a Windows AppContainer. [self_check] runs each
+//! kind of query against the current process, so that a program can fail fast with a clear message at startup rather
+//! than with an unexpected error later.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use std::fmt::{Display, Formatter};
+
+/// Whether one kind of query works in the current environment
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Capability {
+    /// What the capability allows, such as "list ports"
+    pub name: &'static str,
+    /// Why the capability is not available, if it isn't
+    pub error: Option<ProcCtlError>,
+}
+
+impl Capability {
+    fn check(name: &'static str, result: ProcCtlResult<()>) -> Self {
+        Capability {
+            name,
+            error: result.err(),
+        }
+    }
+
+    /// Whether queries which need this capability can be expected to work
+    pub fn is_available(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The result of [self_check], with one entry for each capability checked
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct CapabilityReport {
+    /// Every capability checked, available or not
+    pub capabilities: Vec<Capability>,
+}
+
+impl CapabilityReport {
+    /// Whether every capability checked is available
+    pub fn is_ok(&self) -> bool {
+        self.capabilities.iter().all(Capability::is_available)
+    }
+
+    /// The capabilities which are not available
+    pub fn unavailable(&self) -> impl Iterator<Item = &Capability> {
+        self.capabilities.iter().filter(|c| !c.is_available())
+    }
+
+    /// Fail with the error of the first capability which is not available, if any
+    pub fn into_result(self) -> ProcCtlResult<()> {
+        match self.capabilities.into_iter().find_map(|c| c.error) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for CapabilityReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for capability in &self.capabilities {
+            match &capability.error {
+                None => writeln!(f, "{}: available", capability.name)?,
+                Some(e) => writeln!(f, "{}: unavailable, {}", capability.name, e)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check which queries work in the current environment, by running each of them against the current process.
+///
+/// This binds a TCP listener on a loopback address for the duration of the check, and expects to find it.
+///
+/// ```rust
+/// let report = proc_ctl::self_check();
+/// for capability in report.unavailable() {
+///     eprintln!("{} is not available: {:?}", capability.name, capability.error);
+/// }
+/// ```
+pub fn self_check() -> CapabilityReport {
+    let capabilities = vec![
+        Capability::check("list ports", check_ports()),
+        #[cfg(target_os = "linux")]
+        Capability::check("listen backlog", check_backlog()),
+        #[cfg(feature = "proc")]
+        Capability::check("process info", check_process_info()),
+    ];
+
+    CapabilityReport { capabilities }
+}
+
+fn check_ports() -> ProcCtlResult<()> {
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+        .map_err(|e| ProcCtlError::from_restricted_io("binding a loopback listener", e))?;
+    let port = listener.local_addr()?.port();
+
+    let ports = crate::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(std::process::id())
+        .execute()?;
+
+    // A sandbox may hide sockets rather than deny listing them, so the listener has to be found
+    if ports.contains(&crate::ProtocolPort::Tcp(port)) {
+        Ok(())
+    } else {
+        Err(ProcCtlError::SandboxRestricted(
+            "listing the sockets of this process".to_string(),
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_backlog() -> ProcCtlResult<()> {
+    crate::sock_diag::tcp_listen_queues()
+        .map(|_| ())
+        .map_err(|e| ProcCtlError::from_restricted_io("reading listen queues with sock_diag", e))
+}
+
+#[cfg(feature = "proc")]
+fn check_process_info() -> ProcCtlResult<()> {
+    let pid = std::process::id();
+    let processes = crate::ProcQuery::new().process_id(pid).list_processes()?;
+
+    if processes.is_empty() {
+        Err(ProcCtlError::ProcessNotFound(pid))
+    } else {
+        Ok(())
+    }
+}
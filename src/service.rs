@@ -0,0 +1,233 @@
+//! Finding the main process of a service, from systemd on Linux or the service control manager on Windows.
+//!
+//! On Linux the main pid is read with `systemctl show`, which is available wherever systemd is running. Whether systemd
+//! is running is checked first, in the same way as `sd_booted`, so that a system without it fails with a clear error
+//! rather than one from `systemctl`.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::Pid;
+
+/// Find the main process of a systemd unit, such as `myapp.service`. A name without a suffix is taken to be a service.
+///
+/// Fails with [ProcCtlError::UnsupportedPlatform] if systemd is not running, and with
+/// [ProcCtlError::NoMatchingProcess] if the unit is not loaded or has no main process running.
+///
+/// ```rust no_run
+/// use proc_ctl::PortQuery;
+///
+/// let pid = proc_ctl::systemd_unit_pid("myapp.service").unwrap();
+/// let ports = PortQuery::new().process_id(pid).execute().unwrap();
+/// ```
+#[cfg(target_os = "linux")]
+pub fn systemd_unit_pid(unit: &str) -> ProcCtlResult<Pid> {
+    systemd_main_pid(
+        unit,
+        &crate::tool::SystemToolRunner,
+        crate::port_query::DEFAULT_MAX_TOOL_CONCURRENCY,
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn systemd_main_pid(
+    unit: &str,
+    runner: &dyn crate::tool::ToolRunner,
+    max_tool_concurrency: usize,
+) -> ProcCtlResult<Pid> {
+    if !std::path::Path::new("/run/systemd/system").exists() {
+        return Err(ProcCtlError::UnsupportedPlatform(format!(
+            "systemd is not running, so unit {} can't be looked up",
+            unit
+        )));
+    }
+
+    let output = crate::tool::output(
+        runner,
+        "systemctl",
+        &[
+            "show",
+            "--property=LoadState",
+            "--property=MainPID",
+            "--",
+            unit,
+        ],
+        max_tool_concurrency,
+    )
+    .map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            ProcCtlError::UnsupportedPlatform("systemctl was not found".to_string())
+        } else {
+            ProcCtlError::from_restricted_io("running systemctl", e)
+        }
+    })?;
+    if !output.status.success() {
+        return Err(ProcCtlError::ConfigurationError(format!(
+            "systemctl could not show unit {}: {}",
+            unit,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match parse_systemctl_show(&stdout) {
+        SystemctlShow {
+            load_state: Some("not-found"),
+            ..
+        } => Err(ProcCtlError::NoMatchingProcess(format!(
+            "systemd unit {}, which is not loaded",
+            unit
+        ))),
+        SystemctlShow {
+            main_pid: Some(pid),
+            ..
+        } if pid != 0 => Ok(pid),
+        _ => Err(ProcCtlError::NoMatchingProcess(format!(
+            "systemd unit {}, which has no main process running",
+            unit
+        ))),
+    }
+}
+
+/// The properties read from `systemctl show`
+#[cfg(any(target_os = "linux", test))]
+#[derive(Debug, PartialEq, Eq)]
+struct SystemctlShow<'a> {
+    load_state: Option<&'a str>,
+    main_pid: Option<Pid>,
+}
+
+/// Read the output of `systemctl show`, which has one `Property=value` line for each property
+#[cfg(any(target_os = "linux", test))]
+fn parse_systemctl_show(output: &str) -> SystemctlShow<'_> {
+    let property = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .map(str::trim)
+    };
+
+    SystemctlShow {
+        load_state: property("LoadState"),
+        main_pid: property("MainPID").and_then(|pid| pid.parse().ok()),
+    }
+}
+
+/// Find the process of a Windows service, by its service name such as `MyAppSvc` rather than its display name.
+///
+/// Fails with [ProcCtlError::NoMatchingProcess] if there is no such service or it is not running, and with
+/// [ProcCtlError::PermissionDenied] if the service control manager refuses access. Several services can share one
+/// process, in which case they all have the same pid.
+#[cfg(target_os = "windows")]
+pub fn windows_service_pid(name: &str) -> ProcCtlResult<Pid> {
+    use windows::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_SERVICE_DOES_NOT_EXIST};
+    use windows::Win32::System::Services::{
+        CloseServiceHandle, OpenSCManagerW, OpenServiceW, QueryServiceStatusEx, SC_HANDLE,
+        SC_MANAGER_CONNECT, SC_STATUS_PROCESS_INFO, SERVICE_QUERY_STATUS, SERVICE_STATUS_PROCESS,
+    };
+
+    /// Closes a service handle when it is dropped
+    struct Handle(SC_HANDLE);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle was opened by the service control manager and is closed only here
+            let _ = unsafe { CloseServiceHandle(self.0) };
+        }
+    }
+
+    let error = |operation: &str, e: windows::core::Error| {
+        if e.code() == ERROR_ACCESS_DENIED.to_hresult() {
+            ProcCtlError::PermissionDenied(format!("{} for service {}", operation, name))
+        } else if e.code() == ERROR_SERVICE_DOES_NOT_EXIST.to_hresult() {
+            ProcCtlError::NoMatchingProcess(format!(
+                "Windows service {}, which does not exist",
+                name
+            ))
+        } else {
+            ProcCtlError::ProcessError(format!("{} for service {}: {}", operation, name, e))
+        }
+    };
+
+    let wide_name = name.encode_utf16().chain([0]).collect::<Vec<u16>>();
+
+    // SAFETY: the names are null terminated and outlive the calls, and the status is read into a buffer of exactly its
+    // size which the call is told the length of
+    let status = unsafe {
+        let manager = Handle(
+            OpenSCManagerW(None, None, SC_MANAGER_CONNECT)
+                .map_err(|e| error("opening the service control manager", e))?,
+        );
+        let service = Handle(
+            OpenServiceW(
+                manager.0,
+                windows::core::PCWSTR(wide_name.as_ptr()),
+                SERVICE_QUERY_STATUS,
+            )
+            .map_err(|e| error("opening the service", e))?,
+        );
+
+        let mut status = SERVICE_STATUS_PROCESS::default();
+        let mut needed = 0;
+        QueryServiceStatusEx(
+            service.0,
+            SC_STATUS_PROCESS_INFO,
+            Some(std::slice::from_raw_parts_mut(
+                &mut status as *mut SERVICE_STATUS_PROCESS as *mut u8,
+                std::mem::size_of::<SERVICE_STATUS_PROCESS>(),
+            )),
+            &mut needed,
+        )
+        .map_err(|e| error("querying the status", e))?;
+
+        status
+    };
+
+    match status.dwProcessId {
+        0 => Err(ProcCtlError::NoMatchingProcess(format!(
+            "Windows service {}, which is not running",
+            name
+        ))),
+        pid => Ok(pid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_unit() {
+        assert_eq!(
+            SystemctlShow {
+                load_state: Some("loaded"),
+                main_pid: Some(812),
+            },
+            parse_systemctl_show("LoadState=loaded\nMainPID=812\n")
+        );
+    }
+
+    #[test]
+    fn stopped_and_missing_units() {
+        assert_eq!(
+            Some(0),
+            parse_systemctl_show("MainPID=0\nLoadState=loaded\n").main_pid
+        );
+        assert_eq!(
+            SystemctlShow {
+                load_state: Some("not-found"),
+                main_pid: Some(0),
+            },
+            parse_systemctl_show("LoadState=not-found\nMainPID=0\n")
+        );
+    }
+
+    #[test]
+    fn unexpected_output() {
+        assert_eq!(
+            SystemctlShow {
+                load_state: None,
+                main_pid: None,
+            },
+            parse_systemctl_show("MainPID=not a pid\nLoadStateX=loaded\n")
+        );
+    }
+}
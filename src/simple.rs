@@ -0,0 +1,104 @@
+//! One-line waits for the ports of a child process, see [wait_for_ports].
+//!
+//! These are the quickest way to get started. Each is a [PortQuery] with the defaults, so every protocol and address
+//! family, checked every 100ms until the timeout. Build a [PortQuery] and use [PortQuery::wait_for_port_sync] for
+//! anything more.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::port_query::PortQuery;
+use crate::types::{Port, ProtocolPort};
+use crate::wait::WaitOptions;
+use std::process::Child;
+use std::time::Duration;
+
+/// Wait for `child` to bind at least `min_ports` ports, returning every port it has bound once it has.
+///
+/// Fails with [ProcCtlError::WaitTimedOut] if the child has bound too few ports when `timeout` passes. The error
+/// holds what was found by the last few checks, so an error such as the child having exited can be seen there.
+///
+/// ```rust no_run
+/// use std::process::Command;
+/// use std::time::Duration;
+///
+/// let child = Command::new("my-server").spawn().unwrap();
+/// let ports = proc_ctl::wait_for_ports(&child, 1, Duration::from_secs(10)).unwrap();
+/// ```
+pub fn wait_for_ports(
+    child: &Child,
+    min_ports: usize,
+    timeout: Duration,
+) -> ProcCtlResult<Vec<ProtocolPort>> {
+    let query = child_query(child).expect_min_num_ports(min_ports);
+    Ok(query.wait_for_port_sync(&WaitOptions::new(timeout))?.value)
+}
+
+/// Async equivalent of [wait_for_ports]
+#[cfg(feature = "async")]
+pub async fn wait_for_ports_async(
+    child: &Child,
+    min_ports: usize,
+    timeout: Duration,
+) -> ProcCtlResult<Vec<ProtocolPort>> {
+    let query = child_query(child).expect_min_num_ports(min_ports);
+    Ok(query.wait_for_port(&WaitOptions::new(timeout)).await?.value)
+}
+
+/// Wait for `child` to start listening on a TCP port, for a child which listens on exactly one.
+///
+/// A port bound on both IPv4 and IPv6 counts once. Fails with [ProcCtlError::TooManyPorts] if more than one TCP port
+/// was bound by the time the first was found, or with [ProcCtlError::WaitTimedOut] as for [wait_for_ports].
+///
+/// ```rust no_run
+/// use std::process::Command;
+/// use std::time::Duration;
+///
+/// let child = Command::new("my-server").spawn().unwrap();
+/// let port = proc_ctl::wait_for_tcp_port(&child, Duration::from_secs(10)).unwrap();
+/// println!("listening on http://localhost:{}", port);
+/// ```
+pub fn wait_for_tcp_port(child: &Child, timeout: Duration) -> ProcCtlResult<Port> {
+    let query = child_query(child).tcp_only().expect_min_num_ports(1);
+    only_port(query.wait_for_port_sync(&WaitOptions::new(timeout))?.value)
+}
+
+/// Async equivalent of [wait_for_tcp_port]
+#[cfg(feature = "async")]
+pub async fn wait_for_tcp_port_async(child: &Child, timeout: Duration) -> ProcCtlResult<Port> {
+    let query = child_query(child).tcp_only().expect_min_num_ports(1);
+    only_port(query.wait_for_port(&WaitOptions::new(timeout)).await?.value)
+}
+
+fn child_query(child: &Child) -> PortQuery {
+    PortQuery::new().process_id_from_child(child)
+}
+
+/// The single port found, counting one bound on both address families once
+fn only_port(mut ports: Vec<ProtocolPort>) -> ProcCtlResult<Port> {
+    ports.sort_unstable();
+    ports.dedup();
+
+    match ports.as_slice() {
+        [port] => Ok(port.port()),
+        _ => Err(ProcCtlError::TooManyPorts(ports, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_port_counts_both_families_once() {
+        assert_eq!(
+            8080,
+            only_port(vec![ProtocolPort::Tcp(8080), ProtocolPort::Tcp(8080)]).unwrap()
+        );
+
+        let err = only_port(vec![ProtocolPort::Tcp(8081), ProtocolPort::Tcp(8080)]).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcCtlError::TooManyPorts(ports, 1)
+                if ports == vec![ProtocolPort::Tcp(8080), ProtocolPort::Tcp(8081)]
+        ));
+    }
+}
@@ -0,0 +1,632 @@
+//! Saved copies of the process and socket tables, for running queries offline, see [Snapshot].
+//!
+//! A snapshot taken where a problem happens can be saved to a file, sent along with a bug report, and queried
+//! elsewhere with [crate::PortQuery::execute_on] to reproduce what a query saw. The file is JSON, with a
+//! [Snapshot::format_version] so that a snapshot from a newer release is rejected rather than misread.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::proc_query::ProcInfo;
+use crate::types::{Pid, PortInfo, RawSocket};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The version of the snapshot file format written by this release
+const FORMAT_VERSION: u32 = 1;
+
+/// The process and socket tables of a system at one point in time.
+///
+/// Take one with [Snapshot::capture], save it with [Snapshot::save] and query it with
+/// [crate::PortQuery::execute_on]. Snapshots hold the command line and environment of every process, which may
+/// include secrets, so use a [Redaction] before sharing one.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct Snapshot {
+    /// The version of the file format, which [Snapshot::load] checks
+    pub format_version: u32,
+    /// When the snapshot was taken, as read from the system clock
+    pub taken_at: SystemTime,
+    /// Where the snapshot was taken
+    pub runtime_info: RuntimeInfo,
+    /// Every process which was running
+    pub processes: Vec<ProcInfo>,
+    /// Every socket found, one for each address family, with the process it belongs to in [PortInfo::pid]
+    pub sockets: Vec<PortInfo>,
+    /// Every raw and ICMP echo socket found, if they were asked for with [CaptureOptions::include_raw_sockets]
+    #[serde(default)]
+    pub raw_sockets: Vec<RawSocket>,
+    /// Whether sockets were left out of [Snapshot::sockets] because there were more than
+    /// [CaptureOptions::max_results]. When set, the sockets which were kept are those of the processes with the lowest
+    /// pids.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// The system a [Snapshot] was taken on
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct RuntimeInfo {
+    /// The operating system, as given by [std::env::consts::OS]
+    pub os: String,
+    /// The CPU architecture, as given by [std::env::consts::ARCH]
+    pub arch: String,
+    /// The version of this crate which took the snapshot
+    pub crate_version: String,
+}
+
+impl RuntimeInfo {
+    /// Describe the system this is running on
+    pub fn current() -> Self {
+        RuntimeInfo {
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// What to remove from a [Snapshot] before it is shared, see [Snapshot::redact].
+///
+/// Nothing is removed by default.
+///
+/// ```rust
+/// use proc_ctl::Redaction;
+///
+/// // Keep only the program of each command line, and no environment variables
+/// let redaction = Redaction::new().drop_env().truncate_cmd(1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    drop_env: bool,
+    max_cmd_args: Option<usize>,
+}
+
+impl Redaction {
+    /// Remove nothing
+    pub fn new() -> Self {
+        Redaction::default()
+    }
+
+    /// Remove the environment of every process, marking it as not collected in [ProcInfo::env_collected]
+    pub fn drop_env(mut self) -> Self {
+        self.drop_env = true;
+        self
+    }
+
+    /// Keep only the first `max_args` arguments of each command line, counting the program as the first.
+    ///
+    /// [ProcInfo::argv0] is kept, so processes can still be found by name.
+    pub fn truncate_cmd(mut self, max_args: usize) -> Self {
+        self.max_cmd_args = Some(max_args);
+        self
+    }
+
+    fn apply(&self, process: &mut ProcInfo) {
+        if self.drop_env {
+            process.env.clear();
+            process.env_collected = false;
+        }
+        if let Some(max_args) = self.max_cmd_args {
+            process.cmd.truncate(max_args);
+        }
+    }
+}
+
+/// How to take a [Snapshot], see [Snapshot::capture_with]
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+    redaction: Redaction,
+    include_raw_sockets: bool,
+    max_results: Option<usize>,
+}
+
+impl CaptureOptions {
+    /// Capture everything, without redacting anything
+    pub fn new() -> Self {
+        CaptureOptions::default()
+    }
+
+    /// Remove what `redaction` asks for from the snapshot
+    pub fn redact(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Also list raw sockets and ICMP echo sockets in [Snapshot::raw_sockets], such as those of an agent which pings
+    /// other hosts.
+    ///
+    /// These have no port, so they are never returned by a query. They are only found on Linux, from the tables of the
+    /// network namespace this is running in, and this has no effect on other platforms.
+    pub fn include_raw_sockets(mut self, include: bool) -> Self {
+        self.include_raw_sockets = include;
+        self
+    }
+
+    /// Keep at most `max` sockets in [Snapshot::sockets], setting [Snapshot::truncated] if there were more.
+    ///
+    /// Sockets are listed one process at a time and listing stops once there are enough, so a snapshot of a host with
+    /// very many sockets stays bounded in size. Every process is still listed.
+    pub fn max_results(mut self, max: usize) -> Self {
+        self.max_results = Some(max);
+        self
+    }
+}
+
+/// The sockets of every process, listed one process at a time as they are iterated, see [Snapshot::capture_sockets].
+///
+/// Each socket is listed once for each address family, with the process it belongs to in [PortInfo::pid].
+pub struct CapturedSockets {
+    sockets: Box<dyn Iterator<Item = PortInfo>>,
+    remaining: Option<usize>,
+    truncated: bool,
+}
+
+impl CapturedSockets {
+    fn new(sockets: Box<dyn Iterator<Item = PortInfo>>, max_results: Option<usize>) -> Self {
+        CapturedSockets {
+            sockets,
+            remaining: max_results,
+            truncated: false,
+        }
+    }
+
+    /// Whether sockets were left out because there were more than [CaptureOptions::max_results]. This is only known
+    /// once the iterator has returned `None`.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl CapturedSockets {
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    fn of(processes: &[ProcInfo], max_results: Option<usize>) -> ProcCtlResult<Self> {
+        let mut pids = processes.iter().map(|p| p.pid).collect::<Vec<_>>();
+        pids.sort_unstable();
+        let sockets = crate::port_query::PortQuery::ports_of_all(pids)?;
+        Ok(CapturedSockets::new(sockets, max_results))
+    }
+}
+
+impl Iterator for CapturedSockets {
+    type Item = PortInfo;
+
+    fn next(&mut self) -> Option<PortInfo> {
+        match &mut self.remaining {
+            Some(0) => {
+                // Look one socket further, to tell a limit which was reached from one which was exactly met
+                if !self.truncated && self.sockets.next().is_some() {
+                    self.truncated = true;
+                }
+                None
+            }
+            Some(remaining) => {
+                let socket = self.sockets.next()?;
+                *remaining -= 1;
+                Some(socket)
+            }
+            None => self.sockets.next(),
+        }
+    }
+}
+
+impl std::fmt::Debug for CapturedSockets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CapturedSockets")
+            .field("remaining", &self.remaining)
+            .field("truncated", &self.truncated)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Snapshot {
+    /// Take a snapshot of every process and socket on the running system, removing what `redaction` asks for.
+    ///
+    /// Sockets belonging to processes which can't be read, such as those of other users when not running with the
+    /// privileges to read them, are left out, as they would be from a query for those processes.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortQuery, Redaction, Snapshot};
+    ///
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let snapshot = Snapshot::capture(&Redaction::new().drop_env()).unwrap();
+    ///
+    /// let ports = PortQuery::new()
+    ///     .process_id(std::process::id())
+    ///     .execute_on(&snapshot)
+    ///     .unwrap();
+    /// assert!(ports.contains(&proc_ctl::ProtocolPort::Tcp(listener.local_addr().unwrap().port())));
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub fn capture(redaction: &Redaction) -> ProcCtlResult<Self> {
+        Snapshot::capture_with(&CaptureOptions::new().redact(redaction.clone()))
+    }
+
+    /// Take a snapshot like [Snapshot::capture], with more control over what is captured
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub fn capture_with(options: &CaptureOptions) -> ProcCtlResult<Self> {
+        let taken_at = SystemTime::now();
+        let processes = crate::proc_query::ProcQuery::new()
+            .with_env(!options.redaction.drop_env)
+            .list_processes()?;
+        let mut captured = CapturedSockets::of(&processes, options.max_results)?;
+        let sockets = captured.by_ref().collect();
+
+        #[cfg(target_os = "linux")]
+        let raw_sockets = if options.include_raw_sockets {
+            crate::raw_sockets::raw_sockets()?
+        } else {
+            Vec::new()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let raw_sockets = Vec::new();
+
+        let mut snapshot = Snapshot {
+            format_version: FORMAT_VERSION,
+            taken_at,
+            runtime_info: RuntimeInfo::current(),
+            processes,
+            sockets,
+            raw_sockets,
+            truncated: captured.truncated(),
+        };
+        snapshot.redact(&options.redaction);
+
+        Ok(snapshot)
+    }
+
+    /// List the sockets of every process without keeping them, so that a caller can stop at any point, for example
+    /// once it has found what it is looking for. Of the `options`, only [CaptureOptions::max_results] applies.
+    ///
+    /// Every process is listed first, then the sockets of each process are listed as the iterator reaches it, from one
+    /// snapshot of the socket tables.
+    ///
+    /// ```rust
+    /// use proc_ctl::{CaptureOptions, Snapshot};
+    ///
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let port = proc_ctl::ProtocolPort::Tcp(listener.local_addr().unwrap().port());
+    ///
+    /// let mut sockets = Snapshot::capture_sockets(&CaptureOptions::new()).unwrap();
+    /// assert!(sockets.any(|s| s.port == port && s.pid == std::process::id()));
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+    pub fn capture_sockets(options: &CaptureOptions) -> ProcCtlResult<CapturedSockets> {
+        let processes = crate::proc_query::ProcQuery::new()
+            .with_env(false)
+            .list_processes()?;
+        CapturedSockets::of(&processes, options.max_results)
+    }
+
+    /// Create a snapshot from tables which are already known, for use as a test fixture.
+    ///
+    /// It is marked as taken now, on the system this is running on.
+    pub fn from_tables(processes: Vec<ProcInfo>, sockets: Vec<PortInfo>) -> Self {
+        Snapshot {
+            format_version: FORMAT_VERSION,
+            taken_at: SystemTime::now(),
+            runtime_info: RuntimeInfo::current(),
+            processes,
+            sockets,
+            raw_sockets: Vec::new(),
+            truncated: false,
+        }
+    }
+
+    /// Remove what `redaction` asks for, for example from a snapshot which was saved without redacting it
+    pub fn redact(&mut self, redaction: &Redaction) {
+        for process in &mut self.processes {
+            redaction.apply(process);
+        }
+    }
+
+    /// Write the snapshot to a file at `path`, replacing it if it exists
+    pub fn save(&self, path: impl AsRef<Path>) -> ProcCtlResult<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, self).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    /// Read a snapshot written by [Snapshot::save].
+    ///
+    /// Fails with [ProcCtlError::ConfigurationError] if the snapshot was written in a format this release does not
+    /// support, for example by a newer release.
+    pub fn load(path: impl AsRef<Path>) -> ProcCtlResult<Self> {
+        let contents = std::fs::read(path)?;
+
+        // The version is read on its own first, so that a newer format is reported as such rather than as whichever
+        // field fails to parse
+        #[derive(serde::Deserialize)]
+        struct Version {
+            format_version: u32,
+        }
+        let version = serde_json::from_slice::<Version>(&contents)
+            .map_err(std::io::Error::from)?
+            .format_version;
+        if version != FORMAT_VERSION {
+            return Err(ProcCtlError::ConfigurationError(format!(
+                "snapshot format version {} is not supported, only version {} is",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        Ok(serde_json::from_slice(&contents).map_err(std::io::Error::from)?)
+    }
+
+    /// The process with this pid, if it was running
+    pub fn process(&self, pid: Pid) -> Option<&ProcInfo> {
+        self.processes.iter().find(|p| p.pid == pid)
+    }
+
+    /// The pids of the processes with this name, in ascending order. The name matches the name of a process, with or
+    /// without `.exe`, or the file name of its first argument, so that a snapshot from any platform can be queried.
+    pub(crate) fn pids_by_name(&self, name: &str) -> Vec<Pid> {
+        let name = name.strip_suffix(".exe").unwrap_or(name);
+        let mut pids = self
+            .processes
+            .iter()
+            .filter(|p| {
+                p.name.strip_suffix(".exe").unwrap_or(&p.name) == name
+                    || p.argv0.as_deref().is_some_and(|argv0| {
+                        let file_name = argv0.rsplit(['/', '\\']).next().unwrap_or(argv0);
+                        file_name.strip_suffix(".exe").unwrap_or(file_name) == name
+                    })
+            })
+            .map(|p| p.pid)
+            .collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids
+    }
+
+    /// The pids of the children of a process, their children and so on, in ascending order
+    pub(crate) fn descendant_pids(&self, pid: Pid) -> Vec<Pid> {
+        let mut pids = Vec::new();
+        let mut parents = vec![pid];
+        while let Some(parent) = parents.pop() {
+            for child in self.processes.iter().filter(|p| p.parent == Some(parent)) {
+                if child.pid != pid && !pids.contains(&child.pid) {
+                    pids.push(child.pid);
+                    parents.push(child.pid);
+                }
+            }
+        }
+        pids.sort_unstable();
+        pids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{AddressFamily, ProtocolPort};
+    use crate::{PortQuery, ProcCtlError};
+
+    fn snapshot() -> Snapshot {
+        Snapshot::from_tables(
+            vec![
+                ProcInfo::builder()
+                    .name("server")
+                    .pid(10)
+                    .cmd(["/opt/server", "--token", "secret"])
+                    .env(["TOKEN=secret"])
+                    .build(),
+                ProcInfo::builder()
+                    .name("worker.exe")
+                    .pid(11)
+                    .parent(10)
+                    .build(),
+                ProcInfo::builder()
+                    .name("python3")
+                    .pid(12)
+                    .cmd(["/usr/bin/python3", "app.py"])
+                    .argv0("/usr/local/bin/server")
+                    .build(),
+            ],
+            vec![
+                PortInfo::new(ProtocolPort::Tcp(8080), 10),
+                PortInfo::builder()
+                    .port(ProtocolPort::Tcp(8080))
+                    .family(AddressFamily::Ipv6)
+                    .pid(10)
+                    .build(),
+                PortInfo::new(ProtocolPort::Udp(5353), 11),
+                PortInfo::new(ProtocolPort::Tcp(9000), 12),
+                PortInfo::new(ProtocolPort::Tcp(22), 1),
+            ],
+        )
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("proc-ctl-snapshot-{}.json", std::process::id()));
+        let snapshot = snapshot();
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot.taken_at, loaded.taken_at);
+        assert_eq!(RuntimeInfo::current(), loaded.runtime_info);
+        assert_eq!(snapshot.sockets, loaded.sockets);
+        assert_eq!(
+            snapshot
+                .processes
+                .iter()
+                .map(|p| &p.cmd)
+                .collect::<Vec<_>>(),
+            loaded.processes.iter().map(|p| &p.cmd).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn newer_formats_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("proc-ctl-snapshot-v2-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"format_version": 2, "tables": {}}"#).unwrap();
+        let err = Snapshot::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(&err, ProcCtlError::ConfigurationError(msg) if msg.contains("version 2")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn captured_sockets_stop_at_the_limit() {
+        let listed = std::rc::Rc::new(std::cell::Cell::new(0));
+        let fake = |listed: std::rc::Rc<std::cell::Cell<u32>>| {
+            Box::new((0..1_000_000u32).map(move |n| {
+                listed.set(listed.get() + 1);
+                PortInfo::new(ProtocolPort::Tcp(n as u16), n / 10)
+            }))
+        };
+
+        let mut sockets = CapturedSockets::new(fake(listed.clone()), Some(100));
+        assert_eq!(100, sockets.by_ref().count());
+        assert!(sockets.truncated());
+        assert_eq!(101, listed.get());
+        assert_eq!(None, sockets.next());
+        assert_eq!(101, listed.get());
+
+        // Stopping early lists nothing further
+        listed.set(0);
+        let first = CapturedSockets::new(fake(listed.clone()), None)
+            .find(|s| s.pid == 3)
+            .unwrap();
+        assert_eq!(ProtocolPort::Tcp(30), first.port);
+        assert_eq!(31, listed.get());
+
+        let mut sockets = CapturedSockets::new(fake(listed.clone()), None);
+        assert_eq!(1_000_000, sockets.by_ref().count());
+        assert!(!sockets.truncated());
+
+        let mut sockets = CapturedSockets::new(fake(listed.clone()), Some(1_000_000));
+        assert_eq!(1_000_000, sockets.by_ref().count());
+        assert!(!sockets.truncated());
+    }
+
+    #[test]
+    fn redaction_removes_env_and_arguments() {
+        let mut snapshot = snapshot();
+        snapshot.redact(&Redaction::new().drop_env().truncate_cmd(1));
+
+        let server = snapshot.process(10).unwrap();
+        assert_eq!(vec!["/opt/server"], server.cmd);
+        assert_eq!(Some("/opt/server"), server.argv0.as_deref());
+        assert!(matches!(
+            server.env_var("TOKEN"),
+            Err(ProcCtlError::EnvNotCollected(10))
+        ));
+    }
+
+    #[test]
+    fn query_by_name_merges_families() {
+        let ports = PortQuery::new()
+            .process_name("server")
+            .on_multiple_matches(crate::MultipleMatchPolicy::Aggregate)
+            .execute_on(&snapshot())
+            .unwrap();
+        assert_eq!(
+            vec![ProtocolPort::Tcp(8080), ProtocolPort::Tcp(9000)],
+            ports
+        );
+
+        let err = PortQuery::new()
+            .process_name("server")
+            .execute_on(&snapshot())
+            .unwrap_err();
+        assert!(
+            matches!(err, ProcCtlError::MultipleMatchingProcesses(pids) if pids == vec![10, 12])
+        );
+    }
+
+    #[test]
+    fn query_with_children_and_filters() {
+        let query = PortQuery::new().process_id(10).include_children(true);
+        assert_eq!(
+            vec![ProtocolPort::Tcp(8080), ProtocolPort::Udp(5353)],
+            query.execute_on(&snapshot()).unwrap()
+        );
+
+        let ports = PortQuery::new()
+            .process_id(10)
+            .ip_v6_only()
+            .execute_detailed_on(&snapshot())
+            .unwrap();
+        assert_eq!(vec![AddressFamily::Ipv6], ports[0].families);
+
+        assert_eq!(
+            vec![ProtocolPort::Udp(5353)],
+            PortQuery::new()
+                .process_name("worker")
+                .execute_on(&snapshot())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn query_with_children_includes_the_whole_tree_once() {
+        let snapshot = Snapshot::from_tables(
+            vec![
+                ProcInfo::builder().name("server").pid(10).build(),
+                ProcInfo::builder()
+                    .name("worker")
+                    .pid(11)
+                    .parent(10)
+                    .build(),
+                ProcInfo::builder()
+                    .name("helper")
+                    .pid(12)
+                    .parent(11)
+                    .build(),
+            ],
+            vec![
+                PortInfo::new(ProtocolPort::Tcp(8080), 10),
+                PortInfo::new(ProtocolPort::Tcp(8080), 11),
+                PortInfo::new(ProtocolPort::Udp(5353), 12),
+            ],
+        );
+
+        let ports = PortQuery::new()
+            .process_id(10)
+            .include_children(true)
+            .expect_min_num_ports(2)
+            .execute_detailed_on(&snapshot)
+            .unwrap();
+        assert_eq!(
+            vec![(ProtocolPort::Tcp(8080), 10), (ProtocolPort::Udp(5353), 12)],
+            ports.iter().map(|p| (p.port, p.pid)).collect::<Vec<_>>()
+        );
+
+        assert!(matches!(
+            PortQuery::new()
+                .process_id(10)
+                .include_children(true)
+                .expect_min_num_ports(3)
+                .execute_on(&snapshot),
+            Err(ProcCtlError::TooFewPorts(_, 3))
+        ));
+    }
+
+    #[test]
+    fn query_checks_expectations() {
+        let err = PortQuery::new()
+            .process_id(10)
+            .expect_min_num_ports(2)
+            .execute_on(&snapshot())
+            .unwrap_err();
+        assert!(matches!(err, ProcCtlError::TooFewPorts(_, 2)));
+
+        assert!(matches!(
+            PortQuery::new().process_id(99).execute_on(&snapshot()),
+            Err(ProcCtlError::ProcessNotFound(99))
+        ));
+        assert!(matches!(
+            PortQuery::new()
+                .process_id(10)
+                .verify_accepting(true)
+                .execute_on(&snapshot()),
+            Err(ProcCtlError::ConfigurationError(_))
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+//! A minimal client for the Linux `NETLINK_SOCK_DIAG` interface.
+//!
+//! This reports some socket details which `/proc/net` does not, such as the configured backlog of a listening
+//! socket. With the `netlink` feature it also replaces `/proc/<pid>/net` for listing the TCP and UDP sockets, since
+//! the kernel can filter them by state rather than formatting every socket as text. Messages are built and parsed by
+//! hand so no netlink dependency is needed, see `linux/inet_diag.h`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "netlink")]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HDR_LEN: usize = 16;
+const INET_DIAG_REQ_V2_LEN: usize = 56;
+const INET_DIAG_MSG_LEN: usize = 72;
+const TCP_LISTEN: u32 = 10;
+
+/// The queue lengths of a listening socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ListenQueue {
+    /// Connections waiting to be accepted
+    pub(crate) current: u32,
+    /// The maximum number of connections which can wait to be accepted
+    pub(crate) backlog: u32,
+}
+
+/// A TCP or UDP socket from the tables of a network namespace, read from `/proc/<pid>/net` or with sock_diag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InetSocket {
+    pub(crate) local_address: SocketAddr,
+    /// The state as the kernel numbers it for TCP, which UDP sockets are also given
+    pub(crate) state: u8,
+    /// The read queue, which for a listening TCP socket is the number of connections waiting to be accepted
+    pub(crate) rx_queue: u32,
+    pub(crate) inode: u64,
+}
+
+/// Find every socket of `family` and `protocol` visible to this process which is in one of `states`, a bit mask of
+/// the kernel's TCP state numbers
+#[cfg(feature = "netlink")]
+pub(crate) fn inet_sockets(family: i32, protocol: i32, states: u32) -> io::Result<Vec<InetSocket>> {
+    let socket = open()?;
+
+    send_dump_request(&socket, family as u8, protocol as u8, states)?;
+    let mut sockets = Vec::new();
+    receive_dump(&socket, |msg| {
+        sockets.push(InetSocket {
+            local_address: local_address(msg),
+            state: msg[1],
+            rx_queue: read_u32(msg, 56),
+            inode: read_u32(msg, 68) as u64,
+        })
+    })?;
+
+    Ok(sockets)
+}
+
+/// The local address from the socket id of a `struct inet_diag_msg`, where the port is in network byte order and an
+/// IPv4 address is the start of the address field
+#[cfg(feature = "netlink")]
+fn local_address(msg: &[u8]) -> SocketAddr {
+    let port = u16::from_be_bytes([msg[4], msg[5]]);
+    let mut addr = [0u8; 16];
+    addr.copy_from_slice(&msg[8..24]);
+
+    if msg[0] == libc::AF_INET as u8 {
+        SocketAddr::new(
+            Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).into(),
+            port,
+        )
+    } else {
+        SocketAddr::new(Ipv6Addr::from(addr).into(), port)
+    }
+}
+
+/// Find the queue lengths of every listening TCP socket visible to this process, keyed by socket inode
+pub(crate) fn tcp_listen_queues() -> io::Result<HashMap<u64, ListenQueue>> {
+    let socket = open()?;
+
+    let mut queues = HashMap::new();
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        send_dump_request(
+            &socket,
+            family as u8,
+            libc::IPPROTO_TCP as u8,
+            1 << TCP_LISTEN,
+        )?;
+        receive_dump(&socket, |msg| {
+            // For listening sockets the kernel reports the accept queue as the read queue and the backlog as the
+            // write queue
+            queues.insert(
+                read_u32(msg, 68) as u64,
+                ListenQueue {
+                    current: read_u32(msg, 56),
+                    backlog: read_u32(msg, 60),
+                },
+            );
+        })?;
+    }
+
+    Ok(queues)
+}
+
+fn open() -> io::Result<OwnedFd> {
+    // SAFETY: socket has no memory safety requirements, and the result is checked before it is used as an fd
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a newly opened socket which nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn send_dump_request(socket: &OwnedFd, family: u8, protocol: u8, states: u32) -> io::Result<()> {
+    let len = NLMSG_HDR_LEN + INET_DIAG_REQ_V2_LEN;
+    let mut req = Vec::with_capacity(len);
+
+    // struct nlmsghdr
+    req.extend_from_slice(&(len as u32).to_ne_bytes());
+    req.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    req.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    req.extend_from_slice(&0u32.to_ne_bytes());
+    req.extend_from_slice(&0u32.to_ne_bytes());
+
+    // struct inet_diag_req_v2, with an empty socket id to match every socket
+    req.extend_from_slice(&[family, protocol, 0, 0]);
+    req.extend_from_slice(&states.to_ne_bytes());
+    req.resize(len, 0);
+
+    // SAFETY: the buffer is valid for reads of its whole length
+    let sent = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Read responses until the end of the dump, calling `on_msg` with the body of each `struct inet_diag_msg`
+fn receive_dump(socket: &OwnedFd, mut on_msg: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        // SAFETY: the buffer is valid for writes of its whole length
+        let received = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut data = &buf[..received as usize];
+        while data.len() >= NLMSG_HDR_LEN {
+            let msg_len = read_u32(data, 0) as usize;
+            let msg_type = u16::from_ne_bytes([data[4], data[5]]);
+            if msg_len < NLMSG_HDR_LEN || msg_len > data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated sock_diag response",
+                ));
+            }
+
+            match msg_type {
+                NLMSG_DONE => return Ok(()),
+                NLMSG_ERROR => {
+                    let errno = data
+                        .get(NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4)
+                        .map(|b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                        .unwrap_or(0);
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                SOCK_DIAG_BY_FAMILY if msg_len >= NLMSG_HDR_LEN + INET_DIAG_MSG_LEN => {
+                    on_msg(&data[NLMSG_HDR_LEN..msg_len])
+                }
+                _ => {}
+            }
+
+            // Messages are padded to a 4 byte boundary
+            let aligned = (msg_len + 3) & !3;
+            data = &data[aligned.min(data.len())..];
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
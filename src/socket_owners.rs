@@ -0,0 +1,199 @@
+//! Finding every process which holds a socket, read from `/proc` on Linux.
+//!
+//! A socket stays open for as long as any process has a file descriptor for it, so a socket which is inherited across
+//! `fork`, as pre-fork servers do with their listener, is held by the parent and every worker at once. Only processes
+//! whose file descriptors can be read are found, which usually means those belonging to the same user.
+
+use crate::types::Pid;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// The processes holding a socket which is held by more than one process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SharedSocket {
+    /// Every process holding the socket, in ascending order
+    pub(crate) pids: Vec<Pid>,
+    /// The process treated as the main owner of the socket, see [primary_owner]
+    pub(crate) primary: Pid,
+}
+
+/// The holder of a socket, with what is needed to choose the primary owner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Holder {
+    pid: Pid,
+    parent: Option<Pid>,
+    start_time: u64,
+}
+
+/// Find which of `inodes` are held by more than one process, keyed by socket inode
+pub(crate) fn shared_sockets(inodes: &HashSet<u64>) -> HashMap<u64, SharedSocket> {
+    holders_of(inodes)
+        .into_iter()
+        .filter(|(_, pids)| pids.len() > 1)
+        .map(|(inode, pids)| {
+            let primary = primary_owner(&pids.iter().map(|pid| holder(*pid)).collect::<Vec<_>>());
+            (inode, SharedSocket { pids, primary })
+        })
+        .collect()
+}
+
+/// The most sockets whose holders are tracked at once, so that finding the holders uses a bounded amount of memory
+/// however many sockets the host has
+#[cfg(all(feature = "serde", feature = "proc"))]
+const MAX_TRACKED_SOCKETS: usize = 1 << 18;
+
+/// Find the processes holding each socket, keyed by socket inode.
+///
+/// At most [MAX_TRACKED_SOCKETS] sockets are tracked. Once there are that many, the holders of sockets which are
+/// already tracked are still found, but sockets seen for the first time after that are left out. A shared socket
+/// which is left out is treated as though it were held only by the process it was found for.
+#[cfg(all(feature = "serde", feature = "proc"))]
+pub(crate) fn socket_holders() -> HashMap<u64, Vec<Pid>> {
+    track_holders(socket_fds(), MAX_TRACKED_SOCKETS)
+}
+
+/// Find the processes holding each of `inodes`, in ascending order. A socket which no readable process holds is left
+/// out.
+pub(crate) fn holders_of(inodes: &HashSet<u64>) -> HashMap<u64, Vec<Pid>> {
+    let mut holders = track_holders(
+        socket_fds().filter(|(_, inode)| inodes.contains(inode)),
+        inodes.len(),
+    );
+    for pids in holders.values_mut() {
+        pids.sort_unstable();
+    }
+
+    holders
+}
+
+/// The inodes of the sockets held by each of `pids`. Processes which can't be read are skipped.
+pub(crate) fn socket_inodes_of(pids: &[Pid]) -> HashSet<u64> {
+    pids.iter()
+        .filter_map(|pid| {
+            let pid = crate::pid::to_procfs(*pid).ok()?;
+            procfs::process::Process::new(pid).ok()
+        })
+        .flat_map(|process| socket_fds_of(&process).map(|(_, inode)| inode))
+        .collect()
+}
+
+/// Every socket descriptor of every process which can be read, as the pid and the socket inode, in order of process
+fn socket_fds() -> impl Iterator<Item = (Pid, u64)> {
+    // Processes can exit while they are being read, and those of other users can't be read at all
+    procfs::process::all_processes()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .flat_map(|process| socket_fds_of(&process))
+}
+
+/// The socket descriptors of `process`, as its pid and the socket inode
+fn socket_fds_of(process: &procfs::process::Process) -> impl Iterator<Item = (Pid, u64)> {
+    let pid = process.pid() as Pid;
+    process
+        .fd()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(move |fd| match fd.target {
+            procfs::process::FDTarget::Socket(inode) => Some((pid, inode)),
+            _ => None,
+        })
+}
+
+/// Collect the holders of each socket from the socket descriptors of every process, given in order of process, tracking
+/// at most `limit` sockets
+fn track_holders(
+    fds: impl IntoIterator<Item = (Pid, u64)>,
+    limit: usize,
+) -> HashMap<u64, Vec<Pid>> {
+    let mut holders: HashMap<u64, Vec<Pid>> = HashMap::new();
+    for (pid, inode) in fds {
+        let tracked = holders.len();
+        let pids = match holders.entry(inode) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if tracked < limit => entry.insert(Vec::new()),
+            Entry::Vacant(_) => continue,
+        };
+
+        // A process may hold several descriptors for the same socket, which are always read together
+        if pids.last() != Some(&pid) {
+            pids.push(pid);
+        }
+    }
+
+    holders
+}
+
+fn holder(pid: Pid) -> Holder {
+    let stat = crate::pid::to_procfs(pid)
+        .ok()
+        .and_then(|pid| procfs::process::Process::new(pid).ok()?.stat().ok());
+
+    Holder {
+        pid,
+        parent: stat.as_ref().map(|s| s.ppid as Pid),
+        start_time: stat.map_or(u64::MAX, |s| s.starttime),
+    }
+}
+
+/// Choose the main owner of a shared socket, which is the process it was passed down from.
+///
+/// That is the holder whose parent does not also hold the socket. If there are several, for example because the
+/// socket was passed between unrelated processes, the oldest is chosen, then the lowest pid.
+fn primary_owner(holders: &[Holder]) -> Pid {
+    let is_holder = |pid: Option<Pid>| pid.is_some_and(|pid| holders.iter().any(|h| h.pid == pid));
+
+    holders
+        .iter()
+        .min_by_key(|h| (is_holder(h.parent), h.start_time, h.pid))
+        .map_or(0, |h| h.pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holder(pid: Pid, parent: Pid, start_time: u64) -> Holder {
+        Holder {
+            pid,
+            parent: Some(parent),
+            start_time,
+        }
+    }
+
+    #[test]
+    fn the_process_which_forked_the_others_is_primary() {
+        // Workers started in the same clock tick as their parent, with a wrapped pid
+        assert_eq!(
+            500,
+            primary_owner(&[holder(20, 500, 7), holder(21, 500, 7), holder(500, 1, 7)])
+        );
+    }
+
+    #[test]
+    fn unrelated_holders_fall_back_to_the_oldest() {
+        assert_eq!(30, primary_owner(&[holder(20, 1, 9), holder(30, 1, 8)]));
+        assert_eq!(20, primary_owner(&[holder(20, 1, 8), holder(30, 1, 8)]));
+    }
+
+    #[test]
+    fn tracked_sockets_are_bounded() {
+        // A million descriptors, two for each socket, then a fork which inherits the first socket
+        let fds = (0..1_000_000u64)
+            .map(|n| (n as Pid / 2 + 100, n / 2))
+            .chain([(1, 0)]);
+        let holders = track_holders(fds, 1000);
+
+        assert_eq!(1000, holders.len());
+        assert_eq!(Some(&vec![100, 1]), holders.get(&0));
+        assert_eq!(Some(&vec![1099]), holders.get(&999));
+        assert_eq!(None, holders.get(&1000));
+    }
+
+    #[test]
+    fn a_cycle_of_holders_still_has_a_primary() {
+        // Pids are reused, so a stale parent can point back into the holders
+        assert_eq!(20, primary_owner(&[holder(20, 30, 8), holder(30, 20, 9)]));
+    }
+}
@@ -0,0 +1,240 @@
+//! Giving up on a retry early when its expectation looks like it can never be met, see
+//! [crate::PortQuery::fail_fast_on_stall].
+//!
+//! A process which is still starting binds its sockets over time, so a retry which finds too few ports should usually
+//! keep going. A process which has settled keeps the same sockets from one attempt to the next, and if those didn't
+//! include enough matching ports before, they won't the next time either. A process which hasn't bound anything yet is
+//! never taken to have settled, since it may not have got as far as binding.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::types::{AddressFamily, Pid, ProtocolPort};
+
+/// What the query and the process looked like after one attempt which found too few ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Observation {
+    /// The ports which matched the query
+    pub(crate) found: Vec<ProtocolPort>,
+    /// Every socket of the selected processes, whether or not it matched, in ascending order
+    pub(crate) sockets: Vec<(ProtocolPort, AddressFamily)>,
+}
+
+/// Counts the attempts in a row which have seen the same thing
+#[derive(Debug)]
+pub(crate) struct StallDetector {
+    attempts: usize,
+    last: Option<Observation>,
+    repeated: usize,
+}
+
+impl StallDetector {
+    /// Detect a stall once `attempts` in a row have seen the same thing. A limit of zero is treated as one.
+    pub(crate) fn new(attempts: usize) -> Self {
+        StallDetector {
+            attempts: attempts.max(1),
+            last: None,
+            repeated: 0,
+        }
+    }
+
+    /// Record an attempt which found too few ports, returning whether the process now looks like it has settled
+    pub(crate) fn observe(&mut self, observation: Observation) -> bool {
+        if observation.sockets.is_empty() {
+            self.reset();
+            return false;
+        }
+
+        if self.last.as_ref() == Some(&observation) {
+            self.repeated += 1;
+        } else {
+            self.last = Some(observation);
+            self.repeated = 1;
+        }
+
+        self.repeated >= self.attempts
+    }
+
+    /// Forget what was seen, after an attempt which failed for some other reason
+    pub(crate) fn reset(&mut self) {
+        self.last = None;
+        self.repeated = 0;
+    }
+
+    /// How many attempts in a row have seen the same thing
+    pub(crate) fn repeated(&self) -> usize {
+        self.repeated
+    }
+}
+
+/// Decide whether a retry should give up after `e`, returning the error to give up with.
+///
+/// `exited` is the pid to report when the process is gone for good, which is only the case for a process selected by
+/// pid. `sockets` lists every socket of the selected processes, and is only called when too few ports were found.
+pub(crate) fn give_up(
+    e: &ProcCtlError,
+    exited: Option<Pid>,
+    detector: &mut StallDetector,
+    sockets: impl FnOnce() -> ProcCtlResult<Vec<(ProtocolPort, AddressFamily)>>,
+) -> Option<ProcCtlError> {
+    match e {
+        _ if e.kind() == crate::error::ErrorKind::ProcessNotFound => {
+            detector.reset();
+            exited.map(ProcCtlError::ProcessExited)
+        }
+        ProcCtlError::TooFewPorts(found, expected) => {
+            let Ok(mut sockets) = sockets() else {
+                detector.reset();
+                return None;
+            };
+            sockets.sort_unstable();
+
+            let observation = Observation {
+                found: found.clone(),
+                sockets,
+            };
+            detector.observe(observation).then(|| {
+                ProcCtlError::StalledExpectation(found.clone(), *expected, detector.repeated())
+            })
+        }
+        _ => {
+            detector.reset();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(ports: &[u16]) -> Vec<(ProtocolPort, AddressFamily)> {
+        ports
+            .iter()
+            .map(|p| (ProtocolPort::Tcp(*p), AddressFamily::Ipv4))
+            .collect()
+    }
+
+    /// An attempt which found `found` of the TCP ports the process has bound, which are `sockets`
+    fn seen(found: &[u16], sockets: &[u16]) -> Observation {
+        Observation {
+            found: found.iter().copied().map(ProtocolPort::Tcp).collect(),
+            sockets: tcp(sockets),
+        }
+    }
+
+    /// Give each attempt of a scripted retry to a detector, returning the attempt which gave up and its error, if any
+    /// did
+    fn run(attempts: usize, script: &[Observation]) -> Option<(usize, ProcCtlError)> {
+        let mut detector = StallDetector::new(attempts);
+        script.iter().enumerate().find_map(|(attempt, seen)| {
+            let e = ProcCtlError::TooFewPorts(seen.found.clone(), 2);
+            give_up(&e, None, &mut detector, || Ok(seen.sockets.clone())).map(|e| (attempt + 1, e))
+        })
+    }
+
+    #[test]
+    fn stuck_at_one_port_forever_gives_up() {
+        let script = vec![seen(&[8080], &[8080]); 10];
+
+        let (attempt, e) = run(3, &script).unwrap();
+        assert_eq!(3, attempt);
+        assert!(
+            matches!(&e, ProcCtlError::StalledExpectation(found, 2, 3) if found == &vec![ProtocolPort::Tcp(8080)]),
+            "{:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn filters_which_exclude_every_socket_give_up() {
+        // Such as udp_only against a server which only binds TCP
+        let script = vec![seen(&[], &[8080, 8081]); 10];
+
+        assert_eq!(4, run(4, &script).unwrap().0);
+    }
+
+    #[test]
+    fn gradual_startup_never_gives_up() {
+        let mut script = Vec::new();
+        for bound in 0..6u16 {
+            let ports = (0..bound).map(|p| 8080 + p).collect::<Vec<_>>();
+            // Each step of the startup is seen by fewer attempts than it takes to give up
+            script.extend([seen(&ports, &ports), seen(&ports, &ports)]);
+        }
+
+        assert!(run(3, &script).is_none());
+    }
+
+    #[test]
+    fn sockets_which_keep_changing_never_give_up() {
+        // The matching ports are the same, but the process is still binding others
+        let script = (0..10)
+            .map(|n| seen(&[8080], &[8080, 9000 + n]))
+            .collect::<Vec<_>>();
+
+        assert!(run(2, &script).is_none());
+    }
+
+    #[test]
+    fn a_process_with_no_sockets_has_not_settled() {
+        let script = vec![seen(&[], &[]); 10];
+
+        assert!(run(2, &script).is_none());
+    }
+
+    #[test]
+    fn other_failures_start_the_count_again() {
+        let mut detector = StallDetector::new(2);
+        let too_few = ProcCtlError::TooFewPorts(vec![], 1);
+        let sockets = || Ok(tcp(&[8080]));
+
+        assert!(give_up(&too_few, None, &mut detector, sockets).is_none());
+        assert!(give_up(
+            &ProcCtlError::WouldBlock("process list".to_string()),
+            None,
+            &mut detector,
+            sockets
+        )
+        .is_none());
+        assert!(give_up(&too_few, None, &mut detector, sockets).is_none());
+        assert!(give_up(&too_few, None, &mut detector, sockets).is_some());
+
+        // Sockets which can't be listed can't be compared
+        detector.reset();
+        assert!(give_up(&too_few, None, &mut detector, sockets).is_none());
+        assert!(give_up(&too_few, None, &mut detector, || Err(
+            ProcCtlError::WouldBlock("process list".to_string())
+        ))
+        .is_none());
+        assert!(give_up(&too_few, None, &mut detector, sockets).is_none());
+    }
+
+    #[test]
+    fn a_process_selected_by_pid_which_is_gone_gives_up() {
+        let mut detector = StallDetector::new(5);
+        let listed = std::cell::Cell::new(false);
+        let sockets = || {
+            listed.set(true);
+            Ok(vec![])
+        };
+
+        assert!(matches!(
+            give_up(
+                &ProcCtlError::ProcessNotFound(10),
+                Some(10),
+                &mut detector,
+                sockets
+            ),
+            Some(ProcCtlError::ProcessExited(10))
+        ));
+        assert!(!listed.get());
+
+        // A process selected by name may still start
+        assert!(give_up(
+            &ProcCtlError::NoMatchingProcess("server".to_string()),
+            None,
+            &mut detector,
+            sockets
+        )
+        .is_none());
+    }
+}
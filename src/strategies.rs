@@ -0,0 +1,348 @@
+//! [proptest] strategies which generate ports, processes and the configs of the queries which filter them.
+//!
+//! The values are drawn from small pools of ports, pids and names, so that generated queries often match some of the
+//! generated ports and processes rather than almost never. Pair them with [crate::invariants] to check that a config
+//! is filtered consistently, or with [crate::port_filters::apply] and [crate::proc_filters::apply] to check properties
+//! of your own.
+//!
+//! ```rust
+//! use proc_ctl::strategies::{port_infos, port_query_config};
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn filters_are_consistent(config in port_query_config(), ports in port_infos()) {
+//!         proc_ctl::invariants::check_port_filters(&config, &ports)?;
+//!     }
+//! }
+//! ```
+
+use crate::config::{PortQueryConfig, Protocol};
+use crate::types::{AddressFamily, Pid, Port, PortInfo, ProtocolPort, TcpState};
+use proptest::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+/// Every [TcpState], in the order they are declared
+pub const TCP_STATES: [TcpState; 11] = [
+    TcpState::Listen,
+    TcpState::SynSent,
+    TcpState::SynReceived,
+    TcpState::Established,
+    TcpState::FinWait1,
+    TcpState::FinWait2,
+    TcpState::CloseWait,
+    TcpState::Closing,
+    TcpState::LastAck,
+    TcpState::TimeWait,
+    TcpState::Closed,
+];
+
+/// The ports most values are drawn from
+const PORTS: [Port; 6] = [0, 22, 80, 443, 5353, 8080];
+
+/// A port number, usually one of a handful of well known ports
+pub fn port() -> impl Strategy<Value = Port> {
+    prop_oneof![
+        4 => proptest::sample::select(PORTS.to_vec()),
+        1 => any::<Port>(),
+    ]
+}
+
+/// A TCP or UDP port
+pub fn protocol_port() -> impl Strategy<Value = ProtocolPort> {
+    (any::<bool>(), port()).prop_map(|(tcp, port)| {
+        if tcp {
+            ProtocolPort::Tcp(port)
+        } else {
+            ProtocolPort::Udp(port)
+        }
+    })
+}
+
+/// A pid from 1 to 8
+pub fn pid() -> impl Strategy<Value = Pid> {
+    1..=8u32
+}
+
+/// An address family
+pub fn address_family() -> impl Strategy<Value = AddressFamily> {
+    prop_oneof![Just(AddressFamily::Ipv4), Just(AddressFamily::Ipv6)]
+}
+
+/// A TCP state
+pub fn tcp_state() -> impl Strategy<Value = TcpState> {
+    proptest::sample::select(TCP_STATES.to_vec())
+}
+
+/// An address of `family` which a socket could be bound to: unspecified, loopback or another address
+fn bind_address(family: AddressFamily) -> impl Strategy<Value = IpAddr> {
+    let (unspecified, loopback, other): (IpAddr, IpAddr, IpAddr) = match family {
+        AddressFamily::Ipv4 => (
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(192, 168, 1, 10).into(),
+        ),
+        AddressFamily::Ipv6 => (
+            Ipv6Addr::UNSPECIFIED.into(),
+            Ipv6Addr::LOCALHOST.into(),
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x10).into(),
+        ),
+    };
+    proptest::sample::select(vec![unspecified, loopback, other])
+}
+
+/// A socket of one address family, as a query finds it with [crate::PortQuery::split_families] set and as a
+/// [crate::Snapshot] captures it.
+///
+/// A TCP port is given a state, or none as if captured by an earlier release, and a UDP port never has one. Which
+/// details are known varies, as it does between platforms.
+pub fn port_info() -> impl Strategy<Value = PortInfo> {
+    (protocol_port(), address_family(), pid()).prop_flat_map(|(port, family, pid)| {
+        let tcp = matches!(port, ProtocolPort::Tcp(_));
+        (
+            proptest::option::of(bind_address(family)),
+            proptest::option::of(tcp_state()),
+            proptest::option::of(0..10_000u64),
+            proptest::option::of(0..512u32),
+            proptest::option::of(proptest::collection::vec(self::pid(), 0..3)),
+        )
+            .prop_map(move |(address, state, bound_secs, backlog, shared_with)| {
+                let mut builder = PortInfo::builder().port(port).family(family).pid(pid);
+                if let Some(address) = address {
+                    builder = builder.local_addr(SocketAddr::new(address, port.port()));
+                }
+                if let Some(state) = state.filter(|_| tcp) {
+                    builder = builder.tcp_state(state);
+                }
+                if let Some(secs) = bound_secs {
+                    builder =
+                        builder.bound_since(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                if let Some(backlog) = backlog.filter(|_| tcp) {
+                    builder = builder.backlog(backlog);
+                }
+                if let Some(mut shared_with) = shared_with {
+                    shared_with.sort_unstable();
+                    shared_with.dedup();
+                    shared_with.retain(|p| *p != pid);
+                    builder = builder.shared_with(shared_with);
+                }
+                builder.build()
+            })
+    })
+}
+
+/// Up to 24 sockets, as [port_info] generates them
+pub fn port_infos() -> impl Strategy<Value = Vec<PortInfo>> {
+    proptest::collection::vec(port_info(), 0..24)
+}
+
+/// A config which filters ports and sets expectations on them, but doesn't select processes, verify that ports accept
+/// connections or limit tools, none of which apply to ports which were already found.
+///
+/// Some configs can't match anything, such as one with an empty port range, which [crate::port_filters::apply] fails
+/// with [crate::ProcCtlError::ConfigurationError].
+pub fn port_query_config() -> impl Strategy<Value = PortQueryConfig> {
+    (
+        proptest::option::of(prop_oneof![Just(Protocol::Tcp), Just(Protocol::Udp)]),
+        proptest::option::of(address_family()),
+        proptest::option::of(proptest::collection::vec(tcp_state(), 0..4)),
+        proptest::option::of(port()),
+        proptest::option::of(port()),
+        proptest::option::of(any::<bool>()),
+        any::<bool>(),
+        any::<bool>(),
+        (
+            proptest::option::of(0..6usize),
+            proptest::option::of(0..6usize),
+            proptest::option::of(proptest::collection::vec(protocol_port(), 0..3)),
+            proptest::option::of(0..512u32),
+            proptest::option::of(proptest::collection::vec(protocol_port(), 0..4)),
+        ),
+    )
+        .prop_map(
+            |(
+                protocol,
+                family,
+                tcp_states,
+                min_port,
+                max_port,
+                loopback,
+                split_families,
+                sorted,
+                (min_num_ports, max_num_ports, expected, backlog, allowed),
+            )| PortQueryConfig {
+                protocol,
+                family,
+                tcp_states,
+                min_port,
+                max_port,
+                loopback,
+                split_families,
+                sorted,
+                expect_min_num_ports: min_num_ports,
+                expect_max_num_ports: max_num_ports,
+                expect_ports: expected,
+                expect_backlog_at_least: backlog,
+                forbid_ports_except: allowed,
+                ..PortQueryConfig::default()
+            },
+        )
+}
+
+/// A config from [port_query_config], along with a copy which narrows one of its filters, such as by restricting it
+/// to one protocol or raising the lowest port. The narrower config never matches a port which the first doesn't.
+pub fn narrowed_port_query_config() -> impl Strategy<Value = (PortQueryConfig, PortQueryConfig)> {
+    (
+        port_query_config(),
+        0..5usize,
+        protocol_port(),
+        address_family(),
+        any::<bool>(),
+    )
+        .prop_map(|(config, filter, port, family, loopback)| {
+            let mut narrow = config.clone();
+            match filter {
+                0 => {
+                    narrow.protocol = config.protocol.or(Some(match port {
+                        ProtocolPort::Tcp(_) => Protocol::Tcp,
+                        ProtocolPort::Udp(_) => Protocol::Udp,
+                    }))
+                }
+                1 => narrow.family = config.family.or(Some(family)),
+                2 => {
+                    let states = config.tcp_states.clone().unwrap_or(vec![TcpState::Listen]);
+                    narrow.tcp_states = Some(states.into_iter().skip(1).collect());
+                }
+                3 => {
+                    narrow.min_port = Some(config.min_port.unwrap_or(0).max(port.port()));
+                    narrow.max_port = config.max_port;
+                }
+                _ => narrow.loopback = Some(config.loopback.unwrap_or(loopback)),
+            }
+            (config, narrow)
+        })
+}
+
+#[cfg(feature = "proc")]
+pub use self::processes::*;
+
+#[cfg(feature = "proc")]
+mod processes {
+    use super::pid;
+    use crate::config::ProcQueryConfig;
+    use crate::proc_query::ProcInfo;
+    use crate::types::Pid;
+    use proptest::prelude::*;
+
+    /// The names most values are drawn from
+    const NAMES: [&str; 4] = ["server", "server.exe", "worker", "supervisor"];
+
+    /// The capabilities most values are drawn from
+    const CAPABILITIES: [&str; 2] = ["CAP_NET_BIND_SERVICE", "CAP_NET_RAW"];
+
+    /// A process name, usually one of a handful, with or without `.exe`
+    pub fn process_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            4 => proptest::sample::select(NAMES.to_vec()).prop_map(str::to_string),
+            1 => "[a-z]{1,8}",
+        ]
+    }
+
+    /// A process as a [crate::Snapshot] captures it, with some of the details a query can filter on
+    pub fn proc_info() -> impl Strategy<Value = ProcInfo> {
+        (
+            pid(),
+            process_name(),
+            proptest::option::of(pid()),
+            proptest::option::of("[0-9a-f]{4,12}"),
+            proptest::option::of(any::<u64>()),
+            proptest::option::of((
+                proptest::option::of(0..4096u64),
+                proptest::option::of(0..4096u64),
+            )),
+        )
+            .prop_map(
+                |(pid, name, parent, container_id, capabilities, open_files)| {
+                    let mut builder = ProcInfo::builder().pid(pid).name(name.as_str());
+                    builder = builder.cmd([format!("/usr/bin/{}", name), "--serve".to_string()]);
+                    if let Some(parent) = parent.filter(|parent| *parent != pid) {
+                        builder = builder.parent(parent);
+                    }
+                    if let Some(container_id) = container_id {
+                        builder = builder.container_id(container_id);
+                    }
+                    if let Some(mask) = capabilities {
+                        builder = builder.capabilities(crate::Capabilities::new(mask, mask));
+                    }
+                    if let Some((soft, hard)) = open_files {
+                        let unknown = crate::Limit::new(None, None);
+                        builder = builder.limits(crate::ProcLimits::new(
+                            crate::Limit::new(soft, hard),
+                            unknown,
+                            unknown,
+                        ));
+                    }
+                    builder.build()
+                },
+            )
+    }
+
+    /// Up to 12 processes, as [proc_info] generates them, with different pids
+    pub fn proc_infos() -> impl Strategy<Value = Vec<ProcInfo>> {
+        proptest::collection::vec(proc_info(), 0..12).prop_map(|mut processes| {
+            let mut seen = Vec::<Pid>::new();
+            processes.retain(|p| {
+                let new = !seen.contains(&p.pid);
+                seen.push(p.pid);
+                new
+            });
+            processes
+        })
+    }
+
+    /// A config which filters processes and sets expectations on them, leaving out what can only be collected from a
+    /// running process, such as the environment
+    pub fn proc_query_config() -> impl Strategy<Value = ProcQueryConfig> {
+        (
+            proptest::option::of(pid()),
+            proptest::option::of(process_name()),
+            proptest::option::of(process_name()),
+            any::<bool>(),
+            proptest::option::of(any::<bool>()),
+            proptest::option::of("[0-9a-f]{0,2}"),
+            proptest::collection::vec(
+                proptest::sample::select(CAPABILITIES.to_vec()).prop_map(str::to_string),
+                0..2,
+            ),
+            (
+                proptest::option::of(0..4096u64),
+                proptest::option::of(0..4096u64),
+            ),
+        )
+            .prop_map(
+                |(
+                    process_id,
+                    process_name,
+                    parent_name,
+                    match_canonical_names,
+                    in_container,
+                    container_id_prefix,
+                    has_capability,
+                    (nofile_at_least, expect_nofile_at_least),
+                )| ProcQueryConfig {
+                    process_id,
+                    process_name,
+                    parent_name,
+                    match_canonical_names,
+                    in_container,
+                    container_id_prefix,
+                    has_capability,
+                    nofile_at_least,
+                    expect_nofile_at_least,
+                    ..ProcQueryConfig::default()
+                },
+            )
+    }
+}
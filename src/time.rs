@@ -0,0 +1,150 @@
+//! Measuring time for timeouts, and comparing times read from the system clock.
+//!
+//! Every timeout and elapsed time is measured with a [Deadline] on a [Clock], which is monotonic, so that a step in the
+//! system clock, such as an NTP correction, can neither end a wait early nor make it run forever. `SystemTime` is
+//! only used for times which are reported, such as [crate::PortInfo::bound_since], or compared with a time given by
+//! the caller, such as [crate::PortQuery::bound_after]. The system clock can move backwards between two readings, so
+//! differences between them are taken with [wall_clock_since], which clamps rather than failing.
+
+use crate::clock::Clock;
+#[cfg(any(feature = "proc", test))]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+/// A timeout which started at a point on a monotonic clock
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    start: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// Start a timeout of `timeout` from now
+    pub(crate) fn start(clock: &dyn Clock, timeout: Duration) -> Self {
+        Deadline {
+            start: clock.now(),
+            timeout,
+        }
+    }
+
+    /// The time since the deadline was started
+    pub(crate) fn elapsed(&self, clock: &dyn Clock) -> Duration {
+        elapsed_since(clock, self.start)
+    }
+
+    /// The time left before the timeout, which is zero once it has passed
+    pub(crate) fn remaining(&self, clock: &dyn Clock) -> Duration {
+        self.timeout.saturating_sub(self.elapsed(clock))
+    }
+
+    /// When the timeout passes, or `None` if that is too far in the future for an `Instant` to hold, in which case it
+    /// never passes
+    #[cfg(target_os = "macos")]
+    pub(crate) fn end(&self) -> Option<Instant> {
+        self.start.checked_add(self.timeout)
+    }
+
+    /// How long to sleep before the next attempt, shortened so that it does not run past the timeout, or `None` once
+    /// the timeout has passed
+    pub(crate) fn next_sleep(&self, clock: &dyn Clock, interval: Duration) -> Option<Duration> {
+        let remaining = self.remaining(clock);
+        (!remaining.is_zero()).then(|| interval.min(remaining))
+    }
+}
+
+/// The time since `start` on `clock`, which is zero if the clock reads earlier than `start`
+pub(crate) fn elapsed_since(clock: &dyn Clock, start: Instant) -> Duration {
+    clock.now().saturating_duration_since(start)
+}
+
+/// The time from `earlier` to `later` as read from the system clock
+#[cfg(any(feature = "proc", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WallClockSince {
+    /// The time between the two, or zero if `later` is before `earlier`
+    pub(crate) elapsed: Duration,
+    /// How far `later` is before `earlier`, which happens when the system clock moves backwards between readings
+    pub(crate) went_backwards_by: Option<Duration>,
+}
+
+/// The time from `earlier` to `later` on the system clock, clamped to zero if the clock went backwards between them
+#[cfg(any(feature = "proc", test))]
+pub(crate) fn wall_clock_since(earlier: SystemTime, later: SystemTime) -> WallClockSince {
+    match later.duration_since(earlier) {
+        Ok(elapsed) => WallClockSince {
+            elapsed,
+            went_backwards_by: None,
+        },
+        Err(e) => WallClockSince {
+            elapsed: Duration::ZERO,
+            went_backwards_by: Some(e.duration()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn deadline_is_measured_on_the_clock() {
+        let clock = ManualClock::new();
+        let deadline = Deadline::start(&clock, Duration::from_secs(1));
+
+        // Real time passing does not move the deadline, only the clock does
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(Duration::ZERO, deadline.elapsed(&clock));
+
+        clock.advance(Duration::from_millis(700));
+        assert_eq!(Duration::from_millis(700), deadline.elapsed(&clock));
+        assert_eq!(Duration::from_millis(300), deadline.remaining(&clock));
+        assert_eq!(
+            Some(Duration::from_millis(300)),
+            deadline.next_sleep(&clock, Duration::from_millis(500))
+        );
+
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(Duration::ZERO, deadline.remaining(&clock));
+        assert_eq!(
+            None,
+            deadline.next_sleep(&clock, Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn deadline_started_after_the_clock_reading_is_not_negative() {
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_secs(5));
+        let deadline = Deadline::start(&clock, Duration::from_secs(1));
+
+        // A clock which reads earlier than the start counts as no time having passed
+        let behind = ManualClock::new();
+        assert_eq!(Duration::ZERO, deadline.elapsed(&behind));
+        assert_eq!(Duration::from_secs(1), deadline.remaining(&behind));
+    }
+
+    #[test]
+    fn wall_clock_moving_forwards() {
+        let earlier = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            WallClockSince {
+                elapsed: Duration::from_secs(3),
+                went_backwards_by: None,
+            },
+            wall_clock_since(earlier, earlier + Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn wall_clock_moving_backwards_is_clamped_and_flagged() {
+        let earlier = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            WallClockSince {
+                elapsed: Duration::ZERO,
+                went_backwards_by: Some(Duration::from_secs(3600)),
+            },
+            wall_clock_since(earlier, earlier - Duration::from_secs(3600))
+        );
+    }
+}
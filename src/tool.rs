@@ -0,0 +1,380 @@
+//! Running external tools, such as `lsof` on macOS.
+//!
+//! Every invocation takes a permit from one process-wide semaphore, so that many queries running at once, for
+//! example from parallel tests, don't start an unbounded number of tools. Each caller passes its own limit and waits
+//! until fewer than that many tools are running. The tool is then run by a [ToolRunner], which is the
+//! [SystemToolRunner] unless a query was given another.
+
+use std::io;
+use std::process::{Command, Output};
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// Runs the external tools which some queries rely on, such as `lsof` on macOS and `systemctl` on Linux.
+///
+/// Give a query a runner with [crate::PortQuery::with_tool_runner] to run the tools some other way, such as through
+/// `sudo -n` in an environment where they need more privileges, or from a vendored copy rather than the `PATH`. Tests
+/// can give canned output instead, to check how a query handles what a tool printed without running it.
+///
+/// Queries keep their runner, so it must be safe to share between threads and across a panic like the rest of a query.
+///
+/// ```rust
+/// use proc_ctl::ToolRunner;
+/// use std::process::Output;
+///
+/// /// Run every tool with sudo, failing rather than asking for a password
+/// #[derive(Debug)]
+/// struct Sudo;
+///
+/// impl ToolRunner for Sudo {
+///     fn run(&self, program: &str, args: &[&str]) -> std::io::Result<Output> {
+///         std::process::Command::new("sudo")
+///             .arg("-n")
+///             .arg(program)
+///             .args(args)
+///             .output()
+///     }
+/// }
+///
+/// let query = proc_ctl::PortQuery::new().with_tool_runner(std::sync::Arc::new(Sudo));
+/// ```
+pub trait ToolRunner:
+    std::fmt::Debug + Send + Sync + std::panic::RefUnwindSafe + std::panic::UnwindSafe
+{
+    /// Run `program` with `args` to completion and collect its output, as [Command::output] does.
+    ///
+    /// An error is treated as the tool failing to start, so a runner which can't run the tool at all, for example
+    /// because it isn't installed, should fail with [io::ErrorKind::NotFound].
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+
+    /// Run `program` like [ToolRunner::run], stopping it and failing with [io::ErrorKind::TimedOut] if it is still
+    /// running at `deadline`. This is used by a query with [crate::PortQuery::timeout].
+    ///
+    /// By default the tool is run with [ToolRunner::run] and only fails once it has finished, so a runner which can
+    /// stop a tool early should implement this as well.
+    fn run_before(&self, program: &str, args: &[&str], deadline: Instant) -> io::Result<Output> {
+        let output = self.run(program, args)?;
+        if Instant::now() > deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(output)
+    }
+}
+
+/// Runs tools directly with [Command], finding them on the `PATH`, as used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemToolRunner;
+
+impl ToolRunner for SystemToolRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+
+    /// Run the tool, killing it if it is still running at `deadline`
+    fn run_before(&self, program: &str, args: &[&str], deadline: Instant) -> io::Result<Output> {
+        use std::process::Stdio;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Both pipes are drained while waiting, so that a tool which prints a lot can't block on a full pipe
+        let stdout = child.stdout.take();
+        let stdout = std::thread::spawn(move || read_all(stdout));
+        let stderr = child.stderr.take();
+        let stderr = std::thread::spawn(move || read_all(stderr));
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+/// How often [SystemToolRunner::run_before] checks whether the tool has finished
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Read everything a tool writes to one of its pipes, until it closes the pipe
+fn read_all(pipe: Option<impl io::Read>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_end(&mut buf);
+    }
+    buf
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+static RUNNING: Mutex<usize> = Mutex::new(0);
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+static RELEASED: Condvar = Condvar::new();
+
+#[cfg(test)]
+thread_local! {
+    static SPAWNS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Run `program` with `runner` to completion and collect its output, once fewer than `max_concurrency` tools are
+/// running.
+///
+/// A limit of zero is treated as one.
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+pub(crate) fn output(
+    runner: &dyn ToolRunner,
+    program: &str,
+    args: &[&str],
+    max_concurrency: usize,
+) -> io::Result<Output> {
+    let _permit = Permit::acquire(max_concurrency.max(1));
+
+    #[cfg(test)]
+    SPAWNS.with(|s| s.set(s.get() + 1));
+
+    runner.run(program, args)
+}
+
+/// Run `program` like [output], failing with [io::ErrorKind::TimedOut] if it hasn't finished by `deadline`, including
+/// the time spent waiting for other tools to finish first
+#[cfg(any(target_os = "macos", test))]
+pub(crate) fn output_before(
+    runner: &dyn ToolRunner,
+    program: &str,
+    args: &[&str],
+    max_concurrency: usize,
+    deadline: Instant,
+) -> io::Result<Output> {
+    let _permit =
+        Permit::acquire_before(max_concurrency.max(1), deadline).ok_or(io::ErrorKind::TimedOut)?;
+
+    #[cfg(test)]
+    SPAWNS.with(|s| s.set(s.get() + 1));
+
+    runner.run_before(program, args, deadline)
+}
+
+/// Run `program` like [output], unless `max_concurrency` tools are already running, in which case `None` is returned
+/// straight away
+#[cfg(any(target_os = "macos", test))]
+pub(crate) fn try_output(
+    runner: &dyn ToolRunner,
+    program: &str,
+    args: &[&str],
+    max_concurrency: usize,
+) -> Option<io::Result<Output>> {
+    let _permit = Permit::try_acquire(max_concurrency.max(1))?;
+
+    #[cfg(test)]
+    SPAWNS.with(|s| s.set(s.get() + 1));
+
+    Some(runner.run(program, args))
+}
+
+/// The number of tools started by the current thread
+#[cfg(test)]
+pub(crate) fn spawn_count() -> usize {
+    SPAWNS.with(|s| s.get())
+}
+
+/// A runner which gives the same output for every tool, and records what it was asked to run
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct CannedRunner {
+    pub(crate) code: i32,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+    pub(crate) runs: Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl CannedRunner {
+    pub(crate) fn new(code: i32, stdout: &[u8], stderr: &[u8]) -> Self {
+        CannedRunner {
+            code,
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+            runs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Each program run, followed by its arguments
+    pub(crate) fn runs(&self) -> Vec<Vec<String>> {
+        self.runs.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl ToolRunner for CannedRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        let mut run = vec![program.to_string()];
+        run.extend(args.iter().map(|arg| arg.to_string()));
+        self.runs.lock().unwrap().push(run);
+
+        #[cfg(unix)]
+        let status = std::os::unix::process::ExitStatusExt::from_raw(self.code << 8);
+        #[cfg(windows)]
+        let status = std::os::windows::process::ExitStatusExt::from_raw(self.code as u32);
+
+        Ok(Output {
+            status,
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+        })
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+struct Permit;
+
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+impl Permit {
+    fn acquire(max_concurrency: usize) -> Self {
+        // The count is only changed while the lock is held, so a poisoned lock still holds a valid count
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        while *running >= max_concurrency {
+            running = RELEASED.wait(running).unwrap_or_else(|e| e.into_inner());
+        }
+        *running += 1;
+
+        Permit
+    }
+
+    /// Acquire a permit like [Permit::acquire], giving up at `deadline`
+    #[cfg(any(target_os = "macos", test))]
+    fn acquire_before(max_concurrency: usize, deadline: Instant) -> Option<Self> {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        while *running >= max_concurrency {
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            running = RELEASED
+                .wait_timeout(running, remaining)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        *running += 1;
+
+        Some(Permit)
+    }
+
+    #[cfg(any(target_os = "macos", test))]
+    fn try_acquire(max_concurrency: usize) -> Option<Self> {
+        let mut running = match RUNNING.try_lock() {
+            Ok(running) => running,
+            Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(std::sync::TryLockError::WouldBlock) => return None,
+        };
+        if *running >= max_concurrency {
+            return None;
+        }
+        *running += 1;
+
+        Some(Permit)
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux", test))]
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        *running -= 1;
+        RELEASED.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn permits_never_exceed_the_limit() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8)
+            .map(|_| {
+                let active = active.clone();
+                let peak = peak.clone();
+                std::thread::spawn(move || {
+                    let _permit = Permit::acquire(1);
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(1, peak.load(Ordering::SeqCst));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_tool_still_running_at_the_deadline_is_killed() {
+        let start = Instant::now();
+        let err = SystemToolRunner
+            .run_before(
+                "sleep",
+                &["10"],
+                start + std::time::Duration::from_millis(100),
+            )
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let output = SystemToolRunner
+            .run_before(
+                "echo",
+                &["done"],
+                Instant::now() + std::time::Duration::from_secs(10),
+            )
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(b"done\n".to_vec(), output.stdout);
+    }
+
+    #[test]
+    fn runners_are_given_the_program_and_its_arguments() {
+        let runner = CannedRunner::new(0, b"out", b"");
+
+        let output = output(&runner, "lsof", &["-nP", "-iTCP"], 1).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(b"out".to_vec(), output.stdout);
+        assert_eq!(vec![vec!["lsof", "-nP", "-iTCP"]], runner.runs());
+    }
+
+    #[test]
+    fn try_output_does_not_wait_for_a_permit() {
+        let runner = CannedRunner::new(0, b"", b"");
+        let _permit = Permit::acquire(1);
+
+        assert!(try_output(&runner, "lsof", &[], 1).is_none());
+        assert!(runner.runs().is_empty());
+    }
+
+    #[test]
+    fn spawns_are_counted_per_thread() {
+        let before = spawn_count();
+        let _ = output(&SystemToolRunner, "true", &[], 1);
+        assert_eq!(before + 1, spawn_count());
+    }
+}
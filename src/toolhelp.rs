@@ -0,0 +1,61 @@
+//! Parent links read from a Toolhelp snapshot of the running processes on Windows.
+//!
+//! sysinfo can report a missing or stale parent for a process which has only just started, so when too few children
+//! are found the links are read again straight from the operating system. A Toolhelp snapshot lists each process with
+//! the pid of the process which created it, even if that process has since exited and its pid been reused, so a link
+//! is only trusted if the child started after its parent.
+
+use crate::error::{ProcCtlError, ProcCtlResult};
+use windows::Win32::Foundation::{CloseHandle, ERROR_NO_MORE_FILES, HANDLE};
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+
+/// Take a snapshot of every running process and the pid of its parent
+pub(crate) fn parent_links() -> ProcCtlResult<Vec<crate::proc_query::ParentLink>> {
+    /// Closes the snapshot when it is dropped
+    struct Snapshot(HANDLE);
+
+    impl Drop for Snapshot {
+        fn drop(&mut self) {
+            // SAFETY: the handle was returned by CreateToolhelp32Snapshot and is closed only here
+            let _ = unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    let error = |operation: &str, e: windows::core::Error| {
+        ProcCtlError::ProcessError(format!("{} for a process snapshot: {}", operation, e))
+    };
+
+    // SAFETY: the entry is initialised with its size as the API requires, and only read after a call succeeds
+    unsafe {
+        let snapshot = Snapshot(
+            CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0).map_err(|e| error("creating", e))?,
+        );
+
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut links = Vec::new();
+        let mut next = Process32FirstW(snapshot.0, &mut entry);
+        loop {
+            match next {
+                Ok(()) => links.push(crate::proc_query::ParentLink {
+                    pid: entry.th32ProcessID,
+                    parent: entry.th32ParentProcessID,
+                    name: exe_name(&entry.szExeFile),
+                }),
+                Err(e) if e.code() == ERROR_NO_MORE_FILES.to_hresult() => return Ok(links),
+                Err(e) => return Err(error("reading", e)),
+            }
+            next = Process32NextW(snapshot.0, &mut entry);
+        }
+    }
+}
+
+/// The executable name of a process entry, which is null terminated within its buffer
+fn exe_name(name: &[u16]) -> String {
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    String::from_utf16_lossy(&name[..len])
+}
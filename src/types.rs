@@ -1,3 +1,5 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
 /// A process ID
 pub type Pid = u32;
 
@@ -5,10 +7,1128 @@ pub type Pid = u32;
 pub type Port = u16;
 
 /// A representation of a port using a specific protocol
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "protocol", content = "port", rename_all = "lowercase")
+)]
 pub enum ProtocolPort {
     /// A TCP port
     Tcp(Port),
     /// A UDP port
     Udp(Port),
 }
+
+impl ProtocolPort {
+    /// The port number, whichever the protocol
+    pub fn port(&self) -> Port {
+        match self {
+            ProtocolPort::Tcp(port) | ProtocolPort::Udp(port) => *port,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl crate::export::CsvRecord for ProtocolPort {
+    fn csv_header() -> &'static [&'static str] {
+        &["protocol", "port"]
+    }
+
+    fn csv_fields(&self) -> Vec<String> {
+        match self {
+            ProtocolPort::Tcp(port) => vec!["tcp".to_string(), port.to_string()],
+            ProtocolPort::Udp(port) => vec!["udp".to_string(), port.to_string()],
+        }
+    }
+}
+
+/// The address family of a socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum AddressFamily {
+    /// An IPv4 socket
+    Ipv4,
+    /// An IPv6 socket, which may also accept IPv4 connections unless it is set to be IPv6 only
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// The unspecified address of this family, which a socket bound without an address has
+    pub(crate) fn unspecified(self) -> IpAddr {
+        match self {
+            AddressFamily::Ipv4 => Ipv4Addr::UNSPECIFIED.into(),
+            AddressFamily::Ipv6 => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+}
+
+/// The ports of a query grouped by protocol and address family, see [crate::PortQuery::summary]
+///
+/// The ports in each group are sorted and never repeated. Its [std::fmt::Display] form is a single line such as
+/// `tcp4: 8080,8081 udp6: 5353`, leaving out empty groups.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PortSummary {
+    /// TCP ports bound on IPv4 addresses
+    pub tcp_v4: Vec<Port>,
+    /// TCP ports bound on IPv6 addresses
+    pub tcp_v6: Vec<Port>,
+    /// UDP ports bound on IPv4 addresses
+    pub udp_v4: Vec<Port>,
+    /// UDP ports bound on IPv6 addresses
+    pub udp_v6: Vec<Port>,
+}
+
+impl PortSummary {
+    /// Group ports by protocol and address family
+    ///
+    /// ```rust
+    /// use proc_ctl::{AddressFamily, PortInfo, PortSummary, ProtocolPort};
+    ///
+    /// let summary = PortSummary::from_ports(&[
+    ///     PortInfo::new(ProtocolPort::Tcp(8081), 1),
+    ///     PortInfo::new(ProtocolPort::Tcp(8080), 1),
+    ///     PortInfo::builder()
+    ///         .port(ProtocolPort::Udp(5353))
+    ///         .family(AddressFamily::Ipv6)
+    ///         .build(),
+    /// ]);
+    ///
+    /// assert_eq!("tcp4: 8080,8081 udp6: 5353", summary.to_string());
+    /// assert_eq!(3, summary.total());
+    /// ```
+    pub fn from_ports<'a>(ports: impl IntoIterator<Item = &'a PortInfo>) -> Self {
+        let mut summary = PortSummary::default();
+        for info in ports {
+            for family in &info.families {
+                let (bucket, port) = match (info.port, family) {
+                    (ProtocolPort::Tcp(port), AddressFamily::Ipv4) => (&mut summary.tcp_v4, port),
+                    (ProtocolPort::Tcp(port), AddressFamily::Ipv6) => (&mut summary.tcp_v6, port),
+                    (ProtocolPort::Udp(port), AddressFamily::Ipv4) => (&mut summary.udp_v4, port),
+                    (ProtocolPort::Udp(port), AddressFamily::Ipv6) => (&mut summary.udp_v6, port),
+                };
+                bucket.push(port);
+            }
+        }
+
+        for bucket in summary.buckets_mut() {
+            bucket.sort_unstable();
+            bucket.dedup();
+        }
+
+        summary
+    }
+
+    /// The number of TCP ports, counting a port bound on both families twice
+    pub fn tcp_total(&self) -> usize {
+        self.tcp_v4.len() + self.tcp_v6.len()
+    }
+
+    /// The number of UDP ports, counting a port bound on both families twice
+    pub fn udp_total(&self) -> usize {
+        self.udp_v4.len() + self.udp_v6.len()
+    }
+
+    /// The number of ports in every group
+    pub fn total(&self) -> usize {
+        self.tcp_total() + self.udp_total()
+    }
+
+    /// Whether no ports were found
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// The plain ports, TCP then UDP, each sorted and listed once even if bound on both families
+    pub fn protocol_ports(&self) -> Vec<ProtocolPort> {
+        let merged = |v4: &[Port], v6: &[Port]| {
+            let mut ports = v4.iter().chain(v6).copied().collect::<Vec<_>>();
+            ports.sort_unstable();
+            ports.dedup();
+            ports
+        };
+
+        let tcp = merged(&self.tcp_v4, &self.tcp_v6)
+            .into_iter()
+            .map(ProtocolPort::Tcp);
+        let udp = merged(&self.udp_v4, &self.udp_v6)
+            .into_iter()
+            .map(ProtocolPort::Udp);
+        tcp.chain(udp).collect()
+    }
+
+    fn buckets_mut(&mut self) -> [&mut Vec<Port>; 4] {
+        [
+            &mut self.tcp_v4,
+            &mut self.tcp_v6,
+            &mut self.udp_v4,
+            &mut self.udp_v6,
+        ]
+    }
+}
+
+impl std::fmt::Display for PortSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no ports");
+        }
+
+        let buckets = [
+            ("tcp4", &self.tcp_v4),
+            ("tcp6", &self.tcp_v6),
+            ("udp4", &self.udp_v4),
+            ("udp6", &self.udp_v6),
+        ];
+        let mut separator = "";
+        for (name, ports) in buckets.into_iter().filter(|(_, ports)| !ports.is_empty()) {
+            let ports = ports.iter().map(Port::to_string).collect::<Vec<_>>();
+            write!(f, "{}{}: {}", separator, name, ports.join(","))?;
+            separator = " ";
+        }
+
+        Ok(())
+    }
+}
+
+impl From<PortSummary> for Vec<ProtocolPort> {
+    fn from(summary: PortSummary) -> Self {
+        summary.protocol_ports()
+    }
+}
+
+/// The result of a query, with the time it was found
+#[derive(Debug, Clone)]
+pub struct Observed<T> {
+    /// The query result
+    pub value: T,
+    /// When the query finished
+    pub at: std::time::SystemTime,
+}
+
+impl<T: Clone> Observed<T> {
+    /// Record `value` as observed now, copying it into the previous observation so that its allocations are reused
+    pub(crate) fn record(observed: &mut Option<Observed<T>>, value: &T) {
+        match observed {
+            Some(observed) => {
+                observed.value.clone_from(value);
+                observed.at = std::time::SystemTime::now();
+            }
+            None => {
+                *observed = Some(Observed {
+                    value: value.clone(),
+                    at: std::time::SystemTime::now(),
+                })
+            }
+        }
+    }
+}
+
+/// Who owns a socket, as reported by the operating system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnerKind {
+    /// A normal process
+    Process(Pid),
+    /// The operating system itself. On Windows this is the System process, pid 4.
+    System,
+    /// Nothing owns the socket. On Windows this is reported as pid 0, for sockets which have been released or were
+    /// created during boot. This is not a real process and should not be treated as one.
+    Unowned,
+    /// The port is in use, but the socket could not be attributed to any process
+    Unknown(UnknownOwnerReason),
+}
+
+/// Why the owner of a port in use could not be found, see [OwnerKind::Unknown]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum UnknownOwnerReason {
+    /// The socket belongs to a process which this process is not allowed to see.
+    ///
+    /// On macOS, lsof and libproc can't see the sockets of processes owned by other users, or of processes protected
+    /// by System Integrity Protection or entitlements. Running as root reveals the sockets of other users, but not
+    /// those of protected processes.
+    VisibilityRestricted,
+}
+
+impl OwnerKind {
+    /// Classify an owning pid taken from one of the Windows owner-pid tables
+    #[cfg(any(target_os = "windows", test))]
+    pub(crate) fn from_windows_pid(pid: u32) -> Self {
+        match pid {
+            0 => OwnerKind::Unowned,
+            4 => OwnerKind::System,
+            pid => OwnerKind::Process(pid),
+        }
+    }
+
+    /// Decide the owner of a port from what a scan of the sockets found, falling back to checking whether the port is
+    /// in use with [crate::is_port_in_use].
+    ///
+    /// A port which no scan attributes to an owner but which can't be bound is in use by a socket this process can't
+    /// see, which is reported as [UnknownOwnerReason::VisibilityRestricted] rather than as having no owner. `None`
+    /// means the port is free.
+    ///
+    /// ```rust
+    /// use proc_ctl::{AddressFamily, OwnerKind, ProtocolPort, UnknownOwnerReason};
+    ///
+    /// // A socket which no scan found an owner for
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let port = ProtocolPort::Tcp(listener.local_addr().unwrap().port());
+    ///
+    /// assert_eq!(
+    ///     Some(OwnerKind::Unknown(UnknownOwnerReason::VisibilityRestricted)),
+    ///     OwnerKind::attribute(None, port, AddressFamily::Ipv4)
+    /// );
+    /// ```
+    pub fn attribute(
+        found: Option<OwnerKind>,
+        port: ProtocolPort,
+        family: AddressFamily,
+    ) -> Option<OwnerKind> {
+        found.or_else(|| {
+            crate::probe::is_port_in_use(port, family)
+                .then_some(OwnerKind::Unknown(UnknownOwnerReason::VisibilityRestricted))
+        })
+    }
+}
+
+impl std::fmt::Display for OwnerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OwnerKind::Process(pid) => write!(f, "pid {}", pid),
+            OwnerKind::System => write!(f, "SYSTEM"),
+            OwnerKind::Unowned => write!(f, "unowned"),
+            OwnerKind::Unknown(reason) => write!(f, "unknown, {}", reason),
+        }
+    }
+}
+
+impl std::fmt::Display for UnknownOwnerReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownOwnerReason::VisibilityRestricted => write!(
+                f,
+                "the port is in use by a process this process can't see. On macOS, sockets of other users' processes \
+                 are hidden unless running as root, and those of processes protected by System Integrity Protection \
+                 or entitlements are always hidden"
+            ),
+        }
+    }
+}
+
+/// A socket using a port, and who owns it, found by [crate::PortQuery::owners_of]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PortOwner {
+    /// Who owns the socket. A socket held by several processes, such as a listener shared by the workers of a
+    /// pre-fork server, is listed once for each of them.
+    pub owner: OwnerKind,
+    /// The name of the owning process, when the `proc` feature is enabled and the process could still be read
+    pub name: Option<String>,
+    /// The port, with its protocol
+    pub port: ProtocolPort,
+    /// The address family of the socket
+    pub family: AddressFamily,
+    /// The local address the socket is bound to, when the platform reports it
+    pub local_addr: Option<SocketAddr>,
+}
+
+/// A TCP connection of a process, found by [crate::ConnectionQuery]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Connection {
+    /// The process the connection belongs to
+    pub pid: Pid,
+    /// The local end of the connection
+    pub local_addr: SocketAddr,
+    /// The peer of the connection
+    pub remote_addr: SocketAddr,
+    /// The state of the connection
+    pub state: TcpState,
+}
+
+impl Connection {
+    /// The address family of the connection
+    pub fn family(&self) -> AddressFamily {
+        if self.local_addr.is_ipv6() {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Ipv4
+        }
+    }
+}
+
+/// The state of a TCP socket, see [Connection::state] and [PortInfo::tcp_state]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[non_exhaustive]
+pub enum TcpState {
+    /// The socket is waiting for connections. Connections never have this state, only the listeners found by a
+    /// [crate::PortQuery].
+    Listen,
+    /// A connection has been asked for and no reply has been received yet
+    SynSent,
+    /// A connection request has been received and answered, and the final acknowledgement has not arrived yet
+    SynReceived,
+    /// The connection is open
+    Established,
+    /// This end has closed the connection and is waiting for the peer to acknowledge it
+    FinWait1,
+    /// The peer has acknowledged this end closing, and has not closed its end yet
+    FinWait2,
+    /// The peer has closed its end, and this end has not closed yet
+    CloseWait,
+    /// Both ends closed at once, and this end is waiting for the peer to acknowledge it
+    Closing,
+    /// This end closed after the peer did, and is waiting for the peer to acknowledge it
+    LastAck,
+    /// Both ends have closed, and this end is waiting in case the peer resends anything
+    TimeWait,
+    /// The connection is closed
+    Closed,
+}
+
+/// Detailed information about a port found by a [crate::PortQuery]
+///
+/// New fields are added as more information is collected, so outside this crate values are created with
+/// [PortInfo::new] or [PortInfo::builder] rather than a struct literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PortInfo {
+    /// The protocol and port number
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub port: ProtocolPort,
+    /// The address family of the socket. When the port is bound on both families, this is IPv4 and the other fields
+    /// describe the IPv4 socket.
+    pub family: AddressFamily,
+    /// Every address family the process has the port bound on, in order. This has both families when the same port
+    /// and protocol is bound by the process on IPv4 and on IPv6, unless [crate::PortQuery::split_families] is set.
+    pub families: Vec<AddressFamily>,
+    /// The ID of the process which has the port bound
+    pub pid: Pid,
+    /// The local address the socket is bound to, with its port. An IPv6 address includes the scope ID of the
+    /// interface it is bound on where the platform reports it, which is on Windows and macOS. `None` if the address was
+    /// not found, as for a [PortInfo] created with [PortInfo::new]. See [PortInfo::socket_addr] and
+    /// [PortInfo::connectable_addr].
+    pub local_addr: Option<SocketAddr>,
+    /// For a TCP socket, its state, which is [TcpState::Listen] unless other states were asked for with
+    /// [crate::PortQuery::tcp_states]. `None` for a UDP socket, and for a TCP socket found by an earlier release, which
+    /// only found listeners.
+    pub tcp_state: Option<TcpState>,
+    /// An approximation of when the port was bound, if known.
+    ///
+    /// No platform currently provides socket creation times, so this is the start time of the owning process, which
+    /// is always at or before the time the port was actually bound. A process which binds a port long after it
+    /// started, or which re-binds a port, will appear to have held the port for longer than it has. Only available
+    /// on Linux, where it is accurate to roughly one clock tick (usually 10ms).
+    ///
+    /// It is worked out from the current system time less how long the process has been running, which is measured
+    /// on a monotonic clock. So it follows any step in the system clock: once the clock has been set back by an hour,
+    /// the same process is reported as having bound its ports an hour earlier.
+    pub bound_since: Option<std::time::SystemTime>,
+    /// For a listening TCP socket, the maximum number of connections which can wait to be accepted. Only available
+    /// on Linux.
+    pub backlog: Option<u32>,
+    /// For a listening TCP socket, the number of connections currently waiting to be accepted. Only available on
+    /// Linux.
+    pub current_queue: Option<u32>,
+    /// For a listening TCP socket, whether a test connection to it succeeded. Only set when
+    /// [crate::PortQuery::verify_accepting] is enabled.
+    pub accepting: Option<bool>,
+    /// Whether the socket was passed to the process by a service manager using socket activation, such as a systemd
+    /// `.socket` unit. These sockets are bound before the process starts, so [PortInfo::bound_since] is later than
+    /// the time they were bound. Only available on Linux, and only when the environment of the process can be read.
+    pub via_socket_activation: Option<bool>,
+    /// The other processes which hold the same socket, in ascending order, such as the workers of a server which
+    /// binds before forking. Only found on Linux for detailed results, and only among processes whose file
+    /// descriptors can be read, which usually means those of the same user.
+    pub shared_with: Vec<Pid>,
+    /// When the socket is shared, the process treated as its main owner. This is the process the socket was passed
+    /// down from, or the oldest holder if that can't be told.
+    pub primary_owner: Option<Pid>,
+    /// For a UDP socket, the multicast groups of its address family joined in the network namespace of the process, in
+    /// ascending order. Only found on Linux for detailed results, or with [crate::PortQuery::joined_group].
+    ///
+    /// Linux keeps track of groups per interface rather than per socket, so these are every group joined on any
+    /// interface, by any process, including those the kernel joins itself such as `224.0.0.1`. A group is only
+    /// missing when nothing in the namespace has joined it.
+    pub multicast_groups: Option<Vec<IpAddr>>,
+    /// For a TCP socket, whether the Windows firewall allows inbound connections to it from other machines, by the
+    /// rules for the program of the process and the port in the current firewall profiles. Only found on Windows for
+    /// detailed results with the `windows-firewall` feature, and `None` when the firewall policy or the program of the
+    /// process can't be read.
+    ///
+    /// This is worked out from the enabled rules and the default inbound action, where a block rule wins over an
+    /// allow rule. Rules which filter on remote addresses, interfaces or services are treated as applying, so the
+    /// answer can be wrong for connections from some addresses. Connections over loopback are never filtered.
+    pub firewall_allowed: Option<bool>,
+    /// The inode of the socket, which is how `/proc/<pid>/fd` and tools such as `ss -e` identify it. Only available on
+    /// Linux.
+    pub socket_inode: Option<u64>,
+    /// The file descriptor the process holds the socket on, to match it with the descriptors the process has open
+    /// when looking for a leak. A socket held on more than one descriptor, such as after `dup`, has one of them.
+    /// Available on Linux and macOS.
+    pub fd: Option<i32>,
+}
+
+impl PortInfo {
+    /// Create the information for a port with only its protocol, number and owning process, for use as a test
+    /// fixture.
+    ///
+    /// The address family is IPv4, and every other field is `None`.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortInfo, ProtocolPort};
+    ///
+    /// let info = PortInfo::new(ProtocolPort::Tcp(8080), 1234);
+    /// assert_eq!(None, info.backlog);
+    /// ```
+    pub fn new(port: ProtocolPort, pid: Pid) -> Self {
+        PortInfo::builder().port(port).pid(pid).build()
+    }
+
+    /// Start building the information for a port, for use as a test fixture.
+    ///
+    /// ```rust
+    /// use proc_ctl::{PortInfo, ProtocolPort};
+    ///
+    /// let info = PortInfo::builder()
+    ///     .port(ProtocolPort::Tcp(8080))
+    ///     .pid(1234)
+    ///     .backlog(128)
+    ///     .current_queue(0)
+    ///     .build();
+    ///
+    /// assert_eq!(Some(128), info.backlog);
+    /// ```
+    pub fn builder() -> PortInfoBuilder {
+        PortInfoBuilder::default()
+    }
+
+    /// The address the socket is bound to, such as `0.0.0.0:8080` for a listener on every IPv4 address.
+    ///
+    /// When [PortInfo::local_addr] is not known, this is the unspecified address of [PortInfo::family], since that is
+    /// what a socket bound without an address has. Use [SocketAddr::try_from] to fail instead.
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.local_addr
+            .unwrap_or_else(|| SocketAddr::new(self.family.unspecified(), self.port.port()))
+    }
+
+    /// An address which a client on the same machine can connect to, to reach this socket.
+    ///
+    /// A socket bound to the unspecified address, such as `0.0.0.0` or `::`, accepts connections on every address,
+    /// but can't be connected to at that address on every platform, so the loopback address of the same family is used
+    /// instead. An IPv4 address mapped into IPv6, such as `::ffff:127.0.0.1`, is given as the plain IPv4 address,
+    /// which works whether or not the client has dual-stack sockets. Any other address is kept as it is, including
+    /// the scope ID of a link-local IPv6 address. Linux does not report that scope, so a link-local address found
+    /// there needs the scope set before it can be connected to.
+    ///
+    /// ```rust
+    /// use proc_ctl::PortQuery;
+    /// use std::net::{TcpListener, TcpStream};
+    ///
+    /// // A listener on every IPv4 address, like the one a server would open
+    /// let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    /// let port = listener.local_addr().unwrap().port();
+    ///
+    /// let ports = PortQuery::new()
+    ///     .tcp_only()
+    ///     .process_id(std::process::id())
+    ///     .execute_detailed()
+    ///     .unwrap();
+    /// let info = ports.iter().find(|p| p.port.port() == port).unwrap();
+    ///
+    /// assert!(info.socket_addr().ip().is_unspecified());
+    /// assert!(info.connectable_addr().ip().is_loopback());
+    /// TcpStream::connect(info.connectable_addr()).unwrap();
+    /// ```
+    pub fn connectable_addr(&self) -> SocketAddr {
+        let addr = self.socket_addr();
+        let port = addr.port();
+        match addr {
+            SocketAddr::V4(v4) if v4.ip().is_unspecified() => (Ipv4Addr::LOCALHOST, port).into(),
+            SocketAddr::V4(_) => addr,
+            SocketAddr::V6(v6) => {
+                if let Some(mapped) = v6.ip().to_ipv4_mapped() {
+                    let ip = if mapped.is_unspecified() {
+                        Ipv4Addr::LOCALHOST
+                    } else {
+                        mapped
+                    };
+                    (ip, port).into()
+                } else if v6.ip().is_unspecified() {
+                    (Ipv6Addr::LOCALHOST, port).into()
+                } else {
+                    addr
+                }
+            }
+        }
+    }
+
+    /// Whether the socket is bound to a loopback address, so that it can only be reached from the same machine. This
+    /// includes an IPv4 loopback address mapped into IPv6, such as `::ffff:127.0.0.1`.
+    ///
+    /// A socket bound to the unspecified address, such as `0.0.0.0` or `::`, accepts connections on every address and
+    /// is not loopback. Neither is a socket whose [PortInfo::local_addr] is not known, which is taken to be bound to
+    /// the unspecified address as for [PortInfo::socket_addr].
+    pub fn is_loopback(&self) -> bool {
+        match self.socket_addr().ip() {
+            IpAddr::V4(ip) => ip.is_loopback(),
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map_or(ip.is_loopback(), |mapped| mapped.is_loopback()),
+        }
+    }
+}
+
+/// A single line such as `tcp4 8080 (pid 1234)`, or `tcp46 8080 (pid 1234)` for a port bound on both families
+impl std::fmt::Display for PortInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (protocol, port) = match self.port {
+            ProtocolPort::Tcp(port) => ("tcp", port),
+            ProtocolPort::Udp(port) => ("udp", port),
+        };
+        write!(f, "{}", protocol)?;
+        for family in &self.families {
+            match family {
+                AddressFamily::Ipv4 => write!(f, "4")?,
+                AddressFamily::Ipv6 => write!(f, "6")?,
+            }
+        }
+
+        write!(f, " {} (pid {})", port, self.pid)
+    }
+}
+
+impl From<PortInfo> for ProtocolPort {
+    fn from(info: PortInfo) -> Self {
+        info.port
+    }
+}
+
+impl From<&PortInfo> for ProtocolPort {
+    fn from(info: &PortInfo) -> Self {
+        info.port
+    }
+}
+
+/// The address the socket is bound to, failing with [crate::ProcCtlError::AddressUnknown] if it was not found. See
+/// [PortInfo::socket_addr] for a version which does not fail.
+impl TryFrom<&PortInfo> for SocketAddr {
+    type Error = crate::ProcCtlError;
+
+    fn try_from(info: &PortInfo) -> Result<Self, Self::Error> {
+        info.local_addr
+            .ok_or(crate::ProcCtlError::AddressUnknown(info.port))
+    }
+}
+
+/// Combine the ports which one process has bound on both IPv4 and IPv6 into one port with both families, see
+/// [crate::PortQuery::split_families]. Only sockets in the same TCP state are combined, so that a connection is not
+/// combined with a listener on the same port. The combined port keeps the details of the IPv4 socket, and otherwise
+/// the ports stay in the order they were found.
+pub(crate) fn merge_families(ports: Vec<PortInfo>) -> Vec<PortInfo> {
+    let mut out: Vec<PortInfo> = Vec::with_capacity(ports.len());
+    for info in ports {
+        let other_family = out.iter_mut().find(|o| {
+            o.pid == info.pid
+                && o.port == info.port
+                && o.tcp_state == info.tcp_state
+                && !o.families.iter().any(|f| info.families.contains(f))
+        });
+        let Some(other) = other_family else {
+            out.push(info);
+            continue;
+        };
+
+        let mut families = other.families.clone();
+        families.extend(&info.families);
+        families.sort_unstable();
+        if info.family < other.family {
+            *other = info;
+        }
+        other.families = families;
+    }
+
+    out
+}
+
+/// Combine the ports which more than one process holds, such as a listener bound by a server before it forked its
+/// workers, into the port of the first process to hold it, see [crate::PortQuery::include_children]. The port of the
+/// socket's primary owner is kept instead where that is known. Ports are only combined when they have the same
+/// families, state and local address, and otherwise stay in the order they were found.
+pub(crate) fn merge_shared(ports: Vec<PortInfo>) -> Vec<PortInfo> {
+    let mut out: Vec<PortInfo> = Vec::with_capacity(ports.len());
+    for info in ports {
+        let same_socket = out.iter_mut().find(|o| {
+            o.pid != info.pid
+                && o.port == info.port
+                && o.tcp_state == info.tcp_state
+                && o.local_addr == info.local_addr
+                && o.families == info.families
+        });
+        match same_socket {
+            Some(other) if info.primary_owner == Some(info.pid) => *other = info,
+            Some(_) => {}
+            None => out.push(info),
+        }
+    }
+
+    out
+}
+
+/// Sort ports by protocol then port number, see [crate::PortQuery::sorted]. Ports with the same protocol and number
+/// are sorted by address family, pid and local address, so that the order never depends on the order they were found.
+pub(crate) fn sort_ports(ports: &mut [PortInfo]) {
+    ports.sort_by_key(|p| (p.port, p.family, p.pid, p.local_addr));
+}
+
+/// Builds a [PortInfo], see [PortInfo::builder].
+///
+/// Fields which are not set are `None`, except for the port which defaults to TCP port 0, the address family which
+/// defaults to IPv4, the families which default to just the address family and the pid which defaults to 0.
+#[derive(Debug, Clone)]
+pub struct PortInfoBuilder {
+    port: ProtocolPort,
+    family: AddressFamily,
+    families: Vec<AddressFamily>,
+    pid: Pid,
+    local_addr: Option<SocketAddr>,
+    tcp_state: Option<TcpState>,
+    bound_since: Option<std::time::SystemTime>,
+    backlog: Option<u32>,
+    current_queue: Option<u32>,
+    accepting: Option<bool>,
+    via_socket_activation: Option<bool>,
+    shared_with: Vec<Pid>,
+    primary_owner: Option<Pid>,
+    multicast_groups: Option<Vec<IpAddr>>,
+    firewall_allowed: Option<bool>,
+    socket_inode: Option<u64>,
+    fd: Option<i32>,
+}
+
+impl Default for PortInfoBuilder {
+    fn default() -> Self {
+        PortInfoBuilder {
+            port: ProtocolPort::Tcp(0),
+            family: AddressFamily::Ipv4,
+            families: Vec::new(),
+            pid: 0,
+            local_addr: None,
+            tcp_state: None,
+            bound_since: None,
+            backlog: None,
+            current_queue: None,
+            accepting: None,
+            via_socket_activation: None,
+            shared_with: Vec::new(),
+            primary_owner: None,
+            multicast_groups: None,
+            firewall_allowed: None,
+            socket_inode: None,
+            fd: None,
+        }
+    }
+}
+
+impl PortInfoBuilder {
+    /// Set [PortInfo::port]
+    pub fn port(mut self, port: ProtocolPort) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Set [PortInfo::family]
+    pub fn family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Set [PortInfo::families], and [PortInfo::family] to the first of them
+    pub fn families(mut self, families: impl IntoIterator<Item = AddressFamily>) -> Self {
+        self.families = families.into_iter().collect();
+        self.families.sort_unstable();
+        self.families.dedup();
+        if let Some(first) = self.families.first() {
+            self.family = *first;
+        }
+        self
+    }
+
+    /// Set [PortInfo::pid]
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Set [PortInfo::local_addr], and [PortInfo::family] to match it
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> Self {
+        self.family = if local_addr.is_ipv6() {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Ipv4
+        };
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Set [PortInfo::tcp_state]
+    pub fn tcp_state(mut self, state: TcpState) -> Self {
+        self.tcp_state = Some(state);
+        self
+    }
+
+    /// Set [PortInfo::bound_since]
+    pub fn bound_since(mut self, bound_since: std::time::SystemTime) -> Self {
+        self.bound_since = Some(bound_since);
+        self
+    }
+
+    /// Set [PortInfo::backlog]
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Set [PortInfo::current_queue]
+    pub fn current_queue(mut self, current_queue: u32) -> Self {
+        self.current_queue = Some(current_queue);
+        self
+    }
+
+    /// Set [PortInfo::accepting]
+    pub fn accepting(mut self, accepting: bool) -> Self {
+        self.accepting = Some(accepting);
+        self
+    }
+
+    /// Set [PortInfo::via_socket_activation]
+    pub fn via_socket_activation(mut self, via_socket_activation: bool) -> Self {
+        self.via_socket_activation = Some(via_socket_activation);
+        self
+    }
+
+    /// Set [PortInfo::shared_with]
+    pub fn shared_with(mut self, shared_with: impl IntoIterator<Item = Pid>) -> Self {
+        self.shared_with = shared_with.into_iter().collect();
+        self
+    }
+
+    /// Set [PortInfo::primary_owner]
+    pub fn primary_owner(mut self, primary_owner: Pid) -> Self {
+        self.primary_owner = Some(primary_owner);
+        self
+    }
+
+    /// Set [PortInfo::multicast_groups], which are kept in ascending order
+    pub fn multicast_groups(mut self, groups: impl IntoIterator<Item = IpAddr>) -> Self {
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort_unstable();
+        groups.dedup();
+        self.multicast_groups = Some(groups);
+        self
+    }
+
+    /// Set [PortInfo::firewall_allowed]
+    pub fn firewall_allowed(mut self, allowed: bool) -> Self {
+        self.firewall_allowed = Some(allowed);
+        self
+    }
+
+    /// Set [PortInfo::socket_inode]
+    pub fn socket_inode(mut self, inode: u64) -> Self {
+        self.socket_inode = Some(inode);
+        self
+    }
+
+    /// Set [PortInfo::fd]
+    pub fn fd(mut self, fd: i32) -> Self {
+        self.fd = Some(fd);
+        self
+    }
+
+    /// Create the [PortInfo]
+    pub fn build(self) -> PortInfo {
+        PortInfo {
+            port: self.port,
+            family: self.family,
+            families: if self.families.is_empty() {
+                vec![self.family]
+            } else {
+                self.families
+            },
+            pid: self.pid,
+            local_addr: self.local_addr,
+            tcp_state: self.tcp_state,
+            bound_since: self.bound_since,
+            backlog: self.backlog,
+            current_queue: self.current_queue,
+            accepting: self.accepting,
+            via_socket_activation: self.via_socket_activation,
+            shared_with: self.shared_with,
+            primary_owner: self.primary_owner,
+            multicast_groups: self.multicast_groups,
+            firewall_allowed: self.firewall_allowed,
+            socket_inode: self.socket_inode,
+            fd: self.fd,
+        }
+    }
+}
+
+/// A raw or ICMP socket, which has an IP protocol rather than a port. These are only listed in a [crate::Snapshot]
+/// which asked for them, never by [crate::PortQuery::execute]. See `CaptureOptions::include_raw_sockets`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct RawSocket {
+    /// The IP protocol number, such as 1 for ICMP or 58 for ICMPv6
+    pub protocol: u8,
+    /// Whether this is an ICMP echo socket, which a process can open without `CAP_NET_RAW` on Linux when its group is
+    /// in `net.ipv4.ping_group_range`, rather than a raw socket
+    pub ping: bool,
+    /// The address family of the socket
+    pub family: AddressFamily,
+    /// The local address the socket is bound to, which is unspecified if it is not bound to one
+    pub local_addr: IpAddr,
+    /// The ID of the process holding the socket
+    pub pid: Pid,
+    /// The other processes which hold the same socket, in ascending order
+    pub shared_with: Vec<Pid>,
+}
+
+impl RawSocket {
+    /// Create a raw socket with only its protocol, address family and owning process, for use as a test fixture.
+    ///
+    /// It is not a ping socket, is bound to the unspecified address and is not shared.
+    pub fn new(protocol: u8, family: AddressFamily, pid: Pid) -> Self {
+        RawSocket {
+            protocol,
+            ping: false,
+            family,
+            local_addr: family.unspecified(),
+            pid,
+            shared_with: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bound_to(addr: &str) -> PortInfo {
+        let addr = addr.parse::<SocketAddr>().unwrap();
+        PortInfo::builder()
+            .port(ProtocolPort::Tcp(addr.port()))
+            .local_addr(addr)
+            .build()
+    }
+
+    #[test]
+    fn unspecified_addresses_connect_on_loopback_of_the_same_family() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
+            bound_to("0.0.0.0:8080").connectable_addr()
+        );
+        assert_eq!(
+            "[::1]:8080".parse::<SocketAddr>().unwrap(),
+            bound_to("[::]:8080").connectable_addr()
+        );
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_connect_as_ipv4() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
+            bound_to("[::ffff:0.0.0.0]:8080").connectable_addr()
+        );
+        assert_eq!(
+            "10.1.2.3:8080".parse::<SocketAddr>().unwrap(),
+            bound_to("[::ffff:10.1.2.3]:8080").connectable_addr()
+        );
+    }
+
+    #[test]
+    fn specific_addresses_are_kept_with_their_scope() {
+        for addr in ["10.1.2.3:8080", "[::1]:8080", "[2001:db8::1]:8080"] {
+            assert_eq!(
+                addr.parse::<SocketAddr>().unwrap(),
+                bound_to(addr).connectable_addr()
+            );
+        }
+
+        let link_local = SocketAddr::from(std::net::SocketAddrV6::new(
+            "fe80::1".parse().unwrap(),
+            8080,
+            0,
+            3,
+        ));
+        let info = PortInfo::builder().local_addr(link_local).build();
+        assert_eq!(AddressFamily::Ipv6, info.family);
+        assert_eq!(link_local, info.connectable_addr());
+    }
+
+    #[test]
+    fn only_loopback_addresses_are_loopback() {
+        for addr in [
+            "127.0.0.1:8080",
+            "127.1.2.3:8080",
+            "[::1]:8080",
+            "[::ffff:127.0.0.1]:8080",
+        ] {
+            assert!(bound_to(addr).is_loopback(), "{}", addr);
+        }
+        for addr in [
+            "0.0.0.0:8080",
+            "[::]:8080",
+            "10.1.2.3:8080",
+            "[::ffff:10.1.2.3]:8080",
+        ] {
+            assert!(!bound_to(addr).is_loopback(), "{}", addr);
+        }
+
+        // Taken to be bound to the unspecified address
+        assert!(!PortInfo::builder().build().is_loopback());
+    }
+
+    #[test]
+    fn unknown_addresses() {
+        let info = PortInfo::builder()
+            .port(ProtocolPort::Udp(5353))
+            .family(AddressFamily::Ipv6)
+            .build();
+
+        assert_eq!(
+            "[::]:5353".parse::<SocketAddr>().unwrap(),
+            info.socket_addr()
+        );
+        assert_eq!(
+            "[::1]:5353".parse::<SocketAddr>().unwrap(),
+            info.connectable_addr()
+        );
+        assert!(matches!(
+            SocketAddr::try_from(&info),
+            Err(crate::ProcCtlError::AddressUnknown(ProtocolPort::Udp(5353)))
+        ));
+        assert_eq!(
+            "0.0.0.0:8080".parse::<SocketAddr>().unwrap(),
+            SocketAddr::try_from(&bound_to("0.0.0.0:8080")).unwrap()
+        );
+    }
+
+    fn on(port: ProtocolPort, family: AddressFamily, pid: Pid) -> PortInfoBuilder {
+        PortInfo::builder().port(port).family(family).pid(pid)
+    }
+
+    #[test]
+    fn merge_combines_one_port_of_a_process_across_families() {
+        let merged = merge_families(vec![
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv6, 1)
+                .backlog(64)
+                .build(),
+            on(ProtocolPort::Udp(8080), AddressFamily::Ipv4, 1).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 1)
+                .backlog(128)
+                .build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 2).build(),
+        ]);
+
+        assert_eq!(3, merged.len());
+        assert_eq!(
+            vec![AddressFamily::Ipv4, AddressFamily::Ipv6],
+            merged[0].families
+        );
+        // The details are those of the IPv4 socket
+        assert_eq!(AddressFamily::Ipv4, merged[0].family);
+        assert_eq!(Some(128), merged[0].backlog);
+        assert_eq!("tcp46 8080 (pid 1)", merged[0].to_string());
+        assert_eq!("udp4 8080 (pid 1)", merged[1].to_string());
+        assert_eq!("tcp4 8080 (pid 2)", merged[2].to_string());
+
+        let summary = PortSummary::from_ports(&merged);
+        assert_eq!(vec![8080], summary.tcp_v4);
+        assert_eq!(vec![8080], summary.tcp_v6);
+    }
+
+    #[test]
+    fn merge_keeps_separate_sockets_of_one_family() {
+        let merged = merge_families(vec![
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 1).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 1).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv6, 1).build(),
+        ]);
+
+        assert_eq!(2, merged.len());
+        assert_eq!(
+            vec![AddressFamily::Ipv4, AddressFamily::Ipv6],
+            merged[0].families
+        );
+        assert_eq!(vec![AddressFamily::Ipv4], merged[1].families);
+    }
+
+    #[test]
+    fn merge_shared_lists_a_socket_held_by_several_processes_once() {
+        let merged = merge_shared(vec![
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 10).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 11).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv6, 12).build(),
+            on(ProtocolPort::Tcp(9000), AddressFamily::Ipv4, 12)
+                .primary_owner(13)
+                .build(),
+            on(ProtocolPort::Tcp(9000), AddressFamily::Ipv4, 13)
+                .primary_owner(13)
+                .build(),
+        ]);
+
+        assert_eq!(
+            vec![
+                "tcp4 8080 (pid 10)",
+                "tcp6 8080 (pid 12)",
+                "tcp4 9000 (pid 13)"
+            ],
+            merged.iter().map(PortInfo::to_string).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sort_orders_by_protocol_then_port() {
+        let sorted = vec![
+            on(ProtocolPort::Tcp(22), AddressFamily::Ipv6, 12).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 10).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv4, 11).build(),
+            on(ProtocolPort::Tcp(8080), AddressFamily::Ipv6, 10).build(),
+            on(ProtocolPort::Udp(53), AddressFamily::Ipv4, 10).build(),
+            on(ProtocolPort::Udp(5353), AddressFamily::Ipv4, 10).build(),
+        ];
+
+        // Every rotation and the reverse of the sorted ports, as a backend could find them in any order
+        let mut orders = (0..sorted.len())
+            .map(|n| {
+                let mut ports = sorted.clone();
+                ports.rotate_left(n);
+                ports
+            })
+            .collect::<Vec<_>>();
+        orders.push(sorted.iter().rev().cloned().collect());
+
+        for mut ports in orders {
+            sort_ports(&mut ports);
+            assert_eq!(sorted, ports);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Finding the unix domain sockets a process is listening on, for [crate::PortQuery::unix_sockets].
+//!
+//! On Linux the sockets are read from `/proc/<pid>/net/unix`, which lists every unix socket in the network namespace
+//! of the process, and joined with the socket descriptors of the process by inode. On macOS they are listed by
+//! `lsof -U`. Unix sockets don't have a port, so they are found separately from the ports of a query.
+
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+use crate::error::ProcCtlResult;
+use crate::types::Pid;
+use std::path::PathBuf;
+
+/// The address a unix domain socket is bound to
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "kind", content = "name", rename_all = "lowercase")
+)]
+pub enum UnixSocketAddr {
+    /// A socket bound to a path in the filesystem
+    Path(PathBuf),
+    /// A socket bound to a name in the abstract namespace, which is only found on Linux. The name doesn't include the
+    /// leading NUL, or the `@` which `/proc` and tools such as `ss` show in its place.
+    Abstract(String),
+}
+
+impl std::fmt::Display for UnixSocketAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnixSocketAddr::Path(path) => write!(f, "{}", path.display()),
+            UnixSocketAddr::Abstract(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+/// A unix domain socket which a process is listening on, found by [crate::PortQuery::unix_sockets]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct UnixSocket {
+    /// The process holding the socket
+    pub pid: Pid,
+    /// The path or abstract name the socket is bound to
+    pub addr: UnixSocketAddr,
+}
+
+impl std::fmt::Display for UnixSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unix {} (pid {})", self.addr, self.pid)
+    }
+}
+
+/// Combine the sockets of each process, in the order of `pids`, listing a socket which several of them hold once for
+/// the first process found holding it
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+pub(crate) fn of_pids(
+    pids: &[Pid],
+    mut of_pid: impl FnMut(Pid) -> ProcCtlResult<Vec<UnixSocket>>,
+) -> ProcCtlResult<Vec<UnixSocket>> {
+    let mut out: Vec<UnixSocket> = Vec::new();
+    for pid in pids {
+        for socket in of_pid(*pid)? {
+            if !out.iter().any(|s| s.addr == socket.addr) {
+                out.push(socket);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// The bound unix sockets of one process. Sockets which are connected, including the connections a listener has
+/// accepted, are left out even though `/proc` lists them with the path of the listener.
+#[cfg(target_os = "linux")]
+pub(crate) fn of_linux_pid(pid: Pid) -> ProcCtlResult<Vec<UnixSocket>> {
+    let classify = |e| crate::port_query::classify_proc_error(pid, e);
+    let proc = procfs::process::Process::new(crate::pid::to_procfs(pid)?).map_err(classify)?;
+    let inodes = proc
+        .fd()
+        .map_err(classify)?
+        .filter_map(|fd| match fd.ok()?.target {
+            procfs::process::FDTarget::Socket(inode) => Some(inode),
+            _ => None,
+        })
+        .collect::<std::collections::HashSet<_>>();
+
+    Ok(proc
+        .unix()
+        .map_err(classify)?
+        .into_iter()
+        .filter(|entry| {
+            entry.state == procfs::net::UnixState::UNCONNECTED && inodes.contains(&entry.inode)
+        })
+        .filter_map(|entry| {
+            Some(UnixSocket {
+                pid,
+                addr: linux_addr(entry.path?),
+            })
+        })
+        .collect())
+}
+
+/// The address of a socket as `/proc/net/unix` shows it, where a name in the abstract namespace starts with `@`
+#[cfg(any(target_os = "linux", test))]
+fn linux_addr(path: PathBuf) -> UnixSocketAddr {
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        Some(name) => UnixSocketAddr::Abstract(name.to_string()),
+        None => UnixSocketAddr::Path(path),
+    }
+}
+
+/// Run lsof for the unix sockets of every process
+#[cfg(any(target_os = "macos", test))]
+pub(crate) fn lsof_unix_sockets(
+    runner: &dyn crate::tool::ToolRunner,
+    max_concurrency: usize,
+) -> ProcCtlResult<Vec<UnixSocket>> {
+    let output = crate::tool::output(runner, "lsof", &["-U", "-nP", "-F0pn"], max_concurrency)
+        .map_err(|e| crate::ProcCtlError::from_restricted_io("running lsof", e))?;
+
+    // lsof exits with an error when it finds nothing, so a failure only matters if it was denied access
+    if !output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains("Operation not permitted")
+    {
+        return Err(crate::ProcCtlError::SandboxRestricted(
+            "reading unix sockets with lsof".to_string(),
+        ));
+    }
+
+    Ok(parse_lsof_unix(&output.stdout))
+}
+
+/// Parse the output of `lsof -U -F0pn`.
+///
+/// The name of a bound socket is its path. A connected socket is named by the address of its peer, after `->`, and
+/// one which isn't bound is named by the address of its kernel structure, so only names which are paths are kept.
+#[cfg(any(target_os = "macos", test))]
+fn parse_lsof_unix(output: &[u8]) -> Vec<UnixSocket> {
+    let mut out: Vec<UnixSocket> = Vec::new();
+
+    let mut pid = None;
+    for field in output.split(|b| *b == 0 || *b == b'\n') {
+        let Some((&id, value)) = field.split_first() else {
+            continue;
+        };
+        let value = String::from_utf8_lossy(value);
+
+        match id {
+            b'p' => pid = value.parse::<Pid>().ok(),
+            b'n' if value.starts_with('/') && !value.contains("->") => {
+                let Some(pid) = pid else {
+                    continue;
+                };
+                let addr = UnixSocketAddr::Path(PathBuf::from(value.as_ref()));
+                if !out.iter().any(|s| s.pid == pid && s.addr == addr) {
+                    out.push(UnixSocket { pid, addr });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn socket(pid: Pid, path: &str) -> UnixSocket {
+        UnixSocket {
+            pid,
+            addr: UnixSocketAddr::Path(PathBuf::from(path)),
+        }
+    }
+
+    #[test]
+    fn abstract_names_are_told_apart_from_paths() {
+        assert_eq!(
+            UnixSocketAddr::Abstract("/tmp/.X11-unix/X0".to_string()),
+            linux_addr(PathBuf::from("@/tmp/.X11-unix/X0"))
+        );
+        assert_eq!(
+            UnixSocketAddr::Path(PathBuf::from("/run/app.sock")),
+            linux_addr(PathBuf::from("/run/app.sock"))
+        );
+        assert_eq!(
+            "@/tmp/.X11-unix/X0",
+            linux_addr(PathBuf::from("@/tmp/.X11-unix/X0")).to_string()
+        );
+    }
+
+    #[test]
+    fn lsof_lists_bound_sockets_by_path() {
+        let runner = Arc::new(crate::tool::CannedRunner::new(
+            0,
+            b"p100\0\nf3\0n/var/run/app.sock\0\nf4\0n->0x6b3c1d2e\0\nf5\0n/var/run/app.sock\0\n\
+            p200\0\nf6\0n0x5d2f3e4a\0\nf7\0n/tmp/other.sock\0\n",
+            b"",
+        ));
+
+        let sockets = lsof_unix_sockets(runner.as_ref(), 1).unwrap();
+        assert_eq!(
+            vec![
+                socket(100, "/var/run/app.sock"),
+                socket(200, "/tmp/other.sock")
+            ],
+            sockets
+        );
+        assert_eq!(vec![vec!["lsof", "-U", "-nP", "-F0pn"]], runner.runs());
+    }
+
+    #[test]
+    fn lsof_which_is_denied_access_is_sandbox_restricted() {
+        let runner = crate::tool::CannedRunner::new(1, b"", b"lsof: Operation not permitted\n");
+
+        assert!(matches!(
+            lsof_unix_sockets(&runner, 1),
+            Err(crate::ProcCtlError::SandboxRestricted(_))
+        ));
+    }
+
+    #[test]
+    fn a_socket_held_by_several_processes_is_listed_once() {
+        let sockets = of_pids(&[10, 11], |pid| {
+            Ok(vec![
+                socket(pid, "/run/app.sock"),
+                socket(pid, &format!("/run/{}.sock", pid)),
+            ])
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                "unix /run/app.sock (pid 10)",
+                "unix /run/10.sock (pid 10)",
+                "unix /run/11.sock (pid 11)"
+            ],
+            sockets
+                .iter()
+                .map(UnixSocket::to_string)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Waiting for a query to reach a condition, keeping a bounded history of what was seen along the way.
+//!
+//! Every `wait_for_*` method of a query returns a [WaitOutcome] when the condition is met, and every wait fails with
+//! [crate::ProcCtlError::WaitTimedOut] carrying the same history, so that a wait which failed in CI can be explained
+//! from its error alone.
+
+use crate::clock::Clock;
+use crate::error::{ProcCtlError, ProcCtlResult};
+use crate::time::Deadline;
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+/// How long to wait, and how much history to keep, for the `wait_for_*` functions
+#[derive(Debug, Clone)]
+pub struct WaitOptions {
+    timeout: Duration,
+    interval: Duration,
+    max_history: usize,
+}
+
+impl WaitOptions {
+    /// Wait for up to `timeout`, checking every 100ms and keeping the last 10 observations
+    pub fn new(timeout: Duration) -> Self {
+        WaitOptions {
+            timeout,
+            interval: Duration::from_millis(100),
+            max_history: 10,
+        }
+    }
+
+    /// Set how long to wait between checks
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set how many of the most recent observations to keep. Older observations are dropped, so memory use does not
+    /// grow with the length of the wait.
+    pub fn max_history(mut self, max_history: usize) -> Self {
+        self.max_history = max_history;
+        self
+    }
+}
+
+/// What was seen by one check during a wait
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservationSummary {
+    /// Which check this was, starting from 1
+    pub attempt: usize,
+    /// How long after the start of the wait the check was made
+    pub elapsed: Duration,
+    /// A description of the query result, or of the error if the query failed
+    pub seen: String,
+}
+
+/// How a wait went, shared by successful and timed out waits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitHistory {
+    /// The number of checks made
+    pub attempts: usize,
+    /// How long the wait took
+    pub elapsed: Duration,
+    /// The most recent observations, oldest first, up to [WaitOptions::max_history]
+    pub observations: Vec<ObservationSummary>,
+}
+
+/// How a wait slept between its checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WaitStrategy {
+    /// Sleeping for the interval of the [WaitOptions]
+    Polling,
+    /// Waiting for a pidfd of each process to become readable, which it does as soon as the process exits. Only used
+    /// on Linux.
+    PidFd,
+    /// Waiting on a handle to each process from a blocking thread, which wakes as soon as the process exits. Only used
+    /// on Windows.
+    ProcessHandle,
+}
+
+/// The result of a wait which succeeded
+#[derive(Debug, Clone)]
+pub struct WaitOutcome<T> {
+    /// The value which met the condition
+    pub value: T,
+    /// The number of checks made
+    pub attempts: usize,
+    /// How long the wait took
+    pub elapsed: Duration,
+    /// The most recent observations, oldest first, up to [WaitOptions::max_history]
+    pub observations: Vec<ObservationSummary>,
+    /// How the wait slept before its last check. A wait which was met by its first check never slept, and reports
+    /// [WaitStrategy::Polling].
+    pub strategy: WaitStrategy,
+}
+
+/// Run `check` until it returns a value or the timeout passes. Along with the value, `check` returns a description of
+/// what it saw for the history.
+#[cfg(feature = "async")]
+pub(crate) async fn wait_until<T>(
+    options: &WaitOptions,
+    clock: &dyn Clock,
+    mut check: impl FnMut() -> (Option<T>, String),
+) -> ProcCtlResult<WaitOutcome<T>> {
+    let mut waiting = Waiting::start(options, clock);
+    loop {
+        match waiting.step(clock, check()) {
+            ControlFlow::Break(result) => return result,
+            ControlFlow::Continue(sleep) => clock.sleep_async(sleep).await,
+        }
+    }
+}
+
+/// Run `check` until it returns a value or the timeout passes, sleeping until the future from `watch` completes rather
+/// than for the interval where it can.
+///
+/// When `check` isn't met it can return something to watch, which `watch` is given along with the time left before the
+/// timeout. If `watch` can't wait for it, or `check` has nothing to watch, the wait sleeps for the interval as
+/// [wait_until] does. Either way `check` decides when the wait is over, so waking early is harmless.
+#[cfg(all(feature = "async", feature = "proc"))]
+pub(crate) async fn wait_until_woken<T, W>(
+    options: &WaitOptions,
+    clock: &dyn Clock,
+    mut check: impl FnMut() -> (Option<T>, String, Option<W>),
+    mut watch: impl FnMut(W, Duration) -> Option<(WaitStrategy, crate::clock::SleepFuture)>,
+) -> ProcCtlResult<WaitOutcome<T>> {
+    let mut waiting = Waiting::start(options, clock);
+    let mut strategy = WaitStrategy::Polling;
+    loop {
+        let (value, seen, watched) = check();
+        let sleep = match waiting.step(clock, (value, seen)) {
+            ControlFlow::Break(result) => {
+                return result.map(|outcome| WaitOutcome {
+                    strategy,
+                    ..outcome
+                })
+            }
+            ControlFlow::Continue(sleep) => sleep,
+        };
+
+        let remaining = waiting.deadline.remaining(clock);
+        match watched.and_then(|watched| watch(watched, remaining)) {
+            Some((used, woken)) => {
+                strategy = used;
+                first_of(woken, clock.sleep_async(remaining)).await;
+            }
+            None => {
+                strategy = WaitStrategy::Polling;
+                clock.sleep_async(sleep).await;
+            }
+        }
+    }
+}
+
+/// Wait until either of two futures completes
+#[cfg(all(feature = "async", feature = "proc"))]
+async fn first_of(mut a: crate::clock::SleepFuture, mut b: crate::clock::SleepFuture) {
+    std::future::poll_fn(|cx| {
+        if a.as_mut().poll(cx).is_ready() || b.as_mut().poll(cx).is_ready() {
+            std::task::Poll::Ready(())
+        } else {
+            std::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Blocking equivalent of [wait_until]
+pub(crate) fn wait_until_sync<T>(
+    options: &WaitOptions,
+    clock: &dyn Clock,
+    mut check: impl FnMut() -> (Option<T>, String),
+) -> ProcCtlResult<WaitOutcome<T>> {
+    let mut waiting = Waiting::start(options, clock);
+    loop {
+        match waiting.step(clock, check()) {
+            ControlFlow::Break(result) => return result,
+            ControlFlow::Continue(sleep) => clock.sleep(sleep),
+        }
+    }
+}
+
+/// The state of a wait in progress, shared by the blocking and async waits
+struct Waiting<'a> {
+    options: &'a WaitOptions,
+    deadline: Deadline,
+    observations: VecDeque<ObservationSummary>,
+    attempts: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn start(options: &'a WaitOptions, clock: &dyn Clock) -> Self {
+        Waiting {
+            options,
+            deadline: Deadline::start(clock, options.timeout),
+            observations: VecDeque::with_capacity(options.max_history),
+            attempts: 0,
+        }
+    }
+
+    /// Record the result of a check, then either finish the wait or give how long to sleep before the next check
+    fn step<T>(
+        &mut self,
+        clock: &dyn Clock,
+        (value, seen): (Option<T>, String),
+    ) -> ControlFlow<ProcCtlResult<WaitOutcome<T>>, Duration> {
+        self.attempts += 1;
+        let elapsed = self.deadline.elapsed(clock);
+
+        if self.options.max_history > 0 {
+            if self.observations.len() == self.options.max_history {
+                self.observations.pop_front();
+            }
+            self.observations.push_back(ObservationSummary {
+                attempt: self.attempts,
+                elapsed,
+                seen,
+            });
+        }
+
+        if let Some(value) = value {
+            return ControlFlow::Break(Ok(WaitOutcome {
+                value,
+                attempts: self.attempts,
+                elapsed,
+                observations: std::mem::take(&mut self.observations).into(),
+                strategy: WaitStrategy::Polling,
+            }));
+        }
+
+        match self.deadline.next_sleep(clock, self.options.interval) {
+            Some(sleep) => ControlFlow::Continue(sleep),
+            None => ControlFlow::Break(Err(ProcCtlError::WaitTimedOut(WaitHistory {
+                attempts: self.attempts,
+                elapsed,
+                observations: std::mem::take(&mut self.observations).into(),
+            }))),
+        }
+    }
+}
+
+/// Describe the result of a query for the history
+pub(crate) fn describe<T: std::fmt::Debug>(result: Result<T, &ProcCtlError>) -> String {
+    match result {
+        Ok(value) => format!("{:?}", value),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn history_is_bounded() {
+        let options = WaitOptions::new(Duration::from_secs(1))
+            .interval(Duration::from_millis(100))
+            .max_history(3);
+
+        let err = wait_until(&options, &ManualClock::new(), || {
+            (None::<()>, "nothing".to_string())
+        })
+        .await
+        .unwrap_err();
+
+        let ProcCtlError::WaitTimedOut(history) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(11, history.attempts);
+        assert_eq!(
+            vec![9, 10, 11],
+            history
+                .observations
+                .iter()
+                .map(|o| o.attempt)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn outcome_includes_the_final_observation() {
+        let options = WaitOptions::new(Duration::from_secs(1));
+
+        let mut count = 0;
+        let outcome = wait_until(&options, &ManualClock::new(), || {
+            count += 1;
+            ((count == 3).then_some(count), format!("count {}", count))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(3, outcome.value);
+        assert_eq!(3, outcome.attempts);
+        assert_eq!("count 3", outcome.observations.last().unwrap().seen);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn elapsed_time_is_measured_on_the_clock() {
+        let options =
+            WaitOptions::new(Duration::from_millis(300)).interval(Duration::from_millis(100));
+        let clock = ManualClock::new();
+
+        // Time which passes outside the clock, like a step in the system clock, is not counted
+        let err = wait_until(&options, &clock, || {
+            std::thread::sleep(Duration::from_millis(5));
+            (None::<()>, "nothing".to_string())
+        })
+        .await
+        .unwrap_err();
+
+        let ProcCtlError::WaitTimedOut(history) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(4, history.attempts);
+        assert_eq!(Duration::from_millis(300), history.elapsed);
+        assert_eq!(
+            vec![0, 100, 200, 300],
+            history
+                .observations
+                .iter()
+                .map(|o| o.elapsed.as_millis())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec![Duration::from_millis(100); 3], clock.sleeps());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn no_history_is_kept_when_disabled() {
+        let options = WaitOptions::new(Duration::ZERO).max_history(0);
+
+        let err = wait_until(&options, &ManualClock::new(), || {
+            (None::<()>, "nothing".to_string())
+        })
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, ProcCtlError::WaitTimedOut(h) if h.observations.is_empty()));
+    }
+
+    #[cfg(all(feature = "async", feature = "proc"))]
+    #[tokio::test]
+    async fn woken_wait_does_not_sleep_the_interval() {
+        let options = WaitOptions::new(Duration::from_secs(10)).interval(Duration::from_secs(1));
+        let clock = ManualClock::new();
+
+        let mut count = 0;
+        let outcome = wait_until_woken(
+            &options,
+            &clock,
+            || {
+                count += 1;
+                (
+                    (count == 3).then_some(()),
+                    format!("count {}", count),
+                    Some(count),
+                )
+            },
+            |watched, remaining| {
+                assert_eq!(Duration::from_secs(10), remaining);
+                // Only the first check gives something which can be watched
+                (watched == 1).then(|| {
+                    let woken: crate::clock::SleepFuture = Box::pin(async {});
+                    (WaitStrategy::PidFd, woken)
+                })
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(3, outcome.attempts);
+        assert_eq!(WaitStrategy::Polling, outcome.strategy);
+        assert_eq!(vec![Duration::from_secs(1)], clock.sleeps());
+    }
+
+    #[test]
+    fn blocking_wait_sleeps_on_the_clock() {
+        let options =
+            WaitOptions::new(Duration::from_millis(250)).interval(Duration::from_millis(100));
+        let clock = ManualClock::new();
+
+        let mut count = 0;
+        let outcome = wait_until_sync(&options, &clock, || {
+            count += 1;
+            ((count == 2).then_some(count), format!("count {}", count))
+        })
+        .unwrap();
+        assert_eq!(2, outcome.attempts);
+        assert_eq!(vec![Duration::from_millis(100)], clock.sleeps());
+
+        let err =
+            wait_until_sync(&options, &clock, || (None::<()>, "nothing".to_string())).unwrap_err();
+        let ProcCtlError::WaitTimedOut(history) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(4, history.attempts);
+        assert_eq!(Duration::from_millis(250), history.elapsed);
+    }
+}
@@ -0,0 +1,401 @@
+//! Streams of changes, observed by running a query repeatedly.
+//!
+//! Port changes can be read from a blocking [Iterator], see [PortWatcher], and with the `async` feature from a
+//! [Stream](futures_core::Stream). The query is only run when the consumer asks for the next event and at least one
+//! interval has passed since the previous run. Each run is compared against the state at the previous run, so a consumer which falls behind never
+//! causes events to be buffered. Instead, changes which happen between two runs are coalesced: a port which is bound
+//! and released again between runs produces no events at all.
+//!
+//! A watcher can also keep a history of the events it has seen, for assertions on the whole of a scenario once it has
+//! finished, see [PortEvents::with_history].
+
+#[cfg(feature = "async")]
+use crate::clock::{Clock, SleepFuture};
+use crate::error::{ErrorKind, ProcCtlResult};
+#[cfg(feature = "async")]
+use crate::history::PortHistory;
+use crate::port_query::PortQuery;
+use crate::types::ProtocolPort;
+#[cfg(feature = "async")]
+use futures_core::Stream;
+use std::collections::{BTreeSet, VecDeque};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+/// A change to the ports of a process
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PortEvent {
+    /// The process has bound a port
+    PortBound(ProtocolPort),
+    /// The process has released a port
+    PortReleased(ProtocolPort),
+    /// The process has exited. This is always the last event.
+    ///
+    /// On platforms which can't tell an exited process apart from one with no ports, all of its ports are reported as
+    /// released instead.
+    ProcessExited,
+}
+
+/// An [Iterator] of [PortEvent]s, created with [PortQuery::watch]
+///
+/// This is the blocking equivalent of the stream from `PortQuery::events` with the `async` feature, and sleeps on the
+/// clock of the query until the next run is due. Any ports which are already bound when the watcher starts are reported as [PortEvent::PortBound].
+/// Errors from running the query are returned without ending the iterator, so that transient failures can be
+/// skipped, and it ends after [PortEvent::ProcessExited].
+#[derive(Debug)]
+pub struct PortWatcher {
+    query: PortQuery,
+    interval: Duration,
+    last_run: Option<Instant>,
+    ports: BTreeSet<ProtocolPort>,
+    pending: VecDeque<PortEvent>,
+    done: bool,
+}
+
+impl PortWatcher {
+    pub(crate) fn new(query: PortQuery, interval: Duration) -> Self {
+        PortWatcher {
+            query,
+            interval,
+            last_run: None,
+            ports: BTreeSet::new(),
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Wait until one interval has passed since the previous run, if there was one
+    fn wait_for_next_run(&mut self) {
+        let clock = self.query.clock();
+        if let Some(last_run) = self.last_run {
+            let elapsed = clock.now().saturating_duration_since(last_run);
+            if elapsed < self.interval {
+                clock.sleep(self.interval - elapsed);
+            }
+        }
+        self.last_run = Some(clock.now());
+    }
+}
+
+impl Iterator for PortWatcher {
+    type Item = ProcCtlResult<PortEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+
+            self.wait_for_next_run();
+            match observe_ports(&self.query, &mut self.ports, &mut self.pending) {
+                Ok(exited) => self.done = exited,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Run `query`, queueing the changes since `ports` were found as events and keeping the ports found for next time.
+/// Returns whether the process has exited, in which case [PortEvent::ProcessExited] is queued last.
+fn observe_ports(
+    query: &PortQuery,
+    ports: &mut BTreeSet<ProtocolPort>,
+    pending: &mut VecDeque<PortEvent>,
+) -> ProcCtlResult<bool> {
+    match query.list_ports(false) {
+        Ok(found) => {
+            let found = found.into_iter().map(|p| p.port).collect::<BTreeSet<_>>();
+            pending.extend(
+                ports
+                    .difference(&found)
+                    .map(|p| PortEvent::PortReleased(*p)),
+            );
+            pending.extend(found.difference(ports).map(|p| PortEvent::PortBound(*p)));
+            *ports = found;
+            Ok(false)
+        }
+        Err(e) if e.kind() == ErrorKind::ProcessNotFound => {
+            pending.push_back(PortEvent::ProcessExited);
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A [Stream] of [PortEvent]s, created with [PortQuery::events]
+///
+/// Any ports which are already bound when the stream starts are reported as [PortEvent::PortBound]. Errors from
+/// running the query are yielded without ending the stream, so that transient failures can be skipped.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct PortEvents {
+    query: PortQuery,
+    ticker: Ticker,
+    ports: BTreeSet<ProtocolPort>,
+    pending: VecDeque<PortEvent>,
+    done: bool,
+    history: Option<PortHistory>,
+}
+
+#[cfg(feature = "async")]
+impl PortEvents {
+    pub(crate) fn new(query: PortQuery, interval: Duration) -> Self {
+        PortEvents {
+            ticker: Ticker::new(interval, query.clock().clone()),
+            query,
+            ports: BTreeSet::new(),
+            pending: VecDeque::new(),
+            done: false,
+            history: None,
+        }
+    }
+
+    /// Keep a history of the most recent `capacity` events, with when each was seen, to read with
+    /// [PortEvents::history]. See [PortHistory] for what happens once it is full.
+    ///
+    /// Events are recorded as the query finds them, whether or not they have been taken from the stream yet.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history = Some(PortHistory::new(capacity, self.query.clock().now()));
+        self
+    }
+
+    /// The history of the events seen so far, if [PortEvents::with_history] was used
+    pub fn history(&self) -> Option<&PortHistory> {
+        self.history.as_ref()
+    }
+
+    /// Only yield [PortEvent::PortBound] and [PortEvent::PortReleased], ending the stream when the process exits
+    pub fn ports_only(self) -> PortsOnly {
+        PortsOnly(self)
+    }
+
+    fn observe(&mut self) -> ProcCtlResult<()> {
+        let already_pending = self.pending.len();
+        self.done = observe_ports(&self.query, &mut self.ports, &mut self.pending)?;
+        let at = self.query.clock().now();
+
+        if let Some(history) = &mut self.history {
+            for event in self.pending.iter().skip(already_pending) {
+                history.record(at, event.clone());
+            }
+            history.observed(at);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl Stream for PortEvents {
+    type Item = ProcCtlResult<PortEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            if this.ticker.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+            if let Err(e) = this.observe() {
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+/// A [Stream] of port changes, created with [PortEvents::ports_only]
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct PortsOnly(PortEvents);
+
+#[cfg(feature = "async")]
+impl Stream for PortsOnly {
+    type Item = ProcCtlResult<PortEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.0).poll_next(cx) {
+            Poll::Ready(Some(Ok(PortEvent::ProcessExited))) => Poll::Ready(None),
+            other => other,
+        }
+    }
+}
+
+/// A change to the set of processes matched by a [crate::ProcQuery]
+#[cfg(all(feature = "async", feature = "proc"))]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// Events are produced at most once per interval, so the size of ProcInfo is not worth an allocation for each one
+#[allow(clippy::large_enum_variant)]
+pub enum ProcEvent {
+    /// A matching process was found which was not running at the previous check
+    Started(crate::ProcInfo),
+    /// A matching process was seen at the previous check and is no longer running or no longer matches
+    Exited(crate::Pid),
+}
+
+/// A [Stream] of [ProcEvent]s, created with [crate::ProcQuery::events]
+///
+/// Any processes which match when the stream starts are reported as [ProcEvent::Started]. The stream never ends on its
+/// own.
+#[cfg(all(feature = "async", feature = "proc"))]
+#[derive(Debug)]
+pub struct ProcEvents {
+    query: crate::ProcQuery,
+    ticker: Ticker,
+    pids: BTreeSet<crate::Pid>,
+    pending: VecDeque<ProcEvent>,
+    history: Option<crate::history::ProcHistory>,
+}
+
+#[cfg(all(feature = "async", feature = "proc"))]
+impl ProcEvents {
+    pub(crate) fn new(query: crate::ProcQuery, interval: Duration) -> Self {
+        ProcEvents {
+            ticker: Ticker::new(interval, query.clock().clone()),
+            query,
+            pids: BTreeSet::new(),
+            pending: VecDeque::new(),
+            history: None,
+        }
+    }
+
+    /// Keep a history of the most recent `capacity` events, as [PortEvents::with_history] does
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history = Some(crate::history::ProcHistory::new(
+            capacity,
+            self.query.clock().now(),
+        ));
+        self
+    }
+
+    /// The history of the events seen so far, if [ProcEvents::with_history] was used
+    pub fn history(&self) -> Option<&crate::history::ProcHistory> {
+        self.history.as_ref()
+    }
+
+    /// Only yield the processes which have started
+    pub fn started_only(self) -> StartedOnly {
+        StartedOnly(self)
+    }
+
+    fn observe(&mut self) -> ProcCtlResult<()> {
+        let mut processes = self.query.list_processes()?;
+        processes.sort_by_key(|p| p.pid);
+        let pids = processes.iter().map(|p| p.pid).collect::<BTreeSet<_>>();
+        let at = self.query.clock().now();
+        let already_pending = self.pending.len();
+
+        self.pending
+            .extend(self.pids.difference(&pids).map(|p| ProcEvent::Exited(*p)));
+        self.pending.extend(
+            processes
+                .into_iter()
+                .filter(|p| !self.pids.contains(&p.pid))
+                .map(ProcEvent::Started),
+        );
+        self.pids = pids;
+
+        if let Some(history) = &mut self.history {
+            for event in self.pending.iter().skip(already_pending) {
+                history.record(at, event.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "async", feature = "proc"))]
+impl Stream for ProcEvents {
+    type Item = ProcCtlResult<ProcEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+
+            if this.ticker.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+            if let Err(e) = this.observe() {
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+/// A [Stream] of newly started processes, created with [ProcEvents::started_only]
+#[cfg(all(feature = "async", feature = "proc"))]
+#[derive(Debug)]
+pub struct StartedOnly(ProcEvents);
+
+#[cfg(all(feature = "async", feature = "proc"))]
+impl Stream for StartedOnly {
+    type Item = ProcCtlResult<crate::ProcInfo>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.0).poll_next(cx)) {
+                Some(Ok(ProcEvent::Started(info))) => return Poll::Ready(Some(Ok(info))),
+                Some(Ok(ProcEvent::Exited(_))) => {}
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Completes immediately the first time, then once per interval after the previous completion
+#[cfg(feature = "async")]
+struct Ticker {
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    sleep: Option<SleepFuture>,
+}
+
+#[cfg(feature = "async")]
+impl Ticker {
+    fn new(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Ticker {
+            interval,
+            clock,
+            sleep: None,
+        }
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(sleep) = &mut self.sleep {
+            ready!(sleep.as_mut().poll(cx));
+        }
+
+        self.sleep = Some(self.clock.sleep_async(self.interval));
+        Poll::Ready(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl std::fmt::Debug for Ticker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ticker")
+            .field("interval", &self.interval)
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
@@ -5,21 +5,10 @@
     target_os = "macos"
 ))]
 fn create_command_for_sample(name: &str) -> std::process::Command {
-    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
-        .join("target")
-        .join("release")
-        .join(name);
-    #[cfg(target_os = "windows")]
-    let path = path.with_extension("exe");
-
-    if !path.exists() {
-        panic!(
-            "{} does not exist, try running `cargo build --release --bins`",
-            path.display()
-        );
-    }
-
-    std::process::Command::new(path)
+    proc_ctl::samples::SampleBinaries::new(env!("CARGO_MANIFEST_DIR"))
+        .package(env!("CARGO_PKG_NAME"))
+        .features(["test-util"])
+        .command(name)
 }
 
 #[cfg(any(
@@ -164,6 +153,180 @@ fn udp_port_query_v6() {
     assert_eq!(1, ports.len());
 }
 
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn udp_port_query_returns_udp_ports() {
+    use retry::delay::Fixed;
+
+    for sample in ["udp-port-binder", "udp-port-binder-v6"] {
+        let binder = create_command_for_sample(sample);
+        let mut handle = DropChild::spawn(binder);
+
+        let query = proc_ctl::PortQuery::new()
+            .udp_only()
+            .process_id(handle.id())
+            .expect_min_num_ports(1);
+
+        let ports =
+            retry::retry(Fixed::from_millis(100).take(10), move || query.execute()).unwrap();
+
+        handle.kill().unwrap();
+
+        assert!(
+            ports
+                .iter()
+                .all(|p| matches!(p, proc_ctl::ProtocolPort::Udp(_))),
+            "{} found {:?}",
+            sample,
+            ports
+        );
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_returns_the_bound_port_numbers() {
+    use proc_ctl::ProtocolPort;
+
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tcp_port = ProtocolPort::Tcp(tcp.local_addr().unwrap().port());
+    let udp_port = ProtocolPort::Udp(udp.local_addr().unwrap().port());
+
+    let ports = proc_ctl::PortQuery::new()
+        .ip_v4_only()
+        .process_id(std::process::id())
+        .execute()
+        .unwrap();
+
+    assert!(
+        ports.contains(&tcp_port),
+        "{:?} not in {:?}",
+        tcp_port,
+        ports
+    );
+    assert!(
+        ports.contains(&udp_port),
+        "{:?} not in {:?}",
+        udp_port,
+        ports
+    );
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn udp_port_query_v6_only_skips_v4_ports() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("udp-port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    // Wait for the port to be bound, so that finding nothing below isn't because the binder hasn't started yet
+    let query = proc_ctl::PortQuery::new()
+        .udp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+    retry::retry(Fixed::from_millis(100).take(10), move || query.execute()).unwrap();
+
+    let ports = proc_ctl::PortQuery::new()
+        .udp_only()
+        .ip_v6_only()
+        .process_id(handle.id())
+        .execute()
+        .unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(ports.is_empty(), "Found IPv4 ports {:?}", ports);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn udp_port_query_v4_only_skips_v6_ports() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("udp-port-binder-v6");
+    let mut handle = DropChild::spawn(binder);
+
+    // Wait for the port to be bound, so that finding nothing below isn't because the binder hasn't started yet
+    let query = proc_ctl::PortQuery::new()
+        .udp_only()
+        .ip_v6_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+    retry::retry(Fixed::from_millis(100).take(10), move || query.execute()).unwrap();
+
+    let ports = proc_ctl::PortQuery::new()
+        .udp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .execute()
+        .unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(ports.is_empty(), "Found IPv6 ports {:?}", ports);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn udp_port_query_by_joined_multicast_group() {
+    use proc_ctl::{PortQuery, ProtocolPort};
+    use std::io::BufRead;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::process::Stdio;
+
+    let group = IpAddr::from(Ipv4Addr::new(239, 255, 77, 77));
+
+    let mut cmd = create_command_for_sample("multicast-joiner");
+    cmd.arg(group.to_string()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    if line.starts_with("unavailable") {
+        eprintln!("Skipping, multicast is not available here: {}", line.trim());
+        return;
+    }
+    let port = ProtocolPort::Udp(line.trim().parse().unwrap());
+
+    let joined = PortQuery::new()
+        .udp_only()
+        .process_id(handle.id())
+        .joined_group(group)
+        .execute_detailed()
+        .unwrap();
+    assert_eq!(
+        vec![port],
+        joined.iter().map(|p| p.port).collect::<Vec<_>>()
+    );
+    assert!(joined[0]
+        .multicast_groups
+        .as_ref()
+        .is_some_and(|groups| groups.contains(&group)));
+
+    let not_joined = PortQuery::new()
+        .process_id(handle.id())
+        .joined_group(Ipv4Addr::new(239, 255, 77, 78).into())
+        .execute()
+        .unwrap();
+    assert!(not_joined.is_empty());
+
+    let err = PortQuery::new()
+        .process_id(handle.id())
+        .joined_group(Ipv4Addr::LOCALHOST.into())
+        .execute()
+        .unwrap_err();
+    assert!(
+        matches!(err, proc_ctl::ProcCtlError::ConfigurationError(_)),
+        "{:?}",
+        err
+    );
+}
+
 #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
 #[test]
 fn port_query_which_expects_too_many_ports() {
@@ -212,6 +375,165 @@ fn port_query_with_sync_retry() {
     assert_eq!(1, ports.len());
 }
 
+#[cfg(all(
+    feature = "resilience",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_retry_gives_up_on_a_stall() {
+    use proc_ctl::ProcCtlError;
+    use std::time::{Duration, Instant};
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    // The binder only binds a TCP port, so a UDP port can never be found
+    let query = proc_ctl::PortQuery::new()
+        .udp_only()
+        .process_id_from_child(&handle)
+        .expect_min_num_ports(1)
+        .fail_fast_on_stall(3);
+
+    let start = Instant::now();
+    let result = query.execute_with_retry_sync(Duration::from_millis(50), 200);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(
+        matches!(result, Err(ProcCtlError::StalledExpectation(ref found, 1, 3)) if found.is_empty()),
+        "{:?}",
+        result
+    );
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let result = query.execute_with_retry_sync(Duration::from_millis(50), 200);
+    assert!(
+        matches!(result, Err(ProcCtlError::ProcessExited(pid)) if pid == handle.id()),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(all(
+    feature = "resilience",
+    feature = "test-util",
+    any(target_os = "linux", target_os = "macos")
+))]
+#[test]
+fn port_query_expects_an_exact_number_of_ports() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::ProcCtlError;
+    use std::time::{Duration, Instant};
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 2,
+        ..Default::default()
+    });
+    let query = || {
+        proc_ctl::PortQuery::new()
+            .tcp_only()
+            .process_id(binder.pid())
+    };
+
+    let mut ports = query().expect_exact_num_ports(2).execute().unwrap();
+    ports.sort_unstable();
+    let mut bound = binder.ports();
+    bound.sort_unstable();
+    assert_eq!(bound, ports);
+
+    // Too many ports can't be fixed by retrying, so the retry stops straight away
+    let start = Instant::now();
+    let result = query()
+        .expect_max_num_ports(1)
+        .execute_with_retry_sync(Duration::from_millis(50), 200);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(
+        matches!(result, Err(ProcCtlError::TooManyPorts(ref found, 1)) if found.len() == 2),
+        "{:?}",
+        result
+    );
+
+    let result = query()
+        .expect_exact_num_ports(3)
+        .execute_with_retry_sync(Duration::from_millis(10), 3);
+    assert!(
+        matches!(result, Err(ProcCtlError::TooFewPorts(ref found, 3)) if found.len() == 2),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(all(
+    feature = "resilience",
+    feature = "test-util",
+    any(target_os = "linux", target_os = "macos")
+))]
+#[test]
+fn port_query_waits_for_an_expected_port() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::{ProcCtlError, ProtocolPort};
+    use std::time::Duration;
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 1,
+        ..Default::default()
+    });
+    let port = binder.ports()[0];
+    let query = || proc_ctl::PortQuery::new().process_id(binder.pid());
+
+    let ports = query()
+        .expect_port(port)
+        .execute_with_retry_sync(Duration::from_millis(50), 10)
+        .unwrap();
+    assert_eq!(vec![port], ports);
+
+    let udp = ProtocolPort::Udp(port.port());
+    let result = query()
+        .tcp_only()
+        .expect_port(port)
+        .expect_port(udp)
+        .execute_with_retry_sync(Duration::from_millis(10), 3);
+    assert!(
+        matches!(result, Err(ProcCtlError::MissingPorts(ref missing, ref found)) if missing == &vec![udp] && found == &vec![port]),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(all(
+    feature = "resilience",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_nonblocking_records_last_observed() {
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id_from_child(&handle)
+        .expect_min_num_ports(1);
+    assert!(query.last_observed().is_none());
+
+    let ports = query
+        .execute_with_retry_sync(Duration::from_millis(100), 10)
+        .unwrap();
+    let nonblocking = query.try_execute_nonblocking().unwrap();
+
+    handle.kill().unwrap();
+
+    assert_eq!(ports, nonblocking);
+    let observed = query.last_observed().unwrap();
+    assert_eq!(
+        ports,
+        observed.value.iter().map(|p| p.port).collect::<Vec<_>>()
+    );
+    assert!(observed.at <= std::time::SystemTime::now());
+}
+
 #[cfg(all(
     feature = "async",
     any(target_os = "linux", target_os = "windows", target_os = "macos")
@@ -244,6 +566,7 @@ async fn port_query_with_async_retry() {
 fn proc_query_by_name() {
     use proc_ctl::ProcQuery;
     use std::process::Stdio;
+    use std::time::Duration;
 
     let mut cmd = create_command_for_sample("waiter")
         .stdin(Stdio::piped())
@@ -251,6 +574,8 @@ fn proc_query_by_name() {
         .spawn()
         .unwrap();
 
+    proc_ctl::info_for_child(&cmd, Duration::from_secs(5)).unwrap();
+
     let query = ProcQuery::new().process_name("waiter");
 
     let processes = query.list_processes().unwrap();
@@ -260,6 +585,128 @@ fn proc_query_by_name() {
     assert_eq!(1, processes.len());
 }
 
+#[cfg(feature = "proc")]
+#[test]
+fn info_for_child_right_after_spawn() {
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let mut cmd = create_command_for_sample("waiter");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::null());
+    let mut handle = DropChild::spawn(cmd);
+
+    let info = proc_ctl::info_for_child(&handle, Duration::from_secs(5)).unwrap();
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    assert_eq!(handle.id(), info.pid);
+    assert!(info.start_time > 0);
+    assert!(info
+        .exe
+        .unwrap()
+        .file_stem()
+        .is_some_and(|name| name == "waiter"));
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn info_for_child_after_exit() {
+    use proc_ctl::ErrorKind;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    // With no input the waiter exits straight away
+    let mut cmd = create_command_for_sample("waiter");
+    cmd.stdin(Stdio::null()).stdout(Stdio::null());
+    let mut handle = DropChild::spawn(cmd);
+
+    let result = retry::retry(retry::delay::Fixed::from_millis(100).take(20), || {
+        match proc_ctl::info_for_child(&handle, Duration::from_millis(100)) {
+            Ok(info) => Err(info),
+            Err(e) => Ok(e),
+        }
+    })
+    .unwrap();
+
+    handle.wait().unwrap();
+
+    assert_eq!(ErrorKind::ProcessNotFound, result.kind());
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_with_namespaces() {
+    use proc_ctl::ProcQuery;
+
+    let pid = std::process::id();
+    let without = ProcQuery::new().process_id(pid).list_processes().unwrap();
+    assert_eq!(None, without[0].net_ns);
+
+    let with = ProcQuery::new()
+        .process_id(pid)
+        .with_namespaces(true)
+        .list_processes()
+        .unwrap();
+    assert!(with[0].net_ns.is_some());
+    assert!(with[0].pid_ns.is_some());
+    assert_eq!(
+        std::fs::read_link("/proc/self/root").ok(),
+        with[0].root,
+        "the test process is not expected to be chrooted"
+    );
+    let own_mnt_ns = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+    assert_eq!(
+        Some(format!("mnt:[{}]", with[0].mnt_ns.unwrap())),
+        own_mnt_ns.to_str().map(str::to_string)
+    );
+
+    // Whether the tests run in a container depends on the environment, but the filters must agree with the info
+    let in_container = with[0].container_id.is_some();
+    let same = ProcQuery::new()
+        .process_id(pid)
+        .in_container(in_container)
+        .list_processes()
+        .unwrap();
+    assert_eq!(1, same.len());
+    let opposite = ProcQuery::new()
+        .process_id(pid)
+        .in_container(!in_container)
+        .list_processes()
+        .unwrap();
+    assert!(opposite.is_empty());
+
+    if let Some(id) = &with[0].container_id {
+        let by_prefix = ProcQuery::new()
+            .process_id(pid)
+            .container_id_prefix(&id[..12])
+            .list_processes()
+            .unwrap();
+        assert_eq!(1, by_prefix.len());
+    }
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_paths_relative_to_proc_root() {
+    use proc_ctl::ProcQuery;
+
+    let pid = std::process::id();
+    let plain = ProcQuery::new().process_id(pid).list_processes().unwrap();
+    assert_eq!(None, plain[0].root);
+
+    // The test process shares the root of the query, so its paths are the same either way
+    let relative = ProcQuery::new()
+        .process_id(pid)
+        .paths_relative_to_proc_root(true)
+        .list_processes()
+        .unwrap();
+    assert_eq!(Some(std::path::PathBuf::from("/")), relative[0].root);
+    assert_eq!(None, relative[0].mnt_ns);
+    assert_eq!(plain[0].exe, relative[0].exe);
+    assert_eq!(plain[0].cwd, relative[0].cwd);
+}
+
 #[cfg(feature = "proc")]
 #[test]
 fn proc_query_for_children() {
@@ -278,27 +725,26 @@ fn proc_query_for_children() {
         .expect_min_num_children(1);
 
     let process_names = retry::retry(Fixed::from_millis(100).take(10), move || {
-        query
-            .children()
-            .map(|v| v.into_iter().map(|p| p.name).collect::<Vec<String>>())
+        query.children().map(|v| {
+            v.into_iter()
+                .map(|p| p.canonical_name())
+                .collect::<Vec<String>>()
+        })
     })
     .unwrap();
 
     handle.kill().unwrap();
 
-    assert_eq!(1, process_names.len());
-
-    #[cfg(target_os = "windows")]
-    assert_eq!("port-binder.exe", process_names.first().unwrap());
-    #[cfg(not(target_os = "windows"))]
-    assert_eq!("port-binder", process_names.first().unwrap());
+    assert_eq!(vec!["port-binder"], process_names);
 }
 
-#[cfg(all(feature = "proc", feature = "resilience"))]
+/// The child of a process which has only just started may not have its parent reported by sysinfo yet, but is found
+/// from a Toolhelp snapshot instead
+#[cfg(all(feature = "proc", target_os = "windows"))]
 #[test]
-fn proc_query_for_children_with_retry() {
+fn proc_query_children_of_new_process_windows() {
     use proc_ctl::ProcQuery;
-    use std::time::Duration;
+    use retry::delay::Fixed;
 
     let binder = create_command_for_sample("port-binder");
     let port_binder_path = binder.get_program();
@@ -307,30 +753,26 @@ fn proc_query_for_children_with_retry() {
     runner.args([port_binder_path]);
     let mut handle = DropChild::spawn(runner);
 
-    let process_names = ProcQuery::new()
+    let query = ProcQuery::new()
         .process_id_from_child(&handle)
-        .expect_min_num_children(1)
-        .children_with_retry_sync(Duration::from_millis(100), 10)
-        .unwrap()
-        .into_iter()
-        .map(|p| p.name)
-        .collect::<Vec<String>>();
+        .expect_min_num_children(1);
 
-    handle.kill().unwrap();
+    let children = retry::retry(Fixed::from_millis(20).take(5), || query.children()).unwrap();
 
-    assert_eq!(1, process_names.len());
+    handle.kill().unwrap();
 
-    #[cfg(target_os = "windows")]
-    assert_eq!("port-binder.exe", process_names.first().unwrap());
-    #[cfg(not(target_os = "windows"))]
-    assert_eq!("port-binder", process_names.first().unwrap());
+    assert_eq!(
+        vec!["port-binder.exe"],
+        children.iter().map(|c| c.name.as_str()).collect::<Vec<_>>()
+    );
+    assert_eq!(Some(handle.id()), children[0].parent);
 }
 
-#[cfg(all(feature = "proc", feature = "async"))]
-#[tokio::test]
-async fn proc_query_for_children_async_with_retry() {
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_children_with_self() {
     use proc_ctl::ProcQuery;
-    use std::time::Duration;
+    use retry::delay::Fixed;
 
     let binder = create_command_for_sample("port-binder");
     let port_binder_path = binder.get_program();
@@ -338,23 +780,3486 @@ async fn proc_query_for_children_async_with_retry() {
     let mut runner = create_command_for_sample("proc-runner");
     runner.args([port_binder_path]);
     let mut handle = DropChild::spawn(runner);
+    let runner_pid = handle.id();
 
-    let process_names = ProcQuery::new()
+    let query = ProcQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_children(1);
+
+    let (parent, children) = retry::retry(Fixed::from_millis(100).take(10), || {
+        query.children_with_self()
+    })
+    .unwrap();
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    assert_eq!(runner_pid, parent.pid);
+    assert!(parent.name.starts_with("proc-runner"));
+    assert!(parent.exe.is_some());
+    assert!(!parent.cmd.is_empty());
+    assert_eq!(1, children.len());
+    assert_eq!(Some(runner_pid), children[0].parent);
+
+    let err = query.children_with_self().unwrap_err();
+    assert_eq!(proc_ctl::ErrorKind::ProcessNotFound, err.kind());
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_too_few_children_explains_the_parent() {
+    use proc_ctl::{ProcCtlError, ProcQuery};
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let mut cmd = create_command_for_sample("waiter");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::null());
+    let mut handle = DropChild::spawn(cmd);
+    proc_ctl::info_for_child(&handle, Duration::from_secs(5)).unwrap();
+
+    let err = ProcQuery::new()
         .process_id_from_child(&handle)
         .expect_min_num_children(1)
-        .children_with_retry(Duration::from_millis(100), 10)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|p| p.name)
-        .collect::<Vec<String>>();
+        .children()
+        .unwrap_err();
 
     handle.kill().unwrap();
+    handle.wait().unwrap();
 
-    assert_eq!(1, process_names.len());
+    let ProcCtlError::TooFewChildren(shortfall) = &err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert_eq!(0, shortfall.found_count);
+    assert_eq!(Some(handle.id()), shortfall.parent.as_ref().map(|p| p.pid));
+    assert!(shortfall.parent_status.is_some());
+    assert!(err.to_string().contains("parent: waiter"), "{}", err);
+}
 
-    #[cfg(target_os = "windows")]
-    assert_eq!("port-binder.exe", process_names.first().unwrap());
-    #[cfg(not(target_os = "windows"))]
-    assert_eq!("port-binder", process_names.first().unwrap());
+#[cfg(all(feature = "proc", feature = "resilience"))]
+#[test]
+fn proc_query_for_children_with_retry() {
+    use proc_ctl::ProcQuery;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+
+    let process_names = ProcQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_children(1)
+        .children_with_retry_sync(Duration::from_millis(100), 10)
+        .unwrap()
+        .into_iter()
+        .map(|p| p.canonical_name())
+        .collect::<Vec<String>>();
+
+    handle.kill().unwrap();
+
+    assert_eq!(vec!["port-binder"], process_names);
+}
+
+#[cfg(all(feature = "proc", feature = "async"))]
+#[tokio::test]
+async fn proc_query_for_children_async_with_retry() {
+    use proc_ctl::ProcQuery;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+
+    let process_names = ProcQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_children(1)
+        .children_with_retry(Duration::from_millis(100), 10)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|p| p.canonical_name())
+        .collect::<Vec<String>>();
+
+    handle.kill().unwrap();
+
+    assert_eq!(vec!["port-binder"], process_names);
+}
+
+#[cfg(all(
+    feature = "serde",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_export_json_lines() {
+    use proc_ctl::ExportFormat;
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    let output = retry::retry(Fixed::from_millis(100).take(10), move || {
+        let mut out = Vec::new();
+        query
+            .execute_to_writer(&mut out, ExportFormat::JsonLines)
+            .map(|_| String::from_utf8(out).unwrap())
+    })
+    .unwrap();
+
+    handle.kill().unwrap();
+
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(1, lines.len());
+    assert!(lines[0].starts_with(r#"{"protocol":"tcp","port":"#));
+}
+
+#[cfg(all(
+    feature = "serde",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_export_writes_error_record() {
+    use proc_ctl::ExportFormat;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(2);
+
+    let mut out = Vec::new();
+    let result = query.execute_to_writer(&mut out, ExportFormat::JsonLines);
+
+    handle.kill().unwrap();
+
+    result.expect_err("Should have had an error about too few ports");
+    let output = String::from_utf8(out).unwrap();
+    let record: serde_json::Value = serde_json::from_str(output.lines().last().unwrap()).unwrap();
+    assert_eq!("too_few_ports", record["error"]["code"]);
+}
+
+#[cfg(all(feature = "proc", feature = "serde"))]
+#[test]
+fn proc_query_export_csv() {
+    use proc_ctl::{ExportFormat, ProcQuery};
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("waiter")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    let mut out = Vec::new();
+    ProcQuery::new()
+        .process_id(cmd.id())
+        .list_to_writer(&mut out, ExportFormat::Csv)
+        .unwrap();
+
+    cmd.kill().unwrap();
+
+    let output = String::from_utf8(out).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(2, lines.len());
+    assert_eq!(
+        "name,cmd,argv0,exe,pid,parent,env,cwd,start_time,net_ns,pid_ns,container_id,capabilities,mnt_ns,root,open_files",
+        lines[0]
+    );
+    assert!(lines[1].contains(&cmd.id().to_string()));
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_for_children_by_parent_name() {
+    use proc_ctl::ProcQuery;
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+    let runner_pid = handle.id();
+
+    let query = ProcQuery::new()
+        .parent_name("proc-runner")
+        .process_name("port-binder")
+        .expect_min_num_children(1);
+
+    let children =
+        retry::retry(Fixed::from_millis(100).take(10), move || query.children()).unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(children.iter().any(|c| c.parent == Some(runner_pid)));
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_list_by_parent_name() {
+    use proc_ctl::ProcQuery;
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+    let runner_pid = handle.id();
+
+    let query = ProcQuery::new().parent_name("proc-runner");
+
+    let processes = retry::retry(Fixed::from_millis(100).take(10), move || {
+        query.list_processes().and_then(|p| {
+            if p.iter().any(|p| p.parent == Some(runner_pid)) {
+                Ok(p)
+            } else {
+                Err(proc_ctl::ProcCtlError::NoMatchingProcess(
+                    "child of proc-runner".to_string(),
+                ))
+            }
+        })
+    })
+    .unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(processes
+        .iter()
+        .all(|p| p.canonical_name() == "port-binder"));
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_lists_processes_but_not_their_threads() {
+    use proc_ctl::ProcQuery;
+
+    let (stop, stopped) = std::sync::mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || stopped.recv());
+
+    let threads = std::fs::read_dir("/proc/self/task")
+        .unwrap()
+        .map(|task| task.unwrap().file_name().to_string_lossy().parse().unwrap())
+        .filter(|tid| *tid != std::process::id())
+        .collect::<Vec<u32>>();
+    assert!(!threads.is_empty());
+
+    let processes = ProcQuery::new().list_processes().unwrap();
+
+    drop(stop);
+    thread.join().unwrap().unwrap_err();
+
+    assert!(processes.iter().any(|p| p.pid == std::process::id()));
+    assert!(processes.iter().all(|p| !threads.contains(&p.pid)));
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_info_round_trips_through_sysinfo() {
+    use proc_ctl::sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+    use proc_ctl::{ProcCtlError, ProcInfo, ProcQuery};
+
+    let info = ProcInfo::from_pid(std::process::id()).unwrap();
+    assert_eq!(std::process::id(), info.pid);
+    assert!(info.env_collected);
+
+    let sys_pid = proc_ctl::sysinfo::Pid::try_from(&info).unwrap();
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[sys_pid]),
+        true,
+        ProcessRefreshKind::everything(),
+    );
+    let converted = ProcInfo::from(sys.process(sys_pid).unwrap());
+    assert_eq!(info.pid, converted.pid);
+    assert_eq!(info.name, converted.name);
+    assert_eq!(info.exe, converted.exe);
+    assert_eq!(info.start_time, converted.start_time);
+    assert_eq!(sys_pid, converted.try_into().unwrap());
+
+    let own_pid = sys_pid;
+    let found = ProcQuery::from_sysinfo_filter(move |p| p.pid() == own_pid)
+        .list_processes()
+        .unwrap();
+    assert_eq!(
+        vec![info.pid],
+        found.iter().map(|p| p.pid).collect::<Vec<_>>()
+    );
+
+    let exited = {
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        child.wait().unwrap();
+        child.id()
+    };
+    assert!(matches!(
+        ProcInfo::from_pid(exited),
+        Err(ProcCtlError::ProcessNotFound(pid)) if pid == exited
+    ));
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_detailed_bound_since() {
+    use retry::delay::Fixed;
+    use std::time::SystemTime;
+
+    let before_spawn = SystemTime::now();
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .bound_after(before_spawn)
+        .expect_min_num_ports(1);
+
+    let ports = retry::retry(Fixed::from_millis(100).take(10), move || {
+        query.execute_detailed()
+    })
+    .unwrap();
+
+    handle.kill().unwrap();
+
+    assert_eq!(1, ports.len());
+    assert_eq!(handle.id(), ports[0].pid);
+
+    #[cfg(target_os = "linux")]
+    {
+        let bound_since = ports[0].bound_since.expect("Should know the bind time");
+        assert!(bound_since >= before_spawn);
+        assert!(bound_since <= SystemTime::now() + std::time::Duration::from_millis(100));
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_detailed_firewall_allowed() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    #[cfg(all(target_os = "windows", feature = "windows-firewall"))]
+    let program = binder.get_program().to_owned();
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || {
+        query.execute_detailed()
+    })
+    .unwrap();
+    assert_eq!(1, ports.len());
+
+    #[cfg(not(all(target_os = "windows", feature = "windows-firewall")))]
+    assert_eq!(None, ports[0].firewall_allowed);
+
+    // Changing the firewall needs an elevated prompt, so this only checks a block rule is seen when run as admin
+    #[cfg(all(target_os = "windows", feature = "windows-firewall"))]
+    {
+        let rule = format!("proc-ctl test {}", handle.id());
+        let added = std::process::Command::new("netsh")
+            .args(["advfirewall", "firewall", "add", "rule"])
+            .arg(format!("name={}", rule))
+            .args(["dir=in", "action=block", "protocol=TCP"])
+            .arg(format!("program={}", program.to_string_lossy()))
+            .status()
+            .is_ok_and(|status| status.success());
+        if !added {
+            eprintln!("Skipping, can't add a firewall rule without running as admin");
+            return;
+        }
+
+        let blocked = query.execute_detailed();
+        std::process::Command::new("netsh")
+            .args(["advfirewall", "firewall", "delete", "rule"])
+            .arg(format!("name={}", rule))
+            .status()
+            .unwrap();
+
+        assert_eq!(Some(false), blocked.unwrap()[0].firewall_allowed);
+    }
+
+    handle.kill().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_bound_after_excludes_older_ports() {
+    use retry::delay::Fixed;
+    use std::time::{Duration, SystemTime};
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    // Wait for the port to be bound before checking it gets filtered out
+    retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+
+    let ports = query
+        .bound_after(SystemTime::now() + Duration::from_secs(60))
+        .expect_min_num_ports(0)
+        .execute()
+        .unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(ports.is_empty());
+}
+
+#[cfg(all(
+    feature = "test-util",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn assert_ports_succeeds() {
+    use proc_ctl::assertions::assert_ports;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id_from_child(&handle)
+        .expect_min_num_ports(1);
+
+    let ports = assert_ports(&query, Duration::from_secs(1));
+
+    handle.kill().unwrap();
+
+    assert_eq!(1, ports.len());
+}
+
+#[cfg(all(
+    feature = "test-util",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn assert_ports_panics_with_context() {
+    use proc_ctl::assertions::assert_ports;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+    let pid = handle.id();
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(pid)
+        .expect_min_num_ports(2);
+
+    let result = std::panic::catch_unwind(|| assert_ports(&query, Duration::from_millis(300)));
+
+    handle.kill().unwrap();
+
+    let panic = result.expect_err("Should have panicked about too few ports");
+    let msg = panic.downcast_ref::<String>().unwrap();
+    assert!(msg.starts_with("port assertion failed after"));
+    assert!(msg.contains("too few ports"));
+    assert!(msg.contains(&format!("process_id: Some({})", pid)));
+    #[cfg(feature = "proc")]
+    assert!(msg.contains("port-binder"));
+}
+
+#[cfg(all(feature = "test-util", feature = "proc"))]
+#[test]
+fn assert_children_succeeds() {
+    use proc_ctl::assertions::assert_children;
+    use proc_ctl::ProcQuery;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+
+    let query = ProcQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_children(1);
+
+    let children = assert_children(&query, Duration::from_secs(1));
+
+    handle.kill().unwrap();
+
+    assert_eq!(1, children.len());
+}
+
+#[cfg(all(feature = "test-util", feature = "proc"))]
+#[test]
+fn assert_matches_describes_the_difference() {
+    use proc_ctl::assertions::{assert_children, assert_matches};
+    use proc_ctl::expectations::expect;
+    use proc_ctl::ProcQuery;
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let port_binder_path = binder.get_program();
+
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([port_binder_path]);
+    let mut handle = DropChild::spawn(runner);
+
+    let query = ProcQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_children(1);
+    let children = assert_children(&query, Duration::from_secs(1));
+
+    assert_matches(
+        &children,
+        &[expect().name("port-binder").parent(handle.id())],
+    );
+    let result = std::panic::catch_unwind(|| {
+        assert_matches(&children, &[expect().name("port-binder").count(2)])
+    });
+
+    handle.kill().unwrap();
+
+    let panic = result.expect_err("Should have panicked about too few processes");
+    let msg = panic.downcast_ref::<String>().unwrap();
+    assert!(msg.starts_with("[processes_mismatch]"));
+    assert!(msg.contains("- expected 2 processes named \"port-binder\", found 1"));
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_process_with_rewritten_cmd() {
+    use proc_ctl::{MatchField, ProcQuery};
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("argv-rewriter");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+    let pid = handle.id();
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!("Rewritten", line.trim());
+
+    let by_comm = ProcQuery::new()
+        .process_name("rewritten")
+        .match_on(MatchField::Comm)
+        .list_processes()
+        .unwrap();
+    assert!(by_comm.iter().any(|p| p.pid == pid));
+
+    let by_exe = ProcQuery::new()
+        .process_name("argv-rewriter")
+        .match_on(MatchField::Exe)
+        .list_processes()
+        .unwrap();
+    assert!(by_exe.iter().any(|p| p.pid == pid));
+
+    let by_argv0 = ProcQuery::new()
+        .process_name("argv-rewriter: worker")
+        .match_on(MatchField::Argv0)
+        .list_processes()
+        .unwrap();
+    assert!(by_argv0.iter().any(|p| p.pid == pid));
+
+    let info = ProcQuery::new()
+        .process_id(pid)
+        .refresh_cmd(true)
+        .list_processes()
+        .unwrap();
+    assert_eq!(Some("argv-rewriter: worker"), info[0].argv0.as_deref());
+
+    handle.kill().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_missing_process_is_classified() {
+    use proc_ctl::{ErrorKind, PortQuery, ProcCtlError};
+
+    // Larger than the maximum pid_max, so this can never be a running process
+    let err = PortQuery::new()
+        .process_id(999_999_999)
+        .execute()
+        .unwrap_err();
+
+    assert!(matches!(err, ProcCtlError::ProcessNotFound(999_999_999)));
+    assert_eq!(ErrorKind::ProcessNotFound, err.kind());
+}
+
+#[test]
+fn port_query_expectation_failure_is_classified() {
+    use proc_ctl::{ErrorKind, ProcCtlError, ProtocolPort};
+
+    let err = ProcCtlError::TooFewPorts(vec![ProtocolPort::Tcp(8080)], 2);
+    assert_eq!(ErrorKind::ExpectationNotMet, err.kind());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_out_of_range_pid_is_rejected() {
+    use proc_ctl::{PortQuery, ProcCtlError};
+
+    let err = PortQuery::new().process_id(u32::MAX).execute().unwrap_err();
+
+    assert!(matches!(err, ProcCtlError::ConfigurationError(_)));
+}
+
+/// Copy a sample to a unique path, so that it can be selected by its executable path without matching processes
+/// started by other tests
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+fn copy_sample(name: &str, copy_name: &str) -> std::path::PathBuf {
+    let source = create_command_for_sample(name).get_program().to_owned();
+    let dir = std::env::temp_dir().join(format!("proc-ctl-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let target = dir.join(copy_name);
+    #[cfg(target_os = "windows")]
+    let target = target.with_extension("exe");
+    std::fs::copy(source, &target).unwrap();
+
+    target.canonicalize().unwrap()
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_track_across_restart() {
+    use proc_ctl::{PortQuery, ProcSelector};
+    use retry::delay::Fixed;
+
+    let exe = copy_sample("port-binder", "tracked-binder");
+    let query = PortQuery::new()
+        .track(ProcSelector::ExePath(exe.clone()))
+        .tcp_only()
+        .expect_min_num_ports(1);
+
+    let mut first = DropChild::spawn(std::process::Command::new(&exe));
+    let ports = retry::retry(Fixed::from_millis(100).take(20), || {
+        query.execute_detailed()
+    })
+    .unwrap();
+    let first_pid = first.id();
+    assert_eq!(first_pid, ports[0].pid);
+
+    first.kill().unwrap();
+    first.wait().unwrap();
+
+    let second = DropChild::spawn(std::process::Command::new(&exe));
+    let ports = retry::retry(Fixed::from_millis(100).take(20), || {
+        query.execute_detailed()
+    })
+    .unwrap();
+    assert_eq!(second.id(), ports[0].pid);
+    assert_ne!(first_pid, ports[0].pid);
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_track_multiple_matches() {
+    use proc_ctl::{MultipleMatchPolicy, PortQuery, ProcCtlError, ProcSelector};
+    use retry::delay::Fixed;
+
+    let exe = copy_sample("port-binder", "tracked-binder-pair");
+    let first = DropChild::spawn(std::process::Command::new(&exe));
+    let second = DropChild::spawn(std::process::Command::new(&exe));
+
+    let query = PortQuery::new()
+        .track(ProcSelector::ExePath(exe.clone()))
+        .tcp_only();
+    let pids = retry::retry(Fixed::from_millis(100).take(20), || match query.execute() {
+        Err(ProcCtlError::MultipleMatchingProcesses(pids)) => Ok(pids),
+        other => Err(other),
+    })
+    .unwrap();
+    assert!(pids.contains(&first.id()) && pids.contains(&second.id()));
+
+    let query = query
+        .on_multiple_matches(MultipleMatchPolicy::Aggregate)
+        .expect_min_num_ports(2);
+    let ports = retry::retry(Fixed::from_millis(100).take(20), || {
+        query.execute_detailed()
+    })
+    .unwrap();
+    assert!(ports.iter().any(|p| p.pid == first.id()));
+    assert!(ports.iter().any(|p| p.pid == second.id()));
+}
+
+#[cfg(all(feature = "async", target_os = "linux"))]
+#[tokio::test]
+async fn port_query_events_until_exit() {
+    use futures_util::StreamExt;
+    use proc_ctl::{PortEvent, PortQuery};
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let mut events = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .events(Duration::from_millis(50));
+
+    let bound = tokio::time::timeout(Duration::from_secs(5), events.next())
+        .await
+        .unwrap();
+    assert!(matches!(bound, Some(Ok(PortEvent::PortBound(_)))));
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let rest = tokio::time::timeout(Duration::from_secs(5), events.collect::<Vec<_>>())
+        .await
+        .unwrap();
+    assert!(matches!(rest.last(), Some(Ok(PortEvent::ProcessExited))));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_watch_until_exit() {
+    use proc_ctl::{PortEvent, PortQuery};
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let mut watcher = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .watch(Duration::from_millis(50));
+
+    let bound = watcher.next();
+    assert!(
+        matches!(bound, Some(Ok(PortEvent::PortBound(_)))),
+        "{:?}",
+        bound
+    );
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let rest = watcher.collect::<Vec<_>>();
+    assert!(
+        matches!(rest.last(), Some(Ok(PortEvent::ProcessExited))),
+        "{:?}",
+        rest
+    );
+}
+
+#[cfg(all(
+    feature = "async",
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn proc_query_events_started_only() {
+    use futures_util::StreamExt;
+    use proc_ctl::{MatchField, ProcQuery};
+    use std::time::Duration;
+
+    let exe = copy_sample("waiter", "events-waiter");
+    let mut started = ProcQuery::new()
+        .process_name("events-waiter")
+        .match_on(MatchField::Exe)
+        .events(Duration::from_millis(50))
+        .started_only();
+
+    let mut waiter = std::process::Command::new(&exe);
+    waiter.stdin(std::process::Stdio::piped());
+    let handle = DropChild::spawn(waiter);
+
+    let info = tokio::time::timeout(Duration::from_secs(5), started.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(handle.id(), info.pid);
+}
+
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn port_query_wait_for_port() {
+    use proc_ctl::{PortQuery, ProtocolPort, WaitOptions};
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let outcome = PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id_from_child(&handle)
+        .expect_min_num_ports(1)
+        .wait_for_port(&WaitOptions::new(Duration::from_secs(5)))
+        .await
+        .unwrap();
+
+    handle.kill().unwrap();
+
+    assert!(matches!(outcome.value.as_slice(), [ProtocolPort::Tcp(_)]));
+    assert!(outcome.attempts >= 1);
+    assert_eq!(
+        outcome.attempts,
+        outcome.observations.last().unwrap().attempt
+    );
+}
+
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn port_query_wait_for_port_timeout_keeps_history() {
+    use proc_ctl::{PortQuery, ProcCtlError, WaitOptions};
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    // The waiter never binds a port
+    let mut waiter = create_command_for_sample("waiter");
+    waiter.stdin(Stdio::piped()).stdout(Stdio::null());
+    let mut handle = DropChild::spawn(waiter);
+
+    let err = PortQuery::new()
+        .process_id_from_child(&handle)
+        .expect_min_num_ports(1)
+        .wait_for_port(
+            &WaitOptions::new(Duration::from_millis(500))
+                .interval(Duration::from_millis(50))
+                .max_history(2),
+        )
+        .await
+        .unwrap_err();
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let ProcCtlError::WaitTimedOut(history) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert!(history.attempts > 2);
+    assert_eq!(2, history.observations.len());
+    assert!(history.observations[1].seen.contains("too few ports"));
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn proc_query_match_canonical_names() {
+    use proc_ctl::ProcQuery;
+    use retry::delay::Fixed;
+
+    // Longer than the 15 characters Linux keeps of a process name
+    let exe = copy_sample("waiter", "canonical-waiter");
+    let mut waiter = std::process::Command::new(&exe);
+    waiter.stdin(std::process::Stdio::piped());
+    let mut handle = DropChild::spawn(waiter);
+
+    for name in [
+        "canonical-waiter",
+        "canonical-waiter.exe",
+        "/opt/app/canonical-waiter",
+    ] {
+        let query = ProcQuery::new()
+            .process_name(name)
+            .match_canonical_names(true);
+        let found = retry::retry(Fixed::from_millis(100).take(10), || {
+            query.list_processes().and_then(|p| match p.as_slice() {
+                [] => Err(proc_ctl::ProcCtlError::NoMatchingProcess(name.to_string())),
+                _ => Ok(p),
+            })
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec![(handle.id(), "canonical-waiter".to_string())],
+            found
+                .iter()
+                .map(|p| (p.pid, p.canonical_name()))
+                .collect::<Vec<_>>(),
+            "matching {}",
+            name
+        );
+    }
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+}
+
+#[cfg(all(
+    feature = "async",
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn proc_query_wait_for_match_then_exit() {
+    use proc_ctl::{ProcQuery, WaitOptions};
+    use std::time::Duration;
+
+    let exe = copy_sample("waiter", "wait-for-waiter");
+    let query = ProcQuery::new().process_name("wait-for-waiter");
+    let options = WaitOptions::new(Duration::from_secs(5));
+
+    let mut waiter = std::process::Command::new(&exe);
+    waiter.stdin(std::process::Stdio::piped());
+    let mut handle = DropChild::spawn(waiter);
+
+    let started = query.wait_for_match(&options).await.unwrap();
+    assert_eq!(
+        vec![handle.id()],
+        started.value.iter().map(|p| p.pid).collect::<Vec<_>>()
+    );
+
+    // Not reaped yet, which still counts as exited
+    handle.kill().unwrap();
+    query.wait_for_exit(&options).await.unwrap();
+
+    handle.wait().unwrap();
+}
+
+#[cfg(all(
+    feature = "async",
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows")
+))]
+#[tokio::test]
+async fn proc_query_wait_for_exit_wakes_on_exit() {
+    use proc_ctl::{ProcQuery, WaitOptions, WaitStrategy};
+    use std::time::{Duration, Instant};
+
+    let exe = copy_sample("waiter", "latency-waiter");
+    let query = ProcQuery::new().process_name("latency-waiter");
+
+    let mut waiter = std::process::Command::new(&exe);
+    waiter.stdin(std::process::Stdio::piped());
+    let mut handle = DropChild::spawn(waiter);
+    query
+        .wait_for_match(&WaitOptions::new(Duration::from_secs(5)))
+        .await
+        .unwrap();
+
+    // Far longer than the exit takes to be seen, so a wait which polled would take the whole interval
+    let options = WaitOptions::new(Duration::from_secs(30)).interval(Duration::from_secs(10));
+    let killer = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        handle.kill().unwrap();
+        (Instant::now(), handle)
+    });
+
+    let outcome = query.wait_for_exit(&options).await.unwrap();
+    let exited = Instant::now();
+    let (killed, mut handle) = killer.join().unwrap();
+    handle.wait().unwrap();
+
+    let latency = exited.saturating_duration_since(killed);
+    eprintln!("Saw the exit after {:?}", latency);
+    assert!(latency < Duration::from_secs(1), "took {:?}", latency);
+    assert_eq!(2, outcome.attempts);
+    #[cfg(target_os = "linux")]
+    assert_eq!(WaitStrategy::PidFd, outcome.strategy);
+    #[cfg(target_os = "windows")]
+    assert_eq!(WaitStrategy::ProcessHandle, outcome.strategy);
+}
+
+#[cfg(all(
+    feature = "duct",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_from_duct_handle() {
+    use proc_ctl::{PortQuery, ProcCtlError};
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let handle = duct::cmd!(binder.get_program())
+        .unchecked()
+        .start()
+        .unwrap();
+
+    let query = PortQuery::new()
+        .tcp_only()
+        .expect_min_num_ports(1)
+        .process_id_from_handle(&handle)
+        .unwrap();
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+    assert_eq!(1, ports.len());
+
+    handle.kill().unwrap();
+
+    let err = PortQuery::new()
+        .process_id_from_handle(&handle)
+        .unwrap_err();
+    assert!(matches!(err, ProcCtlError::ProcessNotFound(pid) if pid == handle.pids()[0]));
+}
+
+#[cfg(all(
+    feature = "assert-cmd",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_from_spawned_child() {
+    use proc_ctl::{ProcCtlError, SpawnedChildExt};
+    use retry::delay::Fixed;
+
+    let mut child = create_command_for_sample("port-binder").spawn().unwrap();
+
+    let query = child
+        .port_query()
+        .unwrap()
+        .tcp_only()
+        .expect_min_num_ports(1);
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+    assert_eq!(1, ports.len());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let err = child.port_query().unwrap_err();
+    assert!(matches!(err, ProcCtlError::ProcessNotFound(pid) if pid == child.id()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_backlog() {
+    use proc_ctl::{PortQuery, ProcCtlError, ProtocolPort};
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("backlog-binder");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let port: u16 = line.trim().parse().unwrap();
+
+    let query = PortQuery::new().tcp_only().process_id_from_child(&handle);
+    let ports = query.execute_detailed().unwrap();
+    assert_eq!(1, ports.len());
+    assert!(matches!(ports[0].port, ProtocolPort::Tcp(p) if p == port));
+    assert_eq!(Some(7), ports[0].backlog);
+    assert_eq!(Some(0), ports[0].current_queue);
+
+    // Connections complete without being accepted, so they wait in the queue
+    let _clients = (0..2)
+        .map(|_| std::net::TcpStream::connect(("127.0.0.1", port)).unwrap())
+        .collect::<Vec<_>>();
+    let ports = query.execute_detailed().unwrap();
+    assert_eq!(Some(2), ports[0].current_queue);
+
+    let query = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .expect_backlog_at_least(7);
+    assert_eq!(1, query.execute().unwrap().len());
+
+    let err = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .expect_backlog_at_least(8)
+        .execute()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ProcCtlError::BacklogTooSmall(ProtocolPort::Tcp(p), Some(7), 8) if p == port
+    ));
+
+    handle.kill().unwrap();
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_verify_accepting() {
+    use proc_ctl::PortQuery;
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("backlog-binder");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+
+    let query = PortQuery::new().tcp_only().process_id_from_child(&handle);
+    let ports = query.execute_detailed().unwrap();
+    assert_eq!(None, ports[0].accepting);
+
+    let query = query.verify_accepting(true);
+    let ports = query.execute_detailed().unwrap();
+    assert_eq!(Some(true), ports[0].accepting);
+
+    let ports = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .expect_accepting()
+        .execute()
+        .unwrap();
+    assert_eq!(1, ports.len());
+
+    handle.kill().unwrap();
+}
+
+// Relies on Linux dropping new connections once the accept queue is full
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_expect_accepting_with_full_backlog() {
+    use proc_ctl::{PortQuery, ProcCtlError, ProtocolPort};
+    use std::io::BufRead;
+    use std::net::TcpStream;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let mut cmd = create_command_for_sample("backlog-binder");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let port: u16 = line.trim().parse().unwrap();
+
+    // The binder never accepts, so fill its queue until connections stop completing
+    let address = ([127, 0, 0, 1], port).into();
+    let mut clients = Vec::new();
+    while let Ok(client) = TcpStream::connect_timeout(&address, Duration::from_millis(500)) {
+        clients.push(client);
+        assert!(clients.len() < 64, "the accept queue never filled");
+    }
+
+    let err = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .expect_accepting()
+        .execute()
+        .unwrap_err();
+    assert!(matches!(err, ProcCtlError::NotAccepting(ProtocolPort::Tcp(p)) if p == port));
+
+    handle.kill().unwrap();
+}
+
+#[cfg(all(
+    feature = "resilience",
+    feature = "test-util",
+    any(target_os = "linux", target_os = "macos")
+))]
+#[test]
+fn port_query_waits_for_a_connectable_port() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::ProcCtlError;
+    use std::time::Duration;
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 1,
+        udp4: 1,
+        ..Default::default()
+    });
+    let query = || proc_ctl::PortQuery::new().process_id(binder.pid());
+
+    let port = query()
+        .wait_for_connectable(Duration::from_millis(50), 100)
+        .unwrap();
+    assert!(binder.ports().contains(&port), "{:?}", port);
+    assert!(matches!(port, proc_ctl::ProtocolPort::Tcp(_)));
+
+    // A UDP port can't be connected to, so there is nothing to wait for
+    let result = query()
+        .udp_only()
+        .wait_for_connectable(Duration::from_millis(10), 2);
+    assert!(
+        matches!(result, Err(ProcCtlError::TooFewPorts(ref found, 1)) if found.len() == 1),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(all(
+    feature = "async",
+    feature = "test-util",
+    any(target_os = "linux", target_os = "macos")
+))]
+#[tokio::test]
+async fn port_query_waits_for_a_connectable_port_async() {
+    use proc_ctl::binder::BinderConfig;
+    use std::time::Duration;
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp6: 1,
+        ..Default::default()
+    });
+
+    let port = proc_ctl::PortQuery::new()
+        .process_id(binder.pid())
+        .wait_for_connectable_async(Duration::from_millis(50), 100)
+        .await
+        .unwrap();
+    assert_eq!(binder.ports(), vec![port]);
+}
+
+#[cfg(all(
+    feature = "test-util",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+fn spawn_multi_port_binder(
+    config: &proc_ctl::binder::BinderConfig,
+) -> proc_ctl::binder::MultiPortBinder {
+    proc_ctl::binder::MultiPortBinder::spawn(create_command_for_sample("multi-port-binder"), config)
+        .unwrap()
+}
+
+#[cfg(all(feature = "test-util", any(target_os = "linux", target_os = "macos")))]
+#[test]
+fn reconciler_reports_changes() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::{PortQuery, Reconciler};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 2,
+        ..Default::default()
+    });
+    let ports = binder.ports();
+
+    let (tx, rx) = mpsc::channel();
+    let guard = Reconciler::new(PortQuery::new().process_id(binder.pid()), [ports[0]])
+        .interval(Duration::from_millis(50))
+        .start(move |change| {
+            let _ = tx.send(change.map(|v| {
+                (
+                    v.unexpected.into_iter().map(|p| p.port).collect::<Vec<_>>(),
+                    v.missing,
+                )
+            }));
+        });
+
+    let first = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!((vec![ports[1]], vec![]), first);
+
+    // Nothing changes while the binder is running
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+    drop(binder);
+    let gone = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(
+        gone.as_ref()
+            .map_or(true, |(unexpected, missing)| unexpected.is_empty()
+                && missing == &vec![ports[0]]),
+        "{:?}",
+        gone
+    );
+
+    guard.stop();
+}
+
+#[cfg(all(
+    feature = "test-util",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_finds_every_bound_port() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::PortQuery;
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 2,
+        tcp6: 1,
+        udp4: 1,
+        udp6: 1,
+        ..Default::default()
+    });
+
+    let mut ports = PortQuery::new()
+        .process_id(binder.pid())
+        .expect_min_num_ports(5)
+        .execute()
+        .unwrap();
+    ports.sort();
+
+    let mut expected = binder.ports();
+    expected.sort();
+    assert_eq!(expected, ports);
+}
+
+#[cfg(all(
+    feature = "test-util",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_filters_protocol_and_family() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::{PortQuery, ProtocolPort};
+
+    let binder = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 2,
+        tcp6: 1,
+        udp4: 1,
+        udp6: 1,
+        ..Default::default()
+    });
+
+    let tcp = PortQuery::new()
+        .process_id(binder.pid())
+        .tcp_only()
+        .execute()
+        .unwrap();
+    assert_eq!(3, tcp.len());
+    assert!(tcp.iter().all(|p| matches!(p, ProtocolPort::Tcp(_))));
+
+    let udp = PortQuery::new()
+        .process_id(binder.pid())
+        .udp_only()
+        .execute()
+        .unwrap();
+    assert_eq!(2, udp.len());
+    assert!(udp.iter().all(|p| matches!(p, ProtocolPort::Udp(_))));
+
+    let mut v4 = PortQuery::new()
+        .process_id(binder.pid())
+        .ip_v4_only()
+        .execute()
+        .unwrap();
+    v4.sort();
+    let mut expected = binder
+        .bound()
+        .iter()
+        .filter(|(_, addr)| addr.is_ipv4())
+        .map(|(kind, addr)| match kind.as_str() {
+            "udp4" => ProtocolPort::Udp(addr.port()),
+            _ => ProtocolPort::Tcp(addr.port()),
+        })
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(expected, v4);
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_explain_filtered_processes() {
+    use proc_ctl::{FilterKind, ProcQuery, SkipReason, SkippedProcess};
+
+    let query = ProcQuery::new().process_name("no-process-has-this-name");
+
+    let report = query.list_processes_report().unwrap();
+    assert!(report.matches.is_empty());
+    assert!(report.skipped.is_empty());
+
+    let report = query.explain(true).list_processes_report().unwrap();
+    assert!(report.skipped.contains(&SkippedProcess {
+        pid: std::process::id(),
+        reason: SkipReason::FilteredBy(FilterKind::Name),
+    }));
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_explain_unavailable_exe() {
+    use proc_ctl::{MatchField, ProcQuery, SkipReason};
+
+    // An exited child which has not been waited on has no executable
+    let mut waiter = create_command_for_sample("waiter");
+    waiter
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null());
+    let mut child = waiter.spawn().unwrap();
+    let pid = child.id();
+    while std::fs::read_link(format!("/proc/{}/exe", pid)).is_ok() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let report = ProcQuery::new()
+        .process_name("waiter")
+        .match_on(MatchField::Exe)
+        .explain(true)
+        .list_processes_report()
+        .unwrap();
+
+    child.wait().unwrap();
+
+    assert!(report.matches.iter().all(|p| p.pid != pid));
+    let skipped = report.skipped.iter().find(|s| s.pid == pid).unwrap();
+    assert_eq!(
+        SkipReason::FieldUnavailable(MatchField::Exe),
+        skipped.reason
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_by_process_name() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_name("port-binder")
+        // Binders started by other tests may not have exited yet
+        .on_multiple_matches(proc_ctl::MultipleMatchPolicy::Aggregate)
+        .expect_min_num_ports(1);
+
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || {
+        query.execute_detailed()
+    });
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let ports = ports.unwrap();
+    assert_eq!(1, ports.iter().filter(|p| p.pid == handle.id()).count());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_including_children() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(std::process::id())
+        .include_children(true)
+        .expect_min_num_ports(1);
+
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || {
+        query.execute_detailed()
+    });
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let ports = ports.unwrap();
+    assert_eq!(1, ports.len());
+    assert_eq!(handle.id(), ports[0].pid);
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn port_query_detailed_local_addr_tells_wildcard_from_loopback() {
+    use proc_ctl::PortQuery;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+    let loopback = TcpListener::bind("127.0.0.1:0").unwrap();
+    let wildcard = TcpListener::bind("0.0.0.0:0").unwrap();
+
+    let ports = PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(std::process::id())
+        .execute_detailed()
+        .unwrap();
+    let local_addr = |listener: &TcpListener| {
+        let port = listener.local_addr().unwrap().port();
+        ports
+            .iter()
+            .find(|p| p.port.port() == port)
+            .and_then(|p| p.local_addr)
+    };
+
+    assert_eq!(
+        Some(SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            loopback.local_addr().unwrap().port()
+        ))),
+        local_addr(&loopback)
+    );
+    assert_eq!(
+        Some(SocketAddr::from((
+            Ipv4Addr::UNSPECIFIED,
+            wildcard.local_addr().unwrap().port()
+        ))),
+        local_addr(&wildcard)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_reports_socket_activation() {
+    use retry::delay::Fixed;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let listener_fd = listener.as_raw_fd();
+
+    // Pass the listener as fd 3 like a service manager would. LISTEN_PID has to be set by the child itself, since its
+    // pid isn't known until it starts.
+    let mut command = std::process::Command::new("sh");
+    command
+        .args(["-c", "export LISTEN_PID=$$; exec sleep 30"])
+        .env("LISTEN_FDS", "1");
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 clears close-on-exec on the new fd, unless it is the same fd
+            let result = if listener_fd == 3 {
+                libc::fcntl(3, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(listener_fd, 3)
+            };
+            if result == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut handle = DropChild::spawn(command);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    // The shell holds the socket too, so wait until it has exec'd with LISTEN_PID set
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || {
+        query.execute_detailed().and_then(|ports| {
+            if ports.iter().all(|p| p.via_socket_activation == Some(true)) {
+                Ok(ports)
+            } else {
+                Err(proc_ctl::ProcCtlError::ConfigurationError(format!(
+                    "not yet activated: {:?}",
+                    ports
+                )))
+            }
+        })
+    });
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+
+    let ports = ports.unwrap();
+    assert_eq!(1, ports.len());
+    assert_eq!(proc_ctl::ProtocolPort::Tcp(port), ports[0].port);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_reports_sockets_shared_with_workers() {
+    use std::io::BufRead;
+
+    let mut binder = create_command_for_sample("prefork-binder");
+    binder
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped());
+    let mut handle = DropChild::spawn(binder);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let fields = line
+        .split_whitespace()
+        .map(|f| f.parse::<u32>().unwrap())
+        .collect::<Vec<_>>();
+    let (port, mut workers) = (fields[0] as u16, fields[1..].to_vec());
+    workers.sort();
+
+    let query_for = |pid| {
+        proc_ctl::PortQuery::new()
+            .tcp_only()
+            .ip_v4_only()
+            .process_id(pid)
+            .expect_min_num_ports(1)
+            .execute_detailed()
+    };
+    let parent_ports = query_for(handle.id());
+    let worker_ports = query_for(workers[0]);
+
+    // Closing stdin stops the workers as well as the parent
+    drop(handle.stdin.take());
+    handle.wait().unwrap();
+
+    let parent_ports = parent_ports.unwrap();
+    assert_eq!(1, parent_ports.len());
+    assert_eq!(proc_ctl::ProtocolPort::Tcp(port), parent_ports[0].port);
+    assert_eq!(workers, parent_ports[0].shared_with);
+    assert_eq!(Some(handle.id()), parent_ports[0].primary_owner);
+
+    let worker_ports = worker_ports.unwrap();
+    let mut others = vec![handle.id(), workers[1]];
+    others.sort();
+    assert_eq!(others, worker_ports[0].shared_with);
+    assert_eq!(Some(handle.id()), worker_ports[0].primary_owner);
+}
+
+#[test]
+fn self_check_finds_every_capability_for_this_process() {
+    let report = proc_ctl::self_check();
+
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(0, report.unavailable().count());
+    assert!(report
+        .capabilities
+        .iter()
+        .any(|c| c.name == "list ports" && c.is_available()));
+    report.into_result().unwrap();
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_summary_groups_by_family() {
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder-v6");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    let summary = retry::retry(Fixed::from_millis(100).take(10), move || query.summary()).unwrap();
+
+    handle.kill().unwrap();
+
+    assert_eq!(1, summary.tcp_v6.len());
+    assert!(summary.tcp_v4.is_empty());
+    assert_eq!(1, summary.total());
+    assert_eq!(format!("tcp6: {}", summary.tcp_v6[0]), summary.to_string());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_execute_report_matches_the_other_forms() {
+    use proc_ctl::results::{PortSummary, ProtocolPort, QueryReport};
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let query = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .process_id(handle.id())
+        .expect_min_num_ports(1);
+
+    let report: QueryReport =
+        retry::retry(Fixed::from_millis(100).take(10), || query.execute_report()).unwrap();
+    let ports = query.execute().unwrap();
+
+    handle.kill().unwrap();
+
+    assert_eq!(vec![handle.id()], report.pids());
+    assert!(report.ports[0].bound_since.is_some());
+    assert_eq!(
+        format!(
+            "tcp4: {} from pid {}",
+            report.summary.tcp_v4[0],
+            handle.id()
+        ),
+        report.to_string().split(" in ").next().unwrap()
+    );
+    assert_eq!(ports, Vec::<ProtocolPort>::from(report.clone()));
+    assert_eq!(ports, Vec::<ProtocolPort>::from(PortSummary::from(report)));
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn port_info_connectable_addr_reaches_the_listener() {
+    use retry::delay::Fixed;
+    use std::net::{SocketAddr, TcpStream};
+
+    for (sample, loopback) in [("port-binder", "127.0.0.1"), ("port-binder-v6", "::1")] {
+        let binder = create_command_for_sample(sample);
+        let mut handle = DropChild::spawn(binder);
+
+        let query = proc_ctl::PortQuery::new()
+            .tcp_only()
+            .process_id(handle.id())
+            .expect_min_num_ports(1);
+
+        let ports = retry::retry(Fixed::from_millis(100).take(10), || {
+            query.execute_detailed()
+        })
+        .unwrap();
+        let addr = ports[0].connectable_addr();
+
+        assert_eq!(loopback, addr.ip().to_string());
+        assert_eq!(addr, SocketAddr::try_from(&ports[0]).unwrap());
+
+        // The binder exits once it has accepted a connection
+        TcpStream::connect(addr).unwrap();
+        assert!(handle.wait().unwrap().success(), "{}", sample);
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn port_summary_is_sorted_deduplicated_and_serializable() {
+    use proc_ctl::{AddressFamily, PortInfo, PortSummary, ProtocolPort};
+
+    let v6 = |port| {
+        PortInfo::builder()
+            .port(port)
+            .family(AddressFamily::Ipv6)
+            .build()
+    };
+    let summary = PortSummary::from_ports(&[
+        PortInfo::new(ProtocolPort::Tcp(8081), 1),
+        PortInfo::new(ProtocolPort::Tcp(8080), 1),
+        PortInfo::new(ProtocolPort::Tcp(8081), 2),
+        v6(ProtocolPort::Tcp(8080)),
+        v6(ProtocolPort::Udp(5353)),
+    ]);
+
+    assert_eq!(vec![8080, 8081], summary.tcp_v4);
+    assert_eq!(3, summary.tcp_total());
+    assert_eq!(1, summary.udp_total());
+    assert_eq!("tcp4: 8080,8081 tcp6: 8080 udp6: 5353", summary.to_string());
+    assert_eq!("no ports", PortSummary::default().to_string());
+
+    let json = serde_json::to_string(&summary).unwrap();
+    assert_eq!(
+        r#"{"tcp_v4":[8080,8081],"tcp_v6":[8080],"udp_v4":[],"udp_v6":[5353]}"#,
+        json
+    );
+    assert_eq!(summary, serde_json::from_str::<PortSummary>(&json).unwrap());
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn port_query_by_pidfd_fails_once_the_process_exits() {
+    use proc_ctl::{ErrorKind, PortQuery};
+    use retry::delay::Fixed;
+    use std::time::Duration;
+
+    let mut child = create_command_for_sample("port-binder").spawn().unwrap();
+    let info = proc_ctl::info_for_child(&child, Duration::from_secs(5)).unwrap();
+
+    let query = PortQuery::new()
+        .tcp_only()
+        .process_fd(info.pidfd().unwrap())
+        .expect_min_num_ports(1);
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || query.execute());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(1, ports.unwrap().len());
+    let err = query.execute().unwrap_err();
+    assert_eq!(ErrorKind::ProcessNotFound, err.kind(), "{:?}", err);
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_by_pidfd_stops_matching_once_the_process_exits() {
+    use proc_ctl::ProcQuery;
+    use retry::delay::Fixed;
+    use std::time::Duration;
+
+    let mut child = std::process::Command::new("sleep")
+        .arg("30")
+        .spawn()
+        .unwrap();
+    let info = proc_ctl::info_for_child(&child, Duration::from_secs(5)).unwrap();
+
+    let query = ProcQuery::new().process_fd(info.pidfd().unwrap());
+    let before = query.list_processes();
+
+    // Not yet waited on, so the pid can't have been reused, but the pidfd already shows the process has exited
+    child.kill().unwrap();
+    let exited = retry::retry(Fixed::from_millis(10).take(100), || {
+        match query.list_processes() {
+            Ok(processes) if processes.is_empty() => Ok(()),
+            Ok(processes) => Err(format!("still matched {:?}", processes)),
+            Err(e) => Err(e.to_string()),
+        }
+    });
+    child.wait().unwrap();
+
+    assert_eq!(
+        vec![child.id()],
+        before
+            .unwrap()
+            .into_iter()
+            .map(|p| p.pid)
+            .collect::<Vec<_>>()
+    );
+    exited.unwrap();
+    assert!(matches!(
+        info.pidfd(),
+        Err(proc_ctl::ProcCtlError::ProcessExited(_))
+    ));
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_monitor_fails_fast_on_a_forbidden_port() {
+    use proc_ctl::{PortQuery, ProcCtlError, ProtocolPort};
+    use std::io::BufRead;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let mut cmd = create_command_for_sample("late-binder");
+    cmd.arg("500").stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+    let mut stdout = std::io::BufReader::new(handle.stdout.take().unwrap());
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let allowed = ProtocolPort::Tcp(line.trim().parse().unwrap());
+
+    let query = PortQuery::new()
+        .tcp_only()
+        .process_id(handle.id())
+        .forbid_ports_except([allowed]);
+    let started = Instant::now();
+    let result = query.monitor_for(Duration::from_secs(30), Duration::from_millis(50));
+
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+    let forbidden = ProtocolPort::Tcp(line.trim().parse().unwrap());
+
+    match result {
+        Err(ProcCtlError::ForbiddenPorts(violation)) => {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            assert_eq!(
+                vec![forbidden],
+                violation
+                    .unexpected
+                    .iter()
+                    .map(|p| p.port)
+                    .collect::<Vec<_>>()
+            );
+            let mut snapshot = violation
+                .snapshot
+                .iter()
+                .map(|p| p.port)
+                .collect::<Vec<_>>();
+            snapshot.sort();
+            let mut expected = vec![allowed, forbidden];
+            expected.sort();
+            assert_eq!(expected, snapshot);
+            assert!(violation.at <= std::time::SystemTime::now());
+        }
+        other => panic!("expected a forbidden port, got {:?}", other),
+    }
+}
+
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn port_query_monitor_async_passes_while_only_allowed_ports_are_bound() {
+    use proc_ctl::{PortQuery, ProtocolPort};
+    use std::io::BufRead;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let mut cmd = create_command_for_sample("late-binder");
+    cmd.arg("60000").stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut line = String::new();
+    std::io::BufReader::new(handle.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let allowed = ProtocolPort::Tcp(line.trim().parse().unwrap());
+
+    let query = PortQuery::new()
+        .tcp_only()
+        .process_id(handle.id())
+        .forbid_ports_except([allowed, ProtocolPort::Tcp(1)]);
+
+    query
+        .monitor_for_async(Duration::from_millis(300), Duration::from_millis(50))
+        .await
+        .unwrap();
+    query.execute().unwrap();
+    assert!(PortQuery::new()
+        .monitor_for(Duration::ZERO, Duration::ZERO)
+        .is_err());
+}
+
+#[test]
+fn port_in_use_without_a_visible_owner_is_unknown() {
+    use proc_ctl::{AddressFamily, OwnerKind, ProtocolPort, UnknownOwnerReason};
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = ProtocolPort::Udp(socket.local_addr().unwrap().port());
+
+    let owner = OwnerKind::attribute(None, port, AddressFamily::Ipv4).unwrap();
+    assert_eq!(
+        OwnerKind::Unknown(UnknownOwnerReason::VisibilityRestricted),
+        owner
+    );
+    let rendered = owner.to_string();
+    assert!(
+        rendered.starts_with("unknown, the port is in use"),
+        "{}",
+        rendered
+    );
+    assert!(
+        rendered.contains("System Integrity Protection"),
+        "{}",
+        rendered
+    );
+
+    // A scan which found the owner is trusted without probing
+    assert_eq!(
+        Some(OwnerKind::Process(1)),
+        OwnerKind::attribute(Some(OwnerKind::Process(1)), port, AddressFamily::Ipv4)
+    );
+
+    drop(socket);
+    assert_eq!(None, OwnerKind::attribute(None, port, AddressFamily::Ipv4));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn port_query_from_toml_config_round_trips() {
+    use proc_ctl::{PortQuery, PortQueryConfig, ProtocolPort};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let config: PortQueryConfig = toml::from_str(&format!(
+        r#"
+            process_id = {0}
+            protocol = "tcp"
+            family = "ipv4"
+            min_port = {1}
+            max_port = {1}
+            expect_min_num_ports = 1
+            forbid_ports_except = [{{ protocol = "tcp", port = {1} }}]
+            max_tool_concurrency = 2
+        "#,
+        std::process::id(),
+        port
+    ))
+    .unwrap();
+
+    let query = PortQuery::from_config(&config);
+    assert_eq!(config, query.to_config());
+    assert_eq!(vec![ProtocolPort::Tcp(port)], query.execute().unwrap());
+
+    let written = toml::to_string(&query.to_config()).unwrap();
+    assert_eq!(config, toml::from_str::<PortQueryConfig>(&written).unwrap());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn query_config_rejects_unknown_fields() {
+    use proc_ctl::PortQueryConfig;
+
+    let error = toml::from_str::<PortQueryConfig>("expect_min_num_port = 1")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("expect_min_num_port"), "{}", error);
+
+    let error = toml::from_str::<PortQueryConfig>("retry = { delay_ms = 10, attempt = 3 }")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("attempt"), "{}", error);
+}
+
+#[cfg(all(feature = "proc", feature = "serde"))]
+#[test]
+fn proc_query_from_toml_config_round_trips() {
+    use proc_ctl::{MatchField, ProcQuery, ProcQueryConfig};
+    use std::time::Duration;
+
+    let config: ProcQueryConfig = toml::from_str(&format!(
+        r#"
+            process_id = {}
+            match_on = "Exe"
+            in_container = false
+            retry = {{ delay_ms = 250, attempts = 4 }}
+        "#,
+        std::process::id()
+    ))
+    .unwrap();
+
+    assert_eq!(MatchField::Exe, config.match_on);
+    let retry = config.retry.unwrap();
+    assert_eq!(
+        (Duration::from_millis(250), 4),
+        (retry.delay(), retry.attempts)
+    );
+
+    let query = ProcQuery::from_config(&config);
+    // The retry policy is not kept by the query
+    let mut expected = config.clone();
+    expected.retry = None;
+    assert_eq!(expected, query.to_config());
+
+    let processes = query.list_processes().unwrap();
+    assert_eq!(1, processes.len());
+    assert_eq!(std::process::id(), processes[0].pid);
+}
+
+#[test]
+fn port_query_execute_into_reuses_the_buffer() {
+    use proc_ctl::PortQuery;
+
+    let listeners = (0..3)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>();
+    let query = PortQuery::new()
+        .tcp_only()
+        .process_id(std::process::id())
+        .expect_min_num_ports(listeners.len());
+
+    let mut ports = Vec::with_capacity(64);
+    let buffer = ports.as_ptr();
+    query.execute_into(&mut ports).unwrap();
+
+    assert_eq!(query.execute().unwrap(), ports);
+    assert_eq!(buffer, ports.as_ptr());
+
+    // A failed query leaves the buffer empty rather than holding the previous result
+    let failing = PortQuery::new()
+        .process_id(std::process::id())
+        .expect_min_num_ports(1000);
+    assert!(failing.execute_into(&mut ports).is_err());
+    assert!(ports.is_empty());
+    assert_eq!(buffer, ports.as_ptr());
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_list_processes_into_reuses_each_entry() {
+    use proc_ctl::{ProcInfo, ProcQuery};
+
+    let query = ProcQuery::new().process_id(std::process::id());
+
+    let mut processes = vec![
+        ProcInfo::builder()
+            .name("a name which is longer than any process name")
+            .cmd(["x"; 64])
+            .build(),
+        ProcInfo::new(1, "left over"),
+    ];
+    let name = processes[0].name.as_ptr();
+    let cmd = processes[0].cmd.as_ptr();
+    query.list_processes_into(&mut processes).unwrap();
+
+    let listed = query.list_processes().unwrap();
+    assert_eq!(1, processes.len());
+    assert_eq!(listed[0].pid, processes[0].pid);
+    assert_eq!(listed[0].name, processes[0].name);
+    assert_eq!(listed[0].cmd, processes[0].cmd);
+    assert_eq!(listed[0].argv0, processes[0].argv0);
+    assert_eq!(listed[0].exe, processes[0].exe);
+    assert_eq!(listed[0].start_time, processes[0].start_time);
+
+    assert_eq!(name, processes[0].name.as_ptr());
+    assert_eq!(cmd, processes[0].cmd.as_ptr());
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_capabilities_are_opt_in() {
+    use proc_ctl::ProcQuery;
+
+    let query = ProcQuery::new().process_id(std::process::id());
+    assert_eq!(None, query.list_processes().unwrap()[0].capabilities);
+
+    let listed = query.with_capabilities(true).list_processes().unwrap();
+    let capabilities = listed[0].capabilities.clone();
+    if cfg!(target_os = "linux") {
+        let capabilities = capabilities.unwrap();
+        assert_eq!(
+            capabilities.effective.count_ones() as usize,
+            capabilities.names.len()
+        );
+        assert_eq!(
+            capabilities.effective & !capabilities.permitted,
+            0,
+            "the effective set is always within the permitted set"
+        );
+    } else {
+        assert_eq!(None, capabilities);
+    }
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_filters_by_capability() {
+    use proc_ctl::ProcQuery;
+
+    let own = ProcQuery::new()
+        .process_id(std::process::id())
+        .with_capabilities(true)
+        .list_processes()
+        .unwrap();
+    let can_bind_low_ports = own[0]
+        .capabilities
+        .as_ref()
+        .is_some_and(|c| c.has_effective("CAP_NET_BIND_SERVICE"));
+
+    let matched = ProcQuery::new()
+        .process_id(std::process::id())
+        .has_capability("net_bind_service")
+        .list_processes()
+        .unwrap();
+    assert_eq!(can_bind_low_ports, !matched.is_empty());
+
+    let unknown = ProcQuery::new()
+        .process_id(std::process::id())
+        .has_capability("CAP_NOT_A_CAPABILITY")
+        .list_processes()
+        .unwrap();
+    assert!(unknown.is_empty());
+}
+
+#[cfg(feature = "proc")]
+#[test]
+fn proc_query_limits_are_opt_in_and_can_be_expected() {
+    use proc_ctl::{ErrorKind, ProcCtlError, ProcQuery};
+
+    let query = ProcQuery::new().process_id(std::process::id());
+    assert_eq!(None, query.list_processes().unwrap()[0].limits);
+
+    let limits = ProcQuery::new()
+        .process_id(std::process::id())
+        .with_limits(true)
+        .list_processes()
+        .unwrap()[0]
+        .limits;
+    if !cfg!(target_os = "linux") {
+        assert_eq!(None, limits);
+        return;
+    }
+
+    // The kernel never allows an unlimited number of open files
+    let open_files = limits.unwrap().open_files.soft.unwrap();
+    assert!(open_files > 0);
+
+    let matched = ProcQuery::new()
+        .process_id(std::process::id())
+        .nofile_at_least(open_files)
+        .list_processes()
+        .unwrap();
+    assert_eq!(1, matched.len());
+    let too_many = ProcQuery::new()
+        .process_id(std::process::id())
+        .nofile_at_least(open_files + 1)
+        .list_processes()
+        .unwrap();
+    assert!(too_many.is_empty());
+
+    let met = ProcQuery::new()
+        .process_id(std::process::id())
+        .expect_nofile_at_least(open_files)
+        .list_processes()
+        .unwrap();
+    assert!(met[0].limits.is_some());
+
+    let err = ProcQuery::new()
+        .process_id(std::process::id())
+        .expect_nofile_at_least(open_files + 1)
+        .list_processes()
+        .unwrap_err();
+    assert_eq!(ErrorKind::ExpectationNotMet, err.kind());
+    match err {
+        ProcCtlError::LimitTooLow(shortfall) => {
+            assert_eq!(std::process::id(), shortfall.process.pid);
+            assert_eq!(open_files + 1, shortfall.required);
+            assert_eq!(Some(open_files), shortfall.found.unwrap().soft);
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+/// Set `PROC_CTL_TEST_SYSTEMD_UNIT` to a running unit, such as `ssh.service`, to test finding a real unit
+#[cfg(target_os = "linux")]
+#[test]
+fn query_by_systemd_unit() {
+    use proc_ctl::{ErrorKind, PortQuery, ProcCtlError};
+
+    match std::env::var("PROC_CTL_TEST_SYSTEMD_UNIT") {
+        Ok(unit) => {
+            let pid = proc_ctl::systemd_unit_pid(&unit).unwrap();
+            let ports = PortQuery::new()
+                .systemd_unit(&unit)
+                .unwrap()
+                .execute_detailed()
+                .unwrap();
+            assert!(ports.iter().all(|port| port.pid == pid));
+            #[cfg(feature = "proc")]
+            assert_eq!(
+                pid,
+                proc_ctl::ProcQuery::new()
+                    .systemd_unit(&unit)
+                    .unwrap()
+                    .list_processes()
+                    .unwrap()[0]
+                    .pid
+            );
+        }
+        Err(_) if !std::path::Path::new("/run/systemd/system").exists() => {
+            let result = proc_ctl::systemd_unit_pid("proc-ctl-test.service");
+            assert!(
+                matches!(result, Err(ProcCtlError::UnsupportedPlatform(_))),
+                "{:?}",
+                result
+            );
+        }
+        Err(_) => {
+            let error = PortQuery::new()
+                .systemd_unit("proc-ctl-no-such-unit.service")
+                .unwrap_err();
+            assert_eq!(ErrorKind::ProcessNotFound, error.kind(), "{}", error);
+        }
+    }
+}
+
+/// Set `PROC_CTL_TEST_WINDOWS_SERVICE` to a running service, such as `EventLog`, to test finding a real service
+#[cfg(target_os = "windows")]
+#[test]
+fn query_by_windows_service() {
+    use proc_ctl::{ErrorKind, PortQuery};
+
+    match std::env::var("PROC_CTL_TEST_WINDOWS_SERVICE") {
+        Ok(name) => {
+            let pid = proc_ctl::windows_service_pid(&name).unwrap();
+            assert_ne!(0, pid);
+            PortQuery::new().windows_service(&name).unwrap();
+        }
+        Err(_) => {
+            let error = PortQuery::new()
+                .windows_service("ProcCtlNoSuchService")
+                .unwrap_err();
+            assert_eq!(ErrorKind::ProcessNotFound, error.kind(), "{}", error);
+        }
+    }
+}
+
+/// Spawn three copies of the waiter, which are not selected by name by other tests, and record their start times
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+fn spawn_tracked_waiters(
+    copy_name: &str,
+) -> (
+    Vec<DropChild>,
+    Vec<(proc_ctl::Pid, Option<std::time::SystemTime>)>,
+) {
+    use std::process::Stdio;
+    use std::time::{Duration, SystemTime};
+
+    let exe = copy_sample("waiter", copy_name);
+    let children = (0..3)
+        .map(|_| {
+            let mut cmd = std::process::Command::new(&exe);
+            cmd.stdin(Stdio::piped()).stdout(Stdio::null());
+            DropChild::spawn(cmd)
+        })
+        .collect::<Vec<_>>();
+    let tracked = children
+        .iter()
+        .map(|child| {
+            let info = proc_ctl::info_for_child(child, Duration::from_secs(5)).unwrap();
+            (
+                info.pid,
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(info.start_time)),
+            )
+        })
+        .collect();
+
+    (children, tracked)
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn check_pids_of_children() {
+    use proc_ctl::PidStatus;
+    use std::time::Duration;
+
+    let (mut children, mut tracked) = spawn_tracked_waiters("checked-waiter");
+    children[1].kill().unwrap();
+    children[1].wait().unwrap();
+    // A start time from a day earlier stands in for the pid having been reused since it was recorded
+    tracked[2].1 = tracked[2].1.map(|t| t - Duration::from_secs(24 * 60 * 60));
+
+    let statuses = proc_ctl::check_pids(&tracked).unwrap();
+
+    assert_eq!(
+        vec![PidStatus::Alive, PidStatus::Exited, PidStatus::Replaced],
+        statuses
+    );
+    assert_eq!(
+        vec![PidStatus::Alive],
+        proc_ctl::check_pids(&[(tracked[2].0, None)]).unwrap()
+    );
+    assert!(proc_ctl::check_pids(&[]).unwrap().is_empty());
+}
+
+#[cfg(all(
+    feature = "proc",
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn check_pids_of_children_async() {
+    use proc_ctl::PidStatus;
+
+    let (mut children, tracked) = spawn_tracked_waiters("async-checked-waiter");
+    children[0].kill().unwrap();
+    children[0].wait().unwrap();
+
+    let statuses = proc_ctl::check_pids_async(&tracked).await.unwrap();
+
+    assert_eq!(
+        vec![PidStatus::Exited, PidStatus::Alive, PidStatus::Alive],
+        statuses
+    );
+}
+
+/// Spawn the port releaser and read the port it holds
+#[cfg(any(
+    target_os = "linux",
+    all(feature = "proc", any(target_os = "windows", target_os = "macos"))
+))]
+fn spawn_port_releaser() -> (
+    DropChild,
+    std::io::BufReader<std::process::ChildStdout>,
+    proc_ctl::ProtocolPort,
+) {
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("port-releaser");
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+    let mut stdout = std::io::BufReader::new(handle.stdout.take().unwrap());
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let port = proc_ctl::ProtocolPort::Tcp(line.trim().parse().unwrap());
+
+    (handle, stdout, port)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    all(feature = "proc", any(target_os = "windows", target_os = "macos"))
+))]
+#[test]
+fn port_query_wait_for_release_when_killed() {
+    use proc_ctl::{PortQuery, PortRelease};
+    use std::time::Duration;
+
+    let (mut handle, _stdout, port) = spawn_port_releaser();
+    let query = PortQuery::new().tcp_only().process_id(handle.id());
+
+    let released = std::thread::scope(|s| {
+        let wait = s.spawn(|| {
+            query.wait_for_release(port, Duration::from_millis(50), Duration::from_secs(30))
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        handle.kill().unwrap();
+        handle.wait().unwrap();
+        wait.join().unwrap()
+    });
+
+    assert_eq!(PortRelease::ProcessExited, released.unwrap());
+    // Once the process has gone there is nothing to wait for
+    assert_eq!(
+        PortRelease::ProcessExited,
+        query
+            .wait_for_release(port, Duration::from_millis(50), Duration::ZERO)
+            .unwrap()
+    );
+}
+
+#[cfg(any(
+    target_os = "linux",
+    all(feature = "proc", any(target_os = "windows", target_os = "macos"))
+))]
+#[test]
+fn port_query_wait_for_release_when_closed() {
+    use proc_ctl::{PortQuery, PortRelease, ProcCtlError};
+    use std::io::{BufRead, Write};
+    use std::time::Duration;
+
+    let (mut handle, mut stdout, port) = spawn_port_releaser();
+    let query = PortQuery::new().process_id(handle.id());
+
+    match query.wait_for_release(port, Duration::from_millis(50), Duration::from_millis(200)) {
+        Err(ProcCtlError::PortNotReleased(held, pids)) => {
+            assert_eq!(port, held);
+            assert_eq!(vec![handle.id()], pids);
+        }
+        result => panic!("Expected the port to still be held, got {:?}", result),
+    }
+
+    let mut stdin = handle.stdin.take().unwrap();
+    let released = std::thread::scope(|s| {
+        let wait = s.spawn(|| {
+            query.wait_for_release(port, Duration::from_millis(50), Duration::from_secs(30))
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        writeln!(stdin).unwrap();
+        wait.join().unwrap()
+    });
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!("released", line.trim());
+    assert_eq!(PortRelease::PortClosed, released.unwrap());
+    assert!(handle.try_wait().unwrap().is_none());
+
+    assert!(matches!(
+        PortQuery::new()
+            .udp_only()
+            .process_id(handle.id())
+            .wait_for_release(port, Duration::ZERO, Duration::ZERO),
+        Err(ProcCtlError::ConfigurationError(_))
+    ));
+}
+
+#[cfg(all(
+    feature = "async",
+    any(
+        target_os = "linux",
+        all(feature = "proc", any(target_os = "windows", target_os = "macos"))
+    )
+))]
+#[tokio::test]
+async fn port_query_wait_for_release_async_when_killed() {
+    use proc_ctl::{PortQuery, PortRelease};
+    use std::time::Duration;
+
+    let (mut handle, _stdout, port) = spawn_port_releaser();
+    let query = PortQuery::new().tcp_only().process_id(handle.id());
+
+    let kill = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handle.kill().unwrap();
+        handle.wait().unwrap();
+    };
+    let (released, _) = tokio::join!(
+        query.wait_for_release_async(port, Duration::from_millis(50), Duration::from_secs(30)),
+        kill
+    );
+
+    assert_eq!(PortRelease::ProcessExited, released.unwrap());
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn pipeline_checks_children_and_their_ports() {
+    use proc_ctl::{PipelineStage, ProcCtlError, ProcQuery};
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([binder.get_program()]);
+    let handle = DropChild::spawn(runner);
+
+    let runner_query = || ProcQuery::new().process_id(handle.id());
+    let pipeline = runner_query()
+        .children_matching(|q| q.process_name("port-binder").expect_min_num_children(1))
+        .each_with_ports(|p| p.tcp_only().ip_v4_only().expect_min_num_ports(1));
+
+    let found = retry::retry(Fixed::from_millis(100).take(20), || pipeline.check()).unwrap();
+    assert_eq!(1, found.len());
+    assert_eq!(handle.id(), found[0].process.pid);
+    assert_eq!(None, found[0].ports);
+    assert_eq!(1, found[0].children.len());
+    let child = &found[0].children[0];
+    assert_eq!("port-binder", child.process.canonical_name());
+    assert_eq!(Some(1), child.ports.as_ref().map(|ports| ports.len()));
+
+    // Every stage which fails is reported for the process it failed for
+    let err = runner_query()
+        .children_matching(|q| q.process_name("port-binder"))
+        .each_with_ports(|p| p.tcp_only().ip_v4_only().expect_min_num_ports(5))
+        .check()
+        .unwrap_err();
+    let ProcCtlError::PipelineFailed(failure) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert_eq!(
+        vec![(PipelineStage::Ports, Some(child.process.pid))],
+        failure
+            .failures
+            .iter()
+            .map(|f| (f.stage, f.process.as_ref().map(|p| p.pid)))
+            .collect::<Vec<_>>()
+    );
+    assert!(matches!(
+        failure.failures[0].error,
+        ProcCtlError::TooFewPorts(..)
+    ));
+    assert_eq!(1, failure.found[0].children.len());
+
+    let err = runner_query()
+        .children_matching(|q| q.process_name("port-binder").expect_min_num_children(2))
+        .check()
+        .unwrap_err();
+    let ProcCtlError::PipelineFailed(failure) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert_eq!(PipelineStage::Children, failure.failures[0].stage);
+    assert_eq!(
+        Some(handle.id()),
+        failure.failures[0].process.as_ref().map(|p| p.pid)
+    );
+    assert!(failure.to_string().contains("children of proc-runner"));
+
+    let err = ProcQuery::new()
+        .process_name("no-such-pipeline-process")
+        .with_ports(|p| p.tcp_only())
+        .check()
+        .unwrap_err();
+    assert!(matches!(
+        &err,
+        ProcCtlError::PipelineFailed(failure) if failure.failures[0].stage == PipelineStage::Processes
+    ));
+    assert_eq!(proc_ctl::ErrorKind::ProcessNotFound, err.kind());
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn ports_for_each_matching_process() {
+    use proc_ctl::{PortQuery, ProcCtlError, ProcQuery};
+    use retry::delay::Fixed;
+
+    let exe = copy_sample("port-binder", "pf-binder");
+    let binders = (0..2)
+        .map(|_| DropChild::spawn(std::process::Command::new(&exe)))
+        .collect::<Vec<_>>();
+
+    let processes = ProcQuery::new().process_name("pf-binder");
+    let found = retry::retry(Fixed::from_millis(100).take(30), || {
+        let found = proc_ctl::ports_for(&processes, &PortQuery::new().tcp_only())?;
+        if found.len() == 2
+            && found
+                .iter()
+                .all(|(_, p)| p.as_ref().is_ok_and(|p| !p.is_empty()))
+        {
+            Ok(found)
+        } else {
+            Err(ProcCtlError::NoMatchingProcess(
+                "bound port-binders".to_string(),
+            ))
+        }
+    })
+    .unwrap();
+
+    let mut pids = found.iter().map(|(info, _)| info.pid).collect::<Vec<_>>();
+    pids.sort();
+    let mut expected = binders.iter().map(|b| b.id()).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(expected, pids);
+
+    for (info, ports) in found {
+        let ports = ports.unwrap();
+        assert_eq!(1, ports.len(), "ports of {}", info.pid);
+    }
+
+    // Expectations are checked for each process, and a failure is reported for that process alone
+    let too_many = proc_ctl::ports_for(
+        &processes,
+        &PortQuery::new().tcp_only().expect_min_num_ports(2),
+    )
+    .unwrap();
+    assert_eq!(2, too_many.len());
+    for (_, ports) in too_many {
+        assert_eq!("too_few_ports", ports.unwrap_err().code());
+    }
+}
+
+#[cfg(all(
+    feature = "proc",
+    feature = "resilience",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn ports_for_with_retry_sync_waits_for_ports() {
+    use proc_ctl::{PortQuery, ProcQuery};
+    use std::time::Duration;
+
+    let exe = copy_sample("port-binder", "pf-retry-binder");
+    let binder = DropChild::spawn(std::process::Command::new(&exe));
+
+    let found = proc_ctl::ports_for_with_retry_sync(
+        &ProcQuery::new().process_name("pf-retry-binder"),
+        &PortQuery::new().tcp_only().expect_min_num_ports(1),
+        Duration::from_millis(100),
+        30,
+    )
+    .unwrap();
+
+    assert_eq!(1, found.len());
+    assert_eq!(binder.id(), found[0].0.pid);
+    assert_eq!(1, found[0].1.len());
+}
+
+#[cfg(all(
+    feature = "proc",
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn ports_for_with_retry_fails_without_a_match() {
+    use proc_ctl::{PortQuery, ProcCtlError, ProcQuery};
+    use std::time::Duration;
+
+    let result = proc_ctl::ports_for_with_retry(
+        &ProcQuery::new().process_name("no-such-ports-for-process"),
+        &PortQuery::new(),
+        Duration::from_millis(10),
+        2,
+    )
+    .await;
+
+    assert!(matches!(result, Err(ProcCtlError::NoMatchingProcess(_))));
+}
+
+/// Copy the waiter sample into two directories under the same name, making the second trivially different from the
+/// first by appending a byte, so that only the contents of the executables tell them apart
+#[cfg(feature = "proc")]
+fn copy_waiter_builds(copy_name: &str) -> [std::path::PathBuf; 2] {
+    use std::io::Write;
+
+    let source = create_command_for_sample("waiter").get_program().to_owned();
+    let builds = ["v1", "v2"].map(|version| {
+        let dir = std::env::temp_dir()
+            .join(format!("proc-ctl-{}", std::process::id()))
+            .join(version);
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join(copy_name);
+        #[cfg(target_os = "windows")]
+        let target = target.with_extension("exe");
+        std::fs::copy(&source, &target).unwrap();
+        target.canonicalize().unwrap()
+    });
+
+    let mut v2 = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&builds[1])
+        .unwrap();
+    v2.write_all(&[0]).unwrap();
+
+    builds
+}
+
+#[cfg(feature = "proc")]
+fn spawn_waiter_build(exe: &std::path::Path) -> DropChild {
+    use std::process::Stdio;
+
+    let mut cmd = std::process::Command::new(exe);
+    cmd.stdin(Stdio::piped()).stdout(Stdio::null());
+    DropChild::spawn(cmd)
+}
+
+#[cfg(feature = "exe-hash")]
+#[test]
+fn proc_query_selects_a_build_by_exe_hash_or_size() {
+    use proc_ctl::ProcQuery;
+    use retry::delay::Fixed;
+
+    let builds = copy_waiter_builds("exe-waiter");
+    let v1 = spawn_waiter_build(&builds[0]);
+    let v2 = spawn_waiter_build(&builds[1]);
+
+    let by_hash = ProcQuery::new()
+        .process_name("exe-waiter")
+        .exe_sha256(proc_ctl::file_sha256(&builds[0]).unwrap().to_uppercase());
+    let found = retry::retry(Fixed::from_millis(100).take(30), || {
+        let found = by_hash.list_processes()?;
+        match found.as_slice() {
+            [_] => Ok(found),
+            _ => Err(proc_ctl::ProcCtlError::NoMatchingProcess(
+                "v1 of exe-waiter".to_string(),
+            )),
+        }
+    })
+    .unwrap();
+    assert_eq!(v1.id(), found[0].pid);
+
+    let by_size = ProcQuery::new()
+        .process_name("exe-waiter")
+        .exe_file_size(std::fs::metadata(&builds[1]).unwrap().len());
+    let found = by_size.list_processes().unwrap();
+    assert_eq!(
+        vec![v2.id()],
+        found.iter().map(|p| p.pid).collect::<Vec<_>>()
+    );
+}
+
+#[cfg(all(feature = "proc", target_os = "linux"))]
+#[test]
+fn proc_query_exe_filter_skips_deleted_executables() {
+    use proc_ctl::{ProcQuery, SkipReason};
+    use retry::delay::Fixed;
+
+    let builds = copy_waiter_builds("del-waiter");
+    let running = spawn_waiter_build(&builds[0]);
+    let deleted = spawn_waiter_build(&builds[1]);
+
+    let query = ProcQuery::new()
+        .process_name("del-waiter")
+        .exe_matches(|_| true)
+        .explain(true);
+    retry::retry(Fixed::from_millis(100).take(30), || {
+        let report = query.list_processes_report()?;
+        match report.matches.len() {
+            2 => Ok(()),
+            _ => Err(proc_ctl::ProcCtlError::NoMatchingProcess(
+                "both del-waiters".to_string(),
+            )),
+        }
+    })
+    .unwrap();
+
+    std::fs::remove_file(&builds[1]).unwrap();
+
+    let report = query.list_processes_report().unwrap();
+    assert_eq!(
+        vec![running.id()],
+        report.matches.iter().map(|p| p.pid).collect::<Vec<_>>()
+    );
+    let skipped = report
+        .skipped
+        .iter()
+        .find(|s| s.pid == deleted.id())
+        .unwrap();
+    assert_eq!(SkipReason::ExeDeleted, skipped.reason);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn wait_for_tcp_port_finds_the_listener() {
+    use std::time::Duration;
+
+    let handle = DropChild::spawn(create_command_for_sample("port-binder"));
+
+    let port = proc_ctl::wait_for_tcp_port(&handle, Duration::from_secs(10)).unwrap();
+
+    let ports = proc_ctl::PortQuery::new()
+        .process_id(handle.id())
+        .execute()
+        .unwrap();
+    assert_eq!(vec![proc_ctl::ProtocolPort::Tcp(port)], ports);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn wait_for_ports_times_out_with_history() {
+    use std::time::Duration;
+
+    let mut waiter = create_command_for_sample("waiter");
+    waiter.stdin(std::process::Stdio::piped());
+    let handle = DropChild::spawn(waiter);
+
+    let err = proc_ctl::wait_for_ports(&handle, 1, Duration::from_millis(300)).unwrap_err();
+
+    let proc_ctl::ProcCtlError::WaitTimedOut(history) = err else {
+        panic!("unexpected error {:?}", err);
+    };
+    assert!(history.attempts >= 2, "{:?}", history);
+    assert!(history
+        .observations
+        .last()
+        .unwrap()
+        .seen
+        .contains("too_few_ports"));
+}
+
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[tokio::test]
+async fn wait_for_ports_async_finds_the_listener() {
+    use std::time::Duration;
+
+    let handle = DropChild::spawn(create_command_for_sample("port-binder"));
+
+    let ports = proc_ctl::wait_for_ports_async(&handle, 1, Duration::from_secs(10))
+        .await
+        .unwrap();
+    let port = proc_ctl::wait_for_tcp_port_async(&handle, Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    assert_eq!(vec![proc_ctl::ProtocolPort::Tcp(port)], ports);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_counts_a_port_bound_on_both_families_once() {
+    use proc_ctl::{AddressFamily, PortQuery, ProtocolPort};
+    use retry::delay::Fixed;
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("dual-stack-binder");
+    cmd.stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+
+    let mut stdout = std::io::BufReader::new(handle.stdout.take().unwrap());
+    let mut read_port = || {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        line.trim().parse::<u16>().unwrap()
+    };
+    let tcp = read_port();
+    let udp = read_port();
+
+    let query = PortQuery::new()
+        .process_id(handle.id())
+        .expect_min_num_ports(2);
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+    let detailed = query.execute_detailed().unwrap();
+
+    let split = PortQuery::new()
+        .process_id(handle.id())
+        .split_families(true);
+    let split_ports = split.execute().unwrap();
+    let split_detailed = split.execute_detailed().unwrap();
+
+    assert_eq!(2, ports.len(), "{:?}", ports);
+    assert_eq!(2, detailed.len(), "{:?}", detailed);
+    for info in &detailed {
+        assert_eq!(
+            vec![AddressFamily::Ipv4, AddressFamily::Ipv6],
+            info.families
+        );
+        assert_eq!(AddressFamily::Ipv4, info.family);
+    }
+    assert_eq!(4, split_ports.len(), "{:?}", split_ports);
+    assert!(split_detailed.iter().all(|info| info.families.len() == 1));
+
+    // Windows does not yet report the protocol and number of every port correctly
+    if cfg!(not(target_os = "windows")) {
+        let mut ports = ports;
+        ports.sort();
+        assert_eq!(vec![ProtocolPort::Tcp(tcp), ProtocolPort::Udp(udp)], ports);
+
+        let summary = query.summary().unwrap();
+        assert_eq!(vec![tcp], summary.tcp_v4);
+        assert_eq!(vec![tcp], summary.tcp_v6);
+        assert_eq!(vec![udp], summary.udp_v4);
+        assert_eq!(vec![udp], summary.udp_v6);
+    }
+}
+
+#[cfg(all(
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn proc_query_collects_the_environment_only_when_asked() {
+    use proc_ctl::{ProcCtlError, ProcQuery};
+    use retry::delay::Fixed;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("waiter");
+    cmd.env("PROC_CTL_TEST_ENV", "present")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null());
+    let handle = DropChild::spawn(cmd);
+
+    let find = |query: ProcQuery| {
+        retry::retry(Fixed::from_millis(100).take(10), || {
+            let mut processes = query.list_processes()?;
+            processes
+                .pop()
+                .ok_or_else(|| ProcCtlError::ProcessNotFound(handle.id()))
+        })
+        .unwrap()
+    };
+
+    let collected = find(ProcQuery::new().process_id(handle.id()).with_env(true));
+    assert!(collected.env_collected);
+    assert_eq!(
+        Some("present"),
+        collected.env_var("PROC_CTL_TEST_ENV").unwrap()
+    );
+    assert_eq!("present", collected.env_map().unwrap()["PROC_CTL_TEST_ENV"]);
+
+    // Listed straight after the environment was collected, which must not leak into this result
+    let not_collected = find(ProcQuery::new().process_id(handle.id()).with_env(false));
+    assert!(!not_collected.env_collected);
+    assert!(not_collected.env.is_empty());
+    assert!(matches!(
+        not_collected.env_var("PROC_CTL_TEST_ENV"),
+        Err(ProcCtlError::EnvNotCollected(pid)) if pid == handle.id()
+    ));
+    assert!(matches!(
+        not_collected.env_map(),
+        Err(ProcCtlError::EnvNotCollected(_))
+    ));
+}
+
+#[cfg(all(
+    feature = "serde",
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn port_query_on_a_saved_snapshot_matches_the_live_query() {
+    use proc_ctl::{PortQuery, Redaction, Snapshot};
+    use retry::delay::Fixed;
+    use std::io::BufRead;
+    use std::process::Stdio;
+
+    let mut cmd = create_command_for_sample("dual-stack-binder");
+    cmd.stdout(Stdio::piped());
+    let mut handle = DropChild::spawn(cmd);
+    let mut stdout = std::io::BufReader::new(handle.stdout.take().unwrap());
+    for _ in 0..2 {
+        stdout.read_line(&mut String::new()).unwrap();
+    }
+
+    let query = PortQuery::new()
+        .process_id(handle.id())
+        .expect_min_num_ports(2);
+    let mut live = retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+
+    let path = std::env::temp_dir().join(format!("proc-ctl-snapshot-test-{}.json", handle.id()));
+    Snapshot::capture(&Redaction::new().drop_env().truncate_cmd(1))
+        .unwrap()
+        .save(&path)
+        .unwrap();
+    let snapshot = Snapshot::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut replayed = query.execute_on(&snapshot).unwrap();
+    live.sort_unstable();
+    replayed.sort_unstable();
+    assert_eq!(live, replayed);
+
+    let process = snapshot.process(handle.id()).unwrap();
+    assert!(process.cmd.len() <= 1, "{:?}", process.cmd);
+    assert!(!process.env_collected);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_includes_children_with_or_without_the_proc_feature() {
+    use proc_ctl::{PortQuery, ProcCtlError};
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([binder.get_program()]);
+    let handle = DropChild::spawn(runner);
+
+    let query = PortQuery::new()
+        .process_id(handle.id())
+        .include_children(true)
+        .expect_min_num_ports(1);
+    let result = retry::retry(Fixed::from_millis(100).take(10), || query.execute());
+
+    if cfg!(any(feature = "proc", target_os = "linux")) {
+        let ports = result.unwrap();
+        assert_eq!(1, ports.len());
+        assert!(PortQuery::new()
+            .process_id(handle.id())
+            .execute()
+            .unwrap()
+            .is_empty());
+
+        // The binder exits once it accepts a connection, so that it isn't left running after the runner is killed
+        std::net::TcpStream::connect(("127.0.0.1", ports[0].port())).unwrap();
+    } else {
+        assert!(matches!(
+            result,
+            Err(retry::Error {
+                error: ProcCtlError::UnsupportedWithoutFeature("proc"),
+                ..
+            })
+        ));
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    all(feature = "proc", any(target_os = "windows", target_os = "macos"))
+))]
+#[test]
+fn port_query_includes_grandchildren() {
+    use proc_ctl::PortQuery;
+    use retry::delay::Fixed;
+
+    let binder = create_command_for_sample("port-binder");
+    let inner = create_command_for_sample("proc-runner");
+    let mut runner = create_command_for_sample("proc-runner");
+    runner.args([inner.get_program(), binder.get_program()]);
+    let handle = DropChild::spawn(runner);
+
+    let query = PortQuery::new()
+        .process_id(handle.id())
+        .include_children(true)
+        .expect_min_num_ports(1);
+    let ports = retry::retry(Fixed::from_millis(100).take(10), || query.execute()).unwrap();
+    assert_eq!(1, ports.len());
+
+    // The binder exits once it accepts a connection, so that it isn't left running after the runner is killed
+    std::net::TcpStream::connect(("127.0.0.1", ports[0].port())).unwrap();
+}
+
+#[cfg(all(feature = "async", target_os = "linux"))]
+#[tokio::test]
+async fn port_query_events_keep_a_history() {
+    use futures_util::StreamExt;
+    use proc_ctl::{PortEvent, PortQuery};
+    use std::time::Duration;
+
+    let binder = create_command_for_sample("port-binder");
+    let mut handle = DropChild::spawn(binder);
+
+    let mut events = PortQuery::new()
+        .tcp_only()
+        .process_id_from_child(&handle)
+        .events(Duration::from_millis(50))
+        .with_history(10);
+
+    let port = match tokio::time::timeout(Duration::from_secs(5), events.next())
+        .await
+        .unwrap()
+    {
+        Some(Ok(PortEvent::PortBound(port))) => port,
+        other => panic!("unexpected event {:?}", other),
+    };
+
+    handle.kill().unwrap();
+    handle.wait().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), (&mut events).collect::<Vec<_>>())
+        .await
+        .unwrap();
+
+    let history = events.history().unwrap();
+    let recorded = history.events().collect::<Vec<_>>();
+    assert_eq!(PortEvent::PortBound(port), recorded[0].event);
+    let exited = recorded.last().unwrap();
+    assert_eq!(PortEvent::ProcessExited, exited.event);
+
+    let bound = history.first_bound(port).unwrap();
+    assert!(history.ever_bound(port));
+    assert!(history.was_continuously_bound(port, bound..exited.at));
+    assert!(!history.was_continuously_bound(
+        port,
+        bound..history.last_observation().unwrap() + Duration::from_millis(1)
+    ));
+}
+
+#[cfg(all(feature = "serde", feature = "proc", target_os = "linux"))]
+#[test]
+fn snapshot_includes_raw_sockets_when_asked() {
+    use proc_ctl::{AddressFamily, CaptureOptions, Snapshot};
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // Opening a raw socket needs CAP_NET_RAW, so there is nothing to find without it
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP) };
+    if fd < 0 {
+        println!(
+            "skipping, raw sockets are not permitted: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    let _socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let pid = std::process::id();
+
+    let snapshot =
+        Snapshot::capture_with(&CaptureOptions::new().include_raw_sockets(true)).unwrap();
+    let raw = snapshot
+        .raw_sockets
+        .iter()
+        .find(|s| s.pid == pid)
+        .expect("the raw socket of this process");
+    assert_eq!(1, raw.protocol);
+    assert_eq!(AddressFamily::Ipv4, raw.family);
+    assert!(!raw.ping);
+
+    assert!(Snapshot::capture_with(&CaptureOptions::new())
+        .unwrap()
+        .raw_sockets
+        .is_empty());
+}
+
+#[cfg(all(
+    feature = "serde",
+    feature = "proc",
+    any(target_os = "linux", target_os = "windows", target_os = "macos")
+))]
+#[test]
+fn snapshot_keeps_at_most_max_results_sockets() {
+    use proc_ctl::{CaptureOptions, Snapshot};
+
+    let _listeners = (0..3)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>();
+
+    let snapshot = Snapshot::capture_with(&CaptureOptions::new().max_results(2)).unwrap();
+    assert_eq!(2, snapshot.sockets.len());
+    assert!(snapshot.truncated);
+    assert!(!snapshot.processes.is_empty());
+
+    let all = Snapshot::capture_with(&CaptureOptions::new()).unwrap();
+    assert!(all.sockets.len() >= 3);
+    assert!(!all.truncated);
+}
+
+// Windows is left out until its tables are read with the port in host byte order
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn port_query_owners_of_finds_the_owning_process() {
+    use proc_ctl::{AddressFamily, OwnerKind, PortOwner, PortQuery, ProtocolPort};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let udp = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    let pid = std::process::id();
+
+    let owners = PortQuery::new().owners_of(port).unwrap();
+    assert_eq!(
+        vec![ProtocolPort::Tcp(port), ProtocolPort::Udp(port)],
+        owners.iter().map(|o| o.port).collect::<Vec<_>>()
+    );
+    for owner in &owners {
+        assert_eq!(OwnerKind::Process(pid), owner.owner);
+        assert_eq!(AddressFamily::Ipv4, owner.family);
+        assert_eq!(
+            Some(std::net::SocketAddr::from(([127, 0, 0, 1], port))),
+            owner.local_addr
+        );
+        #[cfg(feature = "proc")]
+        assert!(owner.name.is_some());
+    }
+
+    let owners = PortQuery::new().udp_only().owners_of(port).unwrap();
+    assert_eq!(
+        vec![ProtocolPort::Udp(port)],
+        owners.iter().map(|o| o.port).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        Vec::<PortOwner>::new(),
+        PortQuery::new().ip_v6_only().owners_of(port).unwrap()
+    );
+
+    drop(listener);
+    drop(udp);
+    assert!(PortQuery::new().owners_of(port).unwrap().is_empty());
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn connection_query_finds_both_ends_of_a_connection() {
+    use proc_ctl::{ConnectionQuery, ProcCtlError, TcpState};
+    use std::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let client = TcpStream::connect(server_addr).unwrap();
+    let (accepted, client_addr) = listener.accept().unwrap();
+    assert_eq!(client.local_addr().unwrap(), client_addr);
+
+    let query = || ConnectionQuery::new().process_id(std::process::id());
+
+    let connections = query()
+        .remote_port(server_addr.port())
+        .established_only()
+        .execute()
+        .unwrap();
+    assert_eq!(1, connections.len());
+    assert_eq!(client_addr, connections[0].local_addr);
+    assert_eq!(server_addr, connections[0].remote_addr);
+    assert_eq!(TcpState::Established, connections[0].state);
+
+    // The accepted end belongs to this process too, and the listener itself is never a connection
+    let ends = query()
+        .ip_v4_only()
+        .execute()
+        .unwrap()
+        .into_iter()
+        .filter(|c| c.local_addr == server_addr || c.local_addr == client_addr)
+        .map(|c| (c.local_addr, c.remote_addr))
+        .collect::<Vec<_>>();
+    let mut expected = vec![(server_addr, client_addr), (client_addr, server_addr)];
+    expected.sort();
+    assert_eq!(expected, ends);
+
+    assert!(query()
+        .ip_v6_only()
+        .remote_port(server_addr.port())
+        .execute()
+        .unwrap()
+        .is_empty());
+    let err = query()
+        .remote_port(server_addr.port())
+        .expect_min_num_connections(2)
+        .execute()
+        .unwrap_err();
+    assert!(
+        matches!(&err, ProcCtlError::TooFewConnections(found, 2) if found.len() == 1),
+        "{:?}",
+        err
+    );
+
+    drop(accepted);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+#[test]
+fn port_query_tcp_states_find_connections() {
+    use proc_ctl::{PortQuery, ProtocolPort, TcpState};
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let client_port = client.local_addr().unwrap().port();
+    let (accepted, _) = listener.accept().unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_port = udp.local_addr().unwrap().port();
+
+    let query = || {
+        PortQuery::new()
+            .process_id(std::process::id())
+            .ip_v4_only()
+            .split_families(true)
+    };
+    let states_of = |ports: &[proc_ctl::PortInfo], port: u16| {
+        let mut states = ports
+            .iter()
+            .filter(|p| p.port == ProtocolPort::Tcp(port))
+            .map(|p| p.tcp_state)
+            .collect::<Vec<_>>();
+        states.sort_by_key(|s| *s != Some(TcpState::Listen));
+        states
+    };
+
+    // Only listeners by default, and UDP ports whatever the states
+    let ports = query().execute_detailed().unwrap();
+    assert_eq!(vec![Some(TcpState::Listen)], states_of(&ports, port));
+    assert!(states_of(&ports, client_port).is_empty());
+    assert!(ports
+        .iter()
+        .any(|p| p.port == ProtocolPort::Udp(udp_port) && p.tcp_state.is_none()));
+
+    let ports = query()
+        .tcp_states(&[TcpState::Listen, TcpState::Established])
+        .execute_detailed()
+        .unwrap();
+    assert_eq!(
+        vec![Some(TcpState::Listen), Some(TcpState::Established)],
+        states_of(&ports, port)
+    );
+    assert_eq!(
+        vec![Some(TcpState::Established)],
+        states_of(&ports, client_port)
+    );
+
+    let ports = query()
+        .tcp_states(&[TcpState::Established])
+        .execute_detailed()
+        .unwrap();
+    assert_eq!(vec![Some(TcpState::Established)], states_of(&ports, port));
+    assert!(ports.iter().any(|p| p.port == ProtocolPort::Udp(udp_port)));
+
+    drop(accepted);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_finds_unix_sockets() {
+    use proc_ctl::{PortQuery, UnixSocketAddr};
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+
+    let dir = std::env::temp_dir().join(format!("proc-ctl-unix-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.sock");
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let name = format!("proc-ctl-unix-test-{}", std::process::id());
+    let abstract_listener =
+        UnixListener::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+
+    // The accepted connection is listed by /proc with the path of the listener, but is not listening
+    let _client = UnixStream::connect(&path).unwrap();
+    let _accepted = listener.accept().unwrap();
+
+    let sockets = PortQuery::new()
+        .process_id(std::process::id())
+        .unix_sockets()
+        .unwrap();
+
+    let path_addr = UnixSocketAddr::Path(path.clone());
+    assert_eq!(
+        1,
+        sockets.iter().filter(|s| s.addr == path_addr).count(),
+        "{:?}",
+        sockets
+    );
+    assert!(
+        sockets
+            .iter()
+            .any(|s| s.addr == UnixSocketAddr::Abstract(name.clone())),
+        "{:?}",
+        sockets
+    );
+    assert!(sockets.iter().all(|s| s.pid == std::process::id()));
+
+    drop((listener, abstract_listener));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "windows")]
+#[test]
+fn port_query_unix_sockets_are_unsupported_on_windows() {
+    let err = proc_ctl::PortQuery::new()
+        .process_id(std::process::id())
+        .unix_sockets()
+        .unwrap_err();
+    assert!(
+        matches!(err, proc_ctl::ProcCtlError::UnsupportedPlatform(_)),
+        "{:?}",
+        err
+    );
+}
+
+#[cfg(all(feature = "test-util", any(target_os = "linux", target_os = "macos")))]
+#[test]
+fn port_query_groups_the_ports_of_several_pids() {
+    use proc_ctl::binder::BinderConfig;
+    use proc_ctl::ProcCtlError;
+
+    let one = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 1,
+        ..Default::default()
+    });
+    let two = spawn_multi_port_binder(&BinderConfig {
+        tcp4: 2,
+        ..Default::default()
+    });
+    let query = || {
+        proc_ctl::PortQuery::new()
+            .tcp_only()
+            .process_ids(&[one.pid(), two.pid()])
+    };
+
+    let mut all = query().execute().unwrap();
+    all.sort_unstable();
+    let mut bound = one.ports();
+    bound.extend(two.ports());
+    bound.sort_unstable();
+    assert_eq!(bound, all);
+
+    let grouped = query().expect_min_num_ports(1).execute_grouped().unwrap();
+    assert_eq!(2, grouped.len());
+    assert_eq!(one.ports(), grouped[&one.pid()]);
+    let mut of_two = grouped[&two.pid()].clone();
+    of_two.sort_unstable();
+    let mut bound = two.ports();
+    bound.sort_unstable();
+    assert_eq!(bound, of_two);
+
+    // Each process needs two ports, which only one of them has
+    let result = query().expect_min_num_ports(2).execute();
+    assert!(
+        matches!(result, Err(ProcCtlError::TooFewPorts(ref found, 2)) if *found == one.ports()),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn port_query_detailed_socket_inode_and_fd() {
+    use std::os::fd::AsRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let ports = proc_ctl::PortQuery::new()
+        .tcp_only()
+        .ip_v4_only()
+        .process_id(std::process::id())
+        .execute_detailed()
+        .unwrap();
+
+    let info = ports
+        .iter()
+        .find(|p| p.port == proc_ctl::ProtocolPort::Tcp(port))
+        .expect("Should find the listener");
+    assert_eq!(Some(listener.as_raw_fd()), info.fd);
+    assert!(info.socket_inode.is_some());
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+#[test]
+fn port_query_is_unsupported_on_other_platforms() {
+    use proc_ctl::{ErrorKind, PortQuery, ProcCtlError};
+
+    let query = PortQuery::new().process_id(std::process::id());
+
+    let err = query.execute().unwrap_err();
+    assert!(matches!(err, ProcCtlError::UnsupportedPlatform(_)));
+    assert_eq!(ErrorKind::UnsupportedPlatform, err.kind());
+    assert!(matches!(
+        query.execute_detailed(),
+        Err(ProcCtlError::UnsupportedPlatform(_))
+    ));
 }